@@ -0,0 +1,120 @@
+// Latency of the verify-code path's crypto, with the instance id derivation run inline on the
+// runtime (as it used to be) against on the blocking pool
+
+use blaze_service::server::container::get_unique_instance_id;
+use blaze_service::server::crypto::{APIKey, hash_otp, verify_otp};
+use blaze_service::server::secrets::load_secrets;
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha512;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Verifications running at once, more than the runtime has workers
+const CONCURRENT_VERIFICATIONS: usize = 16;
+const RUNTIME_WORKERS: usize = 2;
+
+/// Tells the ticking task the verifications are done
+static STOP: AtomicBool = AtomicBool::new(false);
+
+fn main() -> anyhow::Result<()> {
+    println!("Verify-code Crypto - Latency Benchmark\n");
+
+    // Only used to derive instance ids here
+    unsafe { std::env::set_var("BLAZE_INSTANCE_SECRET", "bench-instance-secret") };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(RUNTIME_WORKERS)
+        .enable_all()
+        .build()?;
+    runtime.block_on(load_secrets())?;
+
+    println!("Benchmark 1: One Verification");
+    let otp_hash = hash_otp("123456");
+    let start = Instant::now();
+    assert!(verify_otp("123456", &otp_hash));
+    let _ = APIKey::get_new_key("alice", "alice@example.com");
+    println!("   OTP check and new API key: {:?}", start.elapsed());
+    let start = Instant::now();
+    let _ = derive_inline("alice@example.com");
+    println!("   Instance id derivation:    {:?}\n", start.elapsed());
+
+    println!(
+        "Benchmark 2: {} Concurrent Verifications on {} Workers",
+        CONCURRENT_VERIFICATIONS, RUNTIME_WORKERS
+    );
+    for offloaded in [false, true] {
+        let (total, stall) = runtime.block_on(concurrent_verifications(offloaded))?;
+        println!(
+            "   {}: all done in {:?}, other requests stalled up to {:?}",
+            if offloaded {
+                "Blocking pool"
+            } else {
+                "Inline       "
+            },
+            total,
+            stall
+        );
+    }
+    println!();
+
+    println!("Benchmark complete!");
+
+    Ok(())
+}
+
+/// The derivation as it ran before, on whichever thread called it
+fn derive_inline(email: &str) -> String {
+    let mut instance_id = [0u8; 16];
+    pbkdf2_hmac::<Sha512>(
+        email.as_bytes(),
+        b"bench-instance-secret",
+        100_000,
+        &mut instance_id,
+    );
+    hex::encode(instance_id)
+}
+
+/// Runs the verifications at once while another task ticks every millisecond, returns how long the
+/// verifications took and the worst delay the ticking task saw (what any other request would)
+async fn concurrent_verifications(offloaded: bool) -> anyhow::Result<(Duration, Duration)> {
+    STOP.store(false, Ordering::Relaxed);
+    let ticker = tokio::spawn(async {
+        let mut worst = Duration::ZERO;
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while Instant::now() < deadline {
+            let before = Instant::now();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            worst = worst.max(before.elapsed().saturating_sub(Duration::from_millis(1)));
+            if STOP.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        worst
+    });
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let start = Instant::now();
+    let verifications: Vec<_> = (0..CONCURRENT_VERIFICATIONS)
+        .map(|i| {
+            tokio::spawn(async move {
+                let email = format!("user{}@example.com", i);
+                let otp_hash = hash_otp("123456");
+                assert!(verify_otp("123456", &otp_hash));
+                let instance_id = if offloaded {
+                    get_unique_instance_id(&email).await?
+                } else {
+                    derive_inline(&email)
+                };
+                let _ = APIKey::get_new_key("user", &email);
+                anyhow::Ok(instance_id)
+            })
+        })
+        .collect();
+    for verification in verifications {
+        verification.await??;
+    }
+    let total = start.elapsed();
+
+    STOP.store(true, Ordering::Relaxed);
+    let stall = ticker.await?;
+    Ok((total, stall))
+}
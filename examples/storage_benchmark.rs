@@ -1,14 +1,18 @@
 // Simple benchmark for the storage engine
 
+use blaze_service::server::secrets::load_secrets;
 use blaze_service::server::storage::DataStore;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 fn main() -> anyhow::Result<()> {
     println!("HashMap Storage Engine - Performance Benchmark\n");
 
+    // Like the service, so the stores see the configured secrets
+    tokio::runtime::Runtime::new()?.block_on(load_secrets())?;
+
     let _ = std::fs::remove_file("data/bench_insert.json");
     let _ = std::fs::remove_file("data/bench_read.json");
     let _ = std::fs::remove_file("data/bench_concurrent.json");
@@ -19,7 +23,7 @@ fn main() -> anyhow::Result<()> {
     let start = Instant::now();
     let count = 10000;
     for i in 0..count {
-        store.insert(i, format!("value_{}", i))?;
+        store.insert_save(i, format!("value_{}", i))?;
     }
     let duration = start.elapsed();
 
@@ -41,7 +45,7 @@ fn main() -> anyhow::Result<()> {
     //         thread::spawn(move || {
     //             for i in 0..(count / 12) {
     //                 let key = t * (count / 12) + i;
-    //                 store_clone.insert(key, format!("value_{}", key)).unwrap();
+    //                 store_clone.insert_save(key, format!("value_{}", key)).unwrap();
     //             }
     //         })
     //     })
@@ -104,7 +108,7 @@ fn main() -> anyhow::Result<()> {
         let handle = thread::spawn(move || {
             for i in 0..items_per_thread {
                 let key = t * items_per_thread + i;
-                let _ = store_clone.insert(key, format!("thread_{}_value_{}", t, i));
+                let _ = store_clone.insert_save(key, format!("thread_{}_value_{}", t, i));
             }
         });
         handles.push(handle);
@@ -150,11 +154,71 @@ fn main() -> anyhow::Result<()> {
         (size_kb * 1024.0) / count as f64
     );
 
+    println!("Benchmark 7: Write Scaling");
+    // Same number of writes spread over more threads, in memory only and saved on every write
+    let total = 120_000;
+    let mut single_thread = None;
+    for threads in [1, 2, 4, 12] {
+        let duration = concurrent_writes(threads, total / threads, false)?;
+        let baseline = *single_thread.get_or_insert(duration);
+        println!(
+            "   {:>2} thread(s): {} in-memory writes in {:?} ({:.2}x)",
+            threads,
+            total,
+            duration,
+            baseline.as_secs_f64() / duration.as_secs_f64()
+        );
+    }
+    let saved_total = 2_400;
+    let mut single_thread = None;
+    for threads in [1, 12] {
+        let duration = concurrent_writes(threads, saved_total / threads, true)?;
+        let baseline = *single_thread.get_or_insert(duration);
+        println!(
+            "   {:>2} thread(s): {} saved writes in {:?} ({:.2}x, saves are shared)",
+            threads,
+            saved_total,
+            duration,
+            baseline.as_secs_f64() / duration.as_secs_f64()
+        );
+    }
+    println!();
+
     let _ = std::fs::remove_file("data/bench_insert.json");
     let _ = std::fs::remove_file("data/bench_batch.json");
     let _ = std::fs::remove_file("data/bench_concurrent.json");
+    let _ = std::fs::remove_file("data/bench_scaling.json");
 
     println!("Benchmark complete!");
 
     Ok(())
 }
+
+/// Each thread writes its own keys, reading every one back, returns how long they all took
+fn concurrent_writes(threads: u64, per_thread: u64, save: bool) -> anyhow::Result<Duration> {
+    let _ = std::fs::remove_file("data/bench_scaling.json");
+    let store: DataStore<u64, String> = DataStore::new(PathBuf::from("data/bench_scaling.json"))?;
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..per_thread {
+                    let key = t * per_thread + i;
+                    let value = format!("thread_{}_value_{}", t, i);
+                    if save {
+                        store.insert_save(key, value).unwrap();
+                    } else {
+                        store.insert_mem(key, value).unwrap();
+                    }
+                    store.get(&key).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    Ok(start.elapsed())
+}
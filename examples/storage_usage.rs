@@ -1,7 +1,10 @@
 // Example of how to use the DataStore storage engine with User schema
 
 use anyhow::Result;
-use blaze_service::server::schema::{Plans, User};
+use blaze_service::server::schema::{
+    BillingInterval, BillingStatus, InstanceConfig, Plans, SubscriptionState, TaxDetails, User,
+};
+use blaze_service::server::secrets::load_secrets;
 use blaze_service::server::storage::DataStore;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -9,8 +12,13 @@ use std::sync::Arc;
 fn main() -> Result<()> {
     println!("Hashmap Storage Engine Example\n");
 
+    // Users are encrypted with `BLAZE_PII_KEY` when it's set, so secrets are loaded first
+    tokio::runtime::Runtime::new()?.block_on(load_secrets())?;
+
     // Create a user store (email -> User mapping)
-    let user_store: DataStore<String, User> = DataStore::new(PathBuf::from("data/users.json"))?;
+    // Users stored with an older schema (e.g. `instance_url`) are upgraded as they're loaded
+    let user_store: DataStore<String, User> =
+        DataStore::with_schema(PathBuf::from("data/users.json"), User::record_schema())?;
 
     println!("Created user store");
 
@@ -20,13 +28,30 @@ fn main() -> Result<()> {
         email: "alice@example.com".to_string(),
         api_key: Vec::new(),
         is_verified: false,
-        plans: Plans::free_plan(),
-        instance_url: "https://alice.blaze.io".to_string(),
+        plans: Plans::default_plan(),
+        instance_id: "alice-instance".to_string(),
         created_at: chrono::Utc::now().to_rfc3339(),
+        reverified_at: None,
+        trial_expires_at: None,
+        trial_used: false,
+        billing_status: BillingStatus::Active,
+        past_due_since: None,
+        dunning_reminders_sent: 0,
+        clone_instance_ids: vec![],
+        organization_id: None,
+        billing_interval: BillingInterval::Monthly,
+        current_period_end: None,
+        referral_code: None,
+        referred_by: None,
+        subscription_state: SubscriptionState::Active,
+        stripe_subscription_id: None,
+        stripe_customer_id: None,
+        tax: TaxDetails::default(),
+        instance_config: InstanceConfig::default(),
     };
 
     // Insert the user
-    user_store.insert(user.email.clone(), user.clone())?;
+    user_store.insert_save(user.email.clone(), user.clone())?;
     println!("Inserted user: {}", user.email);
 
     // Get the user
@@ -46,7 +71,7 @@ fn main() -> Result<()> {
     println!("\nCreated OTP store");
 
     // Store an OTP
-    otp_store.insert("alice@example.com".to_string(), "123456".to_string())?;
+    otp_store.insert_save("alice@example.com".to_string(), "123456".to_string())?;
     println!(" Stored OTP for alice@example.com");
 
     // Verify OTP
@@ -69,11 +94,28 @@ fn main() -> Result<()> {
                 email: email.clone(),
                 api_key: Vec::new(),
                 is_verified: false,
-                plans: Plans::free_plan(),
-                instance_url: format!("https://user{}.blaze.io", i),
+                plans: Plans::default_plan(),
+                instance_id: format!("user{}-instance", i),
                 created_at: chrono::Utc::now().to_rfc3339(),
+                reverified_at: None,
+                trial_expires_at: None,
+                trial_used: false,
+                billing_status: BillingStatus::Active,
+                past_due_since: None,
+                dunning_reminders_sent: 0,
+                clone_instance_ids: vec![],
+                organization_id: None,
+                billing_interval: BillingInterval::Monthly,
+                current_period_end: None,
+                referral_code: None,
+                referred_by: None,
+                subscription_state: SubscriptionState::Active,
+                stripe_subscription_id: None,
+                stripe_customer_id: None,
+                tax: TaxDetails::default(),
+                instance_config: InstanceConfig::default(),
             };
-            store_clone.insert(email, user).unwrap();
+            store_clone.insert_save(email, user).unwrap();
         });
         handles.push(handle);
     }
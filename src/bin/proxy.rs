@@ -1,31 +1,126 @@
 use anyhow::Result;
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::{
-    Json, Router,
+    Extension, Json, Router,
     body::{Body, Bytes},
-    extract::State,
-    http::{HeaderMap, Method, StatusCode, Uri},
-    response::{IntoResponse, Response},
+    extract::{ConnectInfo, Path, Request, State},
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
     routing::any,
+    serve::ListenerExt,
 };
-use blaze_service::server::crypto::{extract_email_from_api_key, hash_api_key};
-use blaze_service::server::ports::calculate_container_port;
-use blaze_service::server::schema::User;
+use blaze_service::server::access_log::{
+    AccessLog, AccessLogEntry, AccessLogSettings, REQUEST_ID_HEADER, RequestContext, new_request_id,
+};
+use blaze_service::server::activity::{ActivityGuard, ActivityTracker};
+use blaze_service::server::anomaly::{
+    KeyUsageProfile, build_alert_email, instance_token_profile_key,
+};
+use blaze_service::server::audit::{
+    AuditEvent, AuditKind, AuditLog, AuditSettings, key_fingerprint,
+};
+use blaze_service::server::balancer::{BalancerSettings, LoadBalancer};
+use blaze_service::server::capabilities::{
+    BACKEND_VERSION_PATH, build_capabilities, is_blocked_endpoint, is_embedding_endpoint,
+};
+use blaze_service::server::circuit::{BreakerSettings, CircuitBreaker, retry_delay};
+use blaze_service::server::compression::{proxy_compression_layer, request_decompression_layer};
+use blaze_service::server::concurrency::{
+    ConcurrencyLimiter, ConcurrencySettings, InFlightPermit, Rejected,
+};
+use blaze_service::server::container::{
+    ContainerSpec, get_instance_state, restart_blazedb_container,
+};
+use blaze_service::server::container_events::watch_container_events;
+use blaze_service::server::cors::proxy_cors_layer;
+use blaze_service::server::crypto::token::Keyring;
+use blaze_service::server::crypto::{
+    INSTANCE_TOKEN_PREFIX, InstanceTokenClaims, api_key_format_version, api_key_matches_hash,
+    get_instance_token_keyring, hash_api_key, needs_rehash, verify_instance_token,
+};
+use blaze_service::server::forwarding::{append_forwarding_headers, strip_hop_by_hop};
+use blaze_service::server::hibernation::{
+    cold_start_wait, forget_hibernation, hibernate_instance, is_due_for_hibernation, is_hibernated,
+    wake_instance,
+};
+use blaze_service::server::invalidation::{INVALIDATE_PATH, control_addr, is_authorized};
+use blaze_service::server::key_index::{KEY_INDEX_FILE, find_api_key_owner};
+use blaze_service::server::mailer::send_mail;
+use blaze_service::server::maintenance::{get_maintenance_store, maintenance_for, retry_after};
+use blaze_service::server::metering::{
+    UsageMeter, WriteScanner, count_vectors, get_usage_ledger, inspect_limit,
+};
+use blaze_service::server::metrics::{MetricsCollector, get_metrics_store};
+use blaze_service::server::network::{ClientIp, get_trusted_proxy_config};
+use blaze_service::server::pool::{ClientKind, ClientPool, PoolSettings};
+use blaze_service::server::quota::{
+    BACKEND_STATS_PATH, QuotaTracker, QuotaWrite, check_quota, classify_scanned_write,
+    classify_write, parse_stats, plan_limits,
+};
+use blaze_service::server::reachability::{ReachabilityTracker, get_reachability_store};
+use blaze_service::server::rehash::{get_key_rehash_store, queue_key_rehash};
+use blaze_service::server::response_cache::{
+    CACHE_STATUS_HEADER, CacheSettings, CachedResponse, RequestCacheControl, ResponseCache,
+    cache_key, is_cacheable, response_ttl,
+};
+use blaze_service::server::routing::{RouteResolver, get_routing_table};
+use blaze_service::server::schema::{
+    AnomalyAction, ApiKeyOwner, BillingStatus, CapabilityLimits, InvalidationRequest, KeyRehash,
+    MaintenanceWindow, QuotaExceeded, User,
+};
+use blaze_service::server::secrets::load_secrets;
 use blaze_service::server::service::get_data_path;
 use blaze_service::server::storage::DataStore;
-use blaze_service::{error, info};
+use blaze_service::server::streams::{
+    StreamLimiter, StreamPermit, is_event_stream_request, is_upgrade_request,
+    upstream_upgrade_headers,
+};
+use blaze_service::server::tasks::{get_task_registry, shutdown_signal};
+use blaze_service::server::tls::{TlsAcceptor, TlsListener, TlsSettings, https_redirect_url};
+use blaze_service::server::users::UserStore;
+use blaze_service::{error, info, warn};
+use futures_util::StreamExt;
+use http_body::{Frame, SizeHint};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::TokioIo;
 use lru::LruCache;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
 use std::time::Instant;
 use tokio::sync::RwLock;
+use tower_http::set_header::SetResponseHeaderLayer;
 
 #[derive(Clone)]
 struct AppState {
     // LRU Cache: api_key_hash -> User (auto-eviction when full)
     user_cache: Arc<RwLock<LruCache<String, CachedUser>>>,
-    user_store: DataStore<String, User>, // In-memory user store (loaded from disk)
-    client: reqwest::Client,
+    user_store: UserStore, // In-memory user store (loaded from disk), looked up by email
+    key_index: DataStore<String, ApiKeyOwner>, // Owners of opaque keys, written by the service
+    key_usage: DataStore<String, KeyUsageProfile>, // api_key_hash (or per-user instance token key) -> usage profile (owned by the proxy)
+    key_rehashes: DataStore<String, KeyRehash>, // Old key hash -> new one for the service to store (owned by the proxy)
+    maintenance: DataStore<String, MaintenanceWindow>, // Open windows, written by the service
+    routes: RouteResolver, // instance_id -> where its container is, written by the provisioner
+    activity: ActivityTracker, // instance_id -> last activity and open streams
+    usage: UsageMeter,     // Hourly usage not flushed to the ledger yet
+    metrics: MetricsCollector, // Hourly request metrics not flushed yet
+    quotas: QuotaTracker,  // instance_id -> database and vector counts
+    reachability: ReachabilityTracker, // Forwarding successes and failures not flushed yet
+    streams: StreamLimiter, // email -> open WebSockets and event streams
+    in_flight: ConcurrencyLimiter, // instance_id -> requests being forwarded
+    breaker: CircuitBreaker, // instance_id -> connection failures in a row
+    balancer: LoadBalancer, // instance_id -> how its replicas are doing
+    access_log: AccessLog, // Entries not appended to access.log yet
+    audit: AuditLog,       // Authentication entries not appended to the audit log yet
+    cache: ResponseCache,  // Responses to reads, when BLAZE_PROXY_CACHE is on
+    instance_tokens: Option<Arc<Keyring>>, // None disables instance tokens
+    inspect_limit: usize,  // Bigger request bodies are streamed instead of read whole
+    scheme: &'static str,  // What clients reach the proxy over, passed on as X-Forwarded-Proto
+    clients: ClientPool,   // instance_id -> its own connection pools
     start_time: Instant,
 }
 
@@ -34,9 +129,33 @@ struct CachedUser {
     email: String,
     username: String,
     instance_id: String,
+    clone_instance_ids: Vec<String>,
     // TODO: Quota and rate limit enforcement remaining
     #[allow(unused)]
     is_verified: bool,
+    anomaly_action: AnomalyAction,
+    embedding_api_access: bool,
+    limits: CapabilityLimits,
+}
+
+impl CachedUser {
+    fn from_user(user: &User) -> Self {
+        CachedUser {
+            email: user.email.clone(),
+            username: user.username.clone(),
+            instance_id: user.instance_id.clone(),
+            clone_instance_ids: user.clone_instance_ids.clone(),
+            is_verified: user.is_verified,
+            anomaly_action: user.plans.features.anomaly_action,
+            embedding_api_access: user.plans.features.embedding_api_access,
+            limits: plan_limits(&user.plans),
+        }
+    }
+
+    fn owns_instance(&self, instance_id: &str) -> bool {
+        self.instance_id == instance_id
+            || self.clone_instance_ids.iter().any(|id| id == instance_id)
+    }
 }
 
 #[tokio::main]
@@ -45,22 +164,76 @@ async fn main() -> Result<()> {
 
     dotenv::dotenv().ok();
 
-    let user_store = DataStore::<String, User>::new(get_data_path().join("users.json"))?;
+    // Every secret is read once here, before the stores and handlers need them
+    let secrets = load_secrets().await?;
+
+    // Read-only here, the service moves users to their email index
+    let user_store = UserStore::new(DataStore::<String, User>::with_schema(
+        get_data_path().join("users.json"),
+        User::record_schema(),
+    )?);
+    let key_index = DataStore::<String, ApiKeyOwner>::new(get_data_path().join(KEY_INDEX_FILE))?;
+    let key_usage =
+        DataStore::<String, KeyUsageProfile>::new(get_data_path().join("key_usage.json"))?;
+
+    let tls = TlsSettings::from_env()?;
+
+    let instance_tokens = match get_instance_token_keyring(secrets) {
+        Ok(keyring) => Some(Arc::new(keyring)),
+        Err(e) => {
+            warn!("Instance tokens disabled: {}", e);
+            None
+        }
+    };
 
     // LRU Cache with automatic eviction + background reload strategy
     // - Max 1024 entries (oldest evicted when full)
-    // - Background task reloads user_store every 60s
-    // - Cache invalidation happens naturally on next access after reload
+    // - The service invalidates a user right after changing it, see `serve_control`
+    // - Background task reloads user_store when its generation moves, in case an invalidation
+    //   was missed
     let state = AppState {
         user_store,
+        key_index,
+        key_usage,
+        key_rehashes: get_key_rehash_store(),
+        activity: ActivityTracker::new(),
+        usage: UsageMeter::new(),
+        metrics: MetricsCollector::new(),
+        maintenance: get_maintenance_store(),
+        routes: RouteResolver::new(get_routing_table()),
+        quotas: QuotaTracker::new(),
+        reachability: ReachabilityTracker::new(),
+        streams: StreamLimiter::new(),
+        in_flight: ConcurrencyLimiter::new(ConcurrencySettings::from_env()),
+        breaker: CircuitBreaker::new(BreakerSettings::from_env()),
+        balancer: LoadBalancer::new(BalancerSettings::from_env()),
+        access_log: AccessLog::new(AccessLogSettings::from_env()),
+        audit: AuditLog::new(AuditSettings::from_env()),
+        cache: ResponseCache::new(CacheSettings::from_env()),
+        instance_tokens,
+        inspect_limit: inspect_limit(),
+        scheme: if tls.is_some() { "https" } else { "http" },
         user_cache: Arc::new(RwLock::new(LruCache::new(NonZeroUsize::new(1024).unwrap()))),
-        client: reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(30))
-            .build()?,
+        clients: ClientPool::new(PoolSettings::from_env()),
         start_time: Instant::now(),
     };
 
+    // Container states from Docker events instead of an inspect per request
+    get_task_registry().spawn("container-events", watch_container_events);
     update_cache_task(state.clone()).await;
+    reload_maintenance_task(state.clone()).await;
+    refresh_routes_task(state.clone()).await;
+    save_key_usage_task(state.clone()).await;
+    flush_usage_task(state.clone()).await;
+    flush_metrics_task(state.clone()).await;
+    flush_reachability_task(state.clone()).await;
+    flush_access_log_task(state.clone()).await;
+    flush_audit_log_task(state.clone()).await;
+    hibernate_idle_task(state.clone()).await;
+
+    if let Some(addr) = control_addr() {
+        serve_control(addr, state.clone()).await?;
+    }
 
     let app = create_router(state);
 
@@ -72,20 +245,176 @@ async fn main() -> Result<()> {
     let listener = tokio::net::TcpListener::bind(&addr).await?;
     let server_time = chrono::Utc::now();
 
-    info!("Proxy server listening on {}", addr);
+    info!(
+        "Proxy server listening on {} ({})",
+        addr,
+        if tls.is_some() { "HTTPS" } else { "HTTP" }
+    );
     info!("Server started at {}", server_time.to_rfc3339());
     info!("Ready to accept connections");
 
-    axum::serve(listener, app).await?;
+    match tls {
+        Some(tls) => {
+            let listener = TlsListener::new(listener, TlsAcceptor::from_settings(&tls)?)?;
+            if let Some(http_port) = tls.http_port {
+                serve_https_redirect(http_port, port.parse().unwrap_or(443)).await?;
+            }
+            let app = match tls
+                .hsts_header()
+                .and_then(|v| HeaderValue::from_str(&v).ok())
+            {
+                Some(hsts) => app.layer(SetResponseHeaderLayer::if_not_present(
+                    header::STRICT_TRANSPORT_SECURITY,
+                    hsts,
+                )),
+                None => app,
+            };
+
+            // The no-op tap is what gives a custom listener the client address as connect info
+            axum::serve(
+                listener.tap_io(|_| {}),
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal())
+            .await?;
+        }
+        None => {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown_signal())
+            .await?;
+        }
+    }
+
+    // Stop background loops and flush key usage before exiting
+    get_task_registry()
+        .shutdown(std::time::Duration::from_secs(10))
+        .await;
+
+    Ok(())
+}
+
+/// Redirects plain HTTP on `http_port` to HTTPS, in the background until shutdown
+async fn serve_https_redirect(http_port: u16, https_port: u16) -> Result<()> {
+    let redirect = move |headers: HeaderMap, uri: Uri| async move {
+        let Some(host) = headers.get(header::HOST).and_then(|v| v.to_str().ok()) else {
+            return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+        };
+        let path_and_query = uri.path_and_query().map_or("/", |p| p.as_str());
+        Redirect::permanent(&https_redirect_url(host, path_and_query, https_port)).into_response()
+    };
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", http_port)).await?;
+    info!("Redirecting HTTP on port {} to HTTPS", http_port);
+    get_task_registry().spawn("https-redirect", |token| async move {
+        let app = Router::new().fallback(redirect);
+        if let Err(e) = axum::serve(listener, app)
+            .with_graceful_shutdown(token.cancelled_owned())
+            .await
+        {
+            error!("HTTPS redirect server failed: {}", e);
+        }
+    });
+    Ok(())
+}
 
+/// Serves the control endpoints the service calls on `addr`, in the background until shutdown
+async fn serve_control(addr: SocketAddr, state: AppState) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Control listener on {}", addr);
+    get_task_registry().spawn("control-listener", |token| async move {
+        let app = Router::new()
+            .route(INVALIDATE_PATH, post(invalidate_handler))
+            .with_state(state);
+        if let Err(e) = axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(token.cancelled_owned())
+        .await
+        {
+            error!("Control listener failed: {}", e);
+        }
+    });
     Ok(())
 }
 
+/// Reloads the users and drops the cached ones the service changed, all of them without an email
+async fn invalidate_handler(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(request): Json<InvalidationRequest>,
+) -> Response {
+    if !is_authorized(&headers, peer.ip()) {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    if let Err(e) = state.user_store.reload_async().await {
+        error!("Failed to reload user store: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to reload users").into_response();
+    }
+    // A user who just verified may already hold an opaque key
+    if let Err(e) = state.key_index.reload_if_changed_async().await {
+        error!("Failed to reload API key index: {}", e);
+    }
+
+    let mut cache = state.user_cache.write().await;
+    let dropped = match &request.email {
+        Some(email) => {
+            let keys: Vec<String> = cache
+                .iter()
+                .filter(|(_, user)| &user.email == email)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &keys {
+                cache.pop(key);
+            }
+            keys.len()
+        }
+        None => {
+            let dropped = cache.len();
+            cache.clear();
+            dropped
+        }
+    };
+    drop(cache);
+
+    info!(
+        "Invalidated {} cached key(s) for {}",
+        dropped,
+        request.email.as_deref().unwrap_or("every user")
+    );
+    Json(serde_json::json!({ "dropped": dropped })).into_response()
+}
+
 fn create_router(state: AppState) -> Router {
-    Router::new()
-        .route("/health", get(health_check))
+    let router = Router::new()
+        .route(
+            "/v1/blazedb/capabilities/{instance_id}",
+            get(capabilities_handler),
+        )
         .route("/v1/blazedb/{*path}", any(proxy_handler))
+        // Health checks stay out of the access log
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            access_log_layer,
+        ))
+        .route("/health", get(health_check))
         .with_state(state)
+        .layer(request_decompression_layer());
+    let router = match proxy_compression_layer() {
+        Some(compression) => router.layer(compression),
+        None => router,
+    };
+
+    // Preflights are answered before authentication, there's no key on them
+    match proxy_cors_layer() {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
 }
 
 async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
@@ -100,139 +429,1325 @@ async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
     }))
 }
 
+/// Tags the request with an ID and our forwarding hop, and writes its access log entry once the
+/// response has been sent
+async fn access_log_layer(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let context = RequestContext::new(new_request_id());
+    let request_id = HeaderValue::from_str(&context.request_id).ok();
+    let path = request.uri().path().to_string();
+    let entry = AccessLogEntry {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        request_id: context.request_id.clone(),
+        client_ip: client_ip.to_string(),
+        user: None,
+        instance_id: path
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .filter(|id| !id.is_empty())
+            .map(str::to_string),
+        method: request.method().to_string(),
+        path,
+        status: 0,
+        latency_ms: 0,
+        request_bytes: 0,
+        response_bytes: 0,
+    };
+
+    // Replaces one the client sent, the instance gets the same ID the client sees
+    if let Some(request_id) = &request_id {
+        request
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, request_id.clone());
+    }
+    request.extensions_mut().insert(context.clone());
+
+    // The instance sees the whole chain of hops, ours last
+    if let Some(ConnectInfo(peer)) = request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        let peer = peer.ip();
+        let trusted = get_trusted_proxy_config().trusts_peer(&peer);
+        append_forwarding_headers(request.headers_mut(), peer, trusted, state.scheme);
+    }
+    let request_bytes = Arc::new(AtomicU64::new(0));
+    let request = request.map(|inner| {
+        Body::new(CountedBody {
+            inner,
+            counted: request_bytes.clone(),
+            on_end: None,
+        })
+    });
+
+    let mut response = next.run(request).await;
+    if let Some(request_id) = request_id {
+        response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    }
+
+    let pending = PendingAccess {
+        log: state.access_log.clone(),
+        metrics: state.metrics.clone(),
+        context,
+        started,
+        request_bytes,
+        entry: AccessLogEntry {
+            status: response.status().as_u16(),
+            ..entry
+        },
+    };
+    response.map(|inner| {
+        Body::new(CountedBody {
+            inner,
+            counted: Arc::new(AtomicU64::new(0)),
+            on_end: Some(pending),
+        })
+    })
+}
+
+/// An access log entry (and the request's metrics) waiting for its response to be sent
+struct PendingAccess {
+    log: AccessLog,
+    metrics: MetricsCollector,
+    context: RequestContext,
+    started: Instant,
+    request_bytes: Arc<AtomicU64>,
+    entry: AccessLogEntry,
+}
+
+impl PendingAccess {
+    fn finish(self, response_bytes: u64) {
+        let entry = AccessLogEntry {
+            user: self.context.user(),
+            latency_ms: self.started.elapsed().as_millis() as u64,
+            request_bytes: self.request_bytes.load(Ordering::Relaxed),
+            response_bytes,
+            ..self.entry
+        };
+
+        // Only requests for an instance the user was verified to own count towards its metrics
+        if let (Some(user), Some(instance_id)) = (&entry.user, self.context.instance_id()) {
+            self.metrics.record(
+                user,
+                &instance_id,
+                entry.status,
+                entry.latency_ms,
+                (entry.request_bytes, entry.response_bytes),
+            );
+        }
+        self.log.record(entry);
+    }
+}
+
+/// A body passing the access log layer, counting the bytes that went through
+struct CountedBody {
+    inner: Body,
+    counted: Arc<AtomicU64>,
+    on_end: Option<PendingAccess>, // Set on responses, written out when the body is dropped
+}
+
+impl http_body::Body for CountedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            this.counted.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountedBody {
+    fn drop(&mut self) {
+        if let Some(pending) = self.on_end.take() {
+            pending.finish(self.counted.load(Ordering::Relaxed));
+        }
+    }
+}
+
 async fn proxy_handler(
     State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Extension(context): Extension<RequestContext>,
     headers: HeaderMap,
     method: Method,
     uri: Uri,
-    body: Bytes,
+    request: Request,
 ) -> Result<Response, ProxyError> {
     let path = uri.path();
 
+    let attempt =
+        |kind| AuditEvent::attempt(kind, client_ip, method.as_str(), path, &context.request_id);
+
     // Block restricted endpoints
-    if path.contains("/v1/blazedb/embed") || path.contains("/v1/blazedb/query") {
-        error!("Blocked request to restricted endpoint: {}", path);
-        return Err(ProxyError::BlockedEndpoint);
+    if is_blocked_endpoint(path) {
+        error!(
+            "Blocked request to restricted endpoint: {} from {}",
+            path, client_ip
+        );
+        let mut blocked = attempt(AuditKind::BlockedEndpoint);
+        blocked.user = extract_api_key(&headers).ok().and_then(|api_key| {
+            find_api_key_owner(&state.key_index, &api_key)
+                .ok()
+                .flatten()
+        });
+        let error = ProxyError::BlockedEndpoint;
+        state.audit.record(blocked.finish(Some(error.code())));
+        return Err(error);
+    }
+
+    // Extract instance_id from URL
+    let instance_id = path
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .ok_or(ProxyError::InvalidPath)?
+        .to_string();
+
+    info!(
+        "{} {} (Instance ID: {}) from {}",
+        method.as_str(),
+        path,
+        &instance_id.chars().take(8).collect::<String>(),
+        client_ip
+    );
+
+    // Signed instance tokens take the fast path without a user lookup
+    if let Some(token) = extract_instance_token(&headers) {
+        let audit = attempt(AuditKind::InstanceToken);
+        let claims = verify_token_request(&state, &token, &instance_id, &method, audit)?;
+        return proxy_with_instance_token(
+            &state,
+            claims,
+            &instance_id,
+            client_ip,
+            method,
+            headers,
+            request,
+        )
+        .await;
+    }
+
+    let audit = attempt(AuditKind::ApiKey);
+    let (api_key, api_key_hash, user) =
+        authenticate_key(&state, &context, &headers, &instance_id, audit).await?;
+    context.set_instance(&instance_id);
+    check_maintenance(&state, &instance_id)?;
+
+    if is_embedding_endpoint(path) && !user.embedding_api_access {
+        warn!("  ✗ Embedding API not in the plan of {}", user.email);
+        return Err(ProxyError::FeatureNotInPlan);
+    }
+
+    // Compare this request against the key's usage baseline
+    let credential = Credential::api_key(&api_key, &api_key_hash);
+    check_key_usage(&state, &credential, &user, client_ip, &headers).await?;
+
+    // Only read once the request is authorized, nobody else gets to make the proxy buffer
+    let body =
+        read_request_body(request, state.inspect_limit, user.limits.max_request_bytes).await?;
+
+    // Streamed writes are counted on the way through instead, see `forward_to_instance`
+    let quota_write = match &body {
+        RequestBody::Buffered(bytes) if is_write_method(&method) => classify_write(path, bytes),
+        _ => None,
+    };
+    if let Some(write) = &quota_write {
+        enforce_quota(&state, &instance_id, &user.limits, write).await?;
+    }
+
+    let target = Target {
+        email: &user.email,
+        instance_id: &instance_id,
+        limits: &user.limits,
+    };
+    let response = forward_to_instance(&state, &target, &uri, method, headers, body).await?;
+
+    // Counted until the next stats refresh picks it up
+    if let Some(write) = &quota_write
+        && response.status().is_success()
+    {
+        state.quotas.record(&instance_id, write);
+    }
+    Ok(response)
+}
+
+/// A request body, read whole when it's small enough to look into
+enum RequestBody {
+    Buffered(Bytes),
+    Streamed(Body),
+    Upgrade(OnUpgrade), // The connection itself, once the instance switched protocols
+}
+
+/// Reads the body whole if it's at most `limit` bytes, otherwise hands it on as a stream
+/// Bodies saying they're over the plan's `max_bytes` are turned away before any of it is read
+async fn read_request_body(
+    mut request: Request,
+    limit: usize,
+    max_bytes: u64,
+) -> Result<RequestBody, ProxyError> {
+    if is_upgrade_request(request.headers()) {
+        return Ok(RequestBody::Upgrade(hyper::upgrade::on(&mut request)));
+    }
+
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if declared.is_some_and(|len| len as u64 > max_bytes) {
+        return Err(ProxyError::RequestTooLarge(max_bytes));
+    }
+    if declared.is_some_and(|len| len > limit) {
+        return Ok(RequestBody::Streamed(request.into_body()));
+    }
+
+    // Chunked uploads don't say how big they are, read until they turn out too big
+    let mut stream = request.into_body().into_data_stream();
+    let mut chunks = Vec::new();
+    let mut read = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| {
+            warn!("  ✗ Failed to read the request body: {}", e);
+            ProxyError::InvalidBody
+        })?;
+        read += chunk.len();
+        chunks.push(chunk);
+        if read as u64 > max_bytes {
+            return Err(ProxyError::RequestTooLarge(max_bytes));
+        }
+        if read > limit {
+            let rest = futures_util::stream::iter(chunks.into_iter().map(Ok)).chain(stream);
+            return Ok(RequestBody::Streamed(Body::from_stream(rest)));
+        }
+    }
+
+    Ok(RequestBody::Buffered(Bytes::from(chunks.concat())))
+}
+
+/// Rejects a write that would take the instance over the plan's database or vector limits
+async fn enforce_quota(
+    state: &AppState,
+    instance_id: &str,
+    limits: &CapabilityLimits,
+    write: &QuotaWrite,
+) -> Result<(), ProxyError> {
+    let databases = match state.quotas.fresh_counts(instance_id) {
+        Some(databases) => databases,
+        None => {
+            let stats = fetch_instance_stats(state, instance_id).await;
+            state.quotas.refresh(instance_id, stats)
+        }
+    };
+
+    check_quota(&databases, limits, write).map_err(|exceeded| {
+        warn!(
+            "  ✗ Quota exceeded on {}: {:?}",
+            &instance_id.chars().take(8).collect::<String>(),
+            exceeded
+        );
+        ProxyError::QuotaExceeded(exceeded)
+    })
+}
+
+/// Per-database vector counts from the backend's stats endpoint, None if it didn't answer
+async fn fetch_instance_stats(
+    state: &AppState,
+    instance_id: &str,
+) -> Option<std::collections::HashMap<String, u64>> {
+    let stats_url = format!(
+        "{}{}",
+        state.routes.base_url(instance_id),
+        BACKEND_STATS_PATH
+    );
+    let client = state
+        .clients
+        .client(instance_id, ClientKind::Request)
+        .ok()?;
+    match client
+        .get(&stats_url)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+    {
+        Ok(response) if response.status().is_success() => response
+            .json::<serde_json::Value>()
+            .await
+            .ok()
+            .and_then(|doc| parse_stats(&doc)),
+        Ok(response) => {
+            warn!(" ↳ Backend stats endpoint answered {}", response.status());
+            None
+        }
+        Err(e) => {
+            warn!(" ↳ Backend stats endpoint unreachable: {}", e);
+            None
+        }
+    }
+}
+
+/// Checks the API key and that its user owns the instance, returns (key, key hash, user)
+/// The outcome goes to the audit log, with what was learned about the key on the way
+async fn authenticate_key(
+    state: &AppState,
+    context: &RequestContext,
+    headers: &HeaderMap,
+    instance_id: &str,
+    mut audit: AuditEvent,
+) -> Result<(String, String, CachedUser), ProxyError> {
+    audit.instance_id = Some(instance_id.to_string());
+    let authenticated = async {
+        // Extract API key
+        let api_key = extract_api_key(headers)?;
+
+        // Email from the API key, or the index for opaque keys
+        let email = find_api_key_owner(&state.key_index, &api_key)
+            .map_err(|_| ProxyError::DatastoreError)?
+            .ok_or(ProxyError::InvalidApiKey)?;
+        audit.user = Some(email.clone());
+
+        info!(" ↳ User email: {}", email);
+
+        // Verify API key and get user data (with cache)
+        let api_key_hash = hash_api_key(&api_key);
+        audit.key = Some(key_fingerprint(&api_key_hash));
+        let user = verify_api_key(state, &api_key, &api_key_hash, &email).await?;
+
+        info!(" ↳ User: {} ({})", user.username, user.email);
+        context.set_user(&user.email);
+
+        // Verify instance_id matches user's instance_id
+        if !user.owns_instance(instance_id) {
+            error!(
+                "  ✗ Instance ID mismatch! User: {}, Requested: {}",
+                user.instance_id, instance_id
+            );
+            return Err(ProxyError::Forbidden);
+        }
+        Ok((api_key, api_key_hash, user))
+    }
+    .await;
+
+    let failure = authenticated.as_ref().err().map(ProxyError::code);
+    state.audit.record(audit.finish(failure));
+    authenticated
+}
+
+/// Checks the instance token is valid for the instance and the method, returns its claims
+/// The outcome goes to the audit log
+fn verify_token_request(
+    state: &AppState,
+    token: &str,
+    instance_id: &str,
+    method: &Method,
+    mut audit: AuditEvent,
+) -> Result<InstanceTokenClaims, ProxyError> {
+    audit.instance_id = Some(instance_id.to_string());
+    let verified = check_instance_token(state, token, instance_id, method);
+    audit.user = verified.as_ref().ok().map(|claims| claims.email.clone());
+    let failure = verified.as_ref().err().map(ProxyError::code);
+    state.audit.record(audit.finish(failure));
+    verified
+}
+
+fn check_instance_token(
+    state: &AppState,
+    token: &str,
+    instance_id: &str,
+    method: &Method,
+) -> Result<InstanceTokenClaims, ProxyError> {
+    if !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Err(ProxyError::TokenReadOnly);
+    }
+
+    let keyring = state
+        .instance_tokens
+        .as_deref()
+        .ok_or(ProxyError::InvalidInstanceToken)?;
+
+    let claims = verify_instance_token(token, keyring, chrono::Utc::now().timestamp())
+        .ok_or(ProxyError::InvalidInstanceToken)?;
+
+    if claims.instance_id != instance_id {
+        error!(
+            "  ✗ Instance ID mismatch! Token: {}, Requested: {}",
+            claims.instance_id, instance_id
+        );
+        return Err(ProxyError::Forbidden);
+    }
+    Ok(claims)
+}
+
+/// Handles a request authenticated with a signed instance token
+/// The signature alone proves access, the user store is only read for the plan (read-only requests)
+async fn proxy_with_instance_token(
+    state: &AppState,
+    claims: InstanceTokenClaims,
+    instance_id: &str,
+    client_ip: IpAddr,
+    method: Method,
+    headers: HeaderMap,
+    request: Request,
+) -> Result<Response, ProxyError> {
+    let uri = request.uri().clone();
+    let path = uri.path();
+
+    info!(" ↳ Instance token: {}", claims.email);
+    if let Some(context) = request.extensions().get::<RequestContext>() {
+        context.set_user(&claims.email);
+        context.set_instance(instance_id);
     }
+    check_maintenance(state, instance_id)?;
+
+    // Tokens don't carry the plan, size limits and gated endpoints need it
+    let user = state
+        .user_store
+        .get(&claims.email)
+        .map_err(|_| ProxyError::DatastoreError)?
+        .ok_or(ProxyError::InvalidInstanceToken)?; // The account is gone
+    let user = CachedUser::from_user(&user);
+    if is_embedding_endpoint(path) && !user.embedding_api_access {
+        return Err(ProxyError::FeatureNotInPlan);
+    }
+
+    // Held to the same usage baseline as keys, switching credentials doesn't get around it
+    let credential = Credential::instance_token(&user.email);
+    check_key_usage(state, &credential, &user, client_ip, &headers).await?;
+
+    let body =
+        read_request_body(request, state.inspect_limit, user.limits.max_request_bytes).await?;
+    let target = Target {
+        email: &user.email,
+        instance_id,
+        limits: &user.limits,
+    };
+    forward_to_instance(state, &target, &uri, method, headers, body).await
+}
+
+/// Whose instance a request goes to, and the plan limits it's held to
+#[derive(Clone, Copy)]
+struct Target<'a> {
+    email: &'a str,
+    instance_id: &'a str,
+    limits: &'a CapabilityLimits,
+}
+
+/// Forwards an authorized request to the user's BlazeDB container
+async fn forward_to_instance(
+    state: &AppState,
+    target: &Target<'_>,
+    uri: &Uri,
+    method: Method,
+    headers: HeaderMap,
+    body: RequestBody,
+) -> Result<Response, ProxyError> {
+    let Target {
+        email,
+        instance_id,
+        limits,
+    } = *target;
+    let path = uri.path();
+
+    // Strip instance_id from path and build target URL
+    // Example: /v1/blazedb/query/a1a70763... → /v1/blazedb/query
+    let stripped_path = path
+        .rsplit_once('/')
+        .map(|(head, _)| head)
+        .unwrap_or("/v1/blazedb");
+
+    // Instances with replicas spread their requests over them, see `balancer`
+    let base_url = state
+        .balancer
+        .pick(instance_id, &state.routes.targets(instance_id))
+        .unwrap_or_else(|| state.routes.base_url(instance_id));
+    let container_url = format!("{}{}", base_url, stripped_path);
+
+    info!(" ↳ Forwarding to: {}", container_url);
+
+    // Repeated reads are answered from the cache, see `response_cache`
+    let is_read = is_cacheable(&method, path) || matches!(method, Method::HEAD | Method::OPTIONS);
+    let mut cache_entry = None;
+    if state.cache.settings().enabled
+        && is_cacheable(&method, path)
+        && !is_event_stream_request(&headers)
+        && let RequestBody::Buffered(bytes) = &body
+    {
+        let control = RequestCacheControl::parse(&headers);
+        if !control.no_store {
+            let path_and_query = uri.path_and_query().map_or(path, |p| p.as_str());
+            let accept_encoding = headers
+                .get(header::ACCEPT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("");
+            let key = cache_key(email, &method, path_and_query, accept_encoding, bytes);
+            if !control.no_cache
+                && let Some(hit) = state.cache.get(&key, control.max_age)
+            {
+                info!("  ✓ Answered from the cache");
+                state
+                    .usage
+                    .record(email, instance_id, bytes.len() as u64, 0);
+                return Ok(cached_response(hit));
+            }
+            cache_entry = Some((key, state.cache.generation(instance_id)));
+        }
+    }
+
+    // Hibernated instances are started again by the first request that comes in
+    if is_hibernated(instance_id).unwrap_or(false) {
+        wake_hibernated(state, email, instance_id).await?;
+    }
+
+    // An instance that keeps refusing connections fails fast for a while
+    if let Err(retry_in) = state.breaker.check(instance_id) {
+        return Err(ProxyError::CircuitOpen(retry_in.as_secs().max(1)));
+    }
+
+    // Instance stays busy until the response body has been fully streamed back
+    let activity = state.activity.begin(instance_id);
+
+    // WebSockets and event streams stay open, they count against the user's limit instead
+    let slot = if matches!(body, RequestBody::Upgrade(_)) || is_event_stream_request(&headers) {
+        Slot::Stream(open_stream(state, email)?)
+    } else {
+        Slot::InFlight(wait_for_slot(state, instance_id).await?)
+    };
+    let kind = match slot {
+        Slot::Stream(_) => ClientKind::Stream,
+        Slot::InFlight(_) => ClientKind::Request,
+    };
+    let client = &state.clients.client(instance_id, kind).map_err(|e| {
+        error!("  ✗ Failed to create a client for {}: {}", instance_id, e);
+        ProxyError::InternalError
+    })?;
+
+    // Streamed bodies are metered as they go out, buffered ones right away
+    let is_write = is_write_method(&method);
+    let scanner = Arc::new(Mutex::new(WriteScanner::new()));
+    let (upstream_body, metered) = match body {
+        RequestBody::Upgrade(on_upgrade) => {
+            let upgraded =
+                tunnel_to_instance(client, &container_url, &headers, on_upgrade, activity, slot)
+                    .await;
+            report_replica(state, instance_id, &base_url, &upgraded);
+            let response = handle_forward_result(state, email, instance_id, upgraded).await?;
+            state.usage.record(email, instance_id, 0, 0);
+            return Ok(response);
+        }
+        RequestBody::Buffered(bytes) => {
+            let vectors = if is_write { count_vectors(&bytes) } else { 0 };
+            let metered = Some((bytes.len() as u64, vectors));
+            let upstream_body = (!bytes.is_empty()).then(|| reqwest::Body::from(bytes));
+            (upstream_body, metered)
+        }
+        RequestBody::Streamed(body) => {
+            let scanned = ScannedBody {
+                inner: Mutex::new(body),
+                scanner: scanner.clone(),
+                max_bytes: limits.max_request_bytes,
+            };
+            (Some(reqwest::Body::wrap(scanned)), None)
+        }
+    };
+
+    // Forward request, streamed bodies can't be sent twice so they never are
+    let retries = if matches!(
+        method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+    ) {
+        state.breaker.settings().retries
+    } else {
+        0
+    };
+    let forwarded = send_request(
+        client,
+        &container_url,
+        method,
+        headers,
+        upstream_body,
+        retries,
+    )
+    .await
+    .map_err(|e| {
+        // The upload was cut off on its way out for going over the plan's limit
+        let sent = scanner.lock().unwrap_or_else(|e| e.into_inner()).bytes();
+        match e {
+            ProxyError::InvalidBody if sent > limits.max_request_bytes => {
+                ProxyError::RequestTooLarge(limits.max_request_bytes)
+            }
+            e => e,
+        }
+    })
+    .and_then(|response| into_client_response(response, activity, slot, limits.max_response_bytes));
+    report_replica(state, instance_id, &base_url, &forwarded);
+    let response = handle_forward_result(state, email, instance_id, forwarded).await?;
+
+    info!("  ✓ Response: {}", response.status());
+
+    // The instance has read the upload by the time it answers
+    let (request_bytes, vectors) = metered.unwrap_or_else(|| {
+        let scanner = scanner.lock().unwrap_or_else(|e| e.into_inner());
+        let vectors = if is_write { scanner.vectors() } else { 0 };
+        if is_write
+            && response.status().is_success()
+            && let Some(write) = classify_scanned_write(&scanner)
+        {
+            state.quotas.record(instance_id, &write);
+        }
+        (scanner.bytes(), vectors)
+    });
+
+    // Only writes that went through count as vectors written
+    let vectors = if response.status().is_success() {
+        vectors
+    } else {
+        0
+    };
+    state
+        .usage
+        .record(email, instance_id, request_bytes, vectors);
+
+    // Whatever was cached for the instance may be stale after a write
+    if state.cache.settings().enabled && !is_read {
+        state.cache.invalidate(instance_id);
+    }
+
+    match cache_entry {
+        Some((key, generation)) if response.status() == StatusCode::OK => Ok(cache_when_sent(
+            &state.cache,
+            response,
+            key,
+            instance_id,
+            generation,
+        )),
+        _ => Ok(response),
+    }
+}
+
+/// A response from the cache, as the client gets it
+fn cached_response(hit: CachedResponse) -> Response {
+    let age = hit.age().as_secs();
+    let mut response = Response::new(Body::from(hit.body));
+    *response.status_mut() = hit.status;
+    *response.headers_mut() = hit.headers;
+    let headers = response.headers_mut();
+    headers.insert(CACHE_STATUS_HEADER, HeaderValue::from_static("HIT"));
+    headers.insert(header::AGE, HeaderValue::from(age));
+    response
+}
+
+/// Passes the response on, storing it in the cache once all of it went through
+fn cache_when_sent(
+    cache: &ResponseCache,
+    response: Response,
+    key: String,
+    instance_id: &str,
+    generation: u64,
+) -> Response {
+    let (mut parts, inner) = response.into_parts();
+    let ttl = response_ttl(&parts.headers, cache.settings().ttl);
+    let entry = ttl.map(|ttl| {
+        CachedResponse::new(
+            parts.status,
+            parts.headers.clone(),
+            Bytes::new(),
+            instance_id,
+            generation,
+            ttl,
+        )
+    });
+    parts
+        .headers
+        .insert(CACHE_STATUS_HEADER, HeaderValue::from_static("MISS"));
+
+    let Some(entry) = entry else {
+        return Response::from_parts(parts, inner);
+    };
+    let body = CachingBody {
+        inner,
+        cache: cache.clone(),
+        key,
+        entry: Some(entry),
+        buffer: Vec::new(),
+    };
+    Response::from_parts(parts, Body::new(body))
+}
+
+/// A response body on its way to the client, copied into the cache when it's complete
+struct CachingBody {
+    inner: Body,
+    cache: ResponseCache,
+    key: String,
+    entry: Option<CachedResponse>, // None once it turned out too big to cache
+    buffer: Vec<u8>,
+}
+
+impl http_body::Body for CachingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) if this.entry.is_some() => match frame.data_ref() {
+                Some(data)
+                    if this.buffer.len() + data.len() <= this.cache.settings().max_entry_bytes =>
+                {
+                    this.buffer.extend_from_slice(data);
+                }
+                // Too big, or trailers the cache wouldn't give back
+                _ => this.entry = None,
+            },
+            Some(Err(_)) => this.entry = None,
+            None => {
+                if let Some(mut entry) = this.entry.take() {
+                    entry.body = Bytes::from(std::mem::take(&mut this.buffer));
+                    this.cache.insert(std::mem::take(&mut this.key), entry);
+                }
+            }
+            _ => {}
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Tells the balancer how the replica that got the request did, failing ones get ejected
+fn report_replica(
+    state: &AppState,
+    instance_id: &str,
+    base_url: &str,
+    forwarded: &Result<Response, ProxyError>,
+) {
+    let failed = match forwarded {
+        Ok(response) => matches!(
+            response.status(),
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(ProxyError::InstanceUnavailable(_)) => true,
+        Err(_) => return, // Nothing to do with the replica
+    };
+    if !failed {
+        state.balancer.record_success(instance_id, base_url);
+    } else if state.balancer.record_failure(instance_id, base_url) {
+        warn!(
+            "  ✗ Ejected replica {} of {} after repeated failures",
+            base_url, instance_id
+        );
+    }
+}
+
+/// Records whether the instance answered, and explains a failure to connect to it
+async fn handle_forward_result(
+    state: &AppState,
+    email: &str,
+    instance_id: &str,
+    forwarded: Result<Response, ProxyError>,
+) -> Result<Response, ProxyError> {
+    match forwarded {
+        Ok(response) => {
+            state.reachability.record_success(instance_id);
+            state.breaker.record_success(instance_id);
+            Ok(response)
+        }
+        Err(ProxyError::InstanceUnavailable(_)) => {
+            // Instances of suspended users are stopped on purpose, retrying won't help
+            let suspended = state
+                .user_store
+                .get(email)
+                .ok()
+                .flatten()
+                .is_some_and(|user| user.billing_status == BillingStatus::Suspended);
+            if suspended {
+                return Err(ProxyError::PaymentRequired);
+            }
+
+            // Only ask Docker on failure, tells the client whether retrying makes sense
+            let instance_state = get_instance_state(instance_id).await.ok();
+            state.reachability.record_failure(
+                instance_id,
+                &format!(
+                    "Couldn't connect to the instance (container {})",
+                    instance_state.unwrap_or("state unknown")
+                ),
+            );
+            if state.breaker.record_failure(instance_id) {
+                warn!(
+                    "  ✗ Circuit opened for {} after repeated connection failures",
+                    instance_id
+                );
+                if state.breaker.settings().restart_on_open {
+                    restart_failing_instance(state, email, instance_id);
+                }
+            }
+            Err(ProxyError::InstanceUnavailable(instance_state))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// What a forwarded request holds on to until its response has been streamed back
+/// Never read, dropping it is what frees the slot
+#[derive(Debug)]
+#[allow(unused)]
+enum Slot {
+    Stream(StreamPermit),
+    InFlight(InFlightPermit),
+}
+
+/// Waits for one of the instance's in-flight slots, see `concurrency`
+async fn wait_for_slot(state: &AppState, instance_id: &str) -> Result<InFlightPermit, ProxyError> {
+    state
+        .in_flight
+        .acquire(instance_id)
+        .await
+        .map_err(|rejected| {
+            warn!(
+                "  ✗ Too many requests in flight to {} ({:?})",
+                &instance_id.chars().take(8).collect::<String>(),
+                rejected
+            );
+            ProxyError::InstanceBusy(rejected)
+        })
+}
+
+/// Restarts the container of an instance whose breaker just opened, in the background
+fn restart_failing_instance(state: &AppState, email: &str, instance_id: &str) {
+    let Ok(Some(owner)) = state.user_store.get(email) else {
+        return;
+    };
+    let instance_id = instance_id.to_string();
+
+    get_task_registry().spawn("breaker-restart", |_| async move {
+        match restart_blazedb_container(&instance_id, &ContainerSpec::for_user(&owner)).await {
+            Ok(()) => info!("Restarted {} after its circuit opened", instance_id),
+            Err(e) => error!(
+                "Failed to restart {} after its circuit opened: {}",
+                instance_id, e
+            ),
+        }
+    });
+}
+
+/// Takes one of the user's slots for a WebSocket or event stream
+fn open_stream(state: &AppState, email: &str) -> Result<StreamPermit, ProxyError> {
+    let limit = state
+        .user_store
+        .get(email)
+        .map_err(|_| ProxyError::DatastoreError)?
+        .ok_or(ProxyError::DatastoreNotFound)?
+        .plans
+        .features
+        .max_streams;
+
+    state.streams.try_open(email, limit).ok_or_else(|| {
+        warn!("  ✗ {} already has {} streams open", email, limit);
+        ProxyError::TooManyStreams(limit)
+    })
+}
+
+/// Starts a hibernated instance and waits for it, a client that outwaits it gets a 503
+async fn wake_hibernated(
+    state: &AppState,
+    email: &str,
+    instance_id: &str,
+) -> Result<(), ProxyError> {
+    // Counts as activity, or the instance would look idle and hibernate right away
+    let _waking = state.activity.begin(instance_id);
+
+    let owner = state
+        .user_store
+        .get(email)
+        .map_err(|_| ProxyError::DatastoreError)?
+        .ok_or(ProxyError::DatastoreNotFound)?;
+
+    match wake_instance(instance_id, &owner, cold_start_wait()).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ProxyError::InstanceWaking),
+        Err(e) => {
+            error!("  ✗ Failed to wake instance {}: {}", instance_id, e);
+            Err(ProxyError::InstanceUnavailable(None))
+        }
+    }
+}
 
-    // Extract instance_id from URL
-    let instance_id = path
-        .trim_end_matches('/')
-        .split('/')
-        .last()
-        .ok_or(ProxyError::InvalidPath)?
-        .to_string();
+/// Capability document for an instance: plan gates merged with the backend's version endpoint
+/// Accepts an API key or an instance token, like any other read
+async fn capabilities_handler(
+    State(state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    Extension(context): Extension<RequestContext>,
+    Path(instance_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ProxyError> {
+    let path = format!("/v1/blazedb/capabilities/{}", instance_id);
+    let attempt = |kind| AuditEvent::attempt(kind, client_ip, "GET", &path, &context.request_id);
+    let email = if let Some(token) = extract_instance_token(&headers) {
+        let audit = attempt(AuditKind::InstanceToken);
+        verify_token_request(&state, &token, &instance_id, &Method::GET, audit)?.email
+    } else {
+        let audit = attempt(AuditKind::ApiKey);
+        let (_, _, user) =
+            authenticate_key(&state, &context, &headers, &instance_id, audit).await?;
+        user.email
+    };
+    context.set_user(&email);
+    context.set_instance(&instance_id);
 
     info!(
-        "{} {} (Instance ID: {})",
-        method.as_str(),
-        path,
-        &instance_id.chars().take(8).collect::<String>()
+        "GET capabilities (Instance ID: {}) from {}",
+        &instance_id.chars().take(8).collect::<String>(),
+        client_ip
     );
 
-    // Extract API key
-    let api_key = extract_api_key(&headers)?;
+    // The cached user doesn't carry the plan, the store has the current one
+    let user = state
+        .user_store
+        .get(&email)
+        .map_err(|_| ProxyError::DatastoreError)?
+        .ok_or(ProxyError::InvalidApiKey)?;
 
-    // Extract email from API key
-    let email = extract_email_from_api_key(&api_key).ok_or(ProxyError::InvalidApiKey)?;
+    // A backend that doesn't answer (or has no version endpoint) still gets a document
+    let backend_url = format!(
+        "{}{}",
+        state.routes.base_url(&instance_id),
+        BACKEND_VERSION_PATH
+    );
+    let client = state
+        .clients
+        .client(&instance_id, ClientKind::Request)
+        .map_err(|_| ProxyError::InternalError)?;
+    let backend = match client
+        .get(&backend_url)
+        .timeout(std::time::Duration::from_secs(5))
+        .send()
+        .await
+    {
+        Ok(response) if response.status().is_success() => {
+            response.json::<serde_json::Value>().await.ok()
+        }
+        Ok(response) => {
+            warn!(" ↳ Backend version endpoint answered {}", response.status());
+            None
+        }
+        Err(e) => {
+            warn!(" ↳ Backend version endpoint unreachable: {}", e);
+            None
+        }
+    };
 
-    info!(" ↳ User email: {}", email);
+    Ok(Json(build_capabilities(&instance_id, &user.plans, backend)).into_response())
+}
 
-    // Verify API key and get user data (with cache)
-    let api_key_hash = hash_api_key(&api_key).await;
-    let user = verify_api_key(&state, &api_key_hash, &email).await?;
+/// Passes the WebSocket handshake on to the instance, then pipes the connection both ways
+/// The tunnel runs in the background until either side closes it (or the proxy shuts down)
+async fn tunnel_to_instance(
+    client: &reqwest::Client,
+    target_url: &str,
+    headers: &HeaderMap,
+    on_upgrade: OnUpgrade,
+    activity: ActivityGuard,
+    slot: Slot,
+) -> Result<Response, ProxyError> {
+    let response = client
+        .get(target_url)
+        .headers(upstream_upgrade_headers(headers))
+        .send()
+        .await
+        .map_err(|e| {
+            error!("  ✗ Failed to connect to BlazeDB: {}", e);
+            ProxyError::InstanceUnavailable(None)
+        })?;
 
-    info!(" ↳ User: {} ({})", user.username, user.email);
+    // The instance turned the handshake down, the client gets its answer
+    // Tunnels aren't held to the plan's body sizes, neither is that answer
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return into_client_response(response, activity, slot, u64::MAX);
+    }
 
-    // Verify instance_id matches user's instance_id
-    if user.instance_id != instance_id {
-        error!(
-            "  ✗ Instance ID mismatch! User: {}, Requested: {}",
-            user.instance_id, instance_id
-        );
-        return Err(ProxyError::Forbidden);
+    let mut builder = Response::builder().status(StatusCode::SWITCHING_PROTOCOLS);
+    for (key, value) in response.headers().iter() {
+        builder = builder.header(key, value);
     }
+    let switched = builder
+        .body(Body::empty())
+        .map_err(|_| ProxyError::InternalError)?;
 
-    // Strip instance_id from path and build target URL
-    // Example: /v1/blazedb/query/a1a70763... → /v1/blazedb/query
-    let stripped_path = path.rsplitn(2, '/').nth(1).unwrap_or("/v1/blazedb");
-
-    // Build target URL based on environment
-    // INSIDE DOCKER: Use container DNS name (e.g., http://blazedb-a1a70763:8080) [prod]
-    // OUTSIDE DOCKER: Use localhost with port mapping (e.g., http://localhost:PORT) [dev]
-    let container_url = if std::env::var("PROXY_MODE").unwrap_or_default() == "external" {
-        format!(
-            "http://localhost:{}{}",
-            calculate_container_port(&instance_id),
-            stripped_path
-        )
-    } else {
-        // Running INSIDE Docker - use internal DNS
-        format!("http://blazedb-{}:8080{}", instance_id, stripped_path)
-    };
+    let mut upstream = response.upgrade().await.map_err(|e| {
+        error!("  ✗ BlazeDB didn't switch protocols: {}", e);
+        ProxyError::InstanceError
+    })?;
 
-    info!(" ↳ Forwarding to: {}", container_url);
+    get_task_registry().spawn("websocket-tunnel", |token| async move {
+        // Both stay alive exactly as long as the tunnel
+        let _held = (activity, slot);
 
-    // Forward request
-    let response = forward_request(&state.client, &container_url, method, headers, body).await?;
+        // Resolves once the client got the 101 below
+        let mut downstream = match on_upgrade.await {
+            Ok(upgraded) => TokioIo::new(upgraded),
+            Err(e) => {
+                warn!("  ✗ Client didn't switch protocols: {}", e);
+                return;
+            }
+        };
 
-    info!("  ✓ Response: {}", response.status());
+        tokio::select! {
+            _ = token.cancelled() => {}
+            result = tokio::io::copy_bidirectional(&mut downstream, &mut upstream) => {
+                if let Err(e) = result {
+                    info!(" ↳ WebSocket tunnel closed: {}", e);
+                }
+            }
+        }
+    });
 
-    Ok(response)
+    Ok(switched)
 }
 
 #[inline]
-async fn forward_request(
+async fn send_request(
     client: &reqwest::Client,
     target_url: &str,
     method: Method,
     mut headers: HeaderMap,
-    body: Bytes,
-) -> Result<Response, ProxyError> {
+    body: Option<reqwest::Body>,
+    retries: u32,
+) -> Result<reqwest::Response, ProxyError> {
     headers.remove("Authorization");
     headers.remove("authorization");
+    // Framing is up to the upstream connection, a chunked upload goes out chunked again
+    strip_hop_by_hop(&mut headers);
 
-    let mut req_builder = match method {
-        Method::GET => client.get(target_url),
-        Method::POST => client.post(target_url),
-        Method::PUT => client.put(target_url),
-        Method::DELETE => client.delete(target_url),
-        _ => return Err(ProxyError::UnsupportedMethod),
-    };
+    // CONNECT makes no sense for an instance, TRACE would echo the client's headers back
+    if matches!(method, Method::CONNECT | Method::TRACE) {
+        return Err(ProxyError::UnsupportedMethod);
+    }
+    let mut req_builder = client.request(method, target_url);
 
     // Add remaining headers (Content-Type, Accept, etc.)
     req_builder = req_builder.headers(headers);
 
-    if !body.is_empty() {
+    if let Some(body) = body {
         req_builder = req_builder.body(body);
     }
 
-    // Send request
-    let response = req_builder.send().await.map_err(|e| {
-        error!("  ✗ Failed to connect to BlazeDB: {}", e);
-        ProxyError::InstanceUnavailable
-    })?;
+    // Send request, retrying while the instance can't be connected to
+    let mut attempt = 0;
+    let response = loop {
+        let retry = if attempt < retries {
+            req_builder.try_clone()
+        } else {
+            None
+        };
+        match (req_builder.send().await, retry) {
+            (Ok(response), _) => break response,
+            // The client broke off a streamed upload, the instance isn't to blame
+            (Err(e), _) if e.is_body() => {
+                warn!("  ✗ Request body broke off while forwarding: {}", e);
+                return Err(ProxyError::InvalidBody);
+            }
+            (Err(e), Some(next)) if e.is_connect() => {
+                attempt += 1;
+                let delay = retry_delay(attempt);
+                warn!(
+                    "  ↻ Failed to connect to BlazeDB, retry {} of {} in {}ms: {}",
+                    attempt,
+                    retries,
+                    delay.as_millis(),
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                req_builder = next;
+            }
+            (Err(e), _) => {
+                error!("  ✗ Failed to connect to BlazeDB: {}", e);
+                return Err(ProxyError::InstanceUnavailable(None));
+            }
+        }
+    };
+
+    Ok(response)
+}
+
+/// Converts the instance's response into the client's, streaming the body through
+/// A body saying it's over `max_bytes` is dropped, one that only turns out to be is cut off
+fn into_client_response(
+    response: reqwest::Response,
+    activity: ActivityGuard,
+    slot: Slot,
+    max_bytes: u64,
+) -> Result<Response, ProxyError> {
+    if response.content_length().is_some_and(|len| len > max_bytes) {
+        warn!(
+            "  ✗ Response of {:?} bytes is over the plan's {} bytes",
+            response.content_length(),
+            max_bytes
+        );
+        return Err(ProxyError::ResponseTooLarge(max_bytes));
+    }
 
-    // Convert reqwest::Response to axum::Response
     let status = response.status();
     let mut builder = Response::builder().status(status);
 
-    // Copy response headers
-    for (key, value) in response.headers().iter() {
+    // Copy response headers, the connection to the client is framed on its own
+    let mut headers = response.headers().clone();
+    strip_hop_by_hop(&mut headers);
+    for (key, value) in headers.iter() {
         builder = builder.header(key, value);
     }
 
-    // Get response body
-    let body_bytes = response
-        .bytes()
-        .await
-        .map_err(|_| ProxyError::InstanceError)?;
+    // Events have to reach the client as they come, not when a buffer in between fills up
+    let is_event_stream = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if is_event_stream {
+        builder = builder
+            .header(header::CACHE_CONTROL, "no-cache")
+            .header("X-Accel-Buffering", "no");
+    }
 
+    // Stream the response body through frame by frame (trailers included), the activity
+    // guard is dropped with the body so long responses keep the instance busy until they end
+    let inner = axum::http::Response::<reqwest::Body>::from(response).into_body();
     builder
-        .body(Body::from(body_bytes))
+        .body(Body::new(GuardedBody {
+            inner,
+            activity,
+            _slot: slot,
+            sent: 0,
+            max_bytes,
+        }))
         .map_err(|_| ProxyError::InternalError)
 }
 
+fn is_write_method(method: &Method) -> bool {
+    matches!(*method, Method::POST | Method::PUT | Method::PATCH)
+}
+
+/// A streamed request body on its way to the instance, fed through the write scanner
+struct ScannedBody {
+    inner: Mutex<Body>, // Only there to make it Sync, polling goes through `get_mut`
+    scanner: Arc<Mutex<WriteScanner>>,
+    max_bytes: u64, // The upload fails once more than this went through the scanner
+}
+
+impl http_body::Body for ScannedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        let inner = this.inner.get_mut().unwrap_or_else(|e| e.into_inner());
+        let frame = ready!(Pin::new(inner).poll_frame(cx));
+        if let Some(Ok(frame)) = &frame
+            && let Some(data) = frame.data_ref()
+        {
+            let mut scanner = this.scanner.lock().unwrap_or_else(|e| e.into_inner());
+            scanner.feed(data);
+            if scanner.bytes() > this.max_bytes {
+                warn!("  ✗ Upload went over the plan's {} bytes", this.max_bytes);
+                return Poll::Ready(Some(Err(axum::Error::new("request body too large"))));
+            }
+        }
+        Poll::Ready(frame)
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner
+            .lock()
+            .map(|inner| inner.size_hint())
+            .unwrap_or_default()
+    }
+}
+
+/// The instance's response body, holding the request's activity guard and slot until it ends
+struct GuardedBody {
+    inner: reqwest::Body,
+    activity: ActivityGuard,
+    _slot: Slot,
+    sent: u64,
+    max_bytes: u64, // Past this the client gets a broken off response
+}
+
+impl http_body::Body for GuardedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        this.activity.touch();
+        match &frame {
+            Some(Ok(frame)) => {
+                this.sent += frame.data_ref().map_or(0, |data| data.len() as u64);
+                if this.sent > this.max_bytes {
+                    warn!("  ✗ Response went over the plan's {} bytes", this.max_bytes);
+                    return Poll::Ready(Some(Err(axum::Error::new("response body too large"))));
+                }
+            }
+            Some(Err(e)) => error!("  ✗ Response stream from BlazeDB broke off: {}", e),
+            None => {}
+        }
+        Poll::Ready(frame.map(|frame| frame.map_err(axum::Error::new)))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Returns the bearer token if it's a signed instance token rather than an API key
+fn extract_instance_token(headers: &HeaderMap) -> Option<String> {
+    let auth_str = headers.get("Authorization")?.to_str().ok()?;
+    let token = auth_str.strip_prefix("Bearer ").unwrap_or(auth_str).trim();
+
+    token
+        .starts_with(INSTANCE_TOKEN_PREFIX)
+        .then(|| token.to_string())
+}
+
 fn extract_api_key(headers: &HeaderMap) -> Result<String, ProxyError> {
     let auth_header = headers
         .get("Authorization")
@@ -251,7 +1766,7 @@ fn extract_api_key(headers: &HeaderMap) -> Result<String, ProxyError> {
         auth_str
     };
 
-    if !api_key.starts_with("blz_") {
+    if api_key_format_version(api_key).is_none() {
         return Err(ProxyError::InvalidApiKey);
     }
 
@@ -260,8 +1775,9 @@ fn extract_api_key(headers: &HeaderMap) -> Result<String, ProxyError> {
 
 async fn verify_api_key(
     state: &AppState,
+    api_key: &str,
     api_key_hash: &str,
-    email: &String,
+    email: &str,
 ) -> Result<CachedUser, ProxyError> {
     // Check LRU cache first
     {
@@ -273,7 +1789,19 @@ async fn verify_api_key(
     }
 
     // Cache miss - load from disk or memory and verify
-    let cached_user = load_and_verify(&state.user_store, api_key_hash, email).await?;
+    let (cached_user, stored_hash) = load_and_verify(&state.user_store, api_key, email).await?;
+
+    // Users are read-only here, the service stores the new hash, see `server::rehash`
+    if needs_rehash(api_key, &stored_hash) {
+        let key_rehashes = state.key_rehashes.clone();
+        let email = email.to_string();
+        let new_hash = api_key_hash.to_string();
+        get_task_registry().spawn("key-rehash-queue", |_| async move {
+            if let Err(e) = queue_key_rehash(&key_rehashes, &email, &stored_hash, &new_hash).await {
+                error!("Failed to queue a key rehash for {}: {}", email, e);
+            }
+        });
+    }
 
     // Update LRU cache (auto-evicts oldest entry if full)
     {
@@ -285,48 +1813,446 @@ async fn verify_api_key(
 }
 
 // Load and verify user from DataStore (thread-safe with RwLock)
+// Returns the stored hash the key matched along with the user
 async fn load_and_verify(
-    user_store: &DataStore<String, User>,
-    api_key_hash: &str,
-    email: &String,
-) -> Result<CachedUser, ProxyError> {
+    user_store: &UserStore,
+    api_key: &str,
+    email: &str,
+) -> Result<(CachedUser, String), ProxyError> {
     let user = user_store
         .get(email)
         .map_err(|_| ProxyError::DatastoreNotFound)?
         .ok_or(ProxyError::InvalidApiKey)?;
 
-    // Verify API key hash matches
-    let key_valid = user
+    // Verify API key hash matches, peppered or (until it's rehashed) legacy
+    let stored_hash = user
         .api_key
         .iter()
-        .any(|k| !k.is_revoked && k.api_key_hash == api_key_hash);
+        .find(|k| !k.is_revoked && api_key_matches_hash(api_key, &k.api_key_hash))
+        .map(|k| k.api_key_hash.clone())
+        .ok_or(ProxyError::InvalidApiKey)?;
 
-    if !key_valid {
-        return Err(ProxyError::InvalidApiKey);
+    Ok((CachedUser::from_user(&user), stored_hash))
+}
+
+/// What a request was authenticated with, usage is profiled per credential
+struct Credential {
+    profile_key: String, // Key in `key_usage`
+    label: String,       // How alerts name it
+}
+
+impl Credential {
+    fn api_key(api_key: &str, api_key_hash: &str) -> Self {
+        Credential {
+            profile_key: api_key_hash.to_string(),
+            label: api_key.chars().take(12).collect::<String>() + "...",
+        }
     }
 
-    Ok(CachedUser {
-        email: user.email.clone(),
-        username: user.username.clone(),
-        instance_id: user.instance_id.clone(),
-        is_verified: user.is_verified,
-    })
+    /// All of a user's instance tokens share one profile, each only lives a few minutes
+    fn instance_token(email: &str) -> Self {
+        Credential {
+            profile_key: instance_token_profile_key(email),
+            label: format!("{}... (instance tokens)", INSTANCE_TOKEN_PREFIX),
+        }
+    }
+}
+
+/// Records the request in the credential's usage profile and reacts to anomalies per the user's
+/// plan. Fails with `ReverificationRequired` while the credential is paused
+async fn check_key_usage(
+    state: &AppState,
+    credential: &Credential,
+    user: &CachedUser,
+    client_ip: IpAddr,
+    headers: &HeaderMap,
+) -> Result<(), ProxyError> {
+    if user.anomaly_action == AnomalyAction::Ignore {
+        return Ok(());
+    }
+
+    // Only a paused profile needs the re-verification time
+    let is_blocked = state
+        .key_usage
+        .get(&credential.profile_key)
+        .map_err(|_| ProxyError::DatastoreError)?
+        .is_some_and(|profile| profile.is_blocked());
+    let reverified_at = if is_blocked {
+        state
+            .user_store
+            .get(&user.email)
+            .map_err(|_| ProxyError::DatastoreError)?
+            .and_then(|u| u.reverified_at)
+    } else {
+        None
+    };
+
+    // Cloudflare tells us the country, without it only the volume baseline is checked
+    let country = headers.get("cf-ipcountry").and_then(|v| v.to_str().ok());
+
+    let now = chrono::Utc::now();
+    let pause_key = user.anomaly_action == AnomalyAction::Reverify;
+    // Under the store's lock, concurrent requests with the credential all count
+    let check = state
+        .key_usage
+        .upsert_mem(
+            credential.profile_key.clone(),
+            || KeyUsageProfile::new(&user.email),
+            |profile| profile.check(client_ip, country, now, reverified_at.as_deref(), pause_key),
+        )
+        .map_err(|_| ProxyError::DatastoreError)?;
+
+    if check.is_unpaused {
+        info!(" ↳ Key re-verified, lifting pause for {}", user.email);
+    }
+
+    for anomaly in &check.anomalies {
+        warn!(
+            "  ⚠ Key usage anomaly for {}: {}",
+            user.email,
+            anomaly.describe()
+        );
+    }
+
+    if check.should_alert {
+        let (plain_body, html_body) = build_alert_email(
+            &user.username,
+            &credential.label,
+            &check.anomalies,
+            pause_key,
+        );
+        let email = user.email.clone();
+
+        get_task_registry().spawn("anomaly-alert-mail", |_| async move {
+            let sent = tokio::task::spawn_blocking(move || {
+                send_mail(
+                    &email,
+                    "Unusual activity on your BlazeDB API key",
+                    plain_body,
+                    html_body,
+                )
+                .map_err(|e| (email, e))
+            })
+            .await;
+            if let Ok(Err((email, e))) = sent {
+                error!("Failed to send key usage alert to {}: {}", email, e);
+            }
+        });
+    }
+
+    // Paused keys stay paused until the user re-verifies through the service
+    if check.is_paused {
+        Err(ProxyError::ReverificationRequired)
+    } else {
+        Ok(())
+    }
+}
+
+/// Background task to persist key usage profiles periodically
+async fn save_key_usage_task(state: AppState) {
+    let registry = get_task_registry();
+
+    let key_usage = state.key_usage.clone();
+    registry.spawn_periodic(
+        "key-usage-save",
+        tokio::time::Duration::from_secs(60),
+        move || {
+            let key_usage = key_usage.clone();
+            async move {
+                if let Err(e) = key_usage.flush_async().await {
+                    error!("Failed to save key usage profiles: {}", e);
+                }
+            }
+        },
+    );
+
+    // Last save on shutdown so profiles updated since the last tick aren't lost
+    registry.on_shutdown("key-usage-save", move || async move {
+        state.key_usage.flush_async().await?;
+        Ok(())
+    });
+}
+
+/// Background task to merge metered usage into the ledger periodically
+async fn flush_usage_task(state: AppState) {
+    let registry = get_task_registry();
+    let ledger = get_usage_ledger();
+
+    let usage = state.usage.clone();
+    let flush_ledger = ledger.clone();
+    registry.spawn_periodic(
+        "usage-flush",
+        tokio::time::Duration::from_secs(60),
+        move || {
+            let usage = usage.clone();
+            let ledger = flush_ledger.clone();
+            async move {
+                if let Err(e) = usage.flush(&ledger) {
+                    error!("Failed to flush usage: {}", e);
+                }
+            }
+        },
+    );
+
+    // Last flush on shutdown so metered requests aren't lost
+    registry.on_shutdown(
+        "usage-flush",
+        move || async move { state.usage.flush(&ledger) },
+    );
+}
+
+/// Background task to merge request metrics into the metrics store periodically
+async fn flush_metrics_task(state: AppState) {
+    let registry = get_task_registry();
+    let store = get_metrics_store();
+
+    let metrics = state.metrics.clone();
+    let flush_store = store.clone();
+    registry.spawn_periodic(
+        "metrics-flush",
+        tokio::time::Duration::from_secs(60),
+        move || {
+            let metrics = metrics.clone();
+            let store = flush_store.clone();
+            async move {
+                if let Err(e) = metrics.flush(&store) {
+                    error!("Failed to flush metrics: {}", e);
+                }
+            }
+        },
+    );
+
+    registry.on_shutdown("metrics-flush", move || async move {
+        state.metrics.flush(&store)
+    });
+}
+
+/// Background task to append buffered access log entries periodically
+async fn flush_access_log_task(state: AppState) {
+    let registry = get_task_registry();
+
+    let access_log = state.access_log.clone();
+    registry.spawn_periodic(
+        "access-log-flush",
+        tokio::time::Duration::from_secs(5),
+        move || {
+            let access_log = access_log.clone();
+            async move {
+                if let Err(e) = access_log.flush() {
+                    error!("Failed to write the access log: {}", e);
+                }
+            }
+        },
+    );
+
+    registry.on_shutdown("access-log-flush", move || async move {
+        state.access_log.flush()
+    });
+}
+
+/// Background task to append audit entries periodically, and drop the days past the retention
+async fn flush_audit_log_task(state: AppState) {
+    let registry = get_task_registry();
+
+    let audit = state.audit.clone();
+    registry.spawn_periodic(
+        "audit-log-flush",
+        tokio::time::Duration::from_secs(5),
+        move || {
+            let audit = audit.clone();
+            async move {
+                if let Err(e) = audit.flush() {
+                    error!("Failed to write the audit log: {}", e);
+                }
+            }
+        },
+    );
+
+    let audit = state.audit.clone();
+    registry.spawn_periodic(
+        "audit-log-prune",
+        tokio::time::Duration::from_secs(60 * 60),
+        move || {
+            let audit = audit.clone();
+            async move {
+                match audit.prune(chrono::Utc::now()) {
+                    Ok(0) => {}
+                    Ok(pruned) => info!("Deleted {} expired audit log file(s)", pruned),
+                    Err(e) => error!("Failed to prune the audit log: {}", e),
+                }
+            }
+        },
+    );
+
+    registry.on_shutdown(
+        "audit-log-flush",
+        move || async move { state.audit.flush() },
+    );
+}
+
+/// Background task to share the proxy's view of instances with the service periodically
+async fn flush_reachability_task(state: AppState) {
+    let registry = get_task_registry();
+    let store = get_reachability_store();
+
+    let reachability = state.reachability.clone();
+    let flush_store = store.clone();
+    registry.spawn_periodic(
+        "reachability-flush",
+        tokio::time::Duration::from_secs(15),
+        move || {
+            let reachability = reachability.clone();
+            let store = flush_store.clone();
+            async move {
+                if let Err(e) = reachability.flush(&store) {
+                    error!("Failed to flush instance reachability: {}", e);
+                }
+            }
+        },
+    );
+
+    registry.on_shutdown("reachability-flush", move || async move {
+        state.reachability.flush(&store)
+    });
+}
+
+/// Background task to stop containers nobody sent a request for a while
+async fn hibernate_idle_task(state: AppState) {
+    get_task_registry().spawn_periodic(
+        "hibernation",
+        tokio::time::Duration::from_secs(300),
+        move || {
+            let state = state.clone();
+            async move {
+                match hibernate_idle_instances(&state).await {
+                    Ok(count) => {
+                        if count > 0 {
+                            info!("Hibernated {} idle instance(s)", count);
+                        }
+                    }
+                    Err(e) => error!("Failed to hibernate idle instances: {}", e),
+                }
+            }
+        },
+    );
+}
+
+/// Hibernates the running instances that have been idle longer than their plan allows
+async fn hibernate_idle_instances(state: &AppState) -> Result<usize> {
+    let mut hibernated = 0;
+    for user in state.user_store.values()? {
+        if !user.is_verified
+            || user.billing_status == BillingStatus::Suspended
+            || user.plans.features.hibernate_after_hours.is_none()
+        {
+            continue;
+        }
+        for instance_id in user.instance_ids() {
+            if state.activity.open_streams(instance_id) > 0 {
+                continue;
+            }
+            // Not seen since the proxy started, idle at least that long
+            let idle = state
+                .activity
+                .idle_for(instance_id)
+                .unwrap_or_else(|| state.start_time.elapsed());
+            if !is_due_for_hibernation(&user.plans, idle) {
+                continue;
+            }
+
+            let running = match get_instance_state(instance_id).await {
+                Ok(instance_state) => instance_state == "running",
+                Err(e) => {
+                    warn!("Couldn't check {} for hibernation: {}", instance_id, e);
+                    continue;
+                }
+            };
+            if is_hibernated(instance_id)? {
+                // Started again some other way, it hibernates on the next round
+                if running {
+                    forget_hibernation(instance_id)?;
+                }
+                continue;
+            }
+            if running {
+                hibernate_instance(instance_id, &user.email).await?;
+                // Its idle connections lead nowhere now
+                state.clients.forget(instance_id);
+                hibernated += 1;
+            }
+        }
+    }
+    Ok(hibernated)
 }
 
-/// Background task to reload user store from disk periodically
-/// This ensures cache stays fresh without clearing it (LRU will naturally evict stale entries)
+/// Background task to reload the user store once the service saved it
+/// Checks the store's generation every second, a backstop for changes whose invalidation didn't
+/// reach the proxy
 async fn update_cache_task(state: AppState) {
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
+    get_task_registry().spawn_periodic(
+        "user-store-reload",
+        tokio::time::Duration::from_secs(1),
+        move || {
+            let user_store = state.user_store.clone();
+            let key_index = state.key_index.clone();
+            let user_cache = state.user_cache.clone();
+            async move {
+                if let Err(e) = key_index.reload_if_changed_async().await {
+                    error!("Failed to reload API key index: {}", e);
+                }
+                // Drop cached users after a reload so plan changes (and the feature flags
+                // cached with them) apply on the next access
+                match user_store.reload_if_changed_async().await {
+                    Ok(true) => user_cache.write().await.clear(),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to reload user store: {}", e),
+                }
+            }
+        },
+    );
+}
 
-            // Reload user store from disk (cache will naturally refresh on next access)
-            if let Err(e) = state.user_store.reload() {
-                error!("Failed to reload user store: {}", e);
+/// Background task to pick up routes the provisioner recorded, as soon as the file changes
+async fn refresh_routes_task(state: AppState) {
+    get_task_registry().spawn_periodic(
+        "routes-refresh",
+        tokio::time::Duration::from_secs(1),
+        move || {
+            let routes = state.routes.clone();
+            async move {
+                if let Err(e) = routes.refresh() {
+                    error!("Failed to reload the routing table: {}", e);
+                }
             }
+        },
+    );
+}
+
+/// Background task to pick up maintenance windows opened or lifted through the service
+async fn reload_maintenance_task(state: AppState) {
+    get_task_registry().spawn_periodic(
+        "maintenance-reload",
+        tokio::time::Duration::from_secs(5),
+        move || {
+            let maintenance = state.maintenance.clone();
+            async move {
+                if let Err(e) = maintenance.reload_async().await {
+                    error!("Failed to reload maintenance windows: {}", e);
+                }
+            }
+        },
+    );
+}
+
+/// New requests to an instance in maintenance are turned away, the ones in flight finish
+fn check_maintenance(state: &AppState, instance_id: &str) -> Result<(), ProxyError> {
+    match maintenance_for(&state.maintenance, instance_id) {
+        Some(window) => {
+            info!("  ✗ Instance is in maintenance");
+            Err(ProxyError::Maintenance(window))
         }
-    });
+        None => Ok(()),
+    }
 }
 
 #[derive(Debug)]
@@ -337,26 +2263,169 @@ enum ProxyError {
     Forbidden,
     BlockedEndpoint,
     DatastoreNotFound,
-    #[allow(unused)]
     DatastoreError,
-    InstanceUnavailable,
+    ReverificationRequired,
+    InvalidInstanceToken,
+    TokenReadOnly,
+    InstanceUnavailable(Option<&'static str>), // Container state when we could look it up
+    InstanceWaking,                            // Hibernated instance didn't start in time
+    PaymentRequired,                           // Instance stopped after a failed payment
+    FeatureNotInPlan,                          // Endpoint needs a higher plan
+    QuotaExceeded(QuotaExceeded),              // Write would go over the plan's limits
+    InvalidBody,                               // Request body couldn't be read to the end
+    TooManyStreams(u32),                       // User has the plan's WebSockets/event streams open
+    InstanceBusy(Rejected),                    // Too many requests to the instance in flight
+    CircuitOpen(u64),                          // Instance keeps failing, seconds until the next try
+    RequestTooLarge(u64),                      // Body over the plan's limit, in bytes
+    ResponseTooLarge(u64),                     // Instance answered with more than the plan allows
+    Maintenance(MaintenanceWindow),            // Instance (or every instance) is being worked on
     InstanceError,
     UnsupportedMethod,
     InternalError,
 }
 
+impl ProxyError {
+    /// Machine-readable error code, stable for SDKs to match on
+    fn code(&self) -> &'static str {
+        match self {
+            ProxyError::MissingApiKey => "missing_api_key",
+            ProxyError::InvalidApiKey => "invalid_api_key",
+            ProxyError::InvalidPath => "invalid_path",
+            ProxyError::Forbidden => "instance_mismatch",
+            ProxyError::BlockedEndpoint => "blocked_endpoint",
+            ProxyError::DatastoreNotFound => "datastore_not_found",
+            ProxyError::DatastoreError => "datastore_error",
+            ProxyError::ReverificationRequired => "reverification_required",
+            ProxyError::InvalidInstanceToken => "invalid_instance_token",
+            ProxyError::TokenReadOnly => "token_read_only",
+            ProxyError::InstanceUnavailable(_) => "instance_unavailable",
+            ProxyError::InstanceWaking => "instance_waking",
+            ProxyError::PaymentRequired => "payment_required",
+            ProxyError::FeatureNotInPlan => "feature_not_in_plan",
+            ProxyError::QuotaExceeded(_) => "quota_exceeded",
+            ProxyError::InvalidBody => "invalid_body",
+            ProxyError::TooManyStreams(_) => "too_many_streams",
+            ProxyError::InstanceBusy(_) => "instance_busy",
+            ProxyError::CircuitOpen(_) => "circuit_open",
+            ProxyError::RequestTooLarge(_) => "request_too_large",
+            ProxyError::ResponseTooLarge(_) => "response_too_large",
+            ProxyError::Maintenance(_) => "maintenance",
+            ProxyError::InstanceError => "instance_error",
+            ProxyError::UnsupportedMethod => "unsupported_method",
+            ProxyError::InternalError => "internal_error",
+        }
+    }
+
+    /// What the client can do about it
+    fn hint(&self) -> &'static str {
+        match self {
+            ProxyError::MissingApiKey => "Send your API key as `Authorization: Bearer blz1_...`",
+            ProxyError::InvalidApiKey => {
+                "The key is revoked or mistyped, use the key you got after email verification"
+            }
+            ProxyError::InvalidPath => "Append your instance_id to the path",
+            ProxyError::Forbidden => {
+                "Use the instance_id returned with your API key, see POST /v1/blz/instance/status"
+            }
+            ProxyError::BlockedEndpoint => "Don't retry, this endpoint isn't exposed",
+            ProxyError::DatastoreNotFound
+            | ProxyError::DatastoreError
+            | ProxyError::InternalError => "Retry later, check GET /v1/blz/status/incidents",
+            ProxyError::ReverificationRequired => {
+                "Re-verify via POST /v1/blz/keys/reverify, the key works again right after"
+            }
+            ProxyError::InvalidInstanceToken => "Get a fresh token via POST /v1/blz/instance/token",
+            ProxyError::TokenReadOnly => "Use your API key for writes",
+            ProxyError::InstanceUnavailable(state) => match state {
+                Some("starting") | Some("restarting") => "Instance is starting, retry in 10s",
+                Some("missing") => {
+                    "Instance isn't provisioned yet, poll POST /v1/blz/instance/status"
+                }
+                Some("stopped") => "Instance is stopped, retry in 30s",
+                Some("unhealthy") => {
+                    "Instance is unhealthy, retry later or reset via POST /v1/blz/instance/reset"
+                }
+                _ => "Retry in 10s, check GET /v1/blz/status/incidents if it keeps failing",
+            },
+            ProxyError::InstanceWaking => {
+                "Instance was asleep after being idle and is starting, retry in 10s"
+            }
+            ProxyError::PaymentRequired => {
+                "Update your payment method, the instance starts again once a payment goes through"
+            }
+            ProxyError::FeatureNotInPlan => {
+                "Upgrade to the Starter or Pro plan via POST /v1/billing/checkout, see GET /v1/billing/plans"
+            }
+            ProxyError::QuotaExceeded(_) => {
+                "Delete data you don't need or upgrade via POST /v1/billing/checkout, see GET /v1/billing/plans"
+            }
+            ProxyError::InvalidBody => "Send the request again, the upload broke off",
+            ProxyError::TooManyStreams(_) => {
+                "Close a WebSocket or event stream first, or upgrade via POST /v1/billing/checkout"
+            }
+            ProxyError::InstanceBusy(_) => "Send fewer requests in parallel, retry in 1s",
+            ProxyError::CircuitOpen(_) => {
+                "Retry after the Retry-After seconds, check GET /v1/blz/instance/health"
+            }
+            ProxyError::RequestTooLarge(_) => {
+                "Split the write into smaller batches, or upgrade via POST /v1/billing/checkout"
+            }
+            ProxyError::ResponseTooLarge(_) => {
+                "Ask for less per request (paginate, lower top_k), or upgrade via POST /v1/billing/checkout"
+            }
+            ProxyError::Maintenance(_) => {
+                "Retry after the Retry-After seconds, check GET /v1/blz/status/incidents"
+            }
+            ProxyError::InstanceError => "Retry in 10s",
+            ProxyError::UnsupportedMethod => "Use any method but CONNECT or TRACE",
+        }
+    }
+}
+
 impl IntoResponse for ProxyError {
     fn into_response(self) -> Response {
+        let code = self.code();
+        let hint = self.hint();
+        let instance_state = match &self {
+            ProxyError::InstanceUnavailable(state) => *state,
+            ProxyError::InstanceWaking => Some("starting"),
+            _ => None,
+        };
+        let quota = match &self {
+            ProxyError::QuotaExceeded(exceeded) => Some(exceeded.clone()),
+            _ => None,
+        };
+        let max_streams = match &self {
+            ProxyError::TooManyStreams(limit) => Some(*limit),
+            _ => None,
+        };
+        let max_bytes = match &self {
+            ProxyError::RequestTooLarge(limit) | ProxyError::ResponseTooLarge(limit) => {
+                Some(*limit)
+            }
+            _ => None,
+        };
+        let retry_after = match &self {
+            ProxyError::InstanceWaking => Some(10),
+            ProxyError::InstanceBusy(_) => Some(1),
+            ProxyError::CircuitOpen(seconds) => Some(*seconds),
+            ProxyError::Maintenance(window) => Some(retry_after(window, chrono::Utc::now())),
+            _ => None,
+        };
+        let maintenance = match &self {
+            ProxyError::Maintenance(window) => Some(window.clone()),
+            _ => None,
+        };
+
         let (status, message) = match self {
             ProxyError::MissingApiKey => (
                 StatusCode::UNAUTHORIZED,
                 "Missing Authorization header with API key",
             ),
             ProxyError::InvalidApiKey => (StatusCode::UNAUTHORIZED, "Invalid API key"),
-            ProxyError::BlockedEndpoint => (
-                StatusCode::UNAUTHORIZED,
-                "This endpoint is not available",
-            ),
+            ProxyError::BlockedEndpoint => {
+                (StatusCode::UNAUTHORIZED, "This endpoint is not available")
+            }
             ProxyError::InvalidPath => (
                 StatusCode::BAD_REQUEST,
                 "Invalid request path - missing instance_id",
@@ -373,9 +2442,66 @@ impl IntoResponse for ProxyError {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Failed to read user datastore",
             ),
-            ProxyError::InstanceUnavailable => {
+            ProxyError::ReverificationRequired => (
+                StatusCode::FORBIDDEN,
+                "Unusual activity detected on this API key, re-verify via POST /v1/blz/keys/reverify",
+            ),
+            ProxyError::InvalidInstanceToken => (
+                StatusCode::UNAUTHORIZED,
+                "Invalid or expired instance token",
+            ),
+            ProxyError::TokenReadOnly => (
+                StatusCode::FORBIDDEN,
+                "Instance tokens are read-only, use your API key for writes",
+            ),
+            ProxyError::InstanceUnavailable(_) => {
                 (StatusCode::BAD_GATEWAY, "BlazeDB instance is unavailable")
             }
+            ProxyError::InstanceWaking => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "BlazeDB instance is waking up from hibernation",
+            ),
+            ProxyError::PaymentRequired => (
+                StatusCode::PAYMENT_REQUIRED,
+                "Instance is stopped because of a failed payment",
+            ),
+            ProxyError::FeatureNotInPlan => (
+                StatusCode::PAYMENT_REQUIRED,
+                "The embedding API isn't included in your plan",
+            ),
+            ProxyError::QuotaExceeded(_) => (
+                StatusCode::FORBIDDEN,
+                "This write would exceed your plan's limits",
+            ),
+            ProxyError::InvalidBody => (StatusCode::BAD_REQUEST, "Failed to read the request body"),
+            ProxyError::TooManyStreams(_) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many WebSockets or event streams open for your plan",
+            ),
+            ProxyError::InstanceBusy(Rejected::QueueFull) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests to this instance at once",
+            ),
+            ProxyError::CircuitOpen(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "BlazeDB instance keeps refusing connections, requests to it are paused",
+            ),
+            ProxyError::InstanceBusy(Rejected::TimedOut) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests to this instance at once, this one waited too long",
+            ),
+            ProxyError::RequestTooLarge(_) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Request body is larger than your plan allows",
+            ),
+            ProxyError::ResponseTooLarge(_) => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Response is larger than your plan allows",
+            ),
+            ProxyError::Maintenance(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "BlazeDB instance is down for maintenance",
+            ),
             ProxyError::InstanceError => (
                 StatusCode::BAD_GATEWAY,
                 "Error communicating with BlazeDB instance",
@@ -388,13 +2514,34 @@ impl IntoResponse for ProxyError {
             }
         };
 
-        (
-            status,
-            Json(serde_json::json!({
-                "error": message,
-                "timestamp": chrono::Utc::now().to_rfc3339()
-            })),
-        )
-            .into_response()
+        let mut body = serde_json::json!({
+            "error": message,
+            "code": code,
+            "instance_state": instance_state,
+            "quota": quota,
+            "hint": hint,
+            "timestamp": chrono::Utc::now().to_rfc3339()
+        });
+        if let Some(limit) = max_streams {
+            body["max_streams"] = limit.into();
+        }
+        if let Some(limit) = max_bytes {
+            body["max_bytes"] = limit.into();
+        }
+        if let Some(window) = maintenance {
+            body["maintenance"] = serde_json::json!({
+                "message": window.message,
+                "until": window.until,
+            });
+        }
+
+        let mut response = (status, Json(body)).into_response();
+        if let Some(seconds) = retry_after {
+            response.headers_mut().insert(
+                axum::http::header::RETRY_AFTER,
+                axum::http::HeaderValue::from(seconds),
+            );
+        }
+        response
     }
 }
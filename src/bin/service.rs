@@ -1,22 +1,105 @@
 use anyhow::Result;
-use axum::http::{HeaderMap, StatusCode};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query};
+use axum::http::{HeaderMap, Method, StatusCode, header};
 use axum::response::IntoResponse;
-use axum::routing::{get, post};
+use axum::routing::{delete, get, post};
 use axum::{Json, Router};
 use blaze_service::prelude::*;
-use blaze_service::server::crypto::extract_email_from_api_key;
+use blaze_service::server::audit::{
+    DEFAULT_QUERY_LIMIT, MAX_QUERY_LIMIT, get_audit_path, query_audit_log,
+};
+use blaze_service::server::autorestart::{get_restart_events, restart_unhealthy_containers};
+use blaze_service::server::backups::run_scheduled_backups;
+use blaze_service::server::billing::{
+    cancel_subscription, check_can_cancel, create_checkout_session, create_coupon,
+    expire_lapsed_subscriptions, find_usable_coupon, get_billing_history, handle_stripe_webhook,
+    preview_plan_change,
+};
+use blaze_service::server::container::{DEFAULT_LOG_TAIL, get_container_restart_counts};
+use blaze_service::server::container_events::watch_container_events;
+use blaze_service::server::cors::api_cors_layer;
+use blaze_service::server::crypto::{api_key_format_version, secrets_match};
+use blaze_service::server::dunning::enforce_dunning;
+use blaze_service::server::incidents::{
+    list_incidents, record_incident, report_restart_counts, resolve_incident,
+};
+use blaze_service::server::invoices::{generate_monthly_invoices, get_invoices};
+use blaze_service::server::key_index::{find_api_key_owner, get_key_index_store};
+use blaze_service::server::mailer::{
+    flush_mail_queue, get_mail_metrics, get_mail_queue, get_mail_quota,
+};
+use blaze_service::server::maintenance::{end_maintenance, list_maintenance, start_maintenance};
+use blaze_service::server::metering::get_user_usage;
+use blaze_service::server::metrics::{DEFAULT_WINDOW_HOURS, MAX_WINDOW_HOURS, get_user_metrics};
+use blaze_service::server::network::ClientIp;
+use blaze_service::server::organizations::{
+    check_can_join, create_organization, get_user_organization, invite_member, is_managed_member,
+    join_organization, leave_organization,
+};
+use blaze_service::server::plans::{ensure_plans_file, reload_plan_catalog_if_changed};
+use blaze_service::server::preflight::run_preflight;
+use blaze_service::server::provisioning::{
+    reconcile_desired_containers, request_missing_containers,
+};
+use blaze_service::server::recommendation::{get_plan_recommendation, send_recommendation_emails};
+use blaze_service::server::reconciliation::reconcile_billing;
+use blaze_service::server::referrals::{credit_balance, get_referral_code, redeem_referral};
+use blaze_service::server::rotation::rotate_instance_secret;
 use blaze_service::server::schema::{
-    InstanceStatusResponse, InstanceStatusResquest, UserData, UserStats,
+    AccountDeleteRequest, AccountDeleteResponse, AuditQuery, AuditResponse, BillingHistoryResponse,
+    CheckoutRequest, CheckoutResponse, CouponCreateRequest, CouponResponse, IncidentCreateRequest,
+    IncidentResponse, InstanceAction, InstanceBackupListResponse, InstanceBackupResponse,
+    InstanceCloneResponse, InstanceConfig, InstanceConfigResponse, InstanceHealthResponse,
+    InstanceLifecycleResponse, InstanceLogsQuery, InstanceLogsResponse, InstanceMetricsQuery,
+    InstanceMetricsResponse, InstanceReadinessResponse, InstanceResetRequest,
+    InstanceResetResponse, InstanceRestoreRequest, InstanceRestoreResponse, InstanceStatusResponse,
+    InstanceStatusResquest, InstanceTokenResponse, InvoiceListResponse, KeyReverifyRequest,
+    KeyReverifyResponse, MailQuotaResponse, MaintenanceQuery, MaintenanceRequest,
+    MaintenanceResponse, Organization, OrganizationCreateRequest, OrganizationInviteRequest,
+    OrganizationJoinRequest, OrganizationResponse, PlanChangePreviewQuery,
+    PlanChangePreviewResponse, PlanChangeRequest, PlanChangeResponse, PlanRecommendationResponse,
+    PreflightRequest, PreflightResponse, ReconcileQuery, ReconcileResponse, ReferralRedeemRequest,
+    ReferralResponse, ResendCodeResponse, RestartEventsResponse, SecretRotationResponse,
+    SnapshotListResponse, SnapshotResponse, SnapshotRestoreRequest, StorageCompactResponse,
+    StorageStatsResponse, StoreExportQuery, StoreExportResponse, StoreImportQuery,
+    StoreImportResponse, StoreMigrationRequest, StoreMigrationResponse, SubscriptionCancelResponse,
+    TrialRequest, TrialResponse, UsageResponse, UserData, UserStats,
 };
+use blaze_service::server::secrets::{get_secrets, load_secrets};
 use blaze_service::server::service::{
-    get_all_free_users, get_all_pro_users, get_all_starter_users, get_instance_stats,
-    get_unverified_users, is_user_exists, is_user_verified, periodic_save_users, save_user,
-    verify_user,
+    ActionConfirmation, EMAIL_DOMAIN_NOT_ALLOWED, NO_PENDING_VERIFICATION, OTP_COOLDOWN_SECONDS,
+    OTP_MAX_RESENDS, OtpFailure, OtpPurpose, apply_key_rehashes, backup_instance, change_plan,
+    clone_instance, compact_stores, confirm_action, control_instance, create_instance_token,
+    delete_account, downgrade_expired_trials, export_store, get_all_free_users, get_all_pro_users,
+    get_all_starter_users, get_allowed_email_domains, get_backup_file, get_instance_config,
+    get_instance_health, get_instance_logs, get_instance_readiness, get_instance_stats,
+    get_unverified_users, get_user, get_user_plan, import_store, is_auth_privacy_mode,
+    is_email_domain_allowed, is_user_exists, is_user_on_trial, is_user_verified,
+    list_instance_backups, mark_user_reverified, migrate_user_store, pad_auth_response,
+    periodic_save_users, refresh_user_plans, resend_verification_code, reset_instance,
+    restore_instance, save_user, start_trial, storage_stats, update_instance_config,
+    verify_api_key, verify_user,
 };
+use blaze_service::server::snapshots::{
+    apply_pending_restore, create_snapshot, find_snapshot, list_snapshots, run_scheduled_snapshot,
+    stage_restore,
+};
+use blaze_service::server::storage::{ExportFormat, StoreFormat, is_read_only};
+use blaze_service::server::tasks::{get_task_registry, shutdown_signal, shutdown_timeout};
+use blaze_service::server::tax::normalize_tax_details;
 use blaze_service::{error, info, warn};
+use futures_util::StreamExt;
+use std::net::SocketAddr;
 use std::sync::OnceLock;
 use std::time::Duration;
 
+/// How many incidents the public status endpoint returns
+const INCIDENT_HISTORY_LIMIT: usize = 50;
+
+/// Largest store export the import endpoint takes
+const STORE_IMPORT_MAX_BYTES: usize = 256 * 1024 * 1024;
+
 static SERVER_START_TIME: OnceLock<chrono::DateTime<chrono::Local>> = OnceLock::new();
 
 #[tokio::main]
@@ -25,17 +108,38 @@ async fn main() -> Result<()> {
 
     dotenv::dotenv().ok();
 
+    // Every secret is read once here, before the stores and handlers need them
+    load_secrets().await?;
+
     let port = std::env::var("SERVICE_PORT").expect("PORT must be set 😠");
     // Create necessary directories
     create_dirs().await?;
+    // A restore staged before the restart replaces the data before any store reads it
+    if let Some(id) = apply_pending_restore()? {
+        info!("Service data restored from snapshot {}", id);
+    }
+    ensure_plans_file()?;
 
     // Create the router
     let app = create_router().await;
 
     let addr = format!("0.0.0.0:{}", port);
 
+    start_container_events_task().await;
     start_cleanup_task().await;
     start_user_save_task().await;
+    start_restart_monitor_task().await;
+    start_auto_restart_task().await;
+    start_trial_expiry_task().await;
+    start_recommendation_email_task().await;
+    start_invoice_task().await;
+    start_plan_catalog_reload_task().await;
+    start_key_rehash_task().await;
+    start_dunning_task().await;
+    start_mail_queue_task().await;
+    start_provisioning_task().await;
+    start_backup_task().await;
+    start_snapshot_task().await;
 
     let listener = tokio::net::TcpListener::bind(&addr).await?;
     let server_time = chrono::Local::now();
@@ -45,52 +149,383 @@ async fn main() -> Result<()> {
 
     info!("Service server listening on {}", addr);
     info!("Server started at {}", server_time.to_rfc3339().yellow());
-    axum::serve(listener, app).await?;
+    let registry = get_task_registry();
+    let timeout = shutdown_timeout();
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        // Followed log streams would keep their connection open forever otherwise
+        registry.cancel();
+    });
+
+    // Stops accepting connections on the signal and waits for requests in flight (container
+    // operations included), up to the timeout
+    tokio::select! {
+        result = server.into_future() => result?,
+        _ = async {
+            registry.token().cancelled().await;
+            tokio::time::sleep(timeout).await;
+        } => warn!(
+            "Requests still running after {}s, shutting down anyway",
+            timeout.as_secs()
+        ),
+    }
+
+    // Let spawned container operations finish, then flush users before exiting
+    registry.shutdown(timeout).await;
     Ok(())
 }
 
 async fn create_router() -> Router {
-    Router::new()
+    let router = Router::new()
         .route("/v1/blz/health", get(health_check))
         .route("/v1/blz/auth/register", post(auth_register))
         .route("/v1/blz/auth/verify-email", post(auth_verify_email))
+        .route("/v1/blz/auth/resend-code", post(auth_resend_code))
         .route("/v1/blz/auth/verify-code", post(auth_verify_code))
         .route("/v1/billing/plans", get(billing_plans))
         .route("/v1/blz/users/stats", get(get_user_stats)) // Admin endpoint to get user stats SAFELY (NOTHING EXPOSED HERE)
-        .route("/v1/blz/instance/status", post(instance_status))
-    // .route("/billing/checkout", post(billing_checkout))
-    // .route("/billing/webhook", post(stripe_webhook))
-    // .route("/account/status", get(account_status))
+        .route(
+            "/v1/blz/instance/status",
+            get(instance_readiness).post(instance_status),
+        )
+        .route("/v1/blz/instance/token", post(instance_token))
+        .route("/v1/blz/instance/reset", post(instance_reset))
+        .route("/v1/blz/instance/clone", post(instance_clone))
+        .route("/v1/blz/instance/start", post(instance_start))
+        .route("/v1/blz/instance/stop", post(instance_stop))
+        .route("/v1/blz/instance/restart", post(instance_restart))
+        .route("/v1/blz/instance/health", get(instance_health))
+        .route("/v1/blz/instance/logs", get(instance_logs))
+        .route("/v1/blz/instance/metrics", get(instance_metrics))
+        .route(
+            "/v1/blz/instance/config",
+            get(instance_config).put(instance_config_update),
+        )
+        .route(
+            "/v1/blz/instance/backups",
+            get(instance_backups).post(instance_backup),
+        )
+        .route(
+            "/v1/blz/instance/backups/{id}/{volume}",
+            get(instance_backup_download),
+        )
+        .route(
+            "/v1/blz/instance/backups/{id}/restore",
+            post(instance_restore),
+        )
+        .route("/v1/blz/account", delete(account_delete))
+        .route(
+            "/v1/blz/account/recommendation",
+            get(account_recommendation),
+        )
+        .route("/v1/blz/keys/reverify", post(keys_reverify))
+        .route("/v1/blz/orgs", post(org_create).get(org_get))
+        .route("/v1/blz/orgs/invite", post(org_invite))
+        .route("/v1/blz/orgs/join", post(org_join))
+        .route("/v1/blz/orgs/leave", post(org_leave))
+        .route("/v1/blz/referrals", get(referral_get))
+        .route("/v1/blz/referrals/redeem", post(referral_redeem))
+        .route("/v1/billing/cancel", post(billing_cancel))
+        .route("/v1/billing/checkout", post(billing_checkout))
+        .route("/v1/billing/history", get(billing_history))
+        .route("/v1/billing/invoices", get(billing_invoices))
+        .route("/v1/billing/preview-change", get(billing_preview_change))
+        .route("/v1/billing/usage", get(billing_usage))
+        .route("/v1/billing/trial", post(billing_trial))
+        .route("/v1/billing/webhook", post(stripe_webhook))
+        .route("/v1/blz/status/incidents", get(status_incidents))
+        .route("/v1/blz/admin/incidents", post(admin_create_incident))
+        .route(
+            "/v1/blz/admin/incidents/{id}/resolve",
+            post(admin_resolve_incident),
+        )
+        .route(
+            "/v1/blz/admin/instances/{id}/restarts",
+            get(admin_instance_restarts),
+        )
+        .route("/v1/blz/admin/users/plan", post(admin_change_plan))
+        .route("/v1/blz/admin/coupons", post(admin_create_coupon))
+        .route(
+            "/v1/blz/admin/billing/reconcile",
+            get(admin_billing_reconcile),
+        )
+        .route("/v1/blz/admin/storage/migrate", post(admin_migrate_store))
+        .route("/v1/blz/admin/storage/compact", post(admin_compact_stores))
+        .route("/v1/blz/admin/secrets/rotate", post(admin_rotate_secrets))
+        .route(
+            "/v1/blz/admin/storage/{store}/export",
+            get(admin_export_store),
+        )
+        .route(
+            "/v1/blz/admin/storage/{store}/import",
+            post(admin_import_store).layer(DefaultBodyLimit::max(STORE_IMPORT_MAX_BYTES)),
+        )
+        .route(
+            "/v1/blz/admin/diagnostics/storage",
+            get(admin_storage_stats),
+        )
+        .route(
+            "/v1/blz/admin/snapshots",
+            get(admin_list_snapshots).post(admin_create_snapshot),
+        )
+        .route(
+            "/v1/blz/admin/snapshots/restore",
+            post(admin_restore_snapshot),
+        )
+        .route("/v1/blz/admin/mail/quota", get(admin_mail_quota))
+        .route("/v1/blz/admin/diagnostics/preflight", post(admin_preflight))
+        .route(
+            "/v1/blz/admin/maintenance",
+            get(admin_list_maintenance)
+                .post(admin_start_maintenance)
+                .delete(admin_end_maintenance),
+        )
+        .route("/v1/blz/admin/audit", get(admin_audit))
+        // .route("/account/status", get(account_status))
+        .layer(axum::middleware::from_fn(reject_writes_when_read_only));
+
+    // Outermost, so preflights get answered even while storage is read-only
+    match api_cors_layer() {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+/// Answers 503 to anything that could write while a store migration has storage read-only
+async fn reject_writes_when_read_only(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let is_read = matches!(request.method(), &Method::GET | &Method::HEAD);
+    if is_read || !is_read_only() {
+        return next.run(request).await;
+    }
+
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "30")],
+        Json(serde_json::json!({
+            "error": "Service is read-only for maintenance, try again shortly",
+        })),
+    )
+        .into_response()
 }
 
 // Start background cleanup task for OTPs
 pub async fn start_cleanup_task() {
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
-        loop {
-            interval.tick().await;
-            match cleanup_expired_otps().await {
+    get_task_registry().spawn_periodic("otp-cleanup", Duration::from_secs(30), || async {
+        match cleanup_expired_otps().await {
+            Ok(count) => {
+                if count > 0 {
+                    info!("Cleaned up {} expired OTP(s)", count);
+                }
+            }
+            Err(e) => error!("OTP cleanup failed: {}", e),
+        }
+    });
+}
+
+/// How often users changed in memory are saved at most, `BLAZE_USER_SAVE_SECONDS` (10 by default)
+fn user_save_interval() -> Duration {
+    let seconds = std::env::var("BLAZE_USER_SAVE_SECONDS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&secs: &u64| secs > 0)
+        .unwrap_or(10);
+    Duration::from_secs(seconds)
+}
+
+// Start background task saving users changed in memory to disk
+pub async fn start_user_save_task() {
+    let registry = get_task_registry();
+    registry.spawn_periodic("user-save", user_save_interval(), || async {
+        match periodic_save_users().await {
+            Ok(_) => {}
+            Err(e) => error!("User save failed: {}", e),
+        }
+    });
+    // Last save on shutdown so nothing written since the last tick is lost
+    registry.on_shutdown("user-save", || async { Ok(periodic_save_users().await?) });
+}
+
+// Start background task downgrading expired trials
+pub async fn start_trial_expiry_task() {
+    get_task_registry().spawn_periodic("trial-expiry", Duration::from_secs(600), || async {
+        match downgrade_expired_trials().await {
+            Ok(count) => {
+                if count > 0 {
+                    info!("Downgraded {} expired trial(s)", count);
+                }
+            }
+            Err(e) => error!("Trial expiry check failed: {}", e),
+        }
+        // Paid periods that ended without a renewal
+        match expire_lapsed_subscriptions().await {
+            Ok(count) => {
+                if count > 0 {
+                    info!("Expired {} lapsed subscription(s)", count);
+                }
+            }
+            Err(e) => error!("Subscription expiry check failed: {}", e),
+        }
+    });
+}
+
+// Start background task emailing plan recommendations (opt-in, see BLAZE_RECOMMENDATION_EMAILS)
+pub async fn start_recommendation_email_task() {
+    get_task_registry().spawn_periodic(
+        "recommendation-emails",
+        Duration::from_secs(24 * 3600),
+        || async {
+            if let Err(e) = send_recommendation_emails().await {
+                error!("Plan recommendation emails failed: {}", e);
+            }
+        },
+    );
+}
+
+// Start background task creating and emailing last month's invoices
+pub async fn start_invoice_task() {
+    get_task_registry().spawn_periodic(
+        "monthly-invoices",
+        Duration::from_secs(6 * 3600),
+        || async {
+            if let Err(e) = generate_monthly_invoices().await {
+                error!("Monthly invoice generation failed: {}", e);
+            }
+        },
+    );
+}
+
+// Start background task sending mail deferred by the send rate limits
+pub async fn start_mail_queue_task() {
+    get_task_registry().spawn_periodic("mail-queue", Duration::from_secs(60), || async {
+        match tokio::task::spawn_blocking(flush_mail_queue).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => error!("Mail queue flush failed: {}", e),
+            Err(e) => error!("Mail queue task failed: {}", e),
+        }
+    });
+}
+
+// Start background task retrying container spawns until every instance is healthy
+pub async fn start_provisioning_task() {
+    let registry = get_task_registry();
+    registry.spawn("provisioning-seed", |_| async {
+        if let Err(e) = request_missing_containers().await {
+            error!("Looking for missing containers failed: {}", e);
+        }
+    });
+    registry.spawn_periodic(
+        "container-provisioning",
+        Duration::from_secs(15),
+        || async {
+            if let Err(e) = reconcile_desired_containers().await {
+                error!("Container provisioning failed: {}", e);
+            }
+        },
+    );
+}
+
+// Start background task sending payment reminders and suspending after the grace period
+pub async fn start_dunning_task() {
+    get_task_registry().spawn_periodic("dunning", Duration::from_secs(600), || async {
+        match enforce_dunning().await {
+            Ok(count) => {
+                if count > 0 {
+                    info!("Dunning: acted on {} past due user(s)", count);
+                }
+            }
+            Err(e) => error!("Dunning enforcement failed: {}", e),
+        }
+    });
+}
+
+// Start background task reloading the plan catalog when its file changes
+pub async fn start_plan_catalog_reload_task() {
+    get_task_registry().spawn_periodic("plan-catalog-reload", Duration::from_secs(30), || async {
+        match reload_plan_catalog_if_changed() {
+            Ok(true) => match refresh_user_plans().await {
                 Ok(count) => {
                     if count > 0 {
-                        info!("Cleaned up {} expired OTP(s)", count);
+                        info!("Updated the plan of {} user(s)", count);
                     }
                 }
-                Err(e) => error!("OTP cleanup failed: {}", e),
+                Err(e) => error!("Failed to update user plans: {}", e),
+            },
+            Ok(false) => {}
+            Err(e) => error!(
+                "Plan catalog reload failed, keeping the current plans: {}",
+                e
+            ),
+        }
+    });
+}
+
+// Start background task storing the new key hashes the proxy queued
+pub async fn start_key_rehash_task() {
+    get_task_registry().spawn_periodic("key-rehash", Duration::from_secs(60), || async {
+        if let Err(e) = apply_key_rehashes().await {
+            error!("Storing queued key rehashes failed: {}", e);
+        }
+    });
+}
+
+// Start background task following Docker events, so container state lookups skip Docker
+pub async fn start_container_events_task() {
+    get_task_registry().spawn("container-events", watch_container_events);
+}
+
+// Start background task watching for mass container restarts
+pub async fn start_restart_monitor_task() {
+    get_task_registry().spawn_periodic("restart-monitor", Duration::from_secs(60), || async {
+        let result = match get_container_restart_counts().await {
+            Ok(counts) => report_restart_counts(counts),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            error!("Container restart monitor failed: {}", e);
+        }
+    });
+}
+
+// Start background task restarting containers that stay unhealthy
+pub async fn start_auto_restart_task() {
+    get_task_registry().spawn_periodic("auto-restart", Duration::from_secs(60), || async {
+        match restart_unhealthy_containers().await {
+            Ok(count) => {
+                if count > 0 {
+                    info!("Restarted {} unhealthy container(s)", count);
+                }
             }
+            Err(e) => error!("Automatic container restarts failed: {}", e),
         }
     });
 }
 
-// Start background task to periodically save users to disk
-pub async fn start_user_save_task() {
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(10));
-        loop {
-            interval.tick().await;
-            match periodic_save_users().await {
-                Ok(_) => {}
-                Err(e) => error!("User save failed: {}", e),
+// Start background task backing up instances on their plan's schedule
+pub async fn start_backup_task() {
+    get_task_registry().spawn_periodic("scheduled-backups", Duration::from_secs(3600), || async {
+        match run_scheduled_backups().await {
+            Ok(count) => {
+                if count > 0 {
+                    info!("Backed up {} instance(s) on schedule", count);
+                }
             }
+            Err(e) => error!("Scheduled backups failed: {}", e),
+        }
+    });
+}
+
+// Start background task snapshotting the service's own data
+pub async fn start_snapshot_task() {
+    get_task_registry().spawn_periodic("service-snapshots", Duration::from_secs(3600), || async {
+        if let Err(e) = run_scheduled_snapshot().await {
+            error!("Scheduled service snapshot failed: {}", e);
         }
     });
 }
@@ -115,8 +550,14 @@ async fn health_check() -> impl IntoResponse {
 }
 
 /// This endpoint handles user registration and saves the user data.
-async fn auth_register(Json(payload): Json<UserRegisterRequest>) -> impl IntoResponse {
-    info!("User registration attempt for email: {}", payload.email);
+async fn auth_register(
+    ClientIp(client_ip): ClientIp,
+    Json(payload): Json<UserRegisterRequest>,
+) -> impl IntoResponse {
+    info!(
+        "User registration attempt for email: {} from {}",
+        payload.email, client_ip
+    );
     if is_empty_field(&payload.username) || is_empty_field(&payload.email) {
         warn!("Registration failed: Empty username or email");
         return (
@@ -125,10 +566,32 @@ async fn auth_register(Json(payload): Json<UserRegisterRequest>) -> impl IntoRes
                 email: "".to_string(),
                 is_created: false,
                 error: "Username or email cannot be empty".to_string(),
+                error_code: None,
+            }),
+        );
+    }
+
+    // Not hidden by privacy mode, the allowlist is deployment config and nothing about the user
+    if !is_email_domain_allowed(&payload.email, get_allowed_email_domains()) {
+        warn!(
+            "Registration failed: Email domain not allowed for email: {}",
+            payload.email
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            Json(UserRegisterResponse {
+                email: "".to_string(),
+                is_created: false,
+                error: "Registration is restricted to specific email domains".to_string(),
+                error_code: Some(EMAIL_DOMAIN_NOT_ALLOWED.to_string()),
             }),
         );
     }
 
+    if is_auth_privacy_mode() {
+        return private_auth_register(&payload).await;
+    }
+
     match is_user_exists(&payload.email).await {
         Ok(exists) => {
             if exists {
@@ -139,6 +602,7 @@ async fn auth_register(Json(payload): Json<UserRegisterRequest>) -> impl IntoRes
                         email: "".to_string(),
                         is_created: false,
                         error: "User already exists".to_string(),
+                        error_code: None,
                     }),
                 );
             }
@@ -154,6 +618,7 @@ async fn auth_register(Json(payload): Json<UserRegisterRequest>) -> impl IntoRes
                     email: "".to_string(),
                     is_created: false,
                     error: "Internal server error, Sorry!".to_string(),
+                    error_code: None,
                 }),
             );
         }
@@ -178,6 +643,7 @@ async fn auth_register(Json(payload): Json<UserRegisterRequest>) -> impl IntoRes
                     email: "".to_string(),
                     is_created: false,
                     error: "Internal server error, Sorry!".to_string(),
+                    error_code: None,
                 }),
             )
         }
@@ -185,8 +651,14 @@ async fn auth_register(Json(payload): Json<UserRegisterRequest>) -> impl IntoRes
 }
 
 /// This endpoint handles email verification requests which sends a verification code to the user's email.
-async fn auth_verify_email(Json(payload): Json<VerifyEmailRequest>) -> impl IntoResponse {
-    info!("Verify email attempt for email: {}", payload.email);
+async fn auth_verify_email(
+    ClientIp(client_ip): ClientIp,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> impl IntoResponse {
+    info!(
+        "Verify email attempt for email: {} from {}",
+        payload.email, client_ip
+    );
 
     if is_empty_field(&payload.email) {
         warn!("Email verification failed: Empty email");
@@ -195,10 +667,30 @@ async fn auth_verify_email(Json(payload): Json<VerifyEmailRequest>) -> impl Into
             Json(VerifyEmailResponse {
                 is_code_sent: false,
                 error: "Email cannot be empty".to_string(),
+                error_code: None,
+            }),
+        );
+    }
+
+    if !is_email_domain_allowed(&payload.email, get_allowed_email_domains()) {
+        warn!(
+            "Email verification failed: Email domain not allowed for email: {}",
+            payload.email
+        );
+        return (
+            StatusCode::FORBIDDEN,
+            Json(VerifyEmailResponse {
+                is_code_sent: false,
+                error: "Registration is restricted to specific email domains".to_string(),
+                error_code: Some(EMAIL_DOMAIN_NOT_ALLOWED.to_string()),
             }),
         );
     }
 
+    if is_auth_privacy_mode() {
+        return private_auth_verify_email(&payload).await;
+    }
+
     // Check user exists
     match is_user_exists(&payload.email).await {
         Ok(exists) => {
@@ -212,6 +704,7 @@ async fn auth_verify_email(Json(payload): Json<VerifyEmailRequest>) -> impl Into
                     Json(VerifyEmailResponse {
                         is_code_sent: false,
                         error: "User not found".to_string(),
+                        error_code: None,
                     }),
                 );
             }
@@ -226,6 +719,7 @@ async fn auth_verify_email(Json(payload): Json<VerifyEmailRequest>) -> impl Into
                 Json(VerifyEmailResponse {
                     is_code_sent: false,
                     error: "Internal server error, Sorry!".to_string(),
+                    error_code: None,
                 }),
             );
         }
@@ -241,6 +735,7 @@ async fn auth_verify_email(Json(payload): Json<VerifyEmailRequest>) -> impl Into
                     Json(VerifyEmailResponse {
                         is_code_sent: false,
                         error: "User already verified".to_string(),
+                        error_code: None,
                     }),
                 );
             }
@@ -255,6 +750,7 @@ async fn auth_verify_email(Json(payload): Json<VerifyEmailRequest>) -> impl Into
                 Json(VerifyEmailResponse {
                     is_code_sent: false,
                     error: "Internal server error, Sorry!".to_string(),
+                    error_code: None,
                 }),
             );
         }
@@ -272,194 +768,3386 @@ async fn auth_verify_email(Json(payload): Json<VerifyEmailRequest>) -> impl Into
                 Json(VerifyEmailResponse {
                     is_code_sent: false,
                     error: "Sorry, Something went wrong, Error: ".to_string() + &e.to_string(),
+                    error_code: None,
                 }),
             )
         }
     }
 }
 
-// TODO: Explicitly handle cases like user not found, OTP expired, invalid OTP, etc, right now its either 200 or 500.
-/// This endpoint handles verification code submission for email verification.
-async fn auth_verify_code(Json(payload): Json<VerifyOtpRequest>) -> impl IntoResponse {
-    info!("OTP verification attempt for email: {}", payload.email);
-    if is_empty_field(&payload.email) || is_empty_field(&payload.otp) {
-        warn!("OTP verification failed: Empty email or OTP");
+/// This endpoint sends a new code for a verification in progress, the previous code stops working
+/// Shares the cooldown with verify-email and says how long until another code can be asked for
+async fn auth_resend_code(
+    ClientIp(client_ip): ClientIp,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> impl IntoResponse {
+    info!(
+        "Resend code attempt for email: {} from {}",
+        payload.email, client_ip
+    );
+
+    if is_empty_field(&payload.email) {
+        warn!("Resend code failed: Empty email");
         return (
             StatusCode::BAD_REQUEST,
-            Json(VerifyOtpResponse {
-                is_verified: false,
-                message: "Email or OTP cannot be empty".to_string(),
-                api_key: None,
-                instance_id: None,
+            Json(ResendCodeResponse {
+                is_code_sent: false,
+                retry_after_seconds: 0,
+                resends_remaining: 0,
+                error: "Email cannot be empty".to_string(),
+                error_code: None,
             }),
         );
     }
-    match verify_otp_service(&payload).await {
+
+    if is_auth_privacy_mode() {
+        return private_auth_resend_code(&payload).await;
+    }
+
+    match resend_verification_code(&payload.email).await {
         Ok(response) => {
-            if response.is_verified {
-                info!("OTP verified for email: {}", payload.email);
+            let status = match response.error_code.as_deref() {
+                None if response.is_code_sent => StatusCode::OK,
+                None => StatusCode::INTERNAL_SERVER_ERROR, // Mail delivery failed
+                Some(NO_PENDING_VERIFICATION) => StatusCode::NOT_FOUND,
+                Some(_) => StatusCode::TOO_MANY_REQUESTS, // Cooldown or resend limit
+            };
+            if response.is_code_sent {
+                info!(
+                    "Verification code resent to {}, {} resend(s) left",
+                    payload.email, response.resends_remaining
+                );
             } else {
                 warn!(
-                    "OTP verification failed for email: {}: {}",
-                    payload.email, response.message
+                    "Resend code failed for email: {}: {}",
+                    payload.email, response.error
                 );
             }
-            (StatusCode::OK, Json(response))
+            (status, Json(response))
         }
         Err(e) => {
             error!(
-                "OTP verification failed for email: {}, Error: {:?}",
+                "Resend code failed for email: {}, Error: {:?}",
                 payload.email, e
             );
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(VerifyOtpResponse {
-                    is_verified: false,
-                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
-                    api_key: None,
-                    instance_id: None,
+                Json(ResendCodeResponse {
+                    is_code_sent: false,
+                    retry_after_seconds: 0,
+                    resends_remaining: 0,
+                    error: "Internal server error, Sorry!".to_string(),
+                    error_code: None,
                 }),
             )
         }
     }
 }
 
-async fn billing_plans() -> impl IntoResponse {
-    let plans = vec![Plans::free_plan(), Plans::starter_plan(), Plans::pro_plan()];
-    (StatusCode::OK, Json(plans))
-}
+/// Privacy mode resend-code: the same response in the same time whether or not a verification is
+/// in progress, the code goes out in the background
+async fn private_auth_resend_code(
+    payload: &VerifyEmailRequest,
+) -> (StatusCode, Json<ResendCodeResponse>) {
+    let started = std::time::Instant::now();
 
-async fn get_user_stats() -> impl IntoResponse {
-    let unverified_user = get_unverified_users().await.unwrap_or_else(|e| {
-        error!("Failed to fetch unverified users: {:?}", e);
-        Vec::new()
-    });
-    let free_users = get_all_free_users().await.unwrap_or_else(|e| {
-        error!("Failed to fetch free users: {:?}", e);
-        Vec::new()
-    });
-    let starter_users = get_all_starter_users().await.unwrap_or_else(|e| {
-        error!("Failed to fetch starter users: {:?}", e);
-        Vec::new()
-    });
-    let pro_users = get_all_pro_users().await.unwrap_or_else(|e| {
-        error!("Failed to fetch pro users: {:?}", e);
-        Vec::new()
+    let email = payload.email.clone();
+    get_task_registry().spawn("verification-mail", |_| async move {
+        match resend_verification_code(&email).await {
+            Ok(response) if response.is_code_sent => {}
+            Ok(response) => warn!(
+                "Resend code failed for email: {}: {} (hidden by privacy mode)",
+                email, response.error
+            ),
+            Err(e) => error!("Resend code failed for email: {}, Error: {:?}", email, e),
+        }
     });
 
-    let userdata = UserData {
-        unverified_users: unverified_user.into_iter().map(UserStats::from).collect(),
-        free_users: free_users.into_iter().map(UserStats::from).collect(),
-        stater_users: starter_users.into_iter().map(UserStats::from).collect(),
-        pro_users: pro_users.into_iter().map(UserStats::from).collect(),
-    };
-    (StatusCode::OK, Json(userdata))
+    pad_auth_response(started).await;
+
+    (
+        StatusCode::OK,
+        Json(ResendCodeResponse {
+            is_code_sent: true,
+            retry_after_seconds: OTP_COOLDOWN_SECONDS,
+            resends_remaining: OTP_MAX_RESENDS,
+            error: "If a verification is in progress for this email, a new code is on its way"
+                .to_string(),
+            error_code: None,
+        }),
+    )
 }
 
-async fn instance_status(
-    headers: HeaderMap,
-    Json(payload): Json<InstanceStatusResquest>,
-) -> impl IntoResponse {
-    if is_empty_field(&payload.inst_id) {
-        warn!("Instance status check failed: Empty instance ID");
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(InstanceStatusResponse {
-                health: "unknown".to_string(),
-                running_from: "unknown".to_string(),
-                last_error_at: "unknown".to_string(),
-                message: "Instance ID cannot be empty".to_string(),
-            }),
-        );
-    }
+/// Privacy mode registration: known and new emails get the same response in the same time,
+/// the real outcome only goes to the logs
+async fn private_auth_register(
+    payload: &UserRegisterRequest,
+) -> (StatusCode, Json<UserRegisterResponse>) {
+    let started = std::time::Instant::now();
 
-    let api_key = match extract_apy_key(&headers) {
-        Some(api_key) => api_key,
-        None => {
-            warn!("Instance status check failed: Invalid or missing API key");
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(InstanceStatusResponse {
-                    health: "unknown".to_string(),
-                    running_from: "unknown".to_string(),
-                    last_error_at: "unknown".to_string(),
-                    message: "Invalid or missing API key".to_string(),
-                }),
-            );
-        }
+    let outcome = match is_user_exists(&payload.email).await {
+        Ok(true) => Ok(false),
+        Ok(false) => save_user(payload).await.map(|_| true),
+        Err(e) => Err(e),
     };
 
-    let user_email: String = match extract_email_from_api_key(api_key) {
-        Some(email) => email,
-        None => {
-            warn!("Instance status check failed: Unable to extract email from API key");
+    pad_auth_response(started).await;
+
+    match outcome {
+        Ok(true) => info!("User registered successfully with email: {}", payload.email),
+        Ok(false) => warn!(
+            "User already exists with email: {} (hidden by privacy mode)",
+            payload.email
+        ),
+        Err(e) => {
+            error!(
+                "User registration failed for email: {}, Error: {:?}",
+                payload.email, e
+            );
             return (
-                StatusCode::UNAUTHORIZED,
-                Json(InstanceStatusResponse {
-                    health: "unknown".to_string(),
-                    running_from: "unknown".to_string(),
-                    last_error_at: "unknown".to_string(),
-                    message: "Invalid API key format".to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(UserRegisterResponse {
+                    email: "".to_string(),
+                    is_created: false,
+                    error: "Internal server error, Sorry!".to_string(),
+                    error_code: None,
                 }),
             );
         }
-    };
+    }
 
-    // This is redundant, but why not? right
-    match is_user_exists(&user_email).await {
-        Ok(exists) => {
-            if !exists {
-                warn!(
-                    "Instance status check failed: User not found for email: {}",
-                    user_email
-                );
-                return (
-                    StatusCode::NOT_FOUND,
-                    Json(InstanceStatusResponse {
-                        health: "unknown".to_string(),
-                        running_from: "unknown".to_string(),
-                        last_error_at: "unknown".to_string(),
-                        message: "User not found".to_string(),
-                    }),
+    (
+        StatusCode::CREATED,
+        Json(UserRegisterResponse {
+            email: payload.email.clone(),
+            is_created: true,
+            error: "null".to_string(),
+            error_code: None,
+        }),
+    )
+}
+
+/// Privacy mode verify-email: always claims a code was sent, the mail goes out in the background
+/// so its latency doesn't give away that the email is registered
+async fn private_auth_verify_email(
+    payload: &VerifyEmailRequest,
+) -> (StatusCode, Json<VerifyEmailResponse>) {
+    let started = std::time::Instant::now();
+
+    let pending = match is_user_exists(&payload.email).await {
+        Ok(true) => match is_user_verified(&payload.email).await {
+            Ok(true) => {
+                info!(
+                    "User already verified for email: {} (hidden by privacy mode)",
+                    payload.email
                 );
+                Ok(false)
             }
+            other => other.map(|_| true),
+        },
+        Ok(false) => {
+            warn!(
+                "Email verification failed: User not found for email: {} (hidden by privacy mode)",
+                payload.email
+            );
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    };
+
+    match pending {
+        Ok(true) => {
+            let payload = payload.clone();
+            get_task_registry().spawn("verification-mail", |_| async move {
+                match verify_user(&payload).await {
+                    Ok(response) if response.is_code_sent => {}
+                    Ok(response) => warn!(
+                        "Email verification failed for email: {}: {} (hidden by privacy mode)",
+                        payload.email, response.error
+                    ),
+                    Err(e) => error!(
+                        "Email verification failed for email: {}, Error: {:?}",
+                        payload.email, e
+                    ),
+                }
+            });
         }
+        Ok(false) => {}
         Err(e) => {
             error!(
-                "Some error occurred while checking user existence for email: {}, Error: {:?}",
-                user_email, e
+                "Some error occurred while checking user for email: {}, Error: {:?}",
+                payload.email, e
             );
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(InstanceStatusResponse {
-                    health: "unknown".to_string(),
-                    running_from: "unknown".to_string(),
-                    last_error_at: "unknown".to_string(),
-                    message: "Internal server error, Sorry!".to_string(),
+                Json(VerifyEmailResponse {
+                    is_code_sent: false,
+                    error: "Internal server error, Sorry!".to_string(),
+                    error_code: None,
                 }),
             );
         }
     }
 
-    match get_instance_stats(&user_email).await {
-        Ok(stats) => {
-            info!(
-                "Instance status fetched successfully for user: {}",
-                user_email
-            );
-            (StatusCode::OK, Json(stats))
-        }
-        Err(e) => {
-            error!(
+    pad_auth_response(started).await;
+
+    (
+        StatusCode::OK,
+        Json(VerifyEmailResponse {
+            is_code_sent: true,
+            error: "If this email is registered and pending verification, a code is on its way"
+                .to_string(),
+            error_code: None,
+        }),
+    )
+}
+
+/// This endpoint handles verification code submission for email verification.
+/// 404 for an unknown email or no code, 410 for an expired or used code, 401 for a wrong one and
+/// 429 once too many wrong ones were tried
+async fn auth_verify_code(
+    ClientIp(client_ip): ClientIp,
+    Json(payload): Json<VerifyOtpRequest>,
+) -> impl IntoResponse {
+    info!(
+        "OTP verification attempt for email: {} from {}",
+        payload.email, client_ip
+    );
+    let refused = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(VerifyOtpResponse {
+                is_verified: false,
+                message,
+                api_key: None,
+                instance_id: None,
+                instance_state: None,
+                status_url: None,
+            }),
+        )
+    };
+
+    if is_empty_field(&payload.email) || is_empty_field(&payload.otp) {
+        warn!("OTP verification failed: Empty email or OTP");
+        return refused(
+            StatusCode::BAD_REQUEST,
+            "Email or OTP cannot be empty".to_string(),
+        );
+    }
+    match verify_otp_service(&payload).await {
+        Ok(response) => {
+            info!("OTP verified for email: {}", payload.email);
+            (StatusCode::OK, Json(response))
+        }
+        Err(BlazeError::Otp(failure)) => {
+            warn!(
+                "OTP verification failed for email: {}: {}",
+                payload.email, failure
+            );
+            // Privacy mode doesn't tell unknown emails from known ones without a code
+            let failure = match failure {
+                OtpFailure::UnknownEmail if is_auth_privacy_mode() => OtpFailure::NoCode,
+                failure => failure,
+            };
+            refused(failure.status(), failure.to_string())
+        }
+        Err(e) => {
+            error!(
+                "OTP verification failed for email: {}, Error: {:?}",
+                payload.email, e
+            );
+            refused(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// Public list of recent platform incidents, newest first
+async fn status_incidents() -> impl IntoResponse {
+    match list_incidents(INCIDENT_HISTORY_LIMIT) {
+        Ok(incidents) => (StatusCode::OK, Json(serde_json::json!(incidents))),
+        Err(e) => {
+            error!("Failed to list incidents, Error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "message": "Internal server error, Sorry!" })),
+            )
+        }
+    }
+}
+
+/// Open maintenance windows, the global one first
+async fn admin_list_maintenance(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin maintenance list failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(MaintenanceResponse {
+                windows: Vec::new(),
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match list_maintenance() {
+        Ok(windows) => (
+            StatusCode::OK,
+            Json(MaintenanceResponse {
+                message: format!("{} maintenance window(s) open", windows.len()),
+                windows,
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to list maintenance windows: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MaintenanceResponse {
+                    windows: Vec::new(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// Searches the proxy's audit log of authentications, by user and time range (last 24h by default)
+async fn admin_audit(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> impl IntoResponse {
+    let failed = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(AuditResponse {
+                events: Vec::new(),
+                message,
+            }),
+        )
+    };
+
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin audit query failed from {}: {}", client_ip, message);
+        return failed(status, message.to_string());
+    }
+
+    let parse = |time: Option<&str>| -> Result<Option<chrono::DateTime<chrono::Utc>>, ()> {
+        time.map(|t| {
+            chrono::DateTime::parse_from_rfc3339(t)
+                .map(|t| t.with_timezone(&chrono::Utc))
+                .map_err(|_| ())
+        })
+        .transpose()
+    };
+    let (Ok(from), Ok(to)) = (parse(query.from.as_deref()), parse(query.to.as_deref())) else {
+        return failed(
+            StatusCode::BAD_REQUEST,
+            "`from` and `to` must be RFC 3339".to_string(),
+        );
+    };
+    let to = to.unwrap_or_else(chrono::Utc::now);
+    let from = from.unwrap_or(to - chrono::Duration::hours(24));
+    if from > to {
+        return failed(
+            StatusCode::BAD_REQUEST,
+            "`from` must be before `to`".to_string(),
+        );
+    }
+
+    let user = query
+        .user
+        .map(|u| u.trim().to_string())
+        .filter(|u| !u.is_empty());
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_QUERY_LIMIT)
+        .clamp(1, MAX_QUERY_LIMIT);
+
+    // Up to the retention's worth of files, read off the async threads
+    let events = tokio::task::spawn_blocking(move || {
+        query_audit_log(&get_audit_path(), user.as_deref(), from, to, limit)
+    })
+    .await;
+    match events {
+        Ok(Ok(events)) => (
+            StatusCode::OK,
+            Json(AuditResponse {
+                message: format!("{} audit event(s)", events.len()),
+                events,
+            }),
+        ),
+        Ok(Err(e)) => {
+            error!("Failed to read the audit log: {:?}", e);
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+        Err(e) => {
+            error!("Audit log query panicked: {:?}", e);
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// Puts one instance, or all of them without an `instance_id`, in maintenance
+/// The proxy answers new requests to it with a 503 within a few seconds, until it's lifted
+async fn admin_start_maintenance(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<MaintenanceRequest>,
+) -> impl IntoResponse {
+    let failed = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(MaintenanceResponse {
+                windows: Vec::new(),
+                message,
+            }),
+        )
+    };
+
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin maintenance start failed from {}: {}",
+            client_ip, message
+        );
+        return failed(status, message.to_string());
+    }
+
+    let instance_id = payload
+        .instance_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|id| !id.is_empty());
+    if payload
+        .until
+        .as_deref()
+        .is_some_and(|t| chrono::DateTime::parse_from_rfc3339(t).is_err())
+    {
+        return failed(
+            StatusCode::BAD_REQUEST,
+            "`until` must be RFC 3339".to_string(),
+        );
+    }
+
+    match start_maintenance(
+        instance_id,
+        payload.message.as_deref(),
+        payload.until.as_deref(),
+    ) {
+        Ok(window) => {
+            info!(
+                "Maintenance started for {}",
+                instance_id.unwrap_or("every instance")
+            );
+            (
+                StatusCode::OK,
+                Json(MaintenanceResponse {
+                    windows: vec![window],
+                    message: "Maintenance started".to_string(),
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Failed to start maintenance: {:?}", e);
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// Lifts the maintenance window of `?instance_id=`, the global one without it
+async fn admin_end_maintenance(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<MaintenanceQuery>,
+) -> impl IntoResponse {
+    let respond = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(MaintenanceResponse {
+                windows: Vec::new(),
+                message,
+            }),
+        )
+    };
+
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin maintenance end failed from {}: {}",
+            client_ip, message
+        );
+        return respond(status, message.to_string());
+    }
+
+    let instance_id = query
+        .instance_id
+        .as_deref()
+        .map(str::trim)
+        .filter(|id| !id.is_empty());
+    match end_maintenance(instance_id) {
+        Ok(true) => {
+            info!(
+                "Maintenance ended for {}",
+                instance_id.unwrap_or("every instance")
+            );
+            respond(StatusCode::OK, "Maintenance ended".to_string())
+        }
+        Ok(false) => respond(
+            StatusCode::NOT_FOUND,
+            "No maintenance window open for that".to_string(),
+        ),
+        Err(e) => {
+            error!("Failed to end maintenance: {:?}", e);
+            respond(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint lets an admin enter an incident (e.g. a storage failover we can't detect)
+async fn admin_create_incident(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<IncidentCreateRequest>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin incident create failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(IncidentResponse {
+                incident: None,
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    if is_empty_field(&payload.title) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(IncidentResponse {
+                incident: None,
+                message: "Title cannot be empty".to_string(),
+            }),
+        );
+    }
+
+    let timestamps = [&payload.started_at, &payload.resolved_at];
+    if timestamps
+        .iter()
+        .filter_map(|t| t.as_deref())
+        .any(|t| chrono::DateTime::parse_from_rfc3339(t).is_err())
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(IncidentResponse {
+                incident: None,
+                message: "Timestamps must be RFC 3339".to_string(),
+            }),
+        );
+    }
+
+    match record_incident(
+        payload.kind,
+        payload.title.trim(),
+        payload.description.trim(),
+        "admin",
+        payload.started_at,
+        payload.resolved_at,
+    ) {
+        Ok(incident) => (
+            StatusCode::CREATED,
+            Json(IncidentResponse {
+                incident: Some(incident),
+                message: "Incident recorded".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to record incident, Error: {:?}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(IncidentResponse {
+                    incident: None,
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint lets an admin list the automatic restarts of an instance
+async fn admin_instance_restarts(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin instance restarts failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(RestartEventsResponse {
+                instance_id: id,
+                events: Vec::new(),
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match get_restart_events(&id) {
+        Ok(events) => (
+            StatusCode::OK,
+            Json(RestartEventsResponse {
+                message: format!("{} automatic restart(s)", events.len()),
+                instance_id: id,
+                events,
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to list restarts of {}, Error: {:?}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(RestartEventsResponse {
+                    instance_id: id,
+                    events: Vec::new(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint lets an admin mark an incident as resolved
+async fn admin_resolve_incident(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin incident resolve failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(IncidentResponse {
+                incident: None,
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match resolve_incident(&id) {
+        Ok(Some(incident)) => (
+            StatusCode::OK,
+            Json(IncidentResponse {
+                incident: Some(incident),
+                message: "Incident resolved".to_string(),
+            }),
+        ),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(IncidentResponse {
+                incident: None,
+                message: "Incident not found".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to resolve incident {}, Error: {:?}", id, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(IncidentResponse {
+                    incident: None,
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint suggests a plan matching the authenticated user's metered usage.
+async fn account_recommendation(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Plan recommendation failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(PlanRecommendationResponse {
+                    recommendation: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    let recommendation = match get_user(&user_email).await {
+        Ok(Some(user)) => get_plan_recommendation(&user).await,
+        Ok(None) => Err(anyhow::anyhow!("User not found")),
+        Err(e) => Err(e.into()),
+    };
+
+    match recommendation {
+        Ok(recommendation) => (
+            StatusCode::OK,
+            Json(PlanRecommendationResponse {
+                recommendation: Some(recommendation),
+                message: "Recommendation based on your metered usage".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Plan recommendation failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(PlanRecommendationResponse {
+                    recommendation: None,
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint lets an admin run the Docker pre-flight checks on every host (or one)
+async fn admin_preflight(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<PreflightRequest>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin preflight failed from {}: {}", client_ip, message);
+        return (
+            status,
+            Json(PreflightResponse {
+                ok: false,
+                hosts: vec![],
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match run_preflight(payload.host.as_deref()).await {
+        Ok(hosts) => {
+            let ok = hosts.iter().all(|h| h.ok);
+            let failed: Vec<&str> = hosts
+                .iter()
+                .filter(|h| !h.ok)
+                .map(|h| h.host.as_str())
+                .collect();
+            let message = if ok {
+                format!("All {} host(s) passed", hosts.len())
+            } else {
+                format!("Pre-flight failed on: {}", failed.join(", "))
+            };
+            info!("Docker pre-flight: {}", message);
+            (
+                StatusCode::OK,
+                Json(PreflightResponse { ok, hosts, message }),
+            )
+        }
+        Err(e) => (
+            StatusCode::NOT_FOUND,
+            Json(PreflightResponse {
+                ok: false,
+                hosts: vec![],
+                message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+            }),
+        ),
+    }
+}
+
+async fn admin_billing_reconcile(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<ReconcileQuery>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin billing reconciliation failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(ReconcileResponse {
+                report: None,
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match reconcile_billing(query.heal).await {
+        Ok(report) => {
+            let message = if report.drifts.is_empty() {
+                format!(
+                    "No drift across {} user(s) and {} subscription(s)",
+                    report.users_checked, report.subscriptions_checked
+                )
+            } else {
+                format!(
+                    "Found {} drift(s), healed {}",
+                    report.drifts.len(),
+                    report.healed
+                )
+            };
+            info!("Billing reconciliation: {}", message);
+            (
+                StatusCode::OK,
+                Json(ReconcileResponse {
+                    report: Some(report),
+                    message,
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Billing reconciliation failed: {:?}", e);
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(ReconcileResponse {
+                    report: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn admin_mail_quota(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin mail quota view failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(MailQuotaResponse {
+                limits: vec![],
+                queued: 0,
+                metrics: get_mail_metrics(),
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    let status = async {
+        let limits = get_mail_quota()?;
+        let queue = get_mail_queue();
+        queue.reload_async().await?;
+        anyhow::Ok((limits, queue.len()?))
+    }
+    .await;
+
+    match status {
+        Ok((limits, queued)) => (
+            StatusCode::OK,
+            Json(MailQuotaResponse {
+                limits,
+                queued,
+                metrics: get_mail_metrics(),
+                message: "Mail quota retrieved".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!("Failed to read mail quota: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(MailQuotaResponse {
+                    limits: vec![],
+                    queued: 0,
+                    metrics: get_mail_metrics(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn admin_migrate_store(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<StoreMigrationRequest>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin store migration failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(StoreMigrationResponse {
+                success: false,
+                message: message.to_string(),
+                report: None,
+            }),
+        );
+    }
+
+    let Some(target) = StoreFormat::parse(&payload.format) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(StoreMigrationResponse {
+                success: false,
+                message: "format must be one of json, ndjson, sqlite, encrypted".to_string(),
+                report: None,
+            }),
+        );
+    };
+
+    match migrate_user_store(target).await {
+        Ok(report) => (
+            StatusCode::OK,
+            Json(StoreMigrationResponse {
+                success: true,
+                message: format!("User store migrated to {}", payload.format.trim()),
+                report: Some(report),
+            }),
+        ),
+        Err(e) => {
+            error!("User store migration failed: {}", e);
+            (
+                StatusCode::CONFLICT,
+                Json(StoreMigrationResponse {
+                    success: false,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                    report: None,
+                }),
+            )
+        }
+    }
+}
+
+async fn admin_storage_stats(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin storage stats failed from {}: {}", client_ip, message);
+        return (
+            status,
+            Json(StorageStatsResponse {
+                stores: Vec::new(),
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match storage_stats().await {
+        Ok(stores) => (
+            StatusCode::OK,
+            Json(StorageStatsResponse {
+                message: format!("{} store(s)", stores.len()),
+                stores,
+            }),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(StorageStatsResponse {
+                stores: Vec::new(),
+                message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+            }),
+        ),
+    }
+}
+
+async fn admin_compact_stores(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin storage compaction failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(StorageCompactResponse {
+                reports: Vec::new(),
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match compact_stores().await {
+        Ok(reports) => {
+            let reclaimed: i64 = reports
+                .iter()
+                .map(|r| r.size_before_bytes as i64 - r.size_after_bytes as i64)
+                .sum();
+            (
+                StatusCode::OK,
+                Json(StorageCompactResponse {
+                    message: format!(
+                        "Compacted {} store(s), {} bytes reclaimed",
+                        reports.len(),
+                        reclaimed
+                    ),
+                    reports,
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Storage compaction failed: {}", e);
+            (
+                StatusCode::CONFLICT,
+                Json(StorageCompactResponse {
+                    reports: Vec::new(),
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// Downloads one of the service's stores as NDJSON (for `admin_import_store`) or CSV
+async fn admin_export_store(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Path(store): Path<String>,
+    Query(query): Query<StoreExportQuery>,
+) -> axum::response::Response {
+    let failed = |status: StatusCode, message: String| {
+        (status, Json(StoreExportResponse { message })).into_response()
+    };
+
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin store export failed from {}: {}", client_ip, message);
+        return failed(status, message.to_string());
+    }
+
+    let requested = query.format.as_deref().unwrap_or("ndjson");
+    let Some(format) = ExportFormat::parse(requested) else {
+        return failed(
+            StatusCode::BAD_REQUEST,
+            "format must be one of ndjson, csv".to_string(),
+        );
+    };
+
+    match export_store(&store, format).await {
+        Ok((content, entries)) => {
+            let (content_type, extension) = match format {
+                ExportFormat::Ndjson => ("application/x-ndjson", "ndjson"),
+                ExportFormat::Csv => ("text/csv; charset=utf-8", "csv"),
+            };
+            info!(
+                "Exported {} entries of store {} as {}",
+                entries, store, extension
+            );
+            (
+                [
+                    (header::CONTENT_TYPE, content_type.to_string()),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        format!(
+                            "attachment; filename=\"{}-{}.{}\"",
+                            store,
+                            chrono::Utc::now().format("%Y%m%dT%H%M%SZ"),
+                            extension
+                        ),
+                    ),
+                ],
+                content,
+            )
+                .into_response()
+        }
+        Err(BlazeError::Validation(message)) => failed(StatusCode::NOT_FOUND, message),
+        Err(e) => {
+            error!("Export of store {} failed: {}", store, e);
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// Reads an NDJSON export (see `admin_export_store`) into one of the service's stores
+async fn admin_import_store(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Path(store): Path<String>,
+    Query(query): Query<StoreImportQuery>,
+    body: Bytes,
+) -> impl IntoResponse {
+    let failed = |message: String| {
+        Json(StoreImportResponse {
+            success: false,
+            message,
+            report: None,
+        })
+    };
+
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin store import failed from {}: {}", client_ip, message);
+        return (status, failed(message.to_string()));
+    }
+
+    match import_store(&store, body.to_vec(), query.overwrite).await {
+        Ok(report) => (
+            StatusCode::OK,
+            Json(StoreImportResponse {
+                success: true,
+                message: format!(
+                    "Imported {} entries into {}, {} kept as they were",
+                    report.imported, store, report.skipped
+                ),
+                report: Some(report),
+            }),
+        ),
+        Err(BlazeError::Validation(message)) => (StatusCode::BAD_REQUEST, failed(message)),
+        Err(e) => {
+            error!("Import into store {} failed: {}", store, e);
+            (
+                StatusCode::CONFLICT,
+                failed("Something went wrong, Error: ".to_string() + &e.to_string()),
+            )
+        }
+    }
+}
+
+/// Records the instance ids the previous instance secret derived, once the new one is in place
+async fn admin_rotate_secrets(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin secret rotation failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(SecretRotationResponse {
+                success: false,
+                message: message.to_string(),
+                report: None,
+            }),
+        );
+    }
+
+    match rotate_instance_secret().await {
+        Ok(report) => (
+            StatusCode::OK,
+            Json(SecretRotationResponse {
+                success: true,
+                message: format!(
+                    "{} instance id(s) aliased, {} API key hash(es) waiting to be rehashed",
+                    report.aliases_written, report.api_key_hashes_legacy
+                ),
+                report: Some(report),
+            }),
+        ),
+        Err(e) => {
+            error!("Secret rotation failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SecretRotationResponse {
+                    success: false,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                    report: None,
+                }),
+            )
+        }
+    }
+}
+
+async fn admin_list_snapshots(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin snapshot list failed from {}: {}", client_ip, message);
+        return (
+            status,
+            Json(SnapshotListResponse {
+                snapshots: Vec::new(),
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match list_snapshots() {
+        Ok(snapshots) => (
+            StatusCode::OK,
+            Json(SnapshotListResponse {
+                message: format!("{} snapshot(s)", snapshots.len()),
+                snapshots,
+            }),
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(SnapshotListResponse {
+                snapshots: Vec::new(),
+                message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+            }),
+        ),
+    }
+}
+
+async fn admin_create_snapshot(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin snapshot failed from {}: {}", client_ip, message);
+        return (
+            status,
+            Json(SnapshotResponse {
+                snapshot: None,
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    match create_snapshot("admin").await {
+        Ok(snapshot) => (
+            StatusCode::CREATED,
+            Json(SnapshotResponse {
+                message: format!("Snapshot {} created", snapshot.id),
+                snapshot: Some(snapshot),
+            }),
+        ),
+        Err(e) => {
+            error!("Service snapshot failed: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SnapshotResponse {
+                    snapshot: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn admin_restore_snapshot(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<SnapshotRestoreRequest>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!(
+            "Admin snapshot restore failed from {}: {}",
+            client_ip, message
+        );
+        return (
+            status,
+            Json(SnapshotResponse {
+                snapshot: None,
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    let bad_request = |message: &str| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(SnapshotResponse {
+                snapshot: None,
+                message: message.to_string(),
+            }),
+        )
+    };
+    let at = match payload
+        .at
+        .as_deref()
+        .map(chrono::DateTime::parse_from_rfc3339)
+    {
+        Some(Ok(at)) => Some(at.with_timezone(&chrono::Utc)),
+        Some(Err(_)) => return bad_request("at must be RFC 3339"),
+        None => None,
+    };
+    if payload.snapshot_id.is_none() && at.is_none() {
+        return bad_request("snapshot_id or at is required");
+    }
+
+    let snapshot = match find_snapshot(payload.snapshot_id.as_deref(), at) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(SnapshotResponse {
+                    snapshot: None,
+                    message: "No snapshot matches".to_string(),
+                }),
+            );
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(SnapshotResponse {
+                    snapshot: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            );
+        }
+    };
+
+    match stage_restore(snapshot.clone()).await {
+        Ok(_) => {
+            warn!(
+                "Admin staged a restore of snapshot {} from {}",
+                snapshot.id, client_ip
+            );
+            (
+                StatusCode::ACCEPTED,
+                Json(SnapshotResponse {
+                    message: format!(
+                        "Restore of {} staged, restart the service to apply it; storage is read-only until then",
+                        snapshot.id
+                    ),
+                    snapshot: Some(snapshot),
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Staging restore of snapshot {} failed: {}", snapshot.id, e);
+            (
+                StatusCode::CONFLICT,
+                Json(SnapshotResponse {
+                    snapshot: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint lets an admin create a promo code for checkout
+async fn admin_create_coupon(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<CouponCreateRequest>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin coupon create failed from {}: {}", client_ip, message);
+        return (
+            status,
+            Json(CouponResponse {
+                coupon: None,
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    if let Some(expires_at) = &payload.expires_at
+        && chrono::DateTime::parse_from_rfc3339(expires_at).is_err()
+    {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(CouponResponse {
+                coupon: None,
+                message: "expires_at must be RFC 3339".to_string(),
+            }),
+        );
+    }
+
+    match create_coupon(
+        &payload.code,
+        payload.kind,
+        payload.value,
+        payload.max_redemptions,
+        payload.expires_at,
+    ) {
+        Ok(coupon) => {
+            info!("Coupon {} created", coupon.code);
+            (
+                StatusCode::CREATED,
+                Json(CouponResponse {
+                    coupon: Some(coupon),
+                    message: "Coupon created".to_string(),
+                }),
+            )
+        }
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(CouponResponse {
+                coupon: None,
+                message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+            }),
+        ),
+    }
+}
+
+/// This endpoint lets an admin move a user to another plan (e.g. after a manual payment)
+async fn admin_change_plan(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<PlanChangeRequest>,
+) -> impl IntoResponse {
+    if let Err((status, message)) = authenticate_admin(&headers) {
+        warn!("Admin plan change failed from {}: {}", client_ip, message);
+        return (
+            status,
+            Json(PlanChangeResponse {
+                is_changed: false,
+                previous_plan: None,
+                message: message.to_string(),
+            }),
+        );
+    }
+
+    let Some(plan) = Plans::by_name(&payload.plan) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(PlanChangeResponse {
+                is_changed: false,
+                previous_plan: None,
+                message: "Plan must be one of: free, starter, pro".to_string(),
+            }),
+        );
+    };
+
+    match change_plan(&payload.email, plan).await {
+        Ok(previous_plan) => (
+            StatusCode::OK,
+            Json(PlanChangeResponse {
+                is_changed: true,
+                previous_plan: Some(previous_plan.name),
+                message: "Plan changed".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Plan change failed for email: {}, Error: {:?}",
+                payload.email, e
+            );
+            (
+                error_status(&e),
+                Json(PlanChangeResponse {
+                    is_changed: false,
+                    previous_plan: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint returns the authenticated user's billing events (plan changes, payments, refunds).
+async fn billing_history(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Billing history failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(BillingHistoryResponse {
+                    events: Vec::new(),
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match get_billing_history(&user_email) {
+        Ok(events) => (
+            StatusCode::OK,
+            Json(BillingHistoryResponse {
+                events,
+                message: "Billing history retrieved".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to get billing history for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(BillingHistoryResponse {
+                    events: Vec::new(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint returns the authenticated user's monthly invoices.
+async fn billing_invoices(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Invoice list failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(InvoiceListResponse {
+                    invoices: Vec::new(),
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match get_invoices(&user_email) {
+        Ok(invoices) => (
+            StatusCode::OK,
+            Json(InvoiceListResponse {
+                invoices,
+                message: "Invoices retrieved".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to get invoices for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(InvoiceListResponse {
+                    invoices: Vec::new(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint shows what switching to another plan would cost right now, prorated over the
+/// rest of the billing cycle. Nothing is changed, the frontend shows it before checkout.
+async fn billing_preview_change(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<PlanChangePreviewQuery>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Plan change preview failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(PlanChangePreviewResponse {
+                    preview: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    let Some(plan) = Plans::by_name(&query.plan) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(PlanChangePreviewResponse {
+                preview: None,
+                message: format!("Unknown plan '{}'", query.plan),
+            }),
+        );
+    };
+
+    match preview_plan_change(&user_email, &plan).await {
+        Ok(preview) if preview.current_plan == preview.target_plan => (
+            StatusCode::CONFLICT,
+            Json(PlanChangePreviewResponse {
+                preview: None,
+                message: format!("You are already on the {} plan", plan.name),
+            }),
+        ),
+        Ok(preview) => (
+            StatusCode::OK,
+            Json(PlanChangePreviewResponse {
+                preview: Some(preview),
+                message: "Plan change preview".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to preview plan change for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(PlanChangePreviewResponse {
+                    preview: None,
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint cancels the authenticated user's subscription at the end of the paid period.
+async fn billing_cancel(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let cancel_failed = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(SubscriptionCancelResponse {
+                is_cancelled: false,
+                access_until: None,
+                message,
+            }),
+        )
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Cancellation failed from {}: {}", client_ip, message);
+            return cancel_failed(status, message.to_string());
+        }
+    };
+
+    let user = match get_user(&user_email).await {
+        Ok(Some(user)) if user.is_verified => user,
+        Ok(_) => {
+            return cancel_failed(StatusCode::NOT_FOUND, "User not found".to_string());
+        }
+        Err(e) => {
+            error!("Failed to get user {}: {:?}", user_email, e);
+            return cancel_failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            );
+        }
+    };
+    if let Err(message) = check_can_cancel(&user) {
+        return cancel_failed(StatusCode::CONFLICT, message);
+    }
+    if let Ok(true) = is_managed_member(&user) {
+        return cancel_failed(
+            StatusCode::FORBIDDEN,
+            "Your plan is managed by your organization, ask its owner".to_string(),
+        );
+    }
+
+    match cancel_subscription(&user_email).await {
+        Ok(access_until) => (
+            StatusCode::OK,
+            Json(SubscriptionCancelResponse {
+                is_cancelled: true,
+                message: format!(
+                    "Subscription cancelled, you keep your plan until {}",
+                    access_until
+                ),
+                access_until: Some(access_until),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Cancellation failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            cancel_failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint starts a free trial of a paid plan for the authenticated user.
+async fn billing_trial(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<TrialRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Trial start failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(TrialResponse {
+                    is_started: false,
+                    trial_expires_at: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    let plan = match Plans::by_name(&payload.plan) {
+        Some(plan) if plan.trial_days > 0 => plan,
+        _ => {
+            warn!("Trial start failed: No trial for plan '{}'", payload.plan);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(TrialResponse {
+                    is_started: false,
+                    trial_expires_at: None,
+                    message: "Free trials are only available for: pro".to_string(),
+                }),
+            );
+        }
+    };
+
+    match start_trial(&user_email, plan).await {
+        Ok(expires_at) => (
+            StatusCode::OK,
+            Json(TrialResponse {
+                is_started: true,
+                trial_expires_at: Some(expires_at),
+                message: "Trial started".to_string(),
+            }),
+        ),
+        Err(e) => {
+            warn!(
+                "Trial start failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::CONFLICT,
+                Json(TrialResponse {
+                    is_started: false,
+                    trial_expires_at: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint returns the authenticated user's metered usage, per instance and hour.
+async fn billing_usage(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Usage lookup failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(UsageResponse {
+                    total_requests: 0,
+                    total_vectors_written: 0,
+                    hourly: Vec::new(),
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match get_user_usage(&user_email) {
+        Ok(hourly) => (
+            StatusCode::OK,
+            Json(UsageResponse {
+                total_requests: hourly.iter().map(|r| r.requests).sum(),
+                total_vectors_written: hourly.iter().map(|r| r.vectors_written).sum(),
+                hourly,
+                message: "Usage retrieved".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to get usage for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(UsageResponse {
+                    total_requests: 0,
+                    total_vectors_written: 0,
+                    hourly: Vec::new(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// Stripe webhook, the body must stay raw for the signature check
+async fn stripe_webhook(headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    let Some(signature) = headers
+        .get("Stripe-Signature")
+        .and_then(|v| v.to_str().ok())
+    else {
+        warn!("Stripe webhook without signature");
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match handle_stripe_webhook(&body, signature).await {
+        Ok(_) => StatusCode::OK,
+        Err(e) => {
+            // Non-2xx makes Stripe retry the delivery later
+            error!("Stripe webhook failed, Error: {:?}", e);
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+async fn billing_plans() -> impl IntoResponse {
+    let plans = Plans::all();
+    (StatusCode::OK, Json(plans))
+}
+
+/// This endpoint starts a Stripe Checkout for a paid plan and returns the payment page URL.
+async fn billing_checkout(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<CheckoutRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Checkout failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(CheckoutResponse {
+                    checkout_url: None,
+                    session_id: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    let plan = match Plans::by_name(&payload.plan) {
+        Some(plan) if plan.price_per_month > 0 => plan,
+        _ => {
+            warn!("Checkout failed: Invalid plan '{}'", payload.plan);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(CheckoutResponse {
+                    checkout_url: None,
+                    session_id: None,
+                    message: "Plan must be one of: starter, pro".to_string(),
+                }),
+            );
+        }
+    };
+
+    if plan.price_for(payload.interval).is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(CheckoutResponse {
+                checkout_url: None,
+                session_id: None,
+                message: format!(
+                    "The {} plan isn't available {}",
+                    plan.name,
+                    payload.interval.as_str()
+                ),
+            }),
+        );
+    }
+
+    // Members don't pay for themselves, the organization owner does
+    if let Ok(Some(user)) = get_user(&user_email).await
+        && is_managed_member(&user).unwrap_or(false)
+    {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(CheckoutResponse {
+                checkout_url: None,
+                session_id: None,
+                message: "Your plan is managed by your organization".to_string(),
+            }),
+        );
+    }
+
+    let on_trial = is_user_on_trial(&user_email).await.unwrap_or(false);
+    match get_user_plan(&user_email).await {
+        // Buying the plan you're trialing keeps it after the trial
+        Ok(current) if current.name == plan.name && !on_trial => {
+            return (
+                StatusCode::CONFLICT,
+                Json(CheckoutResponse {
+                    checkout_url: None,
+                    session_id: None,
+                    message: format!("You are already on the {} plan", plan.name),
+                }),
+            );
+        }
+        Ok(_) => {}
+        Err(e) => {
+            error!(
+                "Checkout plan lookup failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(CheckoutResponse {
+                    checkout_url: None,
+                    session_id: None,
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            );
+        }
+    }
+
+    if let Some(code) = &payload.promo_code
+        && let Err(e) = find_usable_coupon(code)
+    {
+        warn!("Checkout failed for email: {}: {}", user_email, e);
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(CheckoutResponse {
+                checkout_url: None,
+                session_id: None,
+                message: e.to_string(),
+            }),
+        );
+    }
+
+    let tax = match normalize_tax_details(payload.country.as_deref(), payload.vat_id.as_deref()) {
+        Ok(tax) => tax,
+        Err(e) => {
+            warn!("Checkout failed for email: {}: {}", user_email, e);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(CheckoutResponse {
+                    checkout_url: None,
+                    session_id: None,
+                    message: e.to_string(),
+                }),
+            );
+        }
+    };
+
+    match create_checkout_session(
+        &user_email,
+        &plan,
+        payload.interval,
+        payload.promo_code.as_deref(),
+        tax,
+    )
+    .await
+    {
+        Ok((checkout_url, session_id)) => {
+            info!(
+                "Checkout session {} created for user: {} ({} plan)",
+                session_id, user_email, plan.name
+            );
+            (
+                StatusCode::OK,
+                Json(CheckoutResponse {
+                    checkout_url: Some(checkout_url),
+                    session_id: Some(session_id),
+                    message: "Redirect to checkout_url to complete the payment".to_string(),
+                }),
+            )
+        }
+        Err(e) => {
+            error!("Checkout failed for email: {}, Error: {:?}", user_email, e);
+            (
+                StatusCode::BAD_GATEWAY,
+                Json(CheckoutResponse {
+                    checkout_url: None,
+                    session_id: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn get_user_stats() -> impl IntoResponse {
+    let unverified_user = get_unverified_users().await.unwrap_or_else(|e| {
+        error!("Failed to fetch unverified users: {:?}", e);
+        Vec::new()
+    });
+    let free_users = get_all_free_users().await.unwrap_or_else(|e| {
+        error!("Failed to fetch free users: {:?}", e);
+        Vec::new()
+    });
+    let starter_users = get_all_starter_users().await.unwrap_or_else(|e| {
+        error!("Failed to fetch starter users: {:?}", e);
+        Vec::new()
+    });
+    let pro_users = get_all_pro_users().await.unwrap_or_else(|e| {
+        error!("Failed to fetch pro users: {:?}", e);
+        Vec::new()
+    });
+
+    let userdata = UserData {
+        unverified_users: unverified_user.into_iter().map(UserStats::from).collect(),
+        free_users: free_users.into_iter().map(UserStats::from).collect(),
+        stater_users: starter_users.into_iter().map(UserStats::from).collect(),
+        pro_users: pro_users.into_iter().map(UserStats::from).collect(),
+    };
+    (StatusCode::OK, Json(userdata))
+}
+
+async fn instance_status(
+    headers: HeaderMap,
+    Json(payload): Json<InstanceStatusResquest>,
+) -> impl IntoResponse {
+    if is_empty_field(&payload.inst_id) {
+        warn!("Instance status check failed: Empty instance ID");
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(InstanceStatusResponse {
+                health: "unknown".to_string(),
+                running_from: "unknown".to_string(),
+                last_error_at: "unknown".to_string(),
+                message: "Instance ID cannot be empty".to_string(),
+            }),
+        );
+    }
+
+    let api_key = match extract_apy_key(&headers) {
+        Some(api_key) => api_key,
+        None => {
+            warn!("Instance status check failed: Invalid or missing API key");
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(InstanceStatusResponse {
+                    health: "unknown".to_string(),
+                    running_from: "unknown".to_string(),
+                    last_error_at: "unknown".to_string(),
+                    message: "Invalid or missing API key".to_string(),
+                }),
+            );
+        }
+    };
+
+    let user_email: String = match find_api_key_owner(&get_key_index_store(), api_key)
+        .ok()
+        .flatten()
+    {
+        Some(email) => email,
+        None => {
+            warn!("Instance status check failed: Unable to find the owner of the API key");
+            return (
+                StatusCode::UNAUTHORIZED,
+                Json(InstanceStatusResponse {
+                    health: "unknown".to_string(),
+                    running_from: "unknown".to_string(),
+                    last_error_at: "unknown".to_string(),
+                    message: "Invalid API key format".to_string(),
+                }),
+            );
+        }
+    };
+
+    // This is redundant, but why not? right
+    match is_user_exists(&user_email).await {
+        Ok(exists) => {
+            if !exists {
+                warn!(
+                    "Instance status check failed: User not found for email: {}",
+                    user_email
+                );
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(InstanceStatusResponse {
+                        health: "unknown".to_string(),
+                        running_from: "unknown".to_string(),
+                        last_error_at: "unknown".to_string(),
+                        message: "User not found".to_string(),
+                    }),
+                );
+            }
+        }
+        Err(e) => {
+            error!(
+                "Some error occurred while checking user existence for email: {}, Error: {:?}",
+                user_email, e
+            );
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(InstanceStatusResponse {
+                    health: "unknown".to_string(),
+                    running_from: "unknown".to_string(),
+                    last_error_at: "unknown".to_string(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            );
+        }
+    }
+
+    match get_instance_stats(&user_email).await {
+        Ok(stats) => {
+            info!(
+                "Instance status fetched successfully for user: {}",
+                user_email
+            );
+            (StatusCode::OK, Json(stats))
+        }
+        Err(e) => {
+            error!(
                 "Failed to get instance stats for email: {}, Error: {:?}",
                 user_email, e
             );
             (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                Json(InstanceStatusResponse {
-                    health: "unknown".to_string(),
-                    running_from: "unknown".to_string(),
-                    last_error_at: "unknown".to_string(),
+                Json(InstanceStatusResponse {
+                    health: "unknown".to_string(),
+                    running_from: "unknown".to_string(),
+                    last_error_at: "unknown".to_string(),
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint issues a short-lived signed token for read-only requests to the user's instance.
+async fn instance_token(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!(
+                "Instance token request failed from {}: {}",
+                client_ip, message
+            );
+            return (
+                status,
+                Json(InstanceTokenResponse {
+                    token: None,
+                    expires_at: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match create_instance_token(&user_email).await {
+        Ok((token, claims)) => {
+            info!("Instance token issued for user: {}", user_email);
+            let expires_at =
+                chrono::DateTime::from_timestamp(claims.expires_at, 0).map(|t| t.to_rfc3339());
+            (
+                StatusCode::OK,
+                Json(InstanceTokenResponse {
+                    token: Some(token),
+                    expires_at,
+                    message: "Instance token issued, valid for read-only requests".to_string(),
+                }),
+            )
+        }
+        Err(e) => {
+            error!(
+                "Instance token issue failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(InstanceTokenResponse {
+                    token: None,
+                    expires_at: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// This endpoint clones the authenticated user's instance (data included) into a new one,
+/// e.g. a staging copy of production. Paid plans only, within the plan's instance limit.
+async fn instance_clone(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance clone failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(InstanceCloneResponse {
+                    instance_id: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match clone_instance(&user_email).await {
+        Ok(instance_id) => {
+            info!(
+                "Instance cloned for user: {} -> {}",
+                user_email, instance_id
+            );
+            (
+                StatusCode::CREATED,
+                Json(InstanceCloneResponse {
+                    instance_id: Some(instance_id),
+                    message: "Instance cloned".to_string(),
+                }),
+            )
+        }
+        // Not on a paid plan or out of instances
+        Err(BlazeError::Validation(message)) => {
+            warn!("Instance clone refused for {}: {}", user_email, message);
+            (
+                StatusCode::FORBIDDEN,
+                Json(InstanceCloneResponse {
+                    instance_id: None,
+                    message,
+                }),
+            )
+        }
+        Err(BlazeError::Auth(message)) => (
+            StatusCode::NOT_FOUND,
+            Json(InstanceCloneResponse {
+                instance_id: None,
+                message,
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to clone instance for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(InstanceCloneResponse {
+                    instance_id: None,
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn instance_start(client_ip: ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    instance_lifecycle(client_ip, headers, InstanceAction::Start).await
+}
+
+async fn instance_stop(client_ip: ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    instance_lifecycle(client_ip, headers, InstanceAction::Stop).await
+}
+
+async fn instance_restart(client_ip: ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    instance_lifecycle(client_ip, headers, InstanceAction::Restart).await
+}
+
+async fn instance_lifecycle(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    action: InstanceAction,
+) -> (StatusCode, Json<InstanceLifecycleResponse>) {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!(
+                "Instance {} failed from {}: {}",
+                action.as_str(),
+                client_ip,
+                message
+            );
+            return (
+                status,
+                Json(InstanceLifecycleResponse {
+                    is_done: false,
+                    state: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match control_instance(&user_email, action).await {
+        Ok(state) => {
+            info!(
+                "Instance {} done for user: {} ({})",
+                action.as_str(),
+                user_email,
+                state
+            );
+            (
+                StatusCode::OK,
+                Json(InstanceLifecycleResponse {
+                    is_done: true,
+                    state: Some(state.to_string()),
+                    message: format!("Instance {} done", action.as_str()),
+                }),
+            )
+        }
+        // No instance, or suspended for an unpaid invoice
+        Err(BlazeError::Validation(message)) => {
+            warn!(
+                "Instance {} refused for {}: {}",
+                action.as_str(),
+                user_email,
+                message
+            );
+            (
+                StatusCode::CONFLICT,
+                Json(InstanceLifecycleResponse {
+                    is_done: false,
+                    state: None,
+                    message,
+                }),
+            )
+        }
+        Err(BlazeError::Auth(message)) => (
+            StatusCode::NOT_FOUND,
+            Json(InstanceLifecycleResponse {
+                is_done: false,
+                state: None,
+                message,
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to {} instance for email: {}, Error: {:?}",
+                action.as_str(),
+                user_email,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(InstanceLifecycleResponse {
+                    is_done: false,
+                    state: None,
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// Streams the user's container logs as plain text, `follow=true` keeps the response open
+async fn instance_logs(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<InstanceLogsQuery>,
+) -> axum::response::Response {
+    let failed = |status: StatusCode, message: String| {
+        (status, Json(InstanceLogsResponse { message })).into_response()
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance logs failed from {}: {}", client_ip, message);
+            return failed(status, message.to_string());
+        }
+    };
+
+    let tail = query.tail.unwrap_or(DEFAULT_LOG_TAIL);
+    match get_instance_logs(&user_email, tail, query.follow).await {
+        Ok(logs) => (
+            [
+                (header::CONTENT_TYPE, "text/plain; charset=utf-8"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+            // Ends with shutdown, or a followed stream would hold up draining
+            axum::body::Body::from_stream(
+                logs.take_until(get_task_registry().token().cancelled_owned()),
+            ),
+        )
+            .into_response(),
+        Err(BlazeError::Validation(message)) => failed(StatusCode::CONFLICT, message),
+        Err(BlazeError::Auth(message)) => failed(StatusCode::NOT_FOUND, message),
+        Err(e) => {
+            error!(
+                "Failed to stream instance logs for email: {}, Error: {:?}",
+                user_email, e
+            );
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// Requests, errors, bytes and latency of the user's instances, as measured by the proxy
+async fn instance_metrics(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Query(query): Query<InstanceMetricsQuery>,
+) -> (StatusCode, Json<InstanceMetricsResponse>) {
+    let hours = query
+        .hours
+        .unwrap_or(DEFAULT_WINDOW_HOURS)
+        .clamp(1, MAX_WINDOW_HOURS);
+    let failed = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(InstanceMetricsResponse {
+                hours,
+                instances: Vec::new(),
+                message,
+            }),
+        )
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance metrics failed from {}: {}", client_ip, message);
+            return failed(status, message.to_string());
+        }
+    };
+
+    match get_user_metrics(&user_email, hours) {
+        Ok(instances) => (
+            StatusCode::OK,
+            Json(InstanceMetricsResponse {
+                hours,
+                instances,
+                message: "Metrics retrieved".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to get metrics for email: {}, Error: {:?}",
+                user_email, e
+            );
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// The env settings of the user's containers, API key redacted
+async fn instance_config(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> (StatusCode, Json<InstanceConfigResponse>) {
+    let failed = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(InstanceConfigResponse {
+                config: None,
+                recreated: Vec::new(),
+                message,
+            }),
+        )
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance config failed from {}: {}", client_ip, message);
+            return failed(status, message.to_string());
+        }
+    };
+
+    match get_instance_config(&user_email).await {
+        Ok(config) => (
+            StatusCode::OK,
+            Json(InstanceConfigResponse {
+                config: Some(config.redacted()),
+                recreated: Vec::new(),
+                message: "Unset fields use the service's defaults".to_string(),
+            }),
+        ),
+        Err(BlazeError::Auth(message)) => failed(StatusCode::NOT_FOUND, message),
+        Err(e) => {
+            error!(
+                "Failed to get instance config for email: {}, Error: {:?}",
+                user_email, e
+            );
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// Replaces the env settings of the user's containers, recreating them (data is kept)
+async fn instance_config_update(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<InstanceConfig>,
+) -> (StatusCode, Json<InstanceConfigResponse>) {
+    let failed = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(InstanceConfigResponse {
+                config: None,
+                recreated: Vec::new(),
+                message,
+            }),
+        )
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!(
+                "Instance config update failed from {}: {}",
+                client_ip, message
+            );
+            return failed(status, message.to_string());
+        }
+    };
+
+    match update_instance_config(&user_email, payload).await {
+        Ok((config, recreated)) => {
+            info!(
+                "Instance config updated for user: {}, recreated {} container(s)",
+                user_email,
+                recreated.len()
+            );
+            let message = if recreated.is_empty() {
+                "Config saved, it applies to your next container".to_string()
+            } else {
+                "Config saved, your instance is restarting with it".to_string()
+            };
+            (
+                StatusCode::OK,
+                Json(InstanceConfigResponse {
+                    config: Some(config.redacted()),
+                    recreated,
+                    message,
+                }),
+            )
+        }
+        Err(BlazeError::Validation(message)) => failed(StatusCode::BAD_REQUEST, message),
+        Err(BlazeError::Auth(message)) => failed(StatusCode::NOT_FOUND, message),
+        Err(e) => {
+            error!(
+                "Instance config update failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// Whether the user's instance takes requests yet, for clients waiting after verification
+async fn instance_readiness(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> (StatusCode, Json<InstanceReadinessResponse>) {
+    let failed = |message: String| {
+        Json(InstanceReadinessResponse {
+            instance_id: None,
+            state: None,
+            is_ready: false,
+            message,
+        })
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance readiness failed from {}: {}", client_ip, message);
+            return (status, failed(message.to_string()));
+        }
+    };
+
+    match get_instance_readiness(&user_email).await {
+        Ok(readiness) => (StatusCode::OK, Json(readiness)),
+        Err(BlazeError::Validation(message)) => (StatusCode::CONFLICT, failed(message)),
+        Err(BlazeError::Auth(message)) => (StatusCode::NOT_FOUND, failed(message)),
+        Err(e) => {
+            error!(
+                "Failed to check instance readiness for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                failed("Internal server error, Sorry!".to_string()),
+            )
+        }
+    }
+}
+
+/// Container state and the proxy's view of the user's instance, for "my database is down"
+async fn instance_health(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+) -> (StatusCode, Json<InstanceHealthResponse>) {
+    let failed = |message: String| {
+        Json(InstanceHealthResponse {
+            instance_id: None,
+            state: None,
+            is_healthy: false,
+            started_at: None,
+            last_error_at: None,
+            last_error: None,
+            reachability: "unknown".to_string(),
+            proxy: None,
+            message,
+        })
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance health failed from {}: {}", client_ip, message);
+            return (status, failed(message.to_string()));
+        }
+    };
+
+    match get_instance_health(&user_email).await {
+        Ok(health) => (StatusCode::OK, Json(health)),
+        Err(BlazeError::Validation(message)) => (StatusCode::CONFLICT, failed(message)),
+        Err(BlazeError::Auth(message)) => (StatusCode::NOT_FOUND, failed(message)),
+        Err(e) => {
+            error!(
+                "Failed to check instance health for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                failed("Internal server error, Sorry!".to_string()),
+            )
+        }
+    }
+}
+
+/// Status for a failed library call, user mistakes aren't server errors
+fn error_status(e: &BlazeError) -> StatusCode {
+    match e {
+        BlazeError::Validation(_) => StatusCode::BAD_REQUEST,
+        BlazeError::Auth(_) => StatusCode::NOT_FOUND,
+        BlazeError::Otp(failure) => failure.status(),
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn referral_response(
+    status: StatusCode,
+    referral_code: Option<String>,
+    credit_cents: i64,
+    message: impl Into<String>,
+) -> (StatusCode, Json<ReferralResponse>) {
+    (
+        status,
+        Json(ReferralResponse {
+            referral_code,
+            credit_cents,
+            currency: "usd".to_string(),
+            message: message.into(),
+        }),
+    )
+}
+
+/// This endpoint returns the authenticated user's referral code and credit balance.
+async fn referral_get(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Referral lookup failed from {}: {}", client_ip, message);
+            return referral_response(status, None, 0, message);
+        }
+    };
+
+    let result = match get_referral_code(&user_email).await {
+        Ok(code) => credit_balance(&user_email).map(|balance| (code, balance)),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok((code, balance)) => referral_response(
+            StatusCode::OK,
+            Some(code),
+            balance,
+            "Share your code, you both get credit once they pay",
+        ),
+        Err(e) => {
+            error!(
+                "Failed to get referral code for email: {}, Error: {:?}",
+                user_email, e
+            );
+            referral_response(
+                error_status(&e),
+                None,
+                0,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint redeems another user's referral code for the authenticated user, who gets
+/// credit towards their first payment.
+async fn referral_redeem(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<ReferralRedeemRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Referral redeem failed from {}: {}", client_ip, message);
+            return referral_response(status, None, 0, message);
+        }
+    };
+
+    if is_empty_field(&payload.code) {
+        return referral_response(
+            StatusCode::BAD_REQUEST,
+            None,
+            0,
+            "Referral code is required",
+        );
+    }
+
+    match redeem_referral(&user_email, &payload.code).await {
+        Ok(credited) => {
+            let balance = credit_balance(&user_email).unwrap_or(credited);
+            referral_response(
+                StatusCode::OK,
+                None,
+                balance,
+                format!("Referral code redeemed, {} cents credited", credited),
+            )
+        }
+        Err(e) => {
+            warn!(
+                "Referral redeem failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            referral_response(
+                error_status(&e),
+                None,
+                0,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+fn organization_response(
+    status: StatusCode,
+    organization: Option<Organization>,
+    is_code_sent: bool,
+    message: impl Into<String>,
+) -> (StatusCode, Json<OrganizationResponse>) {
+    (
+        status,
+        Json(OrganizationResponse {
+            organization,
+            is_code_sent,
+            message: message.into(),
+        }),
+    )
+}
+
+/// This endpoint creates an organization owned (and paid for) by the authenticated user.
+async fn org_create(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<OrganizationCreateRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Organization create failed from {}: {}", client_ip, message);
+            return organization_response(status, None, false, message);
+        }
+    };
+
+    match create_organization(&user_email, &payload.name).await {
+        Ok(organization) => organization_response(
+            StatusCode::CREATED,
+            Some(organization),
+            false,
+            "Organization created",
+        ),
+        Err(e) => {
+            warn!(
+                "Organization create failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            organization_response(
+                StatusCode::BAD_REQUEST,
+                None,
+                false,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint returns the authenticated user's organization.
+async fn org_get(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Organization lookup failed from {}: {}", client_ip, message);
+            return organization_response(status, None, false, message);
+        }
+    };
+
+    let organization = match get_user(&user_email).await {
+        Ok(Some(user)) => get_user_organization(&user),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e.into()),
+    };
+    match organization {
+        Ok(Some(organization)) => organization_response(
+            StatusCode::OK,
+            Some(organization),
+            false,
+            "Organization retrieved",
+        ),
+        Ok(None) => organization_response(
+            StatusCode::NOT_FOUND,
+            None,
+            false,
+            "You don't belong to an organization",
+        ),
+        Err(e) => {
+            error!(
+                "Failed to get organization for email: {}, Error: {:?}",
+                user_email, e
+            );
+            organization_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+                false,
+                "Internal server error, Sorry!",
+            )
+        }
+    }
+}
+
+/// This endpoint invites someone to the authenticated owner's organization by email.
+async fn org_invite(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<OrganizationInviteRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Organization invite failed from {}: {}", client_ip, message);
+            return organization_response(status, None, false, message);
+        }
+    };
+
+    if is_empty_field(&payload.email) || !payload.email.contains('@') {
+        return organization_response(StatusCode::BAD_REQUEST, None, false, "Invalid email");
+    }
+
+    match invite_member(&user_email, &payload.email).await {
+        Ok(organization) => {
+            organization_response(StatusCode::OK, Some(organization), false, "Invite sent")
+        }
+        Err(e) => {
+            warn!(
+                "Organization invite failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            organization_response(
+                StatusCode::BAD_REQUEST,
+                None,
+                false,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint joins an organization the authenticated user was invited to.
+/// Send without `otp` first to receive a confirmation code, then again with the code.
+async fn org_join(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<OrganizationJoinRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Organization join failed from {}: {}", client_ip, message);
+            return organization_response(status, None, false, message);
+        }
+    };
+
+    if let Err(e) = check_can_join(&user_email, &payload.organization_id).await {
+        warn!("Organization join failed for {}: {}", user_email, e);
+        return organization_response(
+            StatusCode::FORBIDDEN,
+            None,
+            false,
+            "Something went wrong, Error: ".to_string() + &e.to_string(),
+        );
+    }
+
+    // No code yet sends one to the user's email, the request is then repeated with it
+    match confirm_action(
+        &user_email,
+        payload.otp.as_deref(),
+        OtpPurpose::OrganizationJoin,
+    )
+    .await
+    {
+        Ok(ActionConfirmation::Confirmed) => {}
+        Ok(ActionConfirmation::CodeSent) => {
+            return organization_response(
+                StatusCode::ACCEPTED,
+                None,
+                true,
+                "Confirmation code sent, resend with the otp to join",
+            );
+        }
+        Err(e) => {
+            let (status, message) =
+                confirmation_failure(&user_email, OtpPurpose::OrganizationJoin, e);
+            return organization_response(status, None, false, message);
+        }
+    }
+
+    match join_organization(&user_email, &payload.organization_id).await {
+        Ok(organization) => organization_response(
+            StatusCode::OK,
+            Some(organization),
+            false,
+            "Joined the organization",
+        ),
+        Err(e) => {
+            error!(
+                "Failed to join organization for email: {}, Error: {:?}",
+                user_email, e
+            );
+            organization_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+                false,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint takes the authenticated member out of their organization (back to the free plan).
+async fn org_leave(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Organization leave failed from {}: {}", client_ip, message);
+            return organization_response(status, None, false, message);
+        }
+    };
+
+    match leave_organization(&user_email).await {
+        Ok(()) => organization_response(StatusCode::OK, None, false, "Left the organization"),
+        Err(e) => {
+            warn!(
+                "Organization leave failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            organization_response(
+                StatusCode::BAD_REQUEST,
+                None,
+                false,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint wipes the user's instance data after re-confirming with an OTP.
+async fn instance_reset(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<InstanceResetRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance reset failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(InstanceResetResponse {
+                    is_reset: false,
+                    is_code_sent: false,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    // No code yet sends one to the user's email, the request is then repeated with it
+    match confirm_action(
+        &user_email,
+        payload.otp.as_deref(),
+        OtpPurpose::InstanceReset,
+    )
+    .await
+    {
+        Ok(ActionConfirmation::Confirmed) => {}
+        Ok(ActionConfirmation::CodeSent) => {
+            return (
+                StatusCode::ACCEPTED,
+                Json(InstanceResetResponse {
+                    is_reset: false,
+                    is_code_sent: true,
+                    message: "Confirmation code sent, resend with the otp to reset".to_string(),
+                }),
+            );
+        }
+        Err(e) => {
+            let (status, message) = confirmation_failure(&user_email, OtpPurpose::InstanceReset, e);
+            return (
+                status,
+                Json(InstanceResetResponse {
+                    is_reset: false,
+                    is_code_sent: false,
+                    message,
+                }),
+            );
+        }
+    }
+
+    match reset_instance(&user_email).await {
+        Ok(_) => {
+            info!("Instance reset successfully for user: {}", user_email);
+            (
+                StatusCode::OK,
+                Json(InstanceResetResponse {
+                    is_reset: true,
+                    is_code_sent: false,
+                    message: "Instance data wiped, your database is starting fresh".to_string(),
+                }),
+            )
+        }
+        Err(e) => {
+            error!(
+                "Instance reset failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            let status = error_status(&e);
+            let message = if status.is_server_error() {
+                "Internal server error, Sorry!".to_string()
+            } else {
+                e.to_string()
+            };
+            (
+                status,
+                Json(InstanceResetResponse {
+                    is_reset: false,
+                    is_code_sent: false,
+                    message,
+                }),
+            )
+        }
+    }
+}
+
+async fn instance_backup(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance backup failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(InstanceBackupResponse {
+                    backup: None,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match backup_instance(&user_email).await {
+        Ok(backup) => {
+            info!(
+                "Instance backed up for user: {} ({})",
+                user_email, backup.id
+            );
+            (
+                StatusCode::OK,
+                Json(InstanceBackupResponse {
+                    message: format!("Backup {} created", backup.id),
+                    backup: Some(backup),
+                }),
+            )
+        }
+        Err(BlazeError::Validation(message)) => (
+            StatusCode::CONFLICT,
+            Json(InstanceBackupResponse {
+                backup: None,
+                message,
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Instance backup failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                error_status(&e),
+                Json(InstanceBackupResponse {
+                    backup: None,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+async fn instance_backups(ClientIp(client_ip): ClientIp, headers: HeaderMap) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!(
+                "Instance backup list failed from {}: {}",
+                client_ip, message
+            );
+            return (
+                status,
+                Json(InstanceBackupListResponse {
+                    backups: Vec::new(),
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    match list_instance_backups(&user_email).await {
+        Ok(backups) => (
+            StatusCode::OK,
+            Json(InstanceBackupListResponse {
+                message: format!("{} backup(s)", backups.len()),
+                backups,
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Failed to list backups for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                error_status(&e),
+                Json(InstanceBackupListResponse {
+                    backups: Vec::new(),
+                    message: "Internal server error, Sorry!".to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// Downloads one volume of a backup as a tarball
+async fn instance_backup_download(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Path((backup_id, volume)): Path<(String, String)>,
+) -> axum::response::Response {
+    let failed = |status: StatusCode, message: String| {
+        (
+            status,
+            Json(InstanceBackupResponse {
+                backup: None,
+                message,
+            }),
+        )
+            .into_response()
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Backup download failed from {}: {}", client_ip, message);
+            return failed(status, message.to_string());
+        }
+    };
+
+    let file = match get_backup_file(&user_email, &backup_id, &volume).await {
+        Ok(path) => tokio::fs::File::open(path).await,
+        Err(BlazeError::Validation(message)) => return failed(StatusCode::NOT_FOUND, message),
+        Err(BlazeError::Auth(message)) => return failed(StatusCode::NOT_FOUND, message),
+        Err(e) => {
+            error!(
+                "Backup download failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            return failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            );
+        }
+    };
+
+    match file {
+        Ok(file) => (
+            [
+                (header::CONTENT_TYPE, "application/x-tar".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"{}-{}.tar\"", backup_id, volume),
+                ),
+            ],
+            axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+        )
+            .into_response(),
+        Err(e) => {
+            error!(
+                "Failed to open backup {} for email: {}, Error: {:?}",
+                backup_id, user_email, e
+            );
+            failed(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// Restores a backup into a fresh container after re-confirming with an OTP, like a reset
+async fn instance_restore(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Path(backup_id): Path<String>,
+    Json(payload): Json<InstanceRestoreRequest>,
+) -> impl IntoResponse {
+    let failed = |status: StatusCode, is_code_sent: bool, message: String| {
+        (
+            status,
+            Json(InstanceRestoreResponse {
+                is_restored: false,
+                is_code_sent,
+                message,
+            }),
+        )
+    };
+
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Instance restore failed from {}: {}", client_ip, message);
+            return failed(status, false, message.to_string());
+        }
+    };
+
+    // No code yet sends one to the user's email, the request is then repeated with it
+    match confirm_action(
+        &user_email,
+        payload.otp.as_deref(),
+        OtpPurpose::InstanceRestore,
+    )
+    .await
+    {
+        Ok(ActionConfirmation::Confirmed) => {}
+        Ok(ActionConfirmation::CodeSent) => {
+            return failed(
+                StatusCode::ACCEPTED,
+                true,
+                "Confirmation code sent, resend with the otp to restore".to_string(),
+            );
+        }
+        Err(e) => {
+            let (status, message) =
+                confirmation_failure(&user_email, OtpPurpose::InstanceRestore, e);
+            return failed(status, false, message);
+        }
+    }
+
+    match restore_instance(&user_email, &backup_id).await {
+        Ok(backup) => {
+            info!(
+                "Instance restored from {} for user: {}",
+                backup.id, user_email
+            );
+            (
+                StatusCode::OK,
+                Json(InstanceRestoreResponse {
+                    is_restored: true,
+                    is_code_sent: false,
+                    message: format!(
+                        "Instance restored from the backup of {}, your database is starting",
+                        backup.created_at
+                    ),
+                }),
+            )
+        }
+        Err(BlazeError::Validation(message)) => failed(StatusCode::CONFLICT, false, message),
+        Err(e) => {
+            error!(
+                "Instance restore failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            failed(
+                error_status(&e),
+                false,
+                "Something went wrong, Error: ".to_string() + &e.to_string(),
+            )
+        }
+    }
+}
+
+/// This endpoint deletes the user's account after re-confirming with an OTP.
+async fn account_delete(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<AccountDeleteRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Account deletion failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(AccountDeleteResponse {
+                    is_deleted: false,
+                    is_code_sent: false,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    // No code yet sends one to the user's email, the request is then repeated with it
+    match confirm_action(
+        &user_email,
+        payload.otp.as_deref(),
+        OtpPurpose::AccountDeletion,
+    )
+    .await
+    {
+        Ok(ActionConfirmation::Confirmed) => {}
+        Ok(ActionConfirmation::CodeSent) => {
+            return (
+                StatusCode::ACCEPTED,
+                Json(AccountDeleteResponse {
+                    is_deleted: false,
+                    is_code_sent: true,
+                    message: "Confirmation code sent, resend with the otp to delete".to_string(),
+                }),
+            );
+        }
+        Err(e) => {
+            let (status, message) =
+                confirmation_failure(&user_email, OtpPurpose::AccountDeletion, e);
+            return (
+                status,
+                Json(AccountDeleteResponse {
+                    is_deleted: false,
+                    is_code_sent: false,
+                    message,
+                }),
+            );
+        }
+    }
+
+    match delete_account(&user_email, payload.remove_volumes).await {
+        Ok(_) => {
+            info!("Account deleted for user: {}", user_email);
+            (
+                StatusCode::OK,
+                Json(AccountDeleteResponse {
+                    is_deleted: true,
+                    is_code_sent: false,
+                    message: "Your account has been deleted, sad to see you go".to_string(),
+                }),
+            )
+        }
+        Err(e) => {
+            error!(
+                "Account deletion failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(AccountDeleteResponse {
+                    is_deleted: false,
+                    is_code_sent: false,
                     message: "Something went wrong, Error: ".to_string() + &e.to_string(),
                 }),
             )
@@ -467,8 +4155,158 @@ async fn instance_status(
     }
 }
 
+/// This endpoint re-verifies the user with an OTP, unpausing keys flagged for unusual activity.
+async fn keys_reverify(
+    ClientIp(client_ip): ClientIp,
+    headers: HeaderMap,
+    Json(payload): Json<KeyReverifyRequest>,
+) -> impl IntoResponse {
+    let user_email = match authenticate(&headers).await {
+        Ok(email) => email,
+        Err((status, message)) => {
+            warn!("Key re-verification failed from {}: {}", client_ip, message);
+            return (
+                status,
+                Json(KeyReverifyResponse {
+                    is_reverified: false,
+                    is_code_sent: false,
+                    message: message.to_string(),
+                }),
+            );
+        }
+    };
+
+    // No code yet sends one to the user's email, the request is then repeated with it
+    match confirm_action(
+        &user_email,
+        payload.otp.as_deref(),
+        OtpPurpose::KeyReverification,
+    )
+    .await
+    {
+        Ok(ActionConfirmation::Confirmed) => {}
+        Ok(ActionConfirmation::CodeSent) => {
+            return (
+                StatusCode::ACCEPTED,
+                Json(KeyReverifyResponse {
+                    is_reverified: false,
+                    is_code_sent: true,
+                    message: "Confirmation code sent, resend with the otp to re-verify".to_string(),
+                }),
+            );
+        }
+        Err(e) => {
+            let (status, message) =
+                confirmation_failure(&user_email, OtpPurpose::KeyReverification, e);
+            return (
+                status,
+                Json(KeyReverifyResponse {
+                    is_reverified: false,
+                    is_code_sent: false,
+                    message,
+                }),
+            );
+        }
+    }
+
+    match mark_user_reverified(&user_email).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(KeyReverifyResponse {
+                is_reverified: true,
+                is_code_sent: false,
+                // Saving the user invalidates the proxy cache, the pause lifts on the next request
+                message: "Re-verified, your keys are unpaused".to_string(),
+            }),
+        ),
+        Err(e) => {
+            error!(
+                "Key re-verification failed for email: {}, Error: {:?}",
+                user_email, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(KeyReverifyResponse {
+                    is_reverified: false,
+                    is_code_sent: false,
+                    message: "Something went wrong, Error: ".to_string() + &e.to_string(),
+                }),
+            )
+        }
+    }
+}
+
+/// Status and message for a confirmation that failed, see `confirm_action`
+fn confirmation_failure(email: &str, purpose: OtpPurpose, e: BlazeError) -> (StatusCode, String) {
+    match e {
+        BlazeError::Otp(failure) => {
+            warn!("{} failed for {}: {}", purpose.as_str(), email, failure);
+            (
+                StatusCode::UNAUTHORIZED,
+                "Invalid or expired confirmation code".to_string(),
+            )
+        }
+        // Asked for another code within the cooldown
+        BlazeError::Validation(message) => (StatusCode::TOO_MANY_REQUESTS, message),
+        BlazeError::Mail(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to send confirmation code".to_string(),
+        ),
+        e => {
+            error!(
+                "{} confirmation failed for email: {}, Error: {:?}",
+                purpose.as_str(),
+                email,
+                e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!".to_string(),
+            )
+        }
+    }
+}
+
+/// Authenticates the request with the API key from the header (verified against the stored hash)
+/// Returns the user's email, or the status and message to reply with
+async fn authenticate(headers: &HeaderMap) -> Result<String, (StatusCode, &'static str)> {
+    let api_key =
+        extract_apy_key(headers).ok_or((StatusCode::UNAUTHORIZED, "Invalid or missing API key"))?;
+
+    match verify_api_key(api_key).await {
+        Ok(Some(email)) => Ok(email),
+        Ok(None) => Err((StatusCode::UNAUTHORIZED, "Invalid API key")),
+        Err(e) => {
+            error!("API key verification failed, Error: {:?}", e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Internal server error, Sorry!",
+            ))
+        }
+    }
+}
+
+/// Checks the `X-Admin-Token` header against `BLAZE_ADMIN_TOKEN` from env
+/// Admin endpoints are disabled when the env var isn't set
+fn authenticate_admin(headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    let admin_token = get_secrets()
+        .get("BLAZE_ADMIN_TOKEN")
+        .ok_or((StatusCode::NOT_FOUND, "Not found"))?;
+
+    let provided = headers
+        .get("X-Admin-Token")
+        .and_then(|v| v.to_str().ok())
+        .ok_or((StatusCode::UNAUTHORIZED, "Missing admin token"))?;
+
+    if secrets_match(provided, admin_token) {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Invalid admin token"))
+    }
+}
+
 fn is_empty_field(field: &str) -> bool {
-    if field.trim().is_empty() { true } else { false }
+    field.trim().is_empty()
 }
 
 /// Extracts the API key from the header and validates format
@@ -484,9 +4322,7 @@ fn extract_apy_key(headers: &HeaderMap) -> Option<&str> {
         auth_str
     };
 
-    if !api_key.starts_with("blz_") {
-        return None;
-    }
+    api_key_format_version(api_key)?;
 
     Some(api_key)
 }
@@ -0,0 +1,236 @@
+//! # Proxy access log
+//!
+//! Every proxied request gets an ID, sent to the instance and back to the client as
+//! `X-Request-Id`, and one JSON line in `access.log` under the logs directory once its response
+//! has been sent (user, instance, method, path, status, latency, bytes both ways). That keeps it
+//! apart from the proxy's console output and easy to grep for a request a user reports.
+//!
+//! Entries are buffered and appended every few seconds. The file is rotated once it's over
+//! `BLAZE_ACCESS_LOG_MAX_MB` (64 by default) to `access.log.1`, `.2` and so on, keeping
+//! `BLAZE_ACCESS_LOG_KEEP` (5) old files. `BLAZE_ACCESS_LOG=false` turns the log off.
+
+use crate::server::service::get_logs_path;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const DEFAULT_MAX_MB: u64 = 64;
+const DEFAULT_KEEP: usize = 5;
+
+/// A new random request ID (32 hex characters)
+pub fn new_request_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// One line of the access log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessLogEntry {
+    pub timestamp: String, // When the request came in
+    pub request_id: String,
+    pub client_ip: String,
+    pub user: Option<String>, // None when the request never got authenticated
+    pub instance_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: u64, // Until the response body was sent (or the client went away)
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+}
+
+/// Who a request turned out to be from, filled in by the handler once it's authenticated
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    pub request_id: String,
+    user: Arc<Mutex<Option<String>>>,
+    instance_id: Arc<Mutex<Option<String>>>, // Set once the user is known to own it
+}
+
+impl RequestContext {
+    pub fn new(request_id: String) -> Self {
+        RequestContext {
+            request_id,
+            user: Arc::new(Mutex::new(None)),
+            instance_id: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn set_user(&self, email: &str) {
+        *self.user.lock().unwrap_or_else(|e| e.into_inner()) = Some(email.to_string());
+    }
+
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_instance(&self, instance_id: &str) {
+        *self.instance_id.lock().unwrap_or_else(|e| e.into_inner()) = Some(instance_id.to_string());
+    }
+
+    pub fn instance_id(&self) -> Option<String> {
+        self.instance_id
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogSettings {
+    pub enabled: bool,
+    pub path: PathBuf,
+    pub max_bytes: u64, // Rotated once it's bigger than this
+    pub keep: usize,    // Rotated files kept
+}
+
+impl AccessLogSettings {
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let var = |name: &str| std::env::var(name).ok().map(|v| v.trim().to_string());
+        AccessLogSettings {
+            enabled: var("BLAZE_ACCESS_LOG")
+                .is_none_or(|v| !(v.eq_ignore_ascii_case("false") || v == "0")),
+            path: get_logs_path().join("access.log"),
+            max_bytes: var("BLAZE_ACCESS_LOG_MAX_MB")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_MB)
+                .max(1)
+                * 1024
+                * 1024,
+            keep: var("BLAZE_ACCESS_LOG_KEEP")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_KEEP),
+        }
+    }
+}
+
+/// Access log entries not written yet, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone)]
+pub struct AccessLog {
+    settings: Arc<AccessLogSettings>,
+    pending: Arc<Mutex<Vec<AccessLogEntry>>>,
+}
+
+impl AccessLog {
+    pub fn new(settings: AccessLogSettings) -> Self {
+        AccessLog {
+            settings: Arc::new(settings),
+            pending: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn record(&self, entry: AccessLogEntry) {
+        if !self.settings.enabled {
+            return;
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push(entry);
+    }
+
+    /// Appends the pending entries to the file, rotating it once it's too big
+    pub fn flush(&self) -> Result<()> {
+        let entries = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let path = &self.settings.path;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut lines = String::new();
+        for entry in &entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        file.write_all(lines.as_bytes())?;
+
+        if file.metadata()?.len() > self.settings.max_bytes {
+            rotate(path, self.settings.keep)?;
+        }
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Moves `access.log` to `access.log.1`, shifting the older files up and dropping the oldest
+fn rotate(path: &Path, keep: usize) -> Result<()> {
+    if keep == 0 {
+        std::fs::remove_file(path)?;
+        return Ok(());
+    }
+
+    let oldest = rotated_path(path, keep);
+    if oldest.exists() {
+        std::fs::remove_file(&oldest)?;
+    }
+    for n in (1..keep).rev() {
+        let from = rotated_path(path, n);
+        if from.exists() {
+            std::fs::rename(&from, rotated_path(path, n + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated_path(path, 1))?;
+    Ok(())
+}
+
+#[test]
+fn test_access_log_rotation() {
+    let dir = std::env::temp_dir().join("test_access_log_rotation");
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("access.log");
+    let log = AccessLog::new(AccessLogSettings {
+        enabled: true,
+        path: path.clone(),
+        max_bytes: 400,
+        keep: 2,
+    });
+
+    let entry = |n: u16| AccessLogEntry {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        request_id: new_request_id(),
+        client_ip: "127.0.0.1".to_string(),
+        user: Some("a@x.com".to_string()),
+        instance_id: Some("inst".to_string()),
+        method: "GET".to_string(),
+        path: "/v1/blazedb/query/inst".to_string(),
+        status: n,
+        latency_ms: 3,
+        request_bytes: 0,
+        response_bytes: 120,
+    };
+
+    log.record(entry(200));
+    log.flush().unwrap();
+    let written = std::fs::read_to_string(&path).unwrap();
+    let parsed: AccessLogEntry = serde_json::from_str(written.lines().next().unwrap()).unwrap();
+    assert_eq!(parsed.status, 200);
+    assert_eq!(parsed.request_id.len(), 32);
+
+    // Each flush of two goes over 400 bytes, only two rotated files stay around
+    for status in [201, 202, 203] {
+        log.record(entry(status));
+        log.record(entry(status));
+        log.flush().unwrap();
+    }
+    assert!(!path.exists());
+    assert!(rotated_path(&path, 1).exists());
+    assert!(rotated_path(&path, 2).exists());
+    assert!(!rotated_path(&path, 3).exists());
+    let newest = std::fs::read_to_string(rotated_path(&path, 1)).unwrap();
+    assert!(newest.contains("\"status\":203"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
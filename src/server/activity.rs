@@ -0,0 +1,132 @@
+//! # Instance activity tracking
+//!
+//! The proxy records when each instance last served a request, and how many requests are still
+//! in flight. A request counts as in flight until its response body has been fully streamed back,
+//! so a long subscription or a slow bulk ingest keeps the instance busy the whole time, not just
+//! at the moment it started. Idle checks (e.g. for suspending containers) must go through here.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+struct InstanceActivity {
+    last_activity: Instant,
+    open_streams: usize,
+}
+
+/// Per instance activity, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone, Default)]
+pub struct ActivityTracker {
+    instances: Arc<Mutex<HashMap<String, InstanceActivity>>>,
+}
+
+/// Keeps the instance marked busy while alive, dropping it marks the end of the stream
+/// Attach it to the response body so it lives exactly as long as the stream
+#[derive(Debug)]
+pub struct ActivityGuard {
+    tracker: ActivityTracker,
+    instance_id: String,
+}
+
+impl ActivityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks the start of a request to `instance_id`
+    pub fn begin(&self, instance_id: &str) -> ActivityGuard {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let activity = instances
+            .entry(instance_id.to_string())
+            .or_insert(InstanceActivity {
+                last_activity: Instant::now(),
+                open_streams: 0,
+            });
+        activity.open_streams += 1;
+        activity.last_activity = Instant::now();
+
+        ActivityGuard {
+            tracker: self.clone(),
+            instance_id: instance_id.to_string(),
+        }
+    }
+
+    /// Refreshes the last activity without opening a stream (e.g. on every streamed chunk)
+    pub fn touch(&self, instance_id: &str) {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(activity) = instances.get_mut(instance_id) {
+            activity.last_activity = Instant::now();
+        }
+    }
+
+    fn end(&self, instance_id: &str) {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(activity) = instances.get_mut(instance_id) {
+            activity.open_streams = activity.open_streams.saturating_sub(1);
+            activity.last_activity = Instant::now();
+        }
+    }
+
+    /// Number of requests to `instance_id` whose responses are still streaming
+    pub fn open_streams(&self, instance_id: &str) -> usize {
+        let instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        instances.get(instance_id).map_or(0, |a| a.open_streams)
+    }
+
+    /// How long the instance has been idle, `None` if it has an open stream or was never seen
+    pub fn idle_for(&self, instance_id: &str) -> Option<Duration> {
+        let instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        instances
+            .get(instance_id)
+            .filter(|a| a.open_streams == 0)
+            .map(|a| a.last_activity.elapsed())
+    }
+
+    /// Instances idle for at least `threshold`, instances with open streams are never included
+    pub fn idle_instances(&self, threshold: Duration) -> Vec<String> {
+        let instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        instances
+            .iter()
+            .filter(|(_, a)| a.open_streams == 0 && a.last_activity.elapsed() >= threshold)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+impl ActivityGuard {
+    /// Refreshes the instance's last activity, the stream is still going
+    pub fn touch(&self) {
+        self.tracker.touch(&self.instance_id);
+    }
+}
+
+impl Drop for ActivityGuard {
+    fn drop(&mut self) {
+        self.tracker.end(&self.instance_id);
+    }
+}
+
+#[test]
+fn test_open_stream_is_never_idle() {
+    let tracker = ActivityTracker::new();
+
+    let guard = tracker.begin("a1a70763");
+    assert_eq!(tracker.open_streams("a1a70763"), 1);
+    assert_eq!(tracker.idle_for("a1a70763"), None);
+    assert!(tracker.idle_instances(Duration::ZERO).is_empty());
+
+    drop(guard);
+    assert_eq!(tracker.open_streams("a1a70763"), 0);
+    assert!(tracker.idle_for("a1a70763").is_some());
+    assert_eq!(tracker.idle_instances(Duration::ZERO), vec!["a1a70763"]);
+}
+
+#[test]
+fn test_idle_threshold() {
+    let tracker = ActivityTracker::new();
+    drop(tracker.begin("a1a70763"));
+
+    assert!(tracker.idle_instances(Duration::from_secs(3600)).is_empty());
+    assert_eq!(tracker.idle_for("unknown"), None);
+}
@@ -0,0 +1,401 @@
+//! # Key usage anomaly detection
+//!
+//! The proxy keeps a small usage profile per API key (countries seen, recent addresses, hourly volume
+//! baseline) and flags requests that deviate sharply from it: a country never seen before, or a 10x
+//! volume spike. What happens next (ignore, alert email, pause the key until re-verification) is
+//! decided by the plan's `anomaly_action`.
+
+use crate::server::crypto::{INSTANCE_TOKEN_PREFIX, email_index};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Current hour volume must exceed the baseline by this factor to count as a spike
+pub const SPIKE_FACTOR: f64 = 10.0;
+/// Baselines below this many requests per hour are too small to judge spikes on
+pub const MIN_BASELINE_PER_HOUR: f64 = 10.0;
+/// Hours of history needed before spike detection kicks in
+pub const LEARNING_HOURS: u32 = 24;
+/// At most one alert email per key in this window
+pub const ALERT_COOLDOWN_MINUTES: i64 = 60;
+/// Weight of the latest hour in the moving baseline
+const BASELINE_WEIGHT: f64 = 0.2;
+const MAX_RECENT_ADDRESSES: usize = 10;
+
+/// Profile key all of a user's instance tokens share in the proxy's store, each token only
+/// lives a few minutes
+pub fn instance_token_profile_key(email: &str) -> String {
+    format!("{}{}", INSTANCE_TOKEN_PREFIX, email_index(email))
+}
+
+/// Usage profile of a single API key, keyed by the key hash in the proxy's store
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct KeyUsageProfile {
+    pub email: String,
+    pub known_countries: Vec<String>,
+    pub recent_addresses: Vec<String>,
+    pub baseline_per_hour: f64,
+    pub observed_hours: u32,
+    pub current_hour: i64, // Unix hour of the bucket being counted
+    pub current_hour_count: u64,
+    pub spike_flagged: bool, // Already flagged a spike in the current hour
+    pub blocked_since: Option<String>,
+    pub last_alert_at: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsageAnomaly {
+    NewCountry(String),
+    VolumeSpike {
+        requests_this_hour: u64,
+        baseline_per_hour: f64,
+    },
+}
+
+/// What a request did to its key's profile, see `KeyUsageProfile::check`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsageCheck {
+    pub anomalies: Vec<UsageAnomaly>,
+    pub is_paused: bool, // The key is paused (still, or because of these anomalies)
+    pub is_unpaused: bool, // A re-verification lifted the pause before this request
+    pub should_alert: bool, // An alert email is due, it's already recorded as sent
+}
+
+impl UsageAnomaly {
+    pub fn describe(&self) -> String {
+        match self {
+            UsageAnomaly::NewCountry(country) => {
+                format!("Request from a new country: {}", country)
+            }
+            UsageAnomaly::VolumeSpike {
+                requests_this_hour,
+                baseline_per_hour,
+            } => format!(
+                "{} requests this hour, usually around {:.0}",
+                requests_this_hour, baseline_per_hour
+            ),
+        }
+    }
+}
+
+impl KeyUsageProfile {
+    pub fn new(email: &str) -> Self {
+        KeyUsageProfile {
+            email: email.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Records one request and returns the anomalies it triggered
+    /// The first country seen is learned silently, spikes are flagged at most once per hour
+    pub fn observe(
+        &mut self,
+        ip: IpAddr,
+        country: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Vec<UsageAnomaly> {
+        let mut anomalies = Vec::new();
+
+        let hour = now.timestamp() / 3600;
+        if self.current_hour != hour {
+            self.roll_hour(hour);
+        }
+        self.current_hour_count += 1;
+
+        let address = ip.to_string();
+        if !self.recent_addresses.contains(&address) {
+            self.recent_addresses.push(address);
+            if self.recent_addresses.len() > MAX_RECENT_ADDRESSES {
+                self.recent_addresses.remove(0);
+            }
+        }
+
+        // "XX" is what Cloudflare sends when it doesn't know
+        let country = country
+            .map(|c| c.trim().to_uppercase())
+            .filter(|c| c.len() == 2 && c != "XX");
+
+        if let Some(country) = country
+            && !self.known_countries.contains(&country)
+        {
+            if !self.known_countries.is_empty() {
+                anomalies.push(UsageAnomaly::NewCountry(country.clone()));
+            }
+            self.known_countries.push(country);
+        }
+
+        if !self.spike_flagged
+            && self.observed_hours >= LEARNING_HOURS
+            && self.baseline_per_hour >= MIN_BASELINE_PER_HOUR
+            && self.current_hour_count as f64 > SPIKE_FACTOR * self.baseline_per_hour
+        {
+            self.spike_flagged = true;
+            anomalies.push(UsageAnomaly::VolumeSpike {
+                requests_this_hour: self.current_hour_count,
+                baseline_per_hour: self.baseline_per_hour,
+            });
+        }
+
+        anomalies
+    }
+
+    /// Records one request like `observe` and applies the plan's reaction to what it found
+    /// A pause is lifted once `reverified_at` is past it, and put in place on anomalies when
+    /// `pause_on_anomaly`. Requests while still paused aren't counted
+    pub fn check(
+        &mut self,
+        ip: IpAddr,
+        country: Option<&str>,
+        now: DateTime<Utc>,
+        reverified_at: Option<&str>,
+        pause_on_anomaly: bool,
+    ) -> UsageCheck {
+        let mut check = UsageCheck::default();
+
+        if self.is_blocked() {
+            if !self.is_cleared_by(reverified_at) {
+                check.is_paused = true;
+                return check;
+            }
+            self.blocked_since = None;
+            check.is_unpaused = true;
+        }
+
+        check.anomalies = self.observe(ip, country, now);
+        if !check.anomalies.is_empty() {
+            if pause_on_anomaly {
+                self.blocked_since = Some(now.to_rfc3339());
+                check.is_paused = true;
+            }
+            if self.should_alert(now) {
+                self.last_alert_at = Some(now.to_rfc3339());
+                check.should_alert = true;
+            }
+        }
+
+        check
+    }
+
+    /// Folds the finished hour into the baseline and starts counting a new one
+    fn roll_hour(&mut self, hour: i64) {
+        if self.current_hour != 0 {
+            let finished = self.current_hour_count as f64;
+            self.baseline_per_hour = if self.observed_hours == 0 {
+                finished
+            } else {
+                self.baseline_per_hour * (1.0 - BASELINE_WEIGHT) + finished * BASELINE_WEIGHT
+            };
+
+            // Idle hours in between count as zero traffic (capped at a week)
+            let idle_hours = (hour - self.current_hour - 1).clamp(0, 24 * 7) as u32;
+            for _ in 0..idle_hours {
+                self.baseline_per_hour *= 1.0 - BASELINE_WEIGHT;
+            }
+
+            self.observed_hours = self.observed_hours.saturating_add(1 + idle_hours);
+        }
+
+        self.current_hour = hour;
+        self.current_hour_count = 0;
+        self.spike_flagged = false;
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked_since.is_some()
+    }
+
+    /// Whether enough time passed since the last alert email to send another
+    pub fn should_alert(&self, now: DateTime<Utc>) -> bool {
+        match self
+            .last_alert_at
+            .as_deref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        {
+            Some(last) => {
+                now - last.with_timezone(&Utc) > Duration::minutes(ALERT_COOLDOWN_MINUTES)
+            }
+            None => true,
+        }
+    }
+
+    /// A block is lifted once the user re-verified after it was put in place
+    pub fn is_cleared_by(&self, reverified_at: Option<&str>) -> bool {
+        let blocked_since = self
+            .blocked_since
+            .as_deref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+        let reverified_at = reverified_at.and_then(|t| DateTime::parse_from_rfc3339(t).ok());
+
+        match (blocked_since, reverified_at) {
+            (Some(blocked), Some(reverified)) => reverified > blocked,
+            (None, _) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Builds the (plain, html) bodies of the security alert email
+pub fn build_alert_email(
+    username: &str,
+    key_prefix: &str,
+    anomalies: &[UsageAnomaly],
+    key_paused: bool,
+) -> (String, String) {
+    let details: Vec<String> = anomalies.iter().map(UsageAnomaly::describe).collect();
+
+    let next_step = if key_paused {
+        "Requests with this key are paused until you re-verify via POST /v1/blz/keys/reverify."
+    } else {
+        "If this wasn't you, rotate your API key right away."
+    };
+
+    let plain_body = format!(
+        "Hi {},\n\nWe noticed unusual activity on your BlazeDB API key {}:\n\n- {}\n\n{}",
+        username,
+        key_prefix,
+        details.join("\n- "),
+        next_step
+    );
+
+    let html_body = format!(
+        r#"
+        <!DOCTYPE html>
+        <html>
+        <body style="font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif; color: #333;">
+            <h2 style="color: #c0392b;">Unusual activity on your API key</h2>
+            <p>Hi {},</p>
+            <p>We noticed unusual activity on your BlazeDB API key <code>{}</code>:</p>
+            <ul>{}</ul>
+            <p><strong>{}</strong></p>
+        </body>
+        </html>
+        "#,
+        username,
+        key_prefix,
+        details
+            .iter()
+            .map(|d| format!("<li>{}</li>", d))
+            .collect::<String>(),
+        next_step
+    );
+
+    (plain_body, html_body)
+}
+
+#[test]
+fn test_new_country_flagged_after_first() {
+    let mut profile = KeyUsageProfile::new("a@b.com");
+    let ip: IpAddr = "198.51.100.7".parse().unwrap();
+    let now = Utc::now();
+
+    assert!(profile.observe(ip, Some("IN"), now).is_empty());
+    assert!(profile.observe(ip, Some("in"), now).is_empty());
+    assert_eq!(
+        profile.observe(ip, Some("RU"), now),
+        vec![UsageAnomaly::NewCountry("RU".to_string())]
+    );
+    // Learned now
+    assert!(profile.observe(ip, Some("RU"), now).is_empty());
+}
+
+#[test]
+fn test_volume_spike_after_learning() {
+    let mut profile = KeyUsageProfile::new("a@b.com");
+    let ip: IpAddr = "198.51.100.7".parse().unwrap();
+    let start = Utc::now();
+
+    // A day of steady traffic, 20 requests per hour
+    for hour in 0..(LEARNING_HOURS as i64 + 1) {
+        let now = start + Duration::hours(hour);
+        for _ in 0..20 {
+            assert!(profile.observe(ip, None, now).is_empty());
+        }
+    }
+
+    // Then 10x that within the next hour, flagged exactly once
+    let now = start + Duration::hours(LEARNING_HOURS as i64 + 1);
+    let spikes = (0..300)
+        .flat_map(|_| profile.observe(ip, None, now))
+        .filter(|a| matches!(a, UsageAnomaly::VolumeSpike { .. }))
+        .count();
+    assert_eq!(spikes, 1);
+}
+
+#[test]
+fn test_check_pauses_until_reverified() {
+    let mut profile = KeyUsageProfile::new("a@b.com");
+    let ip: IpAddr = "198.51.100.7".parse().unwrap();
+    let now = Utc::now();
+
+    assert_eq!(
+        profile.check(ip, Some("IN"), now, None, true),
+        UsageCheck::default()
+    );
+
+    // A new country pauses the key and alerts once
+    let check = profile.check(ip, Some("RU"), now, None, true);
+    assert!(check.is_paused && check.should_alert);
+    assert_eq!(check.anomalies.len(), 1);
+
+    // Requests meanwhile are refused without being counted, an older re-verification doesn't count
+    let earlier = (now - Duration::hours(1)).to_rfc3339();
+    let check = profile.check(ip, Some("IN"), now, Some(&earlier), true);
+    assert!(check.is_paused && check.anomalies.is_empty());
+    assert_eq!(profile.current_hour_count, 2);
+
+    // Lifted by re-verifying after the pause
+    let later = (now + Duration::minutes(1)).to_rfc3339();
+    let check = profile.check(ip, Some("IN"), now, Some(&later), true);
+    assert!(!check.is_paused && check.is_unpaused);
+    assert!(!profile.is_blocked());
+
+    // Alerts without pausing otherwise, no second email within the cooldown
+    let check = profile.check(ip, Some("DE"), now, None, false);
+    assert!(!check.is_paused && !check.should_alert);
+    assert_eq!(check.anomalies.len(), 1);
+}
+
+#[test]
+fn test_concurrent_instance_token_requests() -> crate::server::error::Result<()> {
+    use crate::server::storage::DataStore;
+
+    let dir = std::env::temp_dir().join("test_anomaly_concurrent_requests");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let store = DataStore::<String, KeyUsageProfile>::new(dir.join("key_usage.json"))?;
+    let email = "a@b.com";
+    let key = instance_token_profile_key(email);
+    let now = Utc::now();
+
+    // Requests with a user's instance tokens land on one profile at once, none may be lost
+    let threads: Vec<_> = (0..8u8)
+        .map(|thread| {
+            let store = store.clone();
+            let key = key.clone();
+            std::thread::spawn(move || {
+                for request in 0..25u8 {
+                    let ip = IpAddr::from([198, 51, thread, request]);
+                    let check = store
+                        .upsert_mem(
+                            key.clone(),
+                            || KeyUsageProfile::new(email),
+                            |profile| profile.check(ip, Some("IN"), now, None, true),
+                        )
+                        .unwrap();
+                    assert!(!check.is_paused);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let profile = store.get(&key)?.unwrap();
+    assert_eq!(profile.current_hour_count, 200);
+    assert_eq!(profile.email, email);
+    assert_eq!(profile.known_countries, vec!["IN".to_string()]);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
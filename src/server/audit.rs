@@ -0,0 +1,343 @@
+//! # Audit trail
+//!
+//! When a key might have leaked, the questions are where it was used from and what was tried
+//! with it. The proxy appends an entry to an audit log for each authentication, one JSON line per
+//! entry:
+//! - API keys and instance tokens, accepted or not, with the user the credential names, the
+//!   start of the key's hash (never the key), client IP and instance.
+//! - Requests to blocked endpoints.
+//!
+//! Failures are always logged. A credential accepted again from the same IP for the same instance
+//! is logged once an hour, the access log has every request.
+//!
+//! Entries go to one file per day, `audit/audit-YYYY-MM-DD.jsonl` under the logs directory, and
+//! are only ever appended. Days older than `BLAZE_AUDIT_RETENTION_DAYS` (90 by default) are
+//! deleted. `BLAZE_AUDIT_LOG=false` turns the log off. Admins search it through
+//! `GET /v1/blz/admin/audit` by user and time range.
+
+use crate::server::crypto::PEPPERED_HASH_PREFIX;
+use crate::server::service::get_logs_path;
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Hex characters of the key's hash kept in entries, enough to tell keys apart
+pub const KEY_FINGERPRINT_LEN: usize = 12;
+
+pub const DEFAULT_QUERY_LIMIT: usize = 1000;
+pub const MAX_QUERY_LIMIT: usize = 10_000;
+
+const DEFAULT_RETENTION_DAYS: u32 = 90;
+
+/// How long the same accepted credential, IP and instance aren't logged again
+const SUCCESS_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What a request authenticated with
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    ApiKey,
+    InstanceToken,
+    BlockedEndpoint,
+}
+
+/// One line of the audit log
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub kind: AuditKind,
+    pub success: bool,
+    #[serde(default)]
+    pub reason: Option<String>, // Error code when it was turned away
+    #[serde(default)]
+    pub user: Option<String>, // Who the credential says it's from, checked or not
+    #[serde(default)]
+    pub key: Option<String>, // Start of the API key's hash
+    pub client_ip: String,
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    pub method: String,
+    pub path: String,
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+impl AuditEvent {
+    /// An authentication attempt that's still going, see `finish`
+    pub fn attempt(
+        kind: AuditKind,
+        client_ip: IpAddr,
+        method: &str,
+        path: &str,
+        request_id: &str,
+    ) -> Self {
+        AuditEvent {
+            timestamp: Utc::now().to_rfc3339(),
+            kind,
+            success: false,
+            reason: None,
+            user: None,
+            key: None,
+            client_ip: client_ip.to_string(),
+            instance_id: None,
+            method: method.to_string(),
+            path: path.to_string(),
+            request_id: Some(request_id.to_string()),
+        }
+    }
+
+    /// How it ended, accepted without a failure code
+    pub fn finish(mut self, failure: Option<&str>) -> Self {
+        self.success = failure.is_none();
+        self.reason = failure.map(str::to_string);
+        self
+    }
+}
+
+/// What an entry shows of an API key, the start of its hash past the scheme
+pub fn key_fingerprint(api_key_hash: &str) -> String {
+    api_key_hash
+        .trim_start_matches(PEPPERED_HASH_PREFIX)
+        .chars()
+        .take(KEY_FINGERPRINT_LEN)
+        .collect()
+}
+
+/// Directory the daily audit files are in
+pub fn get_audit_path() -> PathBuf {
+    get_logs_path().join("audit")
+}
+
+fn day_file(dir: &Path, day: NaiveDate) -> PathBuf {
+    dir.join(format!("audit-{}.jsonl", day.format("%Y-%m-%d")))
+}
+
+fn file_day(path: &Path) -> Option<NaiveDate> {
+    let name = path.file_name()?.to_str()?;
+    let day = name.strip_prefix("audit-")?.strip_suffix(".jsonl")?;
+    NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()
+}
+
+fn event_time(event: &AuditEvent) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&event.timestamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditSettings {
+    pub enabled: bool,
+    pub dir: PathBuf,
+    pub retention_days: u32,
+}
+
+impl AuditSettings {
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let var = |name: &str| std::env::var(name).ok().map(|v| v.trim().to_string());
+        AuditSettings {
+            enabled: var("BLAZE_AUDIT_LOG")
+                .is_none_or(|v| !(v.eq_ignore_ascii_case("false") || v == "0")),
+            dir: get_audit_path(),
+            retention_days: var("BLAZE_AUDIT_RETENTION_DAYS")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_RETENTION_DAYS)
+                .max(1),
+        }
+    }
+}
+
+/// (key or user, client IP, instance) of an accepted credential
+type SeenKey = (Option<String>, String, Option<String>);
+
+/// Audit entries not written yet, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    settings: Arc<AuditSettings>,
+    pending: Arc<Mutex<Vec<AuditEvent>>>,
+    seen: Arc<Mutex<HashMap<SeenKey, Instant>>>, // When each success was last logged
+}
+
+impl AuditLog {
+    pub fn new(settings: AuditSettings) -> Self {
+        AuditLog {
+            settings: Arc::new(settings),
+            pending: Arc::new(Mutex::new(Vec::new())),
+            seen: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if !self.settings.enabled {
+            return;
+        }
+        if event.success {
+            let key = (
+                event.key.clone().or_else(|| event.user.clone()),
+                event.client_ip.clone(),
+                event.instance_id.clone(),
+            );
+            let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+            if seen
+                .get(&key)
+                .is_some_and(|logged| logged.elapsed() < SUCCESS_INTERVAL)
+            {
+                return;
+            }
+            seen.insert(key, Instant::now());
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push(event);
+    }
+
+    /// Appends the pending entries to the file of the day they happened on
+    pub fn flush(&self) -> Result<()> {
+        self.seen
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, logged| logged.elapsed() < SUCCESS_INTERVAL);
+
+        let events = std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()));
+        if events.is_empty() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(&self.settings.dir)?;
+
+        let mut days: HashMap<NaiveDate, String> = HashMap::new();
+        for event in &events {
+            let day = event_time(event).unwrap_or_else(Utc::now).date_naive();
+            let lines = days.entry(day).or_default();
+            lines.push_str(&serde_json::to_string(event)?);
+            lines.push('\n');
+        }
+        for (day, lines) in days {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(day_file(&self.settings.dir, day))?;
+            file.write_all(lines.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the days past the retention, returns how many files went
+    pub fn prune(&self, now: DateTime<Utc>) -> Result<usize> {
+        let cutoff =
+            (now - chrono::Duration::days(self.settings.retention_days as i64)).date_naive();
+        let entries = match std::fs::read_dir(&self.settings.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut pruned = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if file_day(&path).is_some_and(|day| day < cutoff) {
+                std::fs::remove_file(&path)?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+}
+
+/// Entries between `from` and `to`, of one user when given, newest first, at most `limit`
+pub fn query_audit_log(
+    dir: &Path,
+    user: Option<&str>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    limit: usize,
+) -> Result<Vec<AuditEvent>> {
+    let mut events = Vec::new();
+    let mut day = from.date_naive();
+    while day <= to.date_naive() {
+        let path = day_file(dir, day);
+        day = match day.succ_opt() {
+            Some(next) => next,
+            None => break,
+        };
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        // A line cut off by a crash mid-write is skipped, not fatal
+        events.extend(
+            content
+                .lines()
+                .filter_map(|line| serde_json::from_str::<AuditEvent>(line).ok())
+                .filter(|event| {
+                    user.is_none_or(|user| {
+                        event
+                            .user
+                            .as_deref()
+                            .is_some_and(|u| u.eq_ignore_ascii_case(user))
+                    })
+                })
+                .filter(|event| event_time(event).is_some_and(|t| from <= t && t <= to)),
+        );
+    }
+
+    events.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    events.truncate(limit);
+    Ok(events)
+}
+
+#[test]
+fn test_audit_log_query_and_prune() {
+    let dir = std::env::temp_dir().join("test_audit_log");
+    let _ = std::fs::remove_dir_all(&dir);
+    let log = AuditLog::new(AuditSettings {
+        enabled: true,
+        dir: dir.clone(),
+        retention_days: 30,
+    });
+    let ip: IpAddr = "203.0.113.9".parse().unwrap();
+    let now = Utc::now();
+    let event = |user: &str, failure: Option<&str>, at: DateTime<Utc>| {
+        let mut event =
+            AuditEvent::attempt(AuditKind::ApiKey, ip, "GET", "/v1/blazedb/x/inst", "r");
+        event.timestamp = at.to_rfc3339();
+        event.user = Some(user.to_string());
+        event.key = Some(key_fingerprint("0123456789abcdef0123"));
+        event.instance_id = Some("inst".to_string());
+        event.finish(failure)
+    };
+
+    // The same success twice is logged once, failures every time
+    log.record(event("a@x.com", None, now));
+    log.record(event("a@x.com", None, now));
+    log.record(event("a@x.com", Some("instance_mismatch"), now));
+    log.record(event("b@x.com", Some("invalid_api_key"), now));
+    log.record(event(
+        "a@x.com",
+        Some("invalid_api_key"),
+        now - chrono::Duration::days(40),
+    ));
+    log.flush().unwrap();
+
+    let hour = chrono::Duration::hours(1);
+    let all = query_audit_log(&dir, None, now - hour, now + hour, 100).unwrap();
+    assert_eq!(all.len(), 3);
+    let a = query_audit_log(&dir, Some("A@x.com"), now - hour, now + hour, 100).unwrap();
+    assert_eq!(a.len(), 2);
+    assert_eq!(a.iter().filter(|e| e.success).count(), 1);
+    assert_eq!(a[0].key.as_deref(), Some("0123456789ab"));
+
+    let old = now - chrono::Duration::days(41);
+    assert_eq!(query_audit_log(&dir, None, old, now, 100).unwrap().len(), 4);
+    assert_eq!(log.prune(now).unwrap(), 1);
+    assert_eq!(query_audit_log(&dir, None, old, now, 100).unwrap().len(), 3);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,226 @@
+//! # Automatic restarts
+//!
+//! A container whose health check keeps failing doesn't recover on its own, Docker only marks it
+//! unhealthy. A monitor goes over every instance of every verified user and restarts containers
+//! that have been unhealthy for longer than `BLAZE_AUTO_RESTART_AFTER_SECONDS` (3 minutes by
+//! default). Every restart, failed or not, is recorded per instance under
+//! `get_data_path()/restarts.json` so admins can see which instances keep falling over.
+//!
+//! Instances still being provisioned are left to provisioning, and suspended users' containers
+//! are stopped on purpose. When the unhealthy streak started is only kept in memory, after a
+//! service restart the wait starts over.
+
+use crate::server::container::{
+    ContainerSpec, check_container_health, get_instance_state, restart_blazedb_container,
+};
+use crate::server::provisioning::get_provisioning_store;
+use crate::server::schema::{BillingStatus, RestartEvent};
+use crate::server::service::{get_all_users, get_data_path};
+use crate::server::storage::DataStore;
+use crate::{info, warn};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+const DEFAULT_RESTART_AFTER_SECONDS: i64 = 180;
+
+/// Restart events kept per instance, older ones are dropped
+const MAX_EVENTS_PER_INSTANCE: usize = 50;
+
+static RESTART_STORE: OnceLock<DataStore<String, Vec<RestartEvent>>> = OnceLock::new();
+
+/// instance_id -> when the container was first seen unhealthy
+static UNHEALTHY_SINCE: Mutex<Option<HashMap<String, DateTime<Utc>>>> = Mutex::new(None);
+
+/// Automatic restarts keyed by instance id, oldest first
+pub fn get_restart_store() -> DataStore<String, Vec<RestartEvent>> {
+    RESTART_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("restarts.json");
+            DataStore::<String, Vec<RestartEvent>>::new(path)
+                .expect("CRASH!! Failed to initialize restart datastore")
+        })
+        .clone()
+}
+
+fn restart_after() -> chrono::Duration {
+    dotenv::dotenv().ok();
+    let seconds = std::env::var("BLAZE_AUTO_RESTART_AFTER_SECONDS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_RESTART_AFTER_SECONDS);
+    chrono::Duration::seconds(seconds)
+}
+
+/// The instance's automatic restarts, newest first
+pub fn get_restart_events(instance_id: &str) -> Result<Vec<RestartEvent>> {
+    let mut events = get_restart_store()
+        .get(&instance_id.to_string())?
+        .unwrap_or_default();
+    events.reverse();
+    Ok(events)
+}
+
+fn push_event(events: &mut Vec<RestartEvent>, event: RestartEvent) {
+    events.push(event);
+    if events.len() > MAX_EVENTS_PER_INSTANCE {
+        events.drain(..events.len() - MAX_EVENTS_PER_INSTANCE);
+    }
+}
+
+fn record_restart(event: RestartEvent) -> Result<()> {
+    let store = get_restart_store();
+    let mut events = store.get(&event.instance_id)?.unwrap_or_default();
+    let instance_id = event.instance_id.clone();
+    push_event(&mut events, event);
+    store.insert_save(instance_id, events)?;
+    Ok(())
+}
+
+/// Notes the instance as unhealthy (or not) at `now`, returns since when if it's due a restart
+fn track_unhealthy(
+    tracked: &mut HashMap<String, DateTime<Utc>>,
+    instance_id: &str,
+    unhealthy: bool,
+    now: DateTime<Utc>,
+    threshold: chrono::Duration,
+) -> Option<DateTime<Utc>> {
+    if !unhealthy {
+        tracked.remove(instance_id);
+        return None;
+    }
+    let since = *tracked.entry(instance_id.to_string()).or_insert(now);
+    (now - since >= threshold).then_some(since)
+}
+
+/// Whether the container is failing its health check, stopped or starting ones aren't
+async fn is_unhealthy(instance_id: &str) -> Result<bool> {
+    if check_container_health(&format!("blazedb-{}", instance_id)).await? {
+        return Ok(false);
+    }
+    Ok(get_instance_state(instance_id).await? == "unhealthy")
+}
+
+/// Restarts every container that stayed unhealthy past the threshold, returns how many
+/// This is called periodically via a background task
+pub async fn restart_unhealthy_containers() -> Result<usize> {
+    let threshold = restart_after();
+    let provisioning = get_provisioning_store();
+    let mut checked = Vec::new();
+    let mut due = Vec::new();
+
+    for user in get_all_users().await? {
+        if !user.is_verified || user.billing_status == BillingStatus::Suspended {
+            continue;
+        }
+        for instance_id in user.instance_ids() {
+            if provisioning.contains_key(instance_id)? {
+                continue;
+            }
+            let unhealthy = match is_unhealthy(instance_id).await {
+                Ok(unhealthy) => unhealthy,
+                Err(e) => {
+                    warn!("Health check of {} failed: {}", instance_id, e);
+                    continue;
+                }
+            };
+
+            let now = Utc::now();
+            let mut tracked = UNHEALTHY_SINCE.lock().unwrap_or_else(|e| e.into_inner());
+            let tracked = tracked.get_or_insert_with(HashMap::new);
+            checked.push(instance_id.to_string());
+            if let Some(since) = track_unhealthy(tracked, instance_id, unhealthy, now, threshold) {
+                tracked.remove(instance_id); // The next restart waits a full threshold again
+                due.push((instance_id.to_string(), user.clone(), since));
+            }
+        }
+    }
+
+    // Instances that are gone aren't tracked any longer
+    if let Some(tracked) = UNHEALTHY_SINCE
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .as_mut()
+    {
+        tracked.retain(|id, _| checked.contains(id));
+    }
+
+    let mut restarted = 0;
+    for (instance_id, user, since) in due {
+        let spec = ContainerSpec::for_user(&user);
+        let result = restart_blazedb_container(&instance_id, &spec).await;
+        match &result {
+            Ok(()) => {
+                info!(
+                    "Restarted {} of {}, unhealthy since {}",
+                    instance_id,
+                    user.email,
+                    since.to_rfc3339()
+                );
+                restarted += 1;
+            }
+            Err(e) => warn!("Automatic restart of {} failed: {}", instance_id, e),
+        }
+        record_restart(RestartEvent {
+            instance_id,
+            email: user.email.clone(),
+            unhealthy_since: since.to_rfc3339(),
+            restarted_at: Utc::now().to_rfc3339(),
+            error: result.err().map(|e| e.to_string()),
+        })?;
+    }
+
+    Ok(restarted)
+}
+
+#[test]
+fn test_track_unhealthy() {
+    let threshold = chrono::Duration::seconds(180);
+    let start = Utc::now();
+    let at = |s: i64| start + chrono::Duration::seconds(s);
+    let mut tracked = HashMap::new();
+
+    assert_eq!(
+        track_unhealthy(&mut tracked, "a", true, at(0), threshold),
+        None
+    );
+    assert_eq!(
+        track_unhealthy(&mut tracked, "a", true, at(120), threshold),
+        None
+    );
+    assert_eq!(
+        track_unhealthy(&mut tracked, "a", true, at(180), threshold),
+        Some(start)
+    );
+
+    // Recovering in between starts the wait over
+    assert_eq!(
+        track_unhealthy(&mut tracked, "b", true, at(0), threshold),
+        None
+    );
+    assert_eq!(
+        track_unhealthy(&mut tracked, "b", false, at(60), threshold),
+        None
+    );
+    assert_eq!(
+        track_unhealthy(&mut tracked, "b", true, at(200), threshold),
+        None
+    );
+
+    let mut events = Vec::new();
+    for i in 0..MAX_EVENTS_PER_INSTANCE + 5 {
+        push_event(
+            &mut events,
+            RestartEvent {
+                instance_id: "a".to_string(),
+                email: "alice@example.com".to_string(),
+                unhealthy_since: i.to_string(),
+                restarted_at: i.to_string(),
+                error: None,
+            },
+        );
+    }
+    assert_eq!(events.len(), MAX_EVENTS_PER_INSTANCE);
+    assert_eq!(events[0].restarted_at, "5"); // Oldest dropped first
+}
@@ -0,0 +1,253 @@
+//! # Instance backups
+//!
+//! A backup is a tarball of each of an instance's two volumes (config and sources), exported
+//! through a helper container, under `get_backups_path()/<instance_id>/<backup_id>/`. The records
+//! live in `backups.json` next to them, keyed by instance id. Only the newest `backups_kept` of
+//! the owner's plan are kept, older ones are deleted as new ones come in.
+//!
+//! Plans with `backup_every_hours` get backed up on a schedule (weekly on free, daily on paid
+//! plans): a background task backs up every instance whose newest backup is older than that.
+//! Users can also take one any time, and download either volume's tarball.
+//!
+//! Restoring replaces the instance's container and volumes with fresh ones filled from the
+//! backup. Deleting an account backs the instances up before their volumes are removed, unless
+//! the user asked for their data to be wiped.
+
+use crate::server::container::{
+    ContainerSpec, export_instance_volumes, has_instance_volumes, import_instance_volumes,
+    remove_container_with_volumes, spawn_blazedb_container,
+};
+use crate::server::schema::{BackupRecord, BillingStatus};
+use crate::server::service::{get_all_users, get_backups_path};
+use crate::server::storage::DataStore;
+use crate::{info, warn};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+/// The two tarballs of a backup, by volume kind
+pub const BACKUP_VOLUMES: [&str; 2] = ["config", "sources"];
+
+static BACKUP_STORE: OnceLock<DataStore<String, Vec<BackupRecord>>> = OnceLock::new();
+
+/// Backups keyed by instance id, oldest first
+pub fn get_backup_store() -> DataStore<String, Vec<BackupRecord>> {
+    BACKUP_STORE
+        .get_or_init(|| {
+            let path = get_backups_path().join("backups.json");
+            DataStore::<String, Vec<BackupRecord>>::new(path)
+                .expect("CRASH!! Failed to initialize backup datastore")
+        })
+        .clone()
+}
+
+fn new_backup_id() -> String {
+    format!("bak_{}", hex::encode(rand::random::<[u8; 8]>()))
+}
+
+fn backup_dir(instance_id: &str, backup_id: &str) -> PathBuf {
+    get_backups_path().join(instance_id).join(backup_id)
+}
+
+/// The tarball of one volume (see `BACKUP_VOLUMES`) of a backup
+pub fn backup_file(instance_id: &str, backup_id: &str, volume: &str) -> PathBuf {
+    backup_dir(instance_id, backup_id).join(format!("{}.tar", volume))
+}
+
+/// Whether an instance whose newest backup is `latest` is due another one
+fn is_backup_due(
+    latest: Option<&BackupRecord>,
+    every: chrono::Duration,
+    now: DateTime<Utc>,
+) -> bool {
+    latest
+        .and_then(|b| DateTime::parse_from_rfc3339(&b.created_at).ok())
+        .is_none_or(|t| now - t.with_timezone(&Utc) >= every)
+}
+
+/// Drops the oldest backups beyond `keep` from `backups` and returns them
+fn prune_backups(backups: &mut Vec<BackupRecord>, keep: usize) -> Vec<BackupRecord> {
+    let excess = backups.len().saturating_sub(keep);
+    backups.drain(..excess).collect()
+}
+
+/// The instance's backups, newest first
+pub fn list_backups(instance_id: &str) -> Result<Vec<BackupRecord>> {
+    let mut backups = get_backup_store()
+        .get(&instance_id.to_string())?
+        .unwrap_or_default();
+    backups.reverse();
+    Ok(backups)
+}
+
+/// Backs up both volumes of the instance, then deletes backups beyond the newest `keep`
+pub async fn create_backup(
+    instance_id: &str,
+    email: &str,
+    reason: &str,
+    keep: usize,
+) -> Result<BackupRecord> {
+    let id = new_backup_id();
+    let dir = backup_dir(instance_id, &id);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let size_bytes = match export_instance_volumes(instance_id, &dir).await {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = tokio::fs::remove_dir_all(&dir).await;
+            return Err(e.into());
+        }
+    };
+
+    let record = BackupRecord {
+        id,
+        instance_id: instance_id.to_string(),
+        email: email.to_string(),
+        reason: reason.to_string(),
+        size_bytes,
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+
+    let store = get_backup_store();
+    let mut backups = store.get(&instance_id.to_string())?.unwrap_or_default();
+    backups.push(record.clone());
+    let pruned = prune_backups(&mut backups, keep);
+    store.insert_save(instance_id.to_string(), backups)?;
+
+    for old in pruned {
+        if let Err(e) = tokio::fs::remove_dir_all(backup_dir(instance_id, &old.id)).await {
+            warn!("Failed to delete old backup {}: {}", old.id, e);
+        }
+    }
+
+    info!(
+        "Backed up instance {} as {} ({}, {} bytes)",
+        instance_id, record.id, reason, size_bytes
+    );
+
+    Ok(record)
+}
+
+/// Replaces the instance's container and volumes with fresh ones from the backup
+/// Returns None if the instance has no such backup
+pub async fn restore_backup(
+    instance_id: &str,
+    backup_id: &str,
+    spec: &ContainerSpec,
+) -> Result<Option<BackupRecord>> {
+    let Some(record) = list_backups(instance_id)?
+        .into_iter()
+        .find(|b| b.id == backup_id)
+    else {
+        return Ok(None);
+    };
+
+    // Don't wipe anything for a backup whose files are gone
+    for volume in BACKUP_VOLUMES {
+        let file = backup_file(instance_id, backup_id, volume);
+        if !tokio::fs::try_exists(&file).await? {
+            return Err(anyhow::anyhow!(
+                "Backup {} is missing {}",
+                backup_id,
+                file.display()
+            ));
+        }
+    }
+
+    remove_container_with_volumes(instance_id).await?;
+    import_instance_volumes(instance_id, &backup_dir(instance_id, backup_id)).await?;
+    spawn_blazedb_container(instance_id, spec).await?;
+
+    info!(
+        "Restored instance {} from backup {}",
+        instance_id, backup_id
+    );
+
+    Ok(Some(record))
+}
+
+/// Backs up every instance whose plan has a schedule and whose newest backup is old enough
+/// Returns how many were backed up. This is called periodically via a background task
+pub async fn run_scheduled_backups() -> Result<usize> {
+    let now = Utc::now();
+    let store = get_backup_store();
+    let mut backed_up = 0;
+
+    for user in get_all_users().await? {
+        let Some(hours) = user.plans.features.backup_every_hours else {
+            continue;
+        };
+        if !user.is_verified || user.billing_status == BillingStatus::Suspended {
+            continue;
+        }
+        let every = chrono::Duration::hours(i64::from(hours));
+
+        for instance_id in user.instance_ids() {
+            let backups = store.get(instance_id)?.unwrap_or_default();
+            if !is_backup_due(backups.last(), every, now) {
+                continue;
+            }
+            // Nothing to back up before the instance was ever provisioned
+            if !has_instance_volumes(instance_id).await? {
+                continue;
+            }
+            match create_backup(
+                instance_id,
+                &user.email,
+                "scheduled",
+                user.plans.features.backups_kept,
+            )
+            .await
+            {
+                Ok(_) => backed_up += 1,
+                Err(e) => warn!("Scheduled backup of {} failed: {}", instance_id, e),
+            }
+        }
+    }
+
+    Ok(backed_up)
+}
+
+/// Deletes every backup of the instance
+pub async fn delete_backups(instance_id: &str) -> Result<()> {
+    let dir = get_backups_path().join(instance_id);
+    if tokio::fs::try_exists(&dir).await? {
+        tokio::fs::remove_dir_all(&dir).await?;
+    }
+    get_backup_store().delete(&instance_id.to_string())?;
+    Ok(())
+}
+
+#[test]
+fn test_prune_backups() {
+    let backup = |id: &str| BackupRecord {
+        id: id.to_string(),
+        instance_id: "inst".to_string(),
+        email: "alice@example.com".to_string(),
+        reason: "manual".to_string(),
+        size_bytes: 0,
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+
+    let mut backups: Vec<_> = ["a", "b", "c", "d"].into_iter().map(backup).collect();
+    let pruned = prune_backups(&mut backups, 2);
+    assert_eq!(
+        pruned.iter().map(|b| b.id.as_str()).collect::<Vec<_>>(),
+        ["a", "b"]
+    );
+    assert_eq!(
+        backups.iter().map(|b| b.id.as_str()).collect::<Vec<_>>(),
+        ["c", "d"]
+    );
+    assert!(prune_backups(&mut backups, 7).is_empty());
+    assert!(new_backup_id().starts_with("bak_"));
+
+    let now = Utc::now();
+    let week = chrono::Duration::days(7);
+    let mut latest = backup("e");
+    assert!(is_backup_due(None, week, now));
+    assert!(!is_backup_due(Some(&latest), week, now));
+    latest.created_at = (now - chrono::Duration::days(8)).to_rfc3339();
+    assert!(is_backup_due(Some(&latest), week, now));
+}
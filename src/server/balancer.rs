@@ -0,0 +1,178 @@
+//! # Replica load balancing
+//!
+//! An instance can run on several containers, listed as replicas in its route (see `routing`).
+//! The proxy spreads its requests over them by weight with a smooth weighted round-robin, so with
+//! weights 2 and 1 the first replica gets two requests out of three, never all of them in a burst.
+//!
+//! A replica that couldn't be connected to (or answered 502, 503 or 504) for
+//! `BLAZE_PROXY_EJECT_AFTER` (3) requests in a row is ejected. It gets no requests for
+//! `BLAZE_PROXY_EJECT_SECONDS` (30), then it's tried again, and a request that gets through
+//! clears its failures. When every replica is ejected, the one due back first is still tried. When
+//! to give up on the whole instance is up to its circuit breaker (see `circuit`).
+//!
+//! Instances with a single container don't go through any of this.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_EJECT_AFTER: u32 = 3;
+const DEFAULT_EJECT_SECONDS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BalancerSettings {
+    pub eject_after: u32,   // Failures in a row that get a replica ejected
+    pub ejection: Duration, // How long it stays out
+}
+
+impl BalancerSettings {
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.trim().parse().ok());
+        BalancerSettings {
+            eject_after: var("BLAZE_PROXY_EJECT_AFTER")
+                .unwrap_or(DEFAULT_EJECT_AFTER)
+                .max(1),
+            ejection: Duration::from_secs(
+                var("BLAZE_PROXY_EJECT_SECONDS").map_or(DEFAULT_EJECT_SECONDS, |secs| secs as u64),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReplicaState {
+    current: i64,  // Smooth round-robin counter
+    failures: u32, // Since the last request that got through
+    ejected_until: Option<Instant>,
+}
+
+impl ReplicaState {
+    fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| until > now)
+    }
+}
+
+/// instance_id -> base URL -> replica state, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone)]
+pub struct LoadBalancer {
+    settings: BalancerSettings,
+    instances: Arc<Mutex<HashMap<String, HashMap<String, ReplicaState>>>>,
+}
+
+impl LoadBalancer {
+    pub fn new(settings: BalancerSettings) -> Self {
+        LoadBalancer {
+            settings,
+            instances: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Which of the instance's containers (base URL, weight) the next request goes to
+    pub fn pick(&self, instance_id: &str, targets: &[(String, u32)]) -> Option<String> {
+        if targets.len() <= 1 {
+            return targets.first().map(|(url, _)| url.clone());
+        }
+
+        let now = Instant::now();
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let replicas = instances.entry(instance_id.to_string()).or_default();
+        // Replicas taken out of the route are forgotten
+        replicas.retain(|url, _| targets.iter().any(|(target, _)| target == url));
+
+        let healthy: Vec<&(String, u32)> = targets
+            .iter()
+            .filter(|(url, weight)| {
+                *weight > 0 && !replicas.get(url).is_some_and(|r| r.is_ejected(now))
+            })
+            .collect();
+        if healthy.is_empty() {
+            return targets
+                .iter()
+                .min_by_key(|(url, _)| replicas.get(url).and_then(|r| r.ejected_until))
+                .map(|(url, _)| url.clone());
+        }
+
+        let total: i64 = healthy.iter().map(|(_, weight)| *weight as i64).sum();
+        let mut best: Option<(&String, i64)> = None;
+        for (url, weight) in healthy {
+            let replica = replicas.entry(url.clone()).or_default();
+            replica.current += *weight as i64;
+            if best.is_none_or(|(_, current)| replica.current > current) {
+                best = Some((url, replica.current));
+            }
+        }
+
+        let (url, _) = best?;
+        if let Some(replica) = replicas.get_mut(url) {
+            replica.current -= total;
+        }
+        Some(url.clone())
+    }
+
+    /// The replica answered, its failures are forgotten
+    pub fn record_success(&self, instance_id: &str, url: &str) {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(replica) = instances
+            .get_mut(instance_id)
+            .and_then(|replicas| replicas.get_mut(url))
+        {
+            replica.failures = 0;
+            replica.ejected_until = None;
+        }
+    }
+
+    /// The replica failed a request, true if that got it ejected
+    /// Single container instances aren't tracked, their breaker covers them
+    pub fn record_failure(&self, instance_id: &str, url: &str) -> bool {
+        let now = Instant::now();
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(replicas) = instances.get_mut(instance_id) else {
+            return false;
+        };
+        let replica = replicas.entry(url.to_string()).or_default();
+        replica.failures += 1;
+        if replica.failures < self.settings.eject_after || replica.is_ejected(now) {
+            return false;
+        }
+        replica.failures = 0;
+        replica.ejected_until = Some(now + self.settings.ejection);
+        true
+    }
+}
+
+#[test]
+fn test_weighted_round_robin_and_ejection() {
+    let balancer = LoadBalancer::new(BalancerSettings {
+        eject_after: 2,
+        ejection: Duration::from_secs(30),
+    });
+    let targets = vec![
+        ("http://a:8080".to_string(), 2),
+        ("http://b:8080".to_string(), 1),
+    ];
+    let picks = || balancer.pick("inst", &targets).unwrap();
+
+    // Smooth: a, b, a, then again
+    let first: Vec<String> = (0..6).map(|_| picks()).collect();
+    assert_eq!(first[..3], first[3..]);
+    assert_eq!(first.iter().filter(|url| url.contains("//a")).count(), 4);
+    assert_eq!(first[1], "http://b:8080");
+
+    // Single containers aren't balanced or tracked
+    let single = vec![("http://solo:8080".to_string(), 1)];
+    assert_eq!(balancer.pick("solo", &single).unwrap(), "http://solo:8080");
+    assert!(!balancer.record_failure("solo", "http://solo:8080"));
+
+    // b fails twice in a row and is taken out
+    assert!(!balancer.record_failure("inst", "http://b:8080"));
+    balancer.record_success("inst", "http://b:8080");
+    assert!(!balancer.record_failure("inst", "http://b:8080"));
+    assert!(balancer.record_failure("inst", "http://b:8080"));
+    assert!((0..6).all(|_| balancer.pick("inst", &targets).unwrap() == "http://a:8080"));
+
+    // Both out, the one back first is still tried
+    assert!(!balancer.record_failure("inst", "http://a:8080"));
+    assert!(balancer.record_failure("inst", "http://a:8080"));
+    assert_eq!(balancer.pick("inst", &targets).unwrap(), "http://b:8080");
+}
@@ -0,0 +1,1353 @@
+//! # Billing
+//!
+//! Plan upgrades go through Stripe Checkout: we create a Checkout Session for the plan's Stripe
+//! price, remember it as a pending upgrade under `get_billing_path()`, and send the user to the
+//! hosted payment page. Config comes from env:
+//! - `STRIPE_SECRET_KEY`: API key used for Stripe calls
+//! - `STRIPE_PRICE_STARTER`, `STRIPE_PRICE_PRO`: monthly recurring price ids for each paid plan
+//! - `STRIPE_PRICE_STARTER_YEARLY`, `STRIPE_PRICE_PRO_YEARLY`: yearly ones, for plans with a
+//!   `price_per_year`
+//! - `BILLING_SUCCESS_URL`, `BILLING_CANCEL_URL`: where Stripe sends the user afterwards
+//! - `STRIPE_WEBHOOK_SECRET`: signing secret of the webhook endpoint (`whsec_...`)
+//!
+//! Promo codes live in `coupons.json`, each one is mirrored as a Stripe coupon (created the first
+//! time it's used) and applied to the Checkout Session. Redemptions are counted once paid.
+//!
+//! The buyer's billing country and VAT ID are kept on their Stripe customer (one per user, created
+//! on the first checkout) and the tax provider (see `tax`) adds its own fields to the session. The
+//! tax on every payment is recorded with it.
+//!
+//! Referral credit (see `referrals`) is spent before the card is charged. A checkout without a
+//! promo code gets it as a one-off Stripe coupon (Stripe takes one discount per session), and on
+//! renewals the `invoice.created` webhook adds it as a negative line to the draft invoice before
+//! Stripe finalizes and charges it.
+//!
+//! Stripe tells us about payments through the webhook: a completed checkout moves the user to the
+//! plan they paid for, a refunded charge is recorded. Every billing event (plan change, payment,
+//! refund) is appended to the user's history.
+//!
+//! Every paid invoice pushes the user's `current_period_end` back by the billing interval (a month
+//! or a year), renewals are recorded as payments in the history. A background task moves users
+//! whose period ended a few days ago without a renewal back to the free plan.
+//!
+//! Users cancel at the end of the period: the Stripe subscription is set to
+//! `cancel_at_period_end`, the plan and instance stay until `current_period_end` and the same
+//! background task moves them to the free plan right when it's over, without the grace days.
+//!
+//! Switching between paid plans mid-cycle is prorated: the unused part of the current plan is
+//! credited and the rest of the cycle is charged at the target plan's price, both by the second.
+//! The cycle is the user's current period, or starts at the last payment for the current plan. Without a
+//! payment on record (free plan, trial) there's nothing to credit and the target plan starts a
+//! fresh cycle at full price.
+
+use crate::server::crypto::verify_webhook_signature;
+use crate::server::dunning::{mark_past_due, mark_payment_recovered};
+use crate::server::mailer::send_mail;
+use crate::server::organizations::is_managed_member;
+use crate::server::referrals::{
+    add_credit_entry, credit_balance, has_credit_entry, reward_referrer, spendable_credit,
+};
+use crate::server::schema::{
+    BillingEvent, BillingEventKind, BillingInterval, BillingStatus, Coupon, DiscountKind,
+    PendingUpgrade, Plans, ProrationPreview, SubscriptionState, TaxDetails, User,
+};
+use crate::server::secrets::get_secrets;
+use crate::server::service::{
+    change_plan, end_trial, get_all_users, get_billing_path, get_user, get_user_plan, update_user,
+};
+use crate::server::storage::DataStore;
+use crate::server::tasks::get_task_registry;
+use crate::server::tax::{get_tax_provider, vat_id_kind};
+use crate::{error, info, warn};
+use anyhow::Result;
+use chrono::{DateTime, Months, Utc};
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+pub(crate) const STRIPE_API_BASE: &str = "https://api.stripe.com/v1";
+
+/// Plan prices are in whole units of this currency
+const BILLING_CURRENCY: &str = "usd";
+
+/// Days a paid period can be over before the plan lapses, renewals can arrive late
+const RENEWAL_GRACE_DAYS: i64 = 3;
+
+/// How old a webhook signature timestamp can be before we reject it (replay protection)
+const STRIPE_SIGNATURE_TOLERANCE_SECONDS: i64 = 300;
+
+static BILLING_STORE: OnceLock<DataStore<String, PendingUpgrade>> = OnceLock::new();
+static COUPON_STORE: OnceLock<DataStore<String, Coupon>> = OnceLock::new();
+static BILLING_HISTORY_STORE: OnceLock<DataStore<String, Vec<BillingEvent>>> = OnceLock::new();
+
+/// Pending upgrades keyed by Checkout Session id
+pub fn get_billing_store() -> DataStore<String, PendingUpgrade> {
+    BILLING_STORE
+        .get_or_init(|| {
+            let path = get_billing_path().join("pending_upgrades.json");
+            DataStore::<String, PendingUpgrade>::new(path)
+                .expect("CRASH!! Failed to initialize billing datastore")
+        })
+        .clone()
+}
+
+/// Coupons keyed by their (uppercase) code
+pub fn get_coupon_store() -> DataStore<String, Coupon> {
+    COUPON_STORE
+        .get_or_init(|| {
+            let path = get_billing_path().join("coupons.json");
+            DataStore::<String, Coupon>::new(path)
+                .expect("CRASH!! Failed to initialize coupon datastore")
+        })
+        .clone()
+}
+
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+impl Coupon {
+    /// Why the coupon can't be used right now, if it can't
+    pub fn check_usable(&self, now: chrono::DateTime<chrono::Utc>) -> Result<()> {
+        if let Some(expires_at) = &self.expires_at
+            && chrono::DateTime::parse_from_rfc3339(expires_at).is_ok_and(|t| t <= now)
+        {
+            return Err(anyhow::anyhow!("Promo code {} has expired", self.code));
+        }
+        if self
+            .max_redemptions
+            .is_some_and(|max| self.redemptions >= max)
+        {
+            return Err(anyhow::anyhow!("Promo code {} is used up", self.code));
+        }
+        Ok(())
+    }
+
+    /// Monthly price in cents after the discount
+    pub fn apply(&self, price_cents: u32) -> u32 {
+        match self.kind {
+            DiscountKind::Percent => price_cents - price_cents * self.value.min(100) / 100,
+            DiscountKind::FixedCents => price_cents.saturating_sub(self.value),
+        }
+    }
+}
+
+/// Adds a new coupon, codes are case-insensitive and unique
+pub fn create_coupon(
+    code: &str,
+    kind: DiscountKind,
+    value: u32,
+    max_redemptions: Option<u32>,
+    expires_at: Option<String>,
+) -> Result<Coupon> {
+    let code = normalize_code(code);
+    if code.is_empty() {
+        return Err(anyhow::anyhow!("Promo code cannot be empty"));
+    }
+    if value == 0 || (kind == DiscountKind::Percent && value > 100) {
+        return Err(anyhow::anyhow!("Discount value is out of range"));
+    }
+
+    let store = get_coupon_store();
+    if store.contains_key(&code)? {
+        return Err(anyhow::anyhow!("Promo code {} already exists", code));
+    }
+
+    let coupon = Coupon {
+        code: code.clone(),
+        kind,
+        value,
+        max_redemptions,
+        redemptions: 0,
+        expires_at,
+        stripe_coupon_id: None,
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    store.insert_save(code, coupon.clone())?;
+    Ok(coupon)
+}
+
+/// Looks up a promo code and checks it can be used
+pub fn find_usable_coupon(code: &str) -> Result<Coupon> {
+    let code = normalize_code(code);
+    let coupon = get_coupon_store()
+        .get(&code)?
+        .ok_or_else(|| anyhow::anyhow!("Promo code {} doesn't exist", code))?;
+    coupon.check_usable(chrono::Utc::now())?;
+    Ok(coupon)
+}
+
+/// Counts one redemption of a promo code
+fn redeem_coupon(code: &str) -> Result<()> {
+    let store = get_coupon_store();
+    let code = normalize_code(code);
+    if let Some(mut coupon) = store.get(&code)? {
+        coupon.redemptions += 1;
+        store.insert_save(code, coupon)?;
+    }
+    Ok(())
+}
+
+/// Stripe coupon mirroring ours, created on first use
+async fn ensure_stripe_coupon(secret_key: &str, mut coupon: Coupon) -> Result<String> {
+    if let Some(id) = &coupon.stripe_coupon_id {
+        return Ok(id.clone());
+    }
+
+    let mut params = vec![
+        ("duration", "once".to_string()),
+        ("name", coupon.code.clone()),
+    ];
+    match coupon.kind {
+        DiscountKind::Percent => params.push(("percent_off", coupon.value.to_string())),
+        DiscountKind::FixedCents => {
+            params.push(("amount_off", coupon.value.to_string()));
+            params.push(("currency", BILLING_CURRENCY.to_string()));
+        }
+    }
+
+    let id = create_stripe_coupon(secret_key, &params).await?;
+    coupon.stripe_coupon_id = Some(id.clone());
+    get_coupon_store().insert_save(coupon.code.clone(), coupon)?;
+
+    Ok(id)
+}
+
+async fn create_stripe_coupon(secret_key: &str, params: &[(&str, String)]) -> Result<String> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/coupons", STRIPE_API_BASE))
+        .bearer_auth(secret_key)
+        .form(params)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(stripe_error("coupon", response).await);
+    }
+
+    let stripe_coupon: StripeObject = response.json().await?;
+    Ok(stripe_coupon.id)
+}
+
+/// Builds an error from a failed Stripe call
+pub(crate) async fn stripe_error(what: &str, response: reqwest::Response) -> anyhow::Error {
+    let status = response.status();
+    let message = response
+        .json::<StripeErrorBody>()
+        .await
+        .ok()
+        .and_then(|body| body.error.message)
+        .unwrap_or_else(|| "unknown error".to_string());
+    anyhow::anyhow!("Stripe rejected {} ({}): {}", what, status, message)
+}
+
+/// Billing events keyed by user email, oldest first
+pub fn get_billing_history_store() -> DataStore<String, Vec<BillingEvent>> {
+    BILLING_HISTORY_STORE
+        .get_or_init(|| {
+            let path = get_billing_path().join("history.json");
+            DataStore::<String, Vec<BillingEvent>>::new(path)
+                .expect("CRASH!! Failed to initialize billing history datastore")
+        })
+        .clone()
+}
+
+/// Appends an event to the user's billing history
+pub fn record_billing_event(email: &str, event: BillingEvent) -> Result<()> {
+    let store = get_billing_history_store();
+    let mut history = store.get(&email.to_string())?.unwrap_or_default();
+    history.push(event);
+    store.insert_save(email.to_string(), history)?;
+    Ok(())
+}
+
+/// The user's billing history, newest first
+pub fn get_billing_history(email: &str) -> Result<Vec<BillingEvent>> {
+    let mut history = get_billing_history_store()
+        .get(&email.to_string())?
+        .unwrap_or_default();
+    history.reverse();
+    Ok(history)
+}
+
+/// Start of the billing cycle of the plan the user is on: their last payment for it
+fn last_payment_for(history: &[BillingEvent], plan: &str) -> Option<DateTime<Utc>> {
+    history
+        .iter()
+        .rev()
+        .filter(|e| e.kind == BillingEventKind::Payment && e.plan == plan)
+        .find_map(|e| DateTime::parse_from_rfc3339(&e.created_at).ok())
+        .map(|t| t.with_timezone(&Utc))
+}
+
+/// `cents` scaled by `part / whole`, rounded to the nearest cent
+fn prorated_cents(cents: i64, part: i64, whole: i64) -> i64 {
+    if whole <= 0 {
+        return 0;
+    }
+    (cents * part + whole / 2) / whole
+}
+
+/// Credit and charge for switching from `current` to `target` at `now`
+/// `paid_at` is the last payment for `current`, None when there's nothing to credit
+pub fn prorate(
+    current: &Plans,
+    target: &Plans,
+    interval: BillingInterval,
+    paid_at: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> ProrationPreview {
+    // Plans without a yearly price are billed twelve months at once
+    let period_cents = |plan: &Plans| {
+        plan.price_for(interval)
+            .unwrap_or(plan.price_per_month * interval.months()) as i64
+            * 100
+    };
+    let target_cents = period_cents(target);
+    let month_after = |t: DateTime<Utc>| add_interval(t, interval);
+
+    let (cycle_start, cycle_end, credit_cents, charge_cents) = match paid_at {
+        Some(mut start) if current.price_per_month > 0 && start <= now => {
+            // Renewals aren't recorded one by one, roll forward to the cycle `now` is in
+            let mut end = month_after(start);
+            while end <= now {
+                start = end;
+                end = month_after(start);
+            }
+            let whole = (end - start).num_seconds();
+            let remaining = (end - now).num_seconds();
+            (
+                start,
+                end,
+                prorated_cents(period_cents(current), remaining, whole),
+                prorated_cents(target_cents, remaining, whole),
+            )
+        }
+        _ => (now, month_after(now), 0, target_cents),
+    };
+
+    ProrationPreview {
+        current_plan: current.name.clone(),
+        target_plan: target.name.clone(),
+        cycle_start: cycle_start.to_rfc3339(),
+        cycle_end: cycle_end.to_rfc3339(),
+        days_remaining: (cycle_end - now).num_days(),
+        credit_cents,
+        charge_cents,
+        amount_due_cents: charge_cents - credit_cents,
+        currency: BILLING_CURRENCY.to_string(),
+    }
+}
+
+/// What moving the user to `target` right now would cost, nothing is changed
+pub async fn preview_plan_change(email: &str, target: &Plans) -> Result<ProrationPreview> {
+    let user = get_user(email)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+    let interval = user.billing_interval;
+
+    // The current period when we know it, otherwise the last payment starts it
+    let period_start = user
+        .current_period_end
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|end| end.with_timezone(&Utc))
+        .and_then(|end| end.checked_sub_months(Months::new(interval.months())));
+    let paid_at = match period_start {
+        Some(start) => Some(start),
+        None => {
+            let history = get_billing_history_store()
+                .get(&email.to_string())?
+                .unwrap_or_default();
+            last_payment_for(&history, &user.plans.name)
+        }
+    };
+    Ok(prorate(&user.plans, target, interval, paid_at, Utc::now()))
+}
+
+/// The parts of a Stripe Checkout Session we care about
+#[derive(Deserialize, Debug, Clone)]
+struct StripeCheckoutSession {
+    id: String,
+    url: Option<String>,
+}
+
+/// Any Stripe object, when only the id matters
+#[derive(Deserialize, Debug, Clone)]
+struct StripeObject {
+    id: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct StripeErrorBody {
+    error: StripeError,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct StripeError {
+    message: Option<String>,
+}
+
+pub(crate) fn env_var(name: &str) -> Result<String> {
+    dotenv::dotenv().ok();
+    std::env::var(name).map_err(|_| anyhow::anyhow!("{} must be set in env", name))
+}
+
+/// Stripe price id for a paid plan, e.g. `STRIPE_PRICE_PRO` or `STRIPE_PRICE_PRO_YEARLY` for "Pro"
+pub(crate) fn stripe_price_id(plan: &Plans, interval: BillingInterval) -> Result<String> {
+    match interval {
+        BillingInterval::Monthly => env_var(&format!("STRIPE_PRICE_{}", plan.name.to_uppercase())),
+        BillingInterval::Yearly => {
+            env_var(&format!("STRIPE_PRICE_{}_YEARLY", plan.name.to_uppercase()))
+        }
+    }
+}
+
+/// Form fields of the Checkout Session create call
+fn checkout_form_params(
+    email: &str,
+    customer_id: &str,
+    plan: &Plans,
+    interval: BillingInterval,
+    price_id: &str,
+    success_url: &str,
+    cancel_url: &str,
+) -> Vec<(&'static str, String)> {
+    vec![
+        ("mode", "subscription".to_string()),
+        ("line_items[0][price]", price_id.to_string()),
+        ("line_items[0][quantity]", "1".to_string()),
+        ("customer", customer_id.to_string()),
+        ("client_reference_id", email.to_string()),
+        ("metadata[plan]", plan.name.clone()),
+        ("metadata[interval]", interval.as_str().to_string()),
+        ("success_url", success_url.to_string()),
+        ("cancel_url", cancel_url.to_string()),
+    ]
+}
+
+/// The user's Stripe customer with their billing country and VAT ID, created on the first checkout
+async fn ensure_stripe_customer(secret_key: &str, user: &User, tax: &TaxDetails) -> Result<String> {
+    let client = reqwest::Client::new();
+    let mut params = vec![("email", user.email.clone())];
+    if let Some(country) = &tax.country {
+        params.push(("address[country]", country.clone()));
+    }
+
+    let url = match &user.stripe_customer_id {
+        Some(id) => format!("{}/customers/{}", STRIPE_API_BASE, id),
+        None => format!("{}/customers", STRIPE_API_BASE),
+    };
+    let response = client
+        .post(url)
+        .bearer_auth(secret_key)
+        .form(&params)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(stripe_error("customer", response).await);
+    }
+    let customer: StripeObject = response.json().await?;
+
+    // Tax ids are added one by one, only a new one is sent
+    let known_vat_id = user.stripe_customer_id.is_some() && user.tax.vat_id == tax.vat_id;
+    if let (Some(vat_id), Some(country)) = (&tax.vat_id, &tax.country)
+        && !known_vat_id
+    {
+        let (kind, _) = vat_id_kind(country)
+            .ok_or_else(|| anyhow::anyhow!("No VAT IDs for country {}", country))?;
+        let response = client
+            .post(format!(
+                "{}/customers/{}/tax_ids",
+                STRIPE_API_BASE, customer.id
+            ))
+            .bearer_auth(secret_key)
+            .form(&[("type", kind), ("value", vat_id.as_str())])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(stripe_error("customer tax id", response).await);
+        }
+    }
+    Ok(customer.id)
+}
+
+/// Creates a Stripe Checkout Session for the plan and records it as a pending upgrade
+/// A promo code, when given, must be usable and is applied as a Stripe discount, otherwise the
+/// user's referral credit is. Empty tax details fall back to the ones of the last checkout
+/// Returns (checkout_url, session_id)
+pub async fn create_checkout_session(
+    email: &str,
+    plan: &Plans,
+    interval: BillingInterval,
+    promo_code: Option<&str>,
+    tax: TaxDetails,
+) -> Result<(String, String)> {
+    if plan.price_per_month == 0 {
+        return Err(anyhow::anyhow!("{} plan can't be bought", plan.name));
+    }
+    if plan.price_for(interval).is_none() {
+        return Err(anyhow::anyhow!(
+            "{} plan isn't available {}",
+            plan.name,
+            interval.as_str()
+        ));
+    }
+
+    let coupon = promo_code.map(find_usable_coupon).transpose()?;
+    let user = get_user(email)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+    let tax = if tax.is_empty() {
+        user.tax.clone()
+    } else {
+        tax
+    };
+
+    let secret_key = get_secrets().require("STRIPE_SECRET_KEY")?;
+    let price_id = stripe_price_id(plan, interval)?;
+    let success_url = env_var("BILLING_SUCCESS_URL")?;
+    let cancel_url = env_var("BILLING_CANCEL_URL")?;
+
+    let customer_id = ensure_stripe_customer(secret_key, &user, &tax).await?;
+    let saved_customer_id = customer_id.clone();
+    let saved_tax = tax.clone();
+    update_user(&user.email, |user| {
+        user.stripe_customer_id = Some(saved_customer_id);
+        user.tax = saved_tax;
+    })
+    .await?;
+
+    let mut params = checkout_form_params(
+        email,
+        &customer_id,
+        plan,
+        interval,
+        &price_id,
+        &success_url,
+        &cancel_url,
+    );
+    params.extend(get_tax_provider().checkout_params());
+    let mut credit_cents = 0;
+    if let Some(coupon) = &coupon {
+        let stripe_coupon_id = ensure_stripe_coupon(secret_key, coupon.clone()).await?;
+        params.push(("discounts[0][coupon]", stripe_coupon_id));
+        params.push(("metadata[promo_code]", coupon.code.clone()));
+    } else {
+        let price_cents = plan.price_for(interval).unwrap_or(0) as i64 * 100;
+        credit_cents = spendable_credit(credit_balance(email)?, price_cents);
+    }
+    if credit_cents > 0 {
+        // One-off coupon for exactly this session's credit
+        let stripe_coupon_id = create_stripe_coupon(
+            secret_key,
+            &[
+                ("duration", "once".to_string()),
+                ("name", "Referral credit".to_string()),
+                ("amount_off", credit_cents.to_string()),
+                ("currency", BILLING_CURRENCY.to_string()),
+                ("max_redemptions", "1".to_string()),
+            ],
+        )
+        .await?;
+        params.push(("discounts[0][coupon]", stripe_coupon_id));
+        params.push(("metadata[credit_cents]", credit_cents.to_string()));
+    }
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/checkout/sessions", STRIPE_API_BASE))
+        .bearer_auth(secret_key)
+        .form(&params)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(stripe_error("checkout session", response).await);
+    }
+
+    let session: StripeCheckoutSession = response.json().await?;
+    let checkout_url = session
+        .url
+        .ok_or_else(|| anyhow::anyhow!("Stripe returned a checkout session without a url"))?;
+
+    get_billing_store().insert_save(
+        session.id.clone(),
+        PendingUpgrade {
+            session_id: session.id.clone(),
+            email: email.to_string(),
+            plan: plan.name.clone(),
+            status: "pending".to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+            promo_code: coupon.map(|c| c.code),
+            interval,
+            credit_cents,
+            tax,
+        },
+    )?;
+
+    Ok((checkout_url, session.id))
+}
+
+/// Checks a `Stripe-Signature` header (`t=...,v1=...`) against the raw request body
+/// Stripe signs like our own webhooks do, see `crypto::verify_webhook_signature`
+pub fn verify_stripe_signature(payload: &[u8], header: &str, secret: &str, now: i64) -> bool {
+    verify_webhook_signature(
+        payload,
+        header,
+        secret.as_bytes(),
+        now,
+        STRIPE_SIGNATURE_TOLERANCE_SECONDS,
+    )
+}
+
+/// The parts of a Stripe webhook event we care about, `object` depends on the event type
+#[derive(Deserialize, Debug, Clone)]
+struct StripeEvent {
+    #[serde(rename = "type")]
+    event_type: String,
+    data: StripeEventData,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct StripeEventData {
+    object: serde_json::Value,
+}
+
+/// Verifies and handles a Stripe webhook delivery
+/// Stripe retries failed deliveries, so handling the same event twice must be harmless
+pub async fn handle_stripe_webhook(payload: &[u8], signature_header: &str) -> Result<()> {
+    let secret = get_secrets().require("STRIPE_WEBHOOK_SECRET")?;
+    if !verify_stripe_signature(
+        payload,
+        signature_header,
+        secret,
+        chrono::Utc::now().timestamp(),
+    ) {
+        return Err(anyhow::anyhow!("Invalid Stripe signature"));
+    }
+
+    let event: StripeEvent = serde_json::from_slice(payload)?;
+    let object = &event.data.object;
+
+    match event.event_type.as_str() {
+        // Delayed payment methods (bank debits) complete unpaid and settle with one of the others
+        "checkout.session.completed" | "checkout.session.async_payment_succeeded" => {
+            complete_checkout(object).await
+        }
+        "checkout.session.expired" => close_checkout(object, "expired"),
+        "checkout.session.async_payment_failed" => close_checkout(object, "failed"),
+        "charge.refunded" => record_refund(object).await,
+        "invoice.payment_failed" => invoice_payment_failed(object).await,
+        "invoice.created" => apply_credit_to_invoice(object).await,
+        "invoice.paid" => invoice_paid(object).await,
+        other => {
+            info!("Ignoring Stripe event: {}", other);
+            Ok(())
+        }
+    }
+}
+
+fn object_str<'a>(object: &'a serde_json::Value, key: &str) -> Option<&'a str> {
+    object.get(key).and_then(|v| v.as_str())
+}
+
+/// Whether the session's payment went through, fully discounted ones need none
+fn is_checkout_paid(session: &serde_json::Value) -> bool {
+    matches!(
+        object_str(session, "payment_status"),
+        Some("paid" | "no_payment_required")
+    )
+}
+
+async fn complete_checkout(session: &serde_json::Value) -> Result<()> {
+    let session_id =
+        object_str(session, "id").ok_or_else(|| anyhow::anyhow!("Checkout session without id"))?;
+    if !is_checkout_paid(session) {
+        info!(
+            "Checkout {} completed, waiting for its payment to settle",
+            session_id
+        );
+        return Ok(());
+    }
+
+    let store = get_billing_store();
+    let Some(mut pending) = store.get(&session_id.to_string())? else {
+        warn!("Stripe checkout {} has no pending upgrade", session_id);
+        return Ok(());
+    };
+    if pending.status == "completed" {
+        return Ok(()); // Redelivery
+    }
+
+    let plan = Plans::by_name(&pending.plan)
+        .ok_or_else(|| anyhow::anyhow!("Unknown plan in pending upgrade: {}", pending.plan))?;
+
+    // "paid" means the payment is recorded but the plan change failed, Stripe's retry finishes it
+    if pending.status != "paid" {
+        let description = match &pending.promo_code {
+            Some(code) => format!(
+                "Payment for the {} plan, {} (promo code {})",
+                plan.name,
+                pending.interval.as_str(),
+                code
+            ),
+            None => format!(
+                "Payment for the {} plan, {}",
+                plan.name,
+                pending.interval.as_str()
+            ),
+        };
+        record_billing_event(
+            &pending.email,
+            BillingEvent {
+                kind: BillingEventKind::Payment,
+                description,
+                plan: plan.name.clone(),
+                previous_plan: None,
+                amount_cents: session.get("amount_total").and_then(|v| v.as_i64()),
+                currency: object_str(session, "currency").map(str::to_string),
+                reference: Some(session_id.to_string()),
+                tax: Some(get_tax_provider().tax_charged(session, &pending.tax)),
+                created_at: chrono::Utc::now().to_rfc3339(),
+            },
+        )?;
+        if let Some(code) = &pending.promo_code {
+            redeem_coupon(code)?;
+        }
+        if pending.credit_cents > 0 {
+            // The balance may have been spent on something else since the session was created
+            let spent = spendable_credit(credit_balance(&pending.email)?, pending.credit_cents);
+            if spent > 0 {
+                add_credit_entry(
+                    &pending.email,
+                    -spent,
+                    format!("Applied to the {} plan checkout", plan.name),
+                    session_id,
+                )?;
+            }
+        }
+        reward_referrer(&pending.email).await?;
+        pending.status = "paid".to_string();
+        store.insert_save(session_id.to_string(), pending.clone())?;
+    }
+
+    if get_user_plan(&pending.email).await?.name != plan.name {
+        change_plan(&pending.email, plan).await?;
+    }
+    // Paying during a trial keeps the plan for good
+    end_trial(&pending.email).await?;
+
+    let interval = pending.interval;
+    let period_end = add_interval(chrono::Utc::now(), interval).to_rfc3339();
+    let subscription_id = object_str(session, "subscription").map(str::to_string);
+    update_user(&pending.email, |user| {
+        user.billing_interval = interval;
+        user.current_period_end = Some(period_end);
+        user.subscription_state = SubscriptionState::Active;
+        if subscription_id.is_some() {
+            user.stripe_subscription_id = subscription_id;
+        }
+    })
+    .await?;
+
+    pending.status = "completed".to_string();
+    store.insert_save(session_id.to_string(), pending.clone())?;
+
+    info!(
+        "Checkout {} completed, {} is now on {}",
+        session_id, pending.email, pending.plan
+    );
+    Ok(())
+}
+
+/// Marks a pending checkout `status` ("expired" or "failed"), the plan stays as it is
+fn close_checkout(session: &serde_json::Value, status: &str) -> Result<()> {
+    let Some(session_id) = object_str(session, "id") else {
+        return Ok(());
+    };
+
+    let store = get_billing_store();
+    if let Some(mut pending) = store.get(&session_id.to_string())?
+        && pending.status == "pending"
+    {
+        pending.status = status.to_string();
+        store.insert_save(session_id.to_string(), pending)?;
+    }
+    Ok(())
+}
+
+async fn record_refund(charge: &serde_json::Value) -> Result<()> {
+    let charge_id = object_str(charge, "id").ok_or_else(|| anyhow::anyhow!("Charge without id"))?;
+
+    let email = charge
+        .pointer("/billing_details/email")
+        .and_then(|v| v.as_str())
+        .or_else(|| object_str(charge, "receipt_email"))
+        .ok_or_else(|| anyhow::anyhow!("Refunded charge {} has no email", charge_id))?
+        .to_string();
+
+    let already_recorded = get_billing_history_store()
+        .get(&email)?
+        .unwrap_or_default()
+        .iter()
+        .any(|e| e.kind == BillingEventKind::Refund && e.reference.as_deref() == Some(charge_id));
+    if already_recorded {
+        return Ok(());
+    }
+
+    let plan = get_user_plan(&email).await?;
+
+    record_billing_event(
+        &email,
+        BillingEvent {
+            kind: BillingEventKind::Refund,
+            description: format!("Refund on the {} plan", plan.name),
+            plan: plan.name,
+            previous_plan: None,
+            amount_cents: charge.get("amount_refunded").and_then(|v| v.as_i64()),
+            currency: object_str(charge, "currency").map(str::to_string),
+            reference: Some(charge_id.to_string()),
+            tax: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
+        },
+    )?;
+
+    info!("Recorded refund {} for {}", charge_id, email);
+    Ok(())
+}
+
+/// Email of the customer an invoice belongs to
+fn invoice_email(invoice: &serde_json::Value) -> Result<String> {
+    object_str(invoice, "customer_email")
+        .map(str::to_string)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Invoice {} has no customer email",
+                object_str(invoice, "id").unwrap_or("?")
+            )
+        })
+}
+
+/// Records the failure and starts dunning, Stripe sends this for every failed retry of an invoice
+async fn invoice_payment_failed(invoice: &serde_json::Value) -> Result<()> {
+    let invoice_id =
+        object_str(invoice, "id").ok_or_else(|| anyhow::anyhow!("Invoice without id"))?;
+    let email = invoice_email(invoice)?;
+
+    let already_recorded = get_billing_history_store()
+        .get(&email)?
+        .unwrap_or_default()
+        .iter()
+        .any(|e| {
+            e.kind == BillingEventKind::PaymentFailed && e.reference.as_deref() == Some(invoice_id)
+        });
+    if !already_recorded {
+        let plan = get_user_plan(&email).await?;
+        record_billing_event(
+            &email,
+            BillingEvent {
+                kind: BillingEventKind::PaymentFailed,
+                description: format!("Payment for the {} plan failed", plan.name),
+                plan: plan.name,
+                previous_plan: None,
+                amount_cents: invoice.get("amount_due").and_then(|v| v.as_i64()),
+                currency: object_str(invoice, "currency").map(str::to_string),
+                reference: Some(invoice_id.to_string()),
+                tax: None,
+                created_at: chrono::Utc::now().to_rfc3339(),
+            },
+        )?;
+    }
+
+    mark_past_due(&email).await
+}
+
+/// Spends the user's credit on a renewal invoice while it's still a draft
+/// Stripe finalizes and charges drafts about an hour after creating them
+async fn apply_credit_to_invoice(invoice: &serde_json::Value) -> Result<()> {
+    if object_str(invoice, "status") != Some("draft")
+        || object_str(invoice, "billing_reason") != Some("subscription_cycle")
+    {
+        return Ok(());
+    }
+    let invoice_id =
+        object_str(invoice, "id").ok_or_else(|| anyhow::anyhow!("Invoice without id"))?;
+    let customer = object_str(invoice, "customer")
+        .ok_or_else(|| anyhow::anyhow!("Invoice without customer"))?;
+    let email = invoice_email(invoice)?;
+    if has_credit_entry(&email, invoice_id)? {
+        return Ok(()); // Redelivery
+    }
+
+    let amount_due = invoice
+        .get("amount_due")
+        .and_then(|v| v.as_i64())
+        .unwrap_or(0);
+    let credit_cents = spendable_credit(credit_balance(&email)?, amount_due);
+    if credit_cents == 0 {
+        return Ok(());
+    }
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/invoiceitems", STRIPE_API_BASE))
+        .bearer_auth(get_secrets().require("STRIPE_SECRET_KEY")?)
+        // A retry after the ledger write failed must not add a second line
+        .header("Idempotency-Key", format!("credit-{}", invoice_id))
+        .form(&[
+            ("customer", customer.to_string()),
+            ("invoice", invoice_id.to_string()),
+            ("amount", (-credit_cents).to_string()),
+            ("currency", BILLING_CURRENCY.to_string()),
+            ("description", "Referral credit".to_string()),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(stripe_error("invoice item", response).await);
+    }
+
+    add_credit_entry(
+        &email,
+        -credit_cents,
+        "Applied to a renewal invoice".to_string(),
+        invoice_id,
+    )?;
+    info!(
+        "Applied {} cents of credit to invoice {} of {}",
+        credit_cents, invoice_id, email
+    );
+    Ok(())
+}
+
+async fn invoice_paid(invoice: &serde_json::Value) -> Result<()> {
+    let email = invoice_email(invoice)?;
+    // The first invoice of a subscription is the checkout, recorded when it completed
+    if object_str(invoice, "billing_reason") == Some("subscription_cycle") {
+        record_renewal_payment(&email, invoice).await?;
+    }
+    // Subscription invoices carry the period they pay for on the line item
+    let period_end = invoice
+        .pointer("/lines/data/0/period/end")
+        .and_then(|v| v.as_i64())
+        .and_then(|t| DateTime::from_timestamp(t, 0));
+    renew_billing_period(&email, period_end).await?;
+    mark_payment_recovered(&email).await
+}
+
+/// Adds a paid renewal invoice to the billing history, once
+async fn record_renewal_payment(email: &String, invoice: &serde_json::Value) -> Result<()> {
+    let invoice_id =
+        object_str(invoice, "id").ok_or_else(|| anyhow::anyhow!("Invoice without id"))?;
+    let already_recorded = get_billing_history_store()
+        .get(email)?
+        .unwrap_or_default()
+        .iter()
+        .any(|e| e.kind == BillingEventKind::Payment && e.reference.as_deref() == Some(invoice_id));
+    if already_recorded {
+        return Ok(());
+    }
+
+    let user = get_user(email)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Paid invoice {} of unknown user", invoice_id))?;
+    record_billing_event(
+        email,
+        BillingEvent {
+            kind: BillingEventKind::Payment,
+            description: format!(
+                "Renewal of the {} plan, {}",
+                user.plans.name,
+                user.billing_interval.as_str()
+            ),
+            plan: user.plans.name,
+            previous_plan: None,
+            amount_cents: invoice.get("amount_paid").and_then(|v| v.as_i64()),
+            currency: object_str(invoice, "currency").map(str::to_string),
+            reference: Some(invoice_id.to_string()),
+            tax: Some(get_tax_provider().tax_charged(invoice, &user.tax)),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        },
+    )
+}
+
+fn add_interval(t: DateTime<Utc>, interval: BillingInterval) -> DateTime<Utc> {
+    t.checked_add_months(Months::new(interval.months()))
+        .unwrap_or(t)
+}
+
+/// Pushes the user's paid period back after a renewal, to `period_end` when Stripe sent one
+async fn renew_billing_period(email: &str, period_end: Option<DateTime<Utc>>) -> Result<()> {
+    let Some(user) = get_user(email).await? else {
+        return Ok(());
+    };
+    if user.plans.price_per_month == 0 {
+        return Ok(());
+    }
+
+    let period_end = period_end.unwrap_or_else(|| {
+        let current_end = user
+            .current_period_end
+            .as_deref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        let base = current_end.map_or(Utc::now(), |end| end.max(Utc::now()));
+        add_interval(base, user.billing_interval)
+    });
+    update_user(email, |user| {
+        user.current_period_end = Some(period_end.to_rfc3339())
+    })
+    .await?;
+    Ok(())
+}
+
+/// Why the user can't cancel their subscription, if they can't
+pub fn check_can_cancel(user: &User) -> std::result::Result<(), String> {
+    if user.plans.price_per_month == 0 {
+        return Err("You're on the free plan, there's nothing to cancel".to_string());
+    }
+    if user.trial_expires_at.is_some() {
+        return Err("Trials end on their own, there's nothing to cancel".to_string());
+    }
+    if user.subscription_state == SubscriptionState::CancelAtPeriodEnd {
+        return Err("Your subscription is already cancelled".to_string());
+    }
+    Ok(())
+}
+
+/// Cancels the user's subscription at the end of the paid period and returns when that is
+/// Nothing changes until then, the plan and instance stay
+pub async fn cancel_subscription(email: &String) -> Result<String> {
+    let user = get_user(email)
+        .await?
+        .filter(|u| u.is_verified)
+        .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+    check_can_cancel(&user).map_err(|e| anyhow::anyhow!(e))?;
+    if is_managed_member(&user)? {
+        return Err(anyhow::anyhow!("Your plan is managed by your organization"));
+    }
+
+    match &user.stripe_subscription_id {
+        Some(subscription_id) => {
+            let response = reqwest::Client::new()
+                .post(format!(
+                    "{}/subscriptions/{}",
+                    STRIPE_API_BASE, subscription_id
+                ))
+                .bearer_auth(get_secrets().require("STRIPE_SECRET_KEY")?)
+                .form(&[("cancel_at_period_end", "true")])
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(stripe_error("subscription cancellation", response).await);
+            }
+        }
+        // Upgraded by an admin, or paid before subscription ids were kept
+        None => warn!(
+            "{} cancelled without a Stripe subscription on record, cancel it in Stripe by hand",
+            email
+        ),
+    }
+
+    // Without a paid period on record the plan ends right away
+    let access_until = user
+        .current_period_end
+        .clone()
+        .unwrap_or_else(|| Utc::now().to_rfc3339());
+    let period_end = access_until.clone();
+    update_user(email, |user| {
+        user.subscription_state = SubscriptionState::CancelAtPeriodEnd;
+        user.current_period_end = Some(period_end);
+    })
+    .await?;
+
+    record_billing_event(
+        email,
+        BillingEvent {
+            kind: BillingEventKind::Cancellation,
+            description: format!(
+                "Subscription to the {} plan cancelled, ends {}",
+                user.plans.name, access_until
+            ),
+            plan: user.plans.name.clone(),
+            previous_plan: None,
+            amount_cents: None,
+            currency: None,
+            reference: user.stripe_subscription_id.clone(),
+            tax: None,
+            created_at: Utc::now().to_rfc3339(),
+        },
+    )?;
+
+    send_cancellation_mail(&user, &access_until);
+    info!(
+        "{} cancelled the {} plan, access until {}",
+        email, user.plans.name, access_until
+    );
+    Ok(access_until)
+}
+
+fn send_cancellation_mail(user: &User, access_until: &str) {
+    let until = DateTime::parse_from_rfc3339(access_until)
+        .map(|t| t.format("%B %-d, %Y").to_string())
+        .unwrap_or_else(|_| access_until.to_string());
+    let text = format!(
+        "Your {} subscription is cancelled and won't renew. You keep the plan and your instance until {}, after that you're moved to the free plan. Your data is kept.",
+        user.plans.name, until
+    );
+    let plain_body = format!("Hi {},\n\n{}", user.username, text);
+    let html_body = format!(
+        r#"
+        <html>
+        <body style="font-family: sans-serif;">
+            <h2>Your subscription is cancelled</h2>
+            <p>Hi {},</p>
+            <p>{}</p>
+        </body>
+        </html>
+        "#,
+        user.username, text
+    );
+
+    let email = user.email.clone();
+    get_task_registry().spawn("cancellation-mail", |_| async move {
+        let sent = tokio::task::spawn_blocking(move || {
+            send_mail(
+                &email,
+                "Your BlazeDB subscription is cancelled",
+                plain_body,
+                html_body,
+            )
+            .map_err(|e| (email, e))
+        })
+        .await;
+        if let Ok(Err((email, e))) = sent {
+            error!("Failed to send cancellation email to {}: {}", email, e);
+        }
+    });
+}
+
+/// Whether a paying user's period ended without a renewal, past the grace days
+/// A cancelled subscription ends right at the end of the period
+fn is_period_lapsed(user: &User, now: DateTime<Utc>) -> bool {
+    // Trials expire on their own, past due users are dunning's business
+    if user.plans.price_per_month == 0
+        || user.trial_expires_at.is_some()
+        || user.billing_status != BillingStatus::Active
+    {
+        return false;
+    }
+    user.current_period_end
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .is_some_and(|end| {
+            let grace_days = match user.subscription_state {
+                SubscriptionState::CancelAtPeriodEnd => 0,
+                _ => RENEWAL_GRACE_DAYS,
+            };
+            end.with_timezone(&Utc) + chrono::Duration::days(grace_days) < now
+        })
+}
+
+/// Moves users whose paid period lapsed back to the free plan
+/// This is called periodically via a background task
+pub async fn expire_lapsed_subscriptions() -> Result<usize> {
+    let now = Utc::now();
+    let mut expired = 0;
+    for user in get_all_users()
+        .await?
+        .into_iter()
+        .filter(|u| is_period_lapsed(u, now))
+    {
+        if let Err(e) = change_plan(&user.email, Plans::default_plan()).await {
+            warn!("Failed to expire the plan of {}: {}", user.email, e);
+            continue;
+        }
+        update_user(&user.email, |user| {
+            user.billing_interval = BillingInterval::Monthly;
+            user.current_period_end = None;
+            user.subscription_state = SubscriptionState::Canceled;
+            user.stripe_subscription_id = None;
+        })
+        .await?;
+        info!(
+            "{} plan of {} lapsed without a renewal, moved to the free plan",
+            user.plans.name, user.email
+        );
+        expired += 1;
+    }
+    Ok(expired)
+}
+
+#[test]
+fn test_coupon_usable_and_apply() {
+    let now = chrono::Utc::now();
+    let mut coupon = Coupon {
+        code: "LAUNCH20".to_string(),
+        kind: DiscountKind::Percent,
+        value: 20,
+        max_redemptions: Some(2),
+        redemptions: 0,
+        expires_at: Some((now + chrono::Duration::days(1)).to_rfc3339()),
+        stripe_coupon_id: None,
+        created_at: now.to_rfc3339(),
+    };
+
+    assert!(coupon.check_usable(now).is_ok());
+    assert_eq!(coupon.apply(1900), 1520);
+
+    coupon.redemptions = 2;
+    assert!(coupon.check_usable(now).is_err());
+
+    coupon.redemptions = 0;
+    coupon.expires_at = Some((now - chrono::Duration::minutes(1)).to_rfc3339());
+    assert!(coupon.check_usable(now).is_err());
+
+    coupon.kind = DiscountKind::FixedCents;
+    coupon.value = 2500;
+    assert_eq!(coupon.apply(1900), 0);
+}
+
+#[test]
+fn test_prorate() {
+    use crate::server::plans::builtin_plan;
+    let (free, starter, pro) = (
+        builtin_plan("free"),
+        builtin_plan("starter"),
+        builtin_plan("pro"),
+    );
+    let paid_at = DateTime::parse_from_rfc3339("2026-03-01T00:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    // Halfway through a 31 day cycle
+    let halfway = paid_at + chrono::Duration::hours(31 * 12);
+
+    let upgrade = prorate(
+        &starter,
+        &pro,
+        BillingInterval::Monthly,
+        Some(paid_at),
+        halfway,
+    );
+    assert_eq!(upgrade.credit_cents, 600);
+    assert_eq!(upgrade.charge_cents, 950);
+    assert_eq!(upgrade.amount_due_cents, 350);
+    assert_eq!(upgrade.cycle_end, "2026-04-01T00:00:00+00:00");
+
+    let downgrade = prorate(
+        &pro,
+        &starter,
+        BillingInterval::Monthly,
+        Some(paid_at),
+        halfway,
+    );
+    assert_eq!(downgrade.amount_due_cents, -350);
+
+    // Two renewals later, the cycle is May
+    let later = prorate(
+        &starter,
+        &pro,
+        BillingInterval::Monthly,
+        Some(paid_at),
+        halfway + Months::new(2),
+    );
+    assert_eq!(later.cycle_start, "2026-05-01T00:00:00+00:00");
+
+    // Nothing paid, nothing to credit
+    let from_free = prorate(&free, &pro, BillingInterval::Monthly, None, halfway);
+    assert_eq!(from_free.credit_cents, 0);
+    assert_eq!(from_free.amount_due_cents, 1900);
+
+    // Yearly cycles prorate the yearly prices
+    let yearly = prorate(
+        &starter,
+        &pro,
+        BillingInterval::Yearly,
+        Some(paid_at),
+        paid_at,
+    );
+    assert_eq!(yearly.amount_due_cents, 19000 - 12000);
+    assert_eq!(yearly.cycle_end, "2027-03-01T00:00:00+00:00");
+}
+
+#[test]
+fn test_verify_stripe_signature() {
+    use crate::server::crypto::sign_webhook;
+
+    let payload = br#"{"type":"checkout.session.completed"}"#;
+    let secret = "whsec_test";
+    let now = 1_700_000_000;
+
+    let header = sign_webhook(payload, secret.as_bytes(), now);
+    assert!(verify_stripe_signature(payload, &header, secret, now));
+    assert!(verify_stripe_signature(payload, &header, secret, now + 60));
+
+    // Wrong secret, tampered body, replayed too late
+    assert!(!verify_stripe_signature(
+        payload,
+        &header,
+        "whsec_other",
+        now
+    ));
+    assert!(!verify_stripe_signature(b"{}", &header, secret, now));
+    assert!(!verify_stripe_signature(
+        payload,
+        &header,
+        secret,
+        now + 3600
+    ));
+    assert!(!verify_stripe_signature(payload, "v1=abcd", secret, now));
+}
+
+#[test]
+fn test_is_checkout_paid() {
+    use serde_json::json;
+
+    assert!(is_checkout_paid(
+        &json!({"id": "cs_1", "payment_status": "paid"})
+    ));
+    assert!(is_checkout_paid(
+        &json!({"id": "cs_1", "payment_status": "no_payment_required"})
+    ));
+
+    // Completed with a bank debit still processing, the plan waits for it
+    assert!(!is_checkout_paid(
+        &json!({"id": "cs_1", "payment_status": "unpaid"})
+    ));
+    assert!(!is_checkout_paid(&json!({"id": "cs_1"})));
+}
+
+#[tokio::test]
+async fn test_unpaid_checkout_keeps_plan() {
+    // Returns before looking up the pending upgrade, so nothing is recorded or changed
+    let session = serde_json::json!({"id": "cs_unpaid", "payment_status": "unpaid"});
+    assert!(complete_checkout(&session).await.is_ok());
+    assert!(complete_checkout(&serde_json::json!({})).await.is_err());
+}
+
+#[test]
+fn test_checkout_form_params() {
+    use crate::server::plans::builtin_plan;
+    let plan = builtin_plan("pro");
+    let params = checkout_form_params(
+        "alice@example.com",
+        "cus_123",
+        &plan,
+        BillingInterval::Yearly,
+        "price_123",
+        "https://blz.example/ok",
+        "https://blz.example/cancel",
+    );
+
+    let get = |key: &str| {
+        params
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    };
+
+    assert_eq!(get("mode"), Some("subscription"));
+    assert_eq!(get("line_items[0][price]"), Some("price_123"));
+    assert_eq!(get("client_reference_id"), Some("alice@example.com"));
+    assert_eq!(get("customer"), Some("cus_123"));
+    assert_eq!(get("metadata[plan]"), Some("Pro"));
+    assert_eq!(get("metadata[interval]"), Some("yearly"));
+}
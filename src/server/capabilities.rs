@@ -0,0 +1,115 @@
+//! # Capability discovery
+//!
+//! SDKs ask the proxy what an instance can do instead of finding out from errors. The document
+//! merges three sources: the user's plan (feature gates and limits), the proxy itself (endpoints it
+//! never forwards, or only for some plans) and the BlazeDB backend's version endpoint. A feature is only reported as
+//! enabled when none of them rule it out, features only the backend knows about are passed through.
+
+use crate::server::quota::plan_limits;
+use crate::server::schema::{CapabilitiesResponse, Plans};
+use std::collections::BTreeMap;
+
+/// Endpoints the proxy refuses to forward, whatever the plan
+pub const BLOCKED_ENDPOINTS: &[&str] = &["/v1/blazedb/query"];
+
+/// Endpoints only forwarded for plans with `embedding_api_access`
+pub const EMBEDDING_ENDPOINTS: &[&str] = &["/v1/blazedb/embed"];
+
+/// Backend path (under the instance's base URL) serving its version and feature flags
+pub const BACKEND_VERSION_PATH: &str = "/v1/blazedb/version";
+
+pub fn is_blocked_endpoint(path: &str) -> bool {
+    BLOCKED_ENDPOINTS
+        .iter()
+        .any(|blocked| path.contains(blocked))
+}
+
+pub fn is_embedding_endpoint(path: &str) -> bool {
+    EMBEDDING_ENDPOINTS
+        .iter()
+        .any(|endpoint| path.contains(endpoint))
+}
+
+/// Feature gates coming from the plan and the proxy
+fn plan_gates(plan: &Plans) -> BTreeMap<String, bool> {
+    BTreeMap::from([
+        ("embedding".to_string(), plan.features.embedding_api_access),
+        (
+            "query".to_string(),
+            !is_blocked_endpoint("/v1/blazedb/query"),
+        ),
+        (
+            "demo_datasets".to_string(),
+            plan.features.demo_datasets_included,
+        ),
+        (
+            "dedicated_instance".to_string(),
+            plan.features.dedicated_server_instance,
+        ),
+    ])
+}
+
+/// Builds the capability document, `backend` is the backend's version document if it answered
+/// Its `features` object (name -> bool) can switch gated features off and adds its own
+pub fn build_capabilities(
+    instance_id: &str,
+    plan: &Plans,
+    backend: Option<serde_json::Value>,
+) -> CapabilitiesResponse {
+    let mut features = plan_gates(plan);
+
+    if let Some(reported) = backend
+        .as_ref()
+        .and_then(|doc| doc.get("features"))
+        .and_then(|f| f.as_object())
+    {
+        for (name, value) in reported {
+            let Some(supported) = value.as_bool() else {
+                continue;
+            };
+            features
+                .entry(name.clone())
+                .and_modify(|enabled| *enabled &= supported)
+                .or_insert(supported);
+        }
+    }
+
+    CapabilitiesResponse {
+        instance_id: instance_id.to_string(),
+        plan: plan.name.clone(),
+        features,
+        limits: plan_limits(plan),
+        blocked_endpoints: BLOCKED_ENDPOINTS.iter().map(|e| e.to_string()).collect(),
+        backend_reachable: backend.is_some(),
+        backend,
+        generated_at: chrono::Utc::now().to_rfc3339(),
+    }
+}
+
+#[test]
+fn test_build_capabilities_merges_backend() {
+    use crate::server::plans::builtin_plan;
+
+    let backend = serde_json::json!({
+        "version": "1.4.0",
+        "features": { "demo_datasets": false, "hnsw_index": true, "flag": "yes" }
+    });
+    let caps = build_capabilities("inst", &builtin_plan("pro"), Some(backend));
+
+    assert!(caps.backend_reachable);
+    // Backend can switch a plan feature off, and adds its own
+    assert!(!caps.features["demo_datasets"]);
+    assert!(caps.features["hnsw_index"]);
+    assert!(!caps.features.contains_key("flag"));
+    assert!(caps.features["embedding"]);
+    assert!(caps.features["dedicated_instance"]);
+
+    let caps = build_capabilities("inst", &builtin_plan("free"), None);
+    assert!(!caps.backend_reachable);
+    assert!(!caps.features["embedding"]); // Gated by the plan, not blocked
+    assert!(!caps.features["dedicated_instance"]);
+    assert!(is_embedding_endpoint("/v1/blazedb/embed/inst"));
+    assert!(!is_blocked_endpoint("/v1/blazedb/embed/inst"));
+    assert_eq!(caps.limits.max_databases, 5);
+    assert_eq!(caps.limits.max_request_bytes, 10 * 1024 * 1024);
+}
@@ -0,0 +1,174 @@
+//! # Retries and circuit breaking
+//!
+//! A request the proxy couldn't connect to the instance for is tried again, up to
+//! `BLAZE_PROXY_RETRIES` (2 by default) more times with a jittered backoff. Only idempotent
+//! requests (GET, HEAD, OPTIONS, PUT, DELETE) whose body was read whole are retried, a streamed
+//! body can't be sent twice.
+//!
+//! Once `BLAZE_PROXY_BREAKER_THRESHOLD` (5) requests in a row failed that way, the instance's
+//! breaker opens: requests to it fail fast for `BLAZE_PROXY_BREAKER_COOLDOWN_SECONDS` (30)
+//! instead of each waiting for a connect timeout. After that one request is let through to try,
+//! if it gets through the breaker closes again, otherwise it stays open for another cooldown.
+//! With `BLAZE_PROXY_BREAKER_RESTART=true` the proxy also restarts the container when the
+//! breaker opens.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_RETRIES: u32 = 2;
+const DEFAULT_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN_SECONDS: u64 = 30;
+
+/// Backoff before the first retry, doubled for each one after
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakerSettings {
+    pub retries: u32,
+    pub threshold: u32,
+    pub cooldown: Duration,
+    pub restart_on_open: bool,
+}
+
+impl BreakerSettings {
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let var = |name: &str| std::env::var(name).ok().map(|v| v.trim().to_string());
+        BreakerSettings {
+            retries: var("BLAZE_PROXY_RETRIES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_RETRIES),
+            threshold: var("BLAZE_PROXY_BREAKER_THRESHOLD")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_THRESHOLD)
+                .max(1),
+            cooldown: Duration::from_secs(
+                var("BLAZE_PROXY_BREAKER_COOLDOWN_SECONDS")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_COOLDOWN_SECONDS),
+            ),
+            restart_on_open: var("BLAZE_PROXY_BREAKER_RESTART")
+                .is_some_and(|v| v.eq_ignore_ascii_case("true") || v == "1"),
+        }
+    }
+}
+
+/// How long to wait before the `attempt`th retry (1-based), with up to 50% jitter
+pub fn retry_delay(attempt: u32) -> Duration {
+    let base = RETRY_BASE_DELAY * 2u32.saturating_pow(attempt.saturating_sub(1));
+    base.mul_f64(1.0 + rand::random::<f64>() * 0.5)
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct InstanceCircuit {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+/// Per instance breakers, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    settings: BreakerSettings,
+    instances: Arc<Mutex<HashMap<String, InstanceCircuit>>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: BreakerSettings) -> Self {
+        CircuitBreaker {
+            settings,
+            instances: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn settings(&self) -> &BreakerSettings {
+        &self.settings
+    }
+
+    /// Whether a request may go to the instance, otherwise how long until it may try again
+    pub fn check(&self, instance_id: &str) -> Result<(), Duration> {
+        self.check_at(instance_id, Instant::now())
+    }
+
+    fn check_at(&self, instance_id: &str, now: Instant) -> Result<(), Duration> {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(opened_at) = instances.get(instance_id).and_then(|c| c.opened_at) else {
+            return Ok(());
+        };
+
+        let open_for = now.saturating_duration_since(opened_at);
+        if open_for < self.settings.cooldown {
+            return Err(self.settings.cooldown - open_for);
+        }
+
+        // This one gets to try, everything else keeps failing fast until it's answered
+        if let Some(circuit) = instances.get_mut(instance_id) {
+            circuit.opened_at = Some(now);
+        }
+        Ok(())
+    }
+
+    /// The instance answered, closes its breaker
+    pub fn record_success(&self, instance_id: &str) {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        instances.remove(instance_id);
+    }
+
+    /// A request couldn't connect to the instance, returns true if that opened the breaker
+    pub fn record_failure(&self, instance_id: &str) -> bool {
+        self.record_failure_at(instance_id, Instant::now())
+    }
+
+    fn record_failure_at(&self, instance_id: &str, now: Instant) -> bool {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let circuit = instances.entry(instance_id.to_string()).or_default();
+        circuit.consecutive_failures += 1;
+
+        match circuit.opened_at {
+            // The trial request failed too
+            Some(_) => {
+                circuit.opened_at = Some(now);
+                false
+            }
+            None if circuit.consecutive_failures >= self.settings.threshold => {
+                circuit.opened_at = Some(now);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn test_circuit_breaker() {
+    let breaker = CircuitBreaker::new(BreakerSettings {
+        retries: 2,
+        threshold: 3,
+        cooldown: Duration::from_secs(30),
+        restart_on_open: false,
+    });
+    let start = Instant::now();
+    let at = |s: u64| start + Duration::from_secs(s);
+
+    assert!(!breaker.record_failure_at("a", at(0)));
+    assert!(!breaker.record_failure_at("a", at(1)));
+    assert!(breaker.check_at("a", at(1)).is_ok());
+    assert!(breaker.record_failure_at("a", at(2))); // Opens
+    assert_eq!(breaker.check_at("a", at(12)), Err(Duration::from_secs(20)));
+    assert!(breaker.check_at("b", at(12)).is_ok()); // Per instance
+
+    // One trial after the cooldown, a failing one keeps it open
+    assert!(breaker.check_at("a", at(32)).is_ok());
+    assert!(breaker.check_at("a", at(33)).is_err());
+    assert!(!breaker.record_failure_at("a", at(34)));
+    assert!(breaker.check_at("a", at(40)).is_err());
+
+    // A trial that gets through closes it
+    assert!(breaker.check_at("a", at(64)).is_ok());
+    breaker.record_success("a");
+    assert!(breaker.check_at("a", at(65)).is_ok());
+
+    assert!(retry_delay(1) >= RETRY_BASE_DELAY);
+    assert!(retry_delay(3) >= RETRY_BASE_DELAY * 4);
+    assert!(retry_delay(3) <= RETRY_BASE_DELAY * 6);
+}
@@ -0,0 +1,99 @@
+//! # Compression
+//!
+//! Vector search results are big arrays of floats and compress well. The proxy compresses
+//! responses of at least `BLAZE_PROXY_COMPRESS_MIN_BYTES` (1 KiB by default) with gzip or brotli,
+//! whichever the client prefers in its `Accept-Encoding`. `BLAZE_PROXY_COMPRESSION=false` turns
+//! it off.
+//!
+//! - `Accept-Encoding` is passed on to the instance as it is. What the instance already
+//!   compressed keeps its `Content-Encoding` and goes through untouched, it's never encoded twice.
+//! - Event streams and NDJSON stay as they are, compressing them would hold lines back.
+//! - Request bodies sent with `Content-Encoding: gzip` or `br` are decompressed before anything
+//!   looks at them, so metering, quotas and size limits see the real payload. Other encodings
+//!   are refused with 415.
+
+use axum::http::{Response, header};
+use http_body::Body;
+use tower_http::compression::predicate::{Predicate, SizeAbove};
+use tower_http::compression::{CompressionLayer, CompressionLevel};
+use tower_http::decompression::RequestDecompressionLayer;
+
+const DEFAULT_MIN_BYTES: u16 = 1024;
+
+/// Content types that are sent a piece at a time, or are compressed already
+const NOT_COMPRESSED: &[&str] = &[
+    "text/event-stream",
+    "application/x-ndjson",
+    "application/grpc",
+    "image/",
+    "video/",
+    "audio/",
+];
+
+/// Whether responses of this content type are worth compressing
+pub fn is_compressible_type(content_type: &str) -> bool {
+    let content_type = content_type.trim().to_ascii_lowercase();
+    !NOT_COMPRESSED
+        .iter()
+        .any(|prefix| content_type.starts_with(prefix))
+}
+
+/// Big enough responses whose content type compresses
+#[derive(Debug, Clone, Copy)]
+pub struct CompressPredicate {
+    min_bytes: u16,
+}
+
+impl Predicate for CompressPredicate {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: Body,
+    {
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        is_compressible_type(content_type)
+            && SizeAbove::new(self.min_bytes).should_compress(response)
+    }
+}
+
+fn compression_enabled() -> bool {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_PROXY_COMPRESSION")
+        .ok()
+        .is_none_or(|v| !(v.trim().eq_ignore_ascii_case("false") || v.trim() == "0"))
+}
+
+/// Response compression for the proxy, None when `BLAZE_PROXY_COMPRESSION` is off
+pub fn proxy_compression_layer() -> Option<CompressionLayer<CompressPredicate>> {
+    if !compression_enabled() {
+        return None;
+    }
+    let min_bytes = std::env::var("BLAZE_PROXY_COMPRESS_MIN_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_MIN_BYTES);
+    let layer = CompressionLayer::new()
+        .gzip(true)
+        .br(true)
+        .quality(CompressionLevel::Default)
+        .compress_when(CompressPredicate { min_bytes });
+    Some(layer)
+}
+
+/// Decompresses gzip and brotli request bodies, always on
+pub fn request_decompression_layer() -> RequestDecompressionLayer {
+    RequestDecompressionLayer::new().gzip(true).br(true)
+}
+
+#[test]
+fn test_is_compressible_type() {
+    assert!(is_compressible_type("application/json"));
+    assert!(is_compressible_type("text/plain; charset=utf-8"));
+    assert!(is_compressible_type(""));
+    assert!(!is_compressible_type("text/event-stream"));
+    assert!(!is_compressible_type("Application/X-NDJSON"));
+    assert!(!is_compressible_type("image/png"));
+}
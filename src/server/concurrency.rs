@@ -0,0 +1,164 @@
+//! # In-flight request limits
+//!
+//! All proxied requests share the proxy's threads and bandwidth, so one user firing hundreds of
+//! requests in parallel would slow everyone else down. The proxy lets at most
+//! `BLAZE_PROXY_MAX_IN_FLIGHT` (32 by default) requests per instance through at once. A request
+//! counts until its response has been streamed back. Further requests queue, up to
+//! `BLAZE_PROXY_MAX_QUEUED` (64) per instance for at most `BLAZE_PROXY_QUEUE_TIMEOUT_MS` (10s),
+//! anything past that is turned away right away.
+//!
+//! WebSockets and event streams aren't counted here, they have their own limit (see `streams`).
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+const DEFAULT_MAX_IN_FLIGHT: usize = 32;
+const DEFAULT_MAX_QUEUED: usize = 64;
+const DEFAULT_QUEUE_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConcurrencySettings {
+    pub max_in_flight: usize,
+    pub max_queued: usize,
+    pub queue_timeout: Duration,
+}
+
+impl ConcurrencySettings {
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.trim().parse().ok());
+        ConcurrencySettings {
+            max_in_flight: var("BLAZE_PROXY_MAX_IN_FLIGHT")
+                .unwrap_or(DEFAULT_MAX_IN_FLIGHT)
+                .max(1),
+            max_queued: var("BLAZE_PROXY_MAX_QUEUED").unwrap_or(DEFAULT_MAX_QUEUED),
+            queue_timeout: Duration::from_millis(
+                var("BLAZE_PROXY_QUEUE_TIMEOUT_MS")
+                    .map_or(DEFAULT_QUEUE_TIMEOUT_MS, |ms| ms as u64),
+            ),
+        }
+    }
+}
+
+/// Why a request didn't get through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    QueueFull,
+    TimedOut,
+}
+
+#[derive(Debug)]
+struct InstanceSlots {
+    semaphore: Arc<Semaphore>,
+    queued: usize,
+}
+
+/// Per instance request slots, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone)]
+pub struct ConcurrencyLimiter {
+    settings: ConcurrencySettings,
+    instances: Arc<Mutex<HashMap<String, InstanceSlots>>>,
+}
+
+/// One of the instance's slots, dropping it lets the next queued request through
+#[derive(Debug)]
+pub struct InFlightPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Takes the request out of the queue count however waiting ends
+struct Queued<'a> {
+    limiter: &'a ConcurrencyLimiter,
+    instance_id: &'a str,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let mut instances = self
+            .limiter
+            .instances
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(slots) = instances.get_mut(self.instance_id) {
+            slots.queued = slots.queued.saturating_sub(1);
+        }
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(settings: ConcurrencySettings) -> Self {
+        ConcurrencyLimiter {
+            settings,
+            instances: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Waits for one of the instance's slots, or says why the request can't have one
+    pub async fn acquire(&self, instance_id: &str) -> Result<InFlightPermit, Rejected> {
+        let semaphore = {
+            let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+            let slots = instances
+                .entry(instance_id.to_string())
+                .or_insert_with(|| InstanceSlots {
+                    semaphore: Arc::new(Semaphore::new(self.settings.max_in_flight)),
+                    queued: 0,
+                });
+
+            if let Ok(permit) = slots.semaphore.clone().try_acquire_owned() {
+                return Ok(InFlightPermit { _permit: permit });
+            }
+            if slots.queued >= self.settings.max_queued {
+                return Err(Rejected::QueueFull);
+            }
+            slots.queued += 1;
+            slots.semaphore.clone()
+        };
+
+        let _queued = Queued {
+            limiter: self,
+            instance_id,
+        };
+        match tokio::time::timeout(self.settings.queue_timeout, semaphore.acquire_owned()).await {
+            Ok(Ok(permit)) => Ok(InFlightPermit { _permit: permit }),
+            // The semaphore is never closed, a timeout is the only way out
+            Ok(Err(_)) | Err(_) => Err(Rejected::TimedOut),
+        }
+    }
+
+    /// Requests to the instance waiting for a slot
+    pub fn queued(&self, instance_id: &str) -> usize {
+        let instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        instances.get(instance_id).map_or(0, |slots| slots.queued)
+    }
+}
+
+#[tokio::test]
+async fn test_concurrency_limiter() {
+    let limiter = ConcurrencyLimiter::new(ConcurrencySettings {
+        max_in_flight: 2,
+        max_queued: 1,
+        queue_timeout: Duration::from_millis(50),
+    });
+
+    let first = limiter.acquire("a").await.unwrap();
+    let _second = limiter.acquire("a").await.unwrap();
+    assert!(limiter.acquire("b").await.is_ok()); // Other instances have their own slots
+
+    // Full: one request may wait, it times out unless a slot frees up in time
+    assert_eq!(limiter.acquire("a").await.unwrap_err(), Rejected::TimedOut);
+    assert_eq!(limiter.queued("a"), 0);
+
+    let waiting = {
+        let limiter = limiter.clone();
+        tokio::spawn(async move { limiter.acquire("a").await.map(|_| ()) })
+    };
+    while limiter.queued("a") == 0 {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(limiter.acquire("a").await.unwrap_err(), Rejected::QueueFull);
+
+    drop(first);
+    assert_eq!(waiting.await.unwrap(), Ok(()));
+}
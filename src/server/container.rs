@@ -1,63 +1,311 @@
-use crate::info;
-use crate::server::ports::calculate_container_port;
-use anyhow::Result;
-use bollard::Docker;
+use crate::server::container_events::{TrackedContainer, get_tracked_container};
+use crate::server::error::{BlazeError, Result};
+use crate::server::placement::HostInfo;
+use crate::server::ports::{allocate_container_port, release_container_port};
+use crate::server::routing::{record_route, remove_route, route_for_container};
+use crate::server::schema::{InstanceConfig, Plans, User};
+use crate::server::secrets::get_secrets;
+use crate::{info, warn};
 use bollard::config::VolumeCreateRequest;
 use bollard::models::{
-    ContainerCreateBody, HealthStatusEnum, HostConfig, Mount, MountTypeEnum, PortBinding,
+    ContainerCreateBody, ContainerUpdateBody, HostConfig, Mount, MountTypeEnum, PortBinding,
     RestartPolicy, RestartPolicyNameEnum,
 };
 #[allow(unused)]
 use bollard::query_parameters::{
-    CreateContainerOptions, CreateImageOptions, ListContainersOptions, ListVolumesOptions,
-    RemoveContainerOptions, RemoveVolumeOptions, StartContainerOptions,
+    CreateContainerOptions, CreateImageOptions, DownloadFromContainerOptions,
+    ListContainersOptions, ListVolumesOptions, LogsOptions, RemoveContainerOptions,
+    RemoveVolumeOptions, RestartContainerOptions, StartContainerOptions, StopContainerOptions,
+    UploadToContainerOptions,
 };
+use bollard::{API_DEFAULT_VERSION, Docker};
 use hex::encode;
 use pbkdf2::pbkdf2_hmac;
 use sha2::Sha512;
 use std::collections::HashMap;
+use std::path::Path;
+
+/// Image every BlazeDB instance runs
+pub const BLAZEDB_IMAGE: &str = "ronakgh97/blazedb";
+pub const BLAZEDB_IMAGE_TAG: &str = "latest";
+
+/// Seconds BlazeDB gets to shut down cleanly before it's killed
+const CONTAINER_STOP_TIMEOUT: i32 = 10;
+
+/// Log lines sent when the user doesn't say how many, and the most they can ask for
+pub const DEFAULT_LOG_TAIL: usize = 200;
+pub const MAX_LOG_TAIL: usize = 1000;
+
+/// How often a container that's coming up is looked at
+const STATE_POLL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Small image with `cp`, used to copy one volume into another
+const VOLUME_COPY_IMAGE: &str = "busybox";
+const VOLUME_COPY_IMAGE_TAG: &str = "stable";
+
+/// Where a volume is mounted in a backup helper, also the top directory of its tarball
+const BACKUP_MOUNT: &str = "/volume";
+
+/// Volumes of an instance, by kind
+const VOLUME_KINDS: [&str; 2] = ["config", "sources"];
+
+/// Seconds before a Docker API call is given up (bollard's default)
+const DOCKER_TIMEOUT: u64 = 120;
+
+/// Sockets Podman serves its Docker compatible API on, the rootless one first
+fn podman_sockets(runtime_dir: Option<&str>) -> Vec<String> {
+    runtime_dir
+        .map(|dir| format!("{}/podman/podman.sock", dir.trim_end_matches('/')))
+        .into_iter()
+        .chain(std::iter::once("/run/podman/podman.sock".to_string()))
+        .collect()
+}
+
+/// Like the docker CLI, any `DOCKER_TLS_VERIFY` but empty or "0" turns TLS on
+fn is_tls_verify(value: Option<&str>) -> bool {
+    value.is_some_and(|v| !v.trim().is_empty() && v.trim() != "0")
+}
+
+/// Connects to the Docker API at `url`: `unix://` or `npipe://` sockets, `tcp://`/`http://`, or
+/// `https://`. `tcp://` goes over TLS as well when `DOCKER_TLS_VERIFY` is set, with the client
+/// certificate from `DOCKER_CERT_PATH` (`~/.docker` by default: `key.pem`, `cert.pem`, `ca.pem`)
+pub(crate) fn connect_docker_host(url: &str, timeout: u64) -> Result<Docker> {
+    dotenv::dotenv().ok();
+    let tls = url.starts_with("https://")
+        || (url.starts_with("tcp://")
+            && is_tls_verify(std::env::var("DOCKER_TLS_VERIFY").ok().as_deref()));
+
+    let docker = match url {
+        u if u.starts_with("unix://") || u.starts_with("npipe://") => {
+            Docker::connect_with_socket(u, timeout, API_DEFAULT_VERSION)
+        }
+        u if tls => {
+            let certs = std::env::var("DOCKER_CERT_PATH")
+                .map(std::path::PathBuf::from)
+                .unwrap_or_else(|_| {
+                    dirs::home_dir()
+                        .unwrap_or_else(|| std::path::PathBuf::from("."))
+                        .join(".docker")
+                });
+            Docker::connect_with_ssl(
+                u,
+                &certs.join("key.pem"),
+                &certs.join("cert.pem"),
+                &certs.join("ca.pem"),
+                timeout,
+                API_DEFAULT_VERSION,
+            )
+        }
+        u if u.starts_with("tcp://") || u.starts_with("http://") => {
+            Docker::connect_with_http(u, timeout, API_DEFAULT_VERSION)
+        }
+        _ => {
+            return Err(BlazeError::docker(format!(
+                "Unsupported Docker host {}, expected unix://, npipe://, tcp://, http:// or https://",
+                url
+            )));
+        }
+    };
+
+    docker.map_err(|e| BlazeError::docker(format!("Failed to connect to Docker at {}: {}", url, e)))
+}
+
+/// Connects to the container host: `DOCKER_HOST` when set (a remote daemon, or Podman's socket),
+/// otherwise the local socket or named pipe. Without a Docker socket, Podman's is used if found
+pub(crate) fn connect_docker() -> Result<Docker> {
+    dotenv::dotenv().ok();
+
+    if let Ok(host) = std::env::var("DOCKER_HOST")
+        && !host.trim().is_empty()
+    {
+        return connect_docker_host(host.trim(), DOCKER_TIMEOUT);
+    }
 
-/// Connects to Docker daemon (cross-platform: Windows named pipe or Linux socket)
-fn connect_docker() -> Result<Docker> {
     #[cfg(windows)]
     {
         // Windows: Use named pipe
-        Docker::connect_with_named_pipe_defaults()
-            .map_err(|e| anyhow::anyhow!("Failed to connect to Docker on Windows: {}", e))
+        Docker::connect_with_named_pipe_defaults().map_err(|e| {
+            BlazeError::docker(format!("Failed to connect to Docker on Windows: {}", e))
+        })
     }
 
     #[cfg(not(windows))]
     {
+        if !Path::new("/var/run/docker.sock").exists() {
+            let runtime_dir = std::env::var("XDG_RUNTIME_DIR").ok();
+            if let Some(socket) = podman_sockets(runtime_dir.as_deref())
+                .into_iter()
+                .find(|s| Path::new(s).exists())
+            {
+                return connect_docker_host(&format!("unix://{}", socket), DOCKER_TIMEOUT);
+            }
+        }
+
         // Linux/Mac: Use socket
         Docker::connect_with_local_defaults()
-            .map_err(|e| anyhow::anyhow!("Failed to connect to Docker socket: {}", e))
+            .map_err(|e| BlazeError::docker(format!("Failed to connect to Docker socket: {}", e)))
     }
 }
 
-#[inline]
-pub fn get_unique_instance_id(email: String) -> String {
-    let mut instance_id = [0u8; 16];
+/// Derives the instance id of an email under `secret`
+/// PBKDF2 with 100k iterations takes tens of milliseconds, so it runs on the blocking pool rather
+/// than holding up the runtime (OTP verification waits on it)
+async fn derive_instance_id(email: &str, secret: String) -> Result<String> {
+    let email = email.trim().to_lowercase();
+    let instance_id = tokio::task::spawn_blocking(move || {
+        let mut instance_id = [0u8; 16];
+        pbkdf2_hmac::<Sha512>(
+            email.as_bytes(),
+            secret.as_bytes(),
+            100_000,
+            &mut instance_id,
+        );
+        encode(instance_id)
+    })
+    .await
+    .map_err(anyhow::Error::from)?;
+    Ok(instance_id)
+}
+
+/// Instance id of the email under the current secret (`BLAZE_INSTANCE_SECRET`)
+/// Ids are stored with their users once given out, so rotating the secret only changes the ids of
+/// new instances, see `rotation`
+pub async fn get_unique_instance_id(email: &str) -> Result<String> {
+    let super_secret = get_secrets().require("BLAZE_INSTANCE_SECRET")?.to_string();
+
+    derive_instance_id(email, super_secret).await
+}
+
+/// The secret being rotated out (`BLAZE_INSTANCE_SECRET_PREVIOUS`)
+fn previous_instance_secret() -> Option<String> {
+    get_secrets()
+        .get("BLAZE_INSTANCE_SECRET_PREVIOUS")
+        .map(str::to_string)
+}
 
+/// Whether the previous instance secret is still set, a rotation is under way
+pub fn is_instance_secret_rotating() -> bool {
+    previous_instance_secret().is_some()
+}
+
+/// Instance id of the email under the secret being rotated out, None when no rotation is under way
+pub async fn get_previous_instance_id(email: &str) -> Result<Option<String>> {
+    match previous_instance_secret() {
+        Some(secret) => Ok(Some(derive_instance_id(email, secret).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Log levels a user can give their container
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-qwen3-embedding-0.6b";
+const DEFAULT_EMBEDDING_API_URL: &str = "http://host.docker.internal:1234/v1/embeddings";
+const DEFAULT_EMBEDDING_API_KEY: &str = "local_dev_key";
+
+fn env_or(name: &str, default: &str) -> String {
     dotenv::dotenv().ok();
+    std::env::var(name)
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
 
-    let super_secret =
-        std::env::var("BLAZE_INSTANCE_SECRET").expect("BLAZE_INSTANCE_SECRET must be set in env");
+/// Env of a container with `config`, unset fields come from `BLAZE_EMBEDDING_MODEL`,
+/// `BLAZE_EMBEDDING_API_URL` and `BLAZE_EMBEDDING_API_KEY`
+fn config_env(config: &InstanceConfig) -> Vec<String> {
+    // The service's key only ever goes to the service's embedding API
+    let (api_url, api_key) = match &config.embedding_api_url {
+        Some(url) => (
+            url.clone(),
+            config.embedding_api_key.clone().unwrap_or_default(),
+        ),
+        None => (
+            env_or("BLAZE_EMBEDDING_API_URL", DEFAULT_EMBEDDING_API_URL),
+            env_or("BLAZE_EMBEDDING_API_KEY", DEFAULT_EMBEDDING_API_KEY),
+        ),
+    };
+    let model = match &config.embedding_model {
+        Some(model) => model.clone(),
+        None => env_or("BLAZE_EMBEDDING_MODEL", DEFAULT_EMBEDDING_MODEL),
+    };
 
-    let super_secret = super_secret.as_bytes();
+    vec![
+        format!(
+            "RUST_LOG={}",
+            config.log_level.as_deref().unwrap_or(DEFAULT_LOG_LEVEL)
+        ),
+        format!("EMBEDDING_MODEL={}", model),
+        format!("EMBEDDING_API_URL={}", api_url),
+        format!("EMBEDDING_API_KEY={}", api_key),
+    ]
+}
 
-    let email = email.trim().to_lowercase();
+/// Resources and env a user's container gets, derived from their plan and instance config
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerSpec {
+    pub cpu_count: f64,
+    pub memory_mb: i64,
+    pub pids_limit: i64,
+    pub env: Vec<String>,
+}
+
+impl ContainerSpec {
+    pub fn new(plan: &Plans, config: &InstanceConfig) -> Self {
+        let (cpu_count, memory_mb) = plan.container_limits();
+        let mut env = vec![
+            format!("BLAZE_MAX_DATABASES={}", plan.features.database_no),
+            format!("BLAZE_MAX_VECTORS_PER_DB={}", plan.features.vector_per_db),
+        ];
+        env.extend(config_env(config));
+        ContainerSpec {
+            cpu_count,
+            memory_mb,
+            pids_limit: plan.features.pids_limit,
+            env,
+        }
+    }
 
-    pbkdf2_hmac::<Sha512>(email.as_bytes(), super_secret, 100_000, &mut instance_id);
-    encode(instance_id)
+    /// With the service's default config
+    pub fn for_plan(plan: &Plans) -> Self {
+        Self::new(plan, &InstanceConfig::default())
+    }
+
+    pub fn for_user(user: &User) -> Self {
+        Self::new(&user.plans, &user.instance_config)
+    }
+
+    pub fn nano_cpus(&self) -> i64 {
+        (1_000_000_000.0 * self.cpu_count) as i64
+    }
+
+    /// Relative CPU weight when the host is busy, 1024 (Docker's default) per CPU
+    pub fn cpu_shares(&self) -> i64 {
+        (1024.0 * self.cpu_count) as i64
+    }
+
+    /// Docker wants bytes
+    pub fn memory_bytes(&self) -> i64 {
+        self.memory_mb * 1024 * 1024
+    }
+
+    /// The resource limits alone, for changing a running container
+    pub fn update_body(&self) -> ContainerUpdateBody {
+        ContainerUpdateBody {
+            nano_cpus: Some(self.nano_cpus()),
+            cpu_shares: Some(self.cpu_shares()),
+            memory: Some(self.memory_bytes()),
+            memory_swap: Some(self.memory_bytes()), // Same as memory: no swap on top
+            pids_limit: Some(self.pids_limit),
+            ..Default::default()
+        }
+    }
 }
 
 // TODO: Need to implement retry logic for Docker operations, maybe not but on service module
 /// Spawns a new BlazeDB container for a user
-pub async fn spawn_blazedb_container(
-    instance_id: &str,
-    cpu_count: f64,
-    memory_allocate: i64,
-) -> Result<()> {
+pub async fn spawn_blazedb_container(instance_id: &str, spec: &ContainerSpec) -> Result<()> {
     let docker = connect_docker()?;
 
     let container_name = format!("blazedb-{}", instance_id);
@@ -78,6 +326,9 @@ pub async fn spawn_blazedb_container(
         docker
             .start_container(&container_name, None::<StartContainerOptions>)
             .await?;
+        // Routed the way it was created, whatever the env says now
+        let host_port = get_container_port_mapping(instance_id).await?;
+        record_route(instance_id, route_for_container(instance_id, host_port))?;
         info!("Started existing container: {}", container_name);
         return Ok(());
     }
@@ -90,14 +341,21 @@ pub async fn spawn_blazedb_container(
     let network_mode = std::env::var("BLAZEDB_NETWORK").unwrap_or_else(|_| "bridge".to_string());
 
     // Add port mapping when running in external mode
-    let port_bindings = if network_mode == "bridge" {
-        let host_port = calculate_container_port(instance_id);
-
+    let host_port = if network_mode == "bridge" {
+        // Skips ports already used by another container or host process
+        Some(allocate_container_port(instance_id)?)
+    } else {
+        None
+    };
+    let port_bindings = if let Some(host_port) = host_port {
         let mut bindings = HashMap::new();
         bindings.insert(
             format!("{}/tcp", "8080"), // Container internal port
             Some(vec![PortBinding {
-                host_ip: Some("127.0.0.1".to_string()),
+                // Loopback unless the proxy reaches a remote container host over its network
+                host_ip: Some(
+                    std::env::var("BLAZEDB_PUBLISH_IP").unwrap_or_else(|_| "127.0.0.1".to_string()),
+                ),
                 host_port: Some(host_port.to_string()),
             }]),
         );
@@ -113,15 +371,12 @@ pub async fn spawn_blazedb_container(
 
     // Create new container with both config and sources volumes
     let config = ContainerCreateBody {
-        image: Some("ronakgh97/blazedb:latest".to_string()),
-        //TODO: Fix these env vars, broooo!!
-        env: Some(vec![
-            "RUST_LOG=info".to_string(),
-            "PORT=8080".to_string(),
-            "EMBEDDING_MODEL=text-embedding-qwen3-embedding-0.6b".to_string(),
-            "EMBEDDING_API_URL=http://host.docker.internal:1234/v1/embeddings".to_string(),
-            "EMBEDDING_API_KEY=local_dev_key".to_string(),
-        ]),
+        image: Some(format!("{}:{}", BLAZEDB_IMAGE, BLAZEDB_IMAGE_TAG)),
+        env: Some(
+            std::iter::once("PORT=8080".to_string())
+                .chain(spec.env.iter().cloned())
+                .collect(),
+        ),
         host_config: Some(HostConfig {
             mounts: Some(vec![
                 // Config volume: settings, metadata, cache
@@ -145,8 +400,11 @@ pub async fn spawn_blazedb_container(
                 name: Some(RestartPolicyNameEnum::UNLESS_STOPPED),
                 ..Default::default()
             }),
-            cpu_quota: Some((1_000_000_000.0 * cpu_count) as i64),
-            memory: Some(memory_allocate),
+            nano_cpus: Some(spec.nano_cpus()),
+            cpu_shares: Some(spec.cpu_shares()),
+            memory: Some(spec.memory_bytes()),
+            memory_swap: Some(spec.memory_bytes()), // Same as memory: no swap on top
+            pids_limit: Some(spec.pids_limit),
             ..Default::default()
         }),
         ..Default::default()
@@ -161,6 +419,7 @@ pub async fn spawn_blazedb_container(
     docker
         .start_container(&container_name, None::<StartContainerOptions>)
         .await?;
+    record_route(instance_id, route_for_container(instance_id, host_port))?;
 
     info!("Spawned new container: {}", container_name);
 
@@ -168,6 +427,8 @@ pub async fn spawn_blazedb_container(
 }
 
 /// Destroys a user's BlazeDB container (data persists in volume)
+/// To drop the volumes too, back them up with `export_instance_volumes` and use
+/// `remove_container_with_volumes`
 pub async fn destroy_blazedb_container(instance_id: &str) -> Result<()> {
     let docker = connect_docker()?;
     let container_name = format!("blazedb-{}", instance_id);
@@ -185,28 +446,125 @@ pub async fn destroy_blazedb_container(instance_id: &str) -> Result<()> {
         .remove_container(&container_name, Some(options))
         .await?;
 
-    // TODO: I need backup/restore system first
-    // Remove docker volumes as well
-    // let config_volume = format!("blazedb_config_{}", instance_id);
-    // let sources_volume = format!("blazedb_sources_{}", instance_id);
-    //
-    // let options = RemoveVolumeOptions {
-    //     force: true,
-    //     ..Default::default()
-    // };
-    //
-    // docker
-    //     .remove_volume(&config_volume, Some(options.clone()))
-    //     .await?;
-    // docker
-    //     .remove_volume(&sources_volume, Some(options.clone()))
-    //     .await?;
+    release_container_port(instance_id)?;
+    remove_route(instance_id)?;
 
     info!("️ Destroyed container: {}", container_name);
 
     Ok(())
 }
 
+/// Recreates a user's container with a new spec (limits and env can't all be changed in place)
+/// Both volumes are kept, so the data survives
+pub async fn recreate_blazedb_container(instance_id: &str, spec: &ContainerSpec) -> Result<()> {
+    let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
+
+    if container_exists(&docker, &container_name).await? {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+
+        docker
+            .remove_container(&container_name, Some(options))
+            .await?;
+    }
+
+    spawn_blazedb_container(instance_id, spec).await?;
+
+    info!(
+        "Recreated container {} with {} CPU / {} MB",
+        container_name, spec.cpu_count, spec.memory_mb
+    );
+
+    Ok(())
+}
+
+/// Changes the CPU, memory and pids limits of a user's container in place, without a restart
+/// Env can't change this way, see `recreate_blazedb_container`. Docker refuses to lower memory
+/// below what the container uses right now
+pub async fn update_blazedb_container_limits(
+    instance_id: &str,
+    spec: &ContainerSpec,
+) -> Result<()> {
+    let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
+
+    if !container_exists(&docker, &container_name).await? {
+        return Err(BlazeError::docker(format!(
+            "Container {} doesn't exist",
+            container_name
+        )));
+    }
+
+    docker
+        .update_container(&container_name, spec.update_body())
+        .await?;
+
+    info!(
+        "Updated container {} in place to {} CPU / {} MB / {} pids",
+        container_name, spec.cpu_count, spec.memory_mb, spec.pids_limit
+    );
+
+    Ok(())
+}
+
+/// Moves a user's container to `spec`: in place when only the limits change, recreated (volumes
+/// kept) when the env changes too or the in place update fails
+pub async fn resize_blazedb_container(
+    instance_id: &str,
+    from: &ContainerSpec,
+    to: &ContainerSpec,
+) -> Result<()> {
+    if from.env == to.env {
+        match update_blazedb_container_limits(instance_id, to).await {
+            Ok(()) => return Ok(()),
+            Err(e) => warn!(
+                "In place update of {} failed, recreating it: {}",
+                instance_id, e
+            ),
+        }
+    }
+    recreate_blazedb_container(instance_id, to).await
+}
+
+/// Wipes a user's BlazeDB data by recreating the sources volume (config volume is kept)
+/// The container has to be removed to release the volume, then it is spawned again fresh
+pub async fn reset_blazedb_container_data(instance_id: &str, spec: &ContainerSpec) -> Result<()> {
+    let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
+
+    if container_exists(&docker, &container_name).await? {
+        docker.stop_container(&container_name, None).await?;
+
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
+
+        docker
+            .remove_container(&container_name, Some(options))
+            .await?;
+    }
+
+    let sources_volume = format!("blazedb_sources_{}", instance_id);
+
+    if volume_exists(&docker, &sources_volume).await? {
+        docker
+            .remove_volume(&sources_volume, Some(RemoveVolumeOptions { force: true }))
+            .await?;
+        info!("Removed Docker volume: {}", sources_volume);
+    }
+
+    // Spawning creates the missing sources volume again, empty
+    spawn_blazedb_container(instance_id, spec).await?;
+
+    info!("Reset data for instance: {}", instance_id);
+
+    Ok(())
+}
+
 /// Checks if a container exists
 async fn container_exists(docker: &Docker, name: &str) -> Result<bool> {
     let mut filters = HashMap::new();
@@ -222,8 +580,58 @@ async fn container_exists(docker: &Docker, name: &str) -> Result<bool> {
     Ok(!containers.is_empty())
 }
 
-// TODO: Gotta use this, or find a different robust method to get port mapping
-#[allow(unused)]
+/// Returns the local Docker host with its current load, the only placement target for now
+pub async fn get_local_host_info() -> Result<HostInfo> {
+    let docker = connect_docker()?;
+
+    let mut filters = HashMap::new();
+    filters.insert("name".to_string(), vec!["blazedb-".to_string()]);
+
+    let options = ListContainersOptions {
+        all: true,
+        filters: Some(filters),
+        ..Default::default()
+    };
+
+    let containers = docker.list_containers(Some(options)).await?;
+
+    Ok(HostInfo {
+        name: "local".to_string(),
+        running_containers: containers.len(),
+        dedicated: false,
+    })
+}
+
+/// Returns the Docker restart count of every BlazeDB container, keyed by container name
+pub async fn get_container_restart_counts() -> Result<HashMap<String, i64>> {
+    let docker = connect_docker()?;
+
+    let mut filters = HashMap::new();
+    filters.insert("name".to_string(), vec!["blazedb-".to_string()]);
+
+    let options = ListContainersOptions {
+        all: true,
+        filters: Some(filters),
+        ..Default::default()
+    };
+
+    let mut restart_counts = HashMap::new();
+    for container in docker.list_containers(Some(options)).await? {
+        let Some(id) = container.id else {
+            continue;
+        };
+        // Container summaries don't carry the restart count, only inspect does
+        let info = docker.inspect_container(&id, None).await?;
+        let name = info
+            .name
+            .map(|n| n.trim_start_matches('/').to_string())
+            .unwrap_or(id);
+        restart_counts.insert(name, info.restart_count.unwrap_or(0));
+    }
+
+    Ok(restart_counts)
+}
+
 /// Get the host port mapping for a container (for external mode)
 /// Returns the port number if container has port mapping, None otherwise
 pub async fn get_container_port_mapping(instance_id: &str) -> Result<Option<u16>> {
@@ -234,28 +642,23 @@ pub async fn get_container_port_mapping(instance_id: &str) -> Result<Option<u16>
     let container_info = docker.inspect_container(&container_name, None).await?;
 
     // Check NetworkSettings -> Ports -> "8080/tcp" -> HostPort
-    if let Some(network_settings) = container_info.network_settings {
-        if let Some(ports) = network_settings.ports {
-            if let Some(port_bindings) = ports.get("8080/tcp") {
-                if let Some(bindings) = port_bindings {
-                    if let Some(first_binding) = bindings.first() {
-                        if let Some(host_port_str) = &first_binding.host_port {
-                            if let Ok(port) = host_port_str.parse::<u16>() {
-                                return Ok(Some(port));
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    if let Some(network_settings) = container_info.network_settings
+        && let Some(ports) = network_settings.ports
+        && let Some(port_bindings) = ports.get("8080/tcp")
+        && let Some(bindings) = port_bindings
+        && let Some(first_binding) = bindings.first()
+        && let Some(host_port_str) = &first_binding.host_port
+        && let Ok(port) = host_port_str.parse::<u16>()
+    {
+        return Ok(Some(port));
     }
 
     // No port mapping found (internal network mode)
     Ok(None)
 }
 
-/// Creates a Docker volume if it doesn't exist
-async fn create_volume_if_not_exists(docker: &Docker, volume_name: &str) -> Result<()> {
+/// Checks if a Docker volume exists
+async fn volume_exists(docker: &Docker, volume_name: &str) -> Result<bool> {
     let mut filters = HashMap::new();
     filters.insert("name".to_string(), vec![volume_name.to_string()]);
 
@@ -265,7 +668,12 @@ async fn create_volume_if_not_exists(docker: &Docker, volume_name: &str) -> Resu
 
     let volumes = docker.list_volumes(Some(options)).await?;
 
-    if volumes.volumes.is_none() || volumes.volumes.as_ref().unwrap().is_empty() {
+    Ok(volumes.volumes.is_some_and(|v| !v.is_empty()))
+}
+
+/// Creates a Docker volume if it doesn't exist
+async fn create_volume_if_not_exists(docker: &Docker, volume_name: &str) -> Result<()> {
+    if !volume_exists(docker, volume_name).await? {
         // Volume doesn't exist, create it
 
         let config = VolumeCreateRequest {
@@ -280,42 +688,133 @@ async fn create_volume_if_not_exists(docker: &Docker, volume_name: &str) -> Resu
     Ok(())
 }
 
-#[allow(unused)]
-/// Checks the health status of a container
-pub async fn check_container_health(container_name: &str) -> Result<bool> {
+/// What Docker knows about a container, from the event listener when it's in sync
+/// Only inspects the container otherwise, see `container_events`
+pub async fn get_container_snapshot(container_name: &str) -> Result<TrackedContainer> {
+    if let Some(tracked) = get_tracked_container(container_name) {
+        return Ok(tracked);
+    }
+
     let docker = connect_docker()?;
+    if !container_exists(&docker, container_name).await? {
+        return Ok(TrackedContainer::missing());
+    }
     let container_info = docker.inspect_container(container_name, None).await?;
+    Ok(TrackedContainer::from_state(container_info.state.as_ref()))
+}
+
+/// Checks the health status of a container
+pub async fn check_container_health(container_name: &str) -> Result<bool> {
+    Ok(get_container_snapshot(container_name).await?.is_healthy)
+}
+
+// This function returns a tuple of (is_healthy, started_at, last_error_at, error_state) for the container
+pub async fn get_container_status(container_name: &str) -> Result<(bool, String, String, String)> {
+    let snapshot = get_container_snapshot(container_name).await?;
+    Ok((
+        snapshot.is_healthy,
+        snapshot.started_at,
+        snapshot.finished_at,
+        snapshot.error,
+    ))
+}
 
-    if let Some(state) = container_info.state {
-        if let Some(health) = state.health {
-            return Ok(health.status == Some(HealthStatusEnum::HEALTHY));
+/// Coarse lifecycle state of a user's container, for clients deciding whether to retry
+/// One of "missing", "starting", "running", "unhealthy", "restarting", "stopped"
+pub async fn get_instance_state(instance_id: &str) -> Result<&'static str> {
+    let container_name = format!("blazedb-{}", instance_id);
+    Ok(get_container_snapshot(&container_name).await?.state)
+}
+
+/// Waits up to `wait` for a user's container to be "running" (healthy, or up without a health
+/// check), returns whether it got there
+pub async fn wait_for_running(instance_id: &str, wait: std::time::Duration) -> Result<bool> {
+    let started = std::time::Instant::now();
+    loop {
+        if get_instance_state(instance_id).await? == "running" {
+            return Ok(true);
+        }
+        if started.elapsed() >= wait {
+            return Ok(false);
         }
+        tokio::time::sleep(STATE_POLL).await;
     }
+}
 
-    Ok(false)
+async fn is_container_running(docker: &Docker, container_name: &str) -> Result<bool> {
+    Ok(docker
+        .inspect_container(container_name, None)
+        .await?
+        .state
+        .and_then(|s| s.running)
+        .unwrap_or(false))
 }
 
-// This function returns a tuple of (is_healthy, started_at, last_error_at, error_state) for the container
-pub async fn get_container_status(container_name: &str) -> Result<(bool, String, String, String)> {
+/// Starts a user's stopped container, spawning it again from `spec` if it's gone
+/// Starting a running container does nothing
+pub async fn start_blazedb_container(instance_id: &str, spec: &ContainerSpec) -> Result<()> {
     let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
 
-    let container_info = docker.inspect_container(container_name, None).await?;
+    if container_exists(&docker, &container_name).await?
+        && is_container_running(&docker, &container_name).await?
+    {
+        return Ok(());
+    }
 
-    let result = (false, String::new(), String::new(), String::new());
+    // Starts the existing container, or creates it again with both volumes kept
+    spawn_blazedb_container(instance_id, spec).await
+}
 
-    if let Some(state) = container_info.state {
-        let is_healthy = if let Some(health) = state.health {
-            health.status == Some(HealthStatusEnum::HEALTHY)
-        } else {
-            false
-        };
-        let started_at = state.started_at.unwrap_or(String::new());
-        let last_error_at = state.finished_at.unwrap_or(String::new());
-        let error_state = state.error.unwrap_or(String::from(""));
-        return Ok((is_healthy, started_at, last_error_at, error_state));
+/// Stops a user's container, it stays stopped until started again (data persists)
+/// Unlike `stop_container` a missing container is an error, the user has nothing to stop
+pub async fn stop_blazedb_container(instance_id: &str) -> Result<()> {
+    let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
+
+    if !container_exists(&docker, &container_name).await? {
+        return Err(BlazeError::docker(format!(
+            "Container {} doesn't exist",
+            container_name
+        )));
+    }
+    if !is_container_running(&docker, &container_name).await? {
+        return Ok(());
+    }
+
+    let options = StopContainerOptions {
+        t: Some(CONTAINER_STOP_TIMEOUT),
+        ..Default::default()
+    };
+    docker
+        .stop_container(&container_name, Some(options))
+        .await?;
+
+    info!("Stopped container: {}", container_name);
+
+    Ok(())
+}
+
+/// Restarts a user's container, e.g. when it hangs, spawning it again from `spec` if it's gone
+pub async fn restart_blazedb_container(instance_id: &str, spec: &ContainerSpec) -> Result<()> {
+    let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
+
+    if !container_exists(&docker, &container_name).await? {
+        return spawn_blazedb_container(instance_id, spec).await;
     }
 
-    Ok(result)
+    let options = RestartContainerOptions {
+        t: Some(CONTAINER_STOP_TIMEOUT),
+        ..Default::default()
+    };
+    docker
+        .restart_container(&container_name, Some(options))
+        .await?;
+
+    info!("Restarted container: {}", container_name);
+
+    Ok(())
 }
 
 /// Restarts a container by ID (useful for applying updates without data loss)
@@ -335,8 +834,38 @@ pub async fn restart_container(instance_id: &str) -> Result<()> {
     Ok(())
 }
 
+/// Streams the last `tail` lines (at most `MAX_LOG_TAIL`) of a user's container, stdout and
+/// stderr interleaved, then whatever it logs next if `follow` is set
+pub async fn stream_blazedb_container_logs(
+    instance_id: &str,
+    tail: usize,
+    follow: bool,
+) -> Result<impl futures_util::Stream<Item = Result<axum::body::Bytes>> + Send + use<>> {
+    use futures_util::stream::StreamExt;
+
+    let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
+
+    if !container_exists(&docker, &container_name).await? {
+        return Err(BlazeError::validation(
+            "Instance has no container to read logs from",
+        ));
+    }
+
+    let options = LogsOptions {
+        follow,
+        stdout: true,
+        stderr: true,
+        timestamps: true,
+        tail: tail.min(MAX_LOG_TAIL).to_string(),
+        ..Default::default()
+    };
+    Ok(docker
+        .logs(&container_name, Some(options))
+        .map(|output| Ok(output?.into_bytes())))
+}
+
 /// Stops a container by ID without removing it (data persists, can be restarted later)
-#[allow(unused)]
 pub async fn stop_container(instance_id: &str) -> Result<()> {
     let docker = connect_docker()?;
     let container_name = format!("blazedb-{}", instance_id);
@@ -353,24 +882,22 @@ pub async fn stop_container(instance_id: &str) -> Result<()> {
 }
 
 /// Removes a container and its associated volumes (data loss, use with caution)
-#[allow(unused)]
+/// Volumes are removed even if the container is already gone
 pub async fn remove_container_with_volumes(instance_id: &str) -> Result<()> {
     let docker = connect_docker()?;
     let container_name = format!("blazedb-{}", instance_id);
 
-    if !container_exists(&docker, &container_name).await? {
-        return Ok(()); // Container doesn't exist, nothing to do
-    }
-
     // Remove container
-    let options = RemoveContainerOptions {
-        force: true,
-        ..Default::default()
-    };
+    if container_exists(&docker, &container_name).await? {
+        let options = RemoveContainerOptions {
+            force: true,
+            ..Default::default()
+        };
 
-    docker
-        .remove_container(&container_name, Some(options))
-        .await?;
+        docker
+            .remove_container(&container_name, Some(options))
+            .await?;
+    }
 
     // Remove associated volumes
     let config_volume = format!("blazedb_config_{}", instance_id);
@@ -378,12 +905,16 @@ pub async fn remove_container_with_volumes(instance_id: &str) -> Result<()> {
 
     let volume_options = RemoveVolumeOptions { force: true };
 
-    docker
-        .remove_volume(&config_volume, Some(volume_options.clone()))
-        .await?;
-    docker
-        .remove_volume(&sources_volume, Some(volume_options.clone()))
-        .await?;
+    for volume in [&config_volume, &sources_volume] {
+        if volume_exists(&docker, volume).await? {
+            docker
+                .remove_volume(volume, Some(volume_options.clone()))
+                .await?;
+        }
+    }
+
+    release_container_port(instance_id)?;
+    remove_route(instance_id)?;
 
     info!(
         "Removed container and volumes for instance: {}",
@@ -393,6 +924,289 @@ pub async fn remove_container_with_volumes(instance_id: &str) -> Result<()> {
     Ok(())
 }
 
+/// Copies both volumes of `source_id` into fresh volumes for `target_id`
+/// The source container is paused during the copy so the snapshot is consistent, then resumed
+pub async fn clone_instance_volumes(source_id: &str, target_id: &str) -> Result<()> {
+    let docker = connect_docker()?;
+    let source_container = format!("blazedb-{}", source_id);
+
+    pull_volume_copy_image(&docker).await?;
+
+    // A stopped container has nothing in flight, only a running one needs pausing
+    let paused = container_exists(&docker, &source_container).await?
+        && docker.pause_container(&source_container).await.is_ok();
+
+    let mut copied = Ok(());
+    for kind in ["config", "sources"] {
+        let from = format!("blazedb_{}_{}", kind, source_id);
+        let to = format!("blazedb_{}_{}", kind, target_id);
+        copied = copy_volume(&docker, &from, &to).await;
+        if copied.is_err() {
+            break;
+        }
+    }
+
+    if paused {
+        docker.unpause_container(&source_container).await?;
+    }
+    copied?;
+
+    info!(
+        "Cloned volumes of instance {} into {}",
+        source_id, target_id
+    );
+
+    Ok(())
+}
+
+/// Pulls the helper image used to copy, back up and restore volumes
+async fn pull_volume_copy_image(docker: &Docker) -> Result<()> {
+    use futures_util::stream::StreamExt;
+
+    let options = CreateImageOptions {
+        from_image: Some(VOLUME_COPY_IMAGE.to_string()),
+        tag: Some(VOLUME_COPY_IMAGE_TAG.to_string()),
+        ..Default::default()
+    };
+    let mut stream = docker.create_image(Some(options), None, None);
+    while let Some(result) = stream.next().await {
+        result?;
+    }
+    Ok(())
+}
+
+/// Creates (never starts) a helper container with `volume` mounted at `BACKUP_MOUNT`, its files
+/// are read and written through Docker's archive API
+async fn create_volume_helper(docker: &Docker, volume: &str, read_only: bool) -> Result<String> {
+    let helper_name = format!(
+        "blz-volume-backup-{}",
+        hex::encode(rand::random::<[u8; 4]>())
+    );
+    let config = ContainerCreateBody {
+        image: Some(format!("{}:{}", VOLUME_COPY_IMAGE, VOLUME_COPY_IMAGE_TAG)),
+        host_config: Some(HostConfig {
+            mounts: Some(vec![Mount {
+                target: Some(BACKUP_MOUNT.to_string()),
+                source: Some(volume.to_string()),
+                typ: Some(MountTypeEnum::VOLUME),
+                read_only: Some(read_only),
+                ..Default::default()
+            }]),
+            network_mode: Some("none".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let options = CreateContainerOptions {
+        name: Some(helper_name.clone()),
+        ..Default::default()
+    };
+    docker.create_container(Some(options), config).await?;
+    Ok(helper_name)
+}
+
+async fn remove_volume_helper(docker: &Docker, helper_name: &str) -> Result<()> {
+    let options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    docker.remove_container(helper_name, Some(options)).await?;
+    Ok(())
+}
+
+/// Writes `volume` as a tarball to `file`, returns its size
+async fn export_volume(docker: &Docker, volume: &str, file: &Path) -> Result<u64> {
+    use futures_util::stream::StreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    if !volume_exists(docker, volume).await? {
+        return Err(BlazeError::docker(format!(
+            "Volume {} doesn't exist",
+            volume
+        )));
+    }
+    let helper_name = create_volume_helper(docker, volume, true).await?;
+
+    let exported = async {
+        let mut out = tokio::fs::File::create(file).await?;
+        let options = DownloadFromContainerOptions {
+            path: BACKUP_MOUNT.to_string(),
+        };
+        let mut stream = docker.download_from_container(&helper_name, Some(options));
+        let mut size = 0;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            size += chunk.len() as u64;
+            out.write_all(&chunk).await?;
+        }
+        out.flush().await?;
+        Ok::<_, BlazeError>(size)
+    }
+    .await;
+
+    remove_volume_helper(docker, &helper_name).await?;
+    exported
+}
+
+/// Fills `volume` (created if missing) from a tarball written by `export_volume`
+async fn import_volume(docker: &Docker, volume: &str, file: &Path) -> Result<()> {
+    create_volume_if_not_exists(docker, volume).await?;
+    let helper_name = create_volume_helper(docker, volume, false).await?;
+
+    let tar = tokio_util::io::ReaderStream::new(tokio::fs::File::open(file).await?);
+    // The tarball's top directory is `BACKUP_MOUNT`, so it unpacks right into the volume
+    let options = UploadToContainerOptions {
+        path: "/".to_string(),
+        ..Default::default()
+    };
+    let imported = docker
+        .upload_to_container(&helper_name, Some(options), bollard::body_try_stream(tar))
+        .await;
+
+    remove_volume_helper(docker, &helper_name).await?;
+    Ok(imported?)
+}
+
+/// Whether any volume of the instance exists, i.e. there's data to back up
+pub async fn has_instance_volumes(instance_id: &str) -> Result<bool> {
+    let docker = connect_docker()?;
+    for kind in VOLUME_KINDS {
+        if volume_exists(&docker, &format!("blazedb_{}_{}", kind, instance_id)).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Writes both volumes of the instance into `dir` as `config.tar` and `sources.tar`, returns
+/// their total size. A running container is paused meanwhile so the snapshot is consistent
+pub async fn export_instance_volumes(instance_id: &str, dir: &Path) -> Result<u64> {
+    let docker = connect_docker()?;
+    let container_name = format!("blazedb-{}", instance_id);
+
+    pull_volume_copy_image(&docker).await?;
+
+    let paused = container_exists(&docker, &container_name).await?
+        && docker.pause_container(&container_name).await.is_ok();
+
+    let mut exported = Ok(0);
+    for kind in VOLUME_KINDS {
+        let volume = format!("blazedb_{}_{}", kind, instance_id);
+        match export_volume(&docker, &volume, &dir.join(format!("{}.tar", kind))).await {
+            Ok(size) => exported = exported.map(|total| total + size),
+            Err(e) => {
+                exported = Err(e);
+                break;
+            }
+        }
+    }
+
+    if paused {
+        docker.unpause_container(&container_name).await?;
+    }
+    let size = exported?;
+
+    info!(
+        "Exported volumes of instance {} to {} ({} bytes)",
+        instance_id,
+        dir.display(),
+        size
+    );
+
+    Ok(size)
+}
+
+/// Fills fresh volumes for the instance from the tarballs `export_instance_volumes` wrote to
+/// `dir`. The container and its old volumes must be gone already
+pub async fn import_instance_volumes(instance_id: &str, dir: &Path) -> Result<()> {
+    let docker = connect_docker()?;
+
+    pull_volume_copy_image(&docker).await?;
+
+    for kind in VOLUME_KINDS {
+        let volume = format!("blazedb_{}_{}", kind, instance_id);
+        import_volume(&docker, &volume, &dir.join(format!("{}.tar", kind))).await?;
+    }
+
+    info!(
+        "Imported volumes of instance {} from {}",
+        instance_id,
+        dir.display()
+    );
+
+    Ok(())
+}
+
+/// Copies everything in volume `from` into volume `to` (created if missing) with a throwaway container
+async fn copy_volume(docker: &Docker, from: &str, to: &str) -> Result<()> {
+    use futures_util::stream::StreamExt;
+
+    if !volume_exists(docker, from).await? {
+        return Err(BlazeError::docker(format!("Volume {} doesn't exist", from)));
+    }
+    create_volume_if_not_exists(docker, to).await?;
+
+    let helper_name = format!("blz-volume-copy-{}", hex::encode(rand::random::<[u8; 4]>()));
+    let config = ContainerCreateBody {
+        image: Some(format!("{}:{}", VOLUME_COPY_IMAGE, VOLUME_COPY_IMAGE_TAG)),
+        cmd: Some(vec![
+            "cp".to_string(),
+            "-a".to_string(),
+            "/from/.".to_string(),
+            "/to/".to_string(),
+        ]),
+        host_config: Some(HostConfig {
+            mounts: Some(vec![
+                Mount {
+                    target: Some("/from".to_string()),
+                    source: Some(from.to_string()),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    read_only: Some(true),
+                    ..Default::default()
+                },
+                Mount {
+                    target: Some("/to".to_string()),
+                    source: Some(to.to_string()),
+                    typ: Some(MountTypeEnum::VOLUME),
+                    ..Default::default()
+                },
+            ]),
+            network_mode: Some("none".to_string()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let options = CreateContainerOptions {
+        name: Some(helper_name.clone()),
+        ..Default::default()
+    };
+
+    docker.create_container(Some(options), config).await?;
+    docker
+        .start_container(&helper_name, None::<StartContainerOptions>)
+        .await?;
+
+    // A non-zero exit comes back as an error
+    let mut waited = Ok(());
+    let mut stream = docker.wait_container(&helper_name, None);
+    while let Some(result) = stream.next().await {
+        if let Err(e) = result {
+            waited = Err(BlazeError::docker(format!(
+                "Copying {} to {} failed: {}",
+                from, to, e
+            )));
+        }
+    }
+
+    let options = RemoveContainerOptions {
+        force: true,
+        ..Default::default()
+    };
+    docker.remove_container(&helper_name, Some(options)).await?;
+
+    waited
+}
+
 /// Updates the container image by pulling the latest image and restarting the container to apply changes (data persists)
 #[allow(unused)]
 pub async fn update_container_image(instance_id: &str) -> Result<()> {
@@ -414,8 +1228,8 @@ async fn pull_blazedb_image(docker: &Docker) -> Result<()> {
     use futures_util::stream::StreamExt;
 
     let options = CreateImageOptions {
-        from_image: Some("ronakgh97/blazedb".to_string()),
-        tag: Some("latest".to_string()),
+        from_image: Some(BLAZEDB_IMAGE.to_string()),
+        tag: Some(BLAZEDB_IMAGE_TAG.to_string()),
         ..Default::default()
     };
 
@@ -427,3 +1241,84 @@ async fn pull_blazedb_image(docker: &Docker) -> Result<()> {
 
     Ok(())
 }
+
+/// Pulls the BlazeDB image and fails if the pull does, unlike `pull_blazedb_image`
+/// which falls back to whatever image is cached
+pub(crate) async fn pull_blazedb_image_checked(docker: &Docker) -> Result<()> {
+    use futures_util::stream::StreamExt;
+
+    let options = CreateImageOptions {
+        from_image: Some(BLAZEDB_IMAGE.to_string()),
+        tag: Some(BLAZEDB_IMAGE_TAG.to_string()),
+        ..Default::default()
+    };
+
+    let mut stream = docker.create_image(Some(options), None, None);
+
+    while let Some(result) = stream.next().await {
+        let progress = result?;
+        if let Some(error) = progress.error_detail.and_then(|d| d.message) {
+            return Err(BlazeError::docker(format!("Image pull failed: {}", error)));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_container_spec_for_plan() {
+    use crate::server::plans::builtin_plan;
+    let free = ContainerSpec::for_plan(&builtin_plan("free"));
+    let pro = ContainerSpec::for_plan(&builtin_plan("pro"));
+
+    assert!(pro.cpu_count > free.cpu_count);
+    assert!(pro.memory_mb > free.memory_mb);
+    assert_eq!((free.nano_cpus(), free.cpu_shares()), (500_000_000, 512));
+    assert_eq!(free.memory_bytes(), 256 * 1024 * 1024);
+
+    let update = pro.update_body();
+    assert_eq!(update.memory, update.memory_swap);
+    assert_eq!(update.pids_limit, Some(512));
+    assert!(free.env.contains(&"BLAZE_MAX_DATABASES=5".to_string()));
+    assert!(
+        pro.env
+            .contains(&"BLAZE_MAX_VECTORS_PER_DB=500000".to_string())
+    );
+    assert!(free.env.contains(&"RUST_LOG=info".to_string()));
+
+    // A user's own embedding API never gets the service's key
+    let config = InstanceConfig {
+        embedding_api_url: Some("https://embed.example.com/v1/embeddings".to_string()),
+        log_level: Some("debug".to_string()),
+        ..Default::default()
+    };
+    let custom = ContainerSpec::new(&builtin_plan("free"), &config);
+    assert!(custom.env.contains(&"RUST_LOG=debug".to_string()));
+    assert!(custom.env.contains(&"EMBEDDING_API_KEY=".to_string()));
+    assert_ne!(custom.env, free.env);
+    assert_eq!(
+        (custom.cpu_count, custom.memory_mb),
+        (free.cpu_count, free.memory_mb)
+    );
+}
+
+#[test]
+fn test_docker_host_settings() {
+    assert_eq!(
+        podman_sockets(Some("/run/user/1000/")),
+        [
+            "/run/user/1000/podman/podman.sock",
+            "/run/podman/podman.sock"
+        ]
+    );
+    assert_eq!(podman_sockets(None), ["/run/podman/podman.sock"]);
+
+    assert!(is_tls_verify(Some("1")));
+    assert!(!is_tls_verify(Some("0")));
+    assert!(!is_tls_verify(Some("")));
+    assert!(!is_tls_verify(None));
+
+    // Plain HTTP clients don't connect until the first call
+    assert!(connect_docker_host("http://10.0.0.5:2375", DOCKER_TIMEOUT).is_ok());
+    assert!(connect_docker_host("ftp://10.0.0.5", DOCKER_TIMEOUT).is_err());
+}
@@ -0,0 +1,279 @@
+//! # Container events
+//!
+//! Asking Docker for a container's state on every health check or proxied request adds up. A
+//! listener follows Docker's event stream instead and keeps the state of every `blazedb-`
+//! container in memory (running, starting, unhealthy, stopped, and whether it was OOM-killed),
+//! re-inspecting a container only when an event says it changed.
+//!
+//! The registry is only trusted while the listener is following the stream: it's seeded from a
+//! full listing right after subscribing (events from before the listing are replayed, so none
+//! fall in the gap), and cleared again as soon as the stream breaks. Until it has reconnected,
+//! lookups go to Docker like before. Each process (service and proxy) runs its own listener.
+
+use crate::server::container::connect_docker;
+use crate::{info, warn};
+use anyhow::Result;
+use bollard::Docker;
+use bollard::models::{ContainerState, HealthStatusEnum};
+use bollard::query_parameters::{EventsOptions, ListContainersOptions};
+use futures_util::stream::StreamExt;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+
+/// container name -> last known state, None while the listener isn't following the stream
+static CONTAINER_STATES: RwLock<Option<HashMap<String, TrackedContainer>>> = RwLock::new(None);
+
+/// What Docker last reported about a container
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackedContainer {
+    pub state: &'static str, // See `get_instance_state`
+    pub is_healthy: bool,
+    pub started_at: String,
+    pub finished_at: String,
+    pub error: String,
+    pub oom_killed: bool,
+    pub exit_code: Option<i64>,
+}
+
+impl TrackedContainer {
+    pub fn missing() -> Self {
+        TrackedContainer {
+            state: "missing",
+            is_healthy: false,
+            started_at: String::new(),
+            finished_at: String::new(),
+            error: String::new(),
+            oom_killed: false,
+            exit_code: None,
+        }
+    }
+
+    /// From the state of an inspected container
+    pub fn from_state(state: Option<&ContainerState>) -> Self {
+        let Some(state) = state else {
+            return TrackedContainer {
+                state: "stopped",
+                ..Self::missing()
+            };
+        };
+
+        let health = state.health.as_ref().and_then(|h| h.status);
+        let lifecycle = if state.restarting == Some(true) {
+            "restarting"
+        } else if state.running != Some(true) {
+            "stopped"
+        } else {
+            match health {
+                Some(HealthStatusEnum::STARTING) => "starting",
+                Some(HealthStatusEnum::UNHEALTHY) => "unhealthy",
+                _ => "running",
+            }
+        };
+
+        TrackedContainer {
+            state: lifecycle,
+            is_healthy: health == Some(HealthStatusEnum::HEALTHY),
+            started_at: state.started_at.clone().unwrap_or_default(),
+            finished_at: state.finished_at.clone().unwrap_or_default(),
+            error: state.error.clone().unwrap_or_default(),
+            oom_killed: state.oom_killed.unwrap_or(false),
+            exit_code: state.exit_code,
+        }
+    }
+}
+
+/// The container as the listener last saw it, None if the listener isn't in sync
+/// A container the listener doesn't know of is missing
+pub fn get_tracked_container(container_name: &str) -> Option<TrackedContainer> {
+    let states = CONTAINER_STATES.read().unwrap_or_else(|e| e.into_inner());
+    states.as_ref().map(|states| {
+        states
+            .get(container_name)
+            .cloned()
+            .unwrap_or_else(TrackedContainer::missing)
+    })
+}
+
+fn set_states(states: Option<HashMap<String, TrackedContainer>>) {
+    *CONTAINER_STATES.write().unwrap_or_else(|e| e.into_inner()) = states;
+}
+
+fn update_state(container_name: String, tracked: Option<TrackedContainer>) {
+    let mut states = CONTAINER_STATES.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(states) = states.as_mut() {
+        match tracked {
+            Some(tracked) => states.insert(container_name, tracked),
+            None => states.remove(&container_name),
+        };
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum EventEffect {
+    Inspect,
+    Remove,
+    Ignore,
+}
+
+/// What a container event's action means for its tracked state
+fn event_effect(action: &str) -> EventEffect {
+    match action {
+        "destroy" => EventEffect::Remove,
+        "create" | "start" | "restart" | "die" | "stop" | "oom" | "pause" | "unpause" => {
+            EventEffect::Inspect
+        }
+        _ if action.starts_with("health_status") => EventEffect::Inspect,
+        _ => EventEffect::Ignore, // exec, attach, copy and the like
+    }
+}
+
+/// Inspects the container, None if it's gone already
+async fn inspect(docker: &Docker, container_name: &str) -> Result<Option<TrackedContainer>> {
+    match docker.inspect_container(container_name, None).await {
+        Ok(info) => Ok(Some(TrackedContainer::from_state(info.state.as_ref()))),
+        Err(bollard::errors::Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn list_blazedb_containers(docker: &Docker) -> Result<HashMap<String, TrackedContainer>> {
+    let mut filters = HashMap::new();
+    filters.insert("name".to_string(), vec!["blazedb-".to_string()]);
+
+    let options = ListContainersOptions {
+        all: true,
+        filters: Some(filters),
+        ..Default::default()
+    };
+
+    let mut states = HashMap::new();
+    for container in docker.list_containers(Some(options)).await? {
+        let Some(name) = container
+            .names
+            .and_then(|names| names.into_iter().next())
+            .map(|n| n.trim_start_matches('/').to_string())
+        else {
+            continue;
+        };
+        if let Some(tracked) = inspect(docker, &name).await? {
+            states.insert(name, tracked);
+        }
+    }
+    Ok(states)
+}
+
+/// Subscribes, seeds the registry and follows the stream until it breaks or shutdown
+async fn follow_events(token: &CancellationToken) -> Result<()> {
+    let docker = connect_docker()?;
+
+    // Replayed from just before the listing, a change in between is seen twice at worst
+    let since = chrono::Utc::now().timestamp() - 1;
+    let mut filters = HashMap::new();
+    filters.insert("type".to_string(), vec!["container".to_string()]);
+    let mut events = docker.events(Some(EventsOptions {
+        since: Some(since.to_string()),
+        filters: Some(filters),
+        ..Default::default()
+    }));
+
+    let states = list_blazedb_containers(&docker).await?;
+    info!("Tracking {} container(s) from Docker events", states.len());
+    set_states(Some(states));
+
+    loop {
+        let event = tokio::select! {
+            _ = token.cancelled() => return Ok(()),
+            event = events.next() => event,
+        };
+        let event = match event {
+            Some(event) => event?,
+            None => return Err(anyhow::anyhow!("Docker closed the event stream")),
+        };
+
+        let Some(name) = event
+            .actor
+            .and_then(|a| a.attributes)
+            .and_then(|mut attributes| attributes.remove("name"))
+            .filter(|name| name.starts_with("blazedb-"))
+        else {
+            continue;
+        };
+        let tracked = match event_effect(event.action.as_deref().unwrap_or_default()) {
+            EventEffect::Ignore => continue,
+            EventEffect::Remove => None,
+            EventEffect::Inspect => inspect(&docker, &name).await?,
+        };
+        update_state(name, tracked);
+    }
+}
+
+/// Keeps the registry in sync with Docker until shutdown, reconnecting with a backoff
+pub async fn watch_container_events(token: CancellationToken) {
+    let mut backoff = Duration::from_secs(1);
+    loop {
+        match follow_events(&token).await {
+            Ok(()) => break,
+            Err(e) => warn!(
+                "Lost the Docker event stream, retrying in {}s: {}",
+                backoff.as_secs(),
+                e
+            ),
+        }
+        set_states(None);
+
+        tokio::select! {
+            _ = token.cancelled() => break,
+            _ = tokio::time::sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+    }
+    set_states(None);
+}
+
+#[test]
+fn test_container_state_tracking() {
+    use bollard::models::Health;
+
+    assert_eq!(event_effect("die"), EventEffect::Inspect);
+    assert_eq!(
+        event_effect("health_status: unhealthy"),
+        EventEffect::Inspect
+    );
+    assert_eq!(event_effect("destroy"), EventEffect::Remove);
+    assert_eq!(event_effect("exec_start: sh"), EventEffect::Ignore);
+
+    let running = |health: Option<HealthStatusEnum>| ContainerState {
+        running: Some(true),
+        health: health.map(|status| Health {
+            status: Some(status),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let healthy = TrackedContainer::from_state(Some(&running(Some(HealthStatusEnum::HEALTHY))));
+    assert_eq!((healthy.state, healthy.is_healthy), ("running", true));
+    assert_eq!(
+        TrackedContainer::from_state(Some(&running(None))).state,
+        "running"
+    );
+    assert_eq!(
+        TrackedContainer::from_state(Some(&running(Some(HealthStatusEnum::UNHEALTHY)))).state,
+        "unhealthy"
+    );
+
+    let killed = TrackedContainer::from_state(Some(&ContainerState {
+        running: Some(false),
+        oom_killed: Some(true),
+        exit_code: Some(137),
+        ..Default::default()
+    }));
+    assert_eq!(killed.state, "stopped");
+    assert!(killed.oom_killed);
+    assert_eq!(TrackedContainer::from_state(None).state, "stopped");
+}
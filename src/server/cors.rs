@@ -0,0 +1,108 @@
+//! # CORS
+//!
+//! Lets browser dashboards and client apps call the API and the proxy directly. Origins are set
+//! per deployment: `BLAZE_CORS_ORIGINS` for the API, `BLAZE_PROXY_CORS_ORIGINS` for the proxy
+//! (the API's when unset), each a comma separated list like `https://app.example.com` or `*` for
+//! any origin. Without one no CORS headers are sent and browsers stick to same-origin requests.
+//!
+//! Requests authenticate with a bearer key, not cookies, so credentials are never allowed.
+//! Preflights are answered right away, the proxy doesn't forward OPTIONS requests while CORS is on.
+
+use axum::http::{HeaderName, HeaderValue, Method, header};
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+const DEFAULT_MAX_AGE_SECONDS: u64 = 600;
+
+/// Origins a browser may call from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+/// Parses a comma separated origin list, None if it doesn't name any
+pub fn parse_origins(value: &str) -> Option<CorsOrigins> {
+    let origins: Vec<&str> = value
+        .split(',')
+        .map(|origin| origin.trim().trim_end_matches('/'))
+        .filter(|origin| !origin.is_empty())
+        .collect();
+    if origins.contains(&"*") {
+        return Some(CorsOrigins::Any);
+    }
+
+    let list: Vec<HeaderValue> = origins
+        .iter()
+        .filter(|origin| origin.starts_with("http://") || origin.starts_with("https://"))
+        .filter_map(|origin| HeaderValue::from_str(origin).ok())
+        .collect();
+    (!list.is_empty()).then_some(CorsOrigins::List(list))
+}
+
+fn origins_from_env(name: &str) -> Option<CorsOrigins> {
+    dotenv::dotenv().ok();
+    std::env::var(name).ok().and_then(|v| parse_origins(&v))
+}
+
+fn max_age() -> Duration {
+    Duration::from_secs(
+        std::env::var("BLAZE_CORS_MAX_AGE_SECONDS")
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_MAX_AGE_SECONDS),
+    )
+}
+
+fn base_layer(origins: CorsOrigins) -> CorsLayer {
+    let allow_origin = match origins {
+        CorsOrigins::Any => AllowOrigin::any(),
+        CorsOrigins::List(list) => AllowOrigin::list(list),
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .expose_headers([
+            header::RETRY_AFTER,
+            header::CONTENT_DISPOSITION,
+            HeaderName::from_static("x-request-id"),
+        ])
+        .max_age(max_age())
+}
+
+/// CORS for the API, None when `BLAZE_CORS_ORIGINS` isn't set
+pub fn api_cors_layer() -> Option<CorsLayer> {
+    let layer = base_layer(origins_from_env("BLAZE_CORS_ORIGINS")?)
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::ACCEPT]);
+    Some(layer)
+}
+
+/// CORS for the proxy, which passes any method and header on to the instance
+pub fn proxy_cors_layer() -> Option<CorsLayer> {
+    let origins = origins_from_env("BLAZE_PROXY_CORS_ORIGINS")
+        .or_else(|| origins_from_env("BLAZE_CORS_ORIGINS"))?;
+    let layer = base_layer(origins)
+        .allow_methods(AllowMethods::mirror_request())
+        .allow_headers(AllowHeaders::mirror_request());
+    Some(layer)
+}
+
+#[test]
+fn test_parse_origins() {
+    assert_eq!(
+        parse_origins(" https://app.example.com/, http://localhost:3000,,ftp://x.com"),
+        Some(CorsOrigins::List(vec![
+            HeaderValue::from_static("https://app.example.com"),
+            HeaderValue::from_static("http://localhost:3000"),
+        ]))
+    );
+    assert_eq!(parse_origins("https://a.com, *"), Some(CorsOrigins::Any));
+    assert_eq!(parse_origins(""), None);
+    assert_eq!(parse_origins("example.com"), None);
+}
@@ -1,12 +1,54 @@
+use crate::server::secrets::{Secrets, get_secrets};
+use crate::warn;
+use chacha20poly1305::aead::Aead;
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use pbkdf2::pbkdf2_hmac;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
 use zeroize::ZeroizeOnDrop;
 
+pub mod token;
+
+use token::Keyring;
+
+/// Prefix of API key hashes keyed with the server pepper (`BLAZE_API_KEY_PEPPER`)
+/// Hashes without it are bare SHA-256, from before the pepper
+pub const PEPPERED_HASH_PREFIX: &str = "hmac-sha256:";
+
+/// First part of API keys, naming the format version (see `generate_api_key`)
+pub const API_KEY_PREFIX: &str = "blz1";
+/// First part of keys issued before the format was versioned, still accepted
+pub const LEGACY_API_KEY_PREFIX: &str = "blz";
+/// First part of opaque keys, which don't carry the email (see `generate_opaque_api_key`)
+pub const OPAQUE_API_KEY_PREFIX: &str = "blz2";
+
+static API_KEY_PEPPER: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+static PREVIOUS_API_KEY_PEPPER: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+static ACCEPT_LEGACY_KEY_HASHES: OnceLock<bool> = OnceLock::new();
+static OPAQUE_API_KEYS: OnceLock<bool> = OnceLock::new();
+
+/// Prefix of encrypted personal fields, see `encrypt_pii`
+pub const PII_PREFIX: &str = "pii1:";
+/// Prefix of email index keys, see `email_index`
+pub const EMAIL_INDEX_PREFIX: &str = "idx1:";
+
+static PII_KEYS: OnceLock<Option<PiiKeys>> = OnceLock::new();
+
+/// Keys derived from `BLAZE_PII_KEY`, one per use
+struct PiiKeys {
+    cipher: ChaCha20Poly1305,
+    nonce: [u8; 32],
+    index: [u8; 32],
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq, Hash, ZeroizeOnDrop)]
 pub struct APIKey {
+    #[serde(with = "pii_field")]
     pub user_name: String,
+    #[serde(with = "pii_field")]
     pub user_email: String,
     pub api_key_hash: String,
     pub key_prefix: String,
@@ -15,11 +57,16 @@ pub struct APIKey {
 }
 
 impl APIKey {
-    /// Generates a new APIKey for the given username and email.
+    /// Generates a new APIKey for the given username and email, in the format the deployment
+    /// issues (see `issues_opaque_api_keys`)
     /// Returns (APIKey with hash, plain_text_key for one-time display)
-    pub async fn get_new_key(user_name: &str, user_email: &str) -> (Self, String) {
-        let plain_key = generate_api_key(user_name, user_email).await;
-        let key_hash = hash_api_key(&plain_key).await;
+    pub fn get_new_key(user_name: &str, user_email: &str) -> (Self, String) {
+        let plain_key = if issues_opaque_api_keys() {
+            generate_opaque_api_key()
+        } else {
+            generate_api_key(user_name, user_email)
+        };
+        let key_hash = hash_api_key(&plain_key);
         let prefix = plain_key.chars().take(12).collect::<String>() + "...";
 
         let api_key = APIKey {
@@ -35,34 +82,50 @@ impl APIKey {
     }
 
     /// Revokes the API key.
-    pub async fn revoke(&mut self) {
+    pub fn revoke(&mut self) {
         self.is_revoked = true;
     }
 
     /// Verifies if the provided plain API key matches this stored hash
-    /// The key format embeds the email, but we still verify the full key hash
-    pub async fn verify(&self, plain_key: &str) -> bool {
+    /// Keys embedding an email must embed this one, but we still verify the full key hash
+    pub fn verify(&self, plain_key: &str) -> bool {
         if self.is_revoked {
             return false;
         }
 
-        // Verify email matches (quick check)
-        if let Some(extracted_email) = extract_email_from_api_key(plain_key) {
-            if extracted_email != self.user_email {
-                return false;
+        // Verify email matches (quick check), opaque keys only have their hash
+        match api_key_format_version(plain_key) {
+            Some(2) => {}
+            Some(_) => {
+                if extract_email_from_api_key(plain_key).as_deref() != Some(&self.user_email) {
+                    return false;
+                }
             }
-        } else {
-            return false; // Invalid format
+            None => return false, // Invalid format
         }
 
         // Verify full key hash (security check)
-        let key_hash = hash_api_key(plain_key).await;
-        key_hash == self.api_key_hash
+        api_key_matches_hash(plain_key, &self.api_key_hash)
     }
 }
 
+/// Compares two hashes in constant time, so how long it takes doesn't tell how much of them
+/// matched. Only the length shows, which a hash doesn't keep secret
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Compares a provided secret (token, password...) with the expected one in constant time
+/// Both are hashed first, so not even the expected length shows
+pub fn secrets_match(provided: &str, expected: &str) -> bool {
+    constant_time_eq(
+        &Sha256::digest(provided.as_bytes()),
+        &Sha256::digest(expected.as_bytes()),
+    )
+}
+
 /// Generates a cryptographic salt of the specified length in bytes.
-pub async fn generate_salt(len: usize) -> Vec<u8> {
+pub fn generate_salt(len: usize) -> Vec<u8> {
     let mut salt = vec![0u8; len];
     rand::rng().fill_bytes(&mut salt);
     salt
@@ -70,41 +133,98 @@ pub async fn generate_salt(len: usize) -> Vec<u8> {
 
 /// Generates a secure key using PBKDF2 with HMAC-SHA256.
 /// The key is derived from the user's name and email, combined with a provided salt.
-pub async fn generate_key(user_name: &str, user_email: &str, salt: &[u8]) -> Vec<u8> {
-    let mut key = vec![0u8; 16];
+/// 100k iterations take tens of milliseconds, so they run on the blocking pool, not the runtime
+pub async fn generate_key(
+    user_name: &str,
+    user_email: &str,
+    salt: &[u8],
+) -> anyhow::Result<Vec<u8>> {
     let password = format!("{}:{}", user_name, user_email);
-    pbkdf2_hmac::<Sha256>(
-        password.as_bytes(),
-        salt,
-        100_000, // Number of iterations
-        &mut key,
-    );
-    key
+    let salt = salt.to_vec();
+    let key = tokio::task::spawn_blocking(move || {
+        let mut key = vec![0u8; 16];
+        pbkdf2_hmac::<Sha256>(
+            password.as_bytes(),
+            &salt,
+            100_000, // Number of iterations
+            &mut key,
+        );
+        key
+    })
+    .await?;
+    Ok(key)
 }
 
 /// Generates an API key for the user.
-/// Format: "blz_{base64_email}_{random_secret}"
+/// Format: "blz1_{base64_email}_{random_secret}", `blz1` being the format version
 /// This allows extracting the user email directly from the key (O(1) user lookup)
-pub async fn generate_api_key(_user_name: &str, user_email: &str) -> String {
+pub fn generate_api_key(_user_name: &str, user_email: &str) -> String {
     use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 
     let email_encoded = URL_SAFE_NO_PAD.encode(user_email.as_bytes());
 
     // Generate random secret (32 bytes = 256 bits of entropy)
-    let secret = generate_salt(32).await;
+    let secret = generate_salt(32);
     let secret_encoded = hex::encode(&secret);
 
-    format!("blz_{}_{}", email_encoded, secret_encoded)
+    format!("{}_{}_{}", API_KEY_PREFIX, email_encoded, secret_encoded)
+}
+
+/// Generates an opaque API key, which doesn't leak the email in logs and headers
+/// Format: "blz2_{random_secret}", the owner is found through `key_index`
+pub fn generate_opaque_api_key() -> String {
+    format!(
+        "{}_{}",
+        OPAQUE_API_KEY_PREFIX,
+        hex::encode(generate_salt(32))
+    )
+}
+
+/// Whether new API keys are opaque (`BLAZE_API_KEY_FORMAT=opaque`) rather than carrying the email
+/// (`email`, the default). Keys of both formats are accepted either way
+pub fn issues_opaque_api_keys() -> bool {
+    *OPAQUE_API_KEYS.get_or_init(|| {
+        dotenv::dotenv().ok();
+        match std::env::var("BLAZE_API_KEY_FORMAT") {
+            Ok(format) if format.trim().eq_ignore_ascii_case("opaque") => true,
+            Ok(format) if format.trim().eq_ignore_ascii_case("email") => false,
+            Err(_) => false,
+            Ok(format) => {
+                warn!(
+                    "Ignoring invalid BLAZE_API_KEY_FORMAT: {}, issuing email keys",
+                    format
+                );
+                false
+            }
+        }
+    })
+}
+
+/// Digest opaque keys are indexed under, see `key_index`
+/// Unpeppered, so rotating the pepper doesn't lose the index. The key's 256 random bits are what
+/// keep it from being reversed
+pub fn api_key_lookup_id(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+/// Format version of an API key, 0 for keys from before versioning, None if it isn't one
+pub fn api_key_format_version(api_key: &str) -> Option<u32> {
+    match api_key.split_once('_')?.0 {
+        OPAQUE_API_KEY_PREFIX => Some(2),
+        API_KEY_PREFIX => Some(1),
+        LEGACY_API_KEY_PREFIX => Some(0),
+        _ => None,
+    }
 }
 
 /// Extracts the user email from an API key
-/// Returns None if the key format is invalid
+/// Returns None if the key format is invalid or the key is opaque
 pub fn extract_email_from_api_key(api_key: &str) -> Option<String> {
     use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 
-    // Expected format: blz_{base64_email}_{secret}
+    // Expected format: blz1_{base64_email}_{secret} (or blz_ before versioning)
     let parts: Vec<&str> = api_key.split('_').collect();
-    if parts.len() != 3 || parts[0] != "blz" {
+    if parts.len() != 3 || !matches!(api_key_format_version(api_key), Some(0 | 1)) {
         return None;
     }
 
@@ -113,31 +233,475 @@ pub fn extract_email_from_api_key(api_key: &str) -> Option<String> {
 }
 
 /// Hashes the provided one-time password (OTP) using SHA-256.
-pub async fn hash_otp(otp: &str) -> Vec<u8> {
+/// Letters are uppercased first, alphanumeric codes can be typed in either case
+pub fn hash_otp(otp: &str) -> Vec<u8> {
     let mut hasher = Sha256::new();
-    hasher.update(otp.as_bytes());
+    hasher.update(otp.trim().to_ascii_uppercase().as_bytes());
     hasher.finalize().to_vec()
 }
 
-/// Hashes the provided API key using SHA-256 and returns hex-encoded string
-pub async fn hash_api_key(api_key: &str) -> String {
+/// OTP length when `BLAZE_OTP_LENGTH` isn't set, and the range it may be set in
+pub const DEFAULT_OTP_LENGTH: usize = 6;
+pub const OTP_LENGTH_RANGE: std::ops::RangeInclusive<usize> = 4..=16;
+
+static OTP_SETTINGS: OnceLock<(usize, OtpAlphabet)> = OnceLock::new();
+
+/// Characters OTPs are drawn from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpAlphabet {
+    Numeric,
+    /// Digits and uppercase letters without I, L, O and U, which read like 1, 0 and V
+    /// 5 bits per character, for flows that need more entropy than a typed code (magic links)
+    Alphanumeric,
+}
+
+impl OtpAlphabet {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "numeric" => Some(OtpAlphabet::Numeric),
+            "alphanumeric" => Some(OtpAlphabet::Alphanumeric),
+            _ => None,
+        }
+    }
+
+    fn characters(&self) -> &'static [u8] {
+        match self {
+            OtpAlphabet::Numeric => b"0123456789",
+            OtpAlphabet::Alphanumeric => b"0123456789ABCDEFGHJKMNPQRSTVWXYZ",
+        }
+    }
+}
+
+/// Generates a one-time password of `length` characters from `alphabet`
+/// Drawn from the thread's CSPRNG, every character uniformly (no modulo bias)
+pub fn generate_otp(length: usize, alphabet: OtpAlphabet) -> String {
+    use rand::RngExt;
+
+    let characters = alphabet.characters();
+    let mut rng = rand::rng();
+    (0..length)
+        .map(|_| char::from(characters[rng.random_range(0..characters.len())]))
+        .collect()
+}
+
+/// Length and alphabet of emailed codes, from `BLAZE_OTP_LENGTH` and `BLAZE_OTP_ALPHABET`
+/// (`numeric` or `alphanumeric`), 6 digits by default
+pub fn otp_settings() -> (usize, OtpAlphabet) {
+    *OTP_SETTINGS.get_or_init(|| {
+        dotenv::dotenv().ok();
+        let length = match std::env::var("BLAZE_OTP_LENGTH") {
+            Ok(value) => match value.trim().parse::<usize>() {
+                Ok(length) if OTP_LENGTH_RANGE.contains(&length) => length,
+                _ => {
+                    warn!(
+                        "Ignoring invalid BLAZE_OTP_LENGTH: {}, it must be {} to {}",
+                        value,
+                        OTP_LENGTH_RANGE.start(),
+                        OTP_LENGTH_RANGE.end()
+                    );
+                    DEFAULT_OTP_LENGTH
+                }
+            },
+            Err(_) => DEFAULT_OTP_LENGTH,
+        };
+        let alphabet = match std::env::var("BLAZE_OTP_ALPHABET") {
+            Ok(value) => OtpAlphabet::parse(&value).unwrap_or_else(|| {
+                warn!("Ignoring invalid BLAZE_OTP_ALPHABET: {}", value);
+                OtpAlphabet::Numeric
+            }),
+            Err(_) => OtpAlphabet::Numeric,
+        };
+        (length, alphabet)
+    })
+}
+
+/// Server-side secret API keys are hashed with (`BLAZE_API_KEY_PEPPER`), service and proxy must
+/// share it. None when it isn't set, keys are then hashed the legacy way
+///
+/// To rotate it, move the current one to `BLAZE_API_KEY_PEPPER_PREVIOUS` and set a new one. Keys
+/// hashed with the previous pepper keep working and the service rehashes them as they're used,
+/// see `needs_rehash`
+pub fn get_api_key_pepper() -> Option<&'static [u8]> {
+    API_KEY_PEPPER
+        .get_or_init(|| match get_secrets().get("BLAZE_API_KEY_PEPPER") {
+            Some(pepper) => Some(pepper.as_bytes().to_vec()),
+            None => {
+                warn!("BLAZE_API_KEY_PEPPER is not set, API keys are hashed with bare SHA-256");
+                None
+            }
+        })
+        .as_deref()
+}
+
+/// The pepper being rotated out (`BLAZE_API_KEY_PEPPER_PREVIOUS`), see `get_api_key_pepper`
+pub fn get_previous_api_key_pepper() -> Option<&'static [u8]> {
+    PREVIOUS_API_KEY_PEPPER
+        .get_or_init(|| {
+            get_secrets()
+                .get("BLAZE_API_KEY_PEPPER_PREVIOUS")
+                .map(|pepper| pepper.as_bytes().to_vec())
+        })
+        .as_deref()
+}
+
+/// Whether keys whose stored hash is still bare SHA-256 are accepted
+/// (`BLAZE_ACCEPT_LEGACY_KEY_HASHES`, on by default). The service rehashes them with the pepper
+/// as they're used, turn it off once none are left
+pub fn accepts_legacy_key_hashes() -> bool {
+    *ACCEPT_LEGACY_KEY_HASHES.get_or_init(|| {
+        dotenv::dotenv().ok();
+        std::env::var("BLAZE_ACCEPT_LEGACY_KEY_HASHES")
+            .map(|value| !matches!(value.trim(), "0" | "false" | "off"))
+            .unwrap_or(true)
+    })
+}
+
+/// HMAC-SHA256 of the key under `pepper`, prefixed with `PEPPERED_HASH_PREFIX`
+pub fn hash_api_key_with_pepper(api_key: &str, pepper: &[u8]) -> String {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(pepper).expect("HMAC takes keys of any length");
+    mac.update(api_key.as_bytes());
+    format!(
+        "{}{}",
+        PEPPERED_HASH_PREFIX,
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Bare SHA-256 of the key, how keys were hashed before the pepper
+pub fn legacy_hash_api_key(api_key: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(api_key.as_bytes());
     hex::encode(hasher.finalize())
 }
 
+/// Hashes the provided API key with the server pepper and returns hex-encoded string
+/// Falls back to the legacy hash when no pepper is set
+pub fn hash_api_key(api_key: &str) -> String {
+    match get_api_key_pepper() {
+        Some(pepper) => hash_api_key_with_pepper(api_key, pepper),
+        None => legacy_hash_api_key(api_key),
+    }
+}
+
+/// Whether the key matches a stored hash of either kind, under the current or the previous pepper
+/// See `accepts_legacy_key_hashes`
+pub fn api_key_matches_hash(api_key: &str, stored_hash: &str) -> bool {
+    if stored_hash.starts_with(PEPPERED_HASH_PREFIX) {
+        return [get_api_key_pepper(), get_previous_api_key_pepper()]
+            .into_iter()
+            .flatten()
+            .any(|pepper| {
+                constant_time_eq(
+                    hash_api_key_with_pepper(api_key, pepper).as_bytes(),
+                    stored_hash.as_bytes(),
+                )
+            });
+    }
+    accepts_legacy_key_hashes()
+        && constant_time_eq(
+            legacy_hash_api_key(api_key).as_bytes(),
+            stored_hash.as_bytes(),
+        )
+}
+
+/// Whether the stored hash of a key that matched it should be replaced, because it's bare SHA-256
+/// or was made with the previous pepper
+pub fn needs_rehash(api_key: &str, stored_hash: &str) -> bool {
+    get_api_key_pepper().is_some_and(|pepper| {
+        !constant_time_eq(
+            hash_api_key_with_pepper(api_key, pepper).as_bytes(),
+            stored_hash.as_bytes(),
+        )
+    })
+}
+
 /// Verifies the provided OTP against the stored hash.
-pub async fn verify_otp(otp: &str, hash: &[u8]) -> bool {
-    let otp_hash = hash_otp(otp).await;
-    otp_hash == hash
+pub fn verify_otp(otp: &str, hash: &[u8]) -> bool {
+    let otp_hash = hash_otp(otp);
+    constant_time_eq(&otp_hash, hash)
+}
+
+impl PiiKeys {
+    fn from_secret(secret: &str) -> Self {
+        let derive = |purpose: &str| -> [u8; 32] {
+            Sha256::digest(format!("{}:{}", purpose, secret).as_bytes()).into()
+        };
+        PiiKeys {
+            // Named in full, HMAC's `Mac` has a `new_from_slice` of its own
+            cipher: <ChaCha20Poly1305 as chacha20poly1305::KeyInit>::new(Key::from_slice(&derive(
+                "pii-encryption",
+            ))),
+            nonce: derive("pii-nonce"),
+            index: derive("pii-index"),
+        }
+    }
+
+    fn encrypt(&self, value: &str) -> String {
+        use base64::{Engine as _, engine::general_purpose::STANDARD};
+        use hmac::{Hmac, Mac};
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.nonce).expect("HMAC takes keys of any length");
+        mac.update(value.as_bytes());
+        let nonce = mac.finalize().into_bytes();
+        let nonce = Nonce::from_slice(&nonce[..12]);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(nonce, value.as_bytes())
+                .expect("ChaCha20-Poly1305 encrypts any length"),
+        );
+        format!("{}{}", PII_PREFIX, STANDARD.encode(sealed))
+    }
+
+    fn decrypt(&self, sealed: &str) -> anyhow::Result<String> {
+        use base64::{Engine as _, engine::general_purpose::STANDARD};
+
+        let sealed = STANDARD.decode(sealed)?;
+        if sealed.len() < 12 {
+            return Err(anyhow::anyhow!("Encrypted field is too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                anyhow::anyhow!("Encrypted field doesn't decrypt, wrong BLAZE_PII_KEY?")
+            })?;
+        Ok(String::from_utf8(plain)?)
+    }
+
+    fn index(&self, email: &str) -> String {
+        use hmac::{Hmac, Mac};
+
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.index).expect("HMAC takes keys of any length");
+        mac.update(email.as_bytes());
+        format!(
+            "{}{}",
+            EMAIL_INDEX_PREFIX,
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+}
+
+fn pii_keys() -> Option<&'static PiiKeys> {
+    PII_KEYS
+        .get_or_init(|| {
+            let secret = get_secrets().get("BLAZE_PII_KEY")?;
+            if secret.len() < 32 {
+                warn!("BLAZE_PII_KEY is shorter than 32 characters, personal fields stay in plain text");
+                return None;
+            }
+            Some(PiiKeys::from_secret(secret))
+        })
+        .as_ref()
+}
+
+/// Whether personal fields are encrypted at rest (`BLAZE_PII_KEY` is set, at least 32 characters)
+/// Service and proxy must share the key
+pub fn is_pii_encrypted() -> bool {
+    pii_keys().is_some()
+}
+
+/// Encrypts a personal field (email, name) for storage, as `pii1:{base64 nonce and ciphertext}`
+/// Deterministic: the nonce is an HMAC of the value, so the same value encrypts the same way and
+/// saved files only change when the data does. Returned as is without `BLAZE_PII_KEY`
+pub fn encrypt_pii(value: &str) -> String {
+    match pii_keys() {
+        Some(keys) => keys.encrypt(value),
+        None => value.to_string(),
+    }
+}
+
+/// Reads a personal field back, plain text ones (written before encryption) as they are
+pub fn decrypt_pii(stored: &str) -> anyhow::Result<String> {
+    let Some(sealed) = stored.strip_prefix(PII_PREFIX) else {
+        return Ok(stored.to_string());
+    };
+    pii_keys()
+        .ok_or_else(|| anyhow::anyhow!("BLAZE_PII_KEY must be set to read encrypted fields"))?
+        .decrypt(sealed)
+}
+
+/// Key users are stored under: an HMAC of the email, so lookups by email work without the email
+/// being readable in the file. The email itself without `BLAZE_PII_KEY`
+pub fn email_index(email: &str) -> String {
+    match pii_keys() {
+        Some(keys) => keys.index(email),
+        None => email.to_string(),
+    }
+}
+
+/// Serde helpers for personal `String` fields, encrypted when written and decrypted when read
+/// Use with `#[serde(with = "pii_field")]`, see `encrypt_pii`
+pub mod pii_field {
+    use super::{decrypt_pii, encrypt_pii};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encrypt_pii(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        let stored = String::deserialize(deserializer)?;
+        decrypt_pii(&stored).map_err(serde::de::Error::custom)
+    }
+}
+
+pub const INSTANCE_TOKEN_PREFIX: &str = "blzt_";
+/// Instance tokens can't be revoked, so they are kept short-lived
+pub const INSTANCE_TOKEN_TTL_SECONDS: i64 = 15 * 60;
+/// Audience of instance tokens, see `token`
+pub const INSTANCE_TOKEN_AUDIENCE: &str = "instance";
+
+/// Claims carried by a signed instance token
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct InstanceTokenClaims {
+    pub instance_id: String,
+    pub email: String,
+    pub expires_at: i64, // Unix seconds
+}
+
+/// What an instance token carries besides the registered claims (the email is the subject)
+#[derive(Deserialize, Serialize)]
+struct InstanceTokenData {
+    instance_id: String,
+}
+
+/// The instance token signing secrets (`BLAZE_INSTANCE_TOKEN_SECRET`, and
+/// `BLAZE_INSTANCE_TOKEN_SECRET_PREVIOUS` while rotating). Service and proxy must share them
+pub fn get_instance_token_keyring(secrets: &Secrets) -> anyhow::Result<Keyring> {
+    Keyring::from_secrets(secrets, "BLAZE_INSTANCE_TOKEN_SECRET")
+}
+
+/// Signs the claims, see `token`
+/// Format: "blzt_{jwt}"
+pub fn sign_instance_token(
+    claims: &InstanceTokenClaims,
+    keyring: &Keyring,
+) -> anyhow::Result<String> {
+    let data = InstanceTokenData {
+        instance_id: claims.instance_id.clone(),
+    };
+    let token = keyring.mint(
+        INSTANCE_TOKEN_AUDIENCE,
+        &claims.email,
+        &data,
+        chrono::Utc::now().timestamp(),
+        claims.expires_at,
+    )?;
+
+    Ok(format!("{}{}", INSTANCE_TOKEN_PREFIX, token))
+}
+
+/// Verifies the signature (in constant time) and expiry of an instance token
+/// Returns None if the token is malformed, tampered with, or expired at `now` (Unix seconds)
+pub fn verify_instance_token(
+    token: &str,
+    keyring: &Keyring,
+    now: i64,
+) -> Option<InstanceTokenClaims> {
+    let token = token.strip_prefix(INSTANCE_TOKEN_PREFIX)?;
+    let claims = keyring
+        .verify::<InstanceTokenData>(token, INSTANCE_TOKEN_AUDIENCE, now)
+        .ok()?;
+
+    Some(InstanceTokenClaims {
+        instance_id: claims.data.instance_id,
+        email: claims.sub,
+        expires_at: claims.exp,
+    })
+}
+
+/// Issues a short-lived instance token with the secret from env
+pub fn issue_instance_token(
+    instance_id: &str,
+    email: &str,
+) -> anyhow::Result<(String, InstanceTokenClaims)> {
+    let claims = InstanceTokenClaims {
+        instance_id: instance_id.to_string(),
+        email: email.to_string(),
+        expires_at: chrono::Utc::now().timestamp() + INSTANCE_TOKEN_TTL_SECONDS,
+    };
+
+    let token = sign_instance_token(&claims, &get_instance_token_keyring(get_secrets())?)?;
+
+    Ok((token, claims))
+}
+
+/// How far a webhook's signed timestamp may be from our clock, older deliveries are replays
+pub const WEBHOOK_SIGNATURE_TOLERANCE_SECONDS: i64 = 300;
+
+/// HMAC-SHA256 of `{timestamp}.{payload}`, hex encoded
+pub fn webhook_signature(payload: &[u8], secret: &[u8], timestamp: i64) -> String {
+    use hmac::{Hmac, Mac};
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Signature header for a webhook body sent at `now` (Unix seconds)
+/// Format: "t={timestamp},v1={signature}", the same as Stripe's
+pub fn sign_webhook(payload: &[u8], secret: &[u8], now: i64) -> String {
+    format!("t={},v1={}", now, webhook_signature(payload, secret, now))
+}
+
+/// Checks a `t=...,v1=...` header against the raw request body, in constant time
+/// Fails if no `v1` matches or the timestamp is more than `tolerance` seconds from `now`
+pub fn verify_webhook_signature(
+    payload: &[u8],
+    header: &str,
+    secret: &[u8],
+    now: i64,
+    tolerance: i64,
+) -> bool {
+    use hmac::{Hmac, Mac};
+
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in header.split(',') {
+        match part.trim().split_once('=') {
+            Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+            Some(("v1", value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+
+    let Some(timestamp) = timestamp else {
+        return false;
+    };
+    if (now - timestamp).abs() > tolerance {
+        return false;
+    }
+
+    // Several signatures while the sender rolls its secret, any one will do
+    signatures.into_iter().any(|signature| {
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else {
+            return false;
+        };
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(payload);
+        mac.verify_slice(&signature).is_ok()
+    })
 }
 
-#[tokio::test]
-async fn test_api_key_generation() -> anyhow::Result<()> {
+#[test]
+fn test_api_key_generation() -> anyhow::Result<()> {
     let user_name = "ronakgh97";
     let user_email = "ronakgh999@gmail.com";
 
-    let api_key = generate_api_key(user_name, user_email).await;
+    let api_key = generate_api_key(user_name, user_email);
     println!("Generated API Key: {}", api_key);
 
     assert!(api_key.len() > 20);
@@ -145,3 +709,183 @@ async fn test_api_key_generation() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_api_key_format_versions() {
+    let api_key = generate_api_key("ronakgh97", "ronakgh999@gmail.com");
+    assert!(api_key.starts_with("blz1_"));
+    assert_eq!(api_key_format_version(&api_key), Some(1));
+    assert_eq!(
+        extract_email_from_api_key(&api_key).as_deref(),
+        Some("ronakgh999@gmail.com")
+    );
+
+    // Keys from before versioning still read
+    let legacy = api_key.replacen("blz1_", "blz_", 1);
+    assert_eq!(api_key_format_version(&legacy), Some(0));
+    assert_eq!(
+        extract_email_from_api_key(&legacy).as_deref(),
+        Some("ronakgh999@gmail.com")
+    );
+
+    let unknown = api_key.replacen("blz1_", "blz9_", 1);
+    assert_eq!(api_key_format_version(&unknown), None);
+    assert_eq!(extract_email_from_api_key(&unknown), None);
+
+    // Opaque keys don't carry the email, only their hash ties them to the owner
+    let opaque = generate_opaque_api_key();
+    assert!(opaque.starts_with("blz2_"));
+    assert_eq!(api_key_format_version(&opaque), Some(2));
+    assert_eq!(extract_email_from_api_key(&opaque), None);
+    assert_eq!(api_key_lookup_id(&opaque), api_key_lookup_id(&opaque));
+
+    let stored = APIKey {
+        user_name: "ronakgh97".to_string(),
+        user_email: "ronakgh999@gmail.com".to_string(),
+        api_key_hash: hash_api_key(&opaque),
+        key_prefix: opaque.chars().take(12).collect(),
+        is_revoked: false,
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    assert!(stored.verify(&opaque));
+    assert!(!stored.verify(&generate_opaque_api_key()));
+}
+
+#[test]
+fn test_pii_encryption() -> anyhow::Result<()> {
+    let keys = PiiKeys::from_secret("0123456789abcdef0123456789abcdef");
+    let email = "ronakgh999@gmail.com";
+
+    let sealed = keys.encrypt(email);
+    assert!(sealed.starts_with(PII_PREFIX));
+    assert!(!sealed.contains("ronakgh999"));
+    // Deterministic, saving the same data writes the same file
+    assert_eq!(sealed, keys.encrypt(email));
+    assert_ne!(sealed, keys.encrypt("someone@else.com"));
+    assert_eq!(keys.decrypt(sealed.trim_start_matches(PII_PREFIX))?, email);
+
+    let other = PiiKeys::from_secret("fedcba9876543210fedcba9876543210");
+    assert!(
+        other
+            .decrypt(sealed.trim_start_matches(PII_PREFIX))
+            .is_err()
+    );
+
+    assert!(keys.index(email).starts_with(EMAIL_INDEX_PREFIX));
+    assert_eq!(keys.index(email), keys.index(email));
+    assert_ne!(keys.index(email), other.index(email));
+
+    // Fields written before encryption read as they are
+    assert_eq!(decrypt_pii(email)?, email);
+    Ok(())
+}
+
+#[test]
+fn test_generate_otp() {
+    let numeric = generate_otp(6, OtpAlphabet::Numeric);
+    assert_eq!(numeric.len(), 6);
+    assert!(numeric.chars().all(|c| c.is_ascii_digit()));
+
+    let code = generate_otp(12, OtpAlphabet::Alphanumeric);
+    assert_eq!(code.len(), 12);
+    assert!(
+        code.bytes()
+            .all(|c| OtpAlphabet::Alphanumeric.characters().contains(&c))
+    );
+    // Typed back in lowercase, it still matches
+    assert!(verify_otp(&code.to_lowercase(), &hash_otp(&code)));
+
+    // Every digit comes up about as often, 10% each
+    let mut counts = [0usize; 10];
+    for digit in generate_otp(100_000, OtpAlphabet::Numeric).bytes() {
+        counts[(digit - b'0') as usize] += 1;
+    }
+    assert!(counts.iter().all(|&count| (9_000..11_000).contains(&count)));
+}
+
+#[test]
+fn test_constant_time_comparisons() {
+    assert!(constant_time_eq(b"abc", b"abc"));
+    assert!(!constant_time_eq(b"abc", b"abd"));
+    assert!(!constant_time_eq(b"abc", b"abcd"));
+    assert!(secrets_match("token", "token"));
+    assert!(!secrets_match("token", "token "));
+}
+
+#[test]
+fn test_peppered_api_key_hash() {
+    let api_key = "blz_cm9uYWtAZXhhbXBsZS5jb20_0123456789abcdef";
+    let peppered = hash_api_key_with_pepper(api_key, b"pepper");
+
+    assert!(peppered.starts_with(PEPPERED_HASH_PREFIX));
+    assert_ne!(peppered, hash_api_key_with_pepper(api_key, b"other pepper"));
+    assert_ne!(
+        peppered.trim_start_matches(PEPPERED_HASH_PREFIX),
+        legacy_hash_api_key(api_key)
+    );
+    // Legacy hashes are still matched during the migration window
+    assert!(api_key_matches_hash(api_key, &legacy_hash_api_key(api_key)));
+    assert!(!api_key_matches_hash(
+        "blz_other",
+        &legacy_hash_api_key(api_key)
+    ));
+}
+
+#[test]
+fn test_instance_token_roundtrip() -> anyhow::Result<()> {
+    let keyring = Keyring::new(b"test_secret");
+    let claims = InstanceTokenClaims {
+        instance_id: "a1a70763676476be".to_string(),
+        email: "ronakgh999@gmail.com".to_string(),
+        expires_at: 2_000,
+    };
+
+    let token = sign_instance_token(&claims, &keyring)?;
+    assert!(token.starts_with(INSTANCE_TOKEN_PREFIX));
+
+    assert_eq!(
+        verify_instance_token(&token, &keyring, 1_000),
+        Some(claims.clone())
+    );
+    // Expired
+    assert_eq!(verify_instance_token(&token, &keyring, 2_000), None);
+    // Wrong secret
+    assert_eq!(
+        verify_instance_token(&token, &Keyring::new(b"other_secret"), 1_000),
+        None
+    );
+    // Secret rotated, the previous one still verifies
+    let rotated = Keyring::new(b"next_secret").with_previous(b"test_secret");
+    assert_eq!(verify_instance_token(&token, &rotated, 1_000), Some(claims));
+
+    Ok(())
+}
+
+#[test]
+fn test_instance_token_tampered_claims() -> anyhow::Result<()> {
+    use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+
+    let keyring = Keyring::new(b"test_secret");
+    let claims = InstanceTokenClaims {
+        instance_id: "a1a70763676476be".to_string(),
+        email: "ronakgh999@gmail.com".to_string(),
+        expires_at: 2_000,
+    };
+    let token = sign_instance_token(&claims, &keyring)?;
+    let parts: Vec<&str> = token.split('.').collect();
+
+    // Same header and signature, another instance in the claims
+    let forged_payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&serde_json::json!({
+        "aud": INSTANCE_TOKEN_AUDIENCE,
+        "sub": claims.email,
+        "iat": 0,
+        "exp": claims.expires_at,
+        "jti": "forged",
+        "instance_id": "b2c91234567890ab",
+    }))?);
+    let forged_token = format!("{}.{}.{}", parts[0], forged_payload, parts[2]);
+
+    assert_eq!(verify_instance_token(&forged_token, &keyring, 1_000), None);
+
+    Ok(())
+}
@@ -0,0 +1,267 @@
+//! # Signed tokens
+//!
+//! JWTs (HS256) for whatever we hand out to be presented back: instance tokens, sessions, magic
+//! links, calls between the service and the proxy.
+//!
+//! - Every token names the key that signed it in its `kid` header. A `Keyring` signs with its
+//!   current key and verifies with any of them, so to rotate a secret move it to
+//!   `<VAR>_PREVIOUS`, set a new one, and drop the previous once what it signed has expired.
+//! - Tokens carry an audience (what they're for), so one kind can't be passed off as another.
+//! - Only HS256 is accepted, whatever the header claims (no `none`, no algorithm swaps).
+
+use crate::server::secrets::Secrets;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use zeroize::ZeroizeOnDrop;
+
+const ALGORITHM: &str = "HS256";
+
+/// A signing secret and the id tokens name it by
+#[derive(Clone, ZeroizeOnDrop)]
+pub struct TokenKey {
+    kid: String,
+    secret: Vec<u8>,
+}
+
+impl TokenKey {
+    /// The id is derived from the secret, so service and proxy agree on it without configuring one
+    pub fn new(secret: &[u8]) -> Self {
+        let mut digest = Sha256::new();
+        digest.update(b"blaze-token-kid:");
+        digest.update(secret);
+        TokenKey {
+            kid: hex::encode(digest.finalize())[..16].to_string(),
+            secret: secret.to_vec(),
+        }
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    fn mac(&self, signing_input: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(signing_input.as_bytes());
+        mac
+    }
+}
+
+/// Signs with the current key, verifies with the current and previous ones
+#[derive(Clone)]
+pub struct Keyring {
+    keys: Vec<TokenKey>, // The first one signs
+}
+
+impl Keyring {
+    pub fn new(current: &[u8]) -> Self {
+        Keyring {
+            keys: vec![TokenKey::new(current)],
+        }
+    }
+
+    /// Also accepts tokens signed with `secret`, while it's being rotated out
+    pub fn with_previous(mut self, secret: &[u8]) -> Self {
+        self.keys.push(TokenKey::new(secret));
+        self
+    }
+
+    /// The current secret is `name` and the one being rotated out `<name>_PREVIOUS`
+    pub fn from_secrets(secrets: &Secrets, name: &str) -> anyhow::Result<Self> {
+        let mut keyring = Keyring::new(secrets.require(name)?.as_bytes());
+        if let Some(previous) = secrets.get(&format!("{}_PREVIOUS", name)) {
+            keyring = keyring.with_previous(previous.as_bytes());
+        }
+        Ok(keyring)
+    }
+
+    /// Id of the key new tokens are signed with
+    pub fn current_kid(&self) -> &str {
+        self.keys[0].kid()
+    }
+
+    /// Signs a token for `audience` about `subject`, valid from `now` until `expires_at` (Unix
+    /// seconds). `data` goes into the claims next to the registered ones
+    pub fn mint<T: Serialize>(
+        &self,
+        audience: &str,
+        subject: &str,
+        data: &T,
+        now: i64,
+        expires_at: i64,
+    ) -> anyhow::Result<String> {
+        let key = &self.keys[0];
+        let header = TokenHeader {
+            alg: ALGORITHM.to_string(),
+            typ: "JWT".to_string(),
+            kid: key.kid.clone(),
+        };
+        let claims = TokenClaims {
+            aud: audience.to_string(),
+            sub: subject.to_string(),
+            iat: now,
+            exp: expires_at,
+            jti: hex::encode(rand::random::<[u8; 12]>()),
+            data,
+        };
+
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        let signature = URL_SAFE_NO_PAD.encode(key.mac(&signing_input).finalize().into_bytes());
+        Ok(format!("{}.{}", signing_input, signature))
+    }
+
+    /// Checks the signature (in constant time), audience and expiry of a token at `now`
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        audience: &str,
+        now: i64,
+    ) -> Result<TokenClaims<T>, TokenError> {
+        let (signing_input, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let (header, claims) = signing_input.split_once('.').ok_or(TokenError::Malformed)?;
+
+        let header: TokenHeader = URL_SAFE_NO_PAD
+            .decode(header)
+            .ok()
+            .and_then(|header| serde_json::from_slice(&header).ok())
+            .ok_or(TokenError::Malformed)?;
+        if header.alg != ALGORITHM {
+            return Err(TokenError::Malformed);
+        }
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.kid == header.kid)
+            .ok_or(TokenError::UnknownKey)?;
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        key.mac(signing_input)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::BadSignature)?;
+
+        // Only read once the signature holds
+        let claims: TokenClaims<T> = URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|claims| serde_json::from_slice(&claims).ok())
+            .ok_or(TokenError::Malformed)?;
+        if claims.aud != audience {
+            return Err(TokenError::WrongAudience);
+        }
+        if claims.exp <= now {
+            return Err(TokenError::Expired);
+        }
+        Ok(claims)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenHeader {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// Registered claims of a token, with what the caller put in flattened next to them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TokenClaims<T> {
+    pub aud: String,
+    pub sub: String,
+    pub iat: i64, // Unix seconds
+    pub exp: i64, // Unix seconds
+    pub jti: String,
+    #[serde(flatten)]
+    pub data: T,
+}
+
+/// Why a token was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    UnknownKey, // Signed with a key that was rotated out, or never ours
+    BadSignature,
+    WrongAudience,
+    Expired,
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            TokenError::Malformed => "malformed token",
+            TokenError::UnknownKey => "token signed with an unknown key",
+            TokenError::BadSignature => "invalid token signature",
+            TokenError::WrongAudience => "token meant for something else",
+            TokenError::Expired => "expired token",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+#[test]
+fn test_token_mint_and_verify() -> anyhow::Result<()> {
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Session {
+        scope: String,
+    }
+
+    let keyring = Keyring::new(b"current_secret");
+    let session = Session {
+        scope: "dashboard".to_string(),
+    };
+    let token = keyring.mint("session", "alice@example.com", &session, 1_000, 2_000)?;
+    assert_eq!(token.matches('.').count(), 2);
+
+    let claims: TokenClaims<Session> = keyring.verify(&token, "session", 1_500)?;
+    assert_eq!(claims.sub, "alice@example.com");
+    assert_eq!(claims.data, session);
+
+    assert_eq!(
+        keyring.verify::<Session>(&token, "magic_link", 1_500),
+        Err(TokenError::WrongAudience)
+    );
+    assert_eq!(
+        keyring.verify::<Session>(&token, "session", 2_000),
+        Err(TokenError::Expired)
+    );
+    assert_eq!(
+        Keyring::new(b"other_secret").verify::<Session>(&token, "session", 1_500),
+        Err(TokenError::UnknownKey)
+    );
+
+    // Rotated: the new keyring still takes what the old secret signed, and signs with the new one
+    let rotated = Keyring::new(b"next_secret").with_previous(b"current_secret");
+    assert!(rotated.verify::<Session>(&token, "session", 1_500).is_ok());
+    let fresh = rotated.mint("session", "alice@example.com", &session, 1_000, 2_000)?;
+    assert_eq!(
+        keyring.verify::<Session>(&fresh, "session", 1_500),
+        Err(TokenError::UnknownKey)
+    );
+
+    // Claims swapped under a valid header and signature
+    let mut parts: Vec<&str> = token.split('.').collect();
+    let forged = URL_SAFE_NO_PAD.encode(
+        serde_json::to_vec(&serde_json::json!({
+            "aud": "session", "sub": "mallory@example.com", "iat": 1_000, "exp": 9_000,
+            "jti": "x", "scope": "dashboard"
+        }))
+        .unwrap(),
+    );
+    parts[1] = &forged;
+    assert_eq!(
+        keyring.verify::<Session>(&parts.join("."), "session", 1_500),
+        Err(TokenError::BadSignature)
+    );
+
+    Ok(())
+}
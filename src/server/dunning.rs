@@ -0,0 +1,287 @@
+//! # Dunning
+//!
+//! A failed subscription payment doesn't downgrade anyone right away. The user goes `PastDue` and
+//! keeps their plan and running instance for a grace period (`BLAZE_DUNNING_GRACE_DAYS`, 7 by
+//! default) while reminder emails go out: right away, halfway through and on the last day. If no
+//! payment goes through by then, the user is `Suspended` and their container is stopped, not
+//! destroyed. A later successful payment makes them `Active` again and starts the container.
+//!
+//! Stripe drives the transitions through the webhook (`invoice.payment_failed`, `invoice.paid`),
+//! a background task sends the reminders and suspends.
+
+use crate::server::container::{restart_container, stop_container};
+use crate::server::mailer::send_mail;
+use crate::server::schema::{BillingStatus, User};
+use crate::server::service::{get_all_users, get_user, update_user};
+use crate::server::tasks::get_task_registry;
+use crate::{error, info, warn};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+const DEFAULT_GRACE_DAYS: i64 = 7;
+
+pub fn grace_period_days() -> i64 {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_DUNNING_GRACE_DAYS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_GRACE_DAYS)
+}
+
+/// Days into the grace period at which reminders go out
+fn reminder_schedule(grace_days: i64) -> Vec<i64> {
+    let mut days = vec![0, grace_days / 2, grace_days - 1];
+    days.dedup();
+    days
+}
+
+/// What the enforcement task should do next for a user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DunningStep {
+    Wait,
+    Remind,
+    Suspend,
+}
+
+pub fn next_dunning_step(user: &User, now: DateTime<Utc>, grace_days: i64) -> DunningStep {
+    if user.billing_status != BillingStatus::PastDue {
+        return DunningStep::Wait;
+    }
+    let Some(since) = user
+        .past_due_since
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+    else {
+        return DunningStep::Wait;
+    };
+
+    let elapsed_days = (now - since.with_timezone(&Utc)).num_days();
+    if elapsed_days >= grace_days {
+        return DunningStep::Suspend;
+    }
+
+    let due_reminders = reminder_schedule(grace_days)
+        .into_iter()
+        .filter(|day| *day <= elapsed_days)
+        .count();
+    if due_reminders > user.dunning_reminders_sent as usize {
+        DunningStep::Remind
+    } else {
+        DunningStep::Wait
+    }
+}
+
+/// Days of the grace period left for a past due user
+fn days_left(user: &User, now: DateTime<Utc>, grace_days: i64) -> i64 {
+    user.past_due_since
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map_or(grace_days, |since| {
+            grace_days - (now - since.with_timezone(&Utc)).num_days()
+        })
+        .max(0)
+}
+
+/// Starts a dunning run after a failed payment, repeated failures don't reset the grace period
+pub async fn mark_past_due(email: &String) -> Result<()> {
+    let Some(user) = get_user(email).await? else {
+        warn!("Failed payment for unknown user {}", email);
+        return Ok(());
+    };
+    if user.billing_status != BillingStatus::Active {
+        return Ok(());
+    }
+
+    let user = update_user(email, |user| {
+        user.billing_status = BillingStatus::PastDue;
+        user.past_due_since = Some(Utc::now().to_rfc3339());
+        user.dunning_reminders_sent = 0;
+    })
+    .await?;
+    info!("{} is past due, grace period started", email);
+
+    // First reminder goes out right away instead of on the next enforcement tick
+    run_dunning_step(&user, Utc::now(), grace_period_days()).await
+}
+
+/// Ends a dunning run after a successful payment, starts the instance again if it was stopped
+pub async fn mark_payment_recovered(email: &String) -> Result<()> {
+    let Some(user) = get_user(email).await? else {
+        return Ok(());
+    };
+    if user.billing_status == BillingStatus::Active {
+        return Ok(());
+    }
+
+    let previous = user.billing_status;
+    let user = update_user(email, |user| {
+        user.billing_status = BillingStatus::Active;
+        user.past_due_since = None;
+        user.dunning_reminders_sent = 0;
+    })
+    .await?;
+
+    if previous == BillingStatus::Suspended {
+        for instance_id in user.instance_ids() {
+            restart_container(instance_id).await?;
+        }
+    }
+    info!("{} paid, back to active (was {:?})", email, previous);
+
+    let (plain_body, html_body) = build_dunning_email(&user.username, DunningEmail::Restored);
+    send_dunning_mail(
+        &user.email,
+        "Your BlazeDB payment went through",
+        plain_body,
+        html_body,
+    );
+    Ok(())
+}
+
+async fn run_dunning_step(user: &User, now: DateTime<Utc>, grace_days: i64) -> Result<()> {
+    match next_dunning_step(user, now, grace_days) {
+        DunningStep::Wait => {}
+        DunningStep::Remind => {
+            let reminders = user.dunning_reminders_sent + 1;
+            update_user(&user.email, |user| user.dunning_reminders_sent = reminders).await?;
+
+            let left = days_left(user, now, grace_days);
+            let (plain_body, html_body) =
+                build_dunning_email(&user.username, DunningEmail::Reminder { days_left: left });
+            send_dunning_mail(
+                &user.email,
+                "Your BlazeDB payment failed",
+                plain_body,
+                html_body,
+            );
+        }
+        DunningStep::Suspend => {
+            // Stop first, a user marked suspended with a running container would get service for free
+            for instance_id in user.instance_ids() {
+                stop_container(instance_id).await?;
+            }
+            update_user(&user.email, |user| {
+                user.billing_status = BillingStatus::Suspended
+            })
+            .await?;
+            warn!("{} suspended after the grace period", user.email);
+
+            let (plain_body, html_body) =
+                build_dunning_email(&user.username, DunningEmail::Suspended);
+            send_dunning_mail(
+                &user.email,
+                "Your BlazeDB instance is stopped",
+                plain_body,
+                html_body,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Sends due reminders and suspends users whose grace period is over
+/// This is called periodically via a background task
+pub async fn enforce_dunning() -> Result<usize> {
+    let now = Utc::now();
+    let grace_days = grace_period_days();
+
+    let mut acted = 0;
+    for user in get_all_users()
+        .await?
+        .into_iter()
+        .filter(|u| u.billing_status == BillingStatus::PastDue)
+    {
+        if next_dunning_step(&user, now, grace_days) == DunningStep::Wait {
+            continue;
+        }
+        match run_dunning_step(&user, now, grace_days).await {
+            Ok(()) => acted += 1,
+            Err(e) => error!("Dunning step failed for {}: {}", user.email, e),
+        }
+    }
+    Ok(acted)
+}
+
+enum DunningEmail {
+    Reminder { days_left: i64 },
+    Suspended,
+    Restored,
+}
+
+fn build_dunning_email(username: &str, kind: DunningEmail) -> (String, String) {
+    let (heading, text) = match kind {
+        DunningEmail::Reminder { days_left } => (
+            "Your payment failed".to_string(),
+            format!(
+                "We couldn't charge your payment method. Your instance keeps running for {} more day(s), after that it will be stopped until the payment goes through. Please update your payment method.",
+                days_left
+            ),
+        ),
+        DunningEmail::Suspended => (
+            "Your instance is stopped".to_string(),
+            "We still couldn't charge your payment method, so your instance is stopped. Your data is kept, the instance starts again as soon as a payment goes through.".to_string(),
+        ),
+        DunningEmail::Restored => (
+            "Thanks, you're all set".to_string(),
+            "Your payment went through and your account is in good standing again.".to_string(),
+        ),
+    };
+
+    let plain_body = format!("Hi {},\n\n{}", username, text);
+    let html_body = format!(
+        r#"
+        <html>
+        <body style="font-family: sans-serif;">
+            <h2>{heading}</h2>
+            <p>Hi {username},</p>
+            <p>{text}</p>
+        </body>
+        </html>
+        "#
+    );
+    (plain_body, html_body)
+}
+
+fn send_dunning_mail(email: &str, subject: &'static str, plain_body: String, html_body: String) {
+    let email = email.to_string();
+    get_task_registry().spawn("dunning-mail", |_| async move {
+        let sent = tokio::task::spawn_blocking(move || {
+            send_mail(&email, subject, plain_body, html_body).map_err(|e| (email, e))
+        })
+        .await;
+        if let Ok(Err((email, e))) = sent {
+            error!("Failed to send dunning email to {}: {}", email, e);
+        }
+    });
+}
+
+#[test]
+fn test_next_dunning_step() {
+    use crate::server::schema::test_user;
+
+    let since = DateTime::parse_from_rfc3339("2026-03-01T00:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let mut user = test_user("test@example.com", "pro");
+    user.instance_id = "inst".to_string();
+    user.created_at = since.to_rfc3339();
+    user.billing_status = BillingStatus::PastDue;
+    user.past_due_since = Some(since.to_rfc3339());
+    let day = |d: i64| since + chrono::Duration::days(d) + chrono::Duration::hours(1);
+
+    assert_eq!(reminder_schedule(7), vec![0, 3, 6]);
+    assert_eq!(next_dunning_step(&user, day(0), 7), DunningStep::Remind);
+
+    user.dunning_reminders_sent = 1;
+    assert_eq!(next_dunning_step(&user, day(2), 7), DunningStep::Wait);
+    assert_eq!(next_dunning_step(&user, day(3), 7), DunningStep::Remind);
+    assert_eq!(days_left(&user, day(3), 7), 4);
+
+    user.dunning_reminders_sent = 3;
+    assert_eq!(next_dunning_step(&user, day(6), 7), DunningStep::Wait);
+    assert_eq!(next_dunning_step(&user, day(7), 7), DunningStep::Suspend);
+
+    user.billing_status = BillingStatus::Suspended;
+    assert_eq!(next_dunning_step(&user, day(30), 7), DunningStep::Wait);
+}
@@ -0,0 +1,192 @@
+//! # Errors
+//!
+//! `BlazeError` is what the library's public APIs (`service`, `container`, `storage`) fail with,
+//! so callers can match on the kind of failure instead of inspecting messages. Modules that still
+//! return `anyhow::Result` can use `?` on these (it's a `std::error::Error`), and anything coming
+//! back from them ends up as `Other`, unless it was a `BlazeError` to begin with.
+
+use axum::http::StatusCode;
+use std::fmt;
+
+pub type Result<T> = std::result::Result<T, BlazeError>;
+
+#[derive(Debug)]
+pub enum BlazeError {
+    /// Reading or writing a persisted store, including writes refused while read-only
+    Storage(String),
+    /// Talking to the Docker daemon
+    Docker(String),
+    /// Sending email, including sends refused by the rate limits
+    Mail(String),
+    /// The request doesn't make sense in the current state (already on that plan, bad input)
+    Validation(String),
+    /// Unknown or unverified user, bad credentials
+    Auth(String),
+    /// A submitted verification code was refused
+    Otp(OtpFailure),
+    /// Anything else, from code that still uses `anyhow`
+    Other(anyhow::Error),
+}
+
+impl BlazeError {
+    pub fn storage(message: impl Into<String>) -> Self {
+        BlazeError::Storage(message.into())
+    }
+
+    pub fn docker(message: impl Into<String>) -> Self {
+        BlazeError::Docker(message.into())
+    }
+
+    pub fn mail(message: impl Into<String>) -> Self {
+        BlazeError::Mail(message.into())
+    }
+
+    pub fn validation(message: impl Into<String>) -> Self {
+        BlazeError::Validation(message.into())
+    }
+
+    pub fn auth(message: impl Into<String>) -> Self {
+        BlazeError::Auth(message.into())
+    }
+}
+
+impl fmt::Display for BlazeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlazeError::Storage(message) => write!(f, "Storage error: {}", message),
+            BlazeError::Docker(message) => write!(f, "Docker error: {}", message),
+            BlazeError::Mail(message) => write!(f, "Mail error: {}", message),
+            // Shown to users as is, no prefix
+            BlazeError::Validation(message) | BlazeError::Auth(message) => {
+                write!(f, "{}", message)
+            }
+            BlazeError::Otp(failure) => write!(f, "{}", failure),
+            BlazeError::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Why a submitted code was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtpFailure {
+    UnknownEmail,
+    NoCode,  // Registered, but no code was asked for
+    Expired, // Also when it was already used
+    WrongCode,
+    TooManyAttempts, // The pending code stopped working, a new one has to be asked for
+    NoCapacity,      // Nowhere to place the instance, the code stays valid for a retry
+}
+
+impl OtpFailure {
+    /// 404 for an unknown email or no code, 410 for an expired or used code, 401 for a wrong one
+    /// and 429 once too many wrong ones were tried
+    pub fn status(&self) -> StatusCode {
+        match self {
+            OtpFailure::UnknownEmail | OtpFailure::NoCode => StatusCode::NOT_FOUND,
+            OtpFailure::Expired => StatusCode::GONE,
+            OtpFailure::WrongCode => StatusCode::UNAUTHORIZED,
+            OtpFailure::TooManyAttempts => StatusCode::TOO_MANY_REQUESTS,
+            OtpFailure::NoCapacity => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl fmt::Display for OtpFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            OtpFailure::UnknownEmail => "User not found",
+            OtpFailure::NoCode => "No verification code found for this email",
+            OtpFailure::Expired => "Verification code has expired",
+            OtpFailure::WrongCode => "Invalid verification code",
+            OtpFailure::TooManyAttempts => {
+                "Too many invalid attempts, please request a new verification code"
+            }
+            OtpFailure::NoCapacity => {
+                "No capacity for new instances right now, please try again later"
+            }
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl std::error::Error for BlazeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlazeError::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<anyhow::Error> for BlazeError {
+    fn from(e: anyhow::Error) -> Self {
+        // A BlazeError that went through anyhow keeps its kind
+        match e.downcast::<BlazeError>() {
+            Ok(e) => e,
+            Err(e) => BlazeError::Other(e),
+        }
+    }
+}
+
+impl From<bollard::errors::Error> for BlazeError {
+    fn from(e: bollard::errors::Error) -> Self {
+        BlazeError::Docker(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for BlazeError {
+    fn from(e: std::io::Error) -> Self {
+        BlazeError::Storage(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for BlazeError {
+    fn from(e: serde_json::Error) -> Self {
+        BlazeError::Storage(e.to_string())
+    }
+}
+
+impl From<rusqlite::Error> for BlazeError {
+    fn from(e: rusqlite::Error) -> Self {
+        BlazeError::Storage(e.to_string())
+    }
+}
+
+impl From<chrono::ParseError> for BlazeError {
+    fn from(e: chrono::ParseError) -> Self {
+        BlazeError::Storage(format!("Invalid timestamp: {}", e))
+    }
+}
+
+impl From<hex::FromHexError> for BlazeError {
+    fn from(e: hex::FromHexError) -> Self {
+        BlazeError::Storage(format!("Invalid hex: {}", e))
+    }
+}
+
+/// `anyhow::Context` for storage code, the message is kept in front of the error
+pub(crate) trait StorageContext<T> {
+    fn storage_context(self, message: &str) -> Result<T>;
+}
+
+impl<T, E: fmt::Display> StorageContext<T> for std::result::Result<T, E> {
+    fn storage_context(self, message: &str) -> Result<T> {
+        self.map_err(|e| BlazeError::Storage(format!("{}: {}", message, e)))
+    }
+}
+
+#[test]
+fn test_blaze_error_through_anyhow() {
+    let e: anyhow::Error = BlazeError::validation("Already on the Pro plan").into();
+    assert!(matches!(BlazeError::from(e), BlazeError::Validation(_)));
+
+    let e = BlazeError::from(anyhow::anyhow!("boom"));
+    assert!(matches!(e, BlazeError::Other(_)));
+    assert_eq!(e.to_string(), "boom");
+
+    let e: Result<()> = Err(std::io::Error::other("disk full")).storage_context("Failed to save");
+    assert_eq!(
+        e.unwrap_err().to_string(),
+        "Storage error: Failed to save: disk full"
+    );
+}
@@ -0,0 +1,176 @@
+//! # Header forwarding
+//!
+//! Hop-by-hop headers describe one connection, not the request, so the proxy drops them in both
+//! directions and lets each side frame its own connection (`Transfer-Encoding`, keep-alive). That
+//! covers the fixed list from RFC 9110 and anything the `Connection` header names on top.
+//! WebSocket handshakes keep `Connection` and `Upgrade`, see `streams::upstream_upgrade_headers`.
+//!
+//! Forwarding headers (`X-Forwarded-For`, `-Proto`, `-Host` and `Forwarded`) are extended with the
+//! proxy's own hop before the request goes on, so the instance sees the whole chain. What a peer
+//! that isn't a trusted proxy sent is dropped first, it could claim anything (see `network`).
+
+use axum::http::{HeaderMap, HeaderName, HeaderValue, header};
+use std::net::IpAddr;
+
+/// Headers that only ever apply to a single connection
+pub const HOP_BY_HOP_HEADERS: [HeaderName; 8] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    header::PROXY_AUTHENTICATE,
+    header::PROXY_AUTHORIZATION,
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+/// Removes hop-by-hop headers, including the ones listed in `Connection`
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+
+    for name in HOP_BY_HOP_HEADERS.iter().chain(&listed) {
+        headers.remove(name);
+    }
+}
+
+/// Headers describing the hops a request took before reaching us
+/// `X-Real-IP` isn't extended, only passed on when a trusted proxy set it
+pub const FORWARDING_HEADERS: [HeaderName; 5] = [
+    HeaderName::from_static("x-forwarded-for"),
+    HeaderName::from_static("x-forwarded-proto"),
+    HeaderName::from_static("x-forwarded-host"),
+    HeaderName::from_static("x-real-ip"),
+    header::FORWARDED,
+];
+
+/// Joins every value of a header into one comma separated list
+fn joined(headers: &HeaderMap, name: &HeaderName) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect();
+    (!values.is_empty()).then(|| values.join(", "))
+}
+
+/// Adds a hop to a comma separated header, after the hops already in it
+fn append_hop(headers: &mut HeaderMap, name: &HeaderName, hop: &str) {
+    let chain = match joined(headers, name) {
+        Some(chain) => format!("{}, {}", chain, hop),
+        None => hop.to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&chain) {
+        headers.insert(name, value);
+    }
+}
+
+/// A `Forwarded` node for an address, IPv6 has to be quoted and bracketed (RFC 7239)
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// Appends the hop from `peer` to the forwarding headers sent on to the instance
+/// `trusted` is whether the peer is a proxy of ours, `proto` the scheme it reached us over
+pub fn append_forwarding_headers(
+    headers: &mut HeaderMap,
+    peer: IpAddr,
+    trusted: bool,
+    proto: &str,
+) {
+    if !trusted {
+        for name in &FORWARDING_HEADERS {
+            headers.remove(name);
+        }
+    }
+
+    let [forwarded_for, forwarded_proto, forwarded_host, _, forwarded] = &FORWARDING_HEADERS;
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    append_hop(headers, forwarded_for, &peer.to_string());
+
+    // The first proxy's view of the client wins, ours only when we are the first
+    if !headers.contains_key(forwarded_proto)
+        && let Ok(value) = HeaderValue::from_str(proto)
+    {
+        headers.insert(forwarded_proto, value);
+    }
+    if !headers.contains_key(forwarded_host)
+        && let Some(value) = host.as_deref().and_then(|h| HeaderValue::from_str(h).ok())
+    {
+        headers.insert(forwarded_host, value);
+    }
+
+    let mut element = format!("for={};proto={}", forwarded_node(peer), proto);
+    if let Some(host) = host.filter(|h| !h.contains('"')) {
+        element.push_str(&format!(";host=\"{}\"", host));
+    }
+    append_hop(headers, forwarded, &element);
+}
+
+#[test]
+fn test_strip_hop_by_hop() {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONNECTION, "keep-alive, X-Debug".parse().unwrap());
+    headers.insert("keep-alive", "timeout=5".parse().unwrap());
+    headers.insert("x-debug", "1".parse().unwrap());
+    headers.insert(header::TRANSFER_ENCODING, "chunked".parse().unwrap());
+    headers.insert(header::TE, "trailers".parse().unwrap());
+    headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+    headers.insert(header::CONTENT_LENGTH, "2".parse().unwrap());
+
+    strip_hop_by_hop(&mut headers);
+
+    assert_eq!(headers.len(), 2);
+    assert!(headers.contains_key(header::CONTENT_TYPE));
+    assert!(headers.contains_key(header::CONTENT_LENGTH));
+}
+
+#[test]
+fn test_append_forwarding_headers() {
+    let spoofed = || {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "api.blazedb.io".parse().unwrap());
+        headers.insert("x-forwarded-for", "6.6.6.6".parse().unwrap());
+        headers.append("x-forwarded-for", "198.51.100.7".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        headers.insert(header::FORWARDED, "for=6.6.6.6".parse().unwrap());
+        headers.insert("x-real-ip", "6.6.6.6".parse().unwrap());
+        headers
+    };
+
+    // From a client, whatever it claimed is replaced by what we saw
+    let mut headers = spoofed();
+    append_forwarding_headers(&mut headers, "203.0.113.9".parse().unwrap(), false, "http");
+    assert_eq!(headers["x-forwarded-for"], "203.0.113.9");
+    assert_eq!(headers["x-forwarded-proto"], "http");
+    assert_eq!(headers["x-forwarded-host"], "api.blazedb.io");
+    assert!(!headers.contains_key("x-real-ip"));
+    assert_eq!(
+        headers[header::FORWARDED],
+        r#"for=203.0.113.9;proto=http;host="api.blazedb.io""#
+    );
+
+    // From our load balancer, the chain is kept and extended
+    let mut headers = spoofed();
+    append_forwarding_headers(&mut headers, "fd00::2".parse().unwrap(), true, "http");
+    assert_eq!(headers["x-forwarded-for"], "6.6.6.6, 198.51.100.7, fd00::2");
+    assert_eq!(headers["x-forwarded-proto"], "https");
+    assert_eq!(
+        headers[header::FORWARDED],
+        r#"for=6.6.6.6, for="[fd00::2]";proto=http;host="api.blazedb.io""#
+    );
+}
@@ -0,0 +1,112 @@
+//! # Hibernation
+//!
+//! Containers of plans with `hibernate_after_hours` set are stopped by the proxy once nobody sent
+//! them a request for that long, so idle free instances don't hold on to host memory. What counts
+//! as idle comes from the proxy's `ActivityTracker`, an instance with a response still streaming
+//! is never idle. Instances the proxy hasn't seen since it started count as idle since then.
+//!
+//! Hibernated instances are kept in `get_data_path()/hibernated.json`, written by the proxy (the
+//! service only drops the record when the owner stops their instance). The next request to one
+//! starts its container again and waits up to `BLAZE_COLD_START_WAIT_SECONDS` (20 by default) for
+//! it to come up before forwarding, a client that outwaits that gets a 503 with `Retry-After`. A
+//! container that's stopped without a record (by its owner, or by dunning) stays stopped.
+//!
+//! The proxy stops and starts the containers itself, so it needs the Docker socket (or
+//! `DOCKER_HOST`) too, see `docker-compose.yml`.
+
+use crate::info;
+use crate::server::container::{
+    ContainerSpec, start_blazedb_container, stop_blazedb_container, wait_for_running,
+};
+use crate::server::schema::{HibernatedInstance, Plans, User};
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use anyhow::Result;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const DEFAULT_COLD_START_WAIT_SECONDS: u64 = 20;
+
+static HIBERNATION_STORE: OnceLock<DataStore<String, HibernatedInstance>> = OnceLock::new();
+
+/// Hibernated instances keyed by instance id
+pub fn get_hibernation_store() -> DataStore<String, HibernatedInstance> {
+    HIBERNATION_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("hibernated.json");
+            DataStore::<String, HibernatedInstance>::new(path)
+                .expect("CRASH!! Failed to initialize hibernation datastore")
+        })
+        .clone()
+}
+
+/// How long a request waits for a hibernated instance to start
+pub fn cold_start_wait() -> Duration {
+    dotenv::dotenv().ok();
+    let seconds = std::env::var("BLAZE_COLD_START_WAIT_SECONDS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_COLD_START_WAIT_SECONDS);
+    Duration::from_secs(seconds)
+}
+
+/// Whether an instance on `plan` that's been idle for `idle` should hibernate
+pub fn is_due_for_hibernation(plan: &Plans, idle: Duration) -> bool {
+    plan.features
+        .hibernate_after_hours
+        .is_some_and(|hours| idle >= Duration::from_secs(u64::from(hours) * 3600))
+}
+
+pub fn is_hibernated(instance_id: &str) -> Result<bool> {
+    Ok(get_hibernation_store().contains_key(&instance_id.to_string())?)
+}
+
+/// Whether the instance is hibernated, for readers other than the proxy
+pub fn get_hibernation(instance_id: &str) -> Result<Option<HibernatedInstance>> {
+    let store = get_hibernation_store();
+    store.reload()?;
+    Ok(store.get(&instance_id.to_string())?)
+}
+
+/// Stops the instance's container and records it as hibernated
+pub async fn hibernate_instance(instance_id: &str, email: &str) -> Result<()> {
+    stop_blazedb_container(instance_id).await?;
+    get_hibernation_store().insert_save(
+        instance_id.to_string(),
+        HibernatedInstance {
+            instance_id: instance_id.to_string(),
+            email: email.to_string(),
+            hibernated_at: chrono::Utc::now().to_rfc3339(),
+        },
+    )?;
+    info!("Hibernated idle instance {} of {}", instance_id, email);
+    Ok(())
+}
+
+/// Drops the record of an instance that's running again without the proxy waking it
+pub fn forget_hibernation(instance_id: &str) -> Result<()> {
+    get_hibernation_store().delete(&instance_id.to_string())?;
+    Ok(())
+}
+
+/// Starts a hibernated instance, returns whether it was running within `wait`
+pub async fn wake_instance(instance_id: &str, owner: &User, wait: Duration) -> Result<bool> {
+    start_blazedb_container(instance_id, &ContainerSpec::for_user(owner)).await?;
+    forget_hibernation(instance_id)?;
+    info!("Waking hibernated instance {}", instance_id);
+
+    Ok(wait_for_running(instance_id, wait).await?)
+}
+
+#[test]
+fn test_is_due_for_hibernation() {
+    use crate::server::plans::builtin_plan;
+
+    let free = builtin_plan("free");
+    let pro = builtin_plan("pro");
+    let hours = |h: u64| Duration::from_secs(h * 3600);
+
+    assert!(!is_due_for_hibernation(&free, hours(5)));
+    assert!(is_due_for_hibernation(&free, hours(6)));
+    assert!(!is_due_for_hibernation(&pro, hours(24 * 365))); // Paid plans never hibernate
+}
@@ -0,0 +1,208 @@
+//! # Incident history
+//!
+//! Notable platform events (mass container restarts, SMTP outages, storage failovers) are kept
+//! under `get_data_path()/incidents.json` so users can match their errors against them. Incidents
+//! are either detected here from what the service observes, or entered by an admin.
+//!
+//! Auto-detected incidents stay open while the problem lasts and get resolved by the detector
+//! once things look normal again, at most one open incident per kind.
+//!
+//! Opening and resolving one is sent out as an `incident.opened` / `incident.resolved` webhook.
+
+use crate::server::schema::{Incident, IncidentKind};
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use crate::server::webhooks::send_webhook;
+use crate::{info, warn};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Consecutive failed sends before the SMTP relay is considered down
+const SMTP_FAILURE_THRESHOLD: usize = 3;
+
+/// Containers restarting within one monitor tick before it counts as a mass restart
+const DEFAULT_RESTART_THRESHOLD: usize = 5;
+
+static INCIDENT_STORE: OnceLock<DataStore<String, Incident>> = OnceLock::new();
+static SMTP_FAILURES: AtomicUsize = AtomicUsize::new(0);
+static RESTART_SNAPSHOT: Mutex<Option<HashMap<String, i64>>> = Mutex::new(None);
+
+pub fn get_incident_store() -> DataStore<String, Incident> {
+    INCIDENT_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("incidents.json");
+            DataStore::<String, Incident>::new(path)
+                .expect("CRASH!! Failed to initialize incident datastore")
+        })
+        .clone()
+}
+
+fn new_incident_id() -> String {
+    format!("inc_{}", hex::encode(rand::random::<[u8; 8]>()))
+}
+
+/// Records a new incident and returns it
+pub fn record_incident(
+    kind: IncidentKind,
+    title: &str,
+    description: &str,
+    source: &str,
+    started_at: Option<String>,
+    resolved_at: Option<String>,
+) -> Result<Incident> {
+    let incident = Incident {
+        id: new_incident_id(),
+        kind,
+        title: title.to_string(),
+        description: description.to_string(),
+        source: source.to_string(),
+        started_at: started_at.unwrap_or_else(|| chrono::Utc::now().to_rfc3339()),
+        resolved_at,
+    };
+
+    get_incident_store().insert_save(incident.id.clone(), incident.clone())?;
+    warn!(
+        "Incident recorded: [{:?}] {}",
+        incident.kind, incident.title
+    );
+    send_webhook("incident.opened", serde_json::to_value(&incident)?);
+
+    Ok(incident)
+}
+
+/// Marks an incident as resolved, returns None if there's no incident with that id
+pub fn resolve_incident(id: &str) -> Result<Option<Incident>> {
+    let store = get_incident_store();
+    let Some(mut incident) = store.get(&id.to_string())? else {
+        return Ok(None);
+    };
+
+    if incident.resolved_at.is_none() {
+        incident.resolved_at = Some(chrono::Utc::now().to_rfc3339());
+        store.insert_save(incident.id.clone(), incident.clone())?;
+        info!(
+            "Incident resolved: [{:?}] {}",
+            incident.kind, incident.title
+        );
+        send_webhook("incident.resolved", serde_json::to_value(&incident)?);
+    }
+
+    Ok(Some(incident))
+}
+
+/// Incidents newest first, capped at `limit`
+pub fn list_incidents(limit: usize) -> Result<Vec<Incident>> {
+    let mut incidents = get_incident_store().values()?;
+    // RFC 3339 timestamps in UTC sort lexicographically
+    incidents.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+    incidents.truncate(limit);
+    Ok(incidents)
+}
+
+fn open_auto_incident(kind: IncidentKind) -> Result<Option<Incident>> {
+    Ok(get_incident_store()
+        .values()?
+        .into_iter()
+        .find(|i| i.kind == kind && i.source == "auto" && i.resolved_at.is_none()))
+}
+
+/// Opens an auto-detected incident unless one of that kind is already open
+fn open_auto(kind: IncidentKind, title: &str, description: &str) -> Result<()> {
+    if open_auto_incident(kind)?.is_none() {
+        record_incident(kind, title, description, "auto", None, None)?;
+    }
+    Ok(())
+}
+
+/// Resolves the open auto-detected incident of that kind, if any
+fn resolve_auto(kind: IncidentKind) -> Result<()> {
+    if let Some(incident) = open_auto_incident(kind)? {
+        resolve_incident(&incident.id)?;
+    }
+    Ok(())
+}
+
+/// Feeds the SMTP detector with the result of an email send
+pub fn report_smtp_result(sent: bool) -> Result<()> {
+    if sent {
+        // Only touch the store when we're coming back from failures
+        if SMTP_FAILURES.swap(0, Ordering::SeqCst) > 0 {
+            resolve_auto(IncidentKind::SmtpOutage)?;
+        }
+        return Ok(());
+    }
+
+    let failures = SMTP_FAILURES.fetch_add(1, Ordering::SeqCst) + 1;
+    if failures >= SMTP_FAILURE_THRESHOLD {
+        open_auto(
+            IncidentKind::SmtpOutage,
+            "Email delivery is failing",
+            "Verification and confirmation codes may not arrive, requests depending on them will fail until email delivery recovers.",
+        )?;
+    }
+    Ok(())
+}
+
+/// Number of containers that restarted since the previous snapshot
+fn count_restarted(previous: &HashMap<String, i64>, current: &HashMap<String, i64>) -> usize {
+    current
+        .iter()
+        .filter(|(name, count)| previous.get(*name).is_some_and(|prev| *count > prev))
+        .count()
+}
+
+fn restart_threshold() -> usize {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_INCIDENT_RESTART_THRESHOLD")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_RESTART_THRESHOLD)
+}
+
+/// Feeds the restart detector with the current restart count of every container
+/// The first call only takes a snapshot
+pub fn report_restart_counts(current: HashMap<String, i64>) -> Result<()> {
+    let previous = RESTART_SNAPSHOT
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .replace(current.clone());
+
+    let Some(previous) = previous else {
+        return Ok(());
+    };
+
+    let restarted = count_restarted(&previous, &current);
+    if restarted >= restart_threshold() {
+        open_auto(
+            IncidentKind::ContainerRestarts,
+            "Many instances restarted",
+            &format!(
+                "{} instances restarted at about the same time, requests may have failed while they came back up.",
+                restarted
+            ),
+        )?;
+    } else if restarted == 0 {
+        resolve_auto(IncidentKind::ContainerRestarts)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_count_restarted() {
+    let previous = HashMap::from([
+        ("blazedb-a".to_string(), 0),
+        ("blazedb-b".to_string(), 2),
+        ("blazedb-c".to_string(), 1),
+    ]);
+    let current = HashMap::from([
+        ("blazedb-a".to_string(), 1),
+        ("blazedb-b".to_string(), 2),
+        ("blazedb-c".to_string(), 3),
+        ("blazedb-new".to_string(), 4), // New containers aren't counted
+    ]);
+
+    assert_eq!(count_restarted(&previous, &current), 2);
+    assert_eq!(count_restarted(&current, &current), 0);
+}
@@ -0,0 +1,144 @@
+//! # Proxy cache invalidation
+//!
+//! The proxy keeps verified users in an LRU and reloads its copy of `users.json` when the file's
+//! generation (`users.json.generation`, bumped on every save) moves, which it checks every second.
+//! A revoked key or a downgraded plan shouldn't wait on that, so the service tells the proxy right
+//! after it saved a change to a user (plan change, key revocation, deletion...), and the proxy
+//! reloads the users and drops what it cached for that user.
+//!
+//! - The proxy listens for this on `BLAZE_PROXY_CONTROL_ADDR` (`127.0.0.1:8001` by default, `off`
+//!   to disable), `POST /internal/invalidate` with an optional `email` (everyone without one).
+//! - The service calls `BLAZE_PROXY_CONTROL_URL`, by default the listener's address on loopback.
+//!   When the proxy runs on another host (e.g. its own container), point it there.
+//! - With `BLAZE_ADMIN_TOKEN` set, calls carry it in `X-Admin-Token`. Without it, only loopback
+//!   callers are accepted.
+//!
+//! Calls are fire-and-forget. If one doesn't get through, the generation check still catches up.
+
+use crate::server::crypto::secrets_match;
+use crate::server::schema::InvalidationRequest;
+use crate::server::secrets::get_secrets;
+use crate::server::tasks::get_task_registry;
+use crate::warn;
+use axum::http::HeaderMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+pub const INVALIDATE_PATH: &str = "/internal/invalidate";
+
+const DEFAULT_CONTROL_ADDR: &str = "127.0.0.1:8001";
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn parse_control_addr(value: &str) -> Option<SocketAddr> {
+    let value = value.trim();
+    if value.is_empty() || value.eq_ignore_ascii_case("off") {
+        return None;
+    }
+    match value.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            warn!("Ignoring invalid BLAZE_PROXY_CONTROL_ADDR: {}", value);
+            None
+        }
+    }
+}
+
+/// Where the proxy's control listener binds, None when it's off
+pub fn control_addr() -> Option<SocketAddr> {
+    dotenv::dotenv().ok();
+    let value = std::env::var("BLAZE_PROXY_CONTROL_ADDR")
+        .unwrap_or_else(|_| DEFAULT_CONTROL_ADDR.to_string());
+    parse_control_addr(&value)
+}
+
+/// Invalidation URL for a listener on `addr`, reached over loopback if it binds every interface
+fn invalidate_url(addr: SocketAddr) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
+    format!(
+        "http://{}{}",
+        SocketAddr::new(ip, addr.port()),
+        INVALIDATE_PATH
+    )
+}
+
+/// Where the service sends invalidations, None when the listener is off
+fn notify_url() -> Option<String> {
+    dotenv::dotenv().ok();
+    match std::env::var("BLAZE_PROXY_CONTROL_URL") {
+        Ok(url) if !url.trim().is_empty() => Some(format!(
+            "{}{}",
+            url.trim().trim_end_matches('/'),
+            INVALIDATE_PATH
+        )),
+        _ => control_addr().map(invalidate_url),
+    }
+}
+
+fn admin_token() -> Option<&'static str> {
+    get_secrets().get("BLAZE_ADMIN_TOKEN")
+}
+
+/// Whether a call to the control listener may go through
+pub fn is_authorized(headers: &HeaderMap, peer: IpAddr) -> bool {
+    let Some(token) = admin_token() else {
+        return peer.is_loopback();
+    };
+    headers
+        .get("X-Admin-Token")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|provided| secrets_match(provided, token))
+}
+
+/// Tells the proxy a user changed, everyone when `email` is None
+/// Runs in the background, the caller doesn't wait on the proxy
+pub fn invalidate_proxy_cache(email: Option<&str>) {
+    let Some(url) = notify_url() else {
+        return;
+    };
+    let request = InvalidationRequest {
+        email: email.map(str::to_string),
+    };
+
+    get_task_registry().spawn("proxy-invalidation", |_| async move {
+        let mut call = reqwest::Client::new()
+            .post(&url)
+            .timeout(NOTIFY_TIMEOUT)
+            .json(&request);
+        if let Some(token) = admin_token() {
+            call = call.header("X-Admin-Token", token);
+        }
+        if let Err(e) = call.send().await.and_then(|r| r.error_for_status()) {
+            warn!(
+                "Failed to invalidate the proxy's user cache, it catches up on its next reload: {}",
+                e
+            );
+        }
+    });
+}
+
+#[test]
+fn test_control_addr_and_url() {
+    assert_eq!(parse_control_addr("off"), None);
+    assert_eq!(parse_control_addr(" "), None);
+    assert_eq!(parse_control_addr("not an address"), None);
+
+    let addr = parse_control_addr("127.0.0.1:8001").unwrap();
+    assert_eq!(
+        invalidate_url(addr),
+        "http://127.0.0.1:8001/internal/invalidate"
+    );
+    let any = parse_control_addr("0.0.0.0:9001").unwrap();
+    assert_eq!(
+        invalidate_url(any),
+        "http://127.0.0.1:9001/internal/invalidate"
+    );
+    let any_v6 = parse_control_addr("[::]:9001").unwrap();
+    assert_eq!(
+        invalidate_url(any_v6),
+        "http://[::1]:9001/internal/invalidate"
+    );
+}
@@ -0,0 +1,341 @@
+//! # Invoices
+//!
+//! Once a month every paying user gets an invoice summary for the previous calendar month: their
+//! plan, what was paid and refunded (from the billing history), referral credit spent and the
+//! metered usage, with the tax that was charged, the buyer's VAT ID and a reverse charge note for
+//! EU businesses. It's emailed and a copy is kept as HTML under `get_billing_path()/invoices/`, so
+//! receipts can be sent again or printed for expense reports. The summaries themselves live in
+//! `invoices.json`, keyed by user email.
+//!
+//! Paying means on a paid plan outside a trial, or with a payment or refund during the month. A
+//! user gets at most one invoice per month, generating again is harmless.
+
+use crate::server::billing::get_billing_history_store;
+use crate::server::mailer::send_mail;
+use crate::server::metering::get_user_usage;
+use crate::server::referrals::get_credit_store;
+use crate::server::schema::{BillingEventKind, Invoice, InvoiceLine, User};
+use crate::server::service::{get_all_users, get_billing_path};
+use crate::server::storage::DataStore;
+use crate::server::tasks::get_task_registry;
+use crate::{error, info};
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+static INVOICE_STORE: OnceLock<DataStore<String, Vec<Invoice>>> = OnceLock::new();
+
+/// Invoices keyed by user email, oldest first
+pub fn get_invoice_store() -> DataStore<String, Vec<Invoice>> {
+    INVOICE_STORE
+        .get_or_init(|| {
+            let path = get_billing_path().join("invoices.json");
+            DataStore::<String, Vec<Invoice>>::new(path)
+                .expect("CRASH!! Failed to initialize invoice datastore")
+        })
+        .clone()
+}
+
+fn get_invoice_copies_path() -> PathBuf {
+    get_billing_path().join("invoices")
+}
+
+/// The user's invoices, newest first
+pub fn get_invoices(email: &str) -> Result<Vec<Invoice>> {
+    let mut invoices = get_invoice_store()
+        .get(&email.to_string())?
+        .unwrap_or_default();
+    invoices.reverse();
+    Ok(invoices)
+}
+
+/// Start and end of the calendar month before the one `now` falls in
+fn previous_month(now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
+    let this_month = NaiveDate::from_ymd_opt(now.year(), now.month(), 1)
+        .unwrap_or(now.date_naive())
+        .and_hms_opt(0, 0, 0)
+        .unwrap_or_default()
+        .and_utc();
+    let last_month = this_month
+        .checked_sub_months(Months::new(1))
+        .unwrap_or(this_month);
+    (last_month, this_month)
+}
+
+/// Stable per user and month, e.g. `BLZ-202609-1A2B3C4D`
+fn invoice_number(email: &str, start: DateTime<Utc>) -> String {
+    let digest = Sha256::digest(email.as_bytes());
+    format!(
+        "BLZ-{}-{}",
+        start.format("%Y%m"),
+        hex::encode_upper(&digest[..4])
+    )
+}
+
+fn in_period(timestamp: &str, start: DateTime<Utc>, end: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(timestamp)
+        .is_ok_and(|t| t.with_timezone(&Utc) >= start && t.with_timezone(&Utc) < end)
+}
+
+/// Builds the user's invoice for the month starting at `start`, None if they didn't pay anything
+/// and aren't on a paid plan
+fn build_invoice(
+    user: &User,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Result<Option<Invoice>> {
+    let history = get_billing_history_store()
+        .get(&user.email)?
+        .unwrap_or_default();
+
+    let mut currency = None;
+    let mut tax_cents = 0;
+    let mut reverse_charge = false;
+    let lines: Vec<InvoiceLine> = history
+        .iter()
+        .filter(|e| in_period(&e.created_at, start, end))
+        .filter_map(|e| {
+            let amount_cents = e.amount_cents?;
+            let amount_cents = match e.kind {
+                BillingEventKind::Payment => amount_cents,
+                BillingEventKind::Refund => -amount_cents,
+                _ => return None,
+            };
+            if currency.is_none() {
+                currency = e.currency.clone();
+            }
+            if let Some(tax) = e
+                .tax
+                .as_ref()
+                .filter(|_| e.kind == BillingEventKind::Payment)
+            {
+                tax_cents += tax.tax_cents;
+                reverse_charge |= tax.reverse_charge;
+            }
+            Some(InvoiceLine {
+                description: e.description.clone(),
+                amount_cents,
+                reference: e.reference.clone(),
+            })
+        })
+        .collect();
+
+    let paying = user.plans.price_per_month > 0 && user.trial_expires_at.is_none();
+    if lines.is_empty() && !paying {
+        return Ok(None);
+    }
+
+    let credit_applied_cents = get_credit_store()
+        .get(&user.email)?
+        .unwrap_or_default()
+        .iter()
+        .filter(|e| e.amount_cents < 0 && in_period(&e.created_at, start, end))
+        .map(|e| -e.amount_cents)
+        .sum();
+
+    let usage: Vec<_> = get_user_usage(&user.email)?
+        .into_iter()
+        .filter(|r| in_period(&r.hour, start, end))
+        .collect();
+
+    Ok(Some(Invoice {
+        number: invoice_number(&user.email, start),
+        email: user.email.clone(),
+        period: start.format("%Y-%m").to_string(),
+        plan: user.plans.name.clone(),
+        billing_interval: user.billing_interval,
+        total_cents: lines.iter().map(|l| l.amount_cents).sum(),
+        lines,
+        tax_cents,
+        tax: user.tax.clone(),
+        reverse_charge,
+        credit_applied_cents,
+        currency: currency.unwrap_or_else(|| "usd".to_string()),
+        requests: usage.iter().map(|r| r.requests).sum(),
+        vectors_written: usage.iter().map(|r| r.vectors_written).sum(),
+        request_bytes: usage.iter().map(|r| r.request_bytes).sum(),
+        created_at: now.to_rfc3339(),
+    }))
+}
+
+fn format_amount(cents: i64, currency: &str) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    format!(
+        "{}{}.{:02} {}",
+        sign,
+        cents.abs() / 100,
+        cents.abs() % 100,
+        currency.to_uppercase()
+    )
+}
+
+/// What the invoice says about tax, e.g. "VAT ID: DE123456789 (DE). Tax included: 3.61 USD"
+fn tax_summary(invoice: &Invoice) -> String {
+    let mut parts = Vec::new();
+    match (&invoice.tax.vat_id, &invoice.tax.country) {
+        (Some(vat_id), Some(country)) => parts.push(format!("VAT ID: {} ({})", vat_id, country)),
+        (None, Some(country)) => parts.push(format!("Billing country: {}", country)),
+        _ => {}
+    }
+    parts.push(format!(
+        "Tax included: {}",
+        format_amount(invoice.tax_cents, &invoice.currency)
+    ));
+    if invoice.reverse_charge {
+        parts.push("Reverse charge: VAT to be accounted for by the recipient".to_string());
+    }
+    parts.join(". ")
+}
+
+fn build_invoice_email(username: &str, invoice: &Invoice) -> (String, String) {
+    let line_text = if invoice.lines.is_empty() {
+        "No payments this month".to_string()
+    } else {
+        invoice
+            .lines
+            .iter()
+            .map(|l| {
+                format!(
+                    "- {}: {}",
+                    l.description,
+                    format_amount(l.amount_cents, &invoice.currency)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let plain_body = format!(
+        "Hi {},\n\nInvoice {} for {}\nPlan: {} ({})\n\n{}\n\nReferral credit applied: {}\nTotal: {}\n{}\n\nUsage: {} requests, {} vectors written, {} bytes sent",
+        username,
+        invoice.number,
+        invoice.period,
+        invoice.plan,
+        invoice.billing_interval.as_str(),
+        line_text,
+        format_amount(invoice.credit_applied_cents, &invoice.currency),
+        format_amount(invoice.total_cents, &invoice.currency),
+        tax_summary(invoice),
+        invoice.requests,
+        invoice.vectors_written,
+        invoice.request_bytes
+    );
+
+    let line_rows: String = invoice
+        .lines
+        .iter()
+        .map(|l| {
+            format!(
+                "<tr><td>{}</td><td style=\"text-align: right;\">{}</td></tr>",
+                l.description,
+                format_amount(l.amount_cents, &invoice.currency)
+            )
+        })
+        .collect();
+    let html_body = format!(
+        r#"
+        <html>
+        <body style="font-family: sans-serif;">
+            <h2>Invoice {number}</h2>
+            <p>Hi {username},</p>
+            <p>Your BlazeDB invoice for <strong>{period}</strong>, {plan} plan ({interval}), billed to {email}.</p>
+            <table style="border-collapse: collapse; min-width: 360px;">
+                {line_rows}
+                <tr><td>Referral credit applied</td><td style="text-align: right;">{credit}</td></tr>
+                <tr><td><strong>Total</strong></td><td style="text-align: right;"><strong>{total}</strong></td></tr>
+            </table>
+            <p>{tax}</p>
+            <p>Usage: {requests} requests, {vectors} vectors written, {bytes} bytes sent.</p>
+        </body>
+        </html>
+        "#,
+        number = invoice.number,
+        period = invoice.period,
+        plan = invoice.plan,
+        interval = invoice.billing_interval.as_str(),
+        email = invoice.email,
+        credit = format_amount(invoice.credit_applied_cents, &invoice.currency),
+        total = format_amount(invoice.total_cents, &invoice.currency),
+        tax = tax_summary(invoice),
+        requests = invoice.requests,
+        vectors = invoice.vectors_written,
+        bytes = invoice.request_bytes,
+    );
+    (plain_body, html_body)
+}
+
+/// Creates, stores and emails last month's invoice of every paying user that has none yet
+/// This is called periodically via a background task
+pub async fn generate_monthly_invoices() -> Result<usize> {
+    let now = Utc::now();
+    let (start, end) = previous_month(now);
+    let store = get_invoice_store();
+    let copies_path = get_invoice_copies_path();
+    tokio::fs::create_dir_all(&copies_path).await?;
+
+    let mut generated = 0;
+    for user in get_all_users().await?.into_iter().filter(|u| u.is_verified) {
+        let mut invoices = store.get(&user.email)?.unwrap_or_default();
+        let number = invoice_number(&user.email, start);
+        if invoices.iter().any(|i| i.number == number) {
+            continue;
+        }
+        let Some(invoice) = build_invoice(&user, start, end, now)? else {
+            continue;
+        };
+
+        let (plain_body, html_body) = build_invoice_email(&user.username, &invoice);
+        tokio::fs::write(
+            copies_path.join(format!("{}.html", invoice.number)),
+            &html_body,
+        )
+        .await?;
+        invoices.push(invoice.clone());
+        store.insert_save(user.email.clone(), invoices)?;
+        generated += 1;
+
+        let email = user.email.clone();
+        let subject = format!("Your BlazeDB invoice for {}", invoice.period);
+        get_task_registry().spawn("invoice-mail", |_| async move {
+            let sent = tokio::task::spawn_blocking(move || {
+                send_mail(&email, &subject, plain_body, html_body).map_err(|e| (email, e))
+            })
+            .await;
+            if let Ok(Err((email, e))) = sent {
+                error!("Failed to send invoice to {}: {}", email, e);
+            }
+        });
+    }
+
+    if generated > 0 {
+        info!(
+            "Generated {} invoice(s) for {}",
+            generated,
+            start.format("%Y-%m")
+        );
+    }
+    Ok(generated)
+}
+
+#[test]
+fn test_invoice_period_and_number() {
+    let now = DateTime::parse_from_rfc3339("2026-01-15T10:00:00Z")
+        .unwrap()
+        .with_timezone(&Utc);
+    let (start, end) = previous_month(now);
+    assert_eq!(start.to_rfc3339(), "2025-12-01T00:00:00+00:00");
+    assert_eq!(end.to_rfc3339(), "2026-01-01T00:00:00+00:00");
+
+    assert!(in_period("2025-12-31T23:59:59Z", start, end));
+    assert!(!in_period("2026-01-01T00:00:00Z", start, end));
+
+    let number = invoice_number("alice@example.com", start);
+    assert!(number.starts_with("BLZ-202512-"));
+    assert_eq!(number, invoice_number("alice@example.com", start));
+    assert_ne!(number, invoice_number("bob@example.com", start));
+
+    assert_eq!(format_amount(1900, "usd"), "19.00 USD");
+    assert_eq!(format_amount(-505, "usd"), "-5.05 USD");
+}
@@ -0,0 +1,113 @@
+//! # Opaque API key index
+//!
+//! Email keys (`blz1_{base64_email}_{secret}`) name their owner, which puts the email in every
+//! log line and header they pass through. Opaque keys (`blz2_{secret}`, issued with
+//! `BLAZE_API_KEY_FORMAT=opaque`) don't, their owner is looked up here instead, under the key's
+//! `api_key_lookup_id`, so finding the user stays a single lookup.
+//!
+//! - The service writes the index (`api_key_index.json`) as it issues keys and drops a user's
+//!   entries with their account. The proxy reads its own copy and reloads it when the
+//!   generation moves, like the users.
+//! - Both formats are accepted whatever the deployment issues, so switching formats doesn't
+//!   break keys already handed out. Users move over as they get new keys.
+
+use crate::server::crypto::{
+    api_key_format_version, api_key_lookup_id, extract_email_from_api_key,
+};
+use crate::server::error::Result;
+use crate::server::schema::ApiKeyOwner;
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use std::sync::OnceLock;
+
+pub const KEY_INDEX_FILE: &str = "api_key_index.json";
+
+static KEY_INDEX_STORE: OnceLock<DataStore<String, ApiKeyOwner>> = OnceLock::new();
+
+pub fn get_key_index_store() -> DataStore<String, ApiKeyOwner> {
+    KEY_INDEX_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join(KEY_INDEX_FILE);
+            // The proxy reloads its copy when the generation moves
+            DataStore::<String, ApiKeyOwner>::new(path)
+                .expect("CRASH!! Failed to initialize API key index datastore")
+                .with_generation()
+        })
+        .clone()
+}
+
+/// Email of the key's owner, read from the key or looked up in `index` for opaque ones
+/// None when the key isn't one of ours or isn't indexed, the key itself is verified separately
+pub fn find_api_key_owner(
+    index: &DataStore<String, ApiKeyOwner>,
+    api_key: &str,
+) -> Result<Option<String>> {
+    match api_key_format_version(api_key) {
+        Some(2) => Ok(index
+            .get(&api_key_lookup_id(api_key))?
+            .map(|owner| owner.email)),
+        Some(_) => Ok(extract_email_from_api_key(api_key)),
+        None => Ok(None),
+    }
+}
+
+/// Records the owner of a newly issued key, keys carrying their email need no entry
+pub async fn index_api_key(api_key: &str, email: &str) -> Result<()> {
+    if api_key_format_version(api_key) != Some(2) {
+        return Ok(());
+    }
+    let owner = ApiKeyOwner {
+        email: email.to_string(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+    };
+    get_key_index_store()
+        .insert_save_async(api_key_lookup_id(api_key), owner)
+        .await?;
+    Ok(())
+}
+
+/// Drops every key indexed for the email, returns how many there were
+pub async fn remove_key_owner(email: &str) -> Result<usize> {
+    let index = get_key_index_store();
+    let mut removed = 0;
+    for (lookup_id, owner) in index.entries()? {
+        if owner.email == email {
+            index.delete_async(&lookup_id).await?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[test]
+fn test_find_api_key_owner() -> Result<()> {
+    use crate::server::crypto::{generate_api_key, generate_opaque_api_key};
+
+    let path = std::env::temp_dir().join("test_api_key_index.json");
+    let _ = std::fs::remove_file(&path);
+    let index: DataStore<String, ApiKeyOwner> = DataStore::new(path.clone())?;
+
+    let email_key = generate_api_key("alice", "alice@example.com");
+    assert_eq!(
+        find_api_key_owner(&index, &email_key)?.as_deref(),
+        Some("alice@example.com")
+    );
+
+    let opaque = generate_opaque_api_key();
+    assert_eq!(find_api_key_owner(&index, &opaque)?, None);
+    index.insert_save(
+        api_key_lookup_id(&opaque),
+        ApiKeyOwner {
+            email: "bob@example.com".to_string(),
+            created_at: chrono::Utc::now().to_rfc3339(),
+        },
+    )?;
+    assert_eq!(
+        find_api_key_owner(&index, &opaque)?.as_deref(),
+        Some("bob@example.com")
+    );
+    assert_eq!(find_api_key_owner(&index, "not_a_key")?, None);
+
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
@@ -0,0 +1,401 @@
+//! # Mailer
+//!
+//! Outgoing email over SMTP (Gmail relay with an app password, from `APP_PASSWORD` in env).
+//! The transport is blocking, call it from `spawn_blocking` when you can't afford to wait.
+//!
+//! ## Send rate limits
+//! Relays cap how much you can send (Gmail: about 500 messages a day) and start rejecting
+//! mid-campaign once you're over, so sends are counted against `BLAZE_MAIL_RATE_LIMITS`, a comma
+//! separated list of `scope:count/window` where scope is `*` (everything) or a recipient domain
+//! and window is `minute`, `hour` or `day`, e.g. `*:500/day,*:20/minute,outlook.com:100/hour`.
+//! Defaults to `*:500/day`.
+//!
+//! `send_mail` defers a message to the mail queue (`get_data_path()/mail_queue.json`) when a cap
+//! is reached, `flush_mail_queue` sends it once there's quota again. Time-sensitive mail (codes)
+//! goes through `send_mail_now`, which fails instead, a code that arrives an hour late is useless.
+//! Send times are kept in `get_data_path()/mail_quota.json`, so restarts and the proxy (which
+//! sends alerts) count against the same quota.
+
+use crate::server::secrets::get_secrets;
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use crate::{error, info, warn};
+use anyhow::Result;
+use chrono::Utc;
+use lettre::message::{MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+const MAIL_FROM: &str = "noreply.blz.service@gmail.com";
+const SMTP_RELAY: &str = "smtp.gmail.com";
+
+const DEFAULT_RATE_LIMITS: &str = "*:500/day";
+
+/// Queued messages are dropped after this many failed deliveries
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+
+static RATE_LIMITS: OnceLock<Vec<RateLimit>> = OnceLock::new();
+static QUOTA_LEDGER: OnceLock<DataStore<String, Vec<i64>>> = OnceLock::new();
+static MAIL_QUEUE: OnceLock<DataStore<String, QueuedMail>> = OnceLock::new();
+/// Serializes quota checks within the process, so two sends can't take the last slot
+static QUOTA_LOCK: Mutex<()> = Mutex::new(());
+
+static MAILS_SENT: AtomicU64 = AtomicU64::new(0);
+static MAILS_DEFERRED: AtomicU64 = AtomicU64::new(0);
+static MAILS_FAILED: AtomicU64 = AtomicU64::new(0);
+static MAILS_REFUSED: AtomicU64 = AtomicU64::new(0);
+
+/// One send cap, `scope` is `*` or a recipient domain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    pub scope: String,
+    pub max: u32,
+    pub window_seconds: i64,
+}
+
+/// A message waiting for quota
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMail {
+    pub id: String,
+    pub to: String,
+    pub subject: String,
+    pub plain_body: String,
+    pub html_body: String,
+    pub queued_at: String,
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+/// Usage of one cap right now, for the admin view
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailQuotaStatus {
+    pub scope: String,
+    pub max: u32,
+    pub window_seconds: i64,
+    pub used: u32,
+    pub remaining: u32,
+}
+
+/// Counters since the process started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailMetrics {
+    pub sent: u64,
+    pub deferred: u64,
+    pub failed: u64,
+    pub refused: u64, // `send_mail_now` calls turned down for quota
+}
+
+/// Returned by `send_mail_now` when a cap is reached, see `is_quota_exceeded`
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    pub scope: String,
+}
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Email send quota reached for {}", self.scope)
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+pub fn is_quota_exceeded(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<QuotaExceeded>().is_some()
+}
+
+fn parse_window(window: &str) -> Option<i64> {
+    match window.trim().to_lowercase().as_str() {
+        "minute" | "min" | "m" => Some(60),
+        "hour" | "h" => Some(3600),
+        "day" | "d" => Some(86400),
+        _ => None,
+    }
+}
+
+/// Parses `scope:count/window` entries, invalid entries are logged and skipped
+fn parse_rate_limits(value: &str) -> Vec<RateLimit> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = entry.trim().split_once(':').and_then(|(scope, rate)| {
+                let (max, window) = rate.split_once('/')?;
+                Some(RateLimit {
+                    scope: scope.trim().to_lowercase(),
+                    max: max.trim().parse().ok()?,
+                    window_seconds: parse_window(window)?,
+                })
+            });
+            if parsed.is_none() {
+                warn!("Ignoring invalid mail rate limit: {}", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+pub fn get_rate_limits() -> &'static [RateLimit] {
+    RATE_LIMITS.get_or_init(|| {
+        dotenv::dotenv().ok();
+        let value = std::env::var("BLAZE_MAIL_RATE_LIMITS")
+            .unwrap_or_else(|_| DEFAULT_RATE_LIMITS.to_string());
+        parse_rate_limits(&value)
+    })
+}
+
+/// Send times (unix seconds) per scope
+fn get_quota_ledger() -> DataStore<String, Vec<i64>> {
+    QUOTA_LEDGER
+        .get_or_init(|| {
+            let path = get_data_path().join("mail_quota.json");
+            DataStore::<String, Vec<i64>>::new(path)
+                .expect("CRASH!! Failed to initialize mail quota datastore")
+        })
+        .clone()
+}
+
+pub fn get_mail_queue() -> DataStore<String, QueuedMail> {
+    MAIL_QUEUE
+        .get_or_init(|| {
+            let path = get_data_path().join("mail_queue.json");
+            DataStore::<String, QueuedMail>::new(path)
+                .expect("CRASH!! Failed to initialize mail queue datastore")
+        })
+        .clone()
+}
+
+fn recipient_domain(to: &str) -> String {
+    to.rsplit_once('@')
+        .map(|(_, domain)| domain.trim().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn used_in_window(ledger: &BTreeMap<String, Vec<i64>>, limit: &RateLimit, now: i64) -> u32 {
+    ledger.get(&limit.scope).map_or(0, |sent| {
+        sent.iter()
+            .filter(|t| **t > now - limit.window_seconds)
+            .count() as u32
+    })
+}
+
+/// First cap that a message to `domain` would go over
+fn exceeded_limit<'a>(
+    limits: &'a [RateLimit],
+    ledger: &BTreeMap<String, Vec<i64>>,
+    domain: &str,
+    now: i64,
+) -> Option<&'a RateLimit> {
+    limits
+        .iter()
+        .filter(|limit| limit.scope == "*" || limit.scope == domain)
+        .find(|limit| used_in_window(ledger, limit, now) >= limit.max)
+}
+
+/// Takes a send slot for a message to `to`, or returns the scope whose cap is reached
+fn try_acquire_slot(to: &str) -> Result<std::result::Result<(), String>> {
+    let limits = get_rate_limits();
+    if limits.is_empty() {
+        return Ok(Ok(()));
+    }
+
+    let _guard = QUOTA_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let ledger = get_quota_ledger();
+    // The other process may have sent since we last looked
+    ledger.reload()?;
+
+    let now = Utc::now().timestamp();
+    let domain = recipient_domain(to);
+    let mut snapshot = ledger.snapshot()?;
+    if let Some(limit) = exceeded_limit(limits, &snapshot, &domain, now) {
+        return Ok(Err(limit.scope.clone()));
+    }
+
+    let longest_window = limits.iter().map(|l| l.window_seconds).max().unwrap_or(0);
+    for scope in ["*".to_string(), domain] {
+        if !limits.iter().any(|l| l.scope == scope) {
+            continue;
+        }
+        let mut sent = snapshot.remove(&scope).unwrap_or_default();
+        sent.retain(|t| *t > now - longest_window);
+        sent.push(now);
+        ledger.insert_mem(scope, sent)?;
+    }
+    ledger.save_to_disk()?;
+    Ok(Ok(()))
+}
+
+/// Sends over SMTP right away, no quota involved
+fn deliver(to: &str, subject: &str, plain_body: String, html_body: String) -> Result<()> {
+    let app_password = get_secrets().require("APP_PASSWORD")?.to_string();
+
+    let email_message = Message::builder()
+        .from(MAIL_FROM.parse()?)
+        .to(to.parse()?)
+        .subject(subject)
+        .multipart(
+            MultiPart::alternative()
+                .singlepart(SinglePart::plain(plain_body))
+                .singlepart(SinglePart::html(html_body)),
+        )?;
+
+    let creds = Credentials::new(MAIL_FROM.to_string(), app_password);
+
+    let mailer = SmtpTransport::relay(SMTP_RELAY)?.credentials(creds).build();
+
+    let sent = mailer.send(&email_message);
+    match &sent {
+        Ok(_) => MAILS_SENT.fetch_add(1, Ordering::Relaxed),
+        Err(_) => MAILS_FAILED.fetch_add(1, Ordering::Relaxed),
+    };
+    sent?;
+
+    Ok(())
+}
+
+/// Sends a multipart (plain text + html) email
+/// When a send cap is reached the message is queued and sent later, this still returns Ok
+/// Returns an error if the message can't be built or the relay rejects it
+pub fn send_mail(to: &str, subject: &str, plain_body: String, html_body: String) -> Result<()> {
+    match try_acquire_slot(to)? {
+        Ok(()) => deliver(to, subject, plain_body, html_body),
+        Err(scope) => {
+            let mail = QueuedMail {
+                id: format!("mail_{}", hex::encode(rand::random::<[u8; 8]>())),
+                to: to.to_string(),
+                subject: subject.to_string(),
+                plain_body,
+                html_body,
+                queued_at: Utc::now().to_rfc3339(),
+                attempts: 0,
+            };
+            let queue = get_mail_queue();
+            queue.reload()?;
+            queue.insert_save(mail.id.clone(), mail)?;
+            MAILS_DEFERRED.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Email send quota reached for {}, queued '{}' to {}",
+                scope, subject, to
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Like `send_mail` but fails with `QuotaExceeded` instead of queueing, for mail that's useless late
+pub fn send_mail_now(to: &str, subject: &str, plain_body: String, html_body: String) -> Result<()> {
+    match try_acquire_slot(to)? {
+        Ok(()) => deliver(to, subject, plain_body, html_body),
+        Err(scope) => {
+            MAILS_REFUSED.fetch_add(1, Ordering::Relaxed);
+            Err(QuotaExceeded { scope }.into())
+        }
+    }
+}
+
+/// Sends queued mail oldest first while there's quota, returns how many went out
+/// Blocking, this is called periodically via a background task (in `spawn_blocking`)
+pub fn flush_mail_queue() -> Result<usize> {
+    let queue = get_mail_queue();
+    queue.reload()?;
+
+    let mut pending = queue.values()?;
+    if pending.is_empty() {
+        return Ok(0);
+    }
+    // RFC 3339 timestamps in UTC sort lexicographically
+    pending.sort_by(|a, b| a.queued_at.cmp(&b.queued_at));
+
+    let mut sent = 0;
+    for mut mail in pending {
+        if try_acquire_slot(&mail.to)?.is_err() {
+            continue; // Other domains may still have quota
+        }
+
+        match deliver(
+            &mail.to,
+            &mail.subject,
+            mail.plain_body.clone(),
+            mail.html_body.clone(),
+        ) {
+            Ok(()) => {
+                queue.delete(&mail.id)?;
+                sent += 1;
+            }
+            Err(e) => {
+                mail.attempts += 1;
+                if mail.attempts >= MAX_DELIVERY_ATTEMPTS {
+                    error!(
+                        "Dropping queued email '{}' to {} after {} attempts: {}",
+                        mail.subject, mail.to, mail.attempts, e
+                    );
+                    queue.delete(&mail.id)?;
+                } else {
+                    queue.insert_save(mail.id.clone(), mail)?;
+                }
+            }
+        }
+    }
+
+    if sent > 0 {
+        info!("Sent {} queued email(s)", sent);
+    }
+    Ok(sent)
+}
+
+/// Usage of every cap right now
+pub fn get_mail_quota() -> Result<Vec<MailQuotaStatus>> {
+    let ledger = get_quota_ledger();
+    ledger.reload()?;
+    let snapshot = ledger.snapshot()?;
+    let now = Utc::now().timestamp();
+
+    Ok(get_rate_limits()
+        .iter()
+        .map(|limit| {
+            let used = used_in_window(&snapshot, limit, now);
+            MailQuotaStatus {
+                scope: limit.scope.clone(),
+                max: limit.max,
+                window_seconds: limit.window_seconds,
+                used,
+                remaining: limit.max.saturating_sub(used),
+            }
+        })
+        .collect())
+}
+
+pub fn get_mail_metrics() -> MailMetrics {
+    MailMetrics {
+        sent: MAILS_SENT.load(Ordering::Relaxed),
+        deferred: MAILS_DEFERRED.load(Ordering::Relaxed),
+        failed: MAILS_FAILED.load(Ordering::Relaxed),
+        refused: MAILS_REFUSED.load(Ordering::Relaxed),
+    }
+}
+
+#[test]
+fn test_rate_limits() {
+    let limits = parse_rate_limits("*:3/day, gmail.com:1/minute, bogus, x:1/fortnight");
+    assert_eq!(limits.len(), 2);
+    assert_eq!(limits[1].window_seconds, 60);
+
+    let now = 1_000_000;
+    let mut ledger = BTreeMap::from([("gmail.com".to_string(), vec![now - 10])]);
+    ledger.insert("*".to_string(), vec![now - 10]);
+
+    // gmail.com is capped for the minute, other domains aren't
+    assert_eq!(
+        exceeded_limit(&limits, &ledger, "gmail.com", now).map(|l| l.scope.as_str()),
+        Some("gmail.com")
+    );
+    assert!(exceeded_limit(&limits, &ledger, "example.com", now).is_none());
+    assert!(exceeded_limit(&limits, &ledger, "gmail.com", now + 60).is_none());
+
+    // The global cap applies to everyone
+    ledger.insert("*".to_string(), vec![now - 30, now - 20, now - 10]);
+    assert!(exceeded_limit(&limits, &ledger, "example.com", now).is_some());
+}
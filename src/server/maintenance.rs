@@ -0,0 +1,137 @@
+//! # Maintenance mode
+//!
+//! During image rollouts and migrations the proxy can stop taking requests, for every instance
+//! or just one, without a restart. Admins open and lift windows through
+//! `/v1/blz/admin/maintenance`. The service writes them to `get_data_path()/maintenance.json`
+//! and the proxy reloads the file every few seconds.
+//!
+//! While a window is open, new requests get a 503 with the window's message and a `Retry-After`
+//! going by its `until` (a minute without one). Requests already in flight, WebSockets and
+//! event streams included, run to the end, so an instance drains before it's worked on.
+
+use crate::server::schema::MaintenanceWindow;
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::sync::OnceLock;
+
+/// Key of the window that covers every instance
+pub const GLOBAL_KEY: &str = "*";
+
+pub const DEFAULT_MESSAGE: &str = "BlazeDB is down for scheduled maintenance";
+
+const DEFAULT_RETRY_AFTER_SECONDS: u64 = 60;
+
+static MAINTENANCE_STORE: OnceLock<DataStore<String, MaintenanceWindow>> = OnceLock::new();
+
+/// Open maintenance windows keyed by instance id, `GLOBAL_KEY` for all of them
+pub fn get_maintenance_store() -> DataStore<String, MaintenanceWindow> {
+    MAINTENANCE_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("maintenance.json");
+            DataStore::<String, MaintenanceWindow>::new(path)
+                .expect("CRASH!! Failed to initialize maintenance datastore")
+        })
+        .clone()
+}
+
+fn store_key(instance_id: Option<&str>) -> String {
+    instance_id.unwrap_or(GLOBAL_KEY).to_string()
+}
+
+/// Opens a window (or replaces the open one) for the instance, every instance without one
+/// `until` is expected to be RFC 3339 already
+pub fn start_maintenance(
+    instance_id: Option<&str>,
+    message: Option<&str>,
+    until: Option<&str>,
+) -> Result<MaintenanceWindow> {
+    let window = MaintenanceWindow {
+        instance_id: instance_id.map(str::to_string),
+        message: message
+            .map(str::trim)
+            .filter(|m| !m.is_empty())
+            .unwrap_or(DEFAULT_MESSAGE)
+            .to_string(),
+        started_at: Utc::now().to_rfc3339(),
+        until: until.map(str::to_string),
+    };
+    get_maintenance_store().insert_save(store_key(instance_id), window.clone())?;
+    Ok(window)
+}
+
+/// Lifts the instance's window (the global one without an instance), false if none was open
+pub fn end_maintenance(instance_id: Option<&str>) -> Result<bool> {
+    Ok(get_maintenance_store()
+        .delete(&store_key(instance_id))?
+        .is_some())
+}
+
+/// Every open window, the global one first
+pub fn list_maintenance() -> Result<Vec<MaintenanceWindow>> {
+    let mut windows = get_maintenance_store().values()?;
+    windows.sort_by(|a, b| {
+        (a.instance_id.is_some(), &a.instance_id).cmp(&(b.instance_id.is_some(), &b.instance_id))
+    });
+    Ok(windows)
+}
+
+/// The window an instance is in, its own before the global one
+pub fn maintenance_for(
+    store: &DataStore<String, MaintenanceWindow>,
+    instance_id: &str,
+) -> Option<MaintenanceWindow> {
+    [instance_id, GLOBAL_KEY]
+        .iter()
+        .find_map(|key| store.get(&key.to_string()).ok().flatten())
+}
+
+/// Seconds until the window is expected to be over, a minute when there's no telling
+pub fn retry_after(window: &MaintenanceWindow, now: DateTime<Utc>) -> u64 {
+    window
+        .until
+        .as_deref()
+        .and_then(|until| DateTime::parse_from_rfc3339(until).ok())
+        .map(|until| (until.with_timezone(&Utc) - now).num_seconds())
+        .filter(|&seconds| seconds > 0)
+        .map_or(DEFAULT_RETRY_AFTER_SECONDS, |seconds| seconds as u64)
+}
+
+#[test]
+fn test_maintenance_for_and_retry_after() {
+    let path = std::env::temp_dir().join("test_maintenance_for.json");
+    let _ = std::fs::remove_file(&path);
+    let store = DataStore::<String, MaintenanceWindow>::new(path.clone()).unwrap();
+    let now = Utc::now();
+    let window = |instance_id: Option<&str>, until: Option<DateTime<Utc>>| MaintenanceWindow {
+        instance_id: instance_id.map(str::to_string),
+        message: DEFAULT_MESSAGE.to_string(),
+        started_at: now.to_rfc3339(),
+        until: until.map(|t| t.to_rfc3339()),
+    };
+
+    assert!(maintenance_for(&store, "inst").is_none());
+    store
+        .insert_mem(
+            "inst".to_string(),
+            window(Some("inst"), Some(now + chrono::Duration::seconds(300))),
+        )
+        .unwrap();
+    assert!(maintenance_for(&store, "other").is_none());
+    let own = maintenance_for(&store, "inst").unwrap();
+    assert_eq!(retry_after(&own, now), 300);
+
+    store
+        .insert_mem(GLOBAL_KEY.to_string(), window(None, None))
+        .unwrap();
+    assert_eq!(maintenance_for(&store, "inst").unwrap(), own);
+    let global = maintenance_for(&store, "other").unwrap();
+    assert_eq!(retry_after(&global, now), DEFAULT_RETRY_AFTER_SECONDS);
+
+    // Overdue, clients still get told to come back later
+    let overdue = window(None, Some(now - chrono::Duration::seconds(5)));
+    assert_eq!(retry_after(&overdue, now), DEFAULT_RETRY_AFTER_SECONDS);
+
+    let _ = std::fs::remove_file(&path);
+}
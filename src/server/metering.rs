@@ -0,0 +1,376 @@
+//! # Usage metering
+//!
+//! The proxy sees every request, so it meters usage per user and instance into hourly buckets.
+//! Buckets pile up in memory and get merged into the usage ledger under `get_billing_path()`
+//! periodically, the service only reads the ledger. This is what overage billing will run on.
+//!
+//! Vectors are counted from the JSON bodies of write requests: a top-level array counts each
+//! element, an object with a `vectors`/`documents`/`items` array counts that array, any other
+//! object counts as one. It's an estimate, BlazeDB itself is the source of truth.
+//!
+//! Bodies up to `BLAZE_PROXY_INSPECT_BYTES` (1 MiB by default) are read whole before forwarding,
+//! bigger ones stream through to the instance and are counted on the way by a `WriteScanner`.
+
+use crate::server::schema::UsageRecord;
+use crate::server::service::get_billing_path;
+use crate::server::storage::DataStore;
+use anyhow::Result;
+use chrono::{DateTime, Timelike, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Hourly buckets older than this are dropped from the ledger
+const USAGE_RETENTION_DAYS: i64 = 90;
+
+const DEFAULT_INSPECT_BYTES: usize = 1024 * 1024;
+
+/// Longest top-level string a `WriteScanner` keeps, database names are much shorter
+const MAX_SCANNED_STRING: usize = 256;
+
+/// (email, instance_id, hour)
+type UsageKey = (String, String, String);
+
+static USAGE_LEDGER: OnceLock<DataStore<String, Vec<UsageRecord>>> = OnceLock::new();
+
+/// Usage records keyed by user email, oldest first
+pub fn get_usage_ledger() -> DataStore<String, Vec<UsageRecord>> {
+    USAGE_LEDGER
+        .get_or_init(|| {
+            let path = get_billing_path().join("usage.json");
+            DataStore::<String, Vec<UsageRecord>>::new(path)
+                .expect("CRASH!! Failed to initialize usage ledger")
+        })
+        .clone()
+}
+
+/// Start of the hour `at` falls in, as RFC 3339
+pub(crate) fn hour_bucket(at: DateTime<Utc>) -> String {
+    at.with_minute(0)
+        .and_then(|t| t.with_second(0))
+        .and_then(|t| t.with_nanosecond(0))
+        .unwrap_or(at)
+        .to_rfc3339()
+}
+
+/// Estimates how many vectors a write request carries (see the module docs)
+pub fn count_vectors(body: &[u8]) -> u64 {
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(body) else {
+        return 0;
+    };
+
+    match value {
+        serde_json::Value::Array(items) => items.len() as u64,
+        serde_json::Value::Object(object) => ["vectors", "documents", "items"]
+            .iter()
+            .find_map(|key| object.get(*key).and_then(|v| v.as_array()))
+            .map_or(1, |items| items.len() as u64),
+        _ => 0,
+    }
+}
+
+/// Request bodies up to this size are read whole before forwarding, bigger ones are streamed
+pub fn inspect_limit() -> usize {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_PROXY_INSPECT_BYTES")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_INSPECT_BYTES)
+}
+
+/// Counts vectors like `count_vectors` on a body that arrives in chunks, without keeping it
+/// (an object with more than one of the arrays counts the first one)
+/// Also picks up the top-level `database`, so streamed writes count towards quotas
+#[derive(Debug, Default)]
+pub struct WriteScanner {
+    bytes: u64,
+    depth: usize,
+    top_level: Option<u8>, // b'[' or b'{'
+    in_string: bool,
+    escaped: bool,
+    string: Vec<u8>,
+    expecting_key: bool,
+    key: Option<String>,
+    database: Option<String>,
+    counting_at: Option<usize>, // Depth of the array whose elements are counted
+    element_pending: bool,
+    found_array: bool,
+    count: u64,
+}
+
+impl WriteScanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.bytes += chunk.len() as u64;
+        for &b in chunk {
+            self.feed_byte(b);
+        }
+    }
+
+    fn feed_byte(&mut self, b: u8) {
+        if self.in_string {
+            match b {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => {
+                    self.escaped = true;
+                    return;
+                }
+                b'"' => {
+                    self.in_string = false;
+                    self.end_string();
+                    return;
+                }
+                _ => {}
+            }
+            if self.depth == 1 && self.string.len() < MAX_SCANNED_STRING {
+                self.string.push(b);
+            }
+            return;
+        }
+        if b.is_ascii_whitespace() {
+            return;
+        }
+
+        if self.element_pending && self.counting_at == Some(self.depth) && b != b']' {
+            self.count += 1;
+            self.element_pending = false;
+        }
+
+        match b {
+            b'"' => {
+                self.in_string = true;
+                self.string.clear();
+            }
+            b'{' | b'[' => {
+                self.depth += 1;
+                if self.depth == 1 {
+                    self.top_level = Some(b);
+                    self.expecting_key = b == b'{';
+                    if b == b'[' {
+                        self.start_counting();
+                    }
+                } else if self.depth == 2
+                    && b == b'['
+                    && !self.found_array
+                    && self.top_level == Some(b'{')
+                    && matches!(self.key.as_deref(), Some("vectors" | "documents" | "items"))
+                {
+                    self.found_array = true;
+                    self.start_counting();
+                }
+            }
+            b'}' | b']' => {
+                if self.counting_at == Some(self.depth) {
+                    self.counting_at = None;
+                }
+                self.depth = self.depth.saturating_sub(1);
+            }
+            b',' => {
+                if self.counting_at == Some(self.depth) {
+                    self.element_pending = true;
+                }
+                if self.depth == 1 {
+                    self.expecting_key = true;
+                }
+            }
+            b':' if self.depth == 1 => self.expecting_key = false,
+            _ => {}
+        }
+    }
+
+    fn start_counting(&mut self) {
+        self.counting_at = Some(self.depth);
+        self.element_pending = true;
+    }
+
+    fn end_string(&mut self) {
+        if self.depth != 1 || self.top_level != Some(b'{') {
+            return;
+        }
+        let value = String::from_utf8_lossy(&self.string).into_owned();
+        if self.expecting_key {
+            self.key = Some(value);
+        } else if self.key.as_deref() == Some("database") {
+            self.database = Some(value);
+        }
+    }
+
+    /// Bytes fed so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Vectors in what was fed so far, see `count_vectors`
+    pub fn vectors(&self) -> u64 {
+        match self.top_level {
+            Some(b'[') => self.count,
+            Some(b'{') if self.found_array => self.count,
+            Some(b'{') => 1,
+            _ => 0,
+        }
+    }
+
+    /// The top-level `database` of an object body
+    pub fn database(&self) -> Option<&str> {
+        self.database.as_deref()
+    }
+}
+
+/// Unflushed usage, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone, Default)]
+pub struct UsageMeter {
+    pending: Arc<Mutex<HashMap<UsageKey, UsageRecord>>>,
+}
+
+impl UsageMeter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one proxied request
+    pub fn record(&self, email: &str, instance_id: &str, request_bytes: u64, vectors: u64) {
+        self.record_at(email, instance_id, request_bytes, vectors, Utc::now());
+    }
+
+    fn record_at(
+        &self,
+        email: &str,
+        instance_id: &str,
+        request_bytes: u64,
+        vectors: u64,
+        at: DateTime<Utc>,
+    ) {
+        let hour = hour_bucket(at);
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let record = pending
+            .entry((email.to_string(), instance_id.to_string(), hour.clone()))
+            .or_insert_with(|| UsageRecord {
+                hour,
+                instance_id: instance_id.to_string(),
+                requests: 0,
+                request_bytes: 0,
+                vectors_written: 0,
+            });
+        record.requests += 1;
+        record.request_bytes += request_bytes;
+        record.vectors_written += vectors;
+    }
+
+    /// Takes everything recorded since the last drain, as (email, record)
+    fn drain(&self) -> Vec<(String, UsageRecord)> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending
+            .drain()
+            .map(|((email, _, _), record)| (email, record))
+            .collect()
+    }
+
+    /// Merges the unflushed usage into the ledger and saves it
+    pub fn flush(&self, ledger: &DataStore<String, Vec<UsageRecord>>) -> Result<()> {
+        let drained = self.drain();
+        if drained.is_empty() {
+            return Ok(());
+        }
+
+        let cutoff = hour_bucket(Utc::now() - chrono::Duration::days(USAGE_RETENTION_DAYS));
+
+        for (email, record) in drained {
+            let mut records = ledger.get(&email)?.unwrap_or_default();
+            merge_record(&mut records, record);
+            records.retain(|r| r.hour >= cutoff);
+            ledger.insert_mem(email, records)?;
+        }
+
+        Ok(ledger.save_to_disk()?)
+    }
+}
+
+fn merge_record(records: &mut Vec<UsageRecord>, record: UsageRecord) {
+    match records
+        .iter_mut()
+        .find(|r| r.hour == record.hour && r.instance_id == record.instance_id)
+    {
+        Some(existing) => {
+            existing.requests += record.requests;
+            existing.request_bytes += record.request_bytes;
+            existing.vectors_written += record.vectors_written;
+        }
+        None => {
+            records.push(record);
+            records.sort_by(|a, b| a.hour.cmp(&b.hour));
+        }
+    }
+}
+
+/// The user's hourly usage, oldest first (reloads the ledger, the proxy is the one writing it)
+pub fn get_user_usage(email: &str) -> Result<Vec<UsageRecord>> {
+    let ledger = get_usage_ledger();
+    ledger.reload()?;
+    Ok(ledger.get(&email.to_string())?.unwrap_or_default())
+}
+
+#[test]
+fn test_usage_meter_buckets_by_hour() {
+    let meter = UsageMeter::new();
+    let at = |h: u32, m: u32| {
+        DateTime::parse_from_rfc3339(&format!("2026-01-01T{:02}:{:02}:00Z", h, m))
+            .unwrap()
+            .with_timezone(&Utc)
+    };
+
+    meter.record_at("a@x.com", "inst", 100, 2, at(10, 5));
+    meter.record_at("a@x.com", "inst", 50, 0, at(10, 59));
+    meter.record_at("a@x.com", "inst", 10, 1, at(11, 0));
+
+    let mut records = Vec::new();
+    for (_, record) in meter.drain() {
+        merge_record(&mut records, record);
+    }
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].hour, hour_bucket(at(10, 0)));
+    assert_eq!(records[0].requests, 2);
+    assert_eq!(records[0].request_bytes, 150);
+    assert_eq!(records[0].vectors_written, 2);
+    assert_eq!(records[1].requests, 1);
+    assert!(meter.drain().is_empty());
+}
+
+#[test]
+fn test_count_vectors() {
+    assert_eq!(count_vectors(br#"[{"v":[1]},{"v":[2]},{"v":[3]}]"#), 3);
+    assert_eq!(count_vectors(br#"{"vectors":[[1],[2]]}"#), 2);
+    assert_eq!(count_vectors(br#"{"text":"hello"}"#), 1);
+    assert_eq!(count_vectors(b"not json"), 0);
+}
+
+#[test]
+fn test_write_scanner_matches_count_vectors() {
+    let bodies: [&[u8]; 5] = [
+        br#"[{"v":[1]},{"v":[2]},{"v":[3]}]"#,
+        br#"{"database":"docs","vectors":[[1,2],[3,4]]}"#,
+        br#" { "text" : "a \"[quoted]\" , value" } "#,
+        br#"{"database":"d\"b","meta":{"items":[1,2,3]},"items":[]}"#,
+        b"[]",
+    ];
+
+    // Split at every byte, chunk boundaries mustn't change anything
+    for body in bodies {
+        for split in 0..=body.len() {
+            let mut scanner = WriteScanner::new();
+            scanner.feed(&body[..split]);
+            scanner.feed(&body[split..]);
+            assert_eq!(scanner.vectors(), count_vectors(body), "{:?}", body);
+            assert_eq!(scanner.bytes(), body.len() as u64);
+        }
+    }
+
+    let mut scanner = WriteScanner::new();
+    scanner.feed(bodies[1]);
+    assert_eq!(scanner.database(), Some("docs"));
+    scanner = WriteScanner::new();
+    scanner.feed(bodies[3]);
+    assert_eq!(scanner.database(), Some("d\"b"));
+}
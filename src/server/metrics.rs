@@ -0,0 +1,260 @@
+//! # Instance metrics
+//!
+//! What users get to see of their own traffic: requests, errors, bytes both ways and latency,
+//! per instance. The proxy measures each request once its response has been sent (where the
+//! access log entry is written) and merges hourly buckets into `get_data_path()/metrics.json`
+//! periodically, keeping a week of them. The service only reads the file, for
+//! `GET /v1/blz/instance/metrics`.
+//!
+//! Latencies are counted into fixed buckets so hours merge exactly. p50 and p95 are the upper
+//! bound of the bucket they fall in, anything slower than the last bound shows as that bound.
+
+use crate::server::metering::hour_bucket;
+use crate::server::schema::{InstanceMetrics, MetricsRecord};
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Upper bounds of the latency buckets
+pub const LATENCY_BUCKETS_MS: &[u64] =
+    &[5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+pub const DEFAULT_WINDOW_HOURS: u32 = 24;
+
+/// Hourly buckets older than this are dropped, and the longest window users can ask for
+pub const MAX_WINDOW_HOURS: u32 = 7 * 24;
+
+/// (email, instance_id, hour)
+type MetricsKey = (String, String, String);
+
+static METRICS_STORE: OnceLock<DataStore<String, Vec<MetricsRecord>>> = OnceLock::new();
+
+/// Hourly metrics keyed by user email, oldest first
+pub fn get_metrics_store() -> DataStore<String, Vec<MetricsRecord>> {
+    METRICS_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("metrics.json");
+            DataStore::<String, Vec<MetricsRecord>>::new(path)
+                .expect("CRASH!! Failed to initialize metrics datastore")
+        })
+        .clone()
+}
+
+fn latency_bucket(latency_ms: u64) -> usize {
+    LATENCY_BUCKETS_MS
+        .iter()
+        .position(|&bound| latency_ms <= bound)
+        .unwrap_or(LATENCY_BUCKETS_MS.len())
+}
+
+/// The `q` quantile (0.5 for p50) of a latency histogram, None if it's empty
+pub fn percentile(buckets: &[u64], q: f64) -> Option<u64> {
+    let total: u64 = buckets.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let rank = ((total as f64 * q).ceil() as u64).max(1);
+    let mut seen = 0;
+    let bucket = buckets.iter().position(|&count| {
+        seen += count;
+        seen >= rank
+    })?;
+    Some(LATENCY_BUCKETS_MS[bucket.min(LATENCY_BUCKETS_MS.len() - 1)])
+}
+
+fn merge_counts(stored: &mut MetricsRecord, record: &MetricsRecord) {
+    stored.requests += record.requests;
+    stored.client_errors += record.client_errors;
+    stored.server_errors += record.server_errors;
+    stored.bytes_in += record.bytes_in;
+    stored.bytes_out += record.bytes_out;
+    if stored.latency_buckets.len() < record.latency_buckets.len() {
+        stored
+            .latency_buckets
+            .resize(record.latency_buckets.len(), 0);
+    }
+    for (stored, count) in stored
+        .latency_buckets
+        .iter_mut()
+        .zip(&record.latency_buckets)
+    {
+        *stored += count;
+    }
+}
+
+/// Metrics not flushed yet, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone, Default)]
+pub struct MetricsCollector {
+    pending: Arc<Mutex<HashMap<MetricsKey, MetricsRecord>>>,
+}
+
+impl MetricsCollector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records one request whose response has been sent
+    pub fn record(
+        &self,
+        email: &str,
+        instance_id: &str,
+        status: u16,
+        latency_ms: u64,
+        bytes: (u64, u64), // (in, out)
+    ) {
+        self.record_at(email, instance_id, status, latency_ms, bytes, Utc::now());
+    }
+
+    fn record_at(
+        &self,
+        email: &str,
+        instance_id: &str,
+        status: u16,
+        latency_ms: u64,
+        (bytes_in, bytes_out): (u64, u64),
+        at: DateTime<Utc>,
+    ) {
+        let hour = hour_bucket(at);
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let record = pending
+            .entry((email.to_string(), instance_id.to_string(), hour.clone()))
+            .or_insert_with(|| MetricsRecord {
+                hour,
+                instance_id: instance_id.to_string(),
+                latency_buckets: vec![0; LATENCY_BUCKETS_MS.len() + 1],
+                ..Default::default()
+            });
+        record.requests += 1;
+        match status {
+            400..=499 => record.client_errors += 1,
+            500..=599 => record.server_errors += 1,
+            _ => {}
+        }
+        record.bytes_in += bytes_in;
+        record.bytes_out += bytes_out;
+        record.latency_buckets[latency_bucket(latency_ms)] += 1;
+    }
+
+    /// Takes everything recorded since the last drain, as (email, record)
+    fn drain(&self) -> Vec<(String, MetricsRecord)> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending
+            .drain()
+            .map(|((email, _, _), record)| (email, record))
+            .collect()
+    }
+
+    /// Merges what was recorded since the last flush into the store and saves it
+    pub fn flush(&self, store: &DataStore<String, Vec<MetricsRecord>>) -> Result<()> {
+        let drained = self.drain();
+        if drained.is_empty() {
+            return Ok(());
+        }
+
+        let cutoff = hour_bucket(Utc::now() - chrono::Duration::hours(MAX_WINDOW_HOURS as i64));
+
+        for (email, record) in drained {
+            let mut records = store.get(&email)?.unwrap_or_default();
+            merge_record(&mut records, record);
+            records.retain(|r| r.hour >= cutoff);
+            store.insert_mem(email, records)?;
+        }
+
+        Ok(store.save_to_disk()?)
+    }
+}
+
+fn merge_record(records: &mut Vec<MetricsRecord>, record: MetricsRecord) {
+    match records
+        .iter_mut()
+        .find(|r| r.hour == record.hour && r.instance_id == record.instance_id)
+    {
+        Some(existing) => merge_counts(existing, &record),
+        None => {
+            records.push(record);
+            records.sort_by(|a, b| a.hour.cmp(&b.hour));
+        }
+    }
+}
+
+/// Totals per instance over the given hourly records
+pub fn summarize(records: &[MetricsRecord]) -> Vec<InstanceMetrics> {
+    let mut per_instance: BTreeMap<&str, MetricsRecord> = BTreeMap::new();
+    for record in records {
+        merge_counts(per_instance.entry(&record.instance_id).or_default(), record);
+    }
+
+    per_instance
+        .into_iter()
+        .map(|(instance_id, total)| InstanceMetrics {
+            instance_id: instance_id.to_string(),
+            requests: total.requests,
+            client_errors: total.client_errors,
+            server_errors: total.server_errors,
+            error_rate: if total.requests == 0 {
+                0.0
+            } else {
+                (total.client_errors + total.server_errors) as f64 / total.requests as f64
+            },
+            bytes_in: total.bytes_in,
+            bytes_out: total.bytes_out,
+            p50_latency_ms: percentile(&total.latency_buckets, 0.5),
+            p95_latency_ms: percentile(&total.latency_buckets, 0.95),
+        })
+        .collect()
+}
+
+/// The user's metrics over the last `hours` hours, the current one included
+/// (reloads the store, the proxy is the one writing it)
+pub fn get_user_metrics(email: &str, hours: u32) -> Result<Vec<InstanceMetrics>> {
+    let store = get_metrics_store();
+    store.reload()?;
+    let records = store.get(&email.to_string())?.unwrap_or_default();
+
+    let hours = hours.clamp(1, MAX_WINDOW_HOURS);
+    let cutoff = hour_bucket(Utc::now() - chrono::Duration::hours(hours as i64 - 1));
+    let recent: Vec<MetricsRecord> = records.into_iter().filter(|r| r.hour >= cutoff).collect();
+    Ok(summarize(&recent))
+}
+
+#[test]
+fn test_metrics_collector() {
+    let metrics = MetricsCollector::new();
+    let at = |h: u32| {
+        DateTime::parse_from_rfc3339(&format!("2026-01-01T{:02}:30:00Z", h))
+            .unwrap()
+            .with_timezone(&Utc)
+    };
+
+    for latency in [3, 4, 8, 20, 40, 60, 80, 90, 120, 2000] {
+        metrics.record_at("a@x.com", "inst", 200, latency, (10, 100), at(10));
+    }
+    metrics.record_at("a@x.com", "inst", 404, 1, (0, 20), at(11));
+    metrics.record_at("a@x.com", "inst", 502, 60000, (0, 20), at(11));
+    metrics.record_at("a@x.com", "clone", 200, 7, (5, 5), at(11));
+
+    let mut records = Vec::new();
+    for (_, record) in metrics.drain() {
+        merge_record(&mut records, record);
+    }
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].hour, hour_bucket(at(10)));
+    assert!(metrics.drain().is_empty());
+
+    let summary = summarize(&records);
+    assert_eq!(summary[0].instance_id, "clone");
+    let inst = &summary[1];
+    assert_eq!(inst.requests, 12);
+    assert_eq!((inst.client_errors, inst.server_errors), (1, 1));
+    assert!((inst.error_rate - 2.0 / 12.0).abs() < 1e-9);
+    assert_eq!((inst.bytes_in, inst.bytes_out), (100, 1040));
+    assert_eq!(inst.p50_latency_ms, Some(50));
+    assert_eq!(inst.p95_latency_ms, Some(30000));
+
+    assert_eq!(percentile(&[], 0.5), None);
+    assert_eq!(percentile(&[0, 1], 0.95), Some(10));
+}
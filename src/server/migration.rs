@@ -0,0 +1,180 @@
+//! # Store format migration
+//!
+//! Converts a persisted store to another format (JSON, SQLite, encrypted JSON) without taking the
+//! service down. While it runs, every store in the process is read-only: reads keep working and
+//! writes fail with a retryable error (the service answers 503 for them).
+//!
+//! The store is written to a temporary file next to the original, read back and compared with the
+//! in-memory copy (record count and checksum, see `storage::entries_checksum`). Only when both
+//! match does the temporary file replace the original, which is kept as `<file>.pre-migration`.
+//! Other processes reading the file (the proxy) pick the new format up on their next reload,
+//! encrypted stores need `BLAZE_STORE_KEY` there too.
+
+use crate::info;
+use crate::server::storage;
+use crate::server::storage::{
+    DataStore, StoreFormat, entries_checksum, read_entries, set_read_only, with_suffix,
+    write_entries,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Only one migration at a time, they share the read-only switch
+static MIGRATION_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationReport {
+    pub from: StoreFormat,
+    pub to: StoreFormat,
+    pub records: usize,
+    pub checksum: String,
+    pub backup_path: String,
+    pub duration_ms: u128,
+}
+
+/// Turns read-only mode back off however the migration ends
+struct ReadOnlyGuard;
+
+impl ReadOnlyGuard {
+    fn enable() -> Self {
+        set_read_only(true);
+        ReadOnlyGuard
+    }
+}
+
+impl Drop for ReadOnlyGuard {
+    fn drop(&mut self) {
+        set_read_only(false);
+    }
+}
+
+/// Writes `data` to a temporary file in the target format and verifies it reads back the same
+/// Returns the temporary path and the verified checksum
+fn write_verified<K, V>(
+    path: &Path,
+    target: StoreFormat,
+    schema_version: u32,
+    data: &BTreeMap<K, V>,
+) -> Result<(PathBuf, String)>
+where
+    K: Ord + Clone + Serialize + for<'de> Deserialize<'de>,
+    V: Clone + Serialize + for<'de> Deserialize<'de>,
+{
+    let temp_path = with_suffix(path, ".migrating");
+    let _ = std::fs::remove_file(&temp_path);
+
+    let expected = entries_checksum(data)?;
+    let verify = || -> Result<()> {
+        write_entries(&temp_path, target, schema_version, data)?;
+
+        let (written, format) = read_entries::<K, V>(&temp_path)?;
+        if format != target {
+            return Err(anyhow::anyhow!(
+                "Migrated file reads back as {:?}, expected {:?}",
+                format,
+                target
+            ));
+        }
+        if written.len() != data.len() {
+            return Err(anyhow::anyhow!(
+                "Record count mismatch after migration: {} written, {} expected",
+                written.len(),
+                data.len()
+            ));
+        }
+        let checksum = entries_checksum(&written)?;
+        if checksum != expected {
+            return Err(anyhow::anyhow!("Checksum mismatch after migration"));
+        }
+        Ok(())
+    };
+
+    if let Err(e) = verify() {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    Ok((temp_path, expected))
+}
+
+/// Converts the store's file to `target`, see the module docs
+/// The service is read-only for the duration
+pub fn migrate_store<K, V>(store: &DataStore<K, V>, target: StoreFormat) -> Result<MigrationReport>
+where
+    K: Ord + std::hash::Hash + Clone + Serialize + for<'de> Deserialize<'de>,
+    V: Clone + Serialize + for<'de> Deserialize<'de>,
+{
+    let _lock = MIGRATION_LOCK
+        .try_lock()
+        .map_err(|_| anyhow::anyhow!("Another migration is already running"))?;
+
+    let from = store.format()?;
+    if from == target {
+        return Err(anyhow::anyhow!("Store is already in {:?} format", target));
+    }
+
+    let started = Instant::now();
+    let path = store.path().to_path_buf();
+
+    // Persist pending in-memory writes, then freeze the store
+    store.save_to_disk()?;
+    let _read_only = ReadOnlyGuard::enable();
+    let snapshot = store.snapshot()?;
+
+    info!(
+        "Migrating {} ({} records): {:?} -> {:?}",
+        path.display(),
+        snapshot.len(),
+        from,
+        target
+    );
+
+    let (temp_path, checksum) = write_verified(&path, target, store.schema_version(), &snapshot)?;
+
+    let backup_path = with_suffix(&path, ".pre-migration");
+    std::fs::copy(&path, &backup_path)?;
+    // Rename is atomic, readers see either the old file or the new one
+    std::fs::rename(&temp_path, &path)?;
+    // The last save's copy is in the old format (plaintext for encryption), the backup above replaces it
+    let _ = std::fs::remove_file(storage::backup_path(&path));
+    store.reload()?;
+
+    let report = MigrationReport {
+        from,
+        to: target,
+        records: snapshot.len(),
+        checksum,
+        backup_path: backup_path.display().to_string(),
+        duration_ms: started.elapsed().as_millis(),
+    };
+    info!(
+        "Migrated {} to {:?} in {}ms, backup at {}",
+        path.display(),
+        target,
+        report.duration_ms,
+        report.backup_path
+    );
+    Ok(report)
+}
+
+#[test]
+fn test_write_verified_sqlite() -> Result<()> {
+    let path = std::env::temp_dir().join("test_migration_users.json");
+    let data: BTreeMap<String, Vec<u32>> = BTreeMap::from([
+        ("a@x.com".to_string(), vec![1, 2]),
+        ("b@x.com".to_string(), vec![]),
+    ]);
+
+    let (temp_path, checksum) = write_verified(&path, StoreFormat::Sqlite, 1, &data)?;
+    assert_eq!(StoreFormat::detect(&temp_path)?, StoreFormat::Sqlite);
+
+    let (read_back, _) = read_entries::<String, Vec<u32>>(&temp_path)?;
+    assert_eq!(read_back, data);
+    assert_eq!(entries_checksum(&read_back)?, checksum);
+
+    let _ = std::fs::remove_file(&temp_path);
+    Ok(())
+}
@@ -1,7 +1,59 @@
+pub mod access_log;
+pub mod activity;
+pub mod anomaly;
+pub mod audit;
+pub mod autorestart;
+pub mod backups;
+pub mod balancer;
+pub mod billing;
+pub mod capabilities;
+pub mod circuit;
+pub mod compression;
+pub mod concurrency;
 pub mod container;
+pub mod container_events;
+pub mod cors;
 pub mod crypto;
+pub mod dunning;
+pub mod error;
+pub mod forwarding;
+pub mod hibernation;
+pub mod incidents;
+pub mod invalidation;
+pub mod invoices;
+pub mod key_index;
 pub mod log;
+pub mod mailer;
+pub mod maintenance;
+pub mod metering;
+pub mod metrics;
+pub mod migration;
+pub mod network;
+pub mod organizations;
+pub mod placement;
+pub mod plans;
+pub mod pool;
 pub mod ports;
+pub mod preflight;
+pub mod provisioning;
+pub mod quota;
+pub mod reachability;
+pub mod recommendation;
+pub mod reconciliation;
+pub mod referrals;
+pub mod rehash;
+pub mod response_cache;
+pub mod rotation;
+pub mod routing;
 pub mod schema;
+pub mod secrets;
 pub mod service;
+pub mod snapshots;
 pub mod storage;
+pub mod streams;
+pub mod tasks;
+pub mod tax;
+pub mod tls;
+pub mod users;
+pub mod versioning;
+pub mod webhooks;
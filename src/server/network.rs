@@ -0,0 +1,294 @@
+//! # Client address resolution
+//!
+//! When the binaries sit behind a load balancer or Cloudflare, the TCP peer is the proxy, not the client.
+//! This module decides which forwarding headers to believe, and from which peers, so rate limits,
+//! allowlists and logs record the real client address.
+//!
+//! Configured through env:
+//! - `BLAZE_BEHIND_PROXY`: `true` to honour forwarding headers at all (default `false`)
+//! - `BLAZE_TRUSTED_PROXIES`: comma separated CIDRs whose headers are trusted (default loopback only)
+//! - `BLAZE_FORWARDED_HEADERS`: comma separated headers to read, in priority order
+//!   (`x-forwarded-for`, `forwarded`, `x-real-ip`, `cf-connecting-ip`, default `x-forwarded-for`)
+//!
+//! The same trust decides what the proxy passes on to instances, see
+//! `forwarding::append_forwarding_headers`.
+
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::HeaderMap;
+use axum::http::request::Parts;
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::OnceLock;
+
+static TRUSTED_PROXY_CONFIG: OnceLock<TrustedProxyConfig> = OnceLock::new();
+
+/// Forwarding headers we know how to parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHeader {
+    XForwardedFor,
+    Forwarded,
+    XRealIp,
+    CfConnectingIp,
+}
+
+impl ForwardedHeader {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "x-forwarded-for" => Some(ForwardedHeader::XForwardedFor),
+            "forwarded" => Some(ForwardedHeader::Forwarded),
+            "x-real-ip" => Some(ForwardedHeader::XRealIp),
+            "cf-connecting-ip" => Some(ForwardedHeader::CfConnectingIp),
+            _ => None,
+        }
+    }
+
+    pub fn header_name(&self) -> &'static str {
+        match self {
+            ForwardedHeader::XForwardedFor => "x-forwarded-for",
+            ForwardedHeader::Forwarded => "forwarded",
+            ForwardedHeader::XRealIp => "x-real-ip",
+            ForwardedHeader::CfConnectingIp => "cf-connecting-ip",
+        }
+    }
+
+    /// Returns the address chain carried by this header, client first, closest proxy last
+    fn address_chain(&self, headers: &HeaderMap) -> Vec<IpAddr> {
+        let values = headers
+            .get_all(self.header_name())
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+
+        match self {
+            ForwardedHeader::XForwardedFor | ForwardedHeader::XRealIp => values
+                .flat_map(|v| v.split(','))
+                .filter_map(parse_node)
+                .collect(),
+            ForwardedHeader::Forwarded => values
+                .flat_map(|v| v.split(','))
+                .filter_map(|element| {
+                    element.split(';').find_map(|pair| {
+                        let (key, value) = pair.split_once('=')?;
+                        if key.trim().eq_ignore_ascii_case("for") {
+                            parse_node(value)
+                        } else {
+                            None
+                        }
+                    })
+                })
+                .collect(),
+            // Set by Cloudflare itself, always a single address
+            ForwardedHeader::CfConnectingIp => values.filter_map(parse_node).take(1).collect(),
+        }
+    }
+}
+
+/// Parses one address out of a forwarding header (handles quotes, ports and `[v6]:port`)
+fn parse_node(raw: &str) -> Option<IpAddr> {
+    let node = raw.trim().trim_matches('"');
+
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // Bracketed IPv6 without port: "[2001:db8::1]"
+    node.strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .and_then(|n| n.parse::<IpAddr>().ok())
+}
+
+/// Which peers and headers to trust when resolving the client address
+#[derive(Debug, Clone)]
+pub struct TrustedProxyConfig {
+    pub behind_proxy: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub trusted_headers: Vec<ForwardedHeader>,
+}
+
+impl Default for TrustedProxyConfig {
+    fn default() -> Self {
+        TrustedProxyConfig {
+            behind_proxy: false,
+            trusted_proxies: vec![
+                "127.0.0.0/8".parse().expect("valid CIDR"),
+                "::1/128".parse().expect("valid CIDR"),
+            ],
+            trusted_headers: vec![ForwardedHeader::XForwardedFor],
+        }
+    }
+}
+
+impl TrustedProxyConfig {
+    /// Loads the config from env, invalid entries are skipped with a warning
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+
+        let mut config = TrustedProxyConfig::default();
+
+        if let Ok(value) = std::env::var("BLAZE_BEHIND_PROXY") {
+            config.behind_proxy = matches!(value.trim().to_lowercase().as_str(), "1" | "true");
+        }
+
+        if let Ok(value) = std::env::var("BLAZE_TRUSTED_PROXIES") {
+            config.trusted_proxies = value
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .filter_map(|cidr| match parse_cidr(cidr) {
+                    Some(net) => Some(net),
+                    None => {
+                        crate::warn!("Ignoring invalid trusted proxy CIDR: {}", cidr);
+                        None
+                    }
+                })
+                .collect();
+        }
+
+        if let Ok(value) = std::env::var("BLAZE_FORWARDED_HEADERS") {
+            config.trusted_headers = value
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .filter_map(|name| match ForwardedHeader::parse(name) {
+                    Some(header) => Some(header),
+                    None => {
+                        crate::warn!("Ignoring unknown forwarded header: {}", name.trim());
+                        None
+                    }
+                })
+                .collect();
+        }
+
+        config
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    /// Whether forwarding headers sent by `peer` are believed
+    pub fn trusts_peer(&self, peer: &IpAddr) -> bool {
+        self.behind_proxy && self.is_trusted(peer)
+    }
+
+    /// Resolves the real client address for a request received from `peer`
+    /// Headers are only honoured when the peer itself is a trusted proxy, and the chain is walked
+    /// from the closest hop backwards so a client can't spoof its way past our own proxies
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusts_peer(&peer) {
+            return peer;
+        }
+
+        for header in &self.trusted_headers {
+            let chain = header.address_chain(headers);
+            if chain.is_empty() {
+                continue;
+            }
+
+            // First untrusted hop from the right is the client, if every hop is trusted use the origin
+            let client = chain
+                .iter()
+                .rev()
+                .find(|ip| !self.is_trusted(ip))
+                .or(chain.first());
+
+            if let Some(ip) = client {
+                return *ip;
+            }
+        }
+
+        peer
+    }
+}
+
+/// Parses a CIDR, a bare address is treated as a single host
+fn parse_cidr(value: &str) -> Option<IpNet> {
+    value
+        .parse::<IpNet>()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Returns the process wide trusted proxy config (loaded from env on first use)
+pub fn get_trusted_proxy_config() -> &'static TrustedProxyConfig {
+    TRUSTED_PROXY_CONFIG.get_or_init(TrustedProxyConfig::from_env)
+}
+
+/// Resolves the client address with the process wide config
+pub fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    get_trusted_proxy_config().client_ip(peer, headers)
+}
+
+/// Axum extractor for the real client address
+/// Requires the app to be served with `into_make_service_with_connect_info::<SocketAddr>()`
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+        Ok(ClientIp(resolve_client_ip(peer, &parts.headers)))
+    }
+}
+
+#[cfg(test)]
+fn test_config() -> TrustedProxyConfig {
+    TrustedProxyConfig {
+        behind_proxy: true,
+        trusted_proxies: vec![parse_cidr("10.0.0.0/8").unwrap()],
+        trusted_headers: vec![ForwardedHeader::Forwarded, ForwardedHeader::XForwardedFor],
+    }
+}
+
+#[test]
+fn test_untrusted_peer_headers_ignored() {
+    let config = test_config();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "1.2.3.4".parse().unwrap());
+
+    let peer: IpAddr = "203.0.113.9".parse().unwrap();
+    assert_eq!(config.client_ip(peer, &headers), peer);
+}
+
+#[test]
+fn test_x_forwarded_for_skips_trusted_hops() {
+    let config = test_config();
+    let mut headers = HeaderMap::new();
+    // Client spoofed the first entry, our LB appended the real address
+    headers.insert(
+        "x-forwarded-for",
+        "6.6.6.6, 198.51.100.7, 10.0.0.3".parse().unwrap(),
+    );
+
+    let peer: IpAddr = "10.0.0.2".parse().unwrap();
+    assert_eq!(
+        config.client_ip(peer, &headers),
+        "198.51.100.7".parse::<IpAddr>().unwrap()
+    );
+}
+
+#[test]
+fn test_forwarded_header_parsing() {
+    let config = test_config();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "forwarded",
+        r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.5"#
+            .parse()
+            .unwrap(),
+    );
+
+    let peer: IpAddr = "10.0.0.2".parse().unwrap();
+    assert_eq!(
+        config.client_ip(peer, &headers),
+        "2001:db8::1".parse::<IpAddr>().unwrap()
+    );
+}
@@ -0,0 +1,310 @@
+//! # Organizations
+//!
+//! An organization groups several users under one plan and one bill. The owner creates it, pays
+//! for it (their plan is the organization's plan) and invites members by email. Every member's
+//! instance runs with the owner's plan limits and follows it when the owner changes plans, members
+//! can't check out on their own.
+//!
+//! Invites reuse the OTP machinery: the owner invites an email, the invitee (with their own
+//! account) asks to join and gets a confirmation code, then joins with the code. Leaving puts the
+//! member back on the free plan. Organizations live in `get_data_path()/organizations.json`.
+
+use crate::server::mailer::send_mail;
+use crate::server::schema::{Organization, Plans, User};
+use crate::server::service::{change_plan, end_trial, get_data_path, get_user, update_user};
+use crate::server::storage::DataStore;
+use crate::server::tasks::get_task_registry;
+use crate::{error, info};
+use anyhow::Result;
+use chrono::Utc;
+use std::sync::OnceLock;
+
+static ORGANIZATION_STORE: OnceLock<DataStore<String, Organization>> = OnceLock::new();
+
+/// Organizations keyed by id
+pub fn get_organization_store() -> DataStore<String, Organization> {
+    ORGANIZATION_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("organizations.json");
+            DataStore::<String, Organization>::new(path)
+                .expect("CRASH!! Failed to initialize organization datastore")
+        })
+        .clone()
+}
+
+fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// The organization the user belongs to, if any
+pub fn get_user_organization(user: &User) -> Result<Option<Organization>> {
+    match &user.organization_id {
+        Some(id) => Ok(get_organization_store().get(id)?),
+        None => Ok(None),
+    }
+}
+
+/// Whether the user's plan is managed by an organization they don't own
+pub fn is_managed_member(user: &User) -> Result<bool> {
+    Ok(get_user_organization(user)?.is_some_and(|org| org.owner_email != user.email))
+}
+
+async fn get_verified_user(email: &str) -> Result<User> {
+    get_user(email)
+        .await?
+        .filter(|u| u.is_verified)
+        .ok_or_else(|| anyhow::anyhow!("User not found"))
+}
+
+/// Adds a pending invite, returns false when the email was already invited
+fn add_invite(organization: &mut Organization, invitee: &str) -> Result<bool> {
+    if organization.members.iter().any(|e| e == invitee) {
+        return Err(anyhow::anyhow!("{} is already a member", invitee));
+    }
+    if organization.pending_invites.iter().any(|e| e == invitee) {
+        return Ok(false);
+    }
+    organization.pending_invites.push(invitee.to_string());
+    Ok(true)
+}
+
+/// Turns a pending invite into a membership
+fn accept_invite(organization: &mut Organization, email: &str) -> Result<()> {
+    let before = organization.pending_invites.len();
+    organization.pending_invites.retain(|e| e != email);
+    if organization.pending_invites.len() == before {
+        return Err(anyhow::anyhow!("No pending invite to this organization"));
+    }
+    organization.members.push(email.to_string());
+    Ok(())
+}
+
+/// Creates an organization owned by `owner_email`, who must not be in one already
+pub async fn create_organization(owner_email: &str, name: &str) -> Result<Organization> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 64 {
+        return Err(anyhow::anyhow!(
+            "Organization name must be 1 to 64 characters"
+        ));
+    }
+
+    let owner = get_verified_user(owner_email).await?;
+    if owner.organization_id.is_some() {
+        return Err(anyhow::anyhow!("You already belong to an organization"));
+    }
+
+    let organization = Organization {
+        id: format!("org_{}", hex::encode(rand::random::<[u8; 8]>())),
+        name: name.to_string(),
+        owner_email: owner.email.clone(),
+        members: vec![owner.email.clone()],
+        pending_invites: Vec::new(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    get_organization_store().insert_save(organization.id.clone(), organization.clone())?;
+
+    let id = organization.id.clone();
+    update_user(&owner.email, |user| user.organization_id = Some(id)).await?;
+
+    info!(
+        "Organization {} ({}) created by {}",
+        organization.name, organization.id, owner.email
+    );
+    Ok(organization)
+}
+
+/// Records an invite from the owner and emails the invitee how to join
+pub async fn invite_member(owner_email: &str, invitee: &str) -> Result<Organization> {
+    let invitee = normalize_email(invitee);
+    let owner = get_verified_user(owner_email).await?;
+
+    let store = get_organization_store();
+    let mut organization = get_user_organization(&owner)?
+        .filter(|org| org.owner_email == owner.email)
+        .ok_or_else(|| anyhow::anyhow!("Only an organization owner can invite members"))?;
+
+    if add_invite(&mut organization, &invitee)? {
+        store.insert_save(organization.id.clone(), organization.clone())?;
+    }
+
+    send_invite_mail(&invitee, &organization);
+    info!(
+        "{} invited {} to organization {}",
+        owner.email, invitee, organization.id
+    );
+    Ok(organization)
+}
+
+/// Checks that `email` may join `organization_id`, before a confirmation code is sent
+pub async fn check_can_join(email: &str, organization_id: &str) -> Result<Organization> {
+    let user = get_verified_user(email).await?;
+    if user.organization_id.is_some() {
+        return Err(anyhow::anyhow!("You already belong to an organization"));
+    }
+
+    get_organization_store()
+        .get(&organization_id.to_string())?
+        .filter(|org| org.pending_invites.contains(&user.email))
+        .ok_or_else(|| anyhow::anyhow!("No pending invite to this organization"))
+}
+
+/// Adds an invited user to the organization and moves them to the owner's plan
+/// The confirmation code has to be checked by the caller
+pub async fn join_organization(email: &String, organization_id: &str) -> Result<Organization> {
+    let mut organization = check_can_join(email, organization_id).await?;
+
+    let owner_plan = get_verified_user(&organization.owner_email).await?.plans;
+    let user = get_verified_user(email).await?;
+    if user.plans.name != owner_plan.name {
+        change_plan(email, owner_plan).await?;
+    }
+    // The organization pays from now on, a running trial would downgrade them when it ends
+    end_trial(email).await?;
+
+    accept_invite(&mut organization, &user.email)?;
+    get_organization_store().insert_save(organization.id.clone(), organization.clone())?;
+
+    let id = organization.id.clone();
+    update_user(email, |user| user.organization_id = Some(id)).await?;
+
+    info!("{} joined organization {}", email, organization.id);
+    Ok(organization)
+}
+
+/// Removes a member from their organization and puts them back on the free plan
+/// The owner can't leave, the organization would have nobody paying for it
+pub async fn leave_organization(email: &String) -> Result<()> {
+    let user = get_verified_user(email).await?;
+    let Some(mut organization) = get_user_organization(&user)? else {
+        return Err(anyhow::anyhow!("You don't belong to an organization"));
+    };
+    if organization.owner_email == user.email {
+        return Err(anyhow::anyhow!("The owner can't leave the organization"));
+    }
+
+    organization.members.retain(|e| e != &user.email);
+    get_organization_store().insert_save(organization.id.clone(), organization.clone())?;
+    update_user(email, |user| user.organization_id = None).await?;
+
+    let free_plan = Plans::default_plan();
+    if user.plans.name != free_plan.name {
+        change_plan(email, free_plan).await?;
+    }
+
+    info!("{} left organization {}", email, organization.id);
+    Ok(())
+}
+
+/// Takes a user that's being deleted out of their organization
+/// An owner can only go once nobody else is left, the organization goes with them
+pub fn remove_deleted_user(user: &User) -> Result<()> {
+    let Some(mut organization) = get_user_organization(user)? else {
+        return Ok(());
+    };
+    let store = get_organization_store();
+
+    if organization.owner_email == user.email {
+        if organization.members.iter().any(|e| *e != user.email) {
+            return Err(anyhow::anyhow!(
+                "Remove the other members of your organization first"
+            ));
+        }
+        store.delete(&organization.id)?;
+        info!("Organization {} deleted with its owner", organization.id);
+    } else {
+        organization.members.retain(|e| e != &user.email);
+        store.insert_save(organization.id.clone(), organization)?;
+    }
+    Ok(())
+}
+
+/// Moves every member of the organization `owner_email` owns to `plan`
+/// Called after the owner's plan changed, members that fail are logged and skipped
+pub async fn sync_member_plans(owner_email: &str, plan: &Plans) -> Result<usize> {
+    let Some(owner) = get_user(owner_email).await? else {
+        return Ok(0);
+    };
+    let Some(organization) =
+        get_user_organization(&owner)?.filter(|org| org.owner_email == owner.email)
+    else {
+        return Ok(0);
+    };
+
+    let mut synced = 0;
+    for member in organization.members.iter().filter(|e| **e != owner.email) {
+        match get_user(member).await? {
+            Some(user) if user.plans.name != plan.name => {
+                match change_plan(member, plan.clone()).await {
+                    Ok(_) => synced += 1,
+                    Err(e) => error!(
+                        "Failed to move {} to the {} plan of organization {}: {}",
+                        member, plan.name, organization.id, e
+                    ),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(synced)
+}
+
+fn send_invite_mail(invitee: &str, organization: &Organization) {
+    let invitee = invitee.to_string();
+    let plain_body = format!(
+        "Hi,\n\n{} invited you to the {} organization on BlazeDB. Sign in with your account and join with POST /v1/blz/orgs/join (organization id: {}).",
+        organization.owner_email, organization.name, organization.id
+    );
+    let html_body = format!(
+        r#"
+        <html>
+        <body style="font-family: sans-serif;">
+            <h2>You're invited to {name}</h2>
+            <p>{owner} invited you to the <strong>{name}</strong> organization on BlazeDB.</p>
+            <p>Sign in with your account and join with <code>POST /v1/blz/orgs/join</code>, organization id <code>{id}</code>.</p>
+        </body>
+        </html>
+        "#,
+        name = organization.name,
+        owner = organization.owner_email,
+        id = organization.id
+    );
+
+    get_task_registry().spawn("organization-invite-mail", |_| async move {
+        let sent = tokio::task::spawn_blocking(move || {
+            send_mail(
+                &invitee,
+                "You're invited to a BlazeDB organization",
+                plain_body,
+                html_body,
+            )
+            .map_err(|e| (invitee, e))
+        })
+        .await;
+        if let Ok(Err((invitee, e))) = sent {
+            error!("Failed to send organization invite to {}: {}", invitee, e);
+        }
+    });
+}
+
+#[test]
+fn test_invite_and_accept() -> Result<()> {
+    let mut organization = Organization {
+        id: "org_test".to_string(),
+        name: "Acme".to_string(),
+        owner_email: "owner@acme.com".to_string(),
+        members: vec!["owner@acme.com".to_string()],
+        pending_invites: Vec::new(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+
+    assert!(add_invite(&mut organization, "dev@acme.com")?);
+    assert!(!add_invite(&mut organization, "dev@acme.com")?); // Invited twice, listed once
+    assert!(add_invite(&mut organization, "owner@acme.com").is_err());
+
+    assert!(accept_invite(&mut organization, "eve@evil.com").is_err());
+    accept_invite(&mut organization, "dev@acme.com")?;
+    assert_eq!(organization.members.len(), 2);
+    assert!(organization.pending_invites.is_empty());
+    assert!(add_invite(&mut organization, "dev@acme.com").is_err());
+    Ok(())
+}
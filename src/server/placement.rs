@@ -0,0 +1,163 @@
+//! # Container placement
+//!
+//! Decides which container host a new instance goes to. Today there is only the local Docker host,
+//! but constraints are evaluated over a list of hosts so multi-host setups can plug in later:
+//! - `BLAZE_MAX_CONTAINERS_PER_HOST`: hard cap on instances per host (unset = unlimited)
+//! - `BLAZE_RESERVED_PAID_SLOTS`: slots under the cap that only paid plans may use
+//! - Plans with `dedicated_server_instance` are pinned to dedicated hosts, shared plans never land there
+
+use crate::server::schema::Plans;
+use crate::warn;
+use anyhow::Result;
+use std::sync::OnceLock;
+
+static PLACEMENT_CONSTRAINTS: OnceLock<PlacementConstraints> = OnceLock::new();
+
+/// A container host and its current load
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostInfo {
+    pub name: String,
+    pub running_containers: usize,
+    pub dedicated: bool, // Reserved for tenants with a dedicated server instance
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PlacementConstraints {
+    pub max_containers_per_host: Option<usize>,
+    pub reserved_paid_slots: usize,
+}
+
+impl PlacementConstraints {
+    /// Loads the constraints from env, invalid values are ignored with a warning
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+
+        let mut constraints = PlacementConstraints::default();
+
+        if let Ok(value) = std::env::var("BLAZE_MAX_CONTAINERS_PER_HOST") {
+            match value.trim().parse::<usize>() {
+                Ok(max) => constraints.max_containers_per_host = Some(max),
+                Err(_) => warn!("Ignoring invalid BLAZE_MAX_CONTAINERS_PER_HOST: {}", value),
+            }
+        }
+
+        if let Ok(value) = std::env::var("BLAZE_RESERVED_PAID_SLOTS") {
+            match value.trim().parse::<usize>() {
+                Ok(slots) => constraints.reserved_paid_slots = slots,
+                Err(_) => warn!("Ignoring invalid BLAZE_RESERVED_PAID_SLOTS: {}", value),
+            }
+        }
+
+        constraints
+    }
+
+    /// Whether the host has room for another instance on this plan
+    pub fn has_capacity(&self, plan: &Plans, host: &HostInfo) -> bool {
+        let Some(max) = self.max_containers_per_host else {
+            return true;
+        };
+
+        let is_paid = plan.price_per_month > 0;
+        let limit = if is_paid {
+            max
+        } else {
+            max.saturating_sub(self.reserved_paid_slots)
+        };
+
+        host.running_containers < limit
+    }
+
+    /// Picks the least loaded host that satisfies every constraint for the plan
+    /// Dedicated tenants fall back to shared hosts only when no dedicated host is registered at all
+    pub fn choose_host<'a>(&self, plan: &Plans, hosts: &'a [HostInfo]) -> Result<&'a HostInfo> {
+        let wants_dedicated = plan.features.dedicated_server_instance;
+        let has_dedicated_hosts = hosts.iter().any(|h| h.dedicated);
+
+        if wants_dedicated && !has_dedicated_hosts {
+            warn!(
+                "No dedicated host registered, placing {} plan instance on a shared host",
+                plan.name
+            );
+        }
+
+        let pool_is_dedicated = wants_dedicated && has_dedicated_hosts;
+
+        hosts
+            .iter()
+            .filter(|h| h.dedicated == pool_is_dedicated)
+            .filter(|h| self.has_capacity(plan, h))
+            .min_by_key(|h| h.running_containers)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No {} host has capacity for a new {} plan instance",
+                    if pool_is_dedicated {
+                        "dedicated"
+                    } else {
+                        "shared"
+                    },
+                    plan.name
+                )
+            })
+    }
+}
+
+/// Returns the process wide placement constraints (loaded from env on first use)
+pub fn get_placement_constraints() -> &'static PlacementConstraints {
+    PLACEMENT_CONSTRAINTS.get_or_init(PlacementConstraints::from_env)
+}
+
+#[test]
+fn test_reserved_slots_only_for_paid_plans() {
+    use crate::server::plans::builtin_plan;
+    let constraints = PlacementConstraints {
+        max_containers_per_host: Some(10),
+        reserved_paid_slots: 2,
+    };
+    let host = HostInfo {
+        name: "local".to_string(),
+        running_containers: 8,
+        dedicated: false,
+    };
+
+    assert!(!constraints.has_capacity(&builtin_plan("free"), &host));
+    assert!(constraints.has_capacity(&builtin_plan("starter"), &host));
+    assert!(
+        constraints
+            .choose_host(&builtin_plan("free"), &[host])
+            .is_err()
+    );
+}
+
+#[test]
+fn test_dedicated_tenants_pinned_to_dedicated_hosts() {
+    use crate::server::plans::builtin_plan;
+    let constraints = PlacementConstraints::default();
+    let hosts = vec![
+        HostInfo {
+            name: "shared-1".to_string(),
+            running_containers: 0,
+            dedicated: false,
+        },
+        HostInfo {
+            name: "dedicated-1".to_string(),
+            running_containers: 3,
+            dedicated: true,
+        },
+    ];
+
+    let pro = constraints
+        .choose_host(&builtin_plan("pro"), &hosts)
+        .unwrap();
+    assert_eq!(pro.name, "dedicated-1");
+
+    let free = constraints
+        .choose_host(&builtin_plan("free"), &hosts)
+        .unwrap();
+    assert_eq!(free.name, "shared-1");
+
+    // Single host setups keep working for dedicated tenants
+    let pro = constraints
+        .choose_host(&builtin_plan("pro"), &hosts[..1])
+        .unwrap();
+    assert_eq!(pro.name, "shared-1");
+}
@@ -0,0 +1,262 @@
+//! # Plan catalog
+//!
+//! Plans (pricing, feature limits, container resources, trials) come from a JSON file instead of
+//! the binary: `BLAZE_PLANS_FILE`, or `get_data_path()/plans.json` by default. The file is a list
+//! of plans, cheapest first, and needs at least one free plan (new users start on it). When the
+//! file doesn't exist it's created from the built-in plans, so there's always something to edit.
+//!
+//! The service checks the file every 30s and swaps the catalog in when it changed. A broken file
+//! is logged and ignored, the previous catalog stays in use.
+
+use crate::server::schema::{AnomalyAction, Feature, Plans};
+use crate::server::service::get_data_path;
+use crate::{error, info};
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::SystemTime;
+
+static PLAN_CATALOG: OnceLock<RwLock<Arc<Vec<Plans>>>> = OnceLock::new();
+static PLAN_FILE_MODIFIED: Mutex<Option<SystemTime>> = Mutex::new(None);
+
+pub fn get_plans_file_path() -> PathBuf {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_PLANS_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| get_data_path().join("plans.json"))
+}
+
+fn catalog_lock() -> &'static RwLock<Arc<Vec<Plans>>> {
+    PLAN_CATALOG.get_or_init(|| {
+        let plans = read_plans_file(&get_plans_file_path()).unwrap_or_else(|e| {
+            error!("Failed to load plan catalog, using built-in plans: {}", e);
+            builtin_plans()
+        });
+        RwLock::new(Arc::new(plans))
+    })
+}
+
+/// The current plan catalog, cheapest first
+pub fn get_plan_catalog() -> Arc<Vec<Plans>> {
+    catalog_lock()
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
+/// Checks a catalog before it replaces the current one
+fn validate_catalog(plans: &[Plans]) -> Result<()> {
+    if plans.is_empty() {
+        return Err(anyhow::anyhow!("Plan catalog is empty"));
+    }
+    if !plans.iter().any(|p| p.price_per_month == 0) {
+        return Err(anyhow::anyhow!("Plan catalog needs a free plan"));
+    }
+    for (i, plan) in plans.iter().enumerate() {
+        if plan.name.trim().is_empty() {
+            return Err(anyhow::anyhow!("Plan #{} has no name", i));
+        }
+        if plans[..i]
+            .iter()
+            .any(|p| p.name.eq_ignore_ascii_case(&plan.name))
+        {
+            return Err(anyhow::anyhow!("Plan {} is listed twice", plan.name));
+        }
+        if plan.price_per_year > plan.price_per_month * 12 {
+            return Err(anyhow::anyhow!(
+                "Plan {} costs more yearly than monthly",
+                plan.name
+            ));
+        }
+        if plan.features.cpu_count <= 0.0
+            || plan.features.memory_mb <= 0
+            || plan.features.pids_limit <= 0
+        {
+            return Err(anyhow::anyhow!(
+                "Plan {} has no container resources",
+                plan.name
+            ));
+        }
+        if plan.features.backup_every_hours == Some(0) || plan.features.backups_kept == 0 {
+            return Err(anyhow::anyhow!(
+                "Plan {} has an invalid backup schedule",
+                plan.name
+            ));
+        }
+        if plan.features.max_request_mb == 0 || plan.features.max_response_mb == 0 {
+            return Err(anyhow::anyhow!(
+                "Plan {} wouldn't let any request through",
+                plan.name
+            ));
+        }
+        if plan.features.hibernate_after_hours == Some(0) {
+            return Err(anyhow::anyhow!(
+                "Plan {} would hibernate instances right away",
+                plan.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Reads and validates a plan file, sorted cheapest first
+fn read_plans_file(path: &PathBuf) -> Result<Vec<Plans>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut plans: Vec<Plans> = serde_json::from_str(&content)?;
+    validate_catalog(&plans)?;
+    plans.sort_by_key(|p| p.price_per_month);
+    Ok(plans)
+}
+
+/// Writes the built-in plans to the plan file if there's none yet
+pub fn ensure_plans_file() -> Result<()> {
+    let path = get_plans_file_path();
+    if path.exists() {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, serde_json::to_string_pretty(&builtin_plans())?)?;
+    info!("Wrote built-in plans to {}", path.display());
+    Ok(())
+}
+
+/// Reloads the catalog when the plan file was modified since the last check
+/// Returns whether the catalog changed
+pub fn reload_plan_catalog_if_changed() -> Result<bool> {
+    let path = get_plans_file_path();
+    let modified = std::fs::metadata(&path)?.modified()?;
+
+    let mut last_modified = PLAN_FILE_MODIFIED.lock().unwrap_or_else(|e| e.into_inner());
+    let first_check = last_modified.is_none();
+    if *last_modified == Some(modified) {
+        return Ok(false);
+    }
+    // Remember it even if the file is broken, so the error isn't logged every tick
+    *last_modified = Some(modified);
+
+    let plans = read_plans_file(&path)?;
+    *catalog_lock().write().unwrap_or_else(|e| e.into_inner()) = Arc::new(plans);
+
+    if !first_check {
+        info!("Plan catalog reloaded from {}", path.display());
+    }
+    Ok(true)
+}
+
+/// A built-in plan by name, for tests that shouldn't depend on the plan file
+pub fn builtin_plan(name: &str) -> Plans {
+    builtin_plans()
+        .into_iter()
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+        .unwrap_or_else(|| panic!("No built-in plan named {}", name))
+}
+
+/// The plans used when there's no plan file yet
+pub fn builtin_plans() -> Vec<Plans> {
+    vec![
+        Plans {
+            name: "Free".to_string(),
+            price_per_month: 0,
+            price_per_year: 0,
+            features: Feature {
+                database_no: 5,
+                vector_per_db: 5_000,
+                demo_datasets_included: true,
+                dedicated_user_space: true,
+                embedding_api_access: false,
+                dedicated_server_instance: false,
+                anomaly_action: AnomalyAction::Alert,
+                cpu_count: 0.5,
+                memory_mb: 256,
+                pids_limit: 128,
+                hibernate_after_hours: Some(6),
+                backup_every_hours: Some(24 * 7),
+                backups_kept: 4,
+                max_streams: 5,
+                max_request_mb: 10,
+                max_response_mb: 50,
+            },
+            trial_days: 0,
+        },
+        Plans {
+            name: "Starter".to_string(),
+            price_per_month: 12,
+            price_per_year: 120, // Two months free
+            features: Feature {
+                database_no: 10,
+                vector_per_db: 100_000,
+                demo_datasets_included: true,
+                dedicated_user_space: true,
+                embedding_api_access: true,
+                dedicated_server_instance: false,
+                anomaly_action: AnomalyAction::Alert,
+                cpu_count: 1.0,
+                memory_mb: 1024,
+                pids_limit: 256,
+                hibernate_after_hours: None,
+                backup_every_hours: Some(24),
+                backups_kept: 7,
+                max_streams: 25,
+                max_request_mb: 100,
+                max_response_mb: 500,
+            },
+            trial_days: 0,
+        },
+        Plans {
+            name: "Pro".to_string(),
+            price_per_month: 19,
+            price_per_year: 190,
+            features: Feature {
+                database_no: 20,
+                vector_per_db: 500_000,
+                demo_datasets_included: true,
+                dedicated_user_space: true,
+                embedding_api_access: true,
+                dedicated_server_instance: true,
+                anomaly_action: AnomalyAction::Reverify,
+                cpu_count: 2.0,
+                memory_mb: 2048,
+                pids_limit: 512,
+                hibernate_after_hours: None,
+                backup_every_hours: Some(24),
+                backups_kept: 14,
+                max_streams: 100,
+                max_request_mb: 500,
+                max_response_mb: 2048,
+            },
+            trial_days: 14,
+        },
+    ]
+}
+
+#[test]
+fn test_validate_catalog() {
+    let mut plans = builtin_plans();
+    assert!(validate_catalog(&plans).is_ok());
+
+    // Round trips through the file format
+    let json = serde_json::to_string(&plans).unwrap();
+    let parsed: Vec<Plans> = serde_json::from_str(&json).unwrap();
+    assert_eq!(parsed.len(), plans.len());
+
+    plans[1].name = "free".to_string();
+    assert!(validate_catalog(&plans).is_err());
+
+    let paid_only: Vec<Plans> = builtin_plans()
+        .into_iter()
+        .filter(|p| p.price_per_month > 0)
+        .collect();
+    assert!(validate_catalog(&paid_only).is_err());
+
+    let mut pricey_yearly = builtin_plans();
+    pricey_yearly[2].price_per_year = 19 * 12 + 1;
+    assert!(validate_catalog(&pricey_yearly).is_err());
+
+    let mut no_uploads = builtin_plans();
+    no_uploads[0].features.max_request_mb = 0;
+    assert!(validate_catalog(&no_uploads).is_err());
+    assert!(validate_catalog(&[]).is_err());
+}
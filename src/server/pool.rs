@@ -0,0 +1,154 @@
+//! # Upstream connection pools
+//!
+//! Each instance gets its own HTTP clients, one for plain requests and one for WebSockets and
+//! event streams, so keep-alive connections aren't shared between instances and one slow
+//! instance can't hold on to connections the others need. Clients are made on first use.
+//!
+//! - `BLAZE_PROXY_POOL_IDLE_TIMEOUT_SECONDS` (90 by default) closes connections that sat unused.
+//! - `BLAZE_PROXY_POOL_MAX_IDLE` (32) caps the idle connections kept per instance. How many are
+//!   open at once is up to the in-flight limit (see `concurrency`).
+//! - `BLAZE_PROXY_POOL_MAX_INSTANCES` (1024) caps the instances with a pool, the least recently
+//!   used one is dropped along with its idle connections.
+
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const DEFAULT_IDLE_TIMEOUT_SECONDS: u64 = 90;
+const DEFAULT_MAX_IDLE: usize = 32;
+const DEFAULT_MAX_INSTANCES: usize = 1024;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSettings {
+    pub idle_timeout: Duration,
+    pub max_idle_per_instance: usize,
+    pub max_instances: usize,
+}
+
+impl PoolSettings {
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let var = |name: &str| std::env::var(name).ok().and_then(|v| v.trim().parse().ok());
+        PoolSettings {
+            idle_timeout: Duration::from_secs(
+                var("BLAZE_PROXY_POOL_IDLE_TIMEOUT_SECONDS")
+                    .map_or(DEFAULT_IDLE_TIMEOUT_SECONDS, |secs| secs as u64),
+            ),
+            max_idle_per_instance: var("BLAZE_PROXY_POOL_MAX_IDLE").unwrap_or(DEFAULT_MAX_IDLE),
+            max_instances: var("BLAZE_PROXY_POOL_MAX_INSTANCES")
+                .unwrap_or(DEFAULT_MAX_INSTANCES)
+                .max(1),
+        }
+    }
+}
+
+/// What a client is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientKind {
+    Request,
+    Stream, // WebSockets and event streams, which may go quiet for a long time
+}
+
+#[derive(Debug, Clone)]
+struct InstanceClients {
+    request: reqwest::Client,
+    stream: reqwest::Client,
+}
+
+impl InstanceClients {
+    fn get(&self, kind: ClientKind) -> reqwest::Client {
+        match kind {
+            ClientKind::Request => self.request.clone(),
+            ClientKind::Stream => self.stream.clone(),
+        }
+    }
+}
+
+/// Per instance HTTP clients, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone)]
+pub struct ClientPool {
+    settings: PoolSettings,
+    clients: Arc<Mutex<LruCache<String, InstanceClients>>>,
+}
+
+impl ClientPool {
+    pub fn new(settings: PoolSettings) -> Self {
+        let capacity = NonZeroUsize::new(settings.max_instances).unwrap_or(NonZeroUsize::MIN);
+        ClientPool {
+            settings,
+            clients: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    fn build(&self, kind: ClientKind) -> reqwest::Result<reqwest::Client> {
+        let builder = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .pool_idle_timeout(self.settings.idle_timeout)
+            .pool_max_idle_per_host(self.settings.max_idle_per_instance)
+            .tcp_keepalive(TCP_KEEPALIVE);
+        match kind {
+            // No total timeout, it would cut off long responses, only stalls are timed out
+            ClientKind::Request => builder.read_timeout(READ_TIMEOUT).build(),
+            ClientKind::Stream => builder.build(),
+        }
+    }
+
+    /// The instance's client of that kind, made on first use
+    pub fn client(&self, instance_id: &str, kind: ClientKind) -> reqwest::Result<reqwest::Client> {
+        {
+            let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(existing) = clients.get(instance_id) {
+                return Ok(existing.get(kind));
+            }
+        }
+
+        // Built without the lock held, another request may have beaten us to it meanwhile
+        let built = InstanceClients {
+            request: self.build(ClientKind::Request)?,
+            stream: self.build(ClientKind::Stream)?,
+        };
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(clients
+            .get_or_insert(instance_id.to_string(), || built)
+            .get(kind))
+    }
+
+    /// Drops the instance's clients, their idle connections close once requests using them end
+    pub fn forget(&self, instance_id: &str) {
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        clients.pop(instance_id);
+    }
+}
+
+#[test]
+fn test_client_pool_per_instance() {
+    let pool = ClientPool::new(PoolSettings {
+        idle_timeout: Duration::from_secs(90),
+        max_idle_per_instance: 4,
+        max_instances: 2,
+    });
+    let cached = |pool: &ClientPool, instance_id: &str| {
+        let clients = pool.clients.lock().unwrap();
+        clients.contains(instance_id)
+    };
+
+    pool.client("a", ClientKind::Request).unwrap();
+    pool.client("a", ClientKind::Stream).unwrap();
+    pool.client("b", ClientKind::Request).unwrap();
+    assert!(cached(&pool, "a") && cached(&pool, "b"));
+    assert_eq!(pool.clients.lock().unwrap().len(), 2);
+
+    // Least recently used goes first
+    pool.client("a", ClientKind::Request).unwrap();
+    pool.client("c", ClientKind::Request).unwrap();
+    assert!(cached(&pool, "a") && cached(&pool, "c"));
+    assert!(!cached(&pool, "b"));
+
+    pool.forget("a");
+    assert!(!cached(&pool, "a"));
+}
@@ -1,3 +1,33 @@
+//! # Host port allocation
+//!
+//! In external (bridge) mode every container publishes its port on the host. The port starts as
+//! a hash of the instance id, and when that one is taken (another container, any host process)
+//! the allocator probes the next ones. Whatever gets picked is saved under
+//! `get_data_path()/ports.json`, and goes into the instance's route (see `routing`) once the
+//! container is up.
+
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use crate::warn;
+use anyhow::Result;
+use std::sync::OnceLock;
+
+const PORT_RANGE_START: u16 = 50000;
+const PORT_RANGE_SIZE: u16 = 10000;
+
+static PORT_STORE: OnceLock<DataStore<String, u16>> = OnceLock::new();
+
+/// Allocated host ports: instance_id -> host port
+pub fn get_port_store() -> DataStore<String, u16> {
+    PORT_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("ports.json");
+            DataStore::<String, u16>::new(path)
+                .expect("CRASH!! Failed to initialize port datastore")
+        })
+        .clone()
+}
+
 /// Calculate a deterministic port for a given instance_id
 ///
 /// Uses a simple hashing to map instance IDs to ports in the range 50000-59999.
@@ -13,7 +43,80 @@ pub fn calculate_container_port(instance_id: &str) -> u16 {
         .take(8)
         .fold(0u16, |acc, c| acc.wrapping_add(c as u16));
 
-    50000 + (hash % 10000)
+    PORT_RANGE_START + (hash % PORT_RANGE_SIZE)
+}
+
+/// Whether something on the host is already listening on the port
+pub fn is_port_in_use(port: u16) -> bool {
+    std::net::TcpListener::bind(("127.0.0.1", port)).is_err()
+}
+
+/// First port from the instance's hashed port onwards (wrapping in the range) that isn't taken
+/// by another instance's record and isn't in use on the host
+fn find_free_port(instance_id: &str, taken: &[u16], in_use: impl Fn(u16) -> bool) -> Option<u16> {
+    let start = calculate_container_port(instance_id) - PORT_RANGE_START;
+    (0..PORT_RANGE_SIZE)
+        .map(|offset| PORT_RANGE_START + (start + offset) % PORT_RANGE_SIZE)
+        .find(|port| !taken.contains(port) && !in_use(*port))
+}
+
+/// Picks a host port for a container about to be created and records it for the proxy
+/// Keeps the instance's recorded port when it's still free
+pub fn allocate_container_port(instance_id: &str) -> Result<u16> {
+    let store = get_port_store();
+    let key = instance_id.to_string();
+
+    if let Some(port) = store.get(&key)?
+        && !is_port_in_use(port)
+    {
+        return Ok(port);
+    }
+
+    let taken: Vec<u16> = store
+        .entries()?
+        .into_iter()
+        .filter(|(id, _)| id != instance_id)
+        .map(|(_, port)| port)
+        .collect();
+
+    let port = find_free_port(instance_id, &taken, is_port_in_use)
+        .ok_or_else(|| anyhow::anyhow!("No free host port left for {}", instance_id))?;
+
+    if port != calculate_container_port(instance_id) {
+        warn!(
+            "Port {} is taken, using {} for instance {}",
+            calculate_container_port(instance_id),
+            port,
+            instance_id
+        );
+    }
+
+    store.insert_save(key, port)?;
+    Ok(port)
+}
+
+/// Host port of an instance without a route, from the allocations
+/// Reloads them once on a miss (the service may have just allocated it), then falls back
+/// to the hashed port for containers created before the record existed
+pub fn resolve_container_port(instance_id: &str) -> u16 {
+    let store = get_port_store();
+    let key = instance_id.to_string();
+
+    if let Ok(Some(port)) = store.get(&key) {
+        return port;
+    }
+    if store.reload().is_ok()
+        && let Ok(Some(port)) = store.get(&key)
+    {
+        return port;
+    }
+    calculate_container_port(instance_id)
+}
+
+/// Drops the instance's routing record (when the container is removed for good)
+pub fn release_container_port(instance_id: &str) -> Result<()> {
+    get_port_store().delete(&instance_id.to_string())?;
+    Ok(())
 }
 
 #[test]
@@ -24,7 +127,7 @@ fn test_deterministic_port_assignment() {
     let port2 = calculate_container_port(instance_id);
 
     assert_eq!(port1, port2);
-    assert!(port1 >= 50000 && port1 < 60000);
+    assert!((50000..60000).contains(&port1));
 }
 
 #[test]
@@ -35,6 +138,20 @@ fn test_different_ids_different_ports() {
     let port1 = calculate_container_port(id1);
     let port2 = calculate_container_port(id2);
 
-    assert!(port1 >= 50000 && port1 < 60000);
-    assert!(port2 >= 50000 && port2 < 60000);
+    assert!((50000..60000).contains(&port1));
+    assert!((50000..60000).contains(&port2));
+}
+
+#[test]
+fn test_find_free_port_skips_conflicts() {
+    let instance_id = "a1a70763676476be92f8d80c5ed9ab74";
+    let preferred = calculate_container_port(instance_id);
+
+    assert_eq!(find_free_port(instance_id, &[], |_| false), Some(preferred));
+
+    // Taken by another instance's record, then by some host process
+    let next = find_free_port(instance_id, &[preferred], |port| port == preferred + 1);
+    assert_eq!(next, Some(preferred + 2));
+
+    assert_eq!(find_free_port(instance_id, &[], |_| true), None);
 }
@@ -0,0 +1,295 @@
+//! # Docker pre-flight checks
+//!
+//! Before tenants get routed to a container host, an operator can check that the control plane
+//! can actually use it: connect to Docker, pull the BlazeDB image, create a volume and run a
+//! throwaway container. Every step reports its own result, the first failure skips the rest, and
+//! whatever was created is removed again.
+//!
+//! Hosts are the local Docker daemon plus `BLAZE_DOCKER_HOSTS`, a comma separated list of
+//! `name=url` where url is `tcp://`/`http://` (Docker API over TCP), `https://` (over TLS, see
+//! `connect_docker_host`) or `unix://` (a socket path, Podman's too), e.g.
+//! `edge-1=tcp://10.0.0.5:2375`. Scratch resources are named `blz-preflight-*`, so they
+//! never count as tenant containers.
+
+use crate::server::container::{
+    BLAZEDB_IMAGE, BLAZEDB_IMAGE_TAG, connect_docker, connect_docker_host,
+    pull_blazedb_image_checked,
+};
+use crate::server::schema::{HostPreflight, PreflightStep};
+use crate::warn;
+use anyhow::Result;
+use bollard::Docker;
+use bollard::config::VolumeCreateRequest;
+use bollard::models::{ContainerCreateBody, HostConfig, Mount, MountTypeEnum};
+use bollard::query_parameters::{
+    CreateContainerOptions, RemoveContainerOptions, RemoveVolumeOptions, StartContainerOptions,
+};
+use std::future::Future;
+use std::time::Instant;
+
+/// Seconds before a call to a remote Docker API is given up
+const REMOTE_DOCKER_TIMEOUT: u64 = 60;
+
+/// A Docker host the control plane knows about, `url` is None for the local daemon
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisteredHost {
+    pub name: String,
+    pub url: Option<String>,
+}
+
+/// Parses `name=url` entries, invalid entries are logged and skipped
+fn parse_docker_hosts(value: &str) -> Vec<RegisteredHost> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .filter_map(|entry| {
+            let parsed = entry
+                .trim()
+                .split_once('=')
+                .map(|(name, url)| (name.trim(), url.trim()))
+                .filter(|(name, url)| {
+                    !name.is_empty()
+                        && ["tcp://", "http://", "https://", "unix://"]
+                            .iter()
+                            .any(|scheme| url.starts_with(scheme))
+                })
+                .map(|(name, url)| RegisteredHost {
+                    name: name.to_string(),
+                    url: Some(url.to_string()),
+                });
+            if parsed.is_none() {
+                warn!("Ignoring invalid BLAZE_DOCKER_HOSTS entry: {}", entry);
+            }
+            parsed
+        })
+        .collect()
+}
+
+/// The local host plus everything in `BLAZE_DOCKER_HOSTS`
+pub fn get_registered_hosts() -> Vec<RegisteredHost> {
+    dotenv::dotenv().ok();
+
+    let mut hosts = vec![RegisteredHost {
+        name: "local".to_string(),
+        url: None,
+    }];
+    if let Ok(value) = std::env::var("BLAZE_DOCKER_HOSTS") {
+        hosts.extend(
+            parse_docker_hosts(&value)
+                .into_iter()
+                .filter(|h| h.name != "local"),
+        );
+    }
+    hosts
+}
+
+fn connect_host(host: &RegisteredHost) -> Result<Docker> {
+    match host.url.as_deref() {
+        None => Ok(connect_docker()?),
+        Some(url) => Ok(connect_docker_host(url, REMOTE_DOCKER_TIMEOUT)?),
+    }
+}
+
+/// Runs one step and records how it went
+async fn run_step<F, Fut>(steps: &mut Vec<PreflightStep>, name: &str, step: F) -> bool
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<String>>,
+{
+    let started = Instant::now();
+    let result = step().await;
+    let ok = result.is_ok();
+    steps.push(PreflightStep {
+        name: name.to_string(),
+        ok,
+        skipped: false,
+        detail: result.unwrap_or_else(|e| e.to_string()),
+        duration_ms: started.elapsed().as_millis() as u64,
+    });
+    ok
+}
+
+fn skip_step(steps: &mut Vec<PreflightStep>, name: &str) {
+    steps.push(PreflightStep {
+        name: name.to_string(),
+        ok: false,
+        skipped: true,
+        detail: "Skipped after an earlier failure".to_string(),
+        duration_ms: 0,
+    });
+}
+
+/// Runs every check against one host
+pub async fn preflight_host(host: &RegisteredHost) -> HostPreflight {
+    let mut steps = Vec::new();
+    let suffix = hex::encode(rand::random::<[u8; 4]>());
+    let container_name = format!("blz-preflight-{}", suffix);
+    let volume_name = format!("blz_preflight_{}", suffix);
+
+    let docker = match connect_host(host) {
+        Ok(docker) => Some(docker),
+        Err(e) => {
+            steps.push(PreflightStep {
+                name: "connect".to_string(),
+                ok: false,
+                skipped: false,
+                detail: e.to_string(),
+                duration_ms: 0,
+            });
+            None
+        }
+    };
+
+    let mut ok = docker.is_some();
+    if let Some(docker) = &docker {
+        ok = run_step(&mut steps, "connect", || async {
+            docker.ping().await?;
+            let version = docker.version().await?;
+            // Podman answers the same API, it only shows in the components
+            let is_podman = version
+                .components
+                .iter()
+                .flatten()
+                .any(|c| c.name.starts_with("Podman"));
+            let engine = if is_podman { "Podman" } else { "Docker" };
+            Ok(format!(
+                "{} {} (API {})",
+                engine,
+                version.version.unwrap_or_default(),
+                version.api_version.unwrap_or_default()
+            ))
+        })
+        .await;
+
+        ok = ok
+            && run_step(&mut steps, "pull_image", || async {
+                pull_blazedb_image_checked(docker).await?;
+                Ok(format!("Pulled {}:{}", BLAZEDB_IMAGE, BLAZEDB_IMAGE_TAG))
+            })
+            .await;
+
+        ok = ok
+            && run_step(&mut steps, "create_volume", || async {
+                docker
+                    .create_volume(VolumeCreateRequest {
+                        name: Some(volume_name.clone()),
+                        ..Default::default()
+                    })
+                    .await?;
+                Ok(format!("Created {}", volume_name))
+            })
+            .await;
+
+        ok = ok
+            && run_step(&mut steps, "run_container", || async {
+                let config = ContainerCreateBody {
+                    image: Some(format!("{}:{}", BLAZEDB_IMAGE, BLAZEDB_IMAGE_TAG)),
+                    host_config: Some(HostConfig {
+                        mounts: Some(vec![Mount {
+                            target: Some("/home/blazedb/blaze".to_string()),
+                            source: Some(volume_name.clone()),
+                            typ: Some(MountTypeEnum::VOLUME),
+                            ..Default::default()
+                        }]),
+                        nano_cpus: Some(250_000_000),
+                        memory: Some(256 * 1024 * 1024),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                let options = CreateContainerOptions {
+                    name: Some(container_name.clone()),
+                    ..Default::default()
+                };
+                docker.create_container(Some(options), config).await?;
+                docker
+                    .start_container(&container_name, None::<StartContainerOptions>)
+                    .await?;
+
+                let running = docker
+                    .inspect_container(&container_name, None)
+                    .await?
+                    .state
+                    .and_then(|s| s.running)
+                    .unwrap_or(false);
+                if !running {
+                    return Err(anyhow::anyhow!("Container exited right after starting"));
+                }
+                Ok(format!("Started {}", container_name))
+            })
+            .await;
+
+        // Always clean up, even after a failure somewhere above
+        let cleanup_ok = run_step(&mut steps, "cleanup", || async {
+            let options = RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            };
+            // Both may not exist if an earlier step failed
+            let _ = docker
+                .remove_container(&container_name, Some(options))
+                .await;
+            let _ = docker
+                .remove_volume(&volume_name, Some(RemoveVolumeOptions { force: true }))
+                .await;
+            Ok("Removed scratch container and volume".to_string())
+        })
+        .await;
+        ok = ok && cleanup_ok;
+    }
+
+    for name in ["connect", "pull_image", "create_volume", "run_container"] {
+        if !steps.iter().any(|s| s.name == name) {
+            skip_step(&mut steps, name);
+        }
+    }
+    steps.sort_by_key(|s| {
+        [
+            "connect",
+            "pull_image",
+            "create_volume",
+            "run_container",
+            "cleanup",
+        ]
+        .iter()
+        .position(|n| *n == s.name)
+    });
+
+    HostPreflight {
+        host: host.name.clone(),
+        ok,
+        steps,
+    }
+}
+
+/// Runs the checks on every registered host (or just `only`), one host at a time
+pub async fn run_preflight(only: Option<&str>) -> Result<Vec<HostPreflight>> {
+    let hosts: Vec<RegisteredHost> = get_registered_hosts()
+        .into_iter()
+        .filter(|h| only.is_none_or(|name| h.name == name))
+        .collect();
+
+    if hosts.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No registered host named {}",
+            only.unwrap_or("")
+        ));
+    }
+
+    let mut results = Vec::with_capacity(hosts.len());
+    for host in &hosts {
+        results.push(preflight_host(host).await);
+    }
+    Ok(results)
+}
+
+#[test]
+fn test_parse_docker_hosts() {
+    let hosts = parse_docker_hosts(
+        "edge-1=tcp://10.0.0.5:2375, edge-2 = unix:///var/run/docker.sock,broken,x=ftp://y",
+    );
+    assert_eq!(hosts.len(), 2);
+    assert_eq!(hosts[0].name, "edge-1");
+    assert_eq!(hosts[0].url.as_deref(), Some("tcp://10.0.0.5:2375"));
+    assert_eq!(hosts[1].name, "edge-2");
+}
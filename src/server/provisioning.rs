@@ -0,0 +1,232 @@
+//! # Provisioning
+//!
+//! Verification hands out an instance id right away and spawns the container in the background,
+//! so a Docker hiccup at that moment used to leave the user with an id and nothing behind it.
+//! Every container that should exist is now recorded as a `DesiredContainer` in
+//! `get_data_path()/provisioning.json` before it's spawned.
+//!
+//! A background loop goes over the records and spawns whatever is missing or stopped (restarts
+//! what's unhealthy). Every attempt waits longer for the next one, 15 seconds doubling up to 30
+//! minutes, and a record is only dropped once its container runs healthy. Records of deleted
+//! users, instances they no longer own and suspended users are dropped as well, those containers
+//! are meant to be gone or stopped.
+//!
+//! Verification holds its response up to `BLAZE_READINESS_WAIT_SECONDS` (20 by default) for the
+//! first container to come up, so the API key it hands out works right away. When it takes
+//! longer the response says "provisioning" and the client polls `GET /v1/blz/instance/status`.
+
+use crate::server::container::{
+    ContainerSpec, get_instance_state, restart_blazedb_container, spawn_blazedb_container,
+};
+use crate::server::schema::{BillingStatus, DesiredContainer};
+use crate::server::service::{get_all_users, get_data_path, get_user};
+use crate::server::storage::DataStore;
+use crate::{info, warn};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::sync::OnceLock;
+
+/// Wait after the first attempt, doubled by every further one
+const BASE_BACKOFF_SECONDS: i64 = 15;
+const MAX_BACKOFF_SECONDS: i64 = 30 * 60;
+
+/// How soon a container that's still starting is looked at again
+const STARTING_RECHECK_SECONDS: i64 = 15;
+
+const DEFAULT_READINESS_WAIT_SECONDS: u64 = 20;
+
+static PROVISIONING_STORE: OnceLock<DataStore<String, DesiredContainer>> = OnceLock::new();
+
+/// Containers that should be running, keyed by instance id
+pub fn get_provisioning_store() -> DataStore<String, DesiredContainer> {
+    PROVISIONING_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("provisioning.json");
+            DataStore::<String, DesiredContainer>::new(path)
+                .expect("CRASH!! Failed to initialize provisioning datastore")
+        })
+        .clone()
+}
+
+/// How long verification waits for the new container before answering, 0 doesn't wait
+pub fn readiness_wait() -> std::time::Duration {
+    dotenv::dotenv().ok();
+    let seconds = std::env::var("BLAZE_READINESS_WAIT_SECONDS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_READINESS_WAIT_SECONDS);
+    std::time::Duration::from_secs(seconds)
+}
+
+/// Seconds to wait after the `attempts`th attempt
+fn backoff_seconds(attempts: u32) -> i64 {
+    let doublings = attempts.saturating_sub(1).min(16);
+    (BASE_BACKOFF_SECONDS << doublings).min(MAX_BACKOFF_SECONDS)
+}
+
+fn is_due(desired: &DesiredContainer, now: DateTime<Utc>) -> bool {
+    DateTime::parse_from_rfc3339(&desired.next_attempt_at)
+        .map_or(true, |t| t.with_timezone(&Utc) <= now)
+}
+
+/// Records that the instance should be running, the loop retries it until it is
+pub fn request_container(instance_id: &str, email: &str) -> Result<()> {
+    let now = Utc::now();
+    get_provisioning_store().insert_save(
+        instance_id.to_string(),
+        DesiredContainer {
+            instance_id: instance_id.to_string(),
+            email: email.to_string(),
+            attempts: 0,
+            // Leaves room for the first attempt, made right away by the caller
+            next_attempt_at: (now + chrono::Duration::seconds(BASE_BACKOFF_SECONDS)).to_rfc3339(),
+            last_error: None,
+            created_at: now.to_rfc3339(),
+        },
+    )?;
+    Ok(())
+}
+
+/// Stops retrying the instance, e.g. when its owner stopped it on purpose
+pub fn forget_container(instance_id: &str) -> Result<()> {
+    get_provisioning_store().delete(&instance_id.to_string())?;
+    Ok(())
+}
+
+/// One step towards a healthy container, returns the record to keep or None once it's done
+async fn reconcile_container(
+    mut desired: DesiredContainer,
+    now: DateTime<Utc>,
+) -> Result<Option<DesiredContainer>> {
+    let Some(user) = get_user(&desired.email)
+        .await?
+        .filter(|u| u.is_verified && u.owns_instance(&desired.instance_id))
+    else {
+        info!(
+            "Dropping provisioning of {}, its owner or instance is gone",
+            desired.instance_id
+        );
+        return Ok(None);
+    };
+    if user.billing_status == BillingStatus::Suspended {
+        return Ok(None); // Stopped by dunning, it starts again once the invoice is paid
+    }
+
+    let state = get_instance_state(&desired.instance_id).await;
+    match state {
+        Ok("running") => {
+            info!(
+                "Container of {} is healthy after {} attempt(s)",
+                desired.instance_id,
+                desired.attempts.max(1)
+            );
+            return Ok(None);
+        }
+        Ok("starting") | Ok("restarting") => {
+            desired.next_attempt_at =
+                (now + chrono::Duration::seconds(STARTING_RECHECK_SECONDS)).to_rfc3339();
+            return Ok(Some(desired));
+        }
+        _ => {}
+    }
+
+    let spec = ContainerSpec::for_user(&user);
+    let attempt = match state {
+        Ok("unhealthy") => restart_blazedb_container(&desired.instance_id, &spec).await,
+        Ok(_) => spawn_blazedb_container(&desired.instance_id, &spec).await,
+        Err(e) => Err(e),
+    };
+
+    // Successful spawns count too, a container that keeps crashing backs off the same way
+    desired.attempts += 1;
+    desired.next_attempt_at =
+        (now + chrono::Duration::seconds(backoff_seconds(desired.attempts))).to_rfc3339();
+    desired.last_error = attempt.err().map(|e| e.to_string());
+    if let Some(e) = &desired.last_error {
+        warn!(
+            "Spawning container {} for {} failed (attempt {}), retrying at {}: {}",
+            desired.instance_id, desired.email, desired.attempts, desired.next_attempt_at, e
+        );
+    }
+    Ok(Some(desired))
+}
+
+async fn reconcile_and_store(desired: DesiredContainer, now: DateTime<Utc>) -> Result<()> {
+    let store = get_provisioning_store();
+    let instance_id = desired.instance_id.clone();
+    match reconcile_container(desired, now).await? {
+        Some(desired) => store.insert_save(instance_id, desired)?,
+        None => store.delete(&instance_id)?,
+    };
+    Ok(())
+}
+
+/// Makes an attempt for the instance right away, whenever the next one was due
+pub async fn provision_container(instance_id: &str) -> Result<()> {
+    let Some(desired) = get_provisioning_store().get(&instance_id.to_string())? else {
+        return Ok(());
+    };
+    reconcile_and_store(desired, Utc::now()).await
+}
+
+/// Works on every record that is due, returns how many are still waiting for a healthy container
+/// This is called periodically via a background task
+pub async fn reconcile_desired_containers() -> Result<usize> {
+    let now = Utc::now();
+    let store = get_provisioning_store();
+    for desired in store.values()?.into_iter().filter(|d| is_due(d, now)) {
+        let instance_id = desired.instance_id.clone();
+        if let Err(e) = reconcile_and_store(desired, now).await {
+            warn!("Provisioning of {} failed: {}", instance_id, e);
+        }
+    }
+    Ok(store.len()?)
+}
+
+/// Records every verified user whose primary container is missing, e.g. from a failed spawn
+/// before provisioning was tracked. Called once at startup
+pub async fn request_missing_containers() -> Result<usize> {
+    let store = get_provisioning_store();
+    let mut requested = 0;
+    for user in get_all_users().await? {
+        if !user.is_verified
+            || user.instance_id.is_empty()
+            || user.billing_status == BillingStatus::Suspended
+            || store.contains_key(&user.instance_id)?
+        {
+            continue;
+        }
+        // Docker being down isn't a reason to record anything
+        if let Ok("missing") = get_instance_state(&user.instance_id).await {
+            request_container(&user.instance_id, &user.email)?;
+            requested += 1;
+        }
+    }
+    if requested > 0 {
+        info!("Found {} missing container(s) to provision", requested);
+    }
+    Ok(requested)
+}
+
+#[test]
+fn test_backoff_seconds() {
+    assert_eq!(backoff_seconds(1), 15);
+    assert_eq!(backoff_seconds(2), 30);
+    assert_eq!(backoff_seconds(5), 240);
+    assert_eq!(backoff_seconds(9), MAX_BACKOFF_SECONDS);
+    assert_eq!(backoff_seconds(u32::MAX), MAX_BACKOFF_SECONDS);
+
+    let now = Utc::now();
+    let mut desired = DesiredContainer {
+        instance_id: "inst".to_string(),
+        email: "alice@example.com".to_string(),
+        attempts: 1,
+        next_attempt_at: (now + chrono::Duration::seconds(15)).to_rfc3339(),
+        last_error: None,
+        created_at: now.to_rfc3339(),
+    };
+    assert!(!is_due(&desired, now));
+    assert!(is_due(&desired, now + chrono::Duration::seconds(16)));
+    desired.next_attempt_at = "garbage".to_string();
+    assert!(is_due(&desired, now)); // Unreadable times don't stall it forever
+}
@@ -0,0 +1,245 @@
+//! # Quotas
+//!
+//! Plans limit how many databases an instance has (`database_no`) and how many vectors each one
+//! holds (`vector_per_db`). The proxy checks both before forwarding a write. It keeps the vector
+//! count of every database of the instances it has seen, taken from the backend's stats endpoint
+//! (at most once a minute per instance) and bumped by the writes it forwards in between.
+//!
+//! Creating a database is a POST to `/v1/blazedb/databases` with the name in `name`, vector
+//! writes name their database in `database` (counted like `metering` counts them). Writes to a
+//! database without a known count aren't checked, and a stats endpoint that doesn't answer leaves
+//! the last known counts in place: the counts are an estimate, BlazeDB has the real ones.
+//!
+//! Writes too big to read before forwarding (see `metering::inspect_limit`) aren't checked, they
+//! are counted as they stream through and only the writes after them are held to the limits.
+
+use crate::server::metering::{WriteScanner, count_vectors};
+use crate::server::schema::{CapabilityLimits, Plans, QuotaExceeded, QuotaKind};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Backend path (under the instance's base URL) serving per-database counts
+pub const BACKEND_STATS_PATH: &str = "/v1/blazedb/stats";
+
+/// Route (without the instance id) that creates a database
+pub const DATABASE_CREATE_ROUTE: &str = "/v1/blazedb/databases";
+
+/// How long counts from the stats endpoint are used before asking again
+const STATS_REFRESH: Duration = Duration::from_secs(60);
+
+/// A write that counts against the plan's limits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuotaWrite {
+    CreateDatabase { name: String },
+    Vectors { database: String, count: u64 },
+}
+
+/// What a POST/PUT to `path` (ending with the instance id) adds, if it counts at all
+pub fn classify_write(path: &str, body: &[u8]) -> Option<QuotaWrite> {
+    let route = path
+        .trim_end_matches('/')
+        .rsplit_once('/')
+        .map(|(head, _)| head)?;
+    let value = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+    let field = |key: &str| value.get(key).and_then(|v| v.as_str()).map(str::to_string);
+
+    if route == DATABASE_CREATE_ROUTE {
+        return field("name").map(|name| QuotaWrite::CreateDatabase { name });
+    }
+    let database = field("database")?;
+    let count = count_vectors(body);
+    (count > 0).then_some(QuotaWrite::Vectors { database, count })
+}
+
+/// What a streamed POST/PUT added, going by what its body scanned to
+pub fn classify_scanned_write(scanner: &WriteScanner) -> Option<QuotaWrite> {
+    let database = scanner.database()?.to_string();
+    let count = scanner.vectors();
+    (count > 0).then_some(QuotaWrite::Vectors { database, count })
+}
+
+/// Reads database name -> vector count from the stats document
+/// Takes `{"databases": {"name": count}}` or `{"databases": [{"name": .., "vectors": count}]}`
+pub fn parse_stats(doc: &serde_json::Value) -> Option<HashMap<String, u64>> {
+    match doc.get("databases")? {
+        serde_json::Value::Object(databases) => Some(
+            databases
+                .iter()
+                .filter_map(|(name, count)| Some((name.clone(), count.as_u64()?)))
+                .collect(),
+        ),
+        serde_json::Value::Array(databases) => Some(
+            databases
+                .iter()
+                .filter_map(|db| {
+                    let name = db.get("name")?.as_str()?.to_string();
+                    let count = db
+                        .get("vectors")
+                        .or_else(|| db.get("vector_count"))
+                        .and_then(|v| v.as_u64())
+                        .unwrap_or(0);
+                    Some((name, count))
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+/// The limits of a plan
+pub fn plan_limits(plan: &Plans) -> CapabilityLimits {
+    CapabilityLimits {
+        max_databases: plan.features.database_no,
+        max_vectors_per_db: plan.features.vector_per_db,
+        max_streams: plan.features.max_streams,
+        max_request_bytes: plan.features.max_request_mb * 1024 * 1024,
+        max_response_bytes: plan.features.max_response_mb * 1024 * 1024,
+    }
+}
+
+/// Checks a write against the plan's limits, given the instance's current counts
+pub fn check_quota(
+    databases: &HashMap<String, u64>,
+    limits: &CapabilityLimits,
+    write: &QuotaWrite,
+) -> Result<(), QuotaExceeded> {
+    match write {
+        QuotaWrite::CreateDatabase { name } => {
+            let limit = limits.max_databases as u64;
+            let current = databases.len() as u64;
+            if !databases.contains_key(name) && current >= limit {
+                return Err(QuotaExceeded {
+                    kind: QuotaKind::Databases,
+                    database: name.clone(),
+                    limit,
+                    current,
+                    requested: 1,
+                });
+            }
+        }
+        QuotaWrite::Vectors { database, count } => {
+            let limit = limits.max_vectors_per_db as u64;
+            if let Some(&current) = databases.get(database)
+                && current + count > limit
+            {
+                return Err(QuotaExceeded {
+                    kind: QuotaKind::VectorsPerDatabase,
+                    database: database.clone(),
+                    limit,
+                    current,
+                    requested: *count,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct InstanceCounts {
+    databases: HashMap<String, u64>,
+    checked_at: Instant,
+}
+
+/// Per-instance database counts, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone, Default)]
+pub struct QuotaTracker {
+    instances: Arc<Mutex<HashMap<String, InstanceCounts>>>,
+}
+
+impl QuotaTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The instance's counts, None when they're due for a refresh from the stats endpoint
+    pub fn fresh_counts(&self, instance_id: &str) -> Option<HashMap<String, u64>> {
+        let instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        instances
+            .get(instance_id)
+            .filter(|c| c.checked_at.elapsed() < STATS_REFRESH)
+            .map(|c| c.databases.clone())
+    }
+
+    /// Stores refreshed counts and returns what to check against
+    /// `None` (the stats endpoint didn't answer) keeps the last known counts until the next refresh
+    pub fn refresh(
+        &self,
+        instance_id: &str,
+        databases: Option<HashMap<String, u64>>,
+    ) -> HashMap<String, u64> {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let counts = instances
+            .entry(instance_id.to_string())
+            .or_insert_with(|| InstanceCounts {
+                databases: HashMap::new(),
+                checked_at: Instant::now(),
+            });
+        if let Some(databases) = databases {
+            counts.databases = databases;
+        }
+        counts.checked_at = Instant::now();
+        counts.databases.clone()
+    }
+
+    /// Counts a write the backend accepted
+    pub fn record(&self, instance_id: &str, write: &QuotaWrite) {
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(counts) = instances.get_mut(instance_id) else {
+            return;
+        };
+        match write {
+            QuotaWrite::CreateDatabase { name } => {
+                counts.databases.entry(name.clone()).or_insert(0);
+            }
+            QuotaWrite::Vectors { database, count } => {
+                if let Some(current) = counts.databases.get_mut(database) {
+                    *current += count;
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_check_quota() {
+    use crate::server::plans::builtin_plan;
+
+    let limits = plan_limits(&builtin_plan("free")); // 5 databases, 5K vectors each
+    let stats = serde_json::json!({ "databases": [
+        { "name": "a", "vectors": 4_990 }, { "name": "b", "vectors": 0 },
+        { "name": "c" }, { "name": "d" }, { "name": "e" }
+    ]});
+    let databases = parse_stats(&stats).unwrap();
+    assert_eq!(databases["a"], 4_990);
+
+    let create = classify_write(
+        "/v1/blazedb/databases/inst",
+        br#"{"name":"f","dimension":3}"#,
+    )
+    .unwrap();
+    let exceeded = check_quota(&databases, &limits, &create).unwrap_err();
+    assert_eq!(exceeded.kind, QuotaKind::Databases);
+    assert_eq!((exceeded.limit, exceeded.current), (5, 5));
+
+    let write = classify_write(
+        "/v1/blazedb/insert/inst",
+        br#"{"database":"a","vectors":[[1],[2],[3]]}"#,
+    )
+    .unwrap();
+    assert!(check_quota(&databases, &limits, &write).is_ok());
+
+    let tracker = QuotaTracker::new();
+    tracker.refresh("inst", Some(databases));
+    for _ in 0..3 {
+        tracker.record("inst", &write);
+    }
+    let databases = tracker.fresh_counts("inst").unwrap();
+    let exceeded = check_quota(&databases, &limits, &write).unwrap_err();
+    assert_eq!(exceeded.kind, QuotaKind::VectorsPerDatabase);
+    assert_eq!(exceeded.current, 4_999);
+
+    // Not a quota write: no database named
+    assert!(classify_write("/v1/blazedb/insert/inst", br#"{"vectors":[[1]]}"#).is_none());
+}
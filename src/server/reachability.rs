@@ -0,0 +1,177 @@
+//! # Instance reachability
+//!
+//! Docker only knows whether a container runs and passes its health check, the proxy knows
+//! whether requests actually got through to it. The proxy records every forwarded request as a
+//! success or a failure (couldn't connect, the connection broke) per instance, and merges them
+//! into `get_data_path()/reachability.json` periodically. The service only reads the file, to
+//! tell users why their database looks down (`GET /v1/blz/instance/health`).
+//!
+//! It's only as fresh as the last request: an instance nobody sent anything to is "unknown".
+
+use crate::server::schema::InstanceReachability;
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+static REACHABILITY_STORE: OnceLock<DataStore<String, InstanceReachability>> = OnceLock::new();
+
+/// The proxy's view of every instance it forwarded to, keyed by instance id
+pub fn get_reachability_store() -> DataStore<String, InstanceReachability> {
+    REACHABILITY_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("reachability.json");
+            DataStore::<String, InstanceReachability>::new(path)
+                .expect("CRASH!! Failed to initialize reachability datastore")
+        })
+        .clone()
+}
+
+/// Successes and failures not flushed yet, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone, Default)]
+pub struct ReachabilityTracker {
+    pending: Arc<Mutex<HashMap<String, InstanceReachability>>>,
+}
+
+impl ReachabilityTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a request that got a response from the instance
+    pub fn record_success(&self, instance_id: &str) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = pending.entry(instance_id.to_string()).or_default();
+        entry.last_success_at = Some(Utc::now().to_rfc3339());
+        entry.consecutive_failures = 0;
+    }
+
+    /// Records a request that never got through to the instance
+    pub fn record_failure(&self, instance_id: &str, error: &str) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = pending.entry(instance_id.to_string()).or_default();
+        entry.last_failure_at = Some(Utc::now().to_rfc3339());
+        entry.last_error = Some(error.to_string());
+        entry.consecutive_failures += 1;
+    }
+
+    fn drain(&self) -> Vec<(String, InstanceReachability)> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.drain().collect()
+    }
+
+    /// Merges what was recorded since the last flush into the store and saves it
+    pub fn flush(&self, store: &DataStore<String, InstanceReachability>) -> Result<()> {
+        let drained = self.drain();
+        if drained.is_empty() {
+            return Ok(());
+        }
+
+        for (instance_id, recorded) in drained {
+            let mut reachability = store.get(&instance_id)?.unwrap_or_default();
+            merge_reachability(&mut reachability, recorded);
+            store.insert_mem(instance_id, reachability)?;
+        }
+
+        Ok(store.save_to_disk()?)
+    }
+}
+
+fn merge_reachability(stored: &mut InstanceReachability, recorded: InstanceReachability) {
+    // Failures recorded after a success restart the count, otherwise they add up
+    if recorded.last_success_at.is_some() {
+        stored.last_success_at = recorded.last_success_at;
+        stored.consecutive_failures = recorded.consecutive_failures;
+    } else {
+        stored.consecutive_failures += recorded.consecutive_failures;
+    }
+    if recorded.last_failure_at.is_some() {
+        stored.last_failure_at = recorded.last_failure_at;
+        stored.last_error = recorded.last_error;
+    }
+}
+
+/// The instance as the proxy last saw it (reloads the store, the proxy is the one writing it)
+pub fn get_reachability(instance_id: &str) -> Result<Option<InstanceReachability>> {
+    let store = get_reachability_store();
+    store.reload()?;
+    Ok(store.get(&instance_id.to_string())?)
+}
+
+/// "reachable" or "unreachable" going by the latest request, "unknown" without any
+pub fn reachability_verdict(reachability: Option<&InstanceReachability>) -> &'static str {
+    let parse = |t: &Option<String>| {
+        t.as_deref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc))
+    };
+    let Some(reachability) = reachability else {
+        return "unknown";
+    };
+    match (
+        parse(&reachability.last_success_at),
+        parse(&reachability.last_failure_at),
+    ) {
+        (Some(success), Some(failure)) if failure > success => "unreachable",
+        (Some(_), _) => "reachable",
+        (None, Some(_)) => "unreachable",
+        (None, None) => "unknown",
+    }
+}
+
+/// What the user should make of the container state and the proxy's verdict
+pub fn diagnose(state: &str, verdict: &str) -> String {
+    match (state, verdict) {
+        ("missing", _) => {
+            "Your instance has no container, it's still being provisioned or was removed"
+        }
+        ("stopped", _) => "Your instance is stopped, start it with /v1/blz/instance/start",
+        ("starting", _) | ("restarting", _) => "Your instance is starting, give it a minute",
+        ("unhealthy", _) => {
+            "Your instance is failing its health check, try /v1/blz/instance/restart"
+        }
+        (_, "unreachable") => {
+            "Your instance is running but the proxy couldn't reach it, try /v1/blz/instance/restart"
+        }
+        _ => "Your instance is up",
+    }
+    .to_string()
+}
+
+#[test]
+fn test_reachability_merge_and_verdict() {
+    let tracker = ReachabilityTracker::new();
+    assert_eq!(reachability_verdict(None), "unknown");
+
+    tracker.record_failure("inst", "connection refused");
+    tracker.record_failure("inst", "connection refused");
+    let mut stored = InstanceReachability::default();
+    for (_, recorded) in tracker.drain() {
+        merge_reachability(&mut stored, recorded);
+    }
+    assert_eq!(stored.consecutive_failures, 2);
+    assert_eq!(reachability_verdict(Some(&stored)), "unreachable");
+
+    // Another failing flush adds up, a success in between starts counting again
+    tracker.record_failure("inst", "connection reset");
+    for (_, recorded) in tracker.drain() {
+        merge_reachability(&mut stored, recorded);
+    }
+    assert_eq!(stored.consecutive_failures, 3);
+    assert_eq!(stored.last_error.as_deref(), Some("connection reset"));
+
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    tracker.record_success("inst");
+    for (_, recorded) in tracker.drain() {
+        merge_reachability(&mut stored, recorded);
+    }
+    assert_eq!(stored.consecutive_failures, 0);
+    assert_eq!(reachability_verdict(Some(&stored)), "reachable");
+    assert!(stored.last_error.is_some()); // Kept for the user to see
+
+    assert_eq!(diagnose("running", "reachable"), "Your instance is up");
+    assert!(diagnose("running", "unreachable").contains("restart"));
+    assert!(diagnose("stopped", "unknown").contains("start"));
+}
@@ -0,0 +1,259 @@
+//! # Plan recommendations
+//!
+//! Compares a user's metered usage (see `metering`) against their plan's vector capacity and
+//! suggests an upgrade when they're close to the limit, or a downgrade when a cheaper plan would
+//! comfortably fit. Optionally emailed once a month (`BLAZE_RECOMMENDATION_EMAILS`, off by default).
+
+use crate::server::mailer::send_mail;
+use crate::server::metering::get_user_usage;
+use crate::server::schema::{PlanRecommendation, Plans, UsageRecord, User};
+use crate::server::service::{get_all_users, get_billing_path};
+use crate::server::storage::DataStore;
+use crate::server::tasks::get_task_registry;
+use crate::{error, info};
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use std::sync::OnceLock;
+
+/// Usage of the current plan's capacity above which we suggest an upgrade
+const UPGRADE_UTILIZATION: f64 = 0.8;
+
+/// A cheaper plan is suggested when usage stays under this share of its capacity
+const DOWNGRADE_UTILIZATION: f64 = 0.5;
+
+const RECOMMENDATION_EMAIL_INTERVAL_DAYS: i64 = 30;
+
+static RECOMMENDATION_EMAILS: OnceLock<DataStore<String, String>> = OnceLock::new();
+
+/// When each user was last emailed a recommendation (email -> RFC 3339)
+fn get_recommendation_email_store() -> DataStore<String, String> {
+    RECOMMENDATION_EMAILS
+        .get_or_init(|| {
+            let path = get_billing_path().join("recommendation_emails.json");
+            DataStore::<String, String>::new(path)
+                .expect("CRASH!! Failed to initialize recommendation email datastore")
+        })
+        .clone()
+}
+
+pub fn is_recommendation_email_enabled() -> bool {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_RECOMMENDATION_EMAILS")
+        .map(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true"))
+        .unwrap_or(false)
+}
+
+/// Suggests a plan from the usage history, `plans` must be sorted cheapest first
+/// Stored vectors are estimated as everything written over the ledger's retention window
+pub fn recommend_plan(
+    current: &Plans,
+    usage: &[UsageRecord],
+    plans: &[Plans],
+    now: DateTime<Utc>,
+) -> PlanRecommendation {
+    let estimated_vectors: u64 = usage.iter().map(|r| r.vectors_written).sum();
+
+    let month_ago = (now - Duration::days(30)).to_rfc3339();
+    let monthly_requests = usage
+        .iter()
+        .filter(|r| r.hour >= month_ago)
+        .map(|r| r.requests)
+        .sum();
+
+    let capacity = current.vector_capacity();
+    let utilization = if capacity == 0 {
+        1.0
+    } else {
+        estimated_vectors as f64 / capacity as f64
+    };
+
+    let fits = |plan: &Plans, share: f64| {
+        estimated_vectors as f64 <= plan.vector_capacity() as f64 * share
+    };
+
+    let (action, recommended, reason) = if utilization >= UPGRADE_UTILIZATION {
+        match plans
+            .iter()
+            .find(|p| p.price_per_month > current.price_per_month && fits(p, UPGRADE_UTILIZATION))
+            .or_else(|| plans.iter().max_by_key(|p| p.price_per_month))
+        {
+            Some(plan) if plan.name != current.name => (
+                "upgrade",
+                plan.clone(),
+                format!(
+                    "You're using about {:.0}% of the {} plan's vector capacity",
+                    utilization * 100.0,
+                    current.name
+                ),
+            ),
+            _ => (
+                "stay",
+                current.clone(),
+                "You're on the largest plan".to_string(),
+            ),
+        }
+    } else {
+        match plans
+            .iter()
+            .find(|p| p.price_per_month < current.price_per_month && fits(p, DOWNGRADE_UTILIZATION))
+        {
+            Some(plan) => (
+                "downgrade",
+                plan.clone(),
+                format!("Your usage fits the {} plan with room to spare", plan.name),
+            ),
+            None => (
+                "stay",
+                current.clone(),
+                format!("The {} plan fits your usage", current.name),
+            ),
+        }
+    };
+
+    PlanRecommendation {
+        action: action.to_string(),
+        current_plan: current.name.clone(),
+        recommended_plan: recommended.name.clone(),
+        reason,
+        estimated_vectors,
+        vector_capacity: capacity,
+        utilization_percent: (utilization * 1000.0).round() / 10.0,
+        monthly_requests,
+        current_monthly_cost: current.price_per_month,
+        projected_monthly_cost: recommended.price_per_month,
+    }
+}
+
+/// Recommendation for one user from their metered usage
+pub async fn get_plan_recommendation(user: &User) -> Result<PlanRecommendation> {
+    let usage = get_user_usage(&user.email)?;
+    Ok(recommend_plan(
+        &user.plans,
+        &usage,
+        &Plans::all(),
+        Utc::now(),
+    ))
+}
+
+fn build_recommendation_email(username: &str, rec: &PlanRecommendation) -> (String, String) {
+    let plain_body = format!(
+        "Hi {},\n\n{}. We recommend the {} plan (${}/month, you pay ${}/month now).\n\nChange plans via POST /v1/billing/checkout.",
+        username,
+        rec.reason,
+        rec.recommended_plan,
+        rec.projected_monthly_cost,
+        rec.current_monthly_cost
+    );
+    let html_body = format!(
+        r#"
+        <html>
+        <body style="font-family: sans-serif;">
+            <h2>A plan that fits you better</h2>
+            <p>Hi {},</p>
+            <p>{}. We recommend the <strong>{}</strong> plan (${}/month, you pay ${}/month now).</p>
+            <p>Change plans via <code>POST /v1/billing/checkout</code>.</p>
+        </body>
+        </html>
+        "#,
+        username,
+        rec.reason,
+        rec.recommended_plan,
+        rec.projected_monthly_cost,
+        rec.current_monthly_cost
+    );
+    (plain_body, html_body)
+}
+
+/// Emails users whose plan doesn't fit their usage, at most once per interval
+/// This is called periodically via a background task
+pub async fn send_recommendation_emails() -> Result<usize> {
+    if !is_recommendation_email_enabled() {
+        return Ok(0);
+    }
+
+    let store = get_recommendation_email_store();
+    let now = Utc::now();
+    let resend_after = now - Duration::days(RECOMMENDATION_EMAIL_INTERVAL_DAYS);
+
+    let mut sent = 0;
+    for user in get_all_users().await?.into_iter().filter(|u| u.is_verified) {
+        // Trials are handled by their own emails
+        if user.trial_expires_at.is_some() {
+            continue;
+        }
+
+        let recently_sent = store
+            .get(&user.email)?
+            .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+            .is_some_and(|t| t > resend_after);
+        if recently_sent {
+            continue;
+        }
+
+        let rec = get_plan_recommendation(&user).await?;
+        if rec.action == "stay" {
+            continue;
+        }
+
+        store.insert_save(user.email.clone(), now.to_rfc3339())?;
+        sent += 1;
+
+        let email = user.email.clone();
+        let (plain_body, html_body) = build_recommendation_email(&user.username, &rec);
+        get_task_registry().spawn("recommendation-mail", |_| async move {
+            let result = tokio::task::spawn_blocking(move || {
+                send_mail(
+                    &email,
+                    "A BlazeDB plan that fits you better",
+                    plain_body,
+                    html_body,
+                )
+                .map_err(|e| (email, e))
+            })
+            .await;
+            match result {
+                Ok(Err((email, e))) => {
+                    error!("Failed to send recommendation email to {}: {}", email, e)
+                }
+                Ok(Ok(())) => {}
+                Err(e) => error!("Recommendation email task failed: {}", e),
+            }
+        });
+    }
+
+    if sent > 0 {
+        info!("Sent {} plan recommendation email(s)", sent);
+    }
+    Ok(sent)
+}
+
+#[test]
+fn test_recommend_plan() {
+    use crate::server::plans::{builtin_plan, builtin_plans};
+    let now = Utc::now();
+    let usage = |vectors: u64| {
+        vec![UsageRecord {
+            hour: now.to_rfc3339(),
+            instance_id: "inst".to_string(),
+            requests: 10,
+            request_bytes: 0,
+            vectors_written: vectors,
+        }]
+    };
+    let plans = builtin_plans();
+
+    // Free holds 25k vectors, 24k is close to the limit
+    let rec = recommend_plan(&builtin_plan("free"), &usage(24_000), &plans, now);
+    assert_eq!(rec.action, "upgrade");
+    assert_eq!(rec.recommended_plan, "Starter");
+    assert_eq!(rec.projected_monthly_cost, 12);
+
+    // Barely using Pro, Free is plenty
+    let rec = recommend_plan(&builtin_plan("pro"), &usage(1_000), &plans, now);
+    assert_eq!(rec.action, "downgrade");
+    assert_eq!(rec.recommended_plan, "Free");
+
+    let rec = recommend_plan(&builtin_plan("starter"), &usage(500_000), &plans, now);
+    assert_eq!(rec.action, "stay");
+    assert_eq!(rec.monthly_requests, 10);
+}
@@ -0,0 +1,407 @@
+//! # Billing reconciliation
+//!
+//! Webhooks get lost and admins change plans by hand, so what we think a user pays for can drift
+//! from what Stripe charges them. The reconciliation lists every Stripe subscription (with its
+//! customer's email), matches them to users and reports each difference as a `BillingDrift`.
+//! Stripe's plan comes from the subscription's price, matched against the `STRIPE_PRICE_*` ids.
+//!
+//! With healing on, Stripe wins: users are moved to the plan Stripe bills them for (or to the
+//! free plan when their subscription ended) and the subscription id, cancel state and period end
+//! are copied over. Paid plans without any subscription and subscriptions of unknown emails are
+//! only reported, those need a human.
+
+use crate::server::billing::{STRIPE_API_BASE, stripe_error, stripe_price_id};
+use crate::server::organizations::is_managed_member;
+use crate::server::schema::{
+    BillingDrift, BillingDriftKind, BillingInterval, Plans, ReconcileReport, SubscriptionState,
+    User,
+};
+use crate::server::secrets::get_secrets;
+use crate::server::service::{change_plan, get_all_users, update_user};
+use crate::{info, warn};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+/// Subscription statuses Stripe still bills
+const LIVE_STATUSES: [&str; 4] = ["active", "trialing", "past_due", "unpaid"];
+
+/// How far apart period ends can be before it counts as drift, renewals land at slightly
+/// different times on both sides
+const PERIOD_TOLERANCE_SECONDS: i64 = 24 * 60 * 60;
+
+/// What we need of a Stripe subscription
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StripeSubscription {
+    pub id: String,
+    pub email: Option<String>,
+    pub status: String,
+    pub plan: Option<(String, BillingInterval)>, // None for prices we don't know
+    pub cancel_at_period_end: bool,
+    pub current_period_end: Option<DateTime<Utc>>,
+    pub created: i64,
+}
+
+impl StripeSubscription {
+    pub fn is_live(&self) -> bool {
+        LIVE_STATUSES.contains(&self.status.as_str())
+    }
+
+    fn describe(&self) -> String {
+        match &self.plan {
+            Some((plan, interval)) => format!("{} {} ({})", plan, interval.as_str(), self.status),
+            None => format!("unknown price ({})", self.status),
+        }
+    }
+}
+
+/// Price id -> (plan name, interval) for every paid plan with a price configured
+fn price_plans() -> HashMap<String, (String, BillingInterval)> {
+    let mut prices = HashMap::new();
+    for plan in Plans::all().iter().filter(|p| p.price_per_month > 0) {
+        for interval in [BillingInterval::Monthly, BillingInterval::Yearly] {
+            if let Ok(price_id) = stripe_price_id(plan, interval) {
+                prices.insert(price_id, (plan.name.clone(), interval));
+            }
+        }
+    }
+    prices
+}
+
+/// Reads a subscription object, with its customer expanded
+fn parse_subscription(
+    object: &serde_json::Value,
+    prices: &HashMap<String, (String, BillingInterval)>,
+) -> Option<StripeSubscription> {
+    let item = object.pointer("/items/data/0");
+    let price_id = item
+        .and_then(|i| i.pointer("/price/id"))
+        .and_then(|v| v.as_str());
+    // Newer API versions moved the period onto the items
+    let period_end = object
+        .get("current_period_end")
+        .or_else(|| item.and_then(|i| i.get("current_period_end")))
+        .and_then(|v| v.as_i64())
+        .and_then(|t| DateTime::from_timestamp(t, 0));
+
+    Some(StripeSubscription {
+        id: object.get("id")?.as_str()?.to_string(),
+        email: object
+            .pointer("/customer/email")
+            .and_then(|v| v.as_str())
+            .map(|e| e.trim().to_lowercase()),
+        status: object.get("status")?.as_str()?.to_string(),
+        plan: price_id.and_then(|id| prices.get(id).cloned()),
+        cancel_at_period_end: object
+            .get("cancel_at_period_end")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        current_period_end: period_end,
+        created: object.get("created").and_then(|v| v.as_i64()).unwrap_or(0),
+    })
+}
+
+/// Every subscription in the Stripe account, any status
+async fn fetch_stripe_subscriptions() -> Result<Vec<StripeSubscription>> {
+    let secret_key = get_secrets().require("STRIPE_SECRET_KEY")?;
+    let prices = price_plans();
+    let client = reqwest::Client::new();
+
+    let mut subscriptions = Vec::new();
+    let mut starting_after: Option<String> = None;
+    loop {
+        let mut query = vec![
+            ("status", "all".to_string()),
+            ("limit", "100".to_string()),
+            ("expand[]", "data.customer".to_string()),
+        ];
+        if let Some(id) = &starting_after {
+            query.push(("starting_after", id.clone()));
+        }
+        let url =
+            reqwest::Url::parse_with_params(&format!("{}/subscriptions", STRIPE_API_BASE), &query)?;
+        let response = client.get(url).bearer_auth(secret_key).send().await?;
+        if !response.status().is_success() {
+            return Err(stripe_error("subscription list", response).await);
+        }
+
+        let page: serde_json::Value = response.json().await?;
+        let data = page
+            .get("data")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+        starting_after = data
+            .last()
+            .and_then(|s| s.get("id"))
+            .and_then(|v| v.as_str())
+            .map(str::to_string);
+        subscriptions.extend(data.iter().filter_map(|s| parse_subscription(s, &prices)));
+
+        let has_more = page
+            .get("has_more")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if !has_more || starting_after.is_none() {
+            break;
+        }
+    }
+    Ok(subscriptions)
+}
+
+/// The subscription that counts for a user: the newest live one, or else the newest ended one
+fn current_subscription(mut subscriptions: Vec<StripeSubscription>) -> Option<StripeSubscription> {
+    subscriptions.sort_by_key(|s| (s.is_live(), s.created));
+    subscriptions.pop()
+}
+
+/// Where the user and their Stripe subscription disagree
+/// `managed` users are billed through their organization's owner, not having one is fine
+pub fn find_drift(
+    user: &User,
+    subscription: Option<&StripeSubscription>,
+    managed: bool,
+) -> Vec<BillingDrift> {
+    let drift = |kind: BillingDriftKind, local: String, stripe: String| BillingDrift {
+        email: user.email.clone(),
+        kind,
+        local,
+        stripe,
+        subscription_id: subscription.map(|s| s.id.clone()),
+        healed: false,
+    };
+    let pays_here = user.plans.price_per_month > 0 && user.trial_expires_at.is_none() && !managed;
+
+    let Some(subscription) = subscription.filter(|s| s.is_live()) else {
+        return match subscription {
+            Some(ended) if pays_here => vec![drift(
+                BillingDriftKind::CanceledInStripe,
+                user.plans.name.clone(),
+                ended.describe(),
+            )],
+            None if pays_here => vec![drift(
+                BillingDriftKind::MissingSubscription,
+                user.plans.name.clone(),
+                "no subscription".to_string(),
+            )],
+            _ => vec![],
+        };
+    };
+
+    let mut drifts = Vec::new();
+    if let Some((plan, _)) = &subscription.plan
+        && !plan.eq_ignore_ascii_case(&user.plans.name)
+    {
+        let kind = if user.plans.price_per_month == 0 {
+            BillingDriftKind::PaidButFree
+        } else {
+            BillingDriftKind::PlanMismatch
+        };
+        drifts.push(drift(
+            kind,
+            user.plans.name.clone(),
+            subscription.describe(),
+        ));
+    }
+    if user.stripe_subscription_id.as_deref() != Some(subscription.id.as_str()) {
+        drifts.push(drift(
+            BillingDriftKind::SubscriptionMismatch,
+            user.stripe_subscription_id
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+            subscription.id.clone(),
+        ));
+    }
+    let cancelling = user.subscription_state == SubscriptionState::CancelAtPeriodEnd;
+    if cancelling != subscription.cancel_at_period_end {
+        drifts.push(drift(
+            BillingDriftKind::CancelStateMismatch,
+            format!("cancel at period end: {}", cancelling),
+            format!(
+                "cancel at period end: {}",
+                subscription.cancel_at_period_end
+            ),
+        ));
+    }
+    let local_end = user
+        .current_period_end
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&Utc));
+    if let Some(stripe_end) = subscription.current_period_end
+        && local_end
+            .is_none_or(|end| (end - stripe_end).num_seconds().abs() > PERIOD_TOLERANCE_SECONDS)
+    {
+        drifts.push(drift(
+            BillingDriftKind::PeriodMismatch,
+            user.current_period_end
+                .clone()
+                .unwrap_or_else(|| "none".to_string()),
+            stripe_end.to_rfc3339(),
+        ));
+    }
+    drifts
+}
+
+/// Makes the user match their Stripe subscription
+async fn heal_user(user: &User, subscription: &StripeSubscription) -> Result<()> {
+    if !subscription.is_live() {
+        if user.plans.price_per_month > 0 {
+            change_plan(&user.email, Plans::default_plan()).await?;
+        }
+        update_user(&user.email, |user| {
+            user.billing_interval = BillingInterval::Monthly;
+            user.current_period_end = None;
+            user.subscription_state = SubscriptionState::Canceled;
+            user.stripe_subscription_id = None;
+        })
+        .await?;
+        return Ok(());
+    }
+
+    if let Some((plan_name, _)) = &subscription.plan
+        && !plan_name.eq_ignore_ascii_case(&user.plans.name)
+    {
+        let plan = Plans::by_name(plan_name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown plan {}", plan_name))?;
+        change_plan(&user.email, plan).await?;
+    }
+    let subscription = subscription.clone();
+    update_user(&user.email, |user| {
+        if let Some((_, interval)) = subscription.plan {
+            user.billing_interval = interval;
+        }
+        if let Some(end) = subscription.current_period_end {
+            user.current_period_end = Some(end.to_rfc3339());
+        }
+        user.subscription_state = if subscription.cancel_at_period_end {
+            SubscriptionState::CancelAtPeriodEnd
+        } else {
+            SubscriptionState::Active
+        };
+        user.stripe_subscription_id = Some(subscription.id);
+    })
+    .await?;
+    Ok(())
+}
+
+/// Compares every user with their Stripe subscription, `heal` brings local state in line
+pub async fn reconcile_billing(heal: bool) -> Result<ReconcileReport> {
+    let subscriptions = fetch_stripe_subscriptions().await?;
+    let subscriptions_checked = subscriptions.len();
+    let users: Vec<User> = get_all_users()
+        .await?
+        .into_iter()
+        .filter(|u| u.is_verified)
+        .collect();
+
+    let mut by_email: HashMap<String, Vec<StripeSubscription>> = HashMap::new();
+    let mut drifts = Vec::new();
+    for subscription in subscriptions {
+        match &subscription.email {
+            Some(email) if users.iter().any(|u| u.email == *email) => by_email
+                .entry(email.clone())
+                .or_default()
+                .push(subscription),
+            _ if subscription.is_live() => drifts.push(BillingDrift {
+                email: subscription.email.clone().unwrap_or_default(),
+                kind: BillingDriftKind::UnknownCustomer,
+                local: "no user".to_string(),
+                stripe: subscription.describe(),
+                subscription_id: Some(subscription.id.clone()),
+                healed: false,
+            }),
+            _ => {}
+        }
+    }
+
+    let mut healed = 0;
+    for user in &users {
+        let subscription = by_email.remove(&user.email).and_then(current_subscription);
+        let managed = is_managed_member(user)?;
+        let mut user_drifts = find_drift(user, subscription.as_ref(), managed);
+
+        if heal
+            && let Some(subscription) = &subscription
+            && user_drifts.iter().any(|d| d.kind.is_healable())
+        {
+            match heal_user(user, subscription).await {
+                Ok(()) => {
+                    for drift in user_drifts.iter_mut().filter(|d| d.kind.is_healable()) {
+                        drift.healed = true;
+                        healed += 1;
+                    }
+                    info!("Healed billing drift of {} from Stripe", user.email);
+                }
+                Err(e) => warn!("Failed to heal billing drift of {}: {}", user.email, e),
+            }
+        }
+        drifts.extend(user_drifts);
+    }
+
+    Ok(ReconcileReport {
+        users_checked: users.len(),
+        subscriptions_checked,
+        drifts,
+        healed,
+    })
+}
+
+#[test]
+fn test_find_drift() {
+    use crate::server::plans::builtin_plan;
+
+    let mut user: User = serde_json::from_value(serde_json::json!({
+        "username": "alice",
+        "email": "alice@example.com",
+        "api_key": [],
+        "instance_id": "",
+        "plans": builtin_plan("free"),
+        "is_verified": true,
+        "created_at": "2026-01-01T00:00:00Z"
+    }))
+    .unwrap();
+    let period_end = DateTime::from_timestamp(1_790_000_000, 0);
+    let subscription = StripeSubscription {
+        id: "sub_1".to_string(),
+        email: Some(user.email.clone()),
+        status: "active".to_string(),
+        plan: Some(("Pro".to_string(), BillingInterval::Monthly)),
+        cancel_at_period_end: false,
+        current_period_end: period_end,
+        created: 1,
+    };
+
+    let kinds = |drifts: Vec<BillingDrift>| drifts.iter().map(|d| d.kind).collect::<Vec<_>>();
+    assert_eq!(
+        kinds(find_drift(&user, Some(&subscription), false)),
+        vec![
+            BillingDriftKind::PaidButFree,
+            BillingDriftKind::SubscriptionMismatch,
+            BillingDriftKind::PeriodMismatch
+        ]
+    );
+
+    user.plans = builtin_plan("pro");
+    user.stripe_subscription_id = Some("sub_1".to_string());
+    user.current_period_end = period_end.map(|t| t.to_rfc3339());
+    assert!(find_drift(&user, Some(&subscription), false).is_empty());
+
+    let ended = StripeSubscription {
+        status: "canceled".to_string(),
+        ..subscription.clone()
+    };
+    assert_eq!(
+        kinds(find_drift(&user, Some(&ended), false)),
+        vec![BillingDriftKind::CanceledInStripe]
+    );
+    assert_eq!(
+        current_subscription(vec![subscription.clone(), ended]),
+        Some(subscription)
+    );
+    assert_eq!(
+        kinds(find_drift(&user, None, false)),
+        vec![BillingDriftKind::MissingSubscription]
+    );
+    assert!(find_drift(&user, None, true).is_empty()); // Billed through the organization
+}
@@ -0,0 +1,214 @@
+//! # Referrals
+//!
+//! Every user has a referral code to share. A user who hasn't paid yet can redeem someone else's
+//! code once and gets `BLAZE_REFERRAL_CREDIT_CENTS` (500 by default) of credit right away. The
+//! referrer gets the same amount when that user makes their first payment, so throwaway signups
+//! earn nothing.
+//!
+//! Credit lives in a ledger under `get_billing_path()/credits.json`: grants are positive entries,
+//! spending is negative and the balance is the sum. Every entry has a reference (the referral, the
+//! checkout session, the invoice) that is only recorded once, so a webhook delivered twice doesn't
+//! spend twice. Billing spends the balance before the card is charged, see `billing`.
+
+use crate::info;
+use crate::server::billing::get_billing_history;
+use crate::server::error::{BlazeError, Result};
+use crate::server::schema::{BillingEventKind, CreditEntry, User};
+use crate::server::service::{get_all_users, get_billing_path, get_user, update_user};
+use crate::server::storage::DataStore;
+use chrono::Utc;
+use std::sync::OnceLock;
+
+const DEFAULT_REFERRAL_CREDIT_CENTS: i64 = 500;
+
+static CREDIT_STORE: OnceLock<DataStore<String, Vec<CreditEntry>>> = OnceLock::new();
+
+/// Credit ledger entries keyed by user email, oldest first
+pub fn get_credit_store() -> DataStore<String, Vec<CreditEntry>> {
+    CREDIT_STORE
+        .get_or_init(|| {
+            let path = get_billing_path().join("credits.json");
+            DataStore::<String, Vec<CreditEntry>>::new(path)
+                .expect("CRASH!! Failed to initialize credit datastore")
+        })
+        .clone()
+}
+
+/// Credit both sides of a referral get
+pub fn referral_credit_cents() -> i64 {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_REFERRAL_CREDIT_CENTS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|cents| *cents >= 0)
+        .unwrap_or(DEFAULT_REFERRAL_CREDIT_CENTS)
+}
+
+/// A new random code, e.g. `BLZ3F9A0C12`
+pub fn generate_referral_code() -> String {
+    format!("BLZ{}", hex::encode_upper(rand::random::<[u8; 4]>()))
+}
+
+fn normalize_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+fn ledger_balance(entries: &[CreditEntry]) -> i64 {
+    entries.iter().map(|e| e.amount_cents).sum()
+}
+
+/// How much of `balance` can go towards `amount_due`, never more than either
+pub fn spendable_credit(balance: i64, amount_due: i64) -> i64 {
+    balance.min(amount_due).max(0)
+}
+
+/// The user's credit balance in cents
+pub fn credit_balance(email: &str) -> Result<i64> {
+    let entries = get_credit_store()
+        .get(&email.to_string())?
+        .unwrap_or_default();
+    Ok(ledger_balance(&entries))
+}
+
+/// Whether an entry with `reference` was already recorded for the user
+pub fn has_credit_entry(email: &str, reference: &str) -> Result<bool> {
+    Ok(get_credit_store()
+        .get(&email.to_string())?
+        .unwrap_or_default()
+        .iter()
+        .any(|e| e.reference == reference))
+}
+
+/// Adds a ledger entry, negative to spend credit
+/// Returns false when an entry with the same reference is already there
+pub fn add_credit_entry(
+    email: &str,
+    amount_cents: i64,
+    description: String,
+    reference: &str,
+) -> Result<bool> {
+    let store = get_credit_store();
+    let mut entries = store.get(&email.to_string())?.unwrap_or_default();
+    if entries.iter().any(|e| e.reference == reference) {
+        return Ok(false);
+    }
+    entries.push(CreditEntry {
+        amount_cents,
+        description,
+        reference: reference.to_string(),
+        created_at: Utc::now().to_rfc3339(),
+    });
+    store.insert_save(email.to_string(), entries)?;
+    Ok(true)
+}
+
+/// The user's referral code, users from before referrals get one now
+pub async fn get_referral_code(email: &str) -> Result<String> {
+    let user = get_user(email)
+        .await?
+        .filter(|u| u.is_verified)
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+    if let Some(code) = user.referral_code {
+        return Ok(code);
+    }
+
+    let code = generate_referral_code();
+    let new_code = code.clone();
+    update_user(email, |user| user.referral_code = Some(new_code)).await?;
+    Ok(code)
+}
+
+async fn find_referrer(code: &str) -> Result<Option<User>> {
+    Ok(get_all_users()
+        .await?
+        .into_iter()
+        .find(|u| u.is_verified && u.referral_code.as_deref() == Some(code)))
+}
+
+/// Redeems someone's referral code for the user, returns the credit they got
+pub async fn redeem_referral(email: &String, code: &str) -> Result<i64> {
+    let code = normalize_code(code);
+    let user = get_user(email)
+        .await?
+        .filter(|u| u.is_verified)
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if user.referred_by.is_some() {
+        return Err(BlazeError::validation(
+            "You already redeemed a referral code",
+        ));
+    }
+    if user.referral_code.as_deref() == Some(code.as_str()) {
+        return Err(BlazeError::validation(
+            "You can't redeem your own referral code",
+        ));
+    }
+    // Referrals are for bringing in new customers
+    let has_paid = get_billing_history(email)?
+        .iter()
+        .any(|e| e.kind == BillingEventKind::Payment);
+    if has_paid {
+        return Err(BlazeError::validation(
+            "Referral codes are only for accounts that haven't paid yet",
+        ));
+    }
+
+    let referrer = find_referrer(&code)
+        .await?
+        .ok_or_else(|| BlazeError::validation(format!("Referral code {} doesn't exist", code)))?;
+
+    let referrer_email = referrer.email.clone();
+    update_user(email, |user| user.referred_by = Some(referrer_email)).await?;
+
+    let cents = referral_credit_cents();
+    add_credit_entry(
+        email,
+        cents,
+        format!("Referral credit for joining through {}", code),
+        "referral:redeemed",
+    )?;
+
+    info!("{} redeemed the referral code of {}", email, referrer.email);
+    Ok(cents)
+}
+
+/// Credits the referrer once the referred user paid, later payments change nothing
+pub async fn reward_referrer(email: &String) -> Result<()> {
+    let Some(referrer) = get_user(email).await?.and_then(|u| u.referred_by) else {
+        return Ok(());
+    };
+    let rewarded = add_credit_entry(
+        &referrer,
+        referral_credit_cents(),
+        format!("Referral credit for inviting {}", email),
+        &format!("referral:{}", email),
+    )?;
+    if rewarded {
+        info!("{} earned referral credit for {}", referrer, email);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_credit_balance_and_spend() {
+    let entry = |amount_cents: i64, reference: &str| CreditEntry {
+        amount_cents,
+        description: String::new(),
+        reference: reference.to_string(),
+        created_at: Utc::now().to_rfc3339(),
+    };
+    let entries = vec![
+        entry(500, "referral:redeemed"),
+        entry(500, "referral:bob@example.com"),
+        entry(-300, "cs_test_1"),
+    ];
+    assert_eq!(ledger_balance(&entries), 700);
+
+    assert_eq!(spendable_credit(700, 1200), 700); // Covers part of it
+    assert_eq!(spendable_credit(700, 500), 500); // Rest stays for next time
+    assert_eq!(spendable_credit(0, 500), 0);
+    assert_eq!(spendable_credit(700, 0), 0);
+
+    assert!(generate_referral_code().starts_with("BLZ"));
+    assert_eq!(normalize_code(" blz3f9a0c12 "), "BLZ3F9A0C12");
+}
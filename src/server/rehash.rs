@@ -0,0 +1,163 @@
+//! # API key rehashing
+//!
+//! Keys whose stored hash is still bare SHA-256, or was made with the pepper being rotated out,
+//! get the current pepper's hash as they're presented (see `crypto::needs_rehash`).
+//!
+//! - The service stores the new hash of a key it verifies right away.
+//! - The proxy verifies most keys but only reads `users.json`, so it queues the new hash in
+//!   `key_rehashes.json` under the old one, off the request path. Only the proxy writes it and
+//!   entries expire after a day. The service stores what's queued every minute.
+
+use crate::server::error::Result;
+use crate::server::schema::KeyRehash;
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use crate::server::users::UserStore;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+pub const KEY_REHASH_FILE: &str = "key_rehashes.json";
+/// Rehashes not picked up by then are dropped, the key is queued again the next time it's used
+const QUEUED_REHASH_TTL: Duration = Duration::from_secs(24 * 3600);
+
+static KEY_REHASH_STORE: OnceLock<DataStore<String, KeyRehash>> = OnceLock::new();
+
+/// Queued rehashes keyed by the hash they replace
+pub fn get_key_rehash_store() -> DataStore<String, KeyRehash> {
+    KEY_REHASH_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join(KEY_REHASH_FILE);
+            // The service reloads its copy when the generation moves
+            DataStore::<String, KeyRehash>::new(path)
+                .expect("CRASH!! Failed to initialize key rehash datastore")
+                .with_generation()
+        })
+        .clone()
+}
+
+/// Queues `new_hash` to replace the stored `old_hash` of one of the user's keys, returns whether
+/// it did. Ones already queued aren't queued again
+pub async fn queue_key_rehash(
+    queue: &DataStore<String, KeyRehash>,
+    email: &str,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<bool> {
+    let old_hash = old_hash.to_string();
+    if queue.contains_key(&old_hash)? {
+        return Ok(false);
+    }
+    // Entries are only ever added here, so it's where expired ones go
+    queue.purge_expired_async().await?;
+    let rehash = KeyRehash {
+        email: email.to_string(),
+        new_hash: new_hash.to_string(),
+    };
+    queue
+        .insert_with_ttl_async(old_hash, rehash, QUEUED_REHASH_TTL)
+        .await?;
+    Ok(true)
+}
+
+/// Replaces the stored `old_hash` of one of the user's keys with `new_hash`, the rest of the
+/// user is left as it is now. Returns whether the user had it
+pub async fn replace_key_hash(
+    user_store: &UserStore,
+    email: &str,
+    old_hash: &str,
+    new_hash: &str,
+) -> Result<bool> {
+    let mut is_replaced = false;
+    user_store
+        .update_async(email, |user| {
+            for key in user.api_key.iter_mut() {
+                if key.api_key_hash == old_hash {
+                    key.api_key_hash = new_hash.to_string();
+                    is_replaced = true;
+                }
+            }
+        })
+        .await?;
+    Ok(is_replaced)
+}
+
+/// Stores the rehashes the proxy queued, returns the emails whose keys changed
+/// Ones stored before, or for keys gone since, change nothing
+pub async fn apply_queued_rehashes(
+    queue: &DataStore<String, KeyRehash>,
+    user_store: &UserStore,
+) -> Result<Vec<String>> {
+    queue.reload_if_changed_async().await?;
+
+    let mut emails = Vec::new();
+    for (old_hash, rehash) in queue.entries()? {
+        if replace_key_hash(user_store, &rehash.email, &old_hash, &rehash.new_hash).await?
+            && !emails.contains(&rehash.email)
+        {
+            emails.push(rehash.email);
+        }
+    }
+    Ok(emails)
+}
+
+#[tokio::test]
+async fn test_queued_rehash() -> Result<()> {
+    use crate::server::crypto::{APIKey, hash_api_key_with_pepper, legacy_hash_api_key};
+    use crate::server::schema::test_user;
+
+    let dir = std::env::temp_dir().join("test_rehash_queue");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let user_store = UserStore::new(DataStore::new(dir.join("users.json"))?);
+    let path = dir.join(KEY_REHASH_FILE);
+    let proxy_queue = DataStore::<String, KeyRehash>::new(path.clone())?.with_generation();
+    let service_queue = DataStore::<String, KeyRehash>::new(path)?;
+
+    let email = "alice@example.com";
+    let api_key = "blz1_YWxpY2VAZXhhbXBsZS5jb20_secret";
+    let legacy_hash = legacy_hash_api_key(api_key);
+    let peppered_hash = hash_api_key_with_pepper(api_key, b"pepper");
+    let mut user = test_user(email, "free");
+    user.api_key.push(APIKey {
+        user_name: "alice".to_string(),
+        user_email: email.to_string(),
+        api_key_hash: legacy_hash.clone(),
+        key_prefix: api_key.chars().take(12).collect(),
+        is_revoked: false,
+        created_at: chrono::Utc::now().to_rfc3339(),
+    });
+    user_store.insert_save_async(email, user).await?;
+
+    // Nothing queued yet
+    assert!(
+        apply_queued_rehashes(&service_queue, &user_store)
+            .await?
+            .is_empty()
+    );
+
+    // The proxy verified the key against its legacy hash, once is enough
+    assert!(queue_key_rehash(&proxy_queue, email, &legacy_hash, &peppered_hash).await?);
+    assert!(!queue_key_rehash(&proxy_queue, email, &legacy_hash, &peppered_hash).await?);
+
+    // The service picks it up from the file and stores it
+    assert_eq!(
+        apply_queued_rehashes(&service_queue, &user_store).await?,
+        vec![email.to_string()]
+    );
+    let stored = user_store.get(email)?.unwrap();
+    assert_eq!(stored.api_key[0].api_key_hash, peppered_hash);
+
+    // Still queued until it expires, stored already so nothing changes
+    assert!(
+        apply_queued_rehashes(&service_queue, &user_store)
+            .await?
+            .is_empty()
+    );
+
+    // A key that's gone isn't brought back
+    assert!(!replace_key_hash(&user_store, email, "unknown", &peppered_hash).await?);
+    assert!(!replace_key_hash(&user_store, "bob@example.com", &legacy_hash, "x").await?);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,338 @@
+//! # Response cache
+//!
+//! SDKs and dashboards tend to send the same vector query again and again. With
+//! `BLAZE_PROXY_CACHE=true` the proxy answers identical reads from memory for
+//! `BLAZE_PROXY_CACHE_TTL_SECONDS` (30 by default) instead of asking the instance each time.
+//!
+//! - Only GETs and POSTs to the search routes are cached, and only successful responses up to
+//!   `BLAZE_PROXY_CACHE_MAX_ENTRY_KB` (256). `BLAZE_PROXY_CACHE_MAX_ENTRIES` (1024) are kept in
+//!   all, the least recently used go first.
+//! - Entries are per user, keyed by user, method, path, query, `Accept-Encoding` (the instance
+//!   may have compressed its answer) and a hash of the body.
+//! - Any other request to an instance (a write) drops everything cached for it.
+//! - `Cache-Control` is honoured both ways: clients can skip the cache (`no-cache`, `no-store`)
+//!   or bound the age they accept (`max-age`), the instance can keep a response out of it or
+//!   shorten its TTL.
+//!
+//! Answers say `X-Blaze-Cache: HIT` (with `Age`) or `MISS`.
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, Method, StatusCode, header};
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const CACHE_STATUS_HEADER: &str = "x-blaze-cache";
+
+/// Routes (without the instance id) that only read even though they're POSTs
+pub const CACHEABLE_POST_ROUTES: &[&str] = &["/v1/blazedb/search"];
+
+const DEFAULT_TTL_SECONDS: u64 = 30;
+const DEFAULT_MAX_ENTRIES: usize = 1024;
+const DEFAULT_MAX_ENTRY_KB: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheSettings {
+    pub enabled: bool,
+    pub ttl: Duration,
+    pub max_entries: usize,
+    pub max_entry_bytes: usize,
+}
+
+impl CacheSettings {
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        let var = |name: &str| std::env::var(name).ok().map(|v| v.trim().to_string());
+        CacheSettings {
+            enabled: var("BLAZE_PROXY_CACHE")
+                .is_some_and(|v| v.eq_ignore_ascii_case("true") || v == "1"),
+            ttl: Duration::from_secs(
+                var("BLAZE_PROXY_CACHE_TTL_SECONDS")
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_TTL_SECONDS),
+            ),
+            max_entries: var("BLAZE_PROXY_CACHE_MAX_ENTRIES")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_ENTRIES)
+                .max(1),
+            max_entry_bytes: var("BLAZE_PROXY_CACHE_MAX_ENTRY_KB")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_ENTRY_KB)
+                * 1024,
+        }
+    }
+}
+
+/// Whether a request only reads, so its answer may be cached
+pub fn is_cacheable(method: &Method, path: &str) -> bool {
+    let route = path
+        .trim_end_matches('/')
+        .rsplit_once('/')
+        .map_or(path, |(head, _)| head);
+    *method == Method::GET || (*method == Method::POST && CACHEABLE_POST_ROUTES.contains(&route))
+}
+
+/// Cache key of a read, the body only goes in hashed
+pub fn cache_key(
+    email: &str,
+    method: &Method,
+    path_and_query: &str,
+    accept_encoding: &str,
+    body: &[u8],
+) -> String {
+    let mut hasher = Sha256::new();
+    for part in [
+        email.as_bytes(),
+        method.as_str().as_bytes(),
+        path_and_query.as_bytes(),
+        accept_encoding.as_bytes(),
+    ] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+fn directives(headers: &HeaderMap) -> impl Iterator<Item = String> + '_ {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+}
+
+fn max_age(directive: &str) -> Option<Duration> {
+    directive
+        .strip_prefix("max-age=")
+        .and_then(|secs| secs.trim_matches('"').parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// What the client's `Cache-Control` allows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestCacheControl {
+    pub no_store: bool,            // Neither answered from nor stored in the cache
+    pub no_cache: bool,            // Asks the instance, the answer is still stored
+    pub max_age: Option<Duration>, // Oldest cached answer the client takes
+}
+
+impl RequestCacheControl {
+    pub fn parse(headers: &HeaderMap) -> Self {
+        let mut control = RequestCacheControl::default();
+        for directive in directives(headers) {
+            match directive.as_str() {
+                "no-store" => control.no_store = true,
+                "no-cache" => control.no_cache = true,
+                other => control.max_age = max_age(other).or(control.max_age),
+            }
+        }
+        control
+    }
+}
+
+/// How long the instance's response may be cached, None if it says it mustn't be
+pub fn response_ttl(headers: &HeaderMap, default: Duration) -> Option<Duration> {
+    let mut ttl = default;
+    for directive in directives(headers) {
+        match directive.as_str() {
+            "no-store" | "no-cache" => return None,
+            other => {
+                if let Some(age) = max_age(other) {
+                    ttl = ttl.min(age);
+                }
+            }
+        }
+    }
+    (!ttl.is_zero()).then_some(ttl)
+}
+
+/// A response as it's kept in the cache
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    instance_id: String,
+    generation: u64,
+    stored_at: Instant,
+    ttl: Duration,
+}
+
+impl CachedResponse {
+    /// A response to a read of the instance, made when `generation` was current
+    pub fn new(
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Bytes,
+        instance_id: &str,
+        generation: u64,
+        ttl: Duration,
+    ) -> Self {
+        CachedResponse {
+            status,
+            headers,
+            body,
+            instance_id: instance_id.to_string(),
+            generation,
+            stored_at: Instant::now(),
+            ttl,
+        }
+    }
+
+    pub fn age(&self) -> Duration {
+        self.stored_at.elapsed()
+    }
+}
+
+/// Cached reads and the write generation of each instance, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone)]
+pub struct ResponseCache {
+    settings: CacheSettings,
+    entries: Arc<Mutex<LruCache<String, CachedResponse>>>,
+    generations: Arc<Mutex<HashMap<String, u64>>>, // instance_id -> writes seen
+}
+
+impl ResponseCache {
+    pub fn new(settings: CacheSettings) -> Self {
+        let capacity = NonZeroUsize::new(settings.max_entries).unwrap_or(NonZeroUsize::MIN);
+        ResponseCache {
+            settings,
+            entries: Arc::new(Mutex::new(LruCache::new(capacity))),
+            generations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn settings(&self) -> &CacheSettings {
+        &self.settings
+    }
+
+    /// Taken before a read is forwarded, a write finishing in the meantime keeps it out
+    pub fn generation(&self, instance_id: &str) -> u64 {
+        let generations = self.generations.lock().unwrap_or_else(|e| e.into_inner());
+        generations.get(instance_id).copied().unwrap_or(0)
+    }
+
+    /// A cached answer no older than its TTL (and `max_age`, if the client set one)
+    pub fn get(&self, key: &str, max_age: Option<Duration>) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = entries.get(key)?.clone();
+
+        // Expired or written to since, either way it's no use anymore
+        if entry.age() >= entry.ttl || entry.generation != self.generation(&entry.instance_id) {
+            entries.pop(key);
+            return None;
+        }
+        max_age
+            .is_none_or(|max| entry.age() <= max)
+            .then_some(entry)
+    }
+
+    /// Stores a response, unless it's too big or the instance was written to since the read
+    pub fn insert(&self, key: String, response: CachedResponse) {
+        if response.body.len() > self.settings.max_entry_bytes
+            || response.generation != self.generation(&response.instance_id)
+        {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.put(key, response);
+    }
+
+    /// Drops everything cached for the instance, after a write to it
+    pub fn invalidate(&self, instance_id: &str) {
+        let mut generations = self.generations.lock().unwrap_or_else(|e| e.into_inner());
+        *generations.entry(instance_id.to_string()).or_insert(0) += 1;
+    }
+}
+
+#[test]
+fn test_response_cache() {
+    let cache = ResponseCache::new(CacheSettings {
+        enabled: true,
+        ttl: Duration::from_secs(30),
+        max_entries: 8,
+        max_entry_bytes: 16,
+    });
+    let search = cache_key(
+        "a@x.com",
+        &Method::POST,
+        "/v1/blazedb/search/inst",
+        "",
+        b"{}",
+    );
+    assert_ne!(
+        search,
+        cache_key(
+            "b@x.com",
+            &Method::POST,
+            "/v1/blazedb/search/inst",
+            "",
+            b"{}"
+        )
+    );
+    assert_ne!(
+        search,
+        cache_key(
+            "a@x.com",
+            &Method::POST,
+            "/v1/blazedb/search/inst",
+            "gzip",
+            b"{}"
+        )
+    );
+    assert!(is_cacheable(&Method::POST, "/v1/blazedb/search/inst"));
+    assert!(is_cacheable(&Method::GET, "/v1/blazedb/databases/inst"));
+    assert!(!is_cacheable(&Method::POST, "/v1/blazedb/insert/inst"));
+
+    let response = |body: &'static [u8], generation| {
+        CachedResponse::new(
+            StatusCode::OK,
+            HeaderMap::new(),
+            Bytes::from_static(body),
+            "inst",
+            generation,
+            Duration::from_secs(30),
+        )
+    };
+    cache.insert(search.clone(), response(b"[1]", 0));
+    assert_eq!(cache.get(&search, None).unwrap().body, "[1]");
+    assert!(cache.get(&search, Some(Duration::from_secs(60))).is_some());
+
+    // Too big, or read before a write that has finished since
+    cache.insert("big".to_string(), response(b"0123456789abcdefg", 0));
+    assert!(cache.get("big", None).is_none());
+    cache.invalidate("inst");
+    assert!(cache.get(&search, None).is_none());
+    cache.insert(search.clone(), response(b"[1]", 0));
+    assert!(cache.get(&search, None).is_none());
+    cache.insert(search.clone(), response(b"[2]", 1));
+    assert_eq!(cache.get(&search, None).unwrap().body, "[2]");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CACHE_CONTROL,
+        "no-cache, max-age=5".parse().unwrap(),
+    );
+    let control = RequestCacheControl::parse(&headers);
+    assert!(control.no_cache && !control.no_store);
+    assert_eq!(control.max_age, Some(Duration::from_secs(5)));
+    assert_eq!(response_ttl(&headers, Duration::from_secs(30)), None);
+
+    headers.insert(
+        header::CACHE_CONTROL,
+        "private, max-age=10".parse().unwrap(),
+    );
+    assert_eq!(
+        response_ttl(&headers, Duration::from_secs(30)),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(
+        response_ttl(&HeaderMap::new(), Duration::from_secs(30)),
+        Some(Duration::from_secs(30))
+    );
+}
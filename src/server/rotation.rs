@@ -0,0 +1,138 @@
+//! # Secret rotation
+//!
+//! Instance ids are derived from the owner's email under `BLAZE_INSTANCE_SECRET`, and API keys are
+//! hashed under `BLAZE_API_KEY_PEPPER` (see `crypto`). Both can be rotated without breaking
+//! anyone: the secret being replaced moves to `BLAZE_INSTANCE_SECRET_PREVIOUS` /
+//! `BLAZE_API_KEY_PEPPER_PREVIOUS`, the new one takes its place, and both stay accepted meanwhile.
+//!
+//! - Instance ids are stored with their users once given out, so running instances keep theirs.
+//!   What the secret still decides is the id a returning email gets (an account deleted and
+//!   registered again finds its backups under the same id). `rotate_instance_secret`, triggered by
+//!   an admin after the restart, records for every user the id they have under the name the new
+//!   secret derives (`instance_aliases.json`), so their id is found again without the old secret.
+//!   Deleted accounts aren't in the user store, `resolve_instance_id` also looks for backups under
+//!   the previous secret's id until it's removed.
+//! - API key hashes made with the previous pepper still match, the service rehashes them as their
+//!   keys are used. The report tells how many are left.
+//!
+//! The previous secrets can go once the report shows nothing depending on them.
+
+use crate::server::backups::list_backups;
+use crate::server::container::{
+    get_previous_instance_id, get_unique_instance_id, has_instance_volumes,
+    is_instance_secret_rotating,
+};
+use crate::server::crypto::{PEPPERED_HASH_PREFIX, api_key_format_version};
+use crate::server::service::{get_all_users, get_data_path};
+use crate::server::storage::DataStore;
+use crate::{info, warn};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+static ALIAS_STORE: OnceLock<DataStore<String, String>> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecretRotationReport {
+    pub users: usize,
+    pub instance_ids_current: usize, // Derived from the current secret
+    pub instance_ids_previous: usize, // Derived from the previous secret, aliased now
+    pub instance_ids_other: usize,   // Older than both secrets, aliased now
+    pub aliases_written: usize,
+    pub api_keys_legacy_format: usize, // `blz_` keys from before the format was versioned
+    pub api_key_hashes_legacy: usize,  // Bare SHA-256, rehashed once used
+    pub api_key_hashes_peppered: usize,
+    pub previous_instance_secret_set: bool,
+}
+
+/// Instance ids given out under an older secret, keyed by the id the current secret derives
+/// from the owner's email
+pub fn get_alias_store() -> DataStore<String, String> {
+    ALIAS_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("instance_aliases.json");
+            DataStore::<String, String>::new(path)
+                .expect("CRASH!! Failed to initialize instance alias datastore")
+        })
+        .clone()
+}
+
+/// The instance id to give the email's new instance
+/// An id recorded by a rotation comes first, then the previous secret's id if backups or volumes
+/// are left under it, else the current secret's
+pub async fn resolve_instance_id(email: &str) -> Result<String> {
+    let current = get_unique_instance_id(email).await?;
+    if let Some(aliased) = get_alias_store().get(&current)? {
+        return Ok(aliased);
+    }
+
+    if let Some(previous) = get_previous_instance_id(email).await?
+        && (!list_backups(&previous)?.is_empty() || has_instance_volumes(&previous).await?)
+    {
+        info!(
+            "Instance id of {} kept from the previous secret, its data is still there",
+            email
+        );
+        return Ok(previous);
+    }
+
+    Ok(current)
+}
+
+/// Records the id of every user whose id the current secret doesn't derive, see the module docs
+/// Safe to run again, ids already recorded are left as they are
+pub async fn rotate_instance_secret() -> Result<SecretRotationReport> {
+    let aliases = get_alias_store();
+    let mut report = SecretRotationReport {
+        previous_instance_secret_set: is_instance_secret_rotating(),
+        ..Default::default()
+    };
+
+    for user in get_all_users().await? {
+        report.users += 1;
+        for key in &user.api_key {
+            if api_key_format_version(&key.key_prefix) == Some(0) {
+                report.api_keys_legacy_format += 1;
+            }
+            if key.api_key_hash.starts_with(PEPPERED_HASH_PREFIX) {
+                report.api_key_hashes_peppered += 1;
+            } else {
+                report.api_key_hashes_legacy += 1;
+            }
+        }
+
+        // Not verified yet, no id given out
+        if user.instance_id.is_empty() {
+            continue;
+        }
+        let current = get_unique_instance_id(&user.email).await?;
+        if user.instance_id == current {
+            report.instance_ids_current += 1;
+            continue;
+        }
+        if get_previous_instance_id(&user.email).await?.as_ref() == Some(&user.instance_id) {
+            report.instance_ids_previous += 1;
+        } else {
+            report.instance_ids_other += 1;
+        }
+
+        match aliases.get(&current)? {
+            Some(aliased) if aliased == user.instance_id => {}
+            Some(aliased) => warn!(
+                "Instance alias of {} points to {}, not its instance {}, left as it is",
+                user.email, aliased, user.instance_id
+            ),
+            None => {
+                aliases.insert_mem(current, user.instance_id.clone())?;
+                report.aliases_written += 1;
+            }
+        }
+    }
+    aliases.save_async().await?;
+
+    info!(
+        "Instance secret rotation: {} user(s), {} id(s) on the current secret, {} alias(es) written",
+        report.users, report.instance_ids_current, report.aliases_written
+    );
+    Ok(report)
+}
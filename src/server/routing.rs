@@ -0,0 +1,197 @@
+//! # Routing table
+//!
+//! Where each instance's container can be reached, kept in `get_data_path()/routes.json`. The
+//! provisioner records a route whenever it creates or starts a container and drops it when the
+//! container is removed for good:
+//! - On an internal Docker network (`BLAZEDB_NETWORK` other than `bridge`) it's the container's
+//!   DNS name and port 8080.
+//! - In bridge mode it's the published host port on `BLAZEDB_CONTAINER_HOST` (`localhost` by
+//!   default), as the container was actually created.
+//!
+//! `BLAZEDB_CONTAINER_SCHEME` (`http`) goes with either. The proxy only reads the table, so where
+//! containers run is decided in one place.
+//!
+//! A route can list replicas next to the primary container, each with a weight. The proxy
+//! spreads the instance's requests over them, see `balancer`. Recording the primary again keeps
+//! them.
+//!
+//! The file's modification time is what tells the proxy something changed: it checks every
+//! second and reloads when it did, and reloads once more on a miss (a route recorded a moment
+//! ago). Containers created before the table existed are reached the old way, see
+//! `legacy_base_url`.
+
+use crate::server::ports::resolve_container_port;
+use crate::server::schema::InstanceRoute;
+use crate::server::service::get_data_path;
+use crate::server::storage::DataStore;
+use anyhow::Result;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// Port BlazeDB listens on inside its container
+pub const CONTAINER_PORT: u16 = 8080;
+
+static ROUTING_TABLE: OnceLock<DataStore<String, InstanceRoute>> = OnceLock::new();
+
+/// Routes keyed by instance id
+pub fn get_routing_table() -> DataStore<String, InstanceRoute> {
+    ROUTING_TABLE
+        .get_or_init(|| {
+            let path = get_data_path().join("routes.json");
+            DataStore::<String, InstanceRoute>::new(path)
+                .expect("CRASH!! Failed to initialize routing table")
+        })
+        .clone()
+}
+
+/// Route to a container, `host_port` being the port it publishes on the host (bridge mode)
+pub fn route_for_container(instance_id: &str, host_port: Option<u16>) -> InstanceRoute {
+    dotenv::dotenv().ok();
+    let (host, port) = match host_port {
+        Some(port) => (
+            std::env::var("BLAZEDB_CONTAINER_HOST").unwrap_or_else(|_| "localhost".to_string()),
+            port,
+        ),
+        None => (format!("blazedb-{}", instance_id), CONTAINER_PORT),
+    };
+    InstanceRoute {
+        host,
+        port,
+        scheme: std::env::var("BLAZEDB_CONTAINER_SCHEME").unwrap_or_else(|_| "http".to_string()),
+        updated_at: chrono::Utc::now().to_rfc3339(),
+        weight: 1,
+        replicas: Vec::new(),
+    }
+}
+
+/// Records where the instance's primary container is reached now, replicas already listed stay
+/// The file is left alone if nothing changed
+pub fn record_route(instance_id: &str, mut route: InstanceRoute) -> Result<()> {
+    let table = get_routing_table();
+    let key = instance_id.to_string();
+    if let Some(current) = table.get(&key)? {
+        if (&current.host, current.port, &current.scheme)
+            == (&route.host, route.port, &route.scheme)
+        {
+            return Ok(());
+        }
+        route.weight = current.weight;
+        route.replicas = current.replicas;
+    }
+    table.insert_save(key, route)?;
+    Ok(())
+}
+
+/// Drops the instance's route (when the container is removed for good)
+pub fn remove_route(instance_id: &str) -> Result<()> {
+    get_routing_table().delete(&instance_id.to_string())?;
+    Ok(())
+}
+
+/// Base URL for containers without a route, going by the proxy's own env like before the table
+/// `PROXY_MODE=external` means published ports (on `BLAZEDB_CONTAINER_HOST`), Docker DNS otherwise
+pub fn legacy_base_url(instance_id: &str) -> String {
+    if std::env::var("PROXY_MODE").unwrap_or_default() == "external" {
+        let host = std::env::var("BLAZEDB_CONTAINER_HOST").unwrap_or("localhost".to_string());
+        format!("http://{}:{}", host, resolve_container_port(instance_id))
+    } else {
+        format!("http://blazedb-{}:{}", instance_id, CONTAINER_PORT)
+    }
+}
+
+/// The proxy's copy of the routing table, reloaded when the file changes (cheap to clone)
+#[derive(Clone)]
+pub struct RouteResolver {
+    table: DataStore<String, InstanceRoute>,
+    seen: Arc<Mutex<Option<(SystemTime, u64)>>>, // Modification time and size last loaded
+}
+
+impl RouteResolver {
+    pub fn new(table: DataStore<String, InstanceRoute>) -> Self {
+        let seen = file_version(&table);
+        RouteResolver {
+            table,
+            seen: Arc::new(Mutex::new(seen)),
+        }
+    }
+
+    /// Reloads the table if the file changed since the last load, true if it did
+    pub fn refresh(&self) -> Result<bool> {
+        let version = file_version(&self.table);
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        if version == *seen {
+            return Ok(false);
+        }
+        self.table.reload()?;
+        *seen = version;
+        Ok(true)
+    }
+
+    pub fn resolve(&self, instance_id: &str) -> Option<InstanceRoute> {
+        let key = instance_id.to_string();
+        if let Ok(Some(route)) = self.table.get(&key) {
+            return Some(route);
+        }
+        // Might have been recorded since the last refresh
+        match self.refresh() {
+            Ok(true) => self.table.get(&key).ok().flatten(),
+            _ => None,
+        }
+    }
+
+    /// Base URL of the instance's container, from its route or the old way without one
+    pub fn base_url(&self, instance_id: &str) -> String {
+        self.resolve(instance_id)
+            .map(|route| route.base_url())
+            .unwrap_or_else(|| legacy_base_url(instance_id))
+    }
+
+    /// Base URL and weight of each container serving the instance, never empty
+    pub fn targets(&self, instance_id: &str) -> Vec<(String, u32)> {
+        self.resolve(instance_id)
+            .map(|route| route.targets())
+            .unwrap_or_else(|| vec![(legacy_base_url(instance_id), 1)])
+    }
+}
+
+fn file_version(table: &DataStore<String, InstanceRoute>) -> Option<(SystemTime, u64)> {
+    let metadata = std::fs::metadata(table.path()).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+#[test]
+fn test_route_resolver_picks_up_changes() {
+    let path = std::env::temp_dir().join("test_route_resolver.json");
+    let _ = std::fs::remove_file(&path);
+
+    let writer = DataStore::<String, InstanceRoute>::new(path.clone()).unwrap();
+    let resolver = RouteResolver::new(DataStore::new(path.clone()).unwrap());
+    assert!(resolver.resolve("inst").is_none());
+
+    // Recorded by the other side, found on the miss
+    let route = InstanceRoute {
+        host: "10.0.0.5".to_string(),
+        port: 50123,
+        scheme: "http".to_string(),
+        updated_at: chrono::Utc::now().to_rfc3339(),
+        weight: 1,
+        replicas: Vec::new(),
+    };
+    writer
+        .insert_save("inst".to_string(), route.clone())
+        .unwrap();
+    assert_eq!(resolver.base_url("inst"), "http://10.0.0.5:50123");
+    assert!(!resolver.refresh().unwrap());
+
+    // Moved, picked up on the next refresh
+    let moved = InstanceRoute {
+        host: "fd00::7".to_string(),
+        port: 8080,
+        ..route
+    };
+    writer.insert_save("inst".to_string(), moved).unwrap();
+    assert!(resolver.refresh().unwrap());
+    assert_eq!(resolver.base_url("inst"), "http://[fd00::7]:8080");
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -1,5 +1,13 @@
-use crate::server::crypto::APIKey;
+use crate::server::audit::AuditEvent;
+use crate::server::crypto::{APIKey, pii_field};
+use crate::server::mailer::{MailMetrics, MailQuotaStatus};
+use crate::server::migration::MigrationReport;
+use crate::server::plans::get_plan_catalog;
+use crate::server::rotation::SecretRotationReport;
+use crate::server::storage::{CompactReport, ImportReport, StoreStats};
+use crate::server::versioning::{RecordSchema, rename_field};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 /// Request structure for user registration
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -14,6 +22,8 @@ pub struct UserRegisterResponse {
     pub email: String,
     pub is_created: bool,
     pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>, // Machine-readable, e.g. "email_domain_not_allowed"
 }
 
 /// Request structure for email verification
@@ -27,6 +37,19 @@ pub struct VerifyEmailRequest {
 pub struct VerifyEmailResponse {
     pub is_code_sent: bool,
     pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>, // Machine-readable, e.g. "email_domain_not_allowed"
+}
+
+/// Response to asking for a new code, says when the next one can be asked for
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ResendCodeResponse {
+    pub is_code_sent: bool,
+    pub retry_after_seconds: i64, // Until another code can be asked for, 0 when it can be now
+    pub resends_remaining: u32,   // New codes left in this verification attempt
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>, // Machine-readable, e.g. "otp_cooldown"
 }
 
 /// Request structure for OTP verification
@@ -43,14 +66,55 @@ pub struct VerifyOtpResponse {
     pub message: String,
     pub api_key: Option<String>, // Return plain API key ONLY once after verification
     pub instance_id: Option<String>,
+    #[serde(default)]
+    pub instance_state: Option<String>, // "provisioning" right after verification
+    #[serde(default)]
+    pub status_url: Option<String>, // GET here with the API key until `is_ready`
 }
 /// Structure representing an OTP record
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct OtpRecord {
     pub email: String,
     pub otp_hash: String,
     pub created_at: String,
     pub expires_at: String,
+    #[serde(default)]
+    pub failed_attempts: u32, // Wrong codes tried against this one
+    #[serde(default)]
+    pub purpose: OtpPurpose, // Only confirms what it was sent for
+}
+
+/// What a code was sent for, a code for one action doesn't confirm another
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OtpPurpose {
+    #[default]
+    Verification, // Signup email verification, the only one that gets an API key
+    InstanceReset,
+    InstanceRestore,
+    AccountDeletion,
+    KeyReverification,
+    OrganizationJoin,
+}
+
+impl OtpPurpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OtpPurpose::Verification => "Email verification",
+            OtpPurpose::InstanceReset => "Instance reset",
+            OtpPurpose::InstanceRestore => "Backup restore",
+            OtpPurpose::AccountDeletion => "Account deletion",
+            OtpPurpose::KeyReverification => "Key re-verification",
+            OtpPurpose::OrganizationJoin => "Organization join",
+        }
+    }
+}
+
+/// Codes sent to an email in its current verification attempt
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct OtpAttempt {
+    pub sends: u32,
+    pub started_at: i64, // Unix seconds of the first code
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -66,16 +130,577 @@ pub struct InstanceStatusResponse {
     pub message: String,
 }
 
+/// Whether the user's instance takes requests yet, polled after verification
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceReadinessResponse {
+    pub instance_id: Option<String>,
+    pub state: Option<String>, // "provisioning" until the container exists, see `get_instance_state`
+    pub is_ready: bool,
+    pub message: String,
+}
+
+/// Response structure for a short-lived signed instance token
+/// The token goes in `Authorization: Bearer blzt_...` for read-only proxy requests
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceTokenResponse {
+    pub token: Option<String>,
+    pub expires_at: Option<String>,
+    pub message: String,
+}
+
+/// Request structure for wiping the instance data
+/// Send without `otp` first to receive a confirmation code, then again with the code
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceResetRequest {
+    #[serde(default)]
+    pub otp: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceResetResponse {
+    pub is_reset: bool,
+    pub is_code_sent: bool,
+    pub message: String,
+}
+
+/// A snapshot of both volumes of an instance
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BackupRecord {
+    pub id: String,
+    pub instance_id: String,
+    pub email: String,
+    pub reason: String, // e.g. "manual", "account deletion"
+    pub size_bytes: u64,
+    pub created_at: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceBackupResponse {
+    pub backup: Option<BackupRecord>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceBackupListResponse {
+    pub backups: Vec<BackupRecord>, // Newest first
+    pub message: String,
+}
+
+/// Request structure for restoring a backup, same two steps as a reset
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceRestoreRequest {
+    #[serde(default)]
+    pub otp: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceRestoreResponse {
+    pub is_restored: bool,
+    pub is_code_sent: bool,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceConfigResponse {
+    pub config: Option<InstanceConfig>, // API key redacted
+    pub recreated: Vec<String>,         // Instances recreated with the new env
+    pub message: String,
+}
+
+/// A container that should be running, retried until it's healthy
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct DesiredContainer {
+    pub instance_id: String,
+    pub email: String, // Owner, the container gets their plan's limits
+    #[serde(default)]
+    pub attempts: u32, // Failed spawn attempts so far
+    pub next_attempt_at: String,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    pub created_at: String,
+}
+
+/// Owner of an opaque API key, indexed by `crypto::api_key_lookup_id`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyOwner {
+    #[serde(with = "pii_field")]
+    pub email: String,
+    pub created_at: String,
+}
+
+/// New hash of an API key, queued by the proxy for the service to store, see `server::rehash`
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyRehash {
+    #[serde(with = "pii_field")]
+    pub email: String,
+    pub new_hash: String,
+}
+
+/// Sent by the service to the proxy's control listener after a user changed
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct InvalidationRequest {
+    #[serde(default)]
+    pub email: Option<String>, // Every cached user when left out
+}
+
+/// Body of an outbound webhook, signed in the `Blaze-Signature` header
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct WebhookEvent {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String, // e.g. "incident.opened"
+    pub created_at: String,
+    pub data: serde_json::Value,
+}
+
+/// Where the proxy reaches an instance's container, written by the provisioner
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InstanceRoute {
+    pub host: String, // Container DNS name, or the container host's address
+    pub port: u16,
+    pub scheme: String, // "http" or "https"
+    pub updated_at: String,
+    #[serde(default = "default_replica_weight")]
+    pub weight: u32, // Share of the traffic next to the replicas
+    #[serde(default)]
+    pub replicas: Vec<ReplicaRoute>, // More containers serving the instance, usually none
+}
+
+/// Another container serving the same instance, same scheme as the primary
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReplicaRoute {
+    pub host: String,
+    pub port: u16,
+    #[serde(default = "default_replica_weight")]
+    pub weight: u32,
+}
+
+fn default_replica_weight() -> u32 {
+    1
+}
+
+fn base_url(scheme: &str, host: &str, port: u16) -> String {
+    // IPv6 addresses need brackets in a URL
+    if host.contains(':') && !host.starts_with('[') {
+        format!("{}://[{}]:{}", scheme, host, port)
+    } else {
+        format!("{}://{}:{}", scheme, host, port)
+    }
+}
+
+impl InstanceRoute {
+    /// Base URL of the primary container
+    pub fn base_url(&self) -> String {
+        base_url(&self.scheme, &self.host, self.port)
+    }
+
+    /// Base URL and weight of every container serving the instance, the primary first
+    pub fn targets(&self) -> Vec<(String, u32)> {
+        std::iter::once((self.base_url(), self.weight))
+            .chain(self.replicas.iter().map(|replica| {
+                (
+                    base_url(&self.scheme, &replica.host, replica.port),
+                    replica.weight,
+                )
+            }))
+            .collect()
+    }
+}
+
+/// The proxy's view of an instance: whether forwarded requests got through to it
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstanceReachability {
+    #[serde(default)]
+    pub last_success_at: Option<String>,
+    #[serde(default)]
+    pub last_failure_at: Option<String>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub consecutive_failures: u32, // Failed requests since the last one that got through
+}
+
+/// A container the proxy stopped for being idle, started again by the next request
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HibernatedInstance {
+    pub instance_id: String,
+    pub email: String,
+    pub hibernated_at: String,
+}
+
+/// Requests to an instance, or all of them, are answered with a 503 until it's lifted
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceWindow {
+    pub instance_id: Option<String>, // None for every instance
+    pub message: String,             // Shown to clients
+    pub started_at: String,
+    #[serde(default)]
+    pub until: Option<String>, // RFC 3339, when it's expected to be over (sets Retry-After)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MaintenanceRequest {
+    #[serde(default)]
+    pub instance_id: Option<String>, // Every instance when left out
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub until: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct MaintenanceQuery {
+    #[serde(default)]
+    pub instance_id: Option<String>, // The global window when left out
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MaintenanceResponse {
+    pub windows: Vec<MaintenanceWindow>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AuditQuery {
+    #[serde(default)]
+    pub user: Option<String>, // Every user when left out
+    #[serde(default)]
+    pub from: Option<String>, // RFC 3339, 24 hours ago by default
+    #[serde(default)]
+    pub to: Option<String>, // RFC 3339, now by default
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AuditResponse {
+    pub events: Vec<AuditEvent>, // Newest first
+    pub message: String,
+}
+
+/// An automatic restart of a container that stayed unhealthy
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RestartEvent {
+    pub instance_id: String,
+    pub email: String,
+    pub unhealthy_since: String,
+    pub restarted_at: String,
+    #[serde(default)]
+    pub error: Option<String>, // Why the restart failed, None when it went through
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct RestartEventsResponse {
+    pub instance_id: String,
+    pub events: Vec<RestartEvent>, // Newest first
+    pub message: String,
+}
+
+/// What a user can do to their own running instance
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceAction {
+    Start,
+    Stop,
+    Restart,
+}
+
+impl InstanceAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InstanceAction::Start => "start",
+            InstanceAction::Stop => "stop",
+            InstanceAction::Restart => "restart",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceLifecycleResponse {
+    pub is_done: bool,
+    pub state: Option<String>, // Container state afterwards, see `get_instance_state`
+    pub message: String,
+}
+
+/// Query of `GET /v1/blz/instance/logs`
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct InstanceLogsQuery {
+    #[serde(default)]
+    pub tail: Option<usize>, // Lines from the end, 200 by default, at most 1000
+    #[serde(default)]
+    pub follow: bool, // Keep streaming new lines until the client hangs up
+}
+
+/// Only sent when the logs couldn't be streamed, they come back as plain text otherwise
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceLogsResponse {
+    pub message: String,
+}
+
+/// Everything known about why the user's instance might be down
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceHealthResponse {
+    pub instance_id: Option<String>,
+    pub state: Option<String>, // Container state, see `get_instance_state`
+    pub is_healthy: bool,
+    pub started_at: Option<String>,
+    pub last_error_at: Option<String>, // When the container last exited
+    pub last_error: Option<String>,
+    pub reachability: String, // "reachable", "unreachable" or "unknown", as the proxy sees it
+    pub proxy: Option<InstanceReachability>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceCloneResponse {
+    pub instance_id: Option<String>, // The new instance, same API keys as the original
+    pub message: String,
+}
+
+/// Request structure for deleting the account
+/// Send without `otp` first to receive a confirmation code, then again with the code
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AccountDeleteRequest {
+    #[serde(default)]
+    pub otp: Option<String>,
+    #[serde(default)]
+    pub remove_volumes: bool, // Also wipe the instance data, otherwise it's kept as a backup
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AccountDeleteResponse {
+    pub is_deleted: bool,
+    pub is_code_sent: bool,
+    pub message: String,
+}
+
+/// Request structure for re-verifying after the proxy paused an API key
+/// Send without `otp` first to receive a confirmation code, then again with the code
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KeyReverifyRequest {
+    #[serde(default)]
+    pub otp: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct KeyReverifyResponse {
+    pub is_reverified: bool,
+    pub is_code_sent: bool,
+    pub message: String,
+}
+
 /// Structure representing a user
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct User {
+    #[serde(with = "pii_field")] // Encrypted at rest with `BLAZE_PII_KEY`
     pub username: String,
+    #[serde(with = "pii_field")]
     pub email: String,
     pub api_key: Vec<APIKey>,
     pub is_verified: bool,
     pub plans: Plans,
     pub instance_id: String,
     pub created_at: String,
+    #[serde(default)]
+    pub reverified_at: Option<String>, // Last re-verification after a key usage anomaly
+    #[serde(default)]
+    pub trial_expires_at: Option<String>, // Set while the user is on a free trial of a paid plan
+    #[serde(default)]
+    pub trial_used: bool, // One trial per account
+    #[serde(default)]
+    pub billing_status: BillingStatus,
+    #[serde(default)]
+    pub past_due_since: Option<String>, // When the first failed payment of the current dunning run came in
+    #[serde(default)]
+    pub dunning_reminders_sent: u32,
+    #[serde(default)]
+    pub clone_instance_ids: Vec<String>, // Instances cloned from `instance_id`, e.g. staging copies
+    #[serde(default)]
+    pub organization_id: Option<String>,
+    #[serde(default)]
+    pub billing_interval: BillingInterval,
+    #[serde(default)]
+    pub current_period_end: Option<String>, // End of the paid period, pushed back by every renewal
+    #[serde(default)]
+    pub referral_code: Option<String>, // Given to new users, existing ones get it on first lookup
+    #[serde(default)]
+    pub referred_by: Option<String>, // Email of the user whose code was redeemed
+    #[serde(default)]
+    pub subscription_state: SubscriptionState,
+    #[serde(default)]
+    pub stripe_subscription_id: Option<String>, // Set by the checkout that started the subscription
+    #[serde(default)]
+    pub stripe_customer_id: Option<String>, // Created on the first checkout, reused after that
+    #[serde(default)]
+    pub tax: TaxDetails, // From the last checkout that gave any
+    #[serde(default)]
+    pub instance_config: InstanceConfig,
+}
+
+impl User {
+    /// How stored users changed over time, add an upgrade here when a field is renamed or reshaped
+    /// - 2: `instance_url` became `instance_id`
+    pub fn record_schema() -> RecordSchema {
+        RecordSchema::new().upgrade(|record| rename_field(record, "instance_url", "instance_id"))
+    }
+
+    /// The primary instance followed by its clones
+    pub fn instance_ids(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.instance_id)
+            .filter(|id| !id.is_empty())
+            .chain(self.clone_instance_ids.iter())
+    }
+
+    pub fn owns_instance(&self, instance_id: &str) -> bool {
+        self.instance_ids().any(|id| id == instance_id)
+    }
+}
+
+/// A verified user on the built-in `plan` with nothing else set, tests override what they need
+#[cfg(test)]
+pub fn test_user(email: &str, plan: &str) -> User {
+    User {
+        username: email.split('@').next().unwrap_or(email).to_string(),
+        email: email.to_string(),
+        api_key: Vec::new(),
+        is_verified: true,
+        plans: crate::server::plans::builtin_plan(plan),
+        instance_id: String::new(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+        reverified_at: None,
+        trial_expires_at: None,
+        trial_used: false,
+        billing_status: BillingStatus::Active,
+        past_due_since: None,
+        dunning_reminders_sent: 0,
+        clone_instance_ids: vec![],
+        organization_id: None,
+        billing_interval: BillingInterval::Monthly,
+        current_period_end: None,
+        referral_code: None,
+        referred_by: None,
+        subscription_state: SubscriptionState::Active,
+        stripe_subscription_id: None,
+        stripe_customer_id: None,
+        tax: TaxDetails::default(),
+        instance_config: InstanceConfig::default(),
+    }
+}
+
+/// Where a paying user stands with their payments
+/// Active -> PastDue on a failed payment, PastDue -> Suspended once the grace period is over,
+/// back to Active from either as soon as a payment goes through
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BillingStatus {
+    #[default]
+    Active,
+    PastDue,   // Payment failed, the instance keeps running during the grace period
+    Suspended, // Grace period is over, the instance is stopped (data kept)
+}
+
+/// Where a paid subscription is in its life: Active -> CancelAtPeriodEnd when the user cancels,
+/// -> Canceled once the paid period is over and the user is back on the free plan
+/// A new checkout makes it Active again
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionState {
+    #[default]
+    Active,
+    CancelAtPeriodEnd, // Plan and instance stay until `current_period_end`, no renewal
+    Canceled,
+}
+
+/// Settings of the user's BlazeDB containers, unset fields use the service's defaults
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstanceConfig {
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    #[serde(default)]
+    pub embedding_api_url: Option<String>, // The service's embedding API when unset
+    #[serde(default)]
+    pub embedding_api_key: Option<String>, // Only sent to `embedding_api_url`
+    #[serde(default)]
+    pub log_level: Option<String>, // error, warn, info, debug or trace
+}
+
+impl InstanceConfig {
+    /// Without the API key, for showing it back to the user
+    pub fn redacted(&self) -> Self {
+        InstanceConfig {
+            embedding_api_key: self
+                .embedding_api_key
+                .as_ref()
+                .map(|_| "********".to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+/// Where the buyer pays tax, given at checkout
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaxDetails {
+    #[serde(default)]
+    pub country: Option<String>, // ISO 3166-1 alpha-2, "DE"
+    #[serde(default)]
+    pub vat_id: Option<String>, // Businesses only, with its country prefix, "DE123456789"
+}
+
+impl TaxDetails {
+    pub fn is_empty(&self) -> bool {
+        self.country.is_none() && self.vat_id.is_none()
+    }
+}
+
+/// Tax charged with a payment, as the tax provider worked it out
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaxRecord {
+    pub provider: String, // "stripe" or "none"
+    pub tax_cents: i64,   // Included in the payment's amount
+    #[serde(default)]
+    pub country: Option<String>,
+    #[serde(default)]
+    pub vat_id: Option<String>,
+    #[serde(default)]
+    pub reverse_charge: bool, // EU business customer, they account for the VAT themselves
+}
+
+/// Several users under one plan and one bill, the owner's plan applies to every member
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    pub owner_email: String,  // Pays for the organization
+    pub members: Vec<String>, // Emails, owner included
+    #[serde(default)]
+    pub pending_invites: Vec<String>,
+    pub created_at: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OrganizationCreateRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OrganizationInviteRequest {
+    pub email: String,
+}
+
+/// Request structure for joining an organization you were invited to
+/// Send without `otp` first to receive a confirmation code, then again with the code
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OrganizationJoinRequest {
+    pub organization_id: String,
+    #[serde(default)]
+    pub otp: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OrganizationResponse {
+    pub organization: Option<Organization>,
+    pub is_code_sent: bool,
+    pub message: String,
 }
 
 /// Safe user stats structure for public endpoints
@@ -104,66 +729,760 @@ impl From<User> for UserStats {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Plans {
     pub name: String,
     pub price_per_month: u32,
+    #[serde(default)]
+    pub price_per_year: u32, // Discounted yearly price, 0 means the plan is monthly only
     pub features: Feature,
+    #[serde(default)]
+    pub trial_days: u32, // 0 means the plan has no free trial
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+/// How often a paid plan is billed
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BillingInterval {
+    #[default]
+    Monthly,
+    Yearly,
+}
+
+impl BillingInterval {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BillingInterval::Monthly => "monthly",
+            BillingInterval::Yearly => "yearly",
+        }
+    }
+
+    pub fn months(self) -> u32 {
+        match self {
+            BillingInterval::Monthly => 1,
+            BillingInterval::Yearly => 12,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Feature {
     pub database_no: u32,
     pub vector_per_db: u32,
     pub demo_datasets_included: bool,
     pub dedicated_user_space: bool,
     pub embedding_api_access: bool,
+    #[serde(default)]
+    pub dedicated_server_instance: bool, // Pinned to a dedicated host when one is registered
+    #[serde(default)]
+    pub anomaly_action: AnomalyAction,
+    #[serde(default = "default_cpu_count")]
+    pub cpu_count: f64, // CPUs of the plan's container
+    #[serde(default = "default_memory_mb")]
+    pub memory_mb: i64, // Memory of the plan's container
+    #[serde(default = "default_pids_limit")]
+    pub pids_limit: i64, // Processes/threads the plan's container may run
+    #[serde(default)]
+    pub hibernate_after_hours: Option<u32>, // Stopped after this long without requests, None never
+    #[serde(default)]
+    pub backup_every_hours: Option<u32>, // Scheduled backups, None only backs up on request
+    #[serde(default = "default_backups_kept")]
+    pub backups_kept: usize, // Newest backups kept per instance
+    #[serde(default = "default_max_streams")]
+    pub max_streams: u32, // WebSockets and event streams open at once, per user
+    #[serde(default = "default_max_request_mb")]
+    pub max_request_mb: u64, // Largest request body the proxy forwards
+    #[serde(default = "default_max_response_mb")]
+    pub max_response_mb: u64, // Largest response body the proxy sends back
+}
+
+// Defaults match the free plan, for plan files written before the fields existed
+fn default_cpu_count() -> f64 {
+    0.5
+}
+
+fn default_memory_mb() -> i64 {
+    256
+}
+
+fn default_pids_limit() -> i64 {
+    128
+}
+
+fn default_backups_kept() -> usize {
+    4
+}
+
+fn default_max_streams() -> u32 {
+    5
+}
+
+fn default_max_request_mb() -> u64 {
+    10
+}
+
+fn default_max_response_mb() -> u64 {
+    50
+}
+
+/// What the proxy does when an API key's usage looks unusual (new country, volume spike)
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyAction {
+    Ignore,
+    /// Email a security alert to the user
+    #[default]
+    Alert,
+    /// Email the alert and pause the key until the user re-verifies
+    Reverify,
 }
 
 impl Plans {
-    pub fn free_plan() -> Self {
-        Plans {
-            name: "Free".to_string(),
-            price_per_month: 0,
-            features: Feature {
-                database_no: 5,
-                vector_per_db: 5_000,
-                demo_datasets_included: true,
-                dedicated_user_space: true,
-                embedding_api_access: false,
-            },
+    /// Price of one billing period, None when the plan can't be billed that way
+    pub fn price_for(&self, interval: BillingInterval) -> Option<u32> {
+        match interval {
+            BillingInterval::Monthly => Some(self.price_per_month),
+            BillingInterval::Yearly if self.price_per_year > 0 => Some(self.price_per_year),
+            BillingInterval::Yearly => None,
         }
     }
 
-    pub fn starter_plan() -> Self {
-        Plans {
-            name: "Starter".to_string(),
-            price_per_month: 12,
-            features: Feature {
-                database_no: 10,
-                vector_per_db: 100_000,
-                demo_datasets_included: true,
-                dedicated_user_space: true,
-                embedding_api_access: false,
-            },
-        }
+    /// Looks up a plan in the catalog by name (case-insensitive)
+    pub fn by_name(name: &str) -> Option<Self> {
+        let name = name.trim().to_lowercase();
+        get_plan_catalog()
+            .iter()
+            .find(|plan| plan.name.to_lowercase() == name)
+            .cloned()
+    }
+
+    /// Every plan in the catalog, cheapest first
+    pub fn all() -> Vec<Self> {
+        get_plan_catalog().as_ref().clone()
     }
 
-    pub fn pro_plan() -> Self {
-        Plans {
-            name: "Pro".to_string(),
-            price_per_month: 19,
-            features: Feature {
-                database_no: 20,
-                vector_per_db: 500_000,
-                demo_datasets_included: true,
-                dedicated_user_space: true,
-                embedding_api_access: true,
-            },
+    /// The plan new users start on and expired trials fall back to (the catalog's free plan)
+    pub fn default_plan() -> Self {
+        let catalog = get_plan_catalog();
+        catalog
+            .iter()
+            .find(|plan| plan.price_per_month == 0)
+            .unwrap_or(&catalog[0])
+            .clone()
+    }
+
+    /// How many vectors the plan holds across all its databases
+    pub fn vector_capacity(&self) -> u64 {
+        self.features.database_no as u64 * self.features.vector_per_db as u64
+    }
+
+    /// CPU count and memory (MB) of the plan's BlazeDB container
+    pub fn container_limits(&self) -> (f64, i64) {
+        (self.features.cpu_count, self.features.memory_mb)
+    }
+}
+
+/// Request structure for buying a plan
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CheckoutRequest {
+    pub plan: String, // "starter" or "pro"
+    #[serde(default)]
+    pub interval: BillingInterval,
+    #[serde(default)]
+    pub promo_code: Option<String>,
+    #[serde(default)]
+    pub country: Option<String>, // Billing country, "DE"
+    #[serde(default)]
+    pub vat_id: Option<String>, // EU/UK businesses, for the reverse charge
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CheckoutResponse {
+    pub checkout_url: Option<String>, // Redirect the user here to pay
+    pub session_id: Option<String>,
+    pub message: String,
+}
+
+/// A plan upgrade waiting for its Stripe Checkout Session to be paid
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PendingUpgrade {
+    pub session_id: String,
+    pub email: String,
+    pub plan: String,
+    pub status: String, // "pending", "paid" (plan change still due), "completed", "expired", "failed"
+    pub created_at: String,
+    #[serde(default)]
+    pub promo_code: Option<String>, // Redeemed once the payment completes
+    #[serde(default)]
+    pub interval: BillingInterval,
+    #[serde(default)]
+    pub credit_cents: i64, // Referral credit discounted, spent once the payment completes
+    #[serde(default)]
+    pub tax: TaxDetails,
+}
+
+/// How a coupon discounts the first payment
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscountKind {
+    Percent,    // `value` is a percentage, 1-100
+    FixedCents, // `value` is an amount in cents (USD)
+}
+
+/// A promo code users can enter at checkout
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Coupon {
+    pub code: String, // Stored uppercase
+    pub kind: DiscountKind,
+    pub value: u32,
+    #[serde(default)]
+    pub max_redemptions: Option<u32>, // None means unlimited
+    #[serde(default)]
+    pub redemptions: u32,
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub stripe_coupon_id: Option<String>, // Created on first use
+    pub created_at: String,
+}
+
+/// Request structure for an admin creating a coupon
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CouponCreateRequest {
+    pub code: String,
+    pub kind: DiscountKind,
+    pub value: u32,
+    #[serde(default)]
+    pub max_redemptions: Option<u32>,
+    #[serde(default)]
+    pub expires_at: Option<String>, // RFC 3339
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CouponResponse {
+    pub coupon: Option<Coupon>,
+    pub message: String,
+}
+
+/// Admin request to run the Docker pre-flight checks, on every registered host unless `host` is set
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PreflightRequest {
+    #[serde(default)]
+    pub host: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PreflightResponse {
+    pub ok: bool, // Every step passed on every host
+    pub hosts: Vec<HostPreflight>,
+    pub message: String,
+}
+
+/// How a user's subscription differs between us and Stripe
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BillingDriftKind {
+    PaidButFree,          // Live Stripe subscription, free plan here
+    PlanMismatch,         // Live Stripe subscription for another paid plan
+    CanceledInStripe,     // Subscription ended in Stripe, still on a paid plan here
+    MissingSubscription,  // Paid plan here, no subscription in Stripe at all (admin upgrades)
+    UnknownCustomer,      // Live Stripe subscription of an email we don't know
+    SubscriptionMismatch, // Another subscription id than the one on record
+    CancelStateMismatch,  // Cancel-at-period-end differs
+    PeriodMismatch,       // Period end is more than a day apart
+}
+
+impl BillingDriftKind {
+    /// Whether healing can fix it, the rest needs a human
+    pub fn is_healable(&self) -> bool {
+        !matches!(
+            self,
+            BillingDriftKind::MissingSubscription | BillingDriftKind::UnknownCustomer
+        )
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BillingDrift {
+    pub email: String,
+    pub kind: BillingDriftKind,
+    pub local: String,  // What we have, e.g. "Free"
+    pub stripe: String, // What Stripe has, e.g. "Pro (active)"
+    #[serde(default)]
+    pub subscription_id: Option<String>,
+    pub healed: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReconcileQuery {
+    #[serde(default)]
+    pub heal: bool, // Bring local state in line with Stripe
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReconcileReport {
+    pub users_checked: usize,
+    pub subscriptions_checked: usize,
+    pub drifts: Vec<BillingDrift>,
+    pub healed: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReconcileResponse {
+    pub report: Option<ReconcileReport>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct HostPreflight {
+    pub host: String,
+    pub ok: bool,
+    pub steps: Vec<PreflightStep>,
+}
+
+/// One pre-flight check (connect, pull_image, create_volume, run_container, cleanup)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PreflightStep {
+    pub name: String,
+    pub ok: bool,
+    pub skipped: bool,
+    pub detail: String, // What happened, or the error
+    pub duration_ms: u64,
+}
+
+/// Admin view of the email send caps and the deferred mail queue
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct MailQuotaResponse {
+    pub limits: Vec<MailQuotaStatus>,
+    pub queued: usize,
+    pub metrics: MailMetrics, // Since the service started
+    pub message: String,
+}
+
+/// Admin request to convert the user store to another format ("json", "sqlite" or "encrypted")
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StoreMigrationRequest {
+    pub format: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StoreMigrationResponse {
+    pub success: bool,
+    pub message: String,
+    pub report: Option<MigrationReport>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StorageStatsResponse {
+    pub stores: Vec<StoreStats>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StorageCompactResponse {
+    pub reports: Vec<CompactReport>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SecretRotationResponse {
+    pub success: bool,
+    pub message: String,
+    pub report: Option<SecretRotationReport>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StoreExportQuery {
+    #[serde(default)]
+    pub format: Option<String>, // "ndjson" (default) or "csv"
+}
+
+/// Only sent when the export fails, the export itself is the file
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StoreExportResponse {
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StoreImportQuery {
+    #[serde(default)]
+    pub overwrite: bool, // Replace entries already in the store, they're kept by default
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StoreImportResponse {
+    pub success: bool,
+    pub message: String,
+    pub report: Option<ImportReport>,
+}
+
+/// A snapshot of the service's own data (users, billing, usage, ...), see `snapshots`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ServiceSnapshot {
+    pub id: String,
+    pub reason: String, // e.g. "scheduled", "admin", "before restore"
+    pub files: usize,
+    pub size_bytes: u64,
+    pub sha256: String, // Of the tarball, checked before restoring from it
+    pub created_at: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SnapshotResponse {
+    pub snapshot: Option<ServiceSnapshot>,
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SnapshotListResponse {
+    pub snapshots: Vec<ServiceSnapshot>, // Newest first
+    pub message: String,
+}
+
+/// Admin request to go back to a snapshot, by id or as the newest taken at or before `at` (RFC 3339)
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SnapshotRestoreRequest {
+    #[serde(default)]
+    pub snapshot_id: Option<String>,
+    #[serde(default)]
+    pub at: Option<String>,
+}
+
+/// What a billing history entry is about
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BillingEventKind {
+    PlanChange,
+    Payment,
+    Refund,
+    PaymentFailed,
+    Cancellation,
+}
+
+/// One entry of a user's billing history
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BillingEvent {
+    pub kind: BillingEventKind,
+    pub description: String,
+    pub plan: String,
+    #[serde(default)]
+    pub previous_plan: Option<String>, // Only for plan changes
+    #[serde(default)]
+    pub amount_cents: Option<i64>, // Only for payments and refunds
+    #[serde(default)]
+    pub currency: Option<String>,
+    #[serde(default)]
+    pub reference: Option<String>, // Stripe object id, when there is one
+    #[serde(default)]
+    pub tax: Option<TaxRecord>, // Only for payments
+    pub created_at: String,
+}
+
+impl BillingEvent {
+    pub fn plan_change(previous_plan: &str, plan: &str) -> Self {
+        BillingEvent {
+            kind: BillingEventKind::PlanChange,
+            description: format!("Plan changed from {} to {}", previous_plan, plan),
+            plan: plan.to_string(),
+            previous_plan: Some(previous_plan.to_string()),
+            amount_cents: None,
+            currency: None,
+            reference: None,
+            tax: None,
+            created_at: chrono::Utc::now().to_rfc3339(),
         }
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BillingHistoryResponse {
+    pub events: Vec<BillingEvent>, // Newest first
+    pub message: String,
+}
+
+/// Query of the plan change preview, `?plan=Pro`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlanChangePreviewQuery {
+    pub plan: String,
+}
+
+/// What switching plans now costs, prorated over what's left of the billing cycle
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProrationPreview {
+    pub current_plan: String,
+    pub target_plan: String,
+    pub cycle_start: String, // RFC 3339
+    pub cycle_end: String,
+    pub days_remaining: i64,
+    pub credit_cents: i64,     // Unused part of the current plan
+    pub charge_cents: i64,     // Rest of the cycle on the target plan
+    pub amount_due_cents: i64, // charge - credit, negative is credited to the next invoice
+    pub currency: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlanChangePreviewResponse {
+    pub preview: Option<ProrationPreview>,
+    pub message: String,
+}
+
+/// One line of an invoice, payments are positive and refunds negative
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct InvoiceLine {
+    pub description: String,
+    pub amount_cents: i64,
+    #[serde(default)]
+    pub reference: Option<String>, // Stripe object id, when there is one
+}
+
+/// Monthly invoice summary of a paying user, emailed and kept as HTML under the billing path
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Invoice {
+    pub number: String, // "BLZ-202609-1A2B3C4D"
+    pub email: String,
+    pub period: String, // Calendar month, "2026-09"
+    pub plan: String,
+    pub billing_interval: BillingInterval,
+    pub lines: Vec<InvoiceLine>,
+    pub credit_applied_cents: i64, // Referral credit spent, already taken off the lines
+    pub total_cents: i64,
+    #[serde(default)]
+    pub tax_cents: i64, // Included in the total
+    #[serde(default)]
+    pub tax: TaxDetails,
+    #[serde(default)]
+    pub reverse_charge: bool,
+    pub currency: String,
+    pub requests: u64,
+    pub vectors_written: u64,
+    pub request_bytes: u64,
+    pub created_at: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InvoiceListResponse {
+    pub invoices: Vec<Invoice>, // Newest first
+    pub message: String,
+}
+
+/// One change of a user's credit balance, grants are positive and spending is negative
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CreditEntry {
+    pub amount_cents: i64,
+    pub description: String,
+    pub reference: String, // What it's for (a referral, a checkout session, an invoice), recorded once
+    pub created_at: String,
+}
+
+/// Request structure for redeeming someone's referral code
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReferralRedeemRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ReferralResponse {
+    pub referral_code: Option<String>, // The user's own code, to share
+    pub credit_cents: i64,             // Balance spent before the card is charged
+    pub currency: String,
+    pub message: String,
+}
+
+/// Usage of one instance during one hour, metered by the proxy
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UsageRecord {
+    pub hour: String, // RFC 3339, start of the hour
+    pub instance_id: String,
+    pub requests: u64,
+    pub request_bytes: u64,
+    pub vectors_written: u64, // Estimated from write request bodies
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UsageResponse {
+    pub total_requests: u64,
+    pub total_vectors_written: u64,
+    pub hourly: Vec<UsageRecord>, // Oldest first
+    pub message: String,
+}
+
+/// Traffic to one instance during one hour, measured by the proxy
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct MetricsRecord {
+    pub hour: String, // RFC 3339, start of the hour
+    pub instance_id: String,
+    pub requests: u64,
+    pub client_errors: u64, // 4xx
+    pub server_errors: u64, // 5xx, the proxy's own included
+    pub bytes_in: u64,      // Request bodies
+    pub bytes_out: u64,     // Response bodies
+    #[serde(default)]
+    pub latency_buckets: Vec<u64>, // Requests per `metrics::LATENCY_BUCKETS_MS` bucket, then slower ones
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct InstanceMetricsQuery {
+    #[serde(default)]
+    pub hours: Option<u32>, // How far back, 24 by default, at most 168
+}
+
+/// One instance's traffic over the requested window
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct InstanceMetrics {
+    pub instance_id: String,
+    pub requests: u64,
+    pub client_errors: u64,
+    pub server_errors: u64,
+    pub error_rate: f64, // Share of requests answered with a 4xx or 5xx
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub p50_latency_ms: Option<u64>, // None without any requests
+    pub p95_latency_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InstanceMetricsResponse {
+    pub hours: u32,
+    pub instances: Vec<InstanceMetrics>,
+    pub message: String,
+}
+
+/// Everything an SDK needs to know about what it can do with an instance, served by the proxy
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CapabilitiesResponse {
+    pub instance_id: String,
+    pub plan: String,
+    pub features: BTreeMap<String, bool>, // Plan gates merged with what the backend reports
+    pub limits: CapabilityLimits,
+    pub blocked_endpoints: Vec<String>,
+    pub backend_reachable: bool,
+    pub backend: Option<serde_json::Value>, // The backend's version/feature document as-is
+    pub generated_at: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct CapabilityLimits {
+    pub max_databases: u32,
+    pub max_vectors_per_db: u32,
+    pub max_streams: u32,
+    pub max_request_bytes: u64,
+    pub max_response_bytes: u64,
+}
+
+/// Which plan limit a write ran into
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaKind {
+    Databases,          // Feature.database_no
+    VectorsPerDatabase, // Feature.vector_per_db
+}
+
+/// Details of a write the proxy refused because of the plan's limits
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub kind: QuotaKind,
+    pub database: String,
+    pub limit: u64,
+    pub current: u64,   // What the instance has now
+    pub requested: u64, // What the write would add
+}
+
+/// Request structure for starting a free trial of a paid plan
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TrialRequest {
+    pub plan: String, // "pro"
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SubscriptionCancelResponse {
+    pub is_cancelled: bool,
+    pub access_until: Option<String>, // End of the paid period, the plan stays until then
+    pub message: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TrialResponse {
+    pub is_started: bool,
+    pub trial_expires_at: Option<String>,
+    pub message: String,
+}
+
+/// Plan suggestion from the user's metered usage
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlanRecommendation {
+    pub action: String, // "upgrade", "downgrade" or "stay"
+    pub current_plan: String,
+    pub recommended_plan: String,
+    pub reason: String,
+    pub estimated_vectors: u64,
+    pub vector_capacity: u64, // Of the current plan
+    pub utilization_percent: f64,
+    pub monthly_requests: u64, // Last 30 days
+    pub current_monthly_cost: u32,
+    pub projected_monthly_cost: u32,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlanRecommendationResponse {
+    pub recommendation: Option<PlanRecommendation>,
+    pub message: String,
+}
+
+/// Request structure for an admin moving a user to another plan
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlanChangeRequest {
+    pub email: String,
+    pub plan: String, // "free", "starter" or "pro"
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PlanChangeResponse {
+    pub is_changed: bool,
+    pub previous_plan: Option<String>,
+    pub message: String,
+}
+
+/// What kind of platform event an incident is about
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IncidentKind {
+    ContainerRestarts,
+    SmtpOutage,
+    StorageFailover,
+    Other,
+}
+
+/// A notable platform event users can correlate their errors with
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Incident {
+    pub id: String,
+    pub kind: IncidentKind,
+    pub title: String,
+    pub description: String,
+    pub source: String, // "auto" (detected by the service) or "admin"
+    pub started_at: String,
+    pub resolved_at: Option<String>, // None while the incident is ongoing
+}
+
+/// Request structure for an admin entering an incident
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IncidentCreateRequest {
+    pub kind: IncidentKind,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub started_at: Option<String>, // RFC 3339, defaults to now
+    #[serde(default)]
+    pub resolved_at: Option<String>, // Set when recording an incident after the fact
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct IncidentResponse {
+    pub incident: Option<Incident>,
+    pub message: String,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct UserData {
     pub unverified_users: Vec<UserStats>,
@@ -0,0 +1,472 @@
+//! # Secrets
+//!
+//! Every secret the service and proxy use (SMTP password, instance and token secrets, peppers,
+//! store and PII keys, admin token, Stripe keys) is loaded once at startup by `load_secrets` and
+//! read from memory afterwards, instead of going back to the env (and `.env`) on every call.
+//!
+//! Where they come from is `BLAZE_SECRETS_PROVIDER`:
+//!
+//! - `env` (default): the variables of the same name.
+//! - `file`: one file per secret, named after it, in `BLAZE_SECRETS_DIR` (`/run/secrets` by
+//!   default), as Docker and Kubernetes mount them.
+//! - `vault`: a KV secret at `BLAZE_VAULT_SECRET_PATH` (e.g. `secret/data/blaze`) read from
+//!   `VAULT_ADDR` with `VAULT_TOKEN`, its keys being the secret names.
+//! - `aws`: a Secrets Manager secret `BLAZE_AWS_SECRET_ID` holding a JSON object of the secrets,
+//!   with the usual `AWS_REGION`, `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+//!   `AWS_SESSION_TOKEN`.
+//!
+//! Secrets the provider doesn't have fall back to the env, so they can be moved over one at a
+//! time. Changing a secret (rotating it to `<NAME>_PREVIOUS`) takes a restart.
+
+use crate::{info, warn};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use zeroize::Zeroize;
+
+/// Every secret read through `get_secrets`
+pub const SECRET_NAMES: [&str; 13] = [
+    "APP_PASSWORD",
+    "BLAZE_INSTANCE_SECRET",
+    "BLAZE_INSTANCE_SECRET_PREVIOUS",
+    "BLAZE_INSTANCE_TOKEN_SECRET",
+    "BLAZE_INSTANCE_TOKEN_SECRET_PREVIOUS",
+    "BLAZE_API_KEY_PEPPER",
+    "BLAZE_API_KEY_PEPPER_PREVIOUS",
+    "BLAZE_PII_KEY",
+    "BLAZE_STORE_KEY",
+    "BLAZE_ADMIN_TOKEN",
+    "BLAZE_WEBHOOK_SECRET",
+    "STRIPE_SECRET_KEY",
+    "STRIPE_WEBHOOK_SECRET",
+];
+
+const DEFAULT_SECRETS_DIR: &str = "/run/secrets";
+const PROVIDER_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SECRETS: OnceLock<Secrets> = OnceLock::new();
+
+/// Where secrets are loaded from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretProvider {
+    Env,
+    Files {
+        dir: PathBuf,
+    },
+    Vault {
+        addr: String,
+        token: String,
+        path: String,
+    },
+    AwsSecretsManager {
+        region: String,
+        secret_id: String,
+        credentials: AwsCredentials,
+    },
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct AwsCredentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl std::fmt::Debug for AwsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AwsCredentials")
+            .field("access_key_id", &self.access_key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+fn required_env(name: &str) -> anyhow::Result<String> {
+    env_value(name).ok_or_else(|| anyhow::anyhow!("{} must be set in env", name))
+}
+
+impl SecretProvider {
+    /// The provider named by `BLAZE_SECRETS_PROVIDER`, with its settings from env
+    pub fn from_env() -> anyhow::Result<Self> {
+        dotenv::dotenv().ok();
+
+        match env_value("BLAZE_SECRETS_PROVIDER").as_deref() {
+            None | Some("env") => Ok(SecretProvider::Env),
+            Some("file") => Ok(SecretProvider::Files {
+                dir: PathBuf::from(
+                    env_value("BLAZE_SECRETS_DIR").unwrap_or_else(|| DEFAULT_SECRETS_DIR.into()),
+                ),
+            }),
+            Some("vault") => Ok(SecretProvider::Vault {
+                addr: required_env("VAULT_ADDR")?,
+                token: required_env("VAULT_TOKEN")?,
+                path: required_env("BLAZE_VAULT_SECRET_PATH")?,
+            }),
+            Some("aws") => Ok(SecretProvider::AwsSecretsManager {
+                region: env_value("AWS_REGION")
+                    .or_else(|| env_value("AWS_DEFAULT_REGION"))
+                    .ok_or_else(|| anyhow::anyhow!("AWS_REGION must be set in env"))?,
+                secret_id: required_env("BLAZE_AWS_SECRET_ID")?,
+                credentials: AwsCredentials {
+                    access_key_id: required_env("AWS_ACCESS_KEY_ID")?,
+                    secret_access_key: required_env("AWS_SECRET_ACCESS_KEY")?,
+                    session_token: env_value("AWS_SESSION_TOKEN"),
+                },
+            }),
+            Some(other) => Err(anyhow::anyhow!(
+                "Unknown BLAZE_SECRETS_PROVIDER: {} (env, file, vault or aws)",
+                other
+            )),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SecretProvider::Env => "env",
+            SecretProvider::Files { .. } => "file",
+            SecretProvider::Vault { .. } => "vault",
+            SecretProvider::AwsSecretsManager { .. } => "aws",
+        }
+    }
+
+    /// The secrets this provider has, among `SECRET_NAMES`
+    async fn fetch(&self) -> anyhow::Result<HashMap<String, String>> {
+        let fetched = match self {
+            SecretProvider::Env => HashMap::new(),
+            SecretProvider::Files { dir } => read_secret_files(dir)?,
+            SecretProvider::Vault { addr, token, path } => {
+                fetch_vault_secrets(addr, token, path).await?
+            }
+            SecretProvider::AwsSecretsManager {
+                region,
+                secret_id,
+                credentials,
+            } => fetch_aws_secrets(region, secret_id, credentials).await?,
+        };
+        Ok(fetched
+            .into_iter()
+            .map(|(name, value)| (name, value.trim().to_string()))
+            .filter(|(name, value)| SECRET_NAMES.contains(&name.as_str()) && !value.is_empty())
+            .collect())
+    }
+}
+
+/// Secrets as loaded at startup
+pub struct Secrets {
+    values: HashMap<String, String>,
+}
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        self.values.values_mut().for_each(|value| value.zeroize());
+    }
+}
+
+impl Secrets {
+    /// `fetched` over the env, for every name in `SECRET_NAMES`
+    fn with_env_fallback(mut fetched: HashMap<String, String>) -> Self {
+        for name in SECRET_NAMES {
+            if !fetched.contains_key(name)
+                && let Some(value) = env_value(name)
+            {
+                fetched.insert(name.to_string(), value);
+            }
+        }
+        Secrets { values: fetched }
+    }
+
+    /// Only the env, what's read when nothing called `load_secrets`
+    fn from_env() -> Self {
+        dotenv::dotenv().ok();
+        Secrets::with_env_fallback(HashMap::new())
+    }
+
+    /// The secret, None when it isn't set anywhere
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// The secret, an error naming it when it isn't set
+    pub fn require(&self, name: &str) -> anyhow::Result<&str> {
+        self.get(name)
+            .ok_or_else(|| anyhow::anyhow!("{} must be set (env or secrets provider)", name))
+    }
+}
+
+/// Loads the secrets from the configured provider, call it once at startup before anything reads
+/// them. Fails when the provider can't be reached, rather than running without its secrets
+pub async fn load_secrets() -> anyhow::Result<&'static Secrets> {
+    if let Some(secrets) = SECRETS.get() {
+        return Ok(secrets);
+    }
+    let provider = SecretProvider::from_env()?;
+    let fetched = provider.fetch().await?;
+    info!(
+        "Loaded {} secrets from the {} provider",
+        fetched.len(),
+        provider.name()
+    );
+    Ok(SECRETS.get_or_init(|| Secrets::with_env_fallback(fetched)))
+}
+
+/// Whether secrets read before `load_secrets` may come from the env alone, only when the env is
+/// the configured provider anyway
+fn is_env_fallback_allowed(provider: Option<&str>) -> bool {
+    matches!(provider, None | Some("env"))
+}
+
+/// Secrets loaded at startup. Read before `load_secrets` ran (tests, tools using the library),
+/// they come from the env alone. With another provider configured that would skip it, so it
+/// panics instead: `load_secrets` has to run first
+pub fn get_secrets() -> &'static Secrets {
+    if let Some(secrets) = SECRETS.get() {
+        return secrets;
+    }
+    dotenv::dotenv().ok();
+    let provider = env_value("BLAZE_SECRETS_PROVIDER");
+    if !is_env_fallback_allowed(provider.as_deref()) {
+        panic!(
+            "Secrets read before load_secrets with BLAZE_SECRETS_PROVIDER={}",
+            provider.unwrap_or_default()
+        );
+    }
+    SECRETS.get_or_init(|| {
+        warn!("Secrets read before load_secrets, using the env only");
+        Secrets::from_env()
+    })
+}
+
+/// Mounted secret files named after the secret, unreadable ones are skipped
+fn read_secret_files(dir: &Path) -> anyhow::Result<HashMap<String, String>> {
+    if !dir.is_dir() {
+        return Err(anyhow::anyhow!(
+            "Secrets directory {} doesn't exist",
+            dir.display()
+        ));
+    }
+    let mut secrets = HashMap::new();
+    for name in SECRET_NAMES {
+        let path = dir.join(name);
+        if !path.is_file() {
+            continue;
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(value) => {
+                secrets.insert(name.to_string(), value);
+            }
+            Err(e) => warn!("Ignoring unreadable secret file {}: {}", path.display(), e),
+        }
+    }
+    Ok(secrets)
+}
+
+/// String values of a JSON object, others are ignored
+fn string_fields(object: &serde_json::Value) -> HashMap<String, String> {
+    object
+        .as_object()
+        .map(|fields| {
+            fields
+                .iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Reads a Vault KV secret, version 2 nests the fields one level deeper than version 1
+async fn fetch_vault_secrets(
+    addr: &str,
+    token: &str,
+    path: &str,
+) -> anyhow::Result<HashMap<String, String>> {
+    let url = format!(
+        "{}/v1/{}",
+        addr.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let response: serde_json::Value = reqwest::Client::new()
+        .get(&url)
+        .timeout(PROVIDER_TIMEOUT)
+        .header("X-Vault-Token", token)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| anyhow::anyhow!("Failed to read secrets from Vault: {}", e))?
+        .json()
+        .await?;
+
+    let data = &response["data"];
+    Ok(match data.get("data") {
+        Some(fields) if fields.is_object() && data.get("metadata").is_some() => {
+            string_fields(fields)
+        }
+        _ => string_fields(data),
+    })
+}
+
+/// Reads a Secrets Manager secret whose string is a JSON object of the secrets
+async fn fetch_aws_secrets(
+    region: &str,
+    secret_id: &str,
+    credentials: &AwsCredentials,
+) -> anyhow::Result<HashMap<String, String>> {
+    let host = format!("secretsmanager.{}.amazonaws.com", region);
+    let body = serde_json::to_vec(&serde_json::json!({ "SecretId": secret_id }))?;
+    let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let target = "secretsmanager.GetSecretValue";
+
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1".to_string()),
+        ("host", host.clone()),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(token) = &credentials.session_token {
+        headers.push(("x-amz-security-token", token.clone()));
+    }
+    headers.push(("x-amz-target", target.to_string()));
+    let authorization = aws_authorization(
+        credentials,
+        region,
+        "secretsmanager",
+        &amz_date,
+        &headers,
+        &body,
+    );
+
+    let mut request = reqwest::Client::new()
+        .post(format!("https://{}/", host))
+        .timeout(PROVIDER_TIMEOUT)
+        .header(reqwest::header::AUTHORIZATION, authorization);
+    for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+        request = request.header(*name, value);
+    }
+    let response: serde_json::Value = request
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()
+        .map_err(|e| anyhow::anyhow!("Failed to read secrets from AWS Secrets Manager: {}", e))?
+        .json()
+        .await?;
+
+    let secret_string = response["SecretString"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("AWS secret {} has no SecretString", secret_id))?;
+    let fields: serde_json::Value = serde_json::from_str(secret_string)
+        .map_err(|_| anyhow::anyhow!("AWS secret {} must hold a JSON object", secret_id))?;
+    Ok(string_fields(&fields))
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// SigV4 signing key for a day (`YYYYMMDD`), region and service
+fn aws_signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> [u8; 32] {
+    let key = hmac_sha256(
+        format!("AWS4{}", secret_access_key).as_bytes(),
+        date.as_bytes(),
+    );
+    let key = hmac_sha256(&key, region.as_bytes());
+    let key = hmac_sha256(&key, service.as_bytes());
+    hmac_sha256(&key, b"aws4_request")
+}
+
+/// SigV4 `Authorization` header of a POST to `/`, `headers` lowercase and sorted by name
+fn aws_authorization(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> String {
+    let date = &amz_date[..8];
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>()
+        .join(";");
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+    let canonical_request = format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_headers,
+        hex::encode(Sha256::digest(body))
+    );
+
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+    let key = aws_signing_key(&credentials.secret_access_key, date, region, service);
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        scope,
+        signed_headers,
+        hex::encode(hmac_sha256(&key, string_to_sign.as_bytes()))
+    )
+}
+
+#[test]
+fn test_secret_providers() -> anyhow::Result<()> {
+    // Mounted files, trimmed, unknown names ignored
+    let dir = std::env::temp_dir().join(format!("blaze_secrets_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("APP_PASSWORD"), "smtp-password\n")?;
+    std::fs::write(dir.join("NOT_A_SECRET"), "ignored")?;
+    let files = SecretProvider::Files { dir: dir.clone() };
+    let fetched = tokio::runtime::Runtime::new()?.block_on(files.fetch())?;
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(
+        fetched.get("APP_PASSWORD").map(String::as_str),
+        Some("smtp-password")
+    );
+    assert_eq!(fetched.len(), 1);
+
+    let secrets = Secrets { values: fetched };
+    assert_eq!(secrets.get("APP_PASSWORD"), Some("smtp-password"));
+    assert!(secrets.require("STRIPE_SECRET_KEY").is_err());
+
+    // Read early, only the env provider may fall back to the env alone
+    assert!(is_env_fallback_allowed(None));
+    assert!(is_env_fallback_allowed(Some("env")));
+    assert!(!is_env_fallback_allowed(Some("vault")));
+    assert!(!is_env_fallback_allowed(Some("aws")));
+
+    // Vault KV version 2 nests the fields, version 1 doesn't
+    let v2 = serde_json::json!({"data": {"data": {"BLAZE_PII_KEY": "k"}, "metadata": {}}});
+    assert_eq!(string_fields(&v2["data"]["data"])["BLAZE_PII_KEY"], "k");
+
+    // Signing key derivation example from the AWS SigV4 documentation
+    let key = aws_signing_key(
+        "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+        "20120215",
+        "us-east-1",
+        "iam",
+    );
+    assert_eq!(
+        hex::encode(key),
+        "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d"
+    );
+
+    Ok(())
+}
@@ -1,55 +1,184 @@
 pub use crate::prelude::{
     Plans, User, UserRegisterRequest, UserRegisterResponse, VerifyEmailRequest, VerifyEmailResponse,
 };
+use crate::server::backups::{
+    BACKUP_VOLUMES, backup_file, create_backup, delete_backups, list_backups, restore_backup,
+};
+use crate::server::billing::record_billing_event;
 use crate::server::container::{
-    get_container_status, get_unique_instance_id, spawn_blazedb_container,
+    ContainerSpec, LOG_LEVELS, clone_instance_volumes, get_container_snapshot,
+    get_container_status, get_instance_state, get_local_host_info, has_instance_volumes,
+    recreate_blazedb_container, remove_container_with_volumes, reset_blazedb_container_data,
+    resize_blazedb_container, restart_blazedb_container, spawn_blazedb_container,
+    start_blazedb_container, stop_blazedb_container, stream_blazedb_container_logs,
+    wait_for_running,
 };
 use crate::server::crypto::{
-    APIKey, extract_email_from_api_key, hash_otp, verify_otp as crypto_verify_otp,
+    APIKey, InstanceTokenClaims, generate_otp, hash_api_key, hash_otp, issue_instance_token,
+    needs_rehash, otp_settings, verify_otp as crypto_verify_otp,
+};
+pub use crate::server::error::OtpFailure;
+use crate::server::error::{BlazeError, Result};
+use crate::server::hibernation::{forget_hibernation, get_hibernation};
+use crate::server::incidents::report_smtp_result;
+use crate::server::invalidation::invalidate_proxy_cache;
+use crate::server::key_index::{
+    find_api_key_owner, get_key_index_store, index_api_key, remove_key_owner,
+};
+use crate::server::mailer::{is_quota_exceeded, send_mail, send_mail_now};
+use crate::server::migration::{MigrationReport, migrate_store};
+use crate::server::organizations::{is_managed_member, remove_deleted_user, sync_member_plans};
+use crate::server::placement::get_placement_constraints;
+use crate::server::provisioning::{
+    forget_container, get_provisioning_store, provision_container, readiness_wait,
+    request_container,
+};
+use crate::server::reachability::{diagnose, get_reachability, reachability_verdict};
+use crate::server::referrals::generate_referral_code;
+use crate::server::rehash::{apply_queued_rehashes, get_key_rehash_store, replace_key_hash};
+use crate::server::rotation::resolve_instance_id;
+use crate::server::schema::{
+    BackupRecord, BillingEvent, BillingInterval, BillingStatus, InstanceAction, InstanceConfig,
+    InstanceHealthResponse, InstanceReadinessResponse, InstanceStatusResponse, OtpAttempt,
+    ResendCodeResponse, SubscriptionState, TaxDetails,
+};
+pub use crate::server::schema::{
+    OtpPurpose, OtpRecord, UserStats, VerifyOtpRequest, VerifyOtpResponse,
+};
+use crate::server::storage::{
+    CompactReport, DataStore, ExportFormat, ImportReport, StoreFormat, StoreMaintenance,
+    StoreStats, is_read_only,
 };
-use crate::server::schema::InstanceStatusResponse;
-pub use crate::server::schema::{OtpRecord, UserStats, VerifyOtpRequest, VerifyOtpResponse};
-use crate::server::storage::DataStore;
-use crate::{error, info};
-use anyhow::Result;
+use crate::server::tasks::get_task_registry;
+use crate::server::users::UserStore;
+use crate::{error, info, warn};
 use chrono::{DateTime, Duration, Utc};
-use lettre::message::{MultiPart, SinglePart};
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
 use rayon::iter::ParallelIterator;
 use rayon::prelude::IntoParallelRefIterator;
-use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::Mutex;
 
-static OTP_CACHE: std::sync::OnceLock<Arc<RwLock<HashMap<String, OtpRecord>>>> =
+static OTP_STORE: std::sync::OnceLock<DataStore<String, OtpRecord>> = std::sync::OnceLock::new();
+static OTP_RATE_LIMIT_STORE: std::sync::OnceLock<DataStore<String, i64>> =
     std::sync::OnceLock::new();
-static OTP_RATE_LIMIT: std::sync::OnceLock<Arc<RwLock<HashMap<String, i64>>>> =
+static OTP_ATTEMPT_STORE: std::sync::OnceLock<DataStore<String, OtpAttempt>> =
     std::sync::OnceLock::new();
-const OTP_COOLDOWN_SECONDS: i64 = 30; // 30 seconds cooldown between OTP requests
-static USER_STORE: std::sync::OnceLock<DataStore<String, User>> = std::sync::OnceLock::new();
-
-fn get_otp_cache() -> Arc<RwLock<HashMap<String, OtpRecord>>> {
-    OTP_CACHE
-        .get_or_init(|| Arc::new(RwLock::new(HashMap::new())))
+static OTP_SEND_LOCK: Mutex<()> = Mutex::const_new(()); // Cooldown check and update happen as one
+pub const OTP_COOLDOWN_SECONDS: i64 = 30; // 30 seconds cooldown between OTP requests
+const OTP_TTL_SECONDS: i64 = 60; // OTP valid for 1 minute
+pub const OTP_MAX_RESENDS: u32 = 3; // New codes per verification attempt, after the first
+const OTP_ATTEMPT_WINDOW_SECONDS: i64 = 900; // An attempt lasts 15 minutes from its first code
+const OTP_MAX_FAILED_ATTEMPTS: u32 = 5; // Wrong codes before the pending one stops working
+pub const OTP_COOLDOWN: &str = "otp_cooldown";
+pub const OTP_RESEND_LIMIT: &str = "otp_resend_limit";
+pub const NO_PENDING_VERIFICATION: &str = "no_pending_verification";
+static USER_STORE: std::sync::OnceLock<UserStore> = std::sync::OnceLock::new();
+static AUTH_PRIVACY_MODE: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+const PRIVACY_RESPONSE_FLOOR_MS: u64 = 600; // Every privacy mode auth response takes at least this long
+const PRIVACY_RESPONSE_JITTER_MS: u64 = 100;
+const INSTANCE_STATUS_PATH: &str = "/v1/blz/instance/status";
+static ALLOWED_EMAIL_DOMAINS: std::sync::OnceLock<Vec<String>> = std::sync::OnceLock::new();
+pub const EMAIL_DOMAIN_NOT_ALLOWED: &str = "email_domain_not_allowed";
+
+/// Pending OTPs by email, they expire with the code so a restart doesn't lose them
+fn get_otp_store() -> DataStore<String, OtpRecord> {
+    OTP_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("otps.json");
+            DataStore::<String, OtpRecord>::new(path)
+                .expect("CRASH!! Failed to initialize OTP datastore")
+        })
+        .clone()
+}
+/// Codes sent to each email in its current verification attempt, gone once the attempt is over
+fn get_otp_attempt_store() -> DataStore<String, OtpAttempt> {
+    OTP_ATTEMPT_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("otp_attempts.json");
+            DataStore::<String, OtpAttempt>::new(path)
+                .expect("CRASH!! Failed to initialize OTP attempt datastore")
+        })
         .clone()
 }
-fn get_rate_limit_cache() -> Arc<RwLock<HashMap<String, i64>>> {
-    OTP_RATE_LIMIT
-        .get_or_init(|| Arc::new(RwLock::new(HashMap::new())))
+/// When each email last asked for an OTP, kept for the cooldown
+fn get_otp_rate_limit_store() -> DataStore<String, i64> {
+    OTP_RATE_LIMIT_STORE
+        .get_or_init(|| {
+            let path = get_data_path().join("otp_rate_limits.json");
+            DataStore::<String, i64>::new(path)
+                .expect("CRASH!! Failed to initialize OTP rate limit datastore")
+        })
         .clone()
 }
-async fn get_user_store() -> DataStore<String, User> {
+async fn get_user_store() -> UserStore {
     USER_STORE
         .get_or_init(|| {
             let path = get_data_path().join("users.json");
-            DataStore::<String, User>::new(path)
+            // The proxy reloads its copy when the generation moves
+            let store = DataStore::<String, User>::with_schema(path, User::record_schema())
                 .expect("CRASH!! Failed to initialize user datastore")
+                .with_generation();
+            let users = UserStore::new(store);
+            // Users stored before BLAZE_PII_KEY was set (or with another one) go under their index
+            users
+                .rekey()
+                .expect("CRASH!! Failed to move users to their email index");
+            users
         })
         .clone()
 }
 
+/// Whether the auth endpoints hide which emails are registered (`BLAZE_AUTH_PRIVACY_MODE`, off by default)
+pub fn is_auth_privacy_mode() -> bool {
+    *AUTH_PRIVACY_MODE.get_or_init(|| {
+        dotenv::dotenv().ok();
+        std::env::var("BLAZE_AUTH_PRIVACY_MODE")
+            .map(|value| matches!(value.trim().to_lowercase().as_str(), "1" | "true"))
+            .unwrap_or(false)
+    })
+}
+
+/// Email domains allowed to register (`BLAZE_ALLOWED_EMAIL_DOMAINS`, comma separated)
+/// Empty means anyone can register, which is the default
+pub fn get_allowed_email_domains() -> &'static [String] {
+    ALLOWED_EMAIL_DOMAINS.get_or_init(|| {
+        dotenv::dotenv().ok();
+        std::env::var("BLAZE_ALLOWED_EMAIL_DOMAINS")
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|d| d.trim().trim_start_matches('@').to_lowercase())
+                    .filter(|d| !d.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+/// Whether the email's domain is in the allowlist (exact match, subdomains aren't included)
+pub fn is_email_domain_allowed(email: &str, allowed_domains: &[String]) -> bool {
+    if allowed_domains.is_empty() {
+        return true;
+    }
+
+    email
+        .trim()
+        .rsplit_once('@')
+        .map(|(_, domain)| domain.to_lowercase())
+        .is_some_and(|domain| allowed_domains.contains(&domain))
+}
+
+/// Sleeps until the request started at `started` has taken the privacy floor (plus some jitter),
+/// so known and unknown emails can't be told apart by response time
+pub async fn pad_auth_response(started: std::time::Instant) {
+    let jitter = rand::random::<u64>() % PRIVACY_RESPONSE_JITTER_MS;
+    let target = std::time::Duration::from_millis(PRIVACY_RESPONSE_FLOOR_MS + jitter);
+
+    if let Some(remaining) = target.checked_sub(started.elapsed()) {
+        tokio::time::sleep(remaining).await;
+    }
+}
+
 /// Creates necessary directories for the service: data, logs, and billing.
 pub async fn create_dirs() -> Result<()> {
     let data_path = get_data_path();
@@ -86,6 +215,11 @@ pub fn get_billing_path() -> PathBuf {
     home_dir.join("blz_service").join("billings")
 }
 
+pub fn get_backups_path() -> PathBuf {
+    let home_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    home_dir.join("blz_service").join("backups")
+}
+
 /// Saves to new user to In-Memory datastore
 pub async fn save_user(user_data: &UserRegisterRequest) -> Result<UserRegisterResponse> {
     let user_store = get_user_store().await;
@@ -96,33 +230,191 @@ pub async fn save_user(user_data: &UserRegisterRequest) -> Result<UserRegisterRe
         email: user_data.email.clone(),
         api_key: Vec::new(),
         is_verified: false,
-        plans: Plans::free_plan(),
+        plans: Plans::default_plan(),
         instance_id: String::with_capacity(8 * 16),
         created_at: Utc::now().to_rfc3339(),
+        reverified_at: None,
+        trial_expires_at: None,
+        trial_used: false,
+        billing_status: BillingStatus::Active,
+        past_due_since: None,
+        dunning_reminders_sent: 0,
+        clone_instance_ids: vec![],
+        organization_id: None,
+        billing_interval: BillingInterval::Monthly,
+        current_period_end: None,
+        referral_code: Some(generate_referral_code()),
+        referred_by: None,
+        subscription_state: SubscriptionState::Active,
+        stripe_subscription_id: None,
+        stripe_customer_id: None,
+        tax: TaxDetails::default(),
+        instance_config: InstanceConfig::default(),
     };
 
     // Insert in memory only
     // Periodic background task will save to disk
-    user_store.insert_mem(user_data.email.clone(), user)?;
+    user_store.insert_mem(&user_data.email, user)?;
 
     let response = UserRegisterResponse {
         email: user_data.email.clone(),
         is_created: true,
         error: "null".to_string(),
+        error_code: None,
     };
 
     Ok(response)
 }
 
-/// Periodically saves user data from memory to disk
+/// Saves the users changed in memory since the last save, nothing when none did
 pub async fn periodic_save_users() -> Result<()> {
+    // A migration is writing the file, it saves everything itself
+    if is_read_only() {
+        return Ok(());
+    }
     let user_store = get_user_store().await;
-    user_store.save_to_disk()?;
+    let saved = user_store.flush_async().await?;
+    if saved > 0 {
+        info!("Saved {} changed user(s)", saved);
+    }
     Ok(())
 }
 
+/// The service's stores by name, listed together for storage diagnostics and compaction and
+/// picked by name for exports and imports
+async fn maintained_stores() -> Vec<(&'static str, Box<dyn StoreMaintenance>)> {
+    vec![
+        ("users", Box::new(get_user_store().await.store().clone())),
+        ("api_key_index", Box::new(get_key_index_store())),
+        ("otp", Box::new(get_otp_store())),
+        ("otp_rate_limits", Box::new(get_otp_rate_limit_store())),
+        ("otp_attempts", Box::new(get_otp_attempt_store())),
+        (
+            "billing",
+            Box::new(crate::server::billing::get_billing_store()),
+        ),
+        (
+            "coupons",
+            Box::new(crate::server::billing::get_coupon_store()),
+        ),
+        (
+            "billing_history",
+            Box::new(crate::server::billing::get_billing_history_store()),
+        ),
+        (
+            "invoices",
+            Box::new(crate::server::invoices::get_invoice_store()),
+        ),
+        (
+            "credits",
+            Box::new(crate::server::referrals::get_credit_store()),
+        ),
+        (
+            "organizations",
+            Box::new(crate::server::organizations::get_organization_store()),
+        ),
+        ("ports", Box::new(crate::server::ports::get_port_store())),
+        ("provisioning", Box::new(get_provisioning_store())),
+        (
+            "hibernation",
+            Box::new(crate::server::hibernation::get_hibernation_store()),
+        ),
+        (
+            "backups",
+            Box::new(crate::server::backups::get_backup_store()),
+        ),
+        (
+            "snapshots",
+            Box::new(crate::server::snapshots::get_snapshot_store()),
+        ),
+        (
+            "incidents",
+            Box::new(crate::server::incidents::get_incident_store()),
+        ),
+        (
+            "maintenance",
+            Box::new(crate::server::maintenance::get_maintenance_store()),
+        ),
+        (
+            "metrics",
+            Box::new(crate::server::metrics::get_metrics_store()),
+        ),
+    ]
+}
+
+/// The store called `name`, see `maintained_stores`
+async fn maintained_store(name: &str) -> Result<Box<dyn StoreMaintenance>> {
+    maintained_stores()
+        .await
+        .into_iter()
+        .find(|(store_name, _)| *store_name == name)
+        .map(|(_, store)| store)
+        .ok_or_else(|| BlazeError::validation(format!("No store called {}", name)))
+}
+
+/// Entry counts, sizes and last saves of the service's stores, see `DataStore::stats`
+pub async fn storage_stats() -> Result<Vec<StoreStats>> {
+    let stores = maintained_stores().await;
+    let stats = tokio::task::spawn_blocking(move || {
+        stores
+            .iter()
+            .map(|(_, store)| store.stats())
+            .collect::<Result<Vec<_>>>()
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    Ok(stats)
+}
+
+/// Compacts every store of the service, see `DataStore::compact`
+pub async fn compact_stores() -> Result<Vec<CompactReport>> {
+    let stores = maintained_stores().await;
+    let reports = tokio::task::spawn_blocking(move || {
+        stores
+            .iter()
+            .map(|(_, store)| store.compact())
+            .collect::<Result<Vec<_>>>()
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    Ok(reports)
+}
+
+/// Exports the store called `name` (see `maintained_stores`), returns the file's content and the
+/// number of entries in it
+pub async fn export_store(name: &str, format: ExportFormat) -> Result<(Vec<u8>, usize)> {
+    let store = maintained_store(name).await?;
+    let export = tokio::task::spawn_blocking(move || {
+        let mut content = Vec::new();
+        let entries = store.export(format, &mut content)?;
+        Ok::<_, BlazeError>((content, entries))
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    Ok(export)
+}
+
+/// Imports an NDJSON export into the store called `name`, see `DataStore::import`
+pub async fn import_store(name: &str, content: Vec<u8>, overwrite: bool) -> Result<ImportReport> {
+    let store = maintained_store(name).await?;
+    let report =
+        tokio::task::spawn_blocking(move || store.import(&mut content.as_slice(), overwrite))
+            .await
+            .map_err(anyhow::Error::from)??;
+    Ok(report)
+}
+
+/// Converts the user store to another format, the service is read-only meanwhile
+pub async fn migrate_user_store(target: StoreFormat) -> Result<MigrationReport> {
+    let user_store = get_user_store().await;
+    let report = tokio::task::spawn_blocking(move || migrate_store(user_store.store(), target))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(report)
+}
+
 /// Checks if a user with the given email exists in the datastore.
-pub async fn is_user_exists(email: &String) -> Result<bool> {
+pub async fn is_user_exists(email: &str) -> Result<bool> {
     let datastore = get_user_store().await;
     if let Some(_user) = datastore.get(email)? {
         Ok(true)
@@ -132,7 +424,7 @@ pub async fn is_user_exists(email: &String) -> Result<bool> {
 }
 
 /// Checks if the user with the given email is verified
-pub async fn is_user_verified(email: &String) -> Result<bool> {
+pub async fn is_user_verified(email: &str) -> Result<bool> {
     let datastore = get_user_store().await;
     if let Some(user) = datastore.get(email)? {
         Ok(user.is_verified)
@@ -141,154 +433,1046 @@ pub async fn is_user_verified(email: &String) -> Result<bool> {
     }
 }
 
-/// Initiates the email verification process by sending a verification code to the user's email
-pub async fn verify_user(data: &VerifyEmailRequest) -> Result<VerifyEmailResponse> {
-    match send_verification_code(&data.email).await {
-        Ok(is_sent) => {
-            info!("Verification code sent to {}", &data.email);
-            Ok(VerifyEmailResponse {
-                is_code_sent: is_sent,
-                error: "".to_string(),
-            })
-        }
-        Err(e) => Ok(VerifyEmailResponse {
-            is_code_sent: false,
-            error: format!("Failed to send verification code: {}", e),
-        }),
-    }
+/// Returns the user's current plan
+pub async fn get_user_plan(email: &str) -> Result<Plans> {
+    let datastore = get_user_store().await;
+    datastore
+        .get(email)?
+        .map(|user| user.plans)
+        .ok_or_else(|| BlazeError::auth("User not found"))
+}
+
+/// Initiates the email verification process by sending a verification code to the user's email
+pub async fn verify_user(data: &VerifyEmailRequest) -> Result<VerifyEmailResponse> {
+    match send_verification_code(&data.email, OtpPurpose::Verification).await {
+        Ok(is_sent) => {
+            info!("Verification code sent to {}", &data.email);
+            Ok(VerifyEmailResponse {
+                is_code_sent: is_sent,
+                error: "".to_string(),
+                error_code: None,
+            })
+        }
+        Err(e) => Ok(VerifyEmailResponse {
+            is_code_sent: false,
+            error: format!("Failed to send verification code: {}", e),
+            error_code: None,
+        }),
+    }
+}
+
+/// Checks a submitted code against the email's pending one, a wrong code counts against it
+/// A code sent for another purpose reads as no code. Returns the pending record when the code
+/// matches, it's up to the caller to consume it
+async fn check_otp(
+    otp_store: &DataStore<String, OtpRecord>,
+    attempt_store: &DataStore<String, OtpAttempt>,
+    email: &str,
+    otp: &str,
+    purpose: OtpPurpose,
+) -> Result<OtpRecord> {
+    let email_key = email.to_string();
+
+    let Some(otp_record) = otp_store.get(&email_key)? else {
+        // Gone with its TTL, the attempt it belonged to outlives it
+        return Err(BlazeError::Otp(
+            if attempt_store.contains_key(&email_key)? {
+                OtpFailure::Expired
+            } else {
+                OtpFailure::NoCode
+            },
+        ));
+    };
+    if otp_record.purpose != purpose {
+        return Err(BlazeError::Otp(OtpFailure::NoCode));
+    }
+    if otp_record.failed_attempts >= OTP_MAX_FAILED_ATTEMPTS {
+        return Err(BlazeError::Otp(OtpFailure::TooManyAttempts));
+    }
+
+    let expires_at = DateTime::parse_from_rfc3339(&otp_record.expires_at)?.with_timezone(&Utc);
+    if Utc::now() > expires_at {
+        otp_store.delete_async(&email_key).await?;
+        return Err(BlazeError::Otp(OtpFailure::Expired));
+    }
+
+    let otp_hash_bytes = hex::decode(&otp_record.otp_hash)?;
+    if !crypto_verify_otp(otp, &otp_hash_bytes) {
+        // Kept with the record (and its expiry), so guesses stop at the limit until a new code
+        let failed_attempts = otp_store
+            .update_async(&email_key, |record| record.failed_attempts += 1)
+            .await?
+            .map_or(OTP_MAX_FAILED_ATTEMPTS, |record| record.failed_attempts);
+        if failed_attempts >= OTP_MAX_FAILED_ATTEMPTS {
+            warn!(
+                "Too many invalid codes for {}, the pending one is burned",
+                email
+            );
+            return Err(BlazeError::Otp(OtpFailure::TooManyAttempts));
+        }
+        return Err(BlazeError::Otp(OtpFailure::WrongCode));
+    }
+
+    Ok(otp_record)
+}
+
+/// Consumes the pending code `otp_record` was checked against and ends its attempt
+/// False when it was used meanwhile or replaced by a new one, wrong guesses made since the check
+/// only moved its counter and don't stop it
+async fn consume_otp(
+    otp_store: &DataStore<String, OtpRecord>,
+    attempt_store: &DataStore<String, OtpAttempt>,
+    email: &str,
+    otp_record: &OtpRecord,
+) -> Result<bool> {
+    let email_key = email.to_string();
+    loop {
+        let Some(current) = otp_store.get(&email_key)? else {
+            return Ok(false);
+        };
+        if current.otp_hash != otp_record.otp_hash || current.created_at != otp_record.created_at {
+            return Ok(false);
+        }
+        // Only one of two concurrent consumptions gets it, re-read when a guess got in between
+        if otp_store
+            .compare_and_swap_async(&email_key, Some(&current), None)
+            .await?
+        {
+            break;
+        }
+    }
+    // The attempt is over, a later one gets a fresh resend budget
+    attempt_store.delete_async(&email_key).await?;
+    Ok(true)
+}
+
+/// Why a code was refused when the email has none pending, verified users included
+fn missing_code_failure(user: Option<&User>) -> OtpFailure {
+    match user {
+        None => OtpFailure::UnknownEmail,
+        Some(_) => OtpFailure::NoCode,
+    }
+}
+
+/// Verifies the OTP code provided by the user and updates their verification status
+/// Fails with `BlazeError::Otp` when the code is refused
+pub async fn verify_otp(data: &VerifyOtpRequest) -> Result<VerifyOtpResponse> {
+    let otp_store = get_otp_store();
+    let attempt_store = get_otp_attempt_store();
+    let user_datastore = get_user_store().await;
+
+    let otp_record = match check_otp(
+        &otp_store,
+        &attempt_store,
+        &data.email,
+        &data.otp,
+        OtpPurpose::Verification,
+    )
+    .await
+    {
+        Err(BlazeError::Otp(OtpFailure::NoCode)) => {
+            let user = user_datastore.get(&data.email)?;
+            return Err(BlazeError::Otp(missing_code_failure(user.as_ref())));
+        }
+        result => result?,
+    };
+
+    let user = match user_datastore.get(&data.email)? {
+        Some(u) => u,
+        // README: Edge case, This should not happen because user must exist to have OTP, but just in case
+        None => {
+            otp_store.delete_async(&data.email).await?;
+            return Err(BlazeError::Otp(OtpFailure::UnknownEmail));
+        }
+    };
+
+    // Make sure the instance has somewhere to go before verifying, the OTP stays valid for a retry
+    match get_local_host_info().await {
+        Ok(host) => {
+            if let Err(e) = get_placement_constraints().choose_host(&user.plans, &[host]) {
+                warn!("Placement refused for {}: {}", user.email, e);
+                return Err(BlazeError::Otp(OtpFailure::NoCapacity));
+            }
+        }
+        // Spawning will fail and log on its own if Docker is really down
+        Err(e) => warn!("Could not read host load for placement: {}", e),
+    }
+
+    let unique_instance_id = resolve_instance_id(&user.email).await?;
+
+    // Consume the OTP first, a concurrent verification with the same code stops here
+    if !consume_otp(&otp_store, &attempt_store, &data.email, &otp_record).await? {
+        return Err(BlazeError::Otp(OtpFailure::Expired));
+    }
+
+    let (api_key_struct, plain_key) = APIKey::get_new_key(&user.username, &user.email);
+
+    // Applied to the stored user under the lock, so a change saved meanwhile isn't overwritten
+    let Some(user) = user_datastore
+        .update_async(&data.email, |user| {
+            user.is_verified = true;
+            user.instance_id = unique_instance_id.clone();
+            user.api_key.push(api_key_struct);
+        })
+        .await?
+    else {
+        return Err(BlazeError::Otp(OtpFailure::UnknownEmail));
+    };
+
+    // Opaque keys are found through the index, it has to know the key before it's handed out
+    // Indexed once the user holds it, so a failed update doesn't leave an entry behind
+    index_api_key(&plain_key, &user.email).await?;
+
+    // Recorded first, so the provisioning loop retries it if this spawn fails
+    if let Err(e) = request_container(&unique_instance_id, &user.email) {
+        error!(
+            "Failed to record container {} for provisioning: {}",
+            unique_instance_id, e
+        );
+    }
+
+    // Spawned in the background, so a client giving up on the response doesn't stop it
+    let instance_id = unique_instance_id.clone();
+    get_task_registry().spawn("provision-container", |_| async move {
+        info!(
+            "🐳 Spawning BlazeDB container for user: {} (instance_id: {})",
+            user.email, unique_instance_id
+        );
+
+        // Don't fail the verification, failed attempts are retried with backoff
+        if let Err(e) = provision_container(&unique_instance_id).await {
+            error!("Failed to spawn container for {}: {}", user.email, e);
+        }
+    });
+
+    // Hold the key back a little, so it doesn't point at an instance that fails every request
+    let is_ready = match wait_for_running(&instance_id, readiness_wait()).await {
+        Ok(is_ready) => is_ready,
+        Err(e) => {
+            warn!("Readiness check of {} failed: {}", instance_id, e);
+            false
+        }
+    };
+
+    Ok(VerifyOtpResponse {
+        is_verified: true,
+        message: if is_ready {
+            "Email verified successfully, your instance is ready".to_string()
+        } else {
+            "Email verified successfully, your instance is still starting".to_string()
+        },
+        api_key: Some(plain_key), // Return plain key ONLY this once
+        instance_id: Some(instance_id),
+        // Otherwise clients poll the status until it's ready
+        instance_state: Some(if is_ready { "running" } else { "provisioning" }.to_string()),
+        status_url: Some(INSTANCE_STATUS_PATH.to_string()),
+    })
+}
+
+/// Where the confirmation of a sensitive action stands, see `confirm_action`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionConfirmation {
+    CodeSent,  // No code was given, one was mailed to be sent back
+    Confirmed, // The code was right and is spent, go ahead
+}
+
+/// Confirms a sensitive action (instance reset, etc) for an already verified user in two steps:
+/// without `otp` a code for `purpose` is mailed, with it the code is checked and consumed so it
+/// can't be replayed. Fails with `BlazeError::Otp` when the code is refused, and `Validation`
+/// while the send cooldown runs
+pub async fn confirm_action(
+    email: &str,
+    otp: Option<&str>,
+    purpose: OtpPurpose,
+) -> Result<ActionConfirmation> {
+    let Some(otp) = otp.filter(|otp| !otp.trim().is_empty()) else {
+        if !send_verification_code(email, purpose).await? {
+            return Err(BlazeError::mail("Failed to send confirmation code"));
+        }
+        info!("{} confirmation code sent to {}", purpose.as_str(), email);
+        return Ok(ActionConfirmation::CodeSent);
+    };
+
+    let otp_store = get_otp_store();
+    let attempt_store = get_otp_attempt_store();
+    let otp_record = check_otp(&otp_store, &attempt_store, email, otp, purpose).await?;
+    // Only one of two concurrent confirmations with the same code gets it
+    if !consume_otp(&otp_store, &attempt_store, email, &otp_record).await? {
+        return Err(BlazeError::Otp(OtpFailure::Expired));
+    }
+    Ok(ActionConfirmation::Confirmed)
+}
+
+/// Wipes all data of the user's instance and restarts it fresh (account and API keys are kept)
+pub async fn reset_instance(email: &str) -> Result<()> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation("User has no instance to reset"));
+    }
+
+    info!(
+        "Resetting instance data for user: {} (instance_id: {})",
+        user.email, user.instance_id
+    );
+
+    reset_blazedb_container_data(&user.instance_id, &ContainerSpec::for_user(&user)).await?;
+
+    Ok(())
+}
+
+/// Backs up both volumes of the user's primary instance
+pub async fn backup_instance(email: &str) -> Result<BackupRecord> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation("User has no instance to back up"));
+    }
+
+    Ok(create_backup(
+        &user.instance_id,
+        &user.email,
+        "manual",
+        user.plans.features.backups_kept,
+    )
+    .await?)
+}
+
+/// Backups of the user's primary instance, newest first
+pub async fn list_instance_backups(email: &str) -> Result<Vec<BackupRecord>> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    Ok(list_backups(&user.instance_id)?)
+}
+
+/// The tarball of one volume ("config" or "sources") of a backup of the user's primary instance
+pub async fn get_backup_file(email: &str, backup_id: &str, volume: &str) -> Result<PathBuf> {
+    if !BACKUP_VOLUMES.contains(&volume) {
+        return Err(BlazeError::validation(format!(
+            "Unknown volume {}, expected one of: {}",
+            volume,
+            BACKUP_VOLUMES.join(", ")
+        )));
+    }
+
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !list_backups(&user.instance_id)?
+        .iter()
+        .any(|b| b.id == backup_id)
+    {
+        return Err(BlazeError::validation(format!(
+            "No backup {} for your instance",
+            backup_id
+        )));
+    }
+
+    Ok(backup_file(&user.instance_id, backup_id, volume))
+}
+
+/// Replaces the data of the user's primary instance with a backup, in a fresh container
+pub async fn restore_instance(email: &str, backup_id: &str) -> Result<BackupRecord> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation("User has no instance to restore"));
+    }
+    if user.billing_status == BillingStatus::Suspended {
+        return Err(BlazeError::validation(
+            "Your instance is suspended until the unpaid invoice is settled",
+        ));
+    }
+
+    info!(
+        "Restoring backup {} for user: {} (instance_id: {})",
+        backup_id, user.email, user.instance_id
+    );
+
+    restore_backup(
+        &user.instance_id,
+        backup_id,
+        &ContainerSpec::for_user(&user),
+    )
+    .await?
+    .ok_or_else(|| BlazeError::validation(format!("No backup {} for your instance", backup_id)))
+}
+
+/// Starts, stops or restarts the user's primary instance and returns its state afterwards
+/// A suspended instance stays stopped until the unpaid invoice is settled
+pub async fn control_instance(email: &str, action: InstanceAction) -> Result<&'static str> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation(format!(
+            "User has no instance to {}",
+            action.as_str()
+        )));
+    }
+    if user.billing_status == BillingStatus::Suspended && action != InstanceAction::Stop {
+        return Err(BlazeError::validation(
+            "Your instance is suspended until the unpaid invoice is settled",
+        ));
+    }
+
+    info!(
+        "Instance {} for user: {} (instance_id: {})",
+        action.as_str(),
+        user.email,
+        user.instance_id
+    );
+
+    let spec = ContainerSpec::for_user(&user);
+    match action {
+        InstanceAction::Start => start_blazedb_container(&user.instance_id, &spec).await?,
+        InstanceAction::Stop => {
+            // A container still being provisioned or hibernated would be started again
+            forget_container(&user.instance_id)?;
+            if get_hibernation(&user.instance_id)?.is_some() {
+                forget_hibernation(&user.instance_id)?;
+            }
+            stop_blazedb_container(&user.instance_id).await?
+        }
+        InstanceAction::Restart => restart_blazedb_container(&user.instance_id, &spec).await?,
+    }
+
+    get_instance_state(&user.instance_id).await
+}
+
+/// Trims the config and drops blank fields, errors on anything a container can't be given
+fn normalize_instance_config(config: InstanceConfig) -> Result<InstanceConfig> {
+    let clean = |field: Option<String>, name: &str, max_len: usize| -> Result<Option<String>> {
+        let Some(value) = field
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+        else {
+            return Ok(None);
+        };
+        if value.len() > max_len || value.chars().any(char::is_control) {
+            return Err(BlazeError::validation(format!("Invalid {}", name)));
+        }
+        Ok(Some(value))
+    };
+
+    let config = InstanceConfig {
+        embedding_model: clean(config.embedding_model, "embedding model", 200)?,
+        embedding_api_url: clean(config.embedding_api_url, "embedding API URL", 2048)?,
+        embedding_api_key: clean(config.embedding_api_key, "embedding API key", 512)?,
+        log_level: clean(config.log_level, "log level", 16)?.map(|l| l.to_lowercase()),
+    };
+
+    if let Some(url) = &config.embedding_api_url
+        && !reqwest::Url::parse(url).is_ok_and(|u| matches!(u.scheme(), "http" | "https"))
+    {
+        return Err(BlazeError::validation(
+            "Embedding API URL must be an http(s) URL",
+        ));
+    }
+    if config.embedding_api_key.is_some() && config.embedding_api_url.is_none() {
+        return Err(BlazeError::validation(
+            "An embedding API key needs your own embedding API URL",
+        ));
+    }
+    if let Some(level) = &config.log_level
+        && !LOG_LEVELS.contains(&level.as_str())
+    {
+        return Err(BlazeError::validation(format!(
+            "Unknown log level {}, expected one of: {}",
+            level,
+            LOG_LEVELS.join(", ")
+        )));
+    }
+
+    Ok(config)
+}
+
+/// The user's instance config
+pub async fn get_instance_config(email: &str) -> Result<InstanceConfig> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    Ok(user.instance_config)
+}
+
+/// Recreates the instance's container with `spec` if it has one, a stopped one stays stopped
+async fn apply_container_spec(instance_id: &str, spec: &ContainerSpec) -> Result<bool> {
+    let state = get_instance_state(instance_id).await?;
+    if state == "missing" {
+        return Ok(false); // Provisioning spawns it with the saved config
+    }
+    recreate_blazedb_container(instance_id, spec).await?;
+    if state == "stopped" {
+        stop_blazedb_container(instance_id).await?;
+    }
+    Ok(true)
+}
+
+/// Replaces the user's instance config and recreates their containers with the new env
+/// Returns the saved config and the instances that were recreated
+pub async fn update_instance_config(
+    email: &str,
+    config: InstanceConfig,
+) -> Result<(InstanceConfig, Vec<String>)> {
+    let config = normalize_instance_config(config)?;
+
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified {
+        return Err(BlazeError::auth("User is not verified"));
+    }
+    if user.billing_status == BillingStatus::Suspended {
+        return Err(BlazeError::validation(
+            "Your instance is suspended until the unpaid invoice is settled",
+        ));
+    }
+    if user.instance_config == config {
+        return Ok((config, Vec::new()));
+    }
+
+    let previous_spec = ContainerSpec::for_user(&user);
+    let new_spec = ContainerSpec::new(&user.plans, &config);
+    let mut recreated = Vec::new();
+
+    // Primary first, so a failed Docker call doesn't leave the user with a config it doesn't run
+    if !user.instance_id.is_empty() {
+        info!(
+            "Recreating instance {} for {} with a new config",
+            user.instance_id, user.email
+        );
+
+        match apply_container_spec(&user.instance_id, &new_spec).await {
+            Ok(true) => recreated.push(user.instance_id.clone()),
+            Ok(false) => {}
+            Err(e) => {
+                error!(
+                    "Failed to apply the new config to {}, restoring the previous one: {}",
+                    user.instance_id, e
+                );
+                if let Err(rollback) = apply_container_spec(&user.instance_id, &previous_spec).await
+                {
+                    error!(
+                        "Failed to restore the previous config on {}: {}",
+                        user.instance_id, rollback
+                    );
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    // Clones are best effort, like on a plan change
+    for clone_id in &user.clone_instance_ids {
+        match apply_container_spec(clone_id, &new_spec).await {
+            Ok(true) => recreated.push(clone_id.clone()),
+            Ok(false) => {}
+            Err(e) => error!("Failed to apply the new config to {}: {}", clone_id, e),
+        }
+    }
+
+    user_store
+        .update_async(email, |user| user.instance_config = config.clone())
+        .await?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    Ok((config, recreated))
+}
+
+/// Streams the logs of the user's primary instance, see `stream_blazedb_container_logs`
+pub async fn get_instance_logs(
+    email: &str,
+    tail: usize,
+    follow: bool,
+) -> Result<impl futures_util::Stream<Item = Result<axum::body::Bytes>> + Send + use<>> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation("User has no instance yet"));
+    }
+
+    info!(
+        "Streaming logs of {} for user: {} (tail: {}, follow: {})",
+        user.instance_id, user.email, tail, follow
+    );
+    stream_blazedb_container_logs(&user.instance_id, tail, follow).await
+}
+
+/// The user's primary instance as Docker and the proxy see it, for telling why it's down
+pub async fn get_instance_health(email: &str) -> Result<InstanceHealthResponse> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation("User has no instance yet"));
+    }
+
+    let snapshot = get_container_snapshot(&format!("blazedb-{}", user.instance_id)).await?;
+    let state = snapshot.state;
+
+    let proxy = get_reachability(&user.instance_id)?;
+    let reachability = reachability_verdict(proxy.as_ref());
+    let message = if user.billing_status == BillingStatus::Suspended {
+        "Your instance is suspended until the unpaid invoice is settled".to_string()
+    } else if state == "stopped" && get_hibernation(&user.instance_id)?.is_some() {
+        "Your instance is asleep after being idle, the next request wakes it".to_string()
+    } else if snapshot.oom_killed {
+        "Your instance ran out of memory and was killed, a bigger plan gives it more".to_string()
+    } else {
+        diagnose(state, reachability)
+    };
+
+    // Docker leaves zero timestamps and empty errors around, they mean nothing happened
+    let non_empty = |s: String| (!s.is_empty() && !s.starts_with("0001-")).then_some(s);
+    Ok(InstanceHealthResponse {
+        instance_id: Some(user.instance_id.clone()),
+        state: Some(state.to_string()),
+        is_healthy: snapshot.is_healthy,
+        started_at: non_empty(snapshot.started_at),
+        last_error_at: non_empty(snapshot.finished_at),
+        last_error: non_empty(snapshot.error),
+        reachability: reachability.to_string(),
+        proxy,
+        message,
+    })
+}
+
+/// Whether the user may clone their instance: paid plans only, and every instance (the primary
+/// one and its clones) counts against the plan's `database_no`
+pub fn check_can_clone(user: &User) -> std::result::Result<(), String> {
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err("You have no instance to clone".to_string());
+    }
+    if user.plans.price_per_month == 0 {
+        return Err("Cloning instances needs a paid plan".to_string());
+    }
+    let limit = user.plans.features.database_no;
+    if user.instance_ids().count() >= limit as usize {
+        return Err(format!(
+            "The {} plan allows {} instances, delete one or upgrade",
+            user.plans.name, limit
+        ));
+    }
+    Ok(())
+}
+
+/// Snapshots the user's instance into a new one with the same plan limits
+/// Returns the new instance id, the original keeps running (briefly paused during the copy)
+pub async fn clone_instance(email: &str) -> Result<String> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+    check_can_clone(&user).map_err(BlazeError::Validation)?;
+
+    let clone_id = hex::encode(rand::random::<[u8; 16]>());
+    info!(
+        "Cloning instance {} for {} into {}",
+        user.instance_id, user.email, clone_id
+    );
+
+    let cloned = async {
+        clone_instance_volumes(&user.instance_id, &clone_id).await?;
+        spawn_blazedb_container(&clone_id, &ContainerSpec::for_user(&user)).await
+    }
+    .await;
+    if let Err(e) = cloned {
+        // Don't leave half copied volumes behind
+        if let Err(cleanup) = remove_container_with_volumes(&clone_id).await {
+            error!("Failed to clean up clone {}: {}", clone_id, cleanup);
+        }
+        return Err(e);
+    }
+
+    update_user(email, |user| user.clone_instance_ids.push(clone_id.clone())).await?;
+
+    Ok(clone_id)
+}
+
+/// Moves the user to another plan in one go: resizes their container to the plan's limits,
+/// saves the new plan and records the change in the billing history
+pub async fn change_plan(email: &str, new_plan: Plans) -> Result<Plans> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified {
+        return Err(BlazeError::auth("User is not verified"));
+    }
+
+    if user.plans.name == new_plan.name {
+        return Err(BlazeError::validation(format!(
+            "User is already on the {} plan",
+            new_plan.name
+        )));
+    }
+
+    let previous_plan = user.plans.clone();
+
+    let previous_spec = ContainerSpec::new(&previous_plan, &user.instance_config);
+    let new_spec = ContainerSpec::new(&new_plan, &user.instance_config);
+
+    // Resize first, so a failed Docker call doesn't leave the user on a plan they don't have
+    if !user.instance_id.is_empty() {
+        info!(
+            "Resizing instance {} for {}: {} -> {}",
+            user.instance_id, user.email, previous_plan.name, new_plan.name
+        );
+
+        if let Err(e) = resize_blazedb_container(&user.instance_id, &previous_spec, &new_spec).await
+        {
+            error!(
+                "Failed to resize instance {}, restoring the {} limits: {}",
+                user.instance_id, previous_plan.name, e
+            );
+            if let Err(rollback) =
+                recreate_blazedb_container(&user.instance_id, &previous_spec).await
+            {
+                error!(
+                    "Failed to restore the {} limits on {}: {}",
+                    previous_plan.name, user.instance_id, rollback
+                );
+            }
+            return Err(e);
+        }
+    }
+
+    // Clones are best effort, a failure here shouldn't undo the plan change
+    for clone_id in &user.clone_instance_ids {
+        if let Err(e) = resize_blazedb_container(clone_id, &previous_spec, &new_spec).await {
+            error!("Failed to resize cloned instance {}: {}", clone_id, e);
+        }
+    }
+
+    // Only the plan, so changes saved while the containers were resized aren't lost
+    let user = update_user(email, |user| user.plans = new_plan.clone()).await?;
+
+    record_billing_event(
+        &user.email,
+        BillingEvent::plan_change(&previous_plan.name, &new_plan.name),
+    )?;
+
+    info!(
+        "Plan changed for {}: {} -> {}",
+        user.email, previous_plan.name, new_plan.name
+    );
+
+    // An organization owner's plan is every member's plan
+    // Boxed, members go through change_plan too
+    if user.organization_id.is_some() {
+        match Box::pin(sync_member_plans(&user.email, &new_plan)).await {
+            Ok(0) => {}
+            Ok(synced) => info!(
+                "Moved {} organization member(s) to {}",
+                synced, new_plan.name
+            ),
+            Err(e) => error!(
+                "Failed to sync organization plans for {}: {}",
+                user.email, e
+            ),
+        }
+    }
+
+    Ok(previous_plan)
+}
+
+/// Starts a free trial of a paid plan, the user is moved to the plan right away
+/// Returns when the trial expires (RFC 3339)
+pub async fn start_trial(email: &String, plan: Plans) -> Result<String> {
+    if plan.trial_days == 0 {
+        return Err(BlazeError::validation(format!(
+            "The {} plan has no free trial",
+            plan.name
+        )));
+    }
+
+    let user_store = get_user_store().await;
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if user.trial_used {
+        return Err(BlazeError::validation("Free trial was already used"));
+    }
+    if is_managed_member(&user)? {
+        return Err(BlazeError::validation(
+            "Your plan is managed by your organization",
+        ));
+    }
+    if user.plans.price_per_month > 0 {
+        return Err(BlazeError::validation(
+            "Trials are only for users on the Free plan",
+        ));
+    }
+
+    let expires_at = (Utc::now() + Duration::days(plan.trial_days as i64)).to_rfc3339();
+
+    // Claimed under the store lock, so two requests at once can't both start a trial
+    let mut is_claimed = false;
+    user_store
+        .update_async(email, |user| {
+            if !user.trial_used {
+                user.trial_used = true;
+                is_claimed = true;
+            }
+        })
+        .await?;
+    if !is_claimed {
+        return Err(BlazeError::validation("Free trial was already used"));
+    }
+
+    if let Err(e) = change_plan(email, plan).await {
+        // The trial never started, it's still theirs to use
+        user_store
+            .update_async(email, |user| user.trial_used = false)
+            .await?;
+        return Err(e);
+    }
+
+    update_user(email, |user| {
+        user.trial_expires_at = Some(expires_at.clone())
+    })
+    .await?;
+
+    info!("Trial started for {} until {}", email, expires_at);
+
+    Ok(expires_at)
+}
+
+/// Whether the user is currently on a free trial
+pub async fn is_user_on_trial(email: &str) -> Result<bool> {
+    let user_store = get_user_store().await;
+    Ok(user_store
+        .get(email)?
+        .is_some_and(|user| user.trial_expires_at.is_some()))
 }
 
-// TODO: Decouple the checks for explicit error status code
-/// Verifies the OTP code provided by the user and updates their verification status
-pub async fn verify_otp(data: &VerifyOtpRequest) -> Result<VerifyOtpResponse> {
-    let otp_cache = get_otp_cache();
+/// Applies `update` to a stored user and saves it, returns the updated user
+pub async fn update_user<F>(email: &str, update: F) -> Result<User>
+where
+    F: FnOnce(&mut User),
+{
+    let user_store = get_user_store().await;
+    let user = user_store
+        .update_async(email, update)
+        .await?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
 
-    // Check if OTP record exists for this email
-    let otp_record = {
-        let cache_read = otp_cache.read().await;
-        cache_read.get(&data.email).cloned()
-    };
+    invalidate_proxy_cache(Some(email));
+    Ok(user)
+}
 
-    let otp_record = match otp_record {
-        Some(record) => record,
-        None => {
-            // Dont remove the OTP yet, user may retry within valid time
-            return Ok(VerifyOtpResponse {
-                is_verified: false,
-                message: "No verification code found for this email".to_string(),
-                api_key: None,
-                instance_id: None,
-            });
-        }
-    };
+/// Ends the user's trial without downgrading, when they pay for the plan
+pub async fn end_trial(email: &str) -> Result<()> {
+    let user_store = get_user_store().await;
+    user_store
+        .update_async(email, |user| user.trial_expires_at = None)
+        .await?;
+    Ok(())
+}
+
+/// Whether the user's trial is over
+fn is_trial_expired(user: &User, now: DateTime<Utc>) -> bool {
+    user.trial_expires_at
+        .as_deref()
+        .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        .is_some_and(|expires_at| expires_at <= now)
+}
 
-    // Check if OTP has expired
+/// Moves users whose trial is over back to Free and lets them know by email
+/// This is called periodically via a background task
+pub async fn downgrade_expired_trials() -> Result<usize> {
     let now = Utc::now();
-    let expires_at = DateTime::parse_from_rfc3339(&otp_record.expires_at)?.with_timezone(&Utc);
+    let expired: Vec<User> = get_all_users()
+        .await?
+        .into_iter()
+        .filter(|user| is_trial_expired(user, now))
+        .collect();
 
-    if now > expires_at {
-        // Clean up expired OTP
-        let mut cache_write = otp_cache.write().await;
-        cache_write.remove(&data.email);
-        return Ok(VerifyOtpResponse {
-            is_verified: false,
-            message: "Verification code has expired".to_string(),
-            api_key: None,
-            instance_id: None,
-        });
-    }
+    let mut downgraded = 0;
+    for user in expired {
+        let trial_plan = user.plans.name.clone();
 
-    // Verify the OTP
-    let otp_hash_bytes = hex::decode(&otp_record.otp_hash)?;
-    let is_valid = crypto_verify_otp(&data.otp, &otp_hash_bytes).await;
-
-    if !is_valid {
-        return Ok(VerifyOtpResponse {
-            is_verified: false,
-            message: "Invalid verification code".to_string(),
-            api_key: None,
-            instance_id: None,
+        // Already back on it (cancelled, moved by hand), only the trial is left to clear
+        if trial_plan == Plans::default_plan().name {
+            end_trial(&user.email).await?;
+            continue;
+        }
+
+        if let Err(e) = change_plan(&user.email, Plans::default_plan()).await {
+            error!("Failed to end trial for {}: {}", user.email, e);
+            continue;
+        }
+        end_trial(&user.email).await?;
+        downgraded += 1;
+
+        let email = user.email.clone();
+        let username = user.username.clone();
+        get_task_registry().spawn("trial-ended-mail", |_| async move {
+            let (plain_body, html_body) = build_trial_ended_email(&username, &trial_plan);
+            let sent = tokio::task::spawn_blocking(move || {
+                send_mail(
+                    &email,
+                    "Your BlazeDB trial has ended",
+                    plain_body,
+                    html_body,
+                )
+                .map_err(|e| (email, e))
+            })
+            .await;
+            if let Ok(Err((email, e))) = sent {
+                error!("Failed to send trial ended email to {}: {}", email, e);
+            }
         });
     }
 
-    let user_datastore = get_user_store().await;
+    Ok(downgraded)
+}
 
-    let mut user = match user_datastore.get(&data.email)? {
-        Some(u) => u,
-        // README: Edge case, This should not happen because user must exist to have OTP, but just in case
-        None => {
-            {
-                let mut cache_write = otp_cache.write().await;
-                cache_write.remove(&data.email);
-            }
-            return Ok(VerifyOtpResponse {
-                is_verified: false,
-                message: "User not found".to_string(),
-                api_key: None,
-                instance_id: None,
-            });
-        }
-    };
+fn build_trial_ended_email(username: &str, plan: &str) -> (String, String) {
+    let plain_body = format!(
+        "Hi {},\n\nYour {} trial has ended and your account is back on the Free plan. Your data is kept, but Free plan limits apply again.\n\nUpgrade any time via POST /v1/billing/checkout.",
+        username, plan
+    );
+    let html_body = format!(
+        r#"
+        <html>
+        <body style="font-family: sans-serif;">
+            <h2>Your {plan} trial has ended</h2>
+            <p>Hi {username},</p>
+            <p>Your account is back on the <strong>Free</strong> plan. Your data is kept, but Free plan limits apply again.</p>
+            <p>Upgrade any time via <code>POST /v1/billing/checkout</code>.</p>
+        </body>
+        </html>
+        "#
+    );
+    (plain_body, html_body)
+}
 
-    // Do all updates first, then write back, if any fails before writing
-    // So that the user is not updated or data is corrupted and can retry OTP verification without issues
+/// Issues a short-lived signed token for the user's instance, so the proxy can skip the user lookup
+pub async fn create_instance_token(email: &str) -> Result<(String, InstanceTokenClaims)> {
+    let user_store = get_user_store().await;
 
-    // Update user verification status
-    user.is_verified = true;
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation(
+            "User has no instance to issue a token for",
+        ));
+    }
 
-    // Assign instance ID
-    let unique_instance_id = get_unique_instance_id(user.email.clone());
-    user.instance_id = unique_instance_id.clone();
+    Ok(issue_instance_token(&user.instance_id, &user.email)?)
+}
 
-    // Assign API key upon successful verification
-    let (api_key_struct, plain_key) = APIKey::get_new_key(&user.username, &user.email).await;
-    user.api_key.push(api_key_struct.clone());
+/// Records that the user re-verified, lifting any key pause the proxy put in place before now
+pub async fn mark_user_reverified(email: &String) -> Result<()> {
+    update_user(email, |user| {
+        user.reverified_at = Some(Utc::now().to_rfc3339())
+    })
+    .await?;
 
-    // Write back ALL changes atomically
-    user_datastore.insert_mem(data.email.clone(), user.clone())?;
+    info!("User re-verified after key usage anomaly: {}", email);
 
-    // Clean up used OTP from memory cache
-    {
-        let mut cache_write = otp_cache.write().await;
-        cache_write.remove(&data.email);
-    }
+    Ok(())
+}
 
-    // Spawn container asynchronously, we don't want to block the response while waiting for container to be ready
-    tokio::spawn(async move {
-        info!(
-            "🐳 Spawning BlazeDB container for user: {} (instance_id: {})",
-            user.email, unique_instance_id
-        );
+/// Deletes the user's account: revokes every key, tears down the containers and purges all records
+/// The volumes are backed up before they're removed, unless `remove_volumes` asks for the data
+/// (backups included) to be wiped
+pub async fn delete_account(email: &String, remove_volumes: bool) -> Result<()> {
+    let user_store = get_user_store().await;
 
-        // TODO: Retry logic!!! or inst health or spin up endpoint in service
-        match spawn_blazedb_container(&unique_instance_id, 0.5, 512).await {
-            Ok(_) => {
-                info!("Container spawned successfully for {}", user.email);
-            }
-            Err(e) => {
-                error!("Failed to spawn container for {}: {}", user.email, e);
-                // Don't fail the verification, just log the error
-                // TODO: User can still use the service, container can be spawned later
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    // Before anything is revoked, an owner with members can't go
+    remove_deleted_user(&user)?;
+
+    // Revoke and persist first, so the keys are dead even if a later step fails
+    // A key added meanwhile is revoked too, the update sees it
+    let user = user_store
+        .update_async(email, |user| {
+            for key in user.api_key.iter_mut() {
+                key.is_revoked = true;
             }
+        })
+        .await?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+    invalidate_proxy_cache(Some(email));
+
+    for instance_id in user.instance_ids() {
+        if remove_volumes {
+            delete_backups(instance_id).await?;
+        } else if has_instance_volumes(instance_id).await? {
+            let keep = user.plans.features.backups_kept;
+            create_backup(instance_id, email, "account deletion", keep).await?;
         }
-    });
+        remove_container_with_volumes(instance_id).await?;
+    }
 
-    Ok(VerifyOtpResponse {
-        is_verified: true,
-        message: "Email verified successfully".to_string(),
-        api_key: Some(plain_key), // Return plain key ONLY this once
-        instance_id: Some(user.instance_id),
-    })
+    user_store.delete_async(email).await?;
+    remove_key_owner(email).await?;
+
+    get_otp_store().delete_async(email).await?;
+    get_otp_rate_limit_store().delete_async(email).await?;
+    get_otp_attempt_store().delete_async(email).await?;
+
+    info!(
+        "Deleted account for user: {} (volumes removed: {})",
+        email, remove_volumes
+    );
+
+    Ok(())
 }
 
-#[allow(unused)]
 /// Verifies an API key and returns the associated user email if valid
 /// Returns None if the key is invalid, revoked, or not found
 pub async fn verify_api_key(api_key: &str) -> Result<Option<String>> {
-    // Extract email from API key (format: blz_{base64_email}_{secret})
-    let email = match extract_email_from_api_key(api_key) {
+    // Read from the key (blz1_{base64_email}_{secret}) or the index for opaque keys
+    let email = match find_api_key_owner(&get_key_index_store(), api_key)? {
         Some(e) => e,
-        None => return Ok(None), // Invalid format
+        None => return Ok(None), // Invalid format or unknown key
     };
 
     // Get user from storage
@@ -300,7 +1484,10 @@ pub async fn verify_api_key(api_key: &str) -> Result<Option<String>> {
 
     // Verify the key against user's stored keys
     for stored_key in &user.api_key {
-        if stored_key.verify(api_key).await {
+        if stored_key.verify(api_key) {
+            if needs_rehash(api_key, &stored_key.api_key_hash) {
+                rehash_api_key(&email, &stored_key.api_key_hash, api_key).await?;
+            }
             return Ok(Some(email));
         }
     }
@@ -308,58 +1495,249 @@ pub async fn verify_api_key(api_key: &str) -> Result<Option<String>> {
     Ok(None) // Key not found or revoked
 }
 
-/// Just Sends a verification code (OTP) to the specified email address and stores the hashed OTP in the datastore
-pub async fn send_verification_code(email: &str) -> Result<bool> {
-    let rate_limit_cache = get_rate_limit_cache();
-    let now_timestamp = Utc::now().timestamp();
+/// Replaces a key's bare SHA-256 hash, or one made with the previous pepper, with one made with
+/// the current pepper, now that the key was presented
+async fn rehash_api_key(email: &str, old_hash: &str, api_key: &str) -> Result<()> {
+    let user_store = get_user_store().await;
+    if replace_key_hash(&user_store, email, old_hash, &hash_api_key(api_key)).await? {
+        info!("Rehashed an API key of {} with the current pepper", email);
+    }
+    Ok(())
+}
 
-    // Check rate limiting using write lock
-    // This prevents race conditions where multiple threads could slip through
-    {
-        let mut rate_write = rate_limit_cache.write().await;
-        if let Some(&last_request) = rate_write.get(email) {
-            let elapsed = now_timestamp - last_request;
-            if elapsed < OTP_COOLDOWN_SECONDS {
-                let remaining = OTP_COOLDOWN_SECONDS - elapsed;
-                info!(
-                    "Rate limit hit for {}: {} seconds remaining",
-                    email, remaining
-                );
-                return Err(anyhow::anyhow!(
-                    "Please wait {} seconds before requesting a new code",
-                    remaining
-                ));
+/// Stores the new key hashes the proxy queued, returns how many users' keys changed
+/// See `server::rehash`
+pub async fn apply_key_rehashes() -> Result<usize> {
+    let user_store = get_user_store().await;
+    let emails = apply_queued_rehashes(&get_key_rehash_store(), &user_store).await?;
+    for email in &emails {
+        info!("Rehashed an API key of {} with the current pepper", email);
+        invalidate_proxy_cache(Some(email));
+    }
+    Ok(emails.len())
+}
+
+/// Why no code was sent to an email
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeRefused {
+    CoolingDown { retry_after: i64 }, // Seconds until the cooldown is over
+    ResendLimit { retry_after: i64 }, // Seconds until the verification attempt is over
+}
+
+impl CodeRefused {
+    pub fn retry_after(&self) -> i64 {
+        match self {
+            CodeRefused::CoolingDown { retry_after } | CodeRefused::ResendLimit { retry_after } => {
+                *retry_after
             }
         }
-        // Update rate limit (before releasing lock)
-        rate_write.insert(email.to_string(), now_timestamp);
     }
 
-    // Generate a random 6-digit OTP
-    let otp: String = (0..6)
-        .map(|_| rand::random::<u8>() % 10)
-        .map(|digit| char::from(b'0' + digit))
-        .collect();
+    pub fn error_code(&self) -> &'static str {
+        match self {
+            CodeRefused::CoolingDown { .. } => OTP_COOLDOWN,
+            CodeRefused::ResendLimit { .. } => OTP_RESEND_LIMIT,
+        }
+    }
+}
+
+impl std::fmt::Display for CodeRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodeRefused::CoolingDown { retry_after } => write!(
+                f,
+                "Please wait {} seconds before requesting a new code",
+                retry_after
+            ),
+            CodeRefused::ResendLimit { retry_after } => write!(
+                f,
+                "Too many codes requested, please wait {} seconds before trying again",
+                retry_after
+            ),
+        }
+    }
+}
+
+/// New codes left in an attempt
+fn resends_remaining(attempt: &OtpAttempt) -> u32 {
+    (OTP_MAX_RESENDS + 1).saturating_sub(attempt.sends)
+}
+
+/// Counts a code against the email's cooldown, and a resend against its verification attempt,
+/// refused when either is spent. A first code starts the attempt, other codes leave its budget
+/// alone. Checked and updated holding the send lock, so concurrent requests can't slip through
+async fn reserve_code(
+    rate_limit_store: &DataStore<String, i64>,
+    attempt_store: &DataStore<String, OtpAttempt>,
+    email: &str,
+    now: i64,
+    is_resend: bool,
+) -> Result<std::result::Result<OtpAttempt, CodeRefused>> {
+    let email_key = email.to_string();
+
+    let _sending = OTP_SEND_LOCK.lock().await;
+    if let Some(last_request) = rate_limit_store.get(&email_key)? {
+        let elapsed = now - last_request;
+        if elapsed < OTP_COOLDOWN_SECONDS {
+            let remaining = OTP_COOLDOWN_SECONDS - elapsed;
+            info!(
+                "Rate limit hit for {}: {} seconds remaining",
+                email, remaining
+            );
+            return Ok(Err(CodeRefused::CoolingDown {
+                retry_after: remaining,
+            }));
+        }
+    }
+
+    // Over once its window has passed, even if the TTL sweep hasn't dropped it yet
+    let current = attempt_store
+        .get(&email_key)?
+        .filter(|attempt| now < attempt.started_at + OTP_ATTEMPT_WINDOW_SECONDS);
+    let attempt = match current {
+        Some(attempt) if !is_resend => attempt,
+        Some(attempt) if resends_remaining(&attempt) == 0 => {
+            info!("Resend limit hit for {}", email);
+            return Ok(Err(CodeRefused::ResendLimit {
+                retry_after: (attempt.started_at + OTP_ATTEMPT_WINDOW_SECONDS - now).max(1),
+            }));
+        }
+        Some(attempt) => OtpAttempt {
+            sends: attempt.sends + 1,
+            ..attempt
+        },
+        None => OtpAttempt {
+            sends: 1,
+            started_at: now,
+        },
+    };
+
+    // Update rate limit (before releasing lock), gone once the cooldown is over
+    rate_limit_store
+        .insert_with_ttl_async(
+            email_key.clone(),
+            now,
+            std::time::Duration::from_secs(OTP_COOLDOWN_SECONDS as u64),
+        )
+        .await?;
+    // The attempt keeps the window of its first code
+    let attempt_left = (attempt.started_at + OTP_ATTEMPT_WINDOW_SECONDS - now).max(1);
+    attempt_store
+        .insert_with_ttl_async(
+            email_key,
+            attempt.clone(),
+            std::time::Duration::from_secs(attempt_left as u64),
+        )
+        .await?;
+    Ok(Ok(attempt))
+}
+
+/// Just Sends a verification code (OTP) to the specified email address and stores the hashed OTP in the datastore
+/// Only held to the cooldown, the resend budget is for `resend_verification_code`. The code only
+/// confirms what `purpose` says
+pub async fn send_verification_code(email: &str, purpose: OtpPurpose) -> Result<bool> {
+    let reserved = reserve_code(
+        &get_otp_rate_limit_store(),
+        &get_otp_attempt_store(),
+        email,
+        Utc::now().timestamp(),
+        false,
+    )
+    .await?;
+    if let Err(refused) = reserved {
+        return Err(BlazeError::validation(refused.to_string()));
+    }
+    deliver_verification_code(email, purpose).await
+}
 
-    let otp_hash = hash_otp(&otp).await;
+/// Sends a new code for a verification in progress, the previous code stops working
+/// Shares the cooldown with `send_verification_code`, and an attempt gets `OTP_MAX_RESENDS` new
+/// codes after its first one
+pub async fn resend_verification_code(email: &str) -> Result<ResendCodeResponse> {
+    let refused = |error: String, error_code: &str, retry_after_seconds, resends_remaining| {
+        Ok(ResendCodeResponse {
+            is_code_sent: false,
+            retry_after_seconds,
+            resends_remaining,
+            error,
+            error_code: Some(error_code.to_string()),
+        })
+    };
+
+    let Some(attempt) = get_otp_attempt_store().get(&email.to_string())? else {
+        return refused(
+            "No verification in progress, request a code first".to_string(),
+            NO_PENDING_VERIFICATION,
+            0,
+            0,
+        );
+    };
+
+    let reserved = reserve_code(
+        &get_otp_rate_limit_store(),
+        &get_otp_attempt_store(),
+        email,
+        Utc::now().timestamp(),
+        true,
+    )
+    .await?;
+    match reserved {
+        Ok(attempt) => {
+            let is_code_sent = deliver_verification_code(email, OtpPurpose::Verification).await?;
+            Ok(ResendCodeResponse {
+                is_code_sent,
+                retry_after_seconds: OTP_COOLDOWN_SECONDS,
+                resends_remaining: resends_remaining(&attempt),
+                error: if is_code_sent {
+                    "".to_string()
+                } else {
+                    "Failed to send verification code".to_string()
+                },
+                error_code: None,
+            })
+        }
+        Err(reason) => refused(
+            reason.to_string(),
+            reason.error_code(),
+            reason.retry_after(),
+            resends_remaining(&attempt),
+        ),
+    }
+}
+
+/// Generates a code, stores its hash (replacing any earlier code) and mails it, the cooldown and
+/// attempt were already counted by `reserve_code`
+async fn deliver_verification_code(email: &str, purpose: OtpPurpose) -> Result<bool> {
+    let email_key = email.to_string();
+
+    // 6 digits unless configured otherwise, see `otp_settings`
+    let (otp_length, otp_alphabet) = otp_settings();
+    let otp = generate_otp(otp_length, otp_alphabet);
+
+    let otp_hash = hash_otp(&otp);
     let otp_hash_hex = hex::encode(&otp_hash);
 
     let now = Utc::now();
-    let expires_at = now + Duration::minutes(1); // OTP valid for 1 minute
+    let expires_at = now + Duration::seconds(OTP_TTL_SECONDS);
 
     let otp_record = OtpRecord {
         email: email.to_string(),
         otp_hash: otp_hash_hex,
         created_at: now.to_rfc3339(),
         expires_at: expires_at.to_rfc3339(),
+        failed_attempts: 0,
+        purpose,
     };
 
-    // Store OTP in-memory cache
-    let otp_cache = get_otp_cache();
-    {
-        let mut cache_write = otp_cache.write().await;
-        cache_write.insert(email.to_string(), otp_record.clone());
-    }
+    // Store OTP, it expires with the code and the code it replaces stops working
+    let otp_store = get_otp_store();
+    otp_store
+        .insert_with_ttl_async(
+            email_key.clone(),
+            otp_record.clone(),
+            std::time::Duration::from_secs(OTP_TTL_SECONDS as u64),
+        )
+        .await?;
 
     let html_body = format!(
         r#"
@@ -441,30 +1819,17 @@ pub async fn send_verification_code(email: &str) -> Result<bool> {
 
     let plain_body = format!("Your BlazeDB OTP: {}\n\nExpires in 5 minutes.", otp);
 
-    dotenv::dotenv().ok();
-
-    // Get app_passwords from env
-    let app_password = std::env::var("APP_PASSWORD").expect("APP_PASSWORD must be set 🤬");
-
-    let email_message = Message::builder()
-        .from("noreply.blz.service@gmail.com".parse()?)
-        .to(email.parse()?)
-        .subject("Email Verification Code")
-        .multipart(
-            MultiPart::alternative()
-                .singlepart(SinglePart::plain(plain_body))
-                .singlepart(SinglePart::html(html_body)),
-        )?;
-
-    let creds = Credentials::new("noreply.blz.service@gmail.com".to_string(), app_password);
-
-    let mailer = SmtpTransport::relay("smtp.gmail.com")?
-        .credentials(creds)
-        .build();
+    // A code that arrives after it expired is useless, so don't queue it
+    let sent = send_mail_now(email, "Email Verification Code", plain_body, html_body);
+    // Running out of quota isn't an SMTP outage
+    let quota_hit = sent.as_ref().is_err_and(is_quota_exceeded);
+    if !quota_hit && let Err(e) = report_smtp_result(sent.is_ok()) {
+        error!("Failed to update SMTP incident state: {:?}", e);
+    }
 
-    let response: bool = match mailer.send(&email_message) {
+    let response: bool = match sent {
         Ok(_) => {
-            // Rate limit was already updated atomically at the beginning of the function
+            // Rate limit was already updated atomically by `reserve_code`
             // This means even if email sending fails, the user will still be rate limited for the cooldown period to prevent abuse
             info!("OTP sent to {} (rate limit updated)", email);
             true
@@ -472,9 +1837,7 @@ pub async fn send_verification_code(email: &str) -> Result<bool> {
         Err(e) => {
             error!("Could not send email: {:?}", e);
             // Clean up OTP record from memory cache if email fails
-            let otp_cache = get_otp_cache();
-            let mut cache_write = otp_cache.write().await;
-            cache_write.remove(&email.to_string());
+            otp_store.delete_async(&email_key).await?;
             false
         }
     };
@@ -482,59 +1845,25 @@ pub async fn send_verification_code(email: &str) -> Result<bool> {
     Ok(response)
 }
 
-/// Cleans up expired OTP records from the in-memory cache
+/// Purges expired OTPs and cooldowns from their stores, returns how many OTPs went
 /// This is called periodically via a background task
 pub async fn cleanup_expired_otps() -> Result<usize> {
-    let otp_cache = get_otp_cache();
-    let rate_limit_cache = get_rate_limit_cache();
-    let now = Utc::now();
-    let now_timestamp = now.timestamp();
-    let mut removed_count = 0;
-
-    // Collect expired OTP emails
-    let expired_emails: Vec<String> = {
-        let cache_read = otp_cache.read().await;
-        cache_read
-            .iter()
-            .filter_map(|(email, record)| {
-                if let Ok(expires_at) = DateTime::parse_from_rfc3339(&record.expires_at) {
-                    if now > expires_at.with_timezone(&Utc) {
-                        return Some(email.clone());
-                    }
-                }
-                None
-            })
-            .collect()
-    };
-
-    // Remove expired OTPs older than 1 minute
-    if !expired_emails.is_empty() {
-        let mut cache_write = otp_cache.write().await;
-        for email in &expired_emails {
-            cache_write.remove(email);
-            removed_count += 1;
-        }
-    }
-
-    // Remove rate limits older than cooldown period (30 seconds)
-    {
-        let mut rate_write = rate_limit_cache.write().await;
-        rate_write.retain(|_email, &mut timestamp| {
-            let elapsed = now_timestamp - timestamp;
-            let keep = elapsed < OTP_COOLDOWN_SECONDS;
-            keep
-        });
+    // A migration may be running, expired entries already read as gone
+    if is_read_only() {
+        return Ok(0);
     }
-
+    let removed_count = get_otp_store().purge_expired_async().await?;
+    get_otp_rate_limit_store().purge_expired_async().await?;
+    get_otp_attempt_store().purge_expired_async().await?;
     Ok(removed_count)
 }
 
-pub async fn get_instance_stats(user_email: &String) -> Result<InstanceStatusResponse> {
+pub async fn get_instance_stats(user_email: &str) -> Result<InstanceStatusResponse> {
     let user_store = get_user_store().await;
 
     let instance_id = user_store
-        .get(&user_email)?
-        .ok_or_else(|| anyhow::anyhow!("User not found"))?
+        .get(user_email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?
         .instance_id
         .clone();
 
@@ -544,15 +1873,123 @@ pub async fn get_instance_stats(user_email: &String) -> Result<InstanceStatusRes
         get_container_status(&container_name).await?;
 
     Ok(InstanceStatusResponse {
-        health: is_healthy
-            .then(|| "healthy".to_string())
-            .unwrap_or_else(|| "unhealthy".to_string()),
+        health: if is_healthy {
+            "healthy".to_string()
+        } else {
+            "unhealthy".to_string()
+        },
         running_from,
         last_error_at,
         message: error_state,
     })
 }
 
+/// Whether the user's primary instance takes requests, for clients waiting after verification
+pub async fn get_instance_readiness(email: &str) -> Result<InstanceReadinessResponse> {
+    let user_store = get_user_store().await;
+
+    let user = user_store
+        .get(email)?
+        .ok_or_else(|| BlazeError::auth("User not found"))?;
+
+    if !user.is_verified || user.instance_id.is_empty() {
+        return Err(BlazeError::validation("User has no instance yet"));
+    }
+
+    let provisioning = get_provisioning_store().get(&user.instance_id)?;
+    let state = match get_instance_state(&user.instance_id).await? {
+        "missing" if provisioning.is_some() => "provisioning",
+        state => state,
+    };
+    let is_ready = state == "running";
+
+    let message = if is_ready {
+        "Your instance is ready".to_string()
+    } else if let Some(e) = provisioning.and_then(|p| p.last_error) {
+        format!(
+            "Your instance is being provisioned, the last attempt failed: {}",
+            e
+        )
+    } else if state == "stopped" && get_hibernation(&user.instance_id)?.is_some() {
+        "Your instance is asleep after being idle, the next request wakes it".to_string()
+    } else {
+        match state {
+            "provisioning" | "starting" => "Your instance is starting, try again in a few seconds",
+            "missing" => "Your instance has no container, start it with /v1/blz/instance/start",
+            "stopped" => "Your instance is stopped, start it with /v1/blz/instance/start",
+            _ => "Your instance isn't ready, see /v1/blz/instance/health",
+        }
+        .to_string()
+    };
+
+    Ok(InstanceReadinessResponse {
+        instance_id: Some(user.instance_id),
+        state: Some(state.to_string()),
+        is_ready,
+        message,
+    })
+}
+
+/// Retrieves one user from the datastore
+pub async fn get_user(email: &str) -> Result<Option<User>> {
+    let user_datastore = get_user_store().await;
+    user_datastore.get(email)
+}
+
+/// Updates every user's copy of their plan after the plan catalog changed
+/// Changed container limits are applied to the user's containers right away
+pub async fn refresh_user_plans() -> Result<usize> {
+    let user_store = get_user_store().await;
+
+    let mut refreshed = 0;
+    for user in user_store.values()? {
+        let Some(plan) = Plans::by_name(&user.plans.name) else {
+            warn!(
+                "Plan {} of {} is no longer in the catalog, keeping it",
+                user.plans.name, user.email
+            );
+            continue;
+        };
+        if plan != user.plans {
+            // Catalog edits to the resources apply to running containers right away
+            let previous_spec = ContainerSpec::for_user(&user);
+            let new_spec = ContainerSpec::new(&plan, &user.instance_config);
+            if previous_spec != new_spec {
+                for instance_id in user.instance_ids() {
+                    if let Err(e) =
+                        resize_blazedb_container(instance_id, &previous_spec, &new_spec).await
+                    {
+                        error!("Failed to apply new limits to {}: {}", instance_id, e);
+                    }
+                }
+            }
+            if apply_catalog_plan(&user_store, &user.email, &plan).await? {
+                refreshed += 1;
+            }
+        }
+    }
+
+    if refreshed > 0 {
+        invalidate_proxy_cache(None);
+    }
+    Ok(refreshed)
+}
+
+/// Swaps in the catalog's version of the user's plan, the rest of the record stays as it is now
+/// A plan changed meanwhile (upgrade, trial, etc) is kept, returns whether the refresh landed
+async fn apply_catalog_plan(user_store: &UserStore, email: &str, plan: &Plans) -> Result<bool> {
+    let mut is_applied = false;
+    user_store
+        .update_async(email, |user| {
+            if user.plans.name == plan.name {
+                user.plans = plan.clone();
+                is_applied = true;
+            }
+        })
+        .await?;
+    Ok(is_applied)
+}
+
 /// Retrieves all users from the datastore
 pub async fn get_all_users() -> Result<Vec<User>> {
     let user_datastore = get_user_store().await;
@@ -632,7 +2069,7 @@ pub async fn get_all_pro_users() -> Result<Vec<User>> {
 //     }
 //
 //     // Generate new API key
-//     let (api_key_struct, plain_key) = APIKey::get_new_key(&user.username, &user.email).await;
+//     let (api_key_struct, plain_key) = APIKey::get_new_key(&user.username, &user.email);
 //
 //     // Add to user's API keys
 //     user.api_key.push(api_key_struct.clone());
@@ -670,3 +2107,342 @@ pub async fn get_all_pro_users() -> Result<Vec<User>> {
 //
 //     Ok(found)
 // }
+
+#[test]
+fn test_email_domain_allowlist() {
+    let allowed = vec!["acme.com".to_string(), "acme.io".to_string()];
+
+    assert!(is_email_domain_allowed("alice@acme.com", &allowed));
+    assert!(is_email_domain_allowed(" Bob@ACME.io ", &allowed));
+    assert!(!is_email_domain_allowed("eve@evil.com", &allowed));
+    assert!(!is_email_domain_allowed("eve@mail.acme.com", &allowed));
+    assert!(!is_email_domain_allowed("acme.com", &allowed));
+
+    // No allowlist configured, everyone is welcome
+    assert!(is_email_domain_allowed("eve@evil.com", &[]));
+}
+
+#[test]
+fn test_otp_resend_budget() {
+    let attempt = |sends| OtpAttempt {
+        sends,
+        started_at: 0,
+    };
+    assert_eq!(resends_remaining(&attempt(1)), OTP_MAX_RESENDS);
+    assert_eq!(resends_remaining(&attempt(OTP_MAX_RESENDS)), 1);
+    assert_eq!(resends_remaining(&attempt(OTP_MAX_RESENDS + 1)), 0);
+    assert_eq!(resends_remaining(&attempt(OTP_MAX_RESENDS + 5)), 0);
+
+    let cooling = CodeRefused::CoolingDown { retry_after: 12 };
+    assert_eq!(cooling.error_code(), OTP_COOLDOWN);
+    assert_eq!(cooling.retry_after(), 12);
+    assert!(cooling.to_string().contains("12 seconds"));
+    assert_eq!(
+        CodeRefused::ResendLimit { retry_after: 600 }.error_code(),
+        OTP_RESEND_LIMIT
+    );
+}
+
+#[tokio::test]
+async fn test_reserve_code() -> Result<()> {
+    let dir = std::env::temp_dir().join("test_service_reserve_code");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let rate_limits = DataStore::<String, i64>::new(dir.join("otp_rate_limits.json"))?;
+    let attempts = DataStore::<String, OtpAttempt>::new(dir.join("otp_attempts.json"))?;
+    let email = "alice@example.com";
+    let start = Utc::now().timestamp();
+
+    // The first code starts the attempt, sending again only waits out the cooldown
+    let attempt = reserve_code(&rate_limits, &attempts, email, start, false).await?;
+    assert_eq!(attempt.unwrap().sends, 1);
+    assert_eq!(
+        reserve_code(&rate_limits, &attempts, email, start + 10, true).await?,
+        Err(CodeRefused::CoolingDown { retry_after: 20 })
+    );
+    let attempt = reserve_code(&rate_limits, &attempts, email, start + 30, false).await?;
+    assert_eq!(attempt.unwrap().sends, 1);
+
+    // Resends spend the budget
+    let mut now = start + 30;
+    for sends in 2..=OTP_MAX_RESENDS + 1 {
+        now += OTP_COOLDOWN_SECONDS;
+        let attempt = reserve_code(&rate_limits, &attempts, email, now, true).await?;
+        assert_eq!(attempt.unwrap().sends, sends);
+    }
+    now += OTP_COOLDOWN_SECONDS;
+    assert_eq!(
+        reserve_code(&rate_limits, &attempts, email, now, true).await?,
+        Err(CodeRefused::ResendLimit {
+            retry_after: start + OTP_ATTEMPT_WINDOW_SECONDS - now
+        })
+    );
+    // Plain sends aren't held to the budget
+    now += OTP_COOLDOWN_SECONDS;
+    let attempt = reserve_code(&rate_limits, &attempts, email, now, false).await?;
+    assert_eq!(attempt.unwrap().sends, OTP_MAX_RESENDS + 1);
+
+    // Once the window is over the next code starts a new attempt
+    let later = start + OTP_ATTEMPT_WINDOW_SECONDS;
+    let attempt = reserve_code(&rate_limits, &attempts, email, later, true).await?;
+    assert_eq!(
+        attempt.unwrap(),
+        OtpAttempt {
+            sends: 1,
+            started_at: later
+        }
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_check_otp() -> Result<()> {
+    use axum::http::StatusCode;
+
+    let dir = std::env::temp_dir().join("test_service_check_otp");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let codes = DataStore::<String, OtpRecord>::new(dir.join("otps.json"))?;
+    let attempts = DataStore::<String, OtpAttempt>::new(dir.join("otp_attempts.json"))?;
+    let email = "alice@example.com";
+    let email_key = email.to_string();
+    let status = |result: Result<OtpRecord>| match result {
+        Err(BlazeError::Otp(failure)) => failure.status(),
+        other => panic!("Expected the code to be refused, got {:?}", other),
+    };
+    let pending = |otp: &str, expires_in: i64| OtpRecord {
+        email: email.to_string(),
+        otp_hash: hex::encode(hash_otp(otp)),
+        created_at: Utc::now().to_rfc3339(),
+        expires_at: (Utc::now() + Duration::seconds(expires_in)).to_rfc3339(),
+        failed_attempts: 0,
+        purpose: OtpPurpose::Verification,
+    };
+    let verification = OtpPurpose::Verification;
+    let ttl = std::time::Duration::from_secs(OTP_TTL_SECONDS as u64);
+    let attempt = OtpAttempt {
+        sends: 1,
+        started_at: Utc::now().timestamp(),
+    };
+
+    // Nothing asked for
+    let checked = check_otp(&codes, &attempts, email, "123456", verification).await;
+    assert_eq!(status(checked), StatusCode::NOT_FOUND);
+
+    // Wrong codes, the last allowed one burns the pending code
+    let window = std::time::Duration::from_secs(OTP_ATTEMPT_WINDOW_SECONDS as u64);
+    attempts
+        .insert_with_ttl_async(email_key.clone(), attempt, window)
+        .await?;
+    codes
+        .insert_with_ttl_async(email_key.clone(), pending("123456", 60), ttl)
+        .await?;
+    for _ in 1..OTP_MAX_FAILED_ATTEMPTS {
+        let checked = check_otp(&codes, &attempts, email, "000000", verification).await;
+        assert_eq!(status(checked), StatusCode::UNAUTHORIZED);
+    }
+    let checked = check_otp(&codes, &attempts, email, "000000", verification).await;
+    assert_eq!(status(checked), StatusCode::TOO_MANY_REQUESTS);
+    let checked = check_otp(&codes, &attempts, email, "123456", verification).await;
+    assert_eq!(status(checked), StatusCode::TOO_MANY_REQUESTS);
+
+    // Expired, and still reported so once its TTL dropped it
+    codes
+        .insert_with_ttl_async(email_key.clone(), pending("123456", -1), ttl)
+        .await?;
+    let checked = check_otp(&codes, &attempts, email, "123456", verification).await;
+    assert_eq!(status(checked), StatusCode::GONE);
+    let checked = check_otp(&codes, &attempts, email, "123456", verification).await;
+    assert_eq!(status(checked), StatusCode::GONE);
+
+    // A wrong guess between the check and consuming it doesn't stop the right code
+    codes
+        .insert_with_ttl_async(email_key.clone(), pending("123456", 60), ttl)
+        .await?;
+    let record = check_otp(&codes, &attempts, email, "123456", verification).await?;
+    let checked = check_otp(&codes, &attempts, email, "000000", verification).await;
+    assert_eq!(status(checked), StatusCode::UNAUTHORIZED);
+    assert!(consume_otp(&codes, &attempts, email, &record).await?);
+    assert!(!attempts.contains_key(&email_key)?);
+
+    // Reused, it's gone with its attempt
+    assert!(!consume_otp(&codes, &attempts, email, &record).await?);
+    let checked = check_otp(&codes, &attempts, email, "123456", verification).await;
+    assert_eq!(status(checked), StatusCode::NOT_FOUND);
+
+    assert_eq!(
+        OtpFailure::NoCapacity.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_otp_purpose() -> Result<()> {
+    let dir = std::env::temp_dir().join("test_service_otp_purpose");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let codes = DataStore::<String, OtpRecord>::new(dir.join("otps.json"))?;
+    let attempts = DataStore::<String, OtpAttempt>::new(dir.join("otp_attempts.json"))?;
+    let email = "alice@example.com";
+    let now = Utc::now();
+    codes.insert_mem(
+        email.to_string(),
+        OtpRecord {
+            email: email.to_string(),
+            otp_hash: hex::encode(hash_otp("123456")),
+            created_at: now.to_rfc3339(),
+            expires_at: (now + Duration::seconds(60)).to_rfc3339(),
+            failed_attempts: 0,
+            purpose: OtpPurpose::AccountDeletion,
+        },
+    )?;
+
+    // Neither another action nor the signup verification (which hands out an API key) takes it
+    for purpose in [OtpPurpose::InstanceReset, OtpPurpose::Verification] {
+        let checked = check_otp(&codes, &attempts, email, "123456", purpose).await;
+        assert!(matches!(checked, Err(BlazeError::Otp(OtpFailure::NoCode))));
+    }
+    // And trying doesn't count as a wrong code
+    let record = check_otp(
+        &codes,
+        &attempts,
+        email,
+        "123456",
+        OtpPurpose::AccountDeletion,
+    )
+    .await?;
+    assert_eq!(record.failed_attempts, 0);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_missing_code_failure() {
+    use crate::server::schema::test_user;
+    use axum::http::StatusCode;
+
+    let mut user = test_user("alice@example.com", "free");
+    user.is_verified = false;
+
+    let failure = missing_code_failure(None);
+    assert_eq!(failure, OtpFailure::UnknownEmail);
+    assert_eq!(failure.status(), StatusCode::NOT_FOUND);
+
+    let failure = missing_code_failure(Some(&user));
+    assert_eq!(failure, OtpFailure::NoCode);
+    assert_eq!(failure.status(), StatusCode::NOT_FOUND);
+
+    // Verified with nothing pending is still no code, not an expired one
+    user.is_verified = true;
+    let failure = missing_code_failure(Some(&user));
+    assert_eq!(failure, OtpFailure::NoCode);
+    assert_eq!(failure.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_apply_catalog_plan() -> Result<()> {
+    use crate::server::plans::builtin_plan;
+    use crate::server::schema::test_user;
+
+    let dir = std::env::temp_dir().join("test_service_apply_catalog_plan");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir)?;
+    let user_store = UserStore::new(DataStore::new(dir.join("users.json"))?);
+    let email = "alice@example.com";
+    let user = test_user(email, "starter");
+    user_store.insert_save_async(email, user).await?;
+
+    // The refresh read the user, then a webhook changed another field before it wrote
+    let mut catalog_plan = builtin_plan("starter");
+    catalog_plan.price_per_month += 1;
+    user_store
+        .update_async(email, |user| user.billing_status = BillingStatus::PastDue)
+        .await?;
+    assert!(apply_catalog_plan(&user_store, email, &catalog_plan).await?);
+    let refreshed = user_store.get(email)?.unwrap();
+    assert_eq!(refreshed.plans, catalog_plan);
+    assert_eq!(refreshed.billing_status, BillingStatus::PastDue);
+
+    // An upgrade landing first isn't undone by the old plan's refresh
+    user_store
+        .update_async(email, |user| user.plans = builtin_plan("pro"))
+        .await?;
+    assert!(!apply_catalog_plan(&user_store, email, &catalog_plan).await?);
+    assert_eq!(user_store.get(email)?.unwrap().plans, builtin_plan("pro"));
+
+    // Saved along the way
+    user_store.reload_async().await?;
+    assert_eq!(user_store.get(email)?.unwrap().plans, builtin_plan("pro"));
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[test]
+fn test_trial_expiry() {
+    use crate::server::schema::test_user;
+    let now = Utc::now();
+    let mut user = test_user("alice@example.com", "pro");
+    user.trial_used = true;
+
+    assert!(!is_trial_expired(&user, now)); // Paid, no trial running
+
+    user.trial_expires_at = Some((now + Duration::days(1)).to_rfc3339());
+    assert!(!is_trial_expired(&user, now));
+
+    user.trial_expires_at = Some((now - Duration::minutes(1)).to_rfc3339());
+    assert!(is_trial_expired(&user, now));
+}
+
+#[test]
+fn test_check_can_clone() {
+    use crate::server::plans::builtin_plan;
+    use crate::server::schema::test_user;
+    let mut user = test_user("alice@example.com", "free");
+    user.instance_id = "primary".to_string();
+    assert!(check_can_clone(&user).is_err()); // Free plan
+
+    user.plans = builtin_plan("starter");
+    assert!(check_can_clone(&user).is_ok());
+    assert!(user.owns_instance("primary"));
+
+    // Starter allows 10 instances, the primary one plus 9 clones
+    user.clone_instance_ids = (0..9).map(|i| format!("clone{}", i)).collect();
+    assert!(user.owns_instance("clone8"));
+    assert!(check_can_clone(&user).is_err());
+}
+
+#[test]
+fn test_normalize_instance_config() {
+    let config = |url: Option<&str>, key: Option<&str>, level: Option<&str>| InstanceConfig {
+        embedding_model: Some("  ".to_string()),
+        embedding_api_url: url.map(str::to_string),
+        embedding_api_key: key.map(str::to_string),
+        log_level: level.map(str::to_string),
+    };
+
+    let normalized = normalize_instance_config(config(
+        Some(" https://embed.example.com "),
+        Some("k"),
+        Some("DEBUG"),
+    ))
+    .unwrap();
+    assert_eq!(normalized.embedding_model, None); // Blank means the default
+    assert_eq!(
+        normalized.embedding_api_url.as_deref(),
+        Some("https://embed.example.com")
+    );
+    assert_eq!(normalized.log_level.as_deref(), Some("debug"));
+
+    assert!(
+        normalize_instance_config(config(Some("ftp://embed.example.com"), None, None)).is_err()
+    );
+    assert!(normalize_instance_config(config(None, Some("k"), None)).is_err()); // Keys only go with the user's own URL
+    assert!(normalize_instance_config(config(None, None, Some("verbose"))).is_err());
+}
@@ -0,0 +1,519 @@
+//! # Service data snapshots
+//!
+//! Backs up the service's own data, as opposed to `backups` which covers instances: everything
+//! under `get_data_path()` (users, OTPs, usage, routes, ...) and `get_billing_path()` (billing
+//! history, invoices, coupons). Losing those means losing every customer.
+//!
+//! A snapshot is a gzipped tarball under `get_snapshots_path()` holding both directories and a
+//! `manifest.json` with every file's size and SHA-256. Its record, in `snapshots.json` next to the
+//! tarballs, keeps the SHA-256 of the tarball itself. Users changed in memory are saved first and
+//! SQLite stores are copied with `VACUUM INTO`, so no file is caught halfway through a write.
+//!
+//! A background task takes one every `BLAZE_SNAPSHOT_EVERY_HOURS` (24 by default), only the newest
+//! `BLAZE_SNAPSHOTS_KEPT` (14 by default) are kept. Admins can take one any time.
+//!
+//! Restoring goes back to a snapshot, picked by id or as the newest one taken at or before a point
+//! in time. The tarball is checked against its record and every file against the manifest before
+//! anything is touched. Stores are held in memory while the service runs, so the restore is staged:
+//! the verified files wait in `restore-staged/`, storage turns read-only (nothing gets written
+//! that the restore would throw away) and on the next start, before any store is opened, they
+//! replace the data. The data they replace is snapshotted first, so a restore can be undone the
+//! same way.
+
+use crate::server::schema::ServiceSnapshot;
+use crate::server::service::{
+    get_backups_path, get_billing_path, get_data_path, periodic_save_users,
+};
+use crate::server::storage::{DataStore, StoreFormat, set_read_only, with_suffix};
+use crate::{info, warn};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// Lists a snapshot's files, inside its tarball
+const MANIFEST_NAME: &str = "manifest.json";
+
+static SNAPSHOT_STORE: OnceLock<DataStore<String, ServiceSnapshot>> = OnceLock::new();
+
+/// One snapshot or restore at a time, they share the staging area and temporary copies
+static SNAPSHOT_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    path: String,
+    size_bytes: u64,
+    sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    created_at: String,
+    files: Vec<ManifestEntry>,
+}
+
+/// Where the snapshots, their records and a staged restore are kept
+pub fn get_snapshots_path() -> PathBuf {
+    get_backups_path().join("service")
+}
+
+/// Snapshots keyed by id, ids sort by when they were taken
+pub fn get_snapshot_store() -> DataStore<String, ServiceSnapshot> {
+    SNAPSHOT_STORE
+        .get_or_init(|| {
+            let path = get_snapshots_path().join("snapshots.json");
+            DataStore::<String, ServiceSnapshot>::new(path)
+                .expect("CRASH!! Failed to initialize snapshot datastore")
+        })
+        .clone()
+}
+
+/// The directories a snapshot covers, by their name in the tarball
+fn snapshot_roots() -> [(&'static str, PathBuf); 2] {
+    [("data", get_data_path()), ("billings", get_billing_path())]
+}
+
+fn snapshot_file(id: &str) -> PathBuf {
+    get_snapshots_path().join(format!("{}.tar.gz", id))
+}
+
+fn staged_restore_dir() -> PathBuf {
+    get_snapshots_path().join("restore-staged")
+}
+
+/// Holds the id of the snapshot staged in `staged_restore_dir`
+fn pending_restore_marker() -> PathBuf {
+    get_snapshots_path().join("restore-pending")
+}
+
+fn new_snapshot_id(now: DateTime<Utc>) -> String {
+    format!(
+        "snap_{}_{}",
+        now.format("%Y%m%dT%H%M%SZ"),
+        hex::encode(rand::random::<[u8; 4]>())
+    )
+}
+
+/// How often a snapshot is taken, `BLAZE_SNAPSHOT_EVERY_HOURS` (24 by default)
+fn snapshot_every() -> chrono::Duration {
+    let hours = std::env::var("BLAZE_SNAPSHOT_EVERY_HOURS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&hours: &i64| hours > 0)
+        .unwrap_or(24);
+    chrono::Duration::hours(hours)
+}
+
+/// How many snapshots are kept, `BLAZE_SNAPSHOTS_KEPT` (14 by default)
+fn snapshots_kept() -> usize {
+    std::env::var("BLAZE_SNAPSHOTS_KEPT")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&kept: &usize| kept > 0)
+        .unwrap_or(14)
+}
+
+/// Whether a snapshot is due when the newest one is `latest`
+fn is_snapshot_due(
+    latest: Option<&ServiceSnapshot>,
+    every: chrono::Duration,
+    now: DateTime<Utc>,
+) -> bool {
+    latest
+        .and_then(|s| DateTime::parse_from_rfc3339(&s.created_at).ok())
+        .is_none_or(|t| now - t.with_timezone(&Utc) >= every)
+}
+
+/// The newest of `snapshots` (oldest first) taken at or before `at`
+fn newest_at(snapshots: Vec<ServiceSnapshot>, at: DateTime<Utc>) -> Option<ServiceSnapshot> {
+    snapshots.into_iter().rev().find(|s| {
+        DateTime::parse_from_rfc3339(&s.created_at).is_ok_and(|t| t.with_timezone(&Utc) <= at)
+    })
+}
+
+/// Leftovers of writes in progress and last good copies, not data of their own
+fn is_transient(path: &Path) -> bool {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    [
+        ".tmp",
+        ".bak",
+        ".lock",
+        ".migrating",
+        "-journal",
+        "-wal",
+        "-shm",
+    ]
+    .iter()
+    .any(|suffix| name.ends_with(suffix))
+}
+
+/// Every file under `dir`, in subdirectories too
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if !is_transient(&path) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The file's content, a SQLite store is copied by SQLite so a write in progress isn't caught
+fn read_consistent(path: &Path) -> Result<Vec<u8>> {
+    if StoreFormat::detect(path)? != StoreFormat::Sqlite {
+        return Ok(std::fs::read(path)?);
+    }
+
+    let copy = with_suffix(path, ".snapshot.tmp");
+    let _ = std::fs::remove_file(&copy);
+    let conn =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    conn.execute("VACUUM INTO ?1", [copy.to_string_lossy()])?;
+    let bytes = std::fs::read(&copy);
+    let _ = std::fs::remove_file(&copy);
+    Ok(bytes?)
+}
+
+fn append_file<W: Write>(builder: &mut tar::Builder<W>, path: &str, bytes: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(bytes.len() as u64);
+    header.set_mode(0o600);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    builder.append_data(&mut header, path, bytes)?;
+    Ok(())
+}
+
+/// Writes the directories to a gzipped tarball at `archive`, each under its name, along with
+/// the manifest. Returns how many files went in
+fn write_archive(archive: &Path, roots: &[(&str, PathBuf)]) -> Result<usize> {
+    let file = File::create(archive)?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    let mut manifest = Manifest {
+        created_at: Utc::now().to_rfc3339(),
+        files: Vec::new(),
+    };
+
+    for (name, dir) in roots {
+        let mut files = Vec::new();
+        collect_files(dir, &mut files)?;
+        files.sort();
+
+        for file in files {
+            let bytes = read_consistent(&file)?;
+            let path = Path::new(name)
+                .join(file.strip_prefix(dir)?)
+                .to_string_lossy()
+                .replace('\\', "/");
+            append_file(&mut builder, &path, &bytes)?;
+            manifest.files.push(ManifestEntry {
+                path,
+                size_bytes: bytes.len() as u64,
+                sha256: hex::encode(Sha256::digest(&bytes)),
+            });
+        }
+    }
+
+    append_file(
+        &mut builder,
+        MANIFEST_NAME,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    builder.into_inner()?.finish()?.sync_all()?;
+    Ok(manifest.files.len())
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Unpacks the tarball into `into` once it matches `sha256`, then checks every file against the
+/// manifest. Nothing is left in `into` when either doesn't match. Returns how many files there were
+fn extract_verified(archive: &Path, sha256: &str, into: &Path) -> Result<usize> {
+    if file_sha256(archive)? != sha256 {
+        return Err(anyhow::anyhow!(
+            "{} doesn't match its checksum, the snapshot is damaged",
+            archive.display()
+        ));
+    }
+
+    let _ = std::fs::remove_dir_all(into);
+    let extract = || -> Result<usize> {
+        let mut manifest = None;
+        let mut extracted = BTreeMap::new();
+        let mut tarball = tar::Archive::new(GzDecoder::new(File::open(archive)?));
+
+        for entry in tarball.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(anyhow::anyhow!(
+                    "Snapshot has a file outside of it: {}",
+                    path.display()
+                ));
+            }
+            let mut bytes = Vec::new();
+            entry.read_to_end(&mut bytes)?;
+
+            if path == Path::new(MANIFEST_NAME) {
+                manifest = Some(serde_json::from_slice::<Manifest>(&bytes)?);
+                continue;
+            }
+            let target = into.join(&path);
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&target, &bytes)?;
+            extracted.insert(
+                path.to_string_lossy().replace('\\', "/"),
+                hex::encode(Sha256::digest(&bytes)),
+            );
+        }
+
+        let manifest =
+            manifest.ok_or_else(|| anyhow::anyhow!("Snapshot has no {}", MANIFEST_NAME))?;
+        let expected: BTreeMap<String, String> = manifest
+            .files
+            .into_iter()
+            .map(|file| (file.path, file.sha256))
+            .collect();
+        if extracted != expected {
+            return Err(anyhow::anyhow!("Snapshot files don't match its manifest"));
+        }
+        Ok(extracted.len())
+    };
+
+    extract().inspect_err(|_| {
+        let _ = std::fs::remove_dir_all(into);
+    })
+}
+
+/// Snapshots taken, newest first
+pub fn list_snapshots() -> Result<Vec<ServiceSnapshot>> {
+    let mut snapshots = get_snapshot_store().values()?;
+    snapshots.reverse();
+    Ok(snapshots)
+}
+
+/// The snapshot `id`, or the newest one taken at or before `at`
+pub fn find_snapshot(
+    id: Option<&str>,
+    at: Option<DateTime<Utc>>,
+) -> Result<Option<ServiceSnapshot>> {
+    let store = get_snapshot_store();
+    if let Some(id) = id {
+        return Ok(store.get(&id.to_string())?);
+    }
+    Ok(at.and_then(|at| newest_at(store.values().unwrap_or_default(), at)))
+}
+
+/// Snapshots the data as it is on disk, then deletes the ones beyond the newest `snapshots_kept`
+fn take_snapshot(reason: &str) -> Result<ServiceSnapshot> {
+    let _lock = SNAPSHOT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    std::fs::create_dir_all(get_snapshots_path())?;
+
+    let now = Utc::now();
+    let id = new_snapshot_id(now);
+    let archive = snapshot_file(&id);
+    let files = write_archive(&archive, &snapshot_roots()).inspect_err(|_| {
+        let _ = std::fs::remove_file(&archive);
+    })?;
+
+    let record = ServiceSnapshot {
+        id,
+        reason: reason.to_string(),
+        files,
+        size_bytes: std::fs::metadata(&archive)?.len(),
+        sha256: file_sha256(&archive)?,
+        created_at: now.to_rfc3339(),
+    };
+    let store = get_snapshot_store();
+    store.insert_save(record.id.clone(), record.clone())?;
+
+    let ids = store.keys()?;
+    let excess = ids.len().saturating_sub(snapshots_kept());
+    for old in &ids[..excess] {
+        store.delete(old)?;
+        if let Err(e) = std::fs::remove_file(snapshot_file(old)) {
+            warn!("Failed to delete old snapshot {}: {}", old, e);
+        }
+    }
+
+    info!(
+        "Took snapshot {} of the service data ({}, {} files, {} bytes)",
+        record.id, reason, record.files, record.size_bytes
+    );
+    Ok(record)
+}
+
+/// Snapshots the service data now, users changed in memory are saved first
+pub async fn create_snapshot(reason: &str) -> Result<ServiceSnapshot> {
+    periodic_save_users().await?;
+    let reason = reason.to_string();
+    tokio::task::spawn_blocking(move || take_snapshot(&reason)).await?
+}
+
+/// Takes a snapshot when the newest one is older than `BLAZE_SNAPSHOT_EVERY_HOURS`, returns
+/// whether it did. This is called periodically via a background task
+pub async fn run_scheduled_snapshot() -> Result<bool> {
+    let latest = get_snapshot_store().values()?.pop();
+    if !is_snapshot_due(latest.as_ref(), snapshot_every(), Utc::now()) {
+        return Ok(false);
+    }
+    create_snapshot("scheduled").await?;
+    Ok(true)
+}
+
+/// Verifies the snapshot and stages it to replace the data on the next start, returns how many
+/// files it holds. Storage is read-only from here on, see the module docs
+pub async fn stage_restore(snapshot: ServiceSnapshot) -> Result<usize> {
+    let id = snapshot.id.clone();
+    let files = tokio::task::spawn_blocking(move || -> Result<usize> {
+        let _lock = SNAPSHOT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let files = extract_verified(
+            &snapshot_file(&snapshot.id),
+            &snapshot.sha256,
+            &staged_restore_dir(),
+        )?;
+        std::fs::write(pending_restore_marker(), &snapshot.id)?;
+        Ok(files)
+    })
+    .await??;
+
+    set_read_only(true);
+    info!(
+        "Restore of snapshot {} staged ({} files), restart the service to apply it",
+        id, files
+    );
+    Ok(files)
+}
+
+/// Moves `staged` in place of `live`, which is removed
+fn replace_dir(staged: &Path, live: &Path) -> Result<()> {
+    let old = with_suffix(live, ".before-restore");
+    let _ = std::fs::remove_dir_all(&old);
+    if live.exists() {
+        std::fs::rename(live, &old)?;
+    }
+    if staged.exists() {
+        std::fs::rename(staged, live)?;
+    } else {
+        std::fs::create_dir_all(live)?;
+    }
+    std::fs::remove_dir_all(&old)?;
+    Ok(())
+}
+
+/// Puts a staged restore in place, returns the id of the snapshot restored if there was one
+/// Runs at startup, before any store is opened. The data replaced is snapshotted first
+pub fn apply_pending_restore() -> Result<Option<String>> {
+    let marker = pending_restore_marker();
+    if !marker.exists() {
+        return Ok(None);
+    }
+    let id = std::fs::read_to_string(&marker)?.trim().to_string();
+    let staged = staged_restore_dir();
+
+    take_snapshot("before restore")?;
+    for (name, live) in snapshot_roots() {
+        replace_dir(&staged.join(name), &live)?;
+    }
+    std::fs::remove_file(&marker)?;
+    let _ = std::fs::remove_dir_all(&staged);
+
+    info!("Restored the service data from snapshot {}", id);
+    Ok(Some(id))
+}
+
+#[test]
+fn test_snapshot_archive_round_trip() -> Result<()> {
+    let root = std::env::temp_dir().join("test_service_snapshot");
+    let _ = std::fs::remove_dir_all(&root);
+    let data = root.join("data");
+    std::fs::create_dir_all(data.join("invoices"))?;
+    std::fs::write(data.join("users.json"), b"{}")?;
+    std::fs::write(data.join("users.json.bak"), b"old")?;
+    std::fs::write(data.join("invoices").join("inv_1.pdf"), b"%PDF")?;
+
+    let archive = root.join("snap.tar.gz");
+    assert_eq!(write_archive(&archive, &[("data", data.clone())])?, 2);
+
+    let restored = root.join("restored");
+    let sha256 = file_sha256(&archive)?;
+    assert_eq!(extract_verified(&archive, &sha256, &restored)?, 2);
+    assert_eq!(std::fs::read(restored.join("data/users.json"))?, b"{}");
+    assert_eq!(
+        std::fs::read(restored.join("data/invoices/inv_1.pdf"))?,
+        b"%PDF"
+    );
+    assert!(!restored.join("data/users.json.bak").exists());
+
+    // A tarball that isn't the one recorded restores nothing
+    assert!(extract_verified(&archive, &"0".repeat(64), &restored).is_err());
+
+    // Nor does one whose files aren't the ones in its manifest
+    let mut builder = tar::Builder::new(GzEncoder::new(
+        File::create(&archive)?,
+        Compression::default(),
+    ));
+    append_file(&mut builder, "data/users.json", b"{\"tampered\": true}")?;
+    let manifest = Manifest {
+        created_at: Utc::now().to_rfc3339(),
+        files: vec![ManifestEntry {
+            path: "data/users.json".to_string(),
+            size_bytes: 2,
+            sha256: hex::encode(Sha256::digest(b"{}")),
+        }],
+    };
+    append_file(&mut builder, MANIFEST_NAME, &serde_json::to_vec(&manifest)?)?;
+    builder.into_inner()?.finish()?;
+    assert!(extract_verified(&archive, &file_sha256(&archive)?, &restored).is_err());
+    assert!(!restored.exists());
+
+    let _ = std::fs::remove_dir_all(&root);
+    Ok(())
+}
+
+#[test]
+fn test_snapshot_schedule_and_point_in_time() {
+    let now = Utc::now();
+    let snapshot = |hours_ago: i64| ServiceSnapshot {
+        id: new_snapshot_id(now - chrono::Duration::hours(hours_ago)),
+        reason: "scheduled".to_string(),
+        files: 0,
+        size_bytes: 0,
+        sha256: String::new(),
+        created_at: (now - chrono::Duration::hours(hours_ago)).to_rfc3339(),
+    };
+
+    let day = chrono::Duration::hours(24);
+    assert!(is_snapshot_due(None, day, now));
+    assert!(!is_snapshot_due(Some(&snapshot(2)), day, now));
+    assert!(is_snapshot_due(Some(&snapshot(25)), day, now));
+
+    let snapshots = vec![snapshot(48), snapshot(24), snapshot(1)];
+    let picked = |hours_ago: i64| {
+        newest_at(snapshots.clone(), now - chrono::Duration::hours(hours_ago)).map(|s| s.created_at)
+    };
+    assert_eq!(picked(0), Some(snapshots[2].created_at.clone()));
+    assert_eq!(picked(12), Some(snapshots[1].created_at.clone()));
+    assert_eq!(picked(72), None);
+}
@@ -1,5 +1,5 @@
-// This will do simple Thread safe, concurrent CRUD operations on an in-memory HashMap, with BufReader and BufWriter for reading and writing data.
-// This is not going to be whole ass Storage engine, just simple Buffer Reader and Writer, I swear 🙃, Please don't get too involved (Maybe later use Btree)
+// This will do simple Thread safe, concurrent CRUD operations on an in-memory BTreeMap, with BufReader and BufWriter for reading and writing data.
+// This is not going to be whole ass Storage engine, just simple Buffer Reader and Writer, I swear 🙃, Please don't get too involved (Btree it is now)
 // Lets begin...
 
 //! # Storage Engine
@@ -7,45 +7,1072 @@
 //! A lightweight, thread-safe storage engine with JSON persistence.
 //!
 //! ## Features
-//! - **Thread-safe**: Uses Arc<RwLock<T>> for concurrent access
+//! - **Thread-safe**: Entries are spread over 16 shards by key hash, each behind its own
+//!   RwLock, so threads working on different keys don't wait on each other
+//! - **Shared saves**: Writes that come in while a save is running are saved together by the
+//!   next one, so concurrent writers don't each rewrite the file in turn
 //! - **Fast reads**: Uses memmap2 for memory-mapped file access
 //! - **Efficient writes**: Uses BufWriter for buffered writing
+//! - **Ordered**: Entries are kept sorted by key, for `range`, `scan_prefix` and cursor based
+//!   `page` listing
+//! - **Atomic updates**: `update` and `compare_and_swap` change a value under the write lock, so
+//!   concurrent read-modify-writes don't lose each other's changes
+//! - **Expiring entries**: `insert_with_ttl` entries read as gone once expired and are removed
+//!   by `purge_expired`, their expiry is kept in `<file>.expiry` next to the store
+//! - **Async friendly**: `*_async` variants run the disk I/O on tokio's blocking pool
+//! - **Multi-process**: JSON, NDJSON and encrypted files are loaded under a shared lock and saved
+//!   under an exclusive one (`<file>.lock`), so the service and the proxy never read a file the
+//!   other is replacing. A writer doesn't see the other process's changes until it reloads though,
+//!   use SQLite for stores both write to. Stores opened `with_generation` count their saves in
+//!   `<file>.generation`, readers `reload_if_changed` instead of reloading blindly
+//! - **Crash-safe**: JSON and encrypted files are written to a temporary file, synced and renamed
+//!   over the old one, so a crash mid-write never leaves half a file. Each starts with a checksum
+//!   of its content, checked on load. The file replaced is kept as `<file>.bak` and loaded instead
+//!   when the file itself doesn't pass (SQLite files rely on SQLite's own journal)
 //! - **Generic**: Works with any types that implement Serialize + Deserialize
-//! - **Persistent**: Automatically saves to JSON files
-
-use anyhow::{Context, Result};
+//! - **Portable**: `export_ndjson` and `import` move a store's entries between environments,
+//!   `export_csv` lays them out for spreadsheets and analytics tooling
+//! - **Versioned**: Files carry the schema version of their records (a `BLZVER1` header, SQLite's
+//!   `user_version`), stores opened `with_schema` upgrade older records on load, see `versioning`
+//! - **Persistent**: Automatically saves to compact JSON files, or NDJSON for large stores,
+//!   which load a line at a time
+//! - **Pluggable formats**: A file can also hold NDJSON, SQLite or encrypted JSON (see
+//!   `StoreFormat`), each with its `StorageBackend`. The format is detected on load and kept on save, new files
+//!   get `BLAZE_STORE_FORMAT` (`json` by default). `migration` converts between them. SQLite
+//!   writes single entries in place, so it's the one to use when the service and the proxy
+//!   write to the same store
+
+use crate::server::error::{BlazeError, Result, StorageContext};
+use crate::server::secrets::get_secrets;
+use crate::server::versioning::RecordSchema;
+use crate::{info, warn};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::hash::Hash;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
-
-/// Thread-safe DataStore with in-memory HashMap and persistent JSON storage
-/// Uses Arc<RwLock<T>> for concurrent access and memmap2 for fast reads
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// First bytes of every SQLite database file
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// Header of encrypted store files, followed by a 12 byte nonce and the ciphertext
+const ENCRYPTED_MAGIC: &[u8] = b"BLZENC1\n";
+
+/// Header of NDJSON store files, followed by one `[key, value]` line per entry
+const NDJSON_MAGIC: &[u8] = b"BLZNDJ1\n";
+
+/// Header of checksummed files, followed by the hex SHA-256 of the rest of the file and a newline
+const CHECKSUM_MAGIC: &[u8] = b"BLZSUM1 ";
+const CHECKSUM_HEADER_LEN: usize = CHECKSUM_MAGIC.len() + 64 + 1;
+
+/// Header of files whose records are past schema version 1, followed by the version and a newline
+/// Comes right after the checksum header, see `versioning`
+const VERSION_MAGIC: &[u8] = b"BLZVER1 ";
+const VERSION_HEADER_MAX_LEN: usize = VERSION_MAGIC.len() + 10 + 1;
+
+/// Set while a migration runs, every store in the process refuses writes
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_read_only(read_only: bool) {
+    READ_ONLY.store(read_only, Ordering::SeqCst);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::SeqCst)
+}
+
+fn ensure_writable() -> Result<()> {
+    if is_read_only() {
+        return Err(BlazeError::storage(
+            "Storage is read-only while a migration runs, try again shortly",
+        ));
+    }
+    Ok(())
+}
+
+/// How a store is persisted on disk, the file name doesn't change with the format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreFormat {
+    /// JSON map, written compact
+    Json,
+    /// One `[key, value]` JSON array per line, read a line at a time so large stores load
+    /// without holding the whole file in memory
+    Ndjson,
+    /// SQLite database with one `entries(key, value)` table, both JSON encoded
+    Sqlite,
+    /// JSON encrypted with ChaCha20-Poly1305, keyed by `BLAZE_STORE_KEY`
+    Encrypted,
+}
+
+impl StoreFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "json" => Some(StoreFormat::Json),
+            "ndjson" => Some(StoreFormat::Ndjson),
+            "sqlite" => Some(StoreFormat::Sqlite),
+            "encrypted" => Some(StoreFormat::Encrypted),
+            _ => None,
+        }
+    }
+
+    /// Detects the format of an existing file from its first bytes (past the checksum header)
+    pub fn detect(path: &Path) -> Result<Self> {
+        let header = file_header(path)?;
+        let header = match header.strip_prefix(VERSION_MAGIC) {
+            Some(rest) => rest
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(&[][..], |end| &rest[end + 1..]),
+            None => &header[..],
+        };
+
+        Ok(if header.starts_with(SQLITE_MAGIC) {
+            StoreFormat::Sqlite
+        } else if header.starts_with(ENCRYPTED_MAGIC) {
+            StoreFormat::Encrypted
+        } else if header.starts_with(NDJSON_MAGIC) {
+            StoreFormat::Ndjson
+        } else {
+            StoreFormat::Json
+        })
+    }
+}
+
+/// The first bytes of a file past its checksum header, enough for the version and format headers
+fn file_header(path: &Path) -> Result<Vec<u8>> {
+    let header_len = CHECKSUM_HEADER_LEN + VERSION_HEADER_MAX_LEN + 16;
+    let mut header = Vec::with_capacity(header_len);
+    File::open(path)
+        .storage_context("Failed to open file for reading")?
+        .take(header_len as u64)
+        .read_to_end(&mut header)?;
+    if header.starts_with(CHECKSUM_MAGIC) {
+        header.drain(..CHECKSUM_HEADER_LEN.min(header.len()));
+    }
+    Ok(header)
+}
+
+/// The schema version a store file was written with, from its header alone
+fn stored_version(path: &Path) -> Result<u32> {
+    if StoreFormat::detect(path)? != StoreFormat::Sqlite {
+        return Ok(split_version(&file_header(path)?)?.0);
+    }
+    let conn =
+        rusqlite::Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .storage_context("Failed to open SQLite file")?;
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    Ok(version.max(1))
+}
+
+/// Cipher for encrypted stores, the key is the SHA-256 of `BLAZE_STORE_KEY` (use a long random value)
+fn store_cipher() -> Result<ChaCha20Poly1305> {
+    let secret = get_secrets().get("BLAZE_STORE_KEY").ok_or_else(|| {
+        BlazeError::storage("BLAZE_STORE_KEY must be set to use encrypted stores")
+    })?;
+    if secret.len() < 32 {
+        return Err(BlazeError::storage(
+            "BLAZE_STORE_KEY must be at least 32 characters",
+        ));
+    }
+    let key = Sha256::digest(secret.as_bytes());
+    Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+}
+
+fn create_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).storage_context("Failed to create parent directory")?;
+    }
+    Ok(())
+}
+
+/// `path` with `suffix` appended to the file name
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Where the expiry of a store's entries inserted with a TTL is kept
+pub fn expiry_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".expiry")
+}
+
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Where the file a save replaced is kept, the last good copy
+pub fn backup_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".bak")
+}
+
+/// Where the save counter of a store opened `with_generation` is kept
+pub fn generation_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".generation")
+}
+
+/// The store's save counter, 0 when it has none yet
+pub fn read_generation(path: &Path) -> u64 {
+    std::fs::read_to_string(generation_path(path))
+        .ok()
+        .and_then(|text| text.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Replaces the save counter, renamed over the old one so readers never see half a number
+fn write_generation(path: &Path, generation: u64) -> Result<()> {
+    let generation_path = generation_path(path);
+    let temp_path = with_suffix(&generation_path, &format!(".{}.tmp", std::process::id()));
+    std::fs::write(&temp_path, generation.to_string())
+        .storage_context("Failed to write generation")?;
+    std::fs::rename(&temp_path, &generation_path).storage_context("Failed to replace generation")
+}
+
+fn with_checksum(body: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(CHECKSUM_HEADER_LEN + body.len());
+    bytes.extend_from_slice(CHECKSUM_MAGIC);
+    bytes.extend_from_slice(hex::encode(Sha256::digest(body)).as_bytes());
+    bytes.push(b'\n');
+    bytes.extend_from_slice(body);
+    bytes
+}
+
+/// The file's content past its checksum header, checked against it
+/// Files written before checksums have no header and are taken as they are
+fn verified_body(bytes: &[u8]) -> Result<&[u8]> {
+    let Some(rest) = bytes.strip_prefix(CHECKSUM_MAGIC) else {
+        return Ok(bytes);
+    };
+    if rest.len() < 65 || rest[64] != b'\n' {
+        return Err(BlazeError::storage("Checksum header is cut off"));
+    }
+    let (expected, body) = (&rest[..64], &rest[65..]);
+    if hex::encode(Sha256::digest(body)).as_bytes() != expected {
+        return Err(BlazeError::storage(
+            "File doesn't match its checksum, it was likely cut off mid-write",
+        ));
+    }
+    Ok(body)
+}
+
+/// `body` behind the version header, stores at version 1 go without one
+fn with_version(version: u32, body: Vec<u8>) -> Vec<u8> {
+    if version <= 1 {
+        return body;
+    }
+    let mut bytes = format!("{}{}\n", String::from_utf8_lossy(VERSION_MAGIC), version).into_bytes();
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
+/// The schema version of a (checksum verified) file body and the rest of it
+fn split_version(body: &[u8]) -> Result<(u32, &[u8])> {
+    let Some(rest) = body.strip_prefix(VERSION_MAGIC) else {
+        return Ok((1, body));
+    };
+    let end = rest
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or_else(|| BlazeError::storage("Schema version header is cut off"))?;
+    let version = std::str::from_utf8(&rest[..end])
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| BlazeError::storage("Schema version header is malformed"))?;
+    Ok((version, &rest[end + 1..]))
+}
+
+/// How long a load or save waits for another process to be done with the file
+const FILE_LOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Where the advisory lock of a store file is taken, see `FileLock`
+fn lock_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".lock")
+}
+
+/// Advisory lock on `<file>.lock`, shared while loading and exclusive while saving, so the
+/// service and the proxy never read a file the other one is in the middle of replacing
+/// Taking it is retried until `FILE_LOCK_TIMEOUT`, it's released when dropped
+struct FileLock(File);
+
+impl FileLock {
+    fn shared(path: &Path) -> Result<Self> {
+        Self::acquire(path, false)
+    }
+
+    fn exclusive(path: &Path) -> Result<Self> {
+        Self::acquire(path, true)
+    }
+
+    fn acquire(path: &Path, exclusive: bool) -> Result<Self> {
+        create_parent_dir(path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path(path))
+            .storage_context("Failed to open lock file")?;
+
+        let started = std::time::Instant::now();
+        loop {
+            let locked = if exclusive {
+                file.try_lock()
+            } else {
+                file.try_lock_shared()
+            };
+            match locked {
+                Ok(()) => return Ok(FileLock(file)),
+                Err(TryLockError::WouldBlock) if started.elapsed() < FILE_LOCK_TIMEOUT => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(BlazeError::storage(format!(
+                        "{} is locked by another process, try again shortly",
+                        path.display()
+                    )));
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(BlazeError::storage(format!("Failed to lock file: {}", e)));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// Replaces the file at `path` with `bytes`, a crash leaves either the old file or the new one
+/// The old one is kept at `backup_path`
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<()> {
+    create_parent_dir(path)?;
+    let _lock = FileLock::exclusive(path)?;
+
+    // Unique per write, other threads or the other process may be saving the same store
+    static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+    let temp_path = with_suffix(
+        path,
+        &format!(
+            ".{}.{}.tmp",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ),
+    );
+
+    let write = || -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .storage_context("Failed to open file for writing")?;
+        let mut writer = BufWriter::new(file);
+        writer
+            .write_all(bytes)
+            .storage_context("Failed to write file")?;
+        let file = writer
+            .into_inner()
+            .map_err(|e| BlazeError::storage(format!("Failed to flush writer: {}", e.error())))?;
+        file.sync_all().storage_context("Failed to sync file")?;
+
+        // A hard link keeps the old file as the backup without copying it
+        if path.exists() {
+            let backup = backup_path(path);
+            let _ = std::fs::remove_file(&backup);
+            if std::fs::hard_link(path, &backup).is_err() {
+                std::fs::copy(path, &backup).storage_context("Failed to back up file")?;
+            }
+        }
+        std::fs::rename(&temp_path, path).storage_context("Failed to replace file")
+    };
+    if let Err(e) = write() {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    // The rename only survives a power loss once the directory is synced too
+    if let Some(parent) = path.parent()
+        && let Ok(dir) = File::open(parent)
+    {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// Where a store's entries are persisted, one implementation per `StoreFormat`
+/// `put` and `remove` get the whole map too, for backends that can only write everything at once
+pub trait StorageBackend<K, V>: Send + Sync {
+    fn format(&self) -> StoreFormat;
+
+    /// Reads every entry
+    fn load(&self) -> Result<BTreeMap<K, V>> {
+        Ok(self.load_versioned()?.0)
+    }
+
+    /// Reads every entry and the schema version they were written with, see `versioning`
+    fn load_versioned(&self) -> Result<(BTreeMap<K, V>, u32)>;
+
+    /// Replaces everything stored with `data`
+    fn save_all(&self, data: &BTreeMap<K, V>) -> Result<()>;
+
+    /// Stores one entry, `data` already holding it
+    fn put(&self, _key: &K, _value: &V, data: &BTreeMap<K, V>) -> Result<()> {
+        self.save_all(data)
+    }
+
+    /// Removes one entry, `data` already without it
+    fn remove(&self, _key: &K, data: &BTreeMap<K, V>) -> Result<()> {
+        self.save_all(data)
+    }
+
+    /// Stores the keys as they are in `data`, written or removed
+    fn save_keys(&self, _keys: &[K], data: &BTreeMap<K, V>) -> Result<()> {
+        self.save_all(data)
+    }
+
+    /// Whether `put`, `remove` and `save_keys` need every entry in `data`, or just the keys
+    /// being written
+    fn writes_whole_store(&self) -> bool {
+        true
+    }
+
+    /// Gives back the space the format leaves unused, files rewritten on every save have none
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Backend for a store's format, `BLAZE_STORE_FORMAT` for files that don't exist yet
+/// Writes record it as written with `schema_version`
+pub fn backend_for<K, V>(
+    path: &Path,
+    format: StoreFormat,
+    schema_version: u32,
+) -> Arc<dyn StorageBackend<K, V>>
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    let path = path.to_path_buf();
+    match format {
+        StoreFormat::Json => Arc::new(FileBackend {
+            path,
+            encrypted: false,
+            schema_version,
+        }),
+        StoreFormat::Encrypted => Arc::new(FileBackend {
+            path,
+            encrypted: true,
+            schema_version,
+        }),
+        StoreFormat::Ndjson => Arc::new(NdjsonBackend {
+            path,
+            schema_version,
+        }),
+        StoreFormat::Sqlite => Arc::new(SqliteBackend {
+            path,
+            schema_version,
+        }),
+    }
+}
+
+/// Format new stores are created in, `BLAZE_STORE_FORMAT` (JSON by default)
+/// Existing files keep theirs, see `migration` to convert them
+pub fn default_store_format() -> StoreFormat {
+    dotenv::dotenv().ok();
+    std::env::var("BLAZE_STORE_FORMAT")
+        .ok()
+        .and_then(|v| StoreFormat::parse(&v))
+        .unwrap_or(StoreFormat::Json)
+}
+
+/// The whole map in one file, JSON or encrypted JSON, rewritten on every change
+/// Fine for development and small stores, concurrent writers from two processes overwrite
+/// each other's changes
+pub struct FileBackend {
+    path: PathBuf,
+    encrypted: bool,
+    schema_version: u32,
+}
+
+impl<K, V> StorageBackend<K, V> for FileBackend
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    fn format(&self) -> StoreFormat {
+        if self.encrypted {
+            StoreFormat::Encrypted
+        } else {
+            StoreFormat::Json
+        }
+    }
+
+    fn load_versioned(&self) -> Result<(BTreeMap<K, V>, u32)> {
+        let _lock = FileLock::shared(&self.path)?;
+        if !self.encrypted {
+            let file = File::open(&self.path).storage_context("Failed to open file for reading")?;
+
+            // Use memmap2 for fast memory-mapped file access
+            let mmap = unsafe {
+                memmap2::Mmap::map(&file).storage_context("Failed to create memory map")?
+            };
+
+            // Deserialize from the memory-mapped data
+            let (version, body) = split_version(verified_body(&mmap)?)?;
+            let data =
+                serde_json::from_slice(body).storage_context("Failed to deserialize JSON data")?;
+            return Ok((data, version));
+        }
+
+        let bytes = std::fs::read(&self.path).storage_context("Failed to open file for reading")?;
+        let (version, body) = split_version(verified_body(&bytes)?)?;
+        let body = &body[ENCRYPTED_MAGIC.len().min(body.len())..];
+        if body.len() < 12 {
+            return Err(BlazeError::storage("Encrypted store is truncated"));
+        }
+        let (nonce, ciphertext) = body.split_at(12);
+        let plaintext = store_cipher()?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| BlazeError::storage("Failed to decrypt store, wrong BLAZE_STORE_KEY?"))?;
+        let data = serde_json::from_slice(&plaintext)
+            .storage_context("Failed to deserialize JSON data")?;
+        Ok((data, version))
+    }
+
+    fn save_all(&self, data: &BTreeMap<K, V>) -> Result<()> {
+        if !self.encrypted {
+            let body =
+                serde_json::to_vec(data).storage_context("Failed to serialize data to JSON")?;
+            let body = with_version(self.schema_version, body);
+            return write_atomically(&self.path, &with_checksum(&body));
+        }
+
+        let plaintext =
+            serde_json::to_vec(data).storage_context("Failed to serialize data to JSON")?;
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext = store_cipher()?
+            .encrypt(Nonce::from_slice(&nonce), plaintext.as_slice())
+            .map_err(|_| BlazeError::storage("Failed to encrypt store"))?;
+
+        let mut bytes = Vec::with_capacity(ENCRYPTED_MAGIC.len() + nonce.len() + ciphertext.len());
+        bytes.extend_from_slice(ENCRYPTED_MAGIC);
+        bytes.extend_from_slice(&nonce);
+        bytes.extend_from_slice(&ciphertext);
+        let bytes = with_version(self.schema_version, bytes);
+        write_atomically(&self.path, &with_checksum(&bytes))
+    }
+}
+
+/// One entry per line, read a line at a time: loading never holds more than the entries and
+/// the line being read. The checksum header is checked as the lines go by
+pub struct NdjsonBackend {
+    path: PathBuf,
+    schema_version: u32,
+}
+
+impl<K, V> StorageBackend<K, V> for NdjsonBackend
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    fn format(&self) -> StoreFormat {
+        StoreFormat::Ndjson
+    }
+
+    fn load_versioned(&self) -> Result<(BTreeMap<K, V>, u32)> {
+        let _lock = FileLock::shared(&self.path)?;
+        let file = File::open(&self.path).storage_context("Failed to open file for reading")?;
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        let mut next_line = |line: &mut Vec<u8>| -> Result<bool> {
+            line.clear();
+            Ok(reader
+                .read_until(b'\n', line)
+                .storage_context("Failed to read file")?
+                > 0)
+        };
+        let cut_off = || {
+            BlazeError::storage("File doesn't match its checksum, it was likely cut off mid-write")
+        };
+
+        next_line(&mut line)?;
+        let expected = match line.strip_prefix(CHECKSUM_MAGIC) {
+            Some(rest) if rest.len() == 65 && rest[64] == b'\n' => rest[..64].to_vec(),
+            _ => return Err(BlazeError::storage("Checksum header is cut off")),
+        };
+        let mut hasher = Sha256::new();
+
+        // The version header, when there's one, comes before the format's
+        next_line(&mut line)?;
+        hasher.update(&line);
+        let (version, _) = split_version(&line)?;
+        if line.starts_with(VERSION_MAGIC) {
+            next_line(&mut line)?;
+            hasher.update(&line);
+        }
+        if line != NDJSON_MAGIC {
+            return Err(cut_off());
+        }
+
+        let mut data = BTreeMap::new();
+        while next_line(&mut line)? {
+            hasher.update(&line);
+            let record = line.strip_suffix(b"\n").ok_or_else(cut_off)?;
+            let (key, value) = serde_json::from_slice(record)
+                .storage_context("Failed to deserialize NDJSON record")?;
+            data.insert(key, value);
+        }
+
+        if hex::encode(hasher.finalize()).as_bytes() != expected {
+            return Err(cut_off());
+        }
+        Ok((data, version))
+    }
+
+    fn save_all(&self, data: &BTreeMap<K, V>) -> Result<()> {
+        let mut body = NDJSON_MAGIC.to_vec();
+        for entry in data {
+            serde_json::to_writer(&mut body, &entry)
+                .storage_context("Failed to serialize data to JSON")?;
+            body.push(b'\n');
+        }
+        let body = with_version(self.schema_version, body);
+        write_atomically(&self.path, &with_checksum(&body))
+    }
+}
+
+/// SQLite database with one `entries(key, value)` table, both JSON encoded
+/// Single entries are written on their own, so the service and the proxy can both write to
+/// the same store without losing each other's changes (SQLite locks the file for each write)
+/// The schema version is the database's `user_version`
+pub struct SqliteBackend {
+    path: PathBuf,
+    schema_version: u32,
+}
+
+/// How long a write waits for the other process to be done with the file
+const SQLITE_BUSY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+impl SqliteBackend {
+    fn open(&self) -> Result<rusqlite::Connection> {
+        create_parent_dir(&self.path)?;
+        let conn =
+            rusqlite::Connection::open(&self.path).storage_context("Failed to open SQLite file")?;
+        conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS entries (key TEXT PRIMARY KEY, value TEXT NOT NULL)",
+            [],
+        )?;
+        // Whatever gets written now is at this version, a new file included
+        if self.schema_version > 1 {
+            conn.pragma_update(None, "user_version", self.schema_version)?;
+        }
+        Ok(conn)
+    }
+}
+
+impl<K, V> StorageBackend<K, V> for SqliteBackend
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    fn format(&self) -> StoreFormat {
+        StoreFormat::Sqlite
+    }
+
+    fn load_versioned(&self) -> Result<(BTreeMap<K, V>, u32)> {
+        let conn = rusqlite::Connection::open_with_flags(
+            &self.path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+        )
+        .storage_context("Failed to open SQLite file")?;
+        conn.busy_timeout(SQLITE_BUSY_TIMEOUT)?;
+        let mut select = conn.prepare("SELECT key, value FROM entries")?;
+        let rows = select.query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        let mut data = BTreeMap::new();
+        for row in rows {
+            let (key, value) = row?;
+            data.insert(
+                serde_json::from_str(&key).storage_context("Failed to deserialize SQLite key")?,
+                serde_json::from_str(&value)
+                    .storage_context("Failed to deserialize SQLite value")?,
+            );
+        }
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        Ok((data, version.max(1)))
+    }
+
+    fn save_all(&self, data: &BTreeMap<K, V>) -> Result<()> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM entries", [])?;
+        {
+            let mut insert = tx.prepare("INSERT INTO entries (key, value) VALUES (?1, ?2)")?;
+            for (key, value) in data {
+                insert.execute((serde_json::to_string(key)?, serde_json::to_string(value)?))?;
+            }
+        }
+        tx.commit()
+            .storage_context("Failed to commit SQLite transaction")
+    }
+
+    fn put(&self, key: &K, value: &V, _data: &BTreeMap<K, V>) -> Result<()> {
+        self.open()?.execute(
+            "INSERT INTO entries (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            (serde_json::to_string(key)?, serde_json::to_string(value)?),
+        )?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &K, _data: &BTreeMap<K, V>) -> Result<()> {
+        self.open()?.execute(
+            "DELETE FROM entries WHERE key = ?1",
+            [serde_json::to_string(key)?],
+        )?;
+        Ok(())
+    }
+
+    fn save_keys(&self, keys: &[K], data: &BTreeMap<K, V>) -> Result<()> {
+        let mut conn = self.open()?;
+        let tx = conn.transaction()?;
+        {
+            let mut upsert = tx.prepare(
+                "INSERT INTO entries (key, value) VALUES (?1, ?2)
+                 ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            )?;
+            let mut delete = tx.prepare("DELETE FROM entries WHERE key = ?1")?;
+            for key in keys {
+                match data.get(key) {
+                    Some(value) => upsert
+                        .execute((serde_json::to_string(key)?, serde_json::to_string(value)?))?,
+                    None => delete.execute([serde_json::to_string(key)?])?,
+                };
+            }
+        }
+        tx.commit()
+            .storage_context("Failed to commit SQLite transaction")
+    }
+
+    fn writes_whole_store(&self) -> bool {
+        false
+    }
+
+    /// Folds the write-ahead log back into the database and rewrites it without free pages
+    fn compact(&self) -> Result<()> {
+        let conn = self.open()?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        conn.execute_batch("VACUUM")
+            .storage_context("Failed to vacuum SQLite file")
+    }
+}
+
+/// Writes the entries to `path` in the given format and schema version (replaces the file)
+pub fn write_entries<K, V>(
+    path: &Path,
+    format: StoreFormat,
+    schema_version: u32,
+    data: &BTreeMap<K, V>,
+) -> Result<()>
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    backend_for(path, format, schema_version).save_all(data)
+}
+
+/// Reads every entry of a store file, whatever its format
+/// Falls back to the backup when the file is damaged, returns the entries and the detected format
+pub fn read_entries<K, V>(path: &Path) -> Result<(BTreeMap<K, V>, StoreFormat)>
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    let (data, format, _) = read_versioned_entries(path)?;
+    Ok((data, format))
+}
+
+/// `read_entries` along with the schema version the entries were written with
+pub fn read_versioned_entries<K, V>(path: &Path) -> Result<(BTreeMap<K, V>, StoreFormat, u32)>
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    let e = match read_file_entries(path) {
+        Ok(read) => return Ok(read),
+        Err(e) => e,
+    };
+    let backup = backup_path(path);
+    if !backup.exists() {
+        return Err(e);
+    }
+    match read_file_entries(&backup) {
+        Ok(read) => {
+            warn!(
+                "Couldn't read {} ({}), loaded the last good copy {} instead",
+                path.display(),
+                e,
+                backup.display()
+            );
+            Ok(read)
+        }
+        // The backup's own error wouldn't tell what's wrong with the file
+        Err(_) => Err(e),
+    }
+}
+
+fn read_file_entries<K, V>(path: &Path) -> Result<(BTreeMap<K, V>, StoreFormat, u32)>
+where
+    K: Ord + Serialize + for<'de> Deserialize<'de>,
+    V: Serialize + for<'de> Deserialize<'de>,
+{
+    let format = StoreFormat::detect(path)?;
+    // The version only matters for writing
+    let (data, version) = backend_for::<K, V>(path, format, 1).load_versioned()?;
+    Ok((data, format, version))
+}
+
+/// Order independent digest of a store's content, used to verify migrations
+/// Values go through `serde_json::Value` first so nested maps hash the same in any order
+pub fn entries_checksum<K, V>(data: &BTreeMap<K, V>) -> Result<String>
+where
+    K: Serialize,
+    V: Serialize,
+{
+    let mut rows = data
+        .iter()
+        .map(|(key, value)| {
+            Ok((
+                serde_json::to_value(key)?.to_string(),
+                serde_json::to_value(value)?.to_string(),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    rows.sort();
+
+    let mut hasher = Sha256::new();
+    for (key, value) in rows {
+        hasher.update(key.as_bytes());
+        hasher.update(b"\0");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Health of a store, see `DataStore::stats`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreStats {
+    pub path: String,
+    pub format: StoreFormat,
+    pub schema_version: u32,
+    pub entries: usize,          // Expired ones included until they're purged
+    pub expiring_entries: usize, // Inserted with a TTL
+    pub dirty_entries: usize,    // Changed in memory, not saved yet
+    pub file_size_bytes: u64,    // SQLite's write-ahead log included
+    pub last_saved_at: Option<String>, // By this process, None when it hasn't saved the store yet
+}
+
+/// What `DataStore::compact` did
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompactReport {
+    pub path: String,
+    pub purged_entries: usize,
+    pub size_before_bytes: u64,
+    pub size_after_bytes: u64,
+}
+
+/// Format of `DataStore` exports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One `{"key", "value", "schema_version"}` object per line, what `DataStore::import` reads
+    Ndjson,
+    /// A `key` column and one column per top level field of the values, for spreadsheets and
+    /// analytics tooling. Nested values are written as JSON, it can't be imported back
+    Csv,
+}
+
+impl ExportFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "ndjson" => Some(ExportFormat::Ndjson),
+            "csv" => Some(ExportFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// One line of an NDJSON export
+#[derive(Serialize, Deserialize)]
+struct ExportRecord<K, V> {
+    key: K,
+    value: V,
+    schema_version: u32,
+}
+
+/// What `DataStore::import` did
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub path: String,
+    pub imported: usize,
+    pub skipped: usize, // Already in the store and not overwritten
+}
+
+/// A CSV cell, strings as they are and anything else as JSON
+fn csv_cell(value: Option<&serde_json::Value>) -> String {
+    match value {
+        None | Some(serde_json::Value::Null) => String::new(),
+        Some(serde_json::Value::String(text)) => text.clone(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Size of a store's file on disk, with SQLite's write-ahead log
+fn store_file_size(path: &Path) -> u64 {
+    [path.to_path_buf(), with_suffix(path, "-wal")]
+        .iter()
+        .filter_map(|file| std::fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// One page of entries in key order, see `DataStore::page`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page<K, V> {
+    pub entries: Vec<(K, V)>,
+    /// Key to pass as `after` for the next page, None on the last one
+    pub next: Option<K>,
+}
+
+/// How many shards a store's entries are spread over, each behind its own lock
+const SHARD_COUNT: usize = 16;
+
+/// Which shard a key lives in
+fn shard_index<K: Hash>(key: &K) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SHARD_COUNT
+}
+
+/// The entries of one shard of a store, with what's tracked about them
+struct Shard<K, V> {
+    entries: BTreeMap<K, V>,
+    /// When entries inserted with a TTL expire (unix milliseconds), see `insert_with_ttl`
+    expiry: BTreeMap<K, i64>,
+    /// Keys changed with `insert_mem` and not saved yet, see `flush`
+    dirty: BTreeSet<K>,
+}
+
+impl<K: Ord, V> Shard<K, V> {
+    fn new() -> Self {
+        Shard {
+            entries: BTreeMap::new(),
+            expiry: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+        }
+    }
+
+    fn is_live(&self, key: &K, now: i64) -> bool {
+        self.expiry
+            .get(key)
+            .is_none_or(|&expires_at| expires_at > now)
+    }
+
+    /// The value unless it expired
+    fn get_live(&self, key: &K, now: i64) -> Option<&V> {
+        self.entries.get(key).filter(|_| self.is_live(key, now))
+    }
+}
+
+type ShardLock<K, V> = RwLock<Shard<K, V>>;
+
+fn read_lock<K, V>(shard: &ShardLock<K, V>) -> Result<RwLockReadGuard<'_, Shard<K, V>>> {
+    shard
+        .read()
+        .map_err(|e| BlazeError::storage(format!("Failed to acquire read lock: {}", e)))
+}
+
+fn write_lock<K, V>(shard: &ShardLock<K, V>) -> Result<RwLockWriteGuard<'_, Shard<K, V>>> {
+    shard
+        .write()
+        .map_err(|e| BlazeError::storage(format!("Failed to acquire write lock: {}", e)))
+}
+
+/// Thread-safe DataStore with in-memory BTreeMaps, persisted through a `StorageBackend`
+/// Entries are sharded by key hash, each shard behind its own RwLock, so threads working on
+/// different keys don't wait on each other. Listing merges the shards back into key order
 #[derive(Clone)]
 pub struct DataStore<K, V>
 where
-    K: Eq + Hash + Clone + Serialize + for<'de> Deserialize<'de>,
+    K: Ord + Hash + Clone + Serialize + for<'de> Deserialize<'de>,
     V: Clone + Serialize + for<'de> Deserialize<'de>,
 {
-    /// In-memory storage with thread-safety
-    data: Arc<RwLock<HashMap<K, V>>>,
+    /// In-memory storage, `SHARD_COUNT` shards picked by key hash
+    shards: Arc<[ShardLock<K, V>]>,
     /// File path for persistence
     path: PathBuf,
+    /// Backend for the file's format, follows whatever was last loaded
+    backend: Arc<RwLock<Arc<dyn StorageBackend<K, V>>>>,
+    /// Held while saving, so the last save to finish is the one with the latest data
+    save_lock: Arc<Mutex<()>>,
+    /// Keys waiting on the save in progress, the next save writes them all at once
+    queued: Arc<Mutex<BTreeSet<K>>>,
+    /// When this process last saved the store (unix milliseconds), 0 when it hasn't yet
+    last_saved: Arc<AtomicI64>,
+    /// Upgrades records written with older schema versions on load, see `versioning`
+    schema: Arc<RecordSchema>,
+    /// Whether saves bump the store's generation, see `with_generation`
+    generations: bool,
+    /// Generation of the file as last loaded or saved by this process
+    generation: Arc<AtomicU64>,
 }
 
 impl<K, V> DataStore<K, V>
 where
-    K: Eq + Hash + Clone + Serialize + for<'de> Deserialize<'de>,
+    K: Ord + Hash + Clone + Serialize + for<'de> Deserialize<'de>,
     V: Clone + Serialize + for<'de> Deserialize<'de>,
 {
     /// Create a new DataStore with the given file path
     pub fn new(path: PathBuf) -> Result<Self> {
-        let data = Arc::new(RwLock::new(HashMap::new()));
-        let store = DataStore { data, path };
+        Self::with_schema(path, RecordSchema::new())
+    }
+
+    /// Create a new DataStore whose records follow `schema`, older ones are upgraded on load
+    pub fn with_schema(path: PathBuf, schema: RecordSchema) -> Result<Self> {
+        let shards = (0..SHARD_COUNT)
+            .map(|_| RwLock::new(Shard::new()))
+            .collect();
+        let backend = Arc::new(RwLock::new(backend_for(
+            &path,
+            default_store_format(),
+            schema.version(),
+        )));
+        let store = DataStore {
+            shards,
+            path,
+            backend,
+            save_lock: Arc::new(Mutex::new(())),
+            queued: Arc::new(Mutex::new(BTreeSet::new())),
+            last_saved: Arc::new(AtomicI64::new(0)),
+            schema: Arc::new(schema),
+            generations: false,
+            generation: Arc::new(AtomicU64::new(0)),
+        };
 
         // Load existing data if file exists
         if store.path.exists() {
@@ -55,220 +1082,1054 @@ where
         Ok(store)
     }
 
-    /// Insert or update a key-value pair in memory only
+    /// Every save bumps a counter kept in `<file>.generation`, so processes reading the store can
+    /// tell it changed from that small file and reload only then, see `reload_if_changed`
+    pub fn with_generation(mut self) -> Self {
+        self.generations = true;
+        self
+    }
+
+    /// The shard holding `key`
+    fn shard(&self, key: &K) -> &ShardLock<K, V> {
+        &self.shards[shard_index(key)]
+    }
+
+    /// Every shard locked for reading, in order, a consistent view of the whole store
+    fn read_all(&self) -> Result<Vec<RwLockReadGuard<'_, Shard<K, V>>>> {
+        self.shards.iter().map(read_lock).collect()
+    }
+
+    /// Every shard locked for writing, in order
+    fn write_all(&self) -> Result<Vec<RwLockWriteGuard<'_, Shard<K, V>>>> {
+        self.shards.iter().map(write_lock).collect()
+    }
+
+    /// Live entries with keys in `range`, in key order, at most `limit` of them
+    /// `pick` takes what's needed of each value
+    fn collect_live<R, T, F>(&self, range: R, limit: usize, pick: F) -> Result<Vec<(K, T)>>
+    where
+        R: RangeBounds<K>,
+        F: Fn(&V) -> T,
+    {
+        let shards = self.read_all()?;
+        let now = now_millis();
+
+        let mut live: Vec<(&K, &V)> = Vec::new();
+        for shard in &shards {
+            live.extend(
+                shard
+                    .entries
+                    .range((range.start_bound(), range.end_bound()))
+                    .filter(|(key, _)| shard.is_live(key, now))
+                    .take(limit),
+            );
+        }
+        live.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        live.truncate(limit);
+        Ok(live
+            .into_iter()
+            .map(|(key, value)| (key.clone(), pick(value)))
+            .collect())
+    }
+
+    /// Insert or update a key-value pair in memory only, the next `flush` saves it
     pub fn insert_mem(&self, key: K, value: V) -> Result<Option<V>> {
-        let mut data = self
-            .data
-            .write()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire write lock: {}", e))?;
+        ensure_writable()?;
 
-        let old_value = data.insert(key, value);
+        let mut shard = write_lock(self.shard(&key))?;
+        let old_value = shard.entries.insert(key.clone(), value);
+        let had_expiry = shard.expiry.remove(&key).is_some();
+        shard.dirty.insert(key);
+        drop(shard);
+
+        if had_expiry {
+            self.save_expiry()?;
+        }
         Ok(old_value)
     }
 
+    /// Applies `f` to the value (`default()` when missing) under the write lock, in memory only
+    /// like `insert_mem`, returns what `f` did. Concurrent calls on a key all land
+    pub fn upsert_mem<D, F, R>(&self, key: K, default: D, f: F) -> Result<R>
+    where
+        D: FnOnce() -> V,
+        F: FnOnce(&mut V) -> R,
+    {
+        ensure_writable()?;
+
+        let mut shard = write_lock(self.shard(&key))?;
+        // An expired value starts over, without the expiry
+        let had_expiry = !shard.is_live(&key, now_millis()) && {
+            shard.entries.remove(&key);
+            shard.expiry.remove(&key).is_some()
+        };
+        let result = f(shard.entries.entry(key.clone()).or_insert_with(default));
+        shard.dirty.insert(key);
+        drop(shard);
+
+        if had_expiry {
+            self.save_expiry()?;
+        }
+        Ok(result)
+    }
+
     /// Insert or update a key-value pair
     pub fn insert_save(&self, key: K, value: V) -> Result<Option<V>> {
-        let mut data = self
-            .data
-            .write()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire write lock: {}", e))?;
+        ensure_writable()?;
 
-        let old_value = data.insert(key, value);
-        drop(data); // Release lock before disk I/O
+        let mut shard = write_lock(self.shard(&key))?;
+        let old_value = shard.entries.insert(key.clone(), value);
+        let had_expiry = shard.expiry.remove(&key).is_some();
+        shard.dirty.remove(&key);
+        drop(shard); // Release lock before disk I/O
 
         // Persist to disk
-        self.save_to_disk()?;
+        self.save_key(&key)?;
+        if had_expiry {
+            self.save_expiry()?;
+        }
 
         Ok(old_value)
     }
 
+    /// Insert or update a key-value pair that's gone once `ttl` has passed
+    /// Reads skip it from then on, `purge_expired` removes it for good
+    pub fn insert_with_ttl(&self, key: K, value: V, ttl: std::time::Duration) -> Result<Option<V>> {
+        ensure_writable()?;
+
+        let now = now_millis();
+        let mut shard = write_lock(self.shard(&key))?;
+        let was_expired = !shard.is_live(&key, now);
+        let old_value = shard.entries.insert(key.clone(), value);
+        let expires_at = now.saturating_add(ttl.as_millis() as i64);
+        shard.expiry.insert(key.clone(), expires_at);
+        shard.dirty.remove(&key);
+        drop(shard); // Release lock before disk I/O
+
+        self.save_key(&key)?;
+        self.save_expiry()?;
+
+        Ok(old_value.filter(|_| !was_expired))
+    }
+
+    /// Removes the expired entries, returns how many there were
+    pub fn purge_expired(&self) -> Result<usize> {
+        ensure_writable()?;
+
+        let now = now_millis();
+        let mut expired = Vec::new();
+        let mut purged = 0;
+        for shard in self.shards.iter() {
+            let mut shard = write_lock(shard)?;
+            let keys: Vec<K> = shard
+                .expiry
+                .iter()
+                .filter(|&(_, &expires_at)| expires_at <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in &keys {
+                shard.expiry.remove(key);
+                shard.dirty.remove(key);
+                if shard.entries.remove(key).is_some() {
+                    purged += 1;
+                }
+            }
+            expired.extend(keys);
+        }
+        if expired.is_empty() {
+            return Ok(0);
+        }
+
+        self.save_keys(&expired)?;
+        self.save_expiry()?;
+
+        Ok(purged)
+    }
+
     /// Get a value by key
     pub fn get(&self, key: &K) -> Result<Option<V>> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
+        let shard = read_lock(self.shard(key))?;
 
-        Ok(data.get(key).cloned())
+        Ok(shard.get_live(key, now_millis()).cloned())
     }
 
     /// Delete a key-value pair
     pub fn delete(&self, key: &K) -> Result<Option<V>> {
-        let mut data = self
-            .data
-            .write()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire write lock: {}", e))?;
+        ensure_writable()?;
 
-        let removed = data.remove(key);
-        drop(data); // Release lock before disk I/O
+        let mut shard = write_lock(self.shard(key))?;
+        let expired = !shard.is_live(key, now_millis());
+        let removed = shard.entries.remove(key);
+        let had_expiry = shard.expiry.remove(key).is_some();
+        shard.dirty.remove(key);
+        drop(shard); // Release lock before disk I/O
 
         if removed.is_some() {
-            self.save_to_disk()?;
+            self.save_key(key)?;
+        }
+        if had_expiry {
+            self.save_expiry()?;
+        }
+
+        Ok(removed.filter(|_| !expired))
+    }
+
+    /// Applies `f` to the value under the write lock and saves it, returns the updated value
+    /// None (and `f` isn't called) when the key is missing, a write landing meanwhile isn't lost
+    pub fn update<F>(&self, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce(&mut V),
+    {
+        let updated = self.update_mem(key, f)?;
+        if updated.is_some() {
+            self.save_key(key)?;
+        }
+        Ok(updated)
+    }
+
+    /// Sets the value to `new` only if it's still `expected`, returns whether it did
+    /// None stands for a missing key on either side, so `new: None` deletes
+    /// The swapped in value doesn't expire, like with `insert_save`
+    pub fn compare_and_swap(&self, key: &K, expected: Option<&V>, new: Option<V>) -> Result<bool>
+    where
+        V: PartialEq,
+    {
+        let Some(had_expiry) = self.swap_mem(key, expected, new)? else {
+            return Ok(false);
+        };
+        self.save_swapped(key, had_expiry)?;
+        Ok(true)
+    }
+
+    fn update_mem<F>(&self, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce(&mut V),
+    {
+        ensure_writable()?;
+
+        let mut shard = write_lock(self.shard(key))?;
+
+        if !shard.is_live(key, now_millis()) {
+            return Ok(None);
         }
+        shard.dirty.remove(key);
+        Ok(shard.entries.get_mut(key).map(|value| {
+            f(value);
+            value.clone()
+        }))
+    }
+
+    /// Swaps the value in memory, returns None when it wasn't `expected` or else whether the key
+    /// had an expiry, which is gone now
+    fn swap_mem(&self, key: &K, expected: Option<&V>, new: Option<V>) -> Result<Option<bool>>
+    where
+        V: PartialEq,
+    {
+        ensure_writable()?;
 
-        Ok(removed)
+        let mut shard = write_lock(self.shard(key))?;
+
+        if shard.get_live(key, now_millis()) != expected {
+            return Ok(None);
+        }
+        match new {
+            Some(value) => shard.entries.insert(key.clone(), value),
+            None => shard.entries.remove(key),
+        };
+        shard.dirty.remove(key);
+        Ok(Some(shard.expiry.remove(key).is_some()))
+    }
+
+    fn save_swapped(&self, key: &K, had_expiry: bool) -> Result<()> {
+        self.save_key(key)?;
+        if had_expiry {
+            self.save_expiry()?;
+        }
+        Ok(())
     }
 
     /// Check if a key exists
     pub fn contains_key(&self, key: &K) -> Result<bool> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
+        let shard = read_lock(self.shard(key))?;
 
-        Ok(data.contains_key(key))
+        Ok(shard.get_live(key, now_millis()).is_some())
     }
 
     /// Get all keys
     pub fn keys(&self) -> Result<Vec<K>> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
-
-        Ok(data.keys().cloned().collect())
+        let keys = self.collect_live(.., usize::MAX, |_| ())?;
+        Ok(keys.into_iter().map(|(key, _)| key).collect())
     }
 
     /// Get all values
     pub fn values(&self) -> Result<Vec<V>> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
-
-        Ok(data.values().cloned().collect())
+        let entries = self.collect_live(.., usize::MAX, V::clone)?;
+        Ok(entries.into_iter().map(|(_, value)| value).collect())
     }
 
     /// Get all key-value pairs
     pub fn entries(&self) -> Result<Vec<(K, V)>> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
+        self.collect_live(.., usize::MAX, V::clone)
+    }
 
-        Ok(data.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    /// Entries with keys in `range`, in key order
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<Vec<(K, V)>> {
+        self.collect_live(range, usize::MAX, V::clone)
     }
 
-    /// Get the number of entries
-    pub fn len(&self) -> Result<usize> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
+    /// Entries whose key starts with `prefix`, in key order
+    pub fn scan_prefix(&self, prefix: &str) -> Result<Vec<(K, V)>>
+    where
+        K: std::borrow::Borrow<str>,
+    {
+        let shards = self.read_all()?;
+        let now = now_millis();
+
+        let mut entries: Vec<(K, V)> = Vec::new();
+        for shard in &shards {
+            entries.extend(
+                shard
+                    .entries
+                    .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+                    .take_while(|(key, _)| (*key).borrow().starts_with(prefix))
+                    .filter(|(key, _)| shard.is_live(key, now))
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        Ok(entries)
+    }
 
-        Ok(data.len())
+    /// Up to `limit` entries in key order, starting right after the key `after`
+    /// Keys added or removed between pages don't shift the ones not seen yet
+    pub fn page(&self, after: Option<&K>, limit: usize) -> Result<Page<K, V>> {
+        let start = match after {
+            Some(key) => Bound::Excluded(key),
+            None => Bound::Unbounded,
+        };
+        // One more than asked for tells whether there's a next page
+        let mut entries =
+            self.collect_live((start, Bound::Unbounded), limit.saturating_add(1), V::clone)?;
+
+        let next = if entries.len() > limit {
+            entries.truncate(limit);
+            entries.last().map(|(key, _)| key.clone())
+        } else {
+            None
+        };
+        Ok(Page { entries, next })
+    }
+
+    /// Get the number of entries, expired ones count until they're purged
+    pub fn len(&self) -> Result<usize> {
+        Ok(self
+            .read_all()?
+            .iter()
+            .map(|shard| shard.entries.len())
+            .sum())
     }
 
     /// Check if the store is empty
     pub fn is_empty(&self) -> Result<bool> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
-
-        Ok(data.is_empty())
+        Ok(self
+            .read_all()?
+            .iter()
+            .all(|shard| shard.entries.is_empty()))
     }
 
     /// Clear all data
     pub fn clear(&self) -> Result<()> {
-        let mut data = self
-            .data
+        ensure_writable()?;
+
+        for mut shard in self.write_all()? {
+            // Expiries and pending saves of the cleared keys would hit keys written again later
+            *shard = Shard::new();
+        }
+
+        self.save_to_disk()?;
+        self.save_expiry()?;
+
+        Ok(())
+    }
+
+    /// Save data to disk using BufWriter for efficient writing (Explicitly)
+    pub fn save_to_disk(&self) -> Result<()> {
+        ensure_writable()?;
+
+        let _saving = self.save_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let queued = std::mem::take(&mut *self.queued_keys());
+        let (data, dirty) = {
+            let mut shards = self.write_all()?;
+            let data: BTreeMap<K, V> = shards
+                .iter()
+                .flat_map(|shard| shard.entries.iter())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect();
+            let dirty: Vec<K> = shards
+                .iter_mut()
+                .flat_map(|shard| std::mem::take(&mut shard.dirty))
+                .collect();
+            (data, dirty)
+        };
+        let saved = self.backend()?.save_all(&data);
+        self.record_save(&saved);
+
+        if saved.is_err() {
+            self.mark_dirty(dirty)?;
+            self.queued_keys().extend(queued);
+        }
+        saved
+    }
+
+    /// Saves the keys changed with `insert_mem` since the last save, returns how many
+    /// Writes nothing when nothing changed
+    pub fn flush(&self) -> Result<usize> {
+        ensure_writable()?;
+
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            keys.extend(std::mem::take(&mut write_lock(shard)?.dirty));
+        }
+        // Keys whose save failed earlier go along
+        if keys.is_empty() && self.queued_keys().is_empty() {
+            return Ok(0);
+        }
+        match self.save_keys(&keys) {
+            Ok(()) => Ok(keys.len()),
+            Err(e) => {
+                self.mark_dirty(keys)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Whether `insert_mem` changed something that isn't saved yet
+    pub fn is_dirty(&self) -> bool {
+        self.shards
+            .iter()
+            .any(|shard| read_lock(shard).is_ok_and(|shard| !shard.dirty.is_empty()))
+    }
+
+    /// Keys whose save didn't go through, the next `flush` tries them again
+    fn mark_dirty(&self, keys: Vec<K>) -> Result<()> {
+        for key in keys {
+            write_lock(self.shard(&key))?.dirty.insert(key);
+        }
+        Ok(())
+    }
+
+    fn queued_keys(&self) -> std::sync::MutexGuard<'_, BTreeSet<K>> {
+        self.queued.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Persists the keys as they are in memory now, written or removed
+    /// Keys other threads queue while a save is in progress are all written by the next one,
+    /// so concurrent writers share saves instead of each rewriting the file in turn. Keys whose
+    /// save failed stay queued, so whoever was waiting on them tries again and gets the error
+    fn save_keys(&self, keys: &[K]) -> Result<()> {
+        ensure_writable()?;
+
+        self.queued_keys().extend(keys.iter().cloned());
+        let _saving = self.save_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let batch: Vec<K> = std::mem::take(&mut *self.queued_keys())
+            .into_iter()
+            .collect();
+        if batch.is_empty() {
+            // Saved by whoever held the lock before
+            return Ok(());
+        }
+
+        let saved = self.write_keys(&batch);
+        self.record_save(&saved);
+        if saved.is_err() {
+            self.queued_keys().extend(batch);
+        }
+        saved
+    }
+
+    /// Called with `save_lock` held, generations are bumped in save order
+    fn record_save(&self, saved: &Result<()>) {
+        if saved.is_err() {
+            return;
+        }
+        self.last_saved.store(now_millis(), Ordering::Relaxed);
+
+        if self.generations {
+            let next = read_generation(&self.path).max(self.generation.load(Ordering::Acquire)) + 1;
+            match write_generation(&self.path, next) {
+                Ok(()) => self.generation.store(next, Ordering::Release),
+                // Readers miss this save until the next one
+                Err(e) => warn!(
+                    "Saved {} but couldn't bump its generation: {}",
+                    self.path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    /// Writes the keys through the backend, called with `save_lock` held
+    /// Backends that can write one entry only write that one, see `StorageBackend::put`
+    fn write_keys(&self, keys: &[K]) -> Result<()> {
+        let backend = self.backend()?;
+        let data = if backend.writes_whole_store() {
+            self.snapshot()?
+        } else {
+            let mut data = BTreeMap::new();
+            for key in keys {
+                if let Some(value) = read_lock(self.shard(key))?.entries.get(key) {
+                    data.insert(key.clone(), value.clone());
+                }
+            }
+            data
+        };
+
+        match keys {
+            [key] => match data.get(key) {
+                Some(value) => backend.put(key, value, &data),
+                None => backend.remove(key, &data),
+            },
+            keys => backend.save_keys(keys, &data),
+        }
+    }
+
+    fn save_expiry(&self) -> Result<()> {
+        let _saving = self.save_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let expiry: BTreeMap<K, i64> = self
+            .read_all()?
+            .iter()
+            .flat_map(|shard| shard.expiry.iter())
+            .map(|(key, &expires_at)| (key.clone(), expires_at))
+            .collect();
+        backend_for::<K, i64>(&expiry_path(&self.path), StoreFormat::Json, 1).save_all(&expiry)
+    }
+
+    /// Persists the key as it is in memory now, written or removed, see `save_keys`
+    fn save_key(&self, key: &K) -> Result<()> {
+        self.save_keys(std::slice::from_ref(key))
+    }
+
+    /// Load data from disk using memmap2 for fast reading (Explicitly)
+    pub fn load_from_disk(&self) -> Result<()> {
+        // Read before the data, a save landing in between gets loaded again next time
+        let generation = read_generation(&self.path);
+        let (loaded_data, format) = self.read_upgraded()?;
+
+        let expiry_path = expiry_path(&self.path);
+        let expiry = if expiry_path.exists() {
+            backend_for::<K, i64>(&expiry_path, StoreFormat::Json, 1).load()?
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut shards = self.write_all()?;
+        for shard in shards.iter_mut() {
+            // Memory is what's on disk again, unsaved changes went with the reload
+            **shard = Shard::new();
+        }
+        for (key, value) in loaded_data {
+            shards[shard_index(&key)].entries.insert(key, value);
+        }
+        for (key, expires_at) in expiry {
+            shards[shard_index(&key)].expiry.insert(key, expires_at);
+        }
+        drop(shards);
+        self.queued_keys().clear();
+        self.generation.store(generation, Ordering::Release);
+
+        *self
+            .backend
             .write()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire write lock: {}", e))?;
+            .map_err(|e| BlazeError::storage(format!("Failed to acquire write lock: {}", e)))? =
+            backend_for(&self.path, format, self.schema.version());
+
+        Ok(())
+    }
+
+    /// Reads the file, records written with an older schema version come back upgraded and are
+    /// saved that way
+    fn read_upgraded(&self) -> Result<(BTreeMap<K, V>, StoreFormat)> {
+        let version = self.schema.version();
+        let newer = |stored: u32| {
+            BlazeError::storage(format!(
+                "{} was written with schema version {}, this build only knows up to {}",
+                self.path.display(),
+                stored,
+                version
+            ))
+        };
+
+        // Nothing to upgrade, read straight into the record type rather than through JSON values
+        if version == 1 || stored_version(&self.path).ok() == Some(version) {
+            let (data, format, stored) = read_versioned_entries(&self.path)?;
+            if stored > version {
+                return Err(newer(stored));
+            }
+            if stored == version {
+                return Ok((data, format));
+            }
+            // The file was damaged and the backup read instead is older, upgrade that
+        }
+
+        let (records, format, stored) = read_versioned_entries::<K, serde_json::Value>(&self.path)?;
+        if stored > version {
+            return Err(newer(stored));
+        }
+
+        let mut data = BTreeMap::new();
+        for (key, mut record) in records {
+            self.schema.upgrade_record(stored, &mut record);
+            let value = serde_json::from_value(record).map_err(|e| {
+                BlazeError::storage(format!(
+                    "Record {} of {} doesn't match schema version {}: {}",
+                    serde_json::to_string(&key).unwrap_or_default(),
+                    self.path.display(),
+                    version,
+                    e
+                ))
+            })?;
+            data.insert(key, value);
+        }
+
+        if stored < version {
+            let _saving = self.save_lock.lock().unwrap_or_else(|e| e.into_inner());
+            backend_for(&self.path, format, version).save_all(&data)?;
+            info!(
+                "Upgraded {} record(s) of {} from schema version {} to {}",
+                data.len(),
+                self.path.display(),
+                stored,
+                version
+            );
+        }
+        Ok((data, format))
+    }
+
+    /// Entry counts, file size and when the store was last saved
+    pub fn stats(&self) -> Result<StoreStats> {
+        let (entries, expiring_entries, dirty_entries) =
+            self.read_all()?.iter().fold((0, 0, 0), |(e, x, d), shard| {
+                (
+                    e + shard.entries.len(),
+                    x + shard.expiry.len(),
+                    d + shard.dirty.len(),
+                )
+            });
+        let last_saved = self.last_saved.load(Ordering::Relaxed);
+
+        Ok(StoreStats {
+            path: self.path.display().to_string(),
+            format: self.format()?,
+            schema_version: self.schema_version(),
+            entries,
+            expiring_entries,
+            dirty_entries,
+            file_size_bytes: store_file_size(&self.path),
+            last_saved_at: chrono::DateTime::from_timestamp_millis(last_saved)
+                .filter(|_| last_saved > 0)
+                .map(|at| at.to_rfc3339()),
+        })
+    }
+
+    /// Purges expired entries and rewrites the file from memory, SQLite files (which other
+    /// processes may write to) are only flushed, then vacuumed and their write-ahead log
+    /// truncated, see `StorageBackend::compact`
+    pub fn compact(&self) -> Result<CompactReport> {
+        ensure_writable()?;
+
+        let size_before_bytes = store_file_size(&self.path);
+        let purged_entries = self.purge_expired()?;
+        let backend = self.backend()?;
+        if backend.writes_whole_store() {
+            self.save_to_disk()?;
+        } else {
+            self.flush()?;
+        }
+        backend.compact()?;
+
+        let report = CompactReport {
+            path: self.path.display().to_string(),
+            purged_entries,
+            size_before_bytes,
+            size_after_bytes: store_file_size(&self.path),
+        };
+        info!(
+            "Compacted {}: {} expired entries purged, {} -> {} bytes",
+            report.path, report.purged_entries, report.size_before_bytes, report.size_after_bytes
+        );
+        Ok(report)
+    }
+
+    /// Schema version the store's records are written with
+    pub fn schema_version(&self) -> u32 {
+        self.schema.version()
+    }
+
+    /// Format the store is persisted in
+    pub fn format(&self) -> Result<StoreFormat> {
+        Ok(self.backend()?.format())
+    }
+
+    fn backend(&self) -> Result<Arc<dyn StorageBackend<K, V>>> {
+        let backend = self
+            .backend
+            .read()
+            .map_err(|e| BlazeError::storage(format!("Failed to acquire read lock: {}", e)))?;
+
+        Ok(backend.clone())
+    }
+
+    /// File path the store is persisted to
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-        data.clear();
-        drop(data);
+    /// Reload data from disk (useful for synchronization)
+    pub fn reload(&self) -> Result<()> {
+        if self.path.exists() {
+            self.load_from_disk()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Reloads the store when its generation moved since it was last loaded, see
+    /// `with_generation`. Returns whether it reloaded
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        if read_generation(&self.path) == self.generation.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        self.reload()?;
+        Ok(true)
+    }
+
+    /// Get a snapshot of all data (useful for batch operations)
+    pub fn snapshot(&self) -> Result<BTreeMap<K, V>> {
+        let shards = self.read_all()?;
+
+        Ok(shards
+            .iter()
+            .flat_map(|shard| shard.entries.iter())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    /// Batch insert multiple key-value pairs
+    pub fn batch_insert(&self, entries: Vec<(K, V)>) -> Result<()> {
+        ensure_writable()?;
+
+        // All shards at once, readers see the whole batch or none of it
+        let mut shards = self.write_all()?;
+        for (key, value) in entries {
+            shards[shard_index(&key)].entries.insert(key, value);
+        }
+        drop(shards);
+
+        self.save_to_disk()?;
+
+        Ok(())
+    }
+
+    /// Moves entries to new keys, saved together, returns how many moved
+    /// Renames whose old key is missing are skipped, an entry already under the new key is replaced
+    pub fn rename_keys(&self, renames: Vec<(K, K)>) -> Result<usize> {
+        ensure_writable()?;
+
+        let mut shards = self.write_all()?;
+        let (mut moved, mut expiry_moved) = (0, false);
+        for (from, to) in renames {
+            let shard = &mut shards[shard_index(&from)];
+            let Some(value) = shard.entries.remove(&from) else {
+                continue;
+            };
+            shard.dirty.remove(&from);
+            let expires_at = shard.expiry.remove(&from);
+
+            let shard = &mut shards[shard_index(&to)];
+            shard.dirty.remove(&to);
+            expiry_moved |= shard.expiry.remove(&to).is_some() || expires_at.is_some();
+            if let Some(expires_at) = expires_at {
+                shard.expiry.insert(to.clone(), expires_at);
+            }
+            shard.entries.insert(to, value);
+            moved += 1;
+        }
+        drop(shards);
 
-        self.save_to_disk()?;
+        if moved > 0 {
+            self.save_to_disk()?;
+        }
+        if expiry_moved {
+            self.save_expiry()?;
+        }
+        Ok(moved)
+    }
 
-        Ok(())
+    /// Writes the live entries to `out` as NDJSON, see `ExportFormat::Ndjson`
+    /// Returns the number of entries written
+    pub fn export_ndjson<W: Write>(&self, out: W) -> Result<usize> {
+        let entries = self.entries()?;
+        let schema_version = self.schema_version();
+
+        let mut writer = BufWriter::new(out);
+        for (key, value) in &entries {
+            let record = ExportRecord {
+                key,
+                value,
+                schema_version,
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(entries.len())
     }
 
-    /// Save data to disk using BufWriter for efficient writing (Explicitly)
-    pub fn save_to_disk(&self) -> Result<()> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
+    /// Writes the live entries to `out` as CSV, see `ExportFormat::Csv`
+    /// Returns the number of entries written
+    pub fn export_csv<W: Write>(&self, out: W) -> Result<usize> {
+        let rows = self
+            .entries()?
+            .into_iter()
+            .map(|(key, value)| Ok((serde_json::to_value(key)?, serde_json::to_value(value)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Values that aren't objects get a single `value` column
+        let objects = rows.iter().all(|(_, value)| value.is_object());
+        let columns: BTreeSet<&str> = if objects {
+            rows.iter()
+                .filter_map(|(_, value)| value.as_object())
+                .flat_map(|fields| fields.keys().map(String::as_str))
+                .collect()
+        } else {
+            BTreeSet::from(["value"])
+        };
+
+        let mut writer = csv::Writer::from_writer(out);
+        let csv_error = |e: csv::Error| BlazeError::storage(format!("Failed to write CSV: {}", e));
+        writer
+            .write_record(std::iter::once("key").chain(columns.iter().copied()))
+            .map_err(csv_error)?;
+        for (key, value) in &rows {
+            let cells = columns.iter().map(|column| match objects {
+                true => csv_cell(value.get(column)),
+                false => csv_cell(Some(value)),
+            });
+            writer
+                .write_record(std::iter::once(csv_cell(Some(key))).chain(cells))
+                .map_err(csv_error)?;
+        }
+        writer.flush()?;
+        Ok(rows.len())
+    }
 
-        // Create parent directory if it doesn't exist
-        if let Some(parent) = self.path.parent() {
-            std::fs::create_dir_all(parent).context("Failed to create parent directory")?;
+    /// Reads an NDJSON export (`export_ndjson`) into the store and saves it
+    /// Records exported with an older schema version are upgraded. Keys already in the store are
+    /// replaced when `overwrite` is set and left alone otherwise. Nothing is imported when any
+    /// line doesn't read back
+    pub fn import<R: BufRead>(&self, input: R, overwrite: bool) -> Result<ImportReport> {
+        ensure_writable()?;
+
+        let version = self.schema_version();
+        let mut records = Vec::new();
+        for (number, line) in input.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |e: &dyn std::fmt::Display| {
+                BlazeError::validation(format!("Line {} of the import: {}", number + 1, e))
+            };
+
+            let ExportRecord {
+                key,
+                mut value,
+                schema_version,
+            } = serde_json::from_str::<ExportRecord<K, serde_json::Value>>(&line)
+                .map_err(|e| invalid(&e))?;
+            if schema_version > version {
+                return Err(invalid(&format!(
+                    "exported with schema version {}, this build only knows up to {}",
+                    schema_version, version
+                )));
+            }
+            self.schema.upgrade_record(schema_version, &mut value);
+            let value: V = serde_json::from_value(value).map_err(|e| invalid(&e))?;
+            records.push((key, value));
         }
 
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.path)
-            .context("Failed to open file for writing")?;
+        // All shards at once, like `batch_insert`
+        let total = records.len();
+        let mut shards = self.write_all()?;
+        let now = now_millis();
+        let (mut imported, mut expiry_removed) = (0, false);
+        for (key, value) in records {
+            let shard = &mut shards[shard_index(&key)];
+            if !overwrite && shard.get_live(&key, now).is_some() {
+                continue;
+            }
+            expiry_removed |= shard.expiry.remove(&key).is_some();
+            shard.entries.insert(key, value);
+            imported += 1;
+        }
+        drop(shards);
 
-        let mut writer = BufWriter::new(file);
+        self.save_to_disk()?;
+        if expiry_removed {
+            self.save_expiry()?;
+        }
 
-        serde_json::to_writer_pretty(&mut writer, &*data)
-            .context("Failed to serialize data to JSON")?;
+        let report = ImportReport {
+            path: self.path.display().to_string(),
+            imported,
+            skipped: total - imported,
+        };
+        info!(
+            "Imported {} entries into {}, {} already there kept",
+            report.imported, report.path, report.skipped
+        );
+        Ok(report)
+    }
+}
 
-        writer.flush().context("Failed to flush writer")?;
+/// Stats, compaction, export and import of a store whatever its key and value types, so stores
+/// can be listed together for diagnostics and picked by name by the admin endpoints
+pub trait StoreMaintenance: Send + Sync {
+    fn stats(&self) -> Result<StoreStats>;
+    fn compact(&self) -> Result<CompactReport>;
+    fn export(&self, format: ExportFormat, out: &mut dyn Write) -> Result<usize>;
+    fn import(&self, input: &mut dyn BufRead, overwrite: bool) -> Result<ImportReport>;
+}
 
-        Ok(())
+impl<K, V> StoreMaintenance for DataStore<K, V>
+where
+    K: Ord + Hash + Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+    V: Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+{
+    fn stats(&self) -> Result<StoreStats> {
+        DataStore::stats(self)
     }
 
-    /// Load data from disk using memmap2 for fast reading (Explicitly)
-    pub fn load_from_disk(&self) -> Result<()> {
-        let file = File::open(&self.path).context("Failed to open file for reading")?;
+    fn compact(&self) -> Result<CompactReport> {
+        DataStore::compact(self)
+    }
 
-        // Use memmap2 for fast memory-mapped file access
-        let mmap = unsafe { memmap2::Mmap::map(&file).context("Failed to create memory map")? };
+    fn export(&self, format: ExportFormat, out: &mut dyn Write) -> Result<usize> {
+        match format {
+            ExportFormat::Ndjson => self.export_ndjson(out),
+            ExportFormat::Csv => self.export_csv(out),
+        }
+    }
 
-        // Deserialize from the memory-mapped data
-        let loaded_data: HashMap<K, V> =
-            serde_json::from_slice(&mmap).context("Failed to deserialize JSON data")?;
+    fn import(&self, input: &mut dyn BufRead, overwrite: bool) -> Result<ImportReport> {
+        DataStore::import(self, input, overwrite)
+    }
+}
 
-        let mut data = self
-            .data
-            .write()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire write lock: {}", e))?;
+/// Async variants of the methods that touch the disk, for use from async code
+/// The in-memory part is quick, the file I/O runs on tokio's blocking pool so it doesn't stall
+/// the runtime's worker threads
+impl<K, V> DataStore<K, V>
+where
+    K: Ord + Hash + Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+    V: Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+{
+    async fn blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(DataStore<K, V>) -> Result<T> + Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || f(store))
+            .await
+            .map_err(|e| BlazeError::storage(format!("Storage task failed: {}", e)))?
+    }
 
-        *data = loaded_data;
+    /// Insert or update a key-value pair, see `insert_save`
+    pub async fn insert_save_async(&self, key: K, value: V) -> Result<Option<V>> {
+        self.blocking(move |store| store.insert_save(key, value))
+            .await
+    }
 
-        Ok(())
+    /// Delete a key-value pair, see `delete`
+    pub async fn delete_async(&self, key: &K) -> Result<Option<V>> {
+        let key = key.clone();
+        self.blocking(move |store| store.delete(&key)).await
     }
 
-    /// Reload data from disk (useful for synchronization)
-    pub fn reload(&self) -> Result<()> {
-        if self.path.exists() {
-            self.load_from_disk()
-        } else {
-            Ok(())
+    /// Apply `f` to the value and save it, see `update`
+    /// `f` runs right away under the write lock, only the save goes to the blocking pool
+    pub async fn update_async<F>(&self, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce(&mut V),
+    {
+        let updated = self.update_mem(key, f)?;
+        if updated.is_some() {
+            let key = key.clone();
+            self.blocking(move |store| store.save_key(&key)).await?;
         }
+        Ok(updated)
     }
 
-    /// Get a snapshot of all data (useful for batch operations)
-    pub fn snapshot(&self) -> Result<HashMap<K, V>> {
-        let data = self
-            .data
-            .read()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire read lock: {}", e))?;
+    /// Set the value only if it's still `expected`, see `compare_and_swap`
+    pub async fn compare_and_swap_async(
+        &self,
+        key: &K,
+        expected: Option<&V>,
+        new: Option<V>,
+    ) -> Result<bool>
+    where
+        V: PartialEq,
+    {
+        let Some(had_expiry) = self.swap_mem(key, expected, new)? else {
+            return Ok(false);
+        };
+        let key = key.clone();
+        self.blocking(move |store| store.save_swapped(&key, had_expiry))
+            .await?;
+        Ok(true)
+    }
 
-        Ok(data.clone())
+    /// Save data to disk, see `save_to_disk`
+    pub async fn save_async(&self) -> Result<()> {
+        self.blocking(|store| store.save_to_disk()).await
     }
 
-    /// Batch insert multiple key-value pairs
-    pub fn batch_insert(&self, entries: Vec<(K, V)>) -> Result<()> {
-        let mut data = self
-            .data
-            .write()
-            .map_err(|e| anyhow::anyhow!("Failed to acquire write lock: {}", e))?;
+    /// Insert a key-value pair that expires, see `insert_with_ttl`
+    pub async fn insert_with_ttl_async(
+        &self,
+        key: K,
+        value: V,
+        ttl: std::time::Duration,
+    ) -> Result<Option<V>> {
+        self.blocking(move |store| store.insert_with_ttl(key, value, ttl))
+            .await
+    }
 
-        for (key, value) in entries {
-            data.insert(key, value);
-        }
+    /// Remove the expired entries, see `purge_expired`
+    pub async fn purge_expired_async(&self) -> Result<usize> {
+        self.blocking(|store| store.purge_expired()).await
+    }
 
-        drop(data);
+    /// Save what changed in memory, see `flush`
+    pub async fn flush_async(&self) -> Result<usize> {
+        self.blocking(|store| store.flush()).await
+    }
 
-        self.save_to_disk()?;
+    /// Reload data from disk when its generation moved, see `reload_if_changed`
+    pub async fn reload_if_changed_async(&self) -> Result<bool> {
+        self.blocking(|store| store.reload_if_changed()).await
+    }
 
-        Ok(())
+    /// Reload data from disk, see `reload`
+    pub async fn reload_async(&self) -> Result<()> {
+        self.blocking(|store| store.reload()).await
+    }
+
+    /// Batch insert multiple key-value pairs, see `batch_insert`
+    pub async fn batch_insert_async(&self, entries: Vec<(K, V)>) -> Result<()> {
+        self.blocking(move |store| store.batch_insert(entries))
+            .await
     }
 }
 
@@ -388,8 +2249,658 @@ fn test_concurrent_access() -> Result<()> {
 
     // Verify
     assert_eq!(store.len()?, 100);
+    assert_eq!(store.keys()?, (0..100).collect::<Vec<u64>>());
+
+    // Writes saved together by another thread's save made it to disk too
+    let reopened: DataStore<u64, u64> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.len()?, 100);
+    assert_eq!(reopened.get(&42)?, Some(84));
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    Ok(())
+}
+
+#[test]
+fn test_crash_safe_writes() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_crash_safe.json");
+    let backup = backup_path(&temp_path);
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(&backup);
+
+    // Files from before checksums still load
+    std::fs::write(&temp_path, br#"{"a": 1}"#)?;
+    let store: DataStore<String, i32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(store.get(&"a".to_string())?, Some(1));
+
+    store.insert_save("b".to_string(), 2)?;
+    store.insert_save("c".to_string(), 3)?;
+    assert!(std::fs::read(&temp_path)?.starts_with(CHECKSUM_MAGIC));
+
+    // Cut off mid-write, the previous save is loaded instead
+    let bytes = std::fs::read(&temp_path)?;
+    std::fs::write(&temp_path, &bytes[..bytes.len() - 10])?;
+    let store: DataStore<String, i32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(store.len()?, 2);
+    assert_eq!(store.get(&"c".to_string())?, None);
+
+    // Without a good backup the damage is reported, not papered over
+    std::fs::write(&backup, b"garbage")?;
+    std::fs::write(&temp_path, &bytes[..bytes.len() - 10])?;
+    assert!(DataStore::<String, i32>::new(temp_path.clone()).is_err());
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(&backup);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_operations() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_async.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    store.insert_save_async("a".to_string(), 1).await?;
+    store
+        .batch_insert_async(vec![("b".to_string(), 2), ("c".to_string(), 3)])
+        .await?;
+    assert_eq!(store.delete_async(&"a".to_string()).await?, Some(1));
+
+    // What another process would see
+    let other: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(other.len()?, 2);
+    other.insert_save_async("d".to_string(), 4).await?;
+    store.reload_async().await?;
+    assert_eq!(store.get(&"d".to_string())?, Some(4));
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    Ok(())
+}
+
+#[test]
+fn test_sqlite_backend_shared_between_stores() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_sqlite_shared.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+    write_entries::<String, u32>(&temp_path, StoreFormat::Sqlite, 1, &BTreeMap::new())?;
+
+    // Two processes with their own copy of the same store
+    let service: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    let proxy: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(service.format()?, StoreFormat::Sqlite);
+
+    service.insert_save("a".to_string(), 1)?;
+    proxy.insert_save("b".to_string(), 2)?;
+    service.insert_save("c".to_string(), 3)?;
+    proxy.reload()?;
+    proxy.delete(&"a".to_string())?;
+
+    // Each single write landed, neither overwrote the other's
+    service.reload()?;
+    assert_eq!(service.get(&"a".to_string())?, None);
+    assert_eq!(service.get(&"b".to_string())?, Some(2));
+    assert_eq!(service.get(&"c".to_string())?, Some(3));
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    Ok(())
+}
+
+#[test]
+fn test_dirty_tracking() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_dirty.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(store.flush()?, 0);
+    assert!(!temp_path.exists());
+
+    store.insert_mem("a".to_string(), 1)?;
+    store.insert_mem("b".to_string(), 2)?;
+    store.insert_mem("a".to_string(), 3)?;
+    assert!(store.is_dirty());
+    assert_eq!(store.flush()?, 2);
+    assert!(!store.is_dirty());
+    assert_eq!(store.flush()?, 0);
+
+    // Saving the key some other way counts
+    store.insert_mem("c".to_string(), 4)?;
+    store.insert_save("c".to_string(), 5)?;
+    assert!(!store.is_dirty());
+
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.get(&"a".to_string())?, Some(3));
+    assert_eq!(reopened.get(&"c".to_string())?, Some(5));
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    Ok(())
+}
+
+#[test]
+fn test_entry_ttl() -> Result<()> {
+    use std::env;
+    use std::time::Duration;
+    let temp_path = env::temp_dir().join("test_store_ttl.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    store.insert_with_ttl("gone".to_string(), 1, Duration::ZERO)?;
+    store.insert_with_ttl("kept".to_string(), 2, Duration::from_secs(60))?;
+    store.insert_save("forever".to_string(), 3)?;
+
+    // Expired entries read as absent before they're purged
+    assert_eq!(store.get(&"gone".to_string())?, None);
+    assert!(!store.contains_key(&"gone".to_string())?);
+    assert_eq!(store.entries()?.len(), 2);
+    assert_eq!(store.len()?, 3);
+
+    // Expiry survives a restart
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.get(&"gone".to_string())?, None);
+    assert_eq!(reopened.get(&"kept".to_string())?, Some(2));
+    assert_eq!(reopened.purge_expired()?, 1);
+    assert_eq!(reopened.len()?, 2);
+
+    // Written again without a TTL, it stays
+    reopened.insert_save("kept".to_string(), 4)?;
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.purge_expired()?, 0);
+    assert_eq!(reopened.get(&"kept".to_string())?, Some(4));
+
+    // Cleared, the expiry goes too and doesn't hit the key written again
+    reopened.insert_with_ttl("kept".to_string(), 5, Duration::ZERO)?;
+    reopened.clear()?;
+    reopened.batch_insert(vec![("kept".to_string(), 6)])?;
+    assert_eq!(reopened.get(&"kept".to_string())?, Some(6));
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.stats()?.expiring_entries, 0);
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+
+    Ok(())
+}
+
+#[test]
+fn test_ordered_scans() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_ordered.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    store.batch_insert(
+        ["user:c", "user:a", "org:x", "user:b", "usera"]
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (key.to_string(), i as u32))
+            .collect(),
+    )?;
+
+    let keys = |entries: Vec<(String, u32)>| -> Vec<String> {
+        entries.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+        keys(store.scan_prefix("user:")?),
+        ["user:a", "user:b", "user:c"]
+    );
+    assert_eq!(
+        keys(store.range("user:b".to_string().."usera".to_string())?),
+        ["user:b", "user:c"]
+    );
+
+    // Two pages of two, then the last one
+    let first = store.page(None, 2)?;
+    assert_eq!(keys(first.entries), ["org:x", "user:a"]);
+    let second = store.page(first.next.as_ref(), 2)?;
+    assert_eq!(keys(second.entries), ["user:b", "user:c"]);
+    let last = store.page(second.next.as_ref(), 2)?;
+    assert_eq!(keys(last.entries), ["usera"]);
+    assert_eq!(last.next, None);
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    Ok(())
+}
+
+#[test]
+fn test_update_and_compare_and_swap() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_update.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    let key = "counter".to_string();
+    assert_eq!(store.update(&key, |n| *n += 1)?, None);
+    store.insert_save(key.clone(), 0)?;
+
+    // Every increment lands, none is lost to another thread's read-modify-write
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let key = key.clone();
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    store.update(&key, |n| *n += 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(store.get(&key)?, Some(200));
+
+    assert!(!store.compare_and_swap(&key, Some(&199), Some(0))?);
+    assert!(store.compare_and_swap(&key, Some(&200), Some(0))?);
+    assert!(store.compare_and_swap(&key, Some(&0), None)?);
+    assert!(store.compare_and_swap(&key, None, Some(7))?);
+
+    // Saved along the way
+    store.reload()?;
+    assert_eq!(store.get(&key)?, Some(7));
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    Ok(())
+}
+
+#[test]
+fn test_upsert_mem() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_upsert.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    let key = "counter".to_string();
+
+    // Missing ones start from the default, concurrent increments all land
+    let threads: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            let key = key.clone();
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    store.upsert_mem(key.clone(), || 0, |n| *n += 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(store.get(&key)?, Some(200));
+    assert_eq!(store.upsert_mem(key.clone(), || 0, |n| *n)?, 200);
+
+    // Memory only until flushed
+    assert!(store.is_dirty());
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.get(&key)?, None);
+
+    // Expired is missing
+    let other = "expired".to_string();
+    store.insert_with_ttl(other.clone(), 5, std::time::Duration::ZERO)?;
+    assert_eq!(store.upsert_mem(other.clone(), || 1, |n| *n)?, 1);
+    assert_eq!(store.get(&other)?, Some(1));
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+
+    Ok(())
+}
+
+#[test]
+fn test_schema_upgrade_on_load() -> Result<()> {
+    use crate::server::versioning::rename_field;
+    use std::env;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Instance {
+        instance_id: String,
+    }
+    let schema = || RecordSchema::new().upgrade(|r| rename_field(r, "instance_url", "instance_id"));
+
+    for (format, name) in [
+        (StoreFormat::Json, "test_store_schema.json"),
+        (StoreFormat::Sqlite, "test_store_schema.db"),
+    ] {
+        let temp_path = env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&temp_path);
+        let _ = std::fs::remove_file(backup_path(&temp_path));
+
+        // Written before the rename, at version 1
+        let old = BTreeMap::from([(
+            "alice".to_string(),
+            serde_json::json!({ "instance_url": "alice-instance" }),
+        )]);
+        write_entries(&temp_path, format, 1, &old)?;
+
+        let store: DataStore<String, Instance> =
+            DataStore::with_schema(temp_path.clone(), schema())?;
+        let alice = Instance {
+            instance_id: "alice-instance".to_string(),
+        };
+        assert_eq!(store.get(&"alice".to_string())?, Some(alice.clone()));
+
+        // Saved upgraded, a plain read sees the new shape and version
+        let (saved, _, version) = read_versioned_entries::<String, Instance>(&temp_path)?;
+        assert_eq!(version, 2);
+        assert_eq!(saved.get("alice"), Some(&alice));
+
+        // A build that only knows version 1 leaves the file alone
+        let older = RecordSchema::new();
+        assert!(DataStore::<String, Instance>::with_schema(temp_path.clone(), older).is_err());
+
+        let _ = std::fs::remove_file(&temp_path);
+        let _ = std::fs::remove_file(backup_path(&temp_path));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_ndjson_store() -> Result<()> {
+    use std::env;
+    let temp_path = env::temp_dir().join("test_store_ndjson.json");
+    let backup = backup_path(&temp_path);
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(&backup);
+
+    write_entries::<String, u32>(&temp_path, StoreFormat::Ndjson, 1, &BTreeMap::new())?;
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(store.format()?, StoreFormat::Ndjson);
+    store.insert_save("a".to_string(), 1)?;
+    store.insert_save("b".to_string(), 2)?;
+    store.insert_save("c".to_string(), 3)?;
+
+    // One entry per line after the headers
+    let content = std::fs::read_to_string(&temp_path)?;
+    let lines: Vec<&str> = content.lines().skip(2).collect();
+    assert_eq!(lines, [r#"["a",1]"#, r#"["b",2]"#, r#"["c",3]"#]);
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.entries()?, store.entries()?);
+
+    // Cut off at a line boundary still fails the checksum, the previous save is loaded
+    std::fs::write(&temp_path, content.trim_end_matches("[\"c\",3]\n"))?;
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.len()?, 2);
+
+    // Migrating to JSON writes it compact
+    write_entries(&temp_path, StoreFormat::Json, 1, &store.snapshot()?)?;
+    let (data, format) = read_entries::<String, u32>(&temp_path)?;
+    assert_eq!((data.len(), format), (3, StoreFormat::Json));
+    assert!(std::fs::read_to_string(&temp_path)?.ends_with(r#"{"a":1,"b":2,"c":3}"#));
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(&backup);
+
+    Ok(())
+}
+
+#[test]
+fn test_file_lock_between_processes() -> Result<()> {
+    use std::env;
+    use std::time::{Duration, Instant};
+    let temp_path = env::temp_dir().join("test_store_file_lock.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    store.insert_save("a".to_string(), 1)?;
+
+    // The other process is saving, a load waits for it to be done
+    let saving = FileLock::exclusive(&temp_path)?;
+    let started = Instant::now();
+    let reader = {
+        let temp_path = temp_path.clone();
+        std::thread::spawn(move || DataStore::<String, u32>::new(temp_path).and_then(|s| s.len()))
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    drop(saving);
+    assert_eq!(reader.join().unwrap()?, 1);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // Loads don't wait on each other
+    let _loading = FileLock::shared(&temp_path)?;
+    assert_eq!(DataStore::<String, u32>::new(temp_path.clone())?.len()?, 1);
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(lock_path(&temp_path));
+
+    Ok(())
+}
+
+#[test]
+fn test_stats_and_compact() -> Result<()> {
+    use std::env;
+    use std::time::Duration;
+
+    for (format, name) in [
+        (StoreFormat::Json, "test_store_compact.json"),
+        (StoreFormat::Sqlite, "test_store_compact.db"),
+    ] {
+        let temp_path = env::temp_dir().join(name);
+        let _ = std::fs::remove_file(&temp_path);
+        let _ = std::fs::remove_file(expiry_path(&temp_path));
+        write_entries::<String, String>(&temp_path, format, 1, &BTreeMap::new())?;
+
+        let store: DataStore<String, String> = DataStore::new(temp_path.clone())?;
+        assert_eq!(store.stats()?.last_saved_at, None);
+        for i in 0..50 {
+            store.insert_save(format!("key{}", i), "x".repeat(100))?;
+        }
+        store.insert_with_ttl("gone".to_string(), "x".to_string(), Duration::ZERO)?;
+        store.insert_mem("unsaved".to_string(), "x".to_string())?;
+
+        let stats = store.stats()?;
+        assert_eq!(stats.format, format);
+        assert_eq!(
+            (stats.entries, stats.expiring_entries, stats.dirty_entries),
+            (52, 1, 1)
+        );
+        assert!(stats.file_size_bytes > 0);
+        assert!(stats.last_saved_at.is_some());
+
+        let report = store.compact()?;
+        assert_eq!(report.purged_entries, 1);
+        let stats = store.stats()?;
+        assert_eq!(
+            (stats.entries, stats.expiring_entries, stats.dirty_entries),
+            (51, 0, 0)
+        );
+        assert_eq!(
+            DataStore::<String, String>::new(temp_path.clone())?.len()?,
+            51
+        );
+
+        let _ = std::fs::remove_file(&temp_path);
+        let _ = std::fs::remove_file(backup_path(&temp_path));
+        let _ = std::fs::remove_file(expiry_path(&temp_path));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_export_and_import() -> Result<()> {
+    use crate::server::versioning::rename_field;
+    use serde_json::json;
+    use std::env;
+
+    let source_path = env::temp_dir().join("test_store_export_source.json");
+    let target_path = env::temp_dir().join("test_store_export_target.json");
+    for path in [&source_path, &target_path] {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(backup_path(path));
+        write_entries::<String, serde_json::Value>(path, StoreFormat::Json, 1, &BTreeMap::new())?;
+    }
+
+    let source: DataStore<String, serde_json::Value> = DataStore::new(source_path.clone())?;
+    source.insert_save(
+        "a@x.com".to_string(),
+        json!({"plan": "pro", "instance_url": "i1"}),
+    )?;
+    source.insert_save(
+        "b@x.com".to_string(),
+        json!({"plan": "free", "tags": ["x", "y"]}),
+    )?;
+
+    let mut csv = Vec::new();
+    assert_eq!(source.export_csv(&mut csv)?, 2);
+    assert_eq!(
+        String::from_utf8(csv).unwrap(),
+        "key,instance_url,plan,tags\na@x.com,i1,pro,\nb@x.com,,free,\"[\"\"x\"\",\"\"y\"\"]\"\n"
+    );
+
+    let mut ndjson = Vec::new();
+    assert_eq!(source.export_ndjson(&mut ndjson)?, 2);
+
+    // The target is a version ahead, the exported records are upgraded on import
+    let schema = RecordSchema::new().upgrade(|r| rename_field(r, "instance_url", "instance_id"));
+    let target: DataStore<String, serde_json::Value> =
+        DataStore::with_schema(target_path.clone(), schema)?;
+    target.insert_save("b@x.com".to_string(), json!({"plan": "pro"}))?;
+
+    let report = target.import(ndjson.as_slice(), false)?;
+    assert_eq!((report.imported, report.skipped), (1, 1));
+    assert_eq!(
+        target.get(&"a@x.com".to_string())?,
+        Some(json!({"plan": "pro", "instance_id": "i1"}))
+    );
+    assert_eq!(
+        target.get(&"b@x.com".to_string())?,
+        Some(json!({"plan": "pro"}))
+    );
+
+    target.import(ndjson.as_slice(), true)?;
+    assert_eq!(target.get(&"b@x.com".to_string())?.unwrap()["plan"], "free");
+    target.reload()?;
+    assert_eq!(target.len()?, 2);
+
+    // A bad line anywhere and nothing is imported
+    let broken = [ndjson.as_slice(), b"{\"key\": \"c@x.com\"}\n"].concat();
+    target.clear()?;
+    let err = target.import(broken.as_slice(), true).unwrap_err();
+    assert!(matches!(err, BlazeError::Validation(ref m) if m.starts_with("Line 3")));
+    assert!(target.is_empty()?);
+
+    for path in [&source_path, &target_path] {
+        let _ = std::fs::remove_file(path);
+        let _ = std::fs::remove_file(backup_path(path));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_import_clears_expiry() -> Result<()> {
+    use std::env;
+    use std::time::Duration;
+    let temp_path = env::temp_dir().join("test_store_import_ttl.json");
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+
+    let source: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    source.insert_save("a".to_string(), 1)?;
+    let mut ndjson = Vec::new();
+    source.export_ndjson(&mut ndjson)?;
+
+    // Imported over a key whose TTL ran out, the record is there for good
+    source.insert_with_ttl("a".to_string(), 2, Duration::ZERO)?;
+    source.import(ndjson.as_slice(), true)?;
+
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert_eq!(reopened.get(&"a".to_string())?, Some(1));
+    assert_eq!(reopened.stats()?.expiring_entries, 0);
+
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+
+    Ok(())
+}
+
+#[test]
+fn test_generation_reload() -> Result<()> {
+    use std::env;
+
+    let temp_path = env::temp_dir().join("test_store_generation.json");
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(generation_path(&temp_path));
+
+    let writer: DataStore<String, u32> = DataStore::new(temp_path.clone())?.with_generation();
+    writer.insert_save("a".to_string(), 1)?;
+    assert_eq!(read_generation(&temp_path), 1);
+
+    let reader: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    assert!(!reader.reload_if_changed()?);
+
+    writer.insert_mem("b".to_string(), 2)?;
+    assert!(!reader.reload_if_changed()?);
+    writer.flush()?;
+    writer.delete(&"a".to_string())?;
+    assert_eq!(read_generation(&temp_path), 3);
+    assert!(reader.reload_if_changed()?);
+    assert_eq!(reader.keys()?, vec!["b".to_string()]);
+    assert!(!reader.reload_if_changed()?);
+
+    // A second writer carries on from the file's counter
+    let other: DataStore<String, u32> = DataStore::new(temp_path.clone())?.with_generation();
+    other.insert_save("c".to_string(), 3)?;
+    assert_eq!(read_generation(&temp_path), 4);
 
     let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(backup_path(&temp_path));
+    let _ = std::fs::remove_file(generation_path(&temp_path));
+    Ok(())
+}
+
+#[test]
+fn test_rename_keys() -> Result<()> {
+    use std::env;
+    use std::time::Duration;
+
+    let temp_path = env::temp_dir().join("test_store_rename_keys.json");
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+
+    let store: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    store.insert_save("a".to_string(), 1)?;
+    store.insert_with_ttl("b".to_string(), 2, Duration::from_secs(60))?;
+    store.insert_save("c".to_string(), 3)?;
+
+    let moved = store.rename_keys(vec![
+        ("a".to_string(), "x".to_string()),
+        ("b".to_string(), "c".to_string()),
+        ("missing".to_string(), "y".to_string()),
+    ])?;
+    assert_eq!(moved, 2);
+
+    let reopened: DataStore<String, u32> = DataStore::new(temp_path.clone())?;
+    let mut keys = reopened.keys()?;
+    keys.sort();
+    assert_eq!(keys, vec!["c".to_string(), "x".to_string()]);
+    assert_eq!(reopened.get(&"x".to_string())?, Some(1));
+    assert_eq!(reopened.get(&"c".to_string())?, Some(2));
+    // The expiry moved along with the entry
+    let expiry = std::fs::read_to_string(expiry_path(&temp_path))?;
+    assert!(expiry.contains("\"c\"") && !expiry.contains("\"b\""));
 
+    let _ = std::fs::remove_file(&temp_path);
+    let _ = std::fs::remove_file(expiry_path(&temp_path));
+    let _ = std::fs::remove_file(backup_path(&temp_path));
     Ok(())
 }
@@ -0,0 +1,128 @@
+//! # Long-lived connections
+//!
+//! WebSockets and Server-Sent Events keep a connection to the instance open for as long as the
+//! client wants. The proxy tunnels upgraded connections to the instance byte for byte, and sends
+//! requests asking for `text/event-stream` without a read timeout so quiet subscriptions aren't
+//! cut off. Either kind counts against the `max_streams` of the user's plan while it's open.
+
+use axum::http::{HeaderMap, header};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Open long-lived connections per user, shared across the proxy (cheap to clone)
+#[derive(Debug, Clone, Default)]
+pub struct StreamLimiter {
+    open: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+/// One open connection of a user, dropping it closes the slot
+#[derive(Debug)]
+pub struct StreamPermit {
+    limiter: StreamLimiter,
+    email: String,
+}
+
+impl StreamLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes a slot for the user, None if they already have `limit` connections open
+    pub fn try_open(&self, email: &str, limit: u32) -> Option<StreamPermit> {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let count = open.entry(email.to_string()).or_insert(0);
+        if *count >= limit {
+            return None;
+        }
+        *count += 1;
+
+        Some(StreamPermit {
+            limiter: self.clone(),
+            email: email.to_string(),
+        })
+    }
+
+    /// Number of connections the user has open
+    pub fn open_streams(&self, email: &str) -> u32 {
+        let open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        open.get(email).copied().unwrap_or(0)
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&self.email) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                open.remove(&self.email);
+            }
+        }
+    }
+}
+
+fn has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers.get_all(name).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        })
+    })
+}
+
+/// Whether the client asks to switch protocols (a WebSocket handshake)
+pub fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE) && has_token(headers, header::CONNECTION, "upgrade")
+}
+
+/// Whether the client asks for Server-Sent Events
+pub fn is_event_stream_request(headers: &HeaderMap) -> bool {
+    headers.get_all(header::ACCEPT).iter().any(|value| {
+        value
+            .to_str()
+            .is_ok_and(|value| value.contains("text/event-stream"))
+    })
+}
+
+/// Headers of an upgrade request as the instance gets them
+/// The handshake (Connection, Upgrade, Sec-WebSocket-*) goes through, credentials and the
+/// proxy's own Host don't
+pub fn upstream_upgrade_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut upstream = headers.clone();
+    upstream.remove(header::AUTHORIZATION);
+    upstream.remove(header::HOST);
+    upstream.remove(header::CONTENT_LENGTH);
+    upstream.remove(header::TRANSFER_ENCODING);
+    upstream
+}
+
+#[test]
+fn test_stream_limits_and_detection() {
+    let limiter = StreamLimiter::new();
+    let first = limiter.try_open("a@x.com", 2).unwrap();
+    let _second = limiter.try_open("a@x.com", 2).unwrap();
+    assert!(limiter.try_open("a@x.com", 2).is_none());
+    assert!(limiter.try_open("b@x.com", 2).is_some()); // Limits are per user
+    drop(first);
+    assert_eq!(limiter.open_streams("a@x.com"), 1);
+    assert!(limiter.try_open("a@x.com", 2).is_some());
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONNECTION, "keep-alive, Upgrade".parse().unwrap());
+    headers.insert(header::UPGRADE, "websocket".parse().unwrap());
+    headers.insert(header::AUTHORIZATION, "Bearer blz_x".parse().unwrap());
+    headers.insert(header::HOST, "proxy.example.com".parse().unwrap());
+    assert!(is_upgrade_request(&headers));
+    assert!(!is_event_stream_request(&headers));
+
+    let upstream = upstream_upgrade_headers(&headers);
+    assert!(is_upgrade_request(&upstream));
+    assert!(!upstream.contains_key(header::AUTHORIZATION));
+    assert!(!upstream.contains_key(header::HOST));
+
+    headers.remove(header::UPGRADE);
+    headers.insert(header::ACCEPT, "text/event-stream".parse().unwrap());
+    assert!(!is_upgrade_request(&headers));
+    assert!(is_event_stream_request(&headers));
+}
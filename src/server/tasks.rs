@@ -0,0 +1,218 @@
+//! # Background task registry
+//!
+//! Every background loop and fire-and-forget job is spawned through the registry instead of a bare
+//! `tokio::spawn`. Tasks get a child of the registry's cancellation token, and components can add a
+//! flush hook (e.g. save a datastore to disk). On shutdown the token is cancelled, running tasks are
+//! awaited (with a timeout), then the flush hooks run in registration order.
+
+use crate::{error, info, warn};
+use anyhow::Result;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+static TASK_REGISTRY: OnceLock<TaskRegistry> = OnceLock::new();
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+type FlushFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type FlushHook = Box<dyn FnOnce() -> FlushFuture + Send>;
+
+struct RegisteredTask {
+    name: String,
+    handle: JoinHandle<()>,
+}
+
+#[derive(Default)]
+pub struct TaskRegistry {
+    token: CancellationToken,
+    tasks: Mutex<Vec<RegisteredTask>>,
+    flush_hooks: Mutex<Vec<(String, FlushHook)>>,
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token cancelled when shutdown starts, for loops that aren't spawned here
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Starts shutdown without waiting for anything, loops stop after their current tick and
+    /// long-lived responses watching the token end
+    pub fn cancel(&self) {
+        self.token.cancel();
+    }
+
+    /// Spawns a tracked task, the closure gets a token to watch for shutdown
+    pub fn spawn<F, Fut>(&self, name: &str, task: F)
+    where
+        F: FnOnce(CancellationToken) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(task(self.token.child_token()));
+
+        let mut tasks = self.tasks.lock().unwrap_or_else(|e| e.into_inner());
+        // Forget one-shot jobs that already finished so the list doesn't grow forever
+        tasks.retain(|t| !t.handle.is_finished());
+        tasks.push(RegisteredTask {
+            name: name.to_string(),
+            handle,
+        });
+    }
+
+    /// Spawns a loop running `tick` every `period` until shutdown
+    /// The first tick fires right away, like `tokio::time::interval`
+    pub fn spawn_periodic<F, Fut>(&self, name: &str, period: Duration, mut tick: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        self.spawn(name, move |token| async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                tokio::select! {
+                    _ = token.cancelled() => break,
+                    _ = interval.tick() => tick().await,
+                }
+            }
+        });
+    }
+
+    /// Registers a hook that runs once at shutdown, after the tasks have stopped
+    pub fn on_shutdown<F, Fut>(&self, name: &str, hook: F)
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let mut hooks = self.flush_hooks.lock().unwrap_or_else(|e| e.into_inner());
+        hooks.push((name.to_string(), Box::new(move || Box::pin(hook()))));
+    }
+
+    /// Stops every task and runs the flush hooks
+    /// Tasks still running after `timeout` are aborted
+    pub async fn shutdown(&self, timeout: Duration) {
+        self.token.cancel();
+
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap_or_else(|e| e.into_inner()));
+        info!("Stopping {} background task(s)...", tasks.len());
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        for mut task in tasks {
+            match tokio::time::timeout_at(deadline, &mut task.handle).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Background task '{}' failed: {}", task.name, e),
+                Err(_) => {
+                    warn!(
+                        "Background task '{}' didn't stop in time, aborting",
+                        task.name
+                    );
+                    task.handle.abort();
+                }
+            }
+        }
+
+        let hooks =
+            std::mem::take(&mut *self.flush_hooks.lock().unwrap_or_else(|e| e.into_inner()));
+        for (name, hook) in hooks {
+            if let Err(e) = hook().await {
+                error!("Shutdown hook '{}' failed: {}", name, e);
+            }
+        }
+
+        info!("Background tasks stopped");
+    }
+}
+
+/// How long shutdown waits for open requests, then again for background tasks
+/// `BLAZE_SHUTDOWN_TIMEOUT_SECONDS`, keep both together below Docker's stop grace period
+pub fn shutdown_timeout() -> Duration {
+    dotenv::dotenv().ok();
+    let seconds = std::env::var("BLAZE_SHUTDOWN_TIMEOUT_SECONDS")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECONDS);
+    Duration::from_secs(seconds)
+}
+
+/// Returns the process wide task registry
+pub fn get_task_registry() -> &'static TaskRegistry {
+    TASK_REGISTRY.get_or_init(TaskRegistry::new)
+}
+
+/// Resolves on Ctrl+C or SIGTERM (what `docker stop` sends)
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutdown signal received");
+}
+
+#[tokio::test]
+async fn test_shutdown_stops_loops_then_flushes() {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let registry = TaskRegistry::new();
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let flushed = Arc::new(AtomicUsize::new(0));
+
+    let counter = ticks.clone();
+    registry.spawn_periodic("ticker", Duration::from_millis(5), move || {
+        let counter = counter.clone();
+        async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+        }
+    });
+
+    let ticks_at_flush = ticks.clone();
+    let flush_counter = flushed.clone();
+    registry.on_shutdown("flush", move || async move {
+        flush_counter.store(ticks_at_flush.load(Ordering::SeqCst), Ordering::SeqCst);
+        Ok(())
+    });
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    registry.shutdown(Duration::from_secs(1)).await;
+
+    let after_shutdown = ticks.load(Ordering::SeqCst);
+    assert!(after_shutdown > 0);
+    // The hook ran after the loop stopped, and nothing ticks anymore
+    assert_eq!(flushed.load(Ordering::SeqCst), after_shutdown);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(ticks.load(Ordering::SeqCst), after_shutdown);
+}
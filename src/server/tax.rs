@@ -0,0 +1,227 @@
+//! # Tax
+//!
+//! EU customers are charged VAT at the rate of their country, unless they're a business with a
+//! VAT ID (the reverse charge: they account for it themselves). Checkout takes the billing country
+//! and VAT ID, they're checked here, kept on the user and put on the user's Stripe customer.
+//!
+//! How much tax a payment carries is up to a `TaxProvider`, picked with `BLAZE_TAX_PROVIDER`:
+//! - `stripe` (default): Stripe Tax. Checkout Sessions have automatic tax on, Stripe works out the
+//!   rate from the billing address and applies the reverse charge for valid VAT IDs.
+//! - `none`: no tax, for deployments that aren't registered for VAT anywhere.
+//!
+//! The tax that was charged is read back from the completed checkout or paid invoice and stored
+//! with the payment in the billing history, monthly invoices show it.
+
+use crate::server::schema::{TaxDetails, TaxRecord};
+use anyhow::Result;
+
+/// EU member states, as ISO 3166-1 alpha-2 codes
+const EU_COUNTRIES: [&str; 27] = [
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GR", "HR", "HU", "IE", "IT",
+    "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK",
+];
+
+/// Computes the tax of checkouts and renewals
+pub trait TaxProvider: Send + Sync {
+    /// Stored with every tax record, e.g. "stripe"
+    fn name(&self) -> &'static str;
+
+    /// Fields added to the Checkout Session create call
+    fn checkout_params(&self) -> Vec<(&'static str, String)>;
+
+    /// The tax charged on a completed Checkout Session or a paid invoice
+    /// `details` is what the user gave at checkout, for whatever Stripe didn't send back
+    fn tax_charged(&self, object: &serde_json::Value, details: &TaxDetails) -> TaxRecord;
+}
+
+/// Stripe Tax, the rate and reverse charge are Stripe's call
+pub struct StripeTax;
+
+impl TaxProvider for StripeTax {
+    fn name(&self) -> &'static str {
+        "stripe"
+    }
+
+    fn checkout_params(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("automatic_tax[enabled]", "true".to_string()),
+            ("billing_address_collection", "required".to_string()),
+            ("tax_id_collection[enabled]", "true".to_string()),
+            // Whatever is entered on the payment page goes on the customer, renewals use it
+            ("customer_update[address]", "auto".to_string()),
+            ("customer_update[name]", "auto".to_string()),
+        ]
+    }
+
+    fn tax_charged(&self, object: &serde_json::Value, details: &TaxDetails) -> TaxRecord {
+        // Checkout Sessions have `total_details`/`customer_details`, invoices flat `customer_*`
+        let tax_cents = object
+            .pointer("/total_details/amount_tax")
+            .or_else(|| object.get("tax"))
+            .and_then(|v| v.as_i64())
+            .or_else(|| {
+                object
+                    .get("total_taxes")
+                    .and_then(|v| v.as_array())
+                    .map(|taxes| {
+                        taxes
+                            .iter()
+                            .filter_map(|t| t.get("amount").and_then(|v| v.as_i64()))
+                            .sum()
+                    })
+            })
+            .unwrap_or(0);
+        let field = |session_path: &str, invoice_path: &str| {
+            object
+                .pointer(session_path)
+                .or_else(|| object.pointer(invoice_path))
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+
+        TaxRecord {
+            provider: self.name().to_string(),
+            tax_cents,
+            country: field(
+                "/customer_details/address/country",
+                "/customer_address/country",
+            )
+            .or_else(|| details.country.clone()),
+            vat_id: field(
+                "/customer_details/tax_ids/0/value",
+                "/customer_tax_ids/0/value",
+            )
+            .or_else(|| details.vat_id.clone()),
+            reverse_charge: field("/customer_details/tax_exempt", "/customer_tax_exempt")
+                .as_deref()
+                == Some("reverse"),
+        }
+    }
+}
+
+/// No tax at all
+pub struct NoTax;
+
+impl TaxProvider for NoTax {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn checkout_params(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+
+    fn tax_charged(&self, _object: &serde_json::Value, details: &TaxDetails) -> TaxRecord {
+        TaxRecord {
+            provider: self.name().to_string(),
+            tax_cents: 0,
+            country: details.country.clone(),
+            vat_id: details.vat_id.clone(),
+            reverse_charge: false,
+        }
+    }
+}
+
+/// The provider configured in `BLAZE_TAX_PROVIDER`
+pub fn get_tax_provider() -> &'static dyn TaxProvider {
+    dotenv::dotenv().ok();
+    match std::env::var("BLAZE_TAX_PROVIDER").as_deref() {
+        Ok("none") => &NoTax,
+        _ => &StripeTax,
+    }
+}
+
+pub fn is_eu_country(country: &str) -> bool {
+    EU_COUNTRIES.contains(&country)
+}
+
+/// Stripe's tax id type for VAT IDs of `country` and the prefix they start with
+/// Greek VAT IDs start with EL, not GR
+pub fn vat_id_kind(country: &str) -> Option<(&'static str, String)> {
+    match country {
+        "GR" => Some(("eu_vat", "EL".to_string())),
+        "GB" => Some(("gb_vat", "GB".to_string())),
+        c if is_eu_country(c) => Some(("eu_vat", c.to_string())),
+        _ => None,
+    }
+}
+
+/// Checks and normalizes the country and VAT ID given at checkout
+/// A VAT ID needs its country, the prefix is added when it was left out
+pub fn normalize_tax_details(country: Option<&str>, vat_id: Option<&str>) -> Result<TaxDetails> {
+    let country = country
+        .map(|c| c.trim().to_uppercase())
+        .filter(|c| !c.is_empty());
+    if let Some(country) = &country
+        && (country.len() != 2 || !country.chars().all(|c| c.is_ascii_uppercase()))
+    {
+        return Err(anyhow::anyhow!(
+            "Country must be a two letter code, like DE, got {}",
+            country
+        ));
+    }
+
+    let vat_id: Option<String> = vat_id
+        .map(|v| {
+            v.chars()
+                .filter(|c| !c.is_whitespace() && *c != '.' && *c != '-')
+                .collect::<String>()
+                .to_uppercase()
+        })
+        .filter(|v| !v.is_empty());
+    let Some(vat_id) = vat_id else {
+        return Ok(TaxDetails {
+            country,
+            vat_id: None,
+        });
+    };
+
+    let country = country.ok_or_else(|| anyhow::anyhow!("A VAT ID needs the country it's from"))?;
+    let (_, prefix) = vat_id_kind(&country)
+        .ok_or_else(|| anyhow::anyhow!("VAT IDs are only taken for EU and UK businesses"))?;
+    let number = vat_id.strip_prefix(prefix.as_str()).unwrap_or(&vat_id);
+    if !(2..=12).contains(&number.len()) || !number.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(anyhow::anyhow!(
+            "{} isn't a valid {} VAT ID",
+            vat_id,
+            country
+        ));
+    }
+
+    Ok(TaxDetails {
+        vat_id: Some(format!("{}{}", prefix, number)),
+        country: Some(country),
+    })
+}
+
+#[test]
+fn test_tax_details_and_stripe_tax() -> Result<()> {
+    let details = normalize_tax_details(Some(" de "), Some("123 456 789"))?;
+    assert_eq!(details.country.as_deref(), Some("DE"));
+    assert_eq!(details.vat_id.as_deref(), Some("DE123456789"));
+    let greek = normalize_tax_details(Some("GR"), Some("EL-094259216"))?;
+    assert_eq!(greek.vat_id.as_deref(), Some("EL094259216"));
+
+    assert!(normalize_tax_details(None, Some("DE123456789")).is_err());
+    assert!(normalize_tax_details(Some("US"), Some("123456789")).is_err());
+    assert!(normalize_tax_details(Some("Germany"), None).is_err());
+    assert!(normalize_tax_details(None, None)?.is_empty());
+
+    let session = serde_json::json!({
+        "total_details": { "amount_tax": 361 },
+        "customer_details": {
+            "address": { "country": "FR" },
+            "tax_exempt": "none",
+            "tax_ids": []
+        }
+    });
+    let record = StripeTax.tax_charged(&session, &details);
+    assert_eq!(record.tax_cents, 361);
+    assert_eq!(record.country.as_deref(), Some("FR")); // What Stripe saw wins
+    assert_eq!(record.vat_id.as_deref(), Some("DE123456789"));
+    assert!(!record.reverse_charge);
+
+    let invoice = serde_json::json!({ "tax": 0, "customer_tax_exempt": "reverse" });
+    assert!(StripeTax.tax_charged(&invoice, &details).reverse_charge);
+    Ok(())
+}
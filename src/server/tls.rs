@@ -0,0 +1,384 @@
+//! # TLS for the proxy
+//!
+//! Lets the proxy face the internet on its own, without nginx in front. Certificates come from
+//! either
+//! - PEM files: `BLAZE_PROXY_TLS_CERT` and `BLAZE_PROXY_TLS_KEY`, checked for changes hourly so a
+//!   renewal by certbot (or anything else) is picked up without a restart
+//! - Let's Encrypt: `BLAZE_PROXY_ACME_DOMAINS` (comma separated) and `BLAZE_PROXY_ACME_EMAIL`.
+//!   Certificates are requested and renewed in the background over TLS-ALPN-01, so the TLS port
+//!   has to be reachable as 443. They're cached under `data/acme`.
+//!   `BLAZE_PROXY_ACME_STAGING=true` uses the staging directory while testing.
+//!
+//! With TLS on, plain HTTP on `BLAZE_PROXY_HTTP_PORT` (80 by default, 0 disables it) is
+//! redirected to HTTPS, and responses carry HSTS for `BLAZE_PROXY_HSTS_MAX_AGE` seconds (a year,
+//! 0 leaves the header out).
+
+use crate::server::service::get_data_path;
+use crate::server::tasks::get_task_registry;
+use crate::{error, info, warn};
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use rustls_acme::caches::DirCache;
+use rustls_acme::futures_rustls::rustls::ServerConfig;
+use rustls_acme::futures_rustls::rustls::crypto::ring::sign::any_supported_type;
+use rustls_acme::futures_rustls::rustls::pki_types::pem::PemObject;
+use rustls_acme::futures_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls_acme::futures_rustls::rustls::server::{Acceptor, ClientHello, ResolvesServerCert};
+use rustls_acme::futures_rustls::rustls::sign::CertifiedKey;
+use rustls_acme::futures_rustls::{LazyConfigAcceptor, server::TlsStream};
+use rustls_acme::{AcmeConfig, is_tls_alpn_challenge};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+const DEFAULT_HTTP_PORT: u16 = 80;
+const DEFAULT_HSTS_MAX_AGE: u64 = 365 * 24 * 60 * 60;
+
+/// Handshakes taking longer than this are dropped, slow clients don't hold a task forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often certificate files are checked for a renewal
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Where the proxy's certificate comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertSource {
+    Files {
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    Acme {
+        domains: Vec<String>,
+        contact: Option<String>,
+        production: bool,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsSettings {
+    pub source: CertSource,
+    pub http_port: Option<u16>, // Plain HTTP redirected to HTTPS, None doesn't listen
+    pub hsts_max_age: u64,      // 0 sends no HSTS header
+}
+
+impl TlsSettings {
+    /// None serves plain HTTP, as without any of the variables set
+    pub fn from_env() -> Result<Option<Self>> {
+        dotenv::dotenv().ok();
+        let var = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+
+        let source = match (
+            var("BLAZE_PROXY_TLS_CERT"),
+            var("BLAZE_PROXY_TLS_KEY"),
+            var("BLAZE_PROXY_ACME_DOMAINS"),
+        ) {
+            (Some(cert), Some(key), _) => CertSource::Files {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+            },
+            (Some(_), None, _) | (None, Some(_), _) => {
+                return Err(anyhow::anyhow!(
+                    "Set both BLAZE_PROXY_TLS_CERT and BLAZE_PROXY_TLS_KEY"
+                ));
+            }
+            (None, None, Some(domains)) => CertSource::Acme {
+                domains: domains
+                    .split(',')
+                    .map(|d| d.trim().to_lowercase())
+                    .filter(|d| !d.is_empty())
+                    .collect(),
+                contact: var("BLAZE_PROXY_ACME_EMAIL"),
+                production: !var("BLAZE_PROXY_ACME_STAGING")
+                    .is_some_and(|v| v.eq_ignore_ascii_case("true") || v == "1"),
+            },
+            (None, None, None) => return Ok(None),
+        };
+
+        let http_port = var("BLAZE_PROXY_HTTP_PORT")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_HTTP_PORT);
+        Ok(Some(TlsSettings {
+            source,
+            http_port: (http_port != 0).then_some(http_port),
+            hsts_max_age: var("BLAZE_PROXY_HSTS_MAX_AGE")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_HSTS_MAX_AGE),
+        }))
+    }
+
+    /// Value of the Strict-Transport-Security header, None if it's turned off
+    pub fn hsts_header(&self) -> Option<String> {
+        (self.hsts_max_age > 0).then(|| format!("max-age={}; includeSubDomains", self.hsts_max_age))
+    }
+}
+
+/// Where a plain HTTP request is sent instead, keeping host, path and query
+pub fn https_redirect_url(host: &str, path_and_query: &str, https_port: u16) -> String {
+    // Strip the HTTP port, IPv6 hosts keep their brackets
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') && port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    match https_port {
+        443 => format!("https://{}{}", host, path_and_query),
+        port => format!("https://{}:{}{}", host, port, path_and_query),
+    }
+}
+
+/// Serves the certificate from PEM files, swapped out when the files change
+#[derive(Debug)]
+struct FileCertResolver {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<(Arc<CertifiedKey>, Option<SystemTime>)>,
+}
+
+fn load_certified_key(cert: &Path, key: &Path) -> Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", cert.display(), e))?;
+    if chain.is_empty() {
+        return Err(anyhow::anyhow!("No certificate in {}", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", key.display(), e))?;
+    let signing_key = any_supported_type(&key).context("Unsupported private key")?;
+    Ok(Arc::new(CertifiedKey::new(chain, signing_key)))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+impl FileCertResolver {
+    fn load(cert: PathBuf, key: PathBuf) -> Result<Self> {
+        let certified = load_certified_key(&cert, &key)?;
+        let loaded_at = modified(&cert);
+        Ok(FileCertResolver {
+            cert,
+            key,
+            current: RwLock::new((certified, loaded_at)),
+        })
+    }
+
+    /// Loads the files again if the certificate changed, a broken renewal keeps the old one
+    fn reload_if_changed(&self) {
+        let changed = modified(&self.cert);
+        let loaded_at = self.current.read().unwrap_or_else(|e| e.into_inner()).1;
+        if changed.is_none() || changed == loaded_at {
+            return;
+        }
+
+        match load_certified_key(&self.cert, &self.key) {
+            Ok(certified) => {
+                *self.current.write().unwrap_or_else(|e| e.into_inner()) = (certified, changed);
+                info!("Reloaded the TLS certificate from {}", self.cert.display());
+            }
+            Err(e) => error!("Keeping the current TLS certificate: {}", e),
+        }
+    }
+}
+
+impl ResolvesServerCert for FileCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .0
+                .clone(),
+        )
+    }
+}
+
+/// A TLS connection as the HTTP server reads it
+pub type TlsIo = Compat<TlsStream<Compat<TcpStream>>>;
+
+/// Completes TLS handshakes, answering ACME challenges on the side
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+    challenge: Option<Arc<ServerConfig>>, // Only with Let's Encrypt
+}
+
+impl TlsAcceptor {
+    /// Loads the certificate, or starts getting one from Let's Encrypt in the background
+    pub fn from_settings(settings: &TlsSettings) -> Result<Self> {
+        let (resolver, challenge): (Arc<dyn ResolvesServerCert>, _) = match &settings.source {
+            CertSource::Files { cert, key } => {
+                let resolver = Arc::new(FileCertResolver::load(cert.clone(), key.clone())?);
+                let reloading = resolver.clone();
+                get_task_registry().spawn_periodic(
+                    "tls-cert-reload",
+                    CERT_RELOAD_INTERVAL,
+                    move || {
+                        let resolver = reloading.clone();
+                        async move { resolver.reload_if_changed() }
+                    },
+                );
+                (resolver, None)
+            }
+            CertSource::Acme {
+                domains,
+                contact,
+                production,
+            } => {
+                if domains.is_empty() {
+                    return Err(anyhow::anyhow!("BLAZE_PROXY_ACME_DOMAINS names no domain"));
+                }
+                let mut state = AcmeConfig::new(domains)
+                    .contact(contact.iter().map(|email| format!("mailto:{}", email)))
+                    .cache(DirCache::new(get_data_path().join("acme")))
+                    .directory_lets_encrypt(*production)
+                    .state();
+                let resolver = state.resolver();
+                let challenge = state.challenge_rustls_config();
+
+                // Polling the state is what orders and renews the certificate
+                get_task_registry().spawn("acme", |token| async move {
+                    loop {
+                        tokio::select! {
+                            _ = token.cancelled() => break,
+                            event = state.next() => match event {
+                                Some(Ok(event)) => info!("ACME: {:?}", event),
+                                Some(Err(e)) => error!("ACME: {:?}", e),
+                                None => break,
+                            },
+                        }
+                    }
+                });
+                (resolver, Some(challenge))
+            }
+        };
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+            challenge,
+        })
+    }
+
+    /// Runs the handshake, None for ACME validation connections (they're done after it)
+    pub async fn accept(&self, tcp: TcpStream) -> std::io::Result<Option<TlsIo>> {
+        let start = LazyConfigAcceptor::new(Acceptor::default(), tcp.compat()).await?;
+        if let Some(challenge) = &self.challenge
+            && is_tls_alpn_challenge(&start.client_hello())
+        {
+            start.into_stream(challenge.clone()).await?;
+            return Ok(None);
+        }
+
+        let tls = start.into_stream(self.config.clone()).await?;
+        Ok(Some(tls.compat()))
+    }
+}
+
+/// Accepts TCP connections and hands them on once their TLS handshake is done
+/// Handshakes run in their own tasks, a slow client doesn't hold up everyone behind it
+pub struct TlsListener {
+    handshaken: mpsc::Receiver<(TlsIo, SocketAddr)>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    pub fn new(listener: TcpListener, acceptor: TlsAcceptor) -> Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, handshaken) = mpsc::channel(64);
+
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                let (tcp, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(tcp)).await {
+                        Ok(Ok(Some(io))) => {
+                            let _ = tx.send((io, addr)).await;
+                        }
+                        Ok(Ok(None)) => info!("Answered an ACME challenge from {}", addr),
+                        // Scanners and plain HTTP on the TLS port, nothing worth more than that
+                        Ok(Err(e)) => warn!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => warn!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+
+        Ok(TlsListener {
+            handshaken,
+            local_addr,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsIo;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.handshaken.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only stops once this listener is gone
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[test]
+fn test_https_redirect_url() {
+    assert_eq!(
+        https_redirect_url("proxy.example.com", "/v1/blazedb/query/abc?x=1", 443),
+        "https://proxy.example.com/v1/blazedb/query/abc?x=1"
+    );
+    assert_eq!(
+        https_redirect_url("proxy.example.com:80", "/health", 8443),
+        "https://proxy.example.com:8443/health"
+    );
+    assert_eq!(https_redirect_url("[::1]:8080", "/", 443), "https://[::1]/");
+    assert_eq!(https_redirect_url("[::1]", "/", 443), "https://[::1]/");
+
+    let settings = TlsSettings {
+        source: CertSource::Files {
+            cert: PathBuf::from("cert.pem"),
+            key: PathBuf::from("key.pem"),
+        },
+        http_port: Some(80),
+        hsts_max_age: 600,
+    };
+    assert_eq!(
+        settings.hsts_header().as_deref(),
+        Some("max-age=600; includeSubDomains")
+    );
+    assert!(
+        TlsSettings {
+            hsts_max_age: 0,
+            ..settings
+        }
+        .hsts_header()
+        .is_none()
+    );
+}
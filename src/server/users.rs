@@ -0,0 +1,98 @@
+//! # User store
+//!
+//! Users are kept in `users.json` under `email_index(email)` rather than the email, and their
+//! email and name are encrypted (see `crypto::encrypt_pii`), so the file doesn't give away who the
+//! customers are. Both only apply with `BLAZE_PII_KEY` set, without it users are stored under
+//! their email in plain text as before.
+//!
+//! `UserStore` wraps the `DataStore` and takes emails, the index is worked out on every call.
+//! `rekey` moves users stored under another key (their email, from before the key was set) to
+//! their index, the service runs it on startup.
+
+use crate::info;
+use crate::server::crypto::email_index;
+use crate::server::error::Result;
+use crate::server::schema::User;
+use crate::server::storage::DataStore;
+
+/// The user store, looked up by email, see the module docs
+#[derive(Clone)]
+pub struct UserStore {
+    store: DataStore<String, User>,
+}
+
+impl UserStore {
+    pub fn new(store: DataStore<String, User>) -> Self {
+        UserStore { store }
+    }
+
+    /// The store underneath, keyed by `email_index`, for whole-store maintenance
+    pub fn store(&self) -> &DataStore<String, User> {
+        &self.store
+    }
+
+    /// Moves users stored under anything but their email's index to it, returns how many moved
+    pub fn rekey(&self) -> Result<usize> {
+        let renames: Vec<(String, String)> = self
+            .store
+            .entries()?
+            .into_iter()
+            .map(|(key, user)| (key, email_index(&user.email)))
+            .filter(|(key, index)| key != index)
+            .collect();
+        if renames.is_empty() {
+            return Ok(0);
+        }
+
+        let moved = self.store.rename_keys(renames)?;
+        info!("Moved {} user(s) to their email index", moved);
+        Ok(moved)
+    }
+
+    pub fn get(&self, email: &str) -> Result<Option<User>> {
+        self.store.get(&email_index(email))
+    }
+
+    pub fn contains_key(&self, email: &str) -> Result<bool> {
+        self.store.contains_key(&email_index(email))
+    }
+
+    pub fn values(&self) -> Result<Vec<User>> {
+        self.store.values()
+    }
+
+    pub fn insert_mem(&self, email: &str, user: User) -> Result<Option<User>> {
+        self.store.insert_mem(email_index(email), user)
+    }
+
+    pub async fn insert_save_async(&self, email: &str, user: User) -> Result<Option<User>> {
+        self.store.insert_save_async(email_index(email), user).await
+    }
+
+    pub async fn update_async<F>(&self, email: &str, f: F) -> Result<Option<User>>
+    where
+        F: FnOnce(&mut User),
+    {
+        self.store.update_async(&email_index(email), f).await
+    }
+
+    pub async fn delete_async(&self, email: &str) -> Result<Option<User>> {
+        self.store.delete_async(&email_index(email)).await
+    }
+
+    pub async fn flush_async(&self) -> Result<usize> {
+        self.store.flush_async().await
+    }
+
+    pub async fn save_async(&self) -> Result<()> {
+        self.store.save_async().await
+    }
+
+    pub async fn reload_async(&self) -> Result<()> {
+        self.store.reload_async().await
+    }
+
+    pub async fn reload_if_changed_async(&self) -> Result<bool> {
+        self.store.reload_if_changed_async().await
+    }
+}
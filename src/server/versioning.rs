@@ -0,0 +1,90 @@
+//! # Record schema versioning
+//!
+//! A store's records change shape over time (a field renamed, one split in two). Every persisted
+//! store carries the schema version its records were written with, see `storage`. A
+//! `RecordSchema` lists the upgrades from each version to the next, the store's current version
+//! being one past the last of them. Loading a store written with an older version runs the
+//! missing upgrades over every record, as JSON, before it's read as the record type, and saves
+//! the upgraded records back.
+//!
+//! Upgrades are only ever appended: a store at version 3 runs the third upgrade onwards. Stores
+//! written before versioning are at version 1. A store written with a newer version than the
+//! running build knows isn't loaded at all, rather than having records dropped or overwritten.
+//!
+//! To change a record type, add an upgrade to its schema, e.g. `User::record_schema`.
+
+use serde_json::Value;
+
+type Upgrade = Box<dyn Fn(&mut Value) + Send + Sync>;
+
+/// The upgrades of a store's records, in order, see the module docs
+#[derive(Default)]
+pub struct RecordSchema {
+    upgrades: Vec<Upgrade>,
+}
+
+impl RecordSchema {
+    /// Schema at version 1, nothing to upgrade
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the upgrade to the next version
+    pub fn upgrade<F>(mut self, upgrade: F) -> Self
+    where
+        F: Fn(&mut Value) + Send + Sync + 'static,
+    {
+        self.upgrades.push(Box::new(upgrade));
+        self
+    }
+
+    /// Version records are written with
+    pub fn version(&self) -> u32 {
+        self.upgrades.len() as u32 + 1
+    }
+
+    /// Brings a record written with version `from` up to the current version
+    pub fn upgrade_record(&self, from: u32, record: &mut Value) {
+        let skip = from.saturating_sub(1) as usize;
+        for upgrade in self.upgrades.iter().skip(skip) {
+            upgrade(record);
+        }
+    }
+}
+
+/// Renames a field of a record, a record that already has the new name keeps it as it is
+pub fn rename_field(record: &mut Value, from: &str, to: &str) {
+    if let Some(fields) = record.as_object_mut()
+        && !fields.contains_key(to)
+        && let Some(value) = fields.remove(from)
+    {
+        fields.insert(to.to_string(), value);
+    }
+}
+
+#[test]
+fn test_record_schema_upgrades() {
+    let schema = RecordSchema::new()
+        .upgrade(|record| rename_field(record, "instance_url", "instance_id"))
+        .upgrade(|record| record["plan"] = Value::from("free"));
+    assert_eq!(schema.version(), 3);
+
+    let mut record = serde_json::json!({ "instance_url": "https://alice.blaze.io" });
+    schema.upgrade_record(1, &mut record);
+    assert_eq!(
+        record,
+        serde_json::json!({ "instance_id": "https://alice.blaze.io", "plan": "free" })
+    );
+
+    // Already at version 2, only the second upgrade runs
+    let mut record = serde_json::json!({ "instance_url": "kept" });
+    schema.upgrade_record(2, &mut record);
+    assert_eq!(
+        record,
+        serde_json::json!({ "instance_url": "kept", "plan": "free" })
+    );
+
+    let mut record = serde_json::json!({ "plan": "pro" });
+    schema.upgrade_record(3, &mut record);
+    assert_eq!(record, serde_json::json!({ "plan": "pro" }));
+}
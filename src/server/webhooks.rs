@@ -0,0 +1,138 @@
+//! # Outbound webhooks
+//!
+//! Platform events (incidents opening and resolving) are POSTed as a `WebhookEvent` to
+//! `BLAZE_WEBHOOK_URL`, so operators can wire them into their own alerting.
+//!
+//! - Bodies are signed with `BLAZE_WEBHOOK_SECRET` in a `Blaze-Signature: t=...,v1=...` header,
+//!   the same scheme Stripe uses for ours (see `crypto::sign_webhook`). Receivers check it with
+//!   `crypto::verify_webhook_signature` or any Stripe-compatible verifier. Without a secret
+//!   nothing is sent, unsigned events would be trivially forged.
+//! - Deliveries run in the background and are retried a few times, each attempt signed afresh so
+//!   a late retry isn't refused as a replay. Events still undelivered after that are dropped.
+
+use crate::server::crypto::sign_webhook;
+use crate::server::schema::WebhookEvent;
+use crate::server::secrets::get_secrets;
+use crate::server::tasks::get_task_registry;
+use crate::{info, warn};
+use std::time::Duration;
+
+pub const SIGNATURE_HEADER: &str = "Blaze-Signature";
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// Where events go and the secret they're signed with, None when webhooks are off
+fn webhook_target() -> Option<(String, Vec<u8>)> {
+    dotenv::dotenv().ok();
+    let url = std::env::var("BLAZE_WEBHOOK_URL")
+        .ok()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())?;
+    match get_secrets().get("BLAZE_WEBHOOK_SECRET") {
+        Some(secret) => Some((url, secret.as_bytes().to_vec())),
+        None => {
+            warn!("BLAZE_WEBHOOK_URL is set without BLAZE_WEBHOOK_SECRET, not sending webhooks");
+            None
+        }
+    }
+}
+
+fn new_event(event_type: &str, data: serde_json::Value) -> WebhookEvent {
+    WebhookEvent {
+        id: format!("evt_{}", hex::encode(rand::random::<[u8; 8]>())),
+        event_type: event_type.to_string(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+        data,
+    }
+}
+
+/// Sends an event to the configured webhook, does nothing when webhooks are off
+/// Runs in the background, the caller doesn't wait on the receiver
+pub fn send_webhook(event_type: &str, data: serde_json::Value) {
+    let Some((url, secret)) = webhook_target() else {
+        return;
+    };
+    let event = new_event(event_type, data);
+    let body = match serde_json::to_vec(&event) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to serialize webhook {}: {}", event.event_type, e);
+            return;
+        }
+    };
+
+    get_task_registry().spawn("webhook-delivery", |_| async move {
+        let client = reqwest::Client::new();
+        for attempt in 1..=DELIVERY_ATTEMPTS {
+            let signature = sign_webhook(&body, &secret, chrono::Utc::now().timestamp());
+            let result = client
+                .post(&url)
+                .timeout(DELIVERY_TIMEOUT)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, signature)
+                .body(body.clone())
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            match result {
+                Ok(_) => {
+                    info!("Webhook {} ({}) delivered", event.event_type, event.id);
+                    return;
+                }
+                Err(e) if attempt < DELIVERY_ATTEMPTS => {
+                    warn!(
+                        "Webhook {} ({}) attempt {} failed, retrying: {}",
+                        event.event_type, event.id, attempt, e
+                    );
+                    tokio::time::sleep(Duration::from_secs(2u64.pow(attempt))).await;
+                }
+                Err(e) => warn!(
+                    "Webhook {} ({}) dropped after {} attempts: {}",
+                    event.event_type, event.id, DELIVERY_ATTEMPTS, e
+                ),
+            }
+        }
+    });
+}
+
+#[test]
+fn test_webhook_event_signature() {
+    use crate::server::crypto::{WEBHOOK_SIGNATURE_TOLERANCE_SECONDS, verify_webhook_signature};
+
+    let event = new_event("incident.opened", serde_json::json!({"id": "inc_1"}));
+    assert!(event.id.starts_with("evt_"));
+    let body = serde_json::to_vec(&event).unwrap();
+    let secret = b"whsec_blaze";
+    let now = 1_700_000_000;
+
+    let header = sign_webhook(&body, secret, now);
+    let verify = |body: &[u8], header: &str, secret: &[u8], at: i64| {
+        verify_webhook_signature(
+            body,
+            header,
+            secret,
+            at,
+            WEBHOOK_SIGNATURE_TOLERANCE_SECONDS,
+        )
+    };
+    assert!(verify(&body, &header, secret, now));
+    assert!(verify(&body, &header, secret, now - 60));
+
+    // Another secret, a changed body, a replay
+    assert!(!verify(&body, &header, b"whsec_other", now));
+    assert!(!verify(b"{}", &header, secret, now));
+    assert!(!verify(&body, &header, secret, now + 3600));
+
+    // A rolled secret signs alongside the old one
+    let rolled = format!(
+        "{},{}",
+        header,
+        sign_webhook(&body, b"whsec_next", now)
+            .split_once(',')
+            .unwrap()
+            .1
+    );
+    assert!(verify(&body, &rolled, b"whsec_next", now));
+    assert!(verify(&body, &rolled, secret, now));
+}